@@ -0,0 +1,1283 @@
+//! Stored actions run on request, outside of any build.
+//!
+//! Each run gets its own task and a `run_id`, so the same action can run
+//! several times at once. What the script writes is broadcast line by line
+//! as `ActionLog`, followed by one `ActionResult` with the exit code and the
+//! last lines of output. Inputs reach the script as environment variables;
+//! it is run exactly as written.
+//!
+//! The script runs with the action's `interpreter`, written to a file with
+//! the extension that interpreter expects. With `auto` the script's shebang
+//! line names it, and scripts without one run with bash.
+//!
+//! A script sets the action's outputs by writing `name=value` lines to the
+//! file named by `$BUILDFORGE_OUTPUT`. Workflows run actions with `action`
+//! nodes, whose output goes to the build log and whose outputs become the
+//! node's outputs.
+//!
+//! Each save makes a new `version` of an action, and the last
+//! `KEPT_VERSIONS` are kept in the history database, so an action node can
+//! pin the version it was written against.
+//!
+//! Every run is recorded in the history database as an `ActionRunRecord`,
+//! under the same id as its audit log entry, with the values of secret
+//! inputs masked there and in its output.
+//!
+//! Running actions are kept in a registry by `run_id`, parallel to the
+//! build registry, so `CancelAction` can stop one. A run that outlives its
+//! `timeout_secs` is stopped the same way: its whole process group is killed.
+//!
+//! `TestAction` runs an action that need not be saved, in a scratch
+//! directory, and checks what it did against expectations. Test runs are
+//! registered, logged and time out like any other run, but are not recorded
+//! and leave the stored data alone.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::{watch, Mutex};
+use tracing::{error, info, warn};
+
+use regex::Regex;
+
+use crate::ansi;
+use crate::build_log::{BuildLog, Stream};
+use crate::builds::CancelToken;
+use crate::shell::Shell;
+use crate::{process, requirements, RunActionPayload, ServerContext, ServerMessage, StoredAction};
+
+/// Interpreters an action can name, besides `auto` and the path of a program
+pub const INTERPRETERS: [&str; 6] = ["bash", "sh", "zsh", "pwsh", "python3", "node"];
+
+/// Interpreter of actions that name none
+const DEFAULT_INTERPRETER: &str = "bash";
+
+/// Versions of each action kept for action nodes that pin one
+pub const KEPT_VERSIONS: usize = 20;
+
+/// Environment variable naming the file a script writes its outputs to
+const OUTPUT_ENV: &str = "BUILDFORGE_OUTPUT";
+
+/// Lines of output `ActionResult` carries; the rest went out as `ActionLog`
+const RESULT_TAIL_LINES: usize = 100;
+
+/// Most stdout a test run keeps for its `stdout_matches`
+const TEST_STDOUT_BYTES: usize = 1024 * 1024;
+
+/// Finished runs remembered, so cancelling one is told apart from a typo
+const FINISHED_RUNS: usize = 100;
+
+#[derive(Default)]
+pub struct ActionRegistry {
+    /// Cancel switch of each running action, by run id
+    running: HashMap<String, watch::Sender<bool>>,
+    /// Ids of the most recently finished runs, oldest first
+    finished: VecDeque<String>,
+}
+
+pub type SharedRegistry = Arc<Mutex<ActionRegistry>>;
+
+/// Why a run stopped before its script exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stopped {
+    TimedOut,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelActionPayload {
+    pub run_id: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotCancelledReason {
+    /// No run, or build, with this id is known
+    NotFound,
+    /// The run, or build, finished before the cancel arrived
+    AlreadyFinished,
+}
+
+/// Answer to a `CancelAction` that had nothing to cancel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionNotCancelledPayload {
+    pub run_id: String,
+    pub reason: NotCancelledReason,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionLogPayload {
+    pub action_id: String,
+    pub run_id: String,
+    pub line: String,
+    pub stream: Stream,
+    /// When the line arrived, RFC 3339 with milliseconds
+    pub ts: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionResultPayload {
+    pub action_id: String,
+    pub run_id: String,
+    pub success: bool,
+    /// None if the script did not run or was killed by a signal
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    /// Set if the server killed the script
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stopped: Option<Stopped>,
+    /// The last lines of output, or why the script could not run
+    pub output: String,
+    /// What the script wrote to `$BUILDFORGE_OUTPUT`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub outputs: BTreeMap<String, String>,
+    /// Why the inputs were refused; the action did not run if any are given
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invalid_inputs: Vec<InvalidInput>,
+}
+
+/// One run of an action, as kept in the history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionRunRecord {
+    /// The run id
+    pub id: String,
+    pub action_id: String,
+    pub started_at: String,
+    /// None while the run is going, or if the server stopped during it
+    #[serde(default)]
+    pub finished_at: Option<String>,
+    pub success: bool,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stopped: Option<Stopped>,
+    /// The inputs as given, with the values of secret inputs as `***`
+    #[serde(default)]
+    pub inputs: BTreeMap<String, String>,
+    #[serde(default)]
+    pub output_tail: String,
+    /// The client that asked for the run, if it said who it is
+    #[serde(default)]
+    pub triggered_by: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionHistoryQuery {
+    /// Runs of every action if unset
+    #[serde(default)]
+    pub action_id: Option<String>,
+    #[serde(default = "crate::default_page_size")]
+    pub limit: usize,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionHistoryPage {
+    /// Newest first
+    pub records: Vec<ActionRunRecord>,
+    pub total: usize,
+}
+
+/// Answer to `ListActionVersions`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionVersionsPayload {
+    pub action_id: String,
+    /// The kept versions, newest first, each in full so they can be compared
+    pub versions: Vec<StoredAction>,
+}
+
+/// A `TestAction` request: an action as the editor has it, run with `inputs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestActionPayload {
+    pub action: StoredAction,
+    #[serde(default)]
+    pub inputs: HashMap<String, String>,
+    #[serde(default)]
+    pub expectations: TestExpectations,
+    /// Tags the run's `ActionLog` and `ActionTestResult`; one is made up if unset
+    #[serde(default)]
+    pub run_id: Option<String>,
+    /// Overrides the action's `timeout_secs`
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+/// What a test run should do; each one set becomes an assertion
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TestExpectations {
+    /// Whether the script should exit successfully
+    #[serde(default)]
+    pub success: Option<bool>,
+    /// Outputs the script should set, with their values
+    #[serde(default)]
+    pub outputs: BTreeMap<String, String>,
+    /// Regexes the script's stdout should match
+    #[serde(default)]
+    pub stdout_matches: Vec<String>,
+}
+
+/// One checked expectation of a test run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestAssertion {
+    /// What was checked, such as `success`, `output VERSION` or `stdout matches v\d+`
+    pub name: String,
+    pub expected: String,
+    /// What the run did; None for an output it did not set
+    #[serde(default)]
+    pub actual: Option<String>,
+    pub passed: bool,
+}
+
+/// The report of a `TestAction`. It passes if the script ran to its end and
+/// every assertion holds; with no expectations, if the script succeeded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionTestReport {
+    pub run_id: String,
+    pub passed: bool,
+    pub result: ActionResultPayload,
+    #[serde(default)]
+    pub assertions: Vec<TestAssertion>,
+}
+
+/// The stored action an action node ran, noted on its node run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsedAction {
+    pub id: String,
+    pub version: u64,
+}
+
+/// The `version` of an action node: a number, or `latest`, which is the default
+pub fn pinned_version(config: &serde_json::Value) -> Result<Option<u64>> {
+    match config.get("version") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(s)) if s == "latest" => Ok(None),
+        Some(version) => version
+            .as_u64()
+            .map(Some)
+            .context("version must be a version number or \"latest\""),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvalidInput {
+    pub name: String,
+    pub problem: String,
+}
+
+/// What an input holds, checked before the action runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputType {
+    #[default]
+    String,
+    Number,
+    /// `true` or `false`
+    Boolean,
+    /// One of the input's `choices`
+    Choice,
+}
+
+/// An entry of `StoredAction::inputs`, which also drives the app's form
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionInput {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default, rename = "type")]
+    pub input_type: InputType,
+    #[serde(default)]
+    pub required: bool,
+    /// Masked in the run history and in the output
+    #[serde(default)]
+    pub secret: bool,
+    #[serde(default)]
+    pub default: Option<String>,
+    /// The values a choice allows
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<String>,
+}
+
+impl ActionInput {
+    /// Why `value` does not fit the input, without repeating the value
+    fn check_value(&self, value: &str) -> Result<(), String> {
+        if value.contains('\0') {
+            return Err("The value contains a NUL character".to_string());
+        }
+        match self.input_type {
+            InputType::String => Ok(()),
+            InputType::Number if value.trim().parse::<f64>().is_ok_and(f64::is_finite) => Ok(()),
+            InputType::Number => Err("Must be a number".to_string()),
+            InputType::Boolean if matches!(value, "true" | "false") => Ok(()),
+            InputType::Boolean => Err("Must be true or false".to_string()),
+            InputType::Choice if self.choices.iter().any(|choice| choice == value) => Ok(()),
+            InputType::Choice => Err(format!("Must be one of {}", self.choices.join(", "))),
+        }
+    }
+}
+
+/// The inputs `action` declares
+fn declared_inputs(action: &StoredAction) -> Result<Vec<ActionInput>> {
+    action
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            serde_json::from_value(input.clone()).with_context(|| format!("Input {} is not a valid declaration", i + 1))
+        })
+        .collect()
+}
+
+/// The known interpreters installed on this server
+pub fn available_interpreters() -> Vec<String> {
+    INTERPRETERS
+        .iter()
+        .filter(|name| which::which(name).is_ok())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Checks the interpreter and input declarations of an action about to be saved
+pub fn validate(action: &StoredAction) -> Result<()> {
+    if let Some(interpreter) = &action.interpreter {
+        if interpreter != "auto" && !INTERPRETERS.contains(&interpreter.as_str()) && !Path::new(interpreter).is_absolute() {
+            anyhow::bail!(
+                "Unknown interpreter {}; use auto, {} or the full path of a program",
+                interpreter,
+                INTERPRETERS.join(", ")
+            );
+        }
+    }
+    for spec in &action.requires {
+        requirements::Requirement::parse(spec)?;
+    }
+    let inputs = declared_inputs(action)?;
+    for (i, input) in inputs.iter().enumerate() {
+        if !is_env_name(&input.name) {
+            anyhow::bail!("Input {:?} is not a valid environment variable name; use letters, digits and _", input.name);
+        }
+        if inputs[..i].iter().any(|other| other.name == input.name) {
+            anyhow::bail!("Input {} is declared twice", input.name);
+        }
+        if input.input_type == InputType::Choice && input.choices.is_empty() {
+            anyhow::bail!("Input {} is a choice but has no choices", input.name);
+        }
+        if let Some(default) = &input.default {
+            input
+                .check_value(default)
+                .map_err(|problem| anyhow::anyhow!("The default of input {} does not fit it: {}", input.name, problem))?;
+        }
+    }
+    Ok(())
+}
+
+/// Registers a run of `action` and runs it in its own task, returning the
+/// run's id. Fails if a run with the requested id is still going.
+pub async fn start(
+    ctx: &Arc<ServerContext>,
+    action: StoredAction,
+    request: RunActionPayload,
+    triggered_by: Option<String>,
+) -> Result<String> {
+    let run_id = request.run_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let cancel_rx = register(ctx, &run_id).await?;
+
+    let timeout = request.timeout_secs.or(action.timeout_secs).map(Duration::from_secs);
+    let ctx = ctx.clone();
+    let id = run_id.clone();
+    tokio::spawn(async move {
+        let secrets = secret_values(&action, &request.inputs);
+        let mut record = ActionRunRecord {
+            id: run_id.clone(),
+            action_id: action.id.clone(),
+            started_at: chrono::Utc::now().to_rfc3339(),
+            finished_at: None,
+            success: false,
+            exit_code: None,
+            stopped: None,
+            inputs: recorded_inputs(&action, &request.inputs),
+            output_tail: String::new(),
+            triggered_by,
+        };
+        if let Err(e) = ctx.history.upsert_action_run(record.clone()).await {
+            error!("Failed to record action run {}: {:#}", run_id, e);
+        }
+
+        let result = match action_env(&action, &request.inputs) {
+            Ok(env) => run(&ctx, &action, &run_id, &ctx.workdir, &env, &secrets, timeout, cancel_rx, None).await,
+            Err(invalid_inputs) => invalid_inputs_result(&action, &run_id, invalid_inputs),
+        };
+        unregister(&ctx, run_id).await;
+
+        record.finished_at = Some(chrono::Utc::now().to_rfc3339());
+        record.success = result.success;
+        record.exit_code = result.exit_code;
+        record.stopped = result.stopped;
+        record.output_tail = result.output.clone();
+        if let Err(e) = ctx.history.upsert_action_run(record).await {
+            error!("Failed to record action run {}: {:#}", result.run_id, e);
+        }
+
+        info!("Action {} run {} finished, success: {}", result.action_id, result.run_id, result.success);
+        ctx.broadcast(ServerMessage::ActionResult(result));
+    });
+    Ok(id)
+}
+
+/// Registers a test run of the action in `request` and runs it in its own
+/// task, in a scratch directory, returning the run's id. The report is
+/// broadcast as `ActionTestResult`. Fails if the action would not save, if
+/// an expected stdout pattern is not a valid regex, or if a run with the
+/// requested id is still going.
+pub async fn start_test(ctx: &Arc<ServerContext>, request: TestActionPayload) -> Result<String> {
+    validate(&request.action)?;
+    let patterns = request
+        .expectations
+        .stdout_matches
+        .iter()
+        .map(|pattern| Regex::new(pattern).with_context(|| format!("Invalid regex {}", pattern)))
+        .collect::<Result<Vec<_>>>()?;
+    let run_id = request.run_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let cancel_rx = register(ctx, &run_id).await?;
+
+    let timeout = request.timeout_secs.or(request.action.timeout_secs).map(Duration::from_secs);
+    let ctx = ctx.clone();
+    let id = run_id.clone();
+    tokio::spawn(async move {
+        let action = &request.action;
+        let secrets = secret_values(action, &request.inputs);
+        let stdout = std::sync::Mutex::new(String::new());
+        let scratch = process::TempDir::new("action-test");
+        let result = match action_env(action, &request.inputs) {
+            Ok(env) => match tokio::fs::create_dir(&scratch.0).await {
+                Ok(()) => run(&ctx, action, &run_id, &scratch.0, &env, &secrets, timeout, cancel_rx, Some(&stdout)).await,
+                Err(e) => {
+                    let mut result = invalid_inputs_result(action, &run_id, Vec::new());
+                    result.output = format!("Failed to create a scratch directory: {}", e);
+                    result
+                }
+            },
+            Err(invalid_inputs) => invalid_inputs_result(action, &run_id, invalid_inputs),
+        };
+        drop(scratch);
+        unregister(&ctx, run_id.clone()).await;
+
+        let assertions = check_expectations(&request.expectations, &patterns, &result, &stdout.into_inner().unwrap());
+        let passed = passed(&result, &assertions);
+        info!("Test run {} of action {} finished, passed: {}", run_id, action.id, passed);
+        ctx.broadcast(ServerMessage::ActionTestResult(ActionTestReport {
+            run_id,
+            passed,
+            result,
+            assertions,
+        }));
+    });
+    Ok(id)
+}
+
+/// Whether a test run passed: it ran to its end, and every assertion holds
+/// or, with none, it succeeded
+fn passed(result: &ActionResultPayload, assertions: &[TestAssertion]) -> bool {
+    result.exit_code.is_some()
+        && if assertions.is_empty() {
+            result.success
+        } else {
+            assertions.iter().all(|assertion| assertion.passed)
+        }
+}
+
+/// The assertions for what `expected` sets, checked against a finished run
+fn check_expectations(
+    expected: &TestExpectations,
+    patterns: &[Regex],
+    result: &ActionResultPayload,
+    stdout: &str,
+) -> Vec<TestAssertion> {
+    let mut assertions = Vec::new();
+    if let Some(success) = expected.success {
+        assertions.push(TestAssertion {
+            name: "success".to_string(),
+            expected: success.to_string(),
+            actual: Some(result.success.to_string()),
+            passed: result.success == success,
+        });
+    }
+    for (name, value) in &expected.outputs {
+        let actual = result.outputs.get(name).cloned();
+        assertions.push(TestAssertion {
+            name: format!("output {}", name),
+            expected: value.clone(),
+            passed: actual.as_ref() == Some(value),
+            actual,
+        });
+    }
+    for pattern in patterns {
+        let actual = pattern.find(stdout).map(|found| found.as_str().to_string());
+        assertions.push(TestAssertion {
+            name: format!("stdout matches {}", pattern.as_str()),
+            expected: pattern.as_str().to_string(),
+            passed: actual.is_some(),
+            actual,
+        });
+    }
+    assertions
+}
+
+/// Adds `run_id` to the running actions, returning what cancels it
+async fn register(ctx: &ServerContext, run_id: &str) -> Result<watch::Receiver<bool>> {
+    let (cancel_tx, cancel_rx) = watch::channel(false);
+    let mut registry = ctx.actions.lock().await;
+    if registry.running.contains_key(run_id) {
+        anyhow::bail!("Action run {} is already running", run_id);
+    }
+    registry.finished.retain(|id| id != run_id);
+    registry.running.insert(run_id.to_string(), cancel_tx);
+    Ok(cancel_rx)
+}
+
+/// Moves `run_id` from the running actions to the finished ones
+async fn unregister(ctx: &ServerContext, run_id: String) {
+    let mut registry = ctx.actions.lock().await;
+    registry.running.remove(&run_id);
+    if registry.finished.len() == FINISHED_RUNS {
+        registry.finished.pop_front();
+    }
+    registry.finished.push_back(run_id);
+}
+
+/// The result of a run that never started
+fn invalid_inputs_result(action: &StoredAction, run_id: &str, invalid_inputs: Vec<InvalidInput>) -> ActionResultPayload {
+    ActionResultPayload {
+        action_id: action.id.clone(),
+        run_id: run_id.to_string(),
+        success: false,
+        exit_code: None,
+        stopped: None,
+        output: "Invalid action inputs".to_string(),
+        outputs: BTreeMap::new(),
+        invalid_inputs,
+    }
+}
+
+/// Stops the run `run_id`, which then reports `Stopped::Cancelled`
+pub async fn cancel(ctx: &ServerContext, run_id: &str) -> Result<(), ActionNotCancelledPayload> {
+    let registry = ctx.actions.lock().await;
+    if let Some(cancel) = registry.running.get(run_id) {
+        let _ = cancel.send(true);
+        return Ok(());
+    }
+    let reason = if registry.finished.iter().any(|id| id == run_id) {
+        NotCancelledReason::AlreadyFinished
+    } else {
+        NotCancelledReason::NotFound
+    };
+    Err(ActionNotCancelledPayload {
+        run_id: run_id.to_string(),
+        reason,
+    })
+}
+
+/// Runs `action` in `workdir`, broadcasting its output. Its stdout is also
+/// collected in `stdout` when given, up to `TEST_STDOUT_BYTES`.
+#[allow(clippy::too_many_arguments)]
+async fn run(
+    ctx: &ServerContext,
+    action: &StoredAction,
+    run_id: &str,
+    workdir: &Path,
+    env: &[(String, String)],
+    secrets: &[String],
+    timeout: Option<Duration>,
+    mut cancel: watch::Receiver<bool>,
+    stdout: Option<&std::sync::Mutex<String>>,
+) -> ActionResultPayload {
+    let mut result = ActionResultPayload {
+        action_id: action.id.clone(),
+        run_id: run_id.to_string(),
+        success: false,
+        exit_code: None,
+        stopped: None,
+        output: String::new(),
+        outputs: BTreeMap::new(),
+        invalid_inputs: Vec::new(),
+    };
+    let Spawned { mut child, script: _script, outputs } = match spawn(action, workdir, env).await {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            error!("Action {} could not start: {:#}", action.id, e);
+            result.output = format!("{:#}", e);
+            return result;
+        }
+    };
+
+    let stdout_pipe = child.stdout.take();
+    let stderr = child.stderr.take();
+    let tail = std::sync::Mutex::new(VecDeque::with_capacity(RESULT_TAIL_LINES));
+    let run = Run {
+        ctx,
+        action_id: &action.id,
+        run_id,
+        secrets,
+        tail: &tail,
+        stdout,
+    };
+    // Killed with everything it started unless it exits by itself
+    let mut group = process::GroupGuard(child.id());
+    let finished = async {
+        let (status, _, _) = tokio::join!(
+            child.wait(),
+            run.forward(stdout_pipe, Stream::Stdout),
+            run.forward(stderr, Stream::Stderr),
+        );
+        status
+    };
+    let timed_out = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    let status = tokio::select! {
+        status = finished => {
+            group.0 = None;
+            Ok(status)
+        }
+        _ = timed_out => Err(Stopped::TimedOut),
+        _ = cancel.wait_for(|cancelled| *cancelled) => Err(Stopped::Cancelled),
+    };
+    drop(group);
+
+    result.output = Vec::from(tail.into_inner().unwrap()).join("\n");
+    match status {
+        Ok(Ok(status)) => {
+            result.success = status.success();
+            result.exit_code = status.code();
+            result.outputs = read_outputs(action, &outputs).await;
+        }
+        Ok(Err(e)) => result.output.push_str(&format!("\nFailed to wait for the script: {}", e)),
+        Err(stopped) => {
+            let reason = match stopped {
+                Stopped::TimedOut => format!("timed out after {} s", timeout.unwrap_or_default().as_secs()),
+                Stopped::Cancelled => "cancelled".to_string(),
+            };
+            warn!("Action {} run {} {}, killed it", action.id, run_id, reason);
+            result.output.push_str(&format!("\n[action {}, killed]", reason));
+            result.stopped = Some(stopped);
+        }
+    }
+    result
+}
+
+/// Runs `action` as a node of a build, its output going to the build log,
+/// and returns its exit code and outputs
+pub async fn run_in_build(
+    action: &StoredAction,
+    inputs: &HashMap<String, String>,
+    workdir: &Path,
+    build_env: &[(String, String)],
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<(i32, BTreeMap<String, String>)> {
+    let env = action_env(action, inputs).map_err(|invalid| {
+        let problems: Vec<String> = invalid.iter().map(|i| format!("{}: {}", i.name, i.problem)).collect();
+        anyhow::anyhow!("Invalid inputs for action {}: {}", action.name, problems.join("; "))
+    })?;
+    for secret in secret_values(action, inputs) {
+        log.add_secret(&secret);
+    }
+    // Inputs win over build variables of the same name
+    let env: Vec<(String, String)> = build_env.iter().cloned().chain(env).collect();
+    let Spawned { child, script: _script, outputs } = spawn(action, workdir, &env).await?;
+    let code = process::run(child, cancel, log).await?.check("Action")?;
+    Ok((code, read_outputs(action, &outputs).await))
+}
+
+/// Checks the inputs an action node gives, by name, before the build
+/// starts; their values may use build variables, so are checked when it runs
+pub fn check_input_names<'a>(action: &StoredAction, given: impl Iterator<Item = &'a str> + Clone) -> Result<()> {
+    let Ok(declared) = declared_inputs(action) else {
+        return Ok(());
+    };
+    if let Some(unknown) = given.clone().find(|name| !declared.iter().any(|input| input.name == *name)) {
+        anyhow::bail!("Action {} has no input {}", action.name, unknown);
+    }
+    if let Some(missing) = declared
+        .iter()
+        .find(|input| input.required && input.default.is_none() && !given.clone().any(|name| name == input.name))
+    {
+        anyhow::bail!("Action {} needs its input {}", action.name, missing.name);
+    }
+    Ok(())
+}
+
+/// A started script, with the files it reads and writes, which are removed
+/// when this is dropped
+struct Spawned {
+    child: tokio::process::Child,
+    script: process::TempFile,
+    outputs: process::TempFile,
+}
+
+/// Writes the script of `action` to a file and starts its interpreter on it
+async fn spawn(action: &StoredAction, workdir: &Path, env: &[(String, String)]) -> Result<Spawned> {
+    let interpreter = action.interpreter.as_deref().unwrap_or(DEFAULT_INTERPRETER);
+    let (program, args) = match interpreter {
+        "auto" => shebang(&action.script).unwrap_or_else(|| (DEFAULT_INTERPRETER.to_string(), Vec::new())),
+        _ => (interpreter.to_string(), Vec::new()),
+    };
+    if which::which(&program).is_err() {
+        anyhow::bail!("{} not found on server", program);
+    }
+    let shell = Shell::parse(&program);
+
+    // Named by a fresh id, as the run id comes from the client
+    let id = uuid::Uuid::new_v4();
+    let file = process::TempFile(workdir.join(format!(".buildforge-action-{}.{}", id, shell.script_extension())));
+    file.write_private(&shell.script_contents(&action.script))
+        .await
+        .context("Failed to write the action's script")?;
+    let outputs = process::TempFile(workdir.join(format!(".buildforge-action-{}.out", id)));
+    outputs.write_private("").await.context("Failed to create the action's output file")?;
+    let path = file.0.to_string_lossy();
+    let argv = if args.is_empty() {
+        shell.script_argv(&path)
+    } else {
+        std::iter::once(program.clone()).chain(args).chain([path.to_string()]).collect()
+    };
+
+    info!("Running action {} with {}", action.id, program);
+    let mut command = Command::new(&argv[0]);
+    command
+        .args(&argv[1..])
+        .envs(env.iter().cloned())
+        .env(OUTPUT_ENV, &outputs.0)
+        .current_dir(workdir);
+    let child = process::configure(&mut command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    Ok(Spawned {
+        child,
+        script: file,
+        outputs,
+    })
+}
+
+/// The `name=value` lines the script wrote to its output file. Only the
+/// outputs the action declares are kept, unless it declares none.
+async fn read_outputs(action: &StoredAction, file: &process::TempFile) -> BTreeMap<String, String> {
+    let declared: Vec<&str> = action.outputs.iter().filter_map(|o| o.get("name")?.as_str()).collect();
+    let content = tokio::fs::read_to_string(&file.0).await.unwrap_or_default();
+    let mut outputs = BTreeMap::new();
+    for line in content.lines() {
+        let Some((name, value)) = line.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if declared.is_empty() || declared.contains(&name) {
+            outputs.insert(name.to_string(), value.to_string());
+        } else {
+            warn!("Action {} set the undeclared output {}, ignoring it", action.id, name);
+        }
+    }
+    outputs
+}
+
+/// The program and arguments the `#!` line of `script` names, looking
+/// through `/usr/bin/env`
+fn shebang(script: &str) -> Option<(String, Vec<String>)> {
+    let line = script.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?;
+    if Path::new(program).file_name().is_some_and(|name| name == "env") {
+        // Skipping options such as -S
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+    Some((program.to_string(), words.map(str::to_string).collect()))
+}
+
+/// One run of an action while its output is forwarded
+struct Run<'a> {
+    ctx: &'a ServerContext,
+    action_id: &'a str,
+    run_id: &'a str,
+    /// Values of secret inputs, replaced by `***` in the output
+    secrets: &'a [String],
+    /// Last lines of stdout and stderr together, for `ActionResult`
+    tail: &'a std::sync::Mutex<VecDeque<String>>,
+    /// All of stdout without ANSI codes, up to `TEST_STDOUT_BYTES`, for test runs
+    stdout: Option<&'a std::sync::Mutex<String>>,
+}
+
+impl Run<'_> {
+    /// Broadcasts every line of `pipe` as `ActionLog`, held to the
+    /// settings' line length and ANSI mode like a build log
+    async fn forward<R: AsyncRead + Unpin>(&self, pipe: Option<R>, stream: Stream) {
+        let Some(pipe) = pipe else {
+            return;
+        };
+        let settings = self.ctx.settings();
+        let mut reader = BufReader::new(pipe);
+        while let Ok(Some(line)) = process::next_line(&mut reader, settings.max_log_line_bytes).await {
+            // Stripped before redacting, so a secret cannot hide behind a color
+            let stripped = self.redact(ansi::strip(&line));
+            let line = self.redact(line);
+            {
+                let mut tail = self.tail.lock().unwrap();
+                if tail.len() == RESULT_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(stripped.clone());
+            }
+            if let (Stream::Stdout, Some(stdout)) = (stream, self.stdout) {
+                let mut stdout = stdout.lock().unwrap();
+                if stdout.len() + stripped.len() < TEST_STDOUT_BYTES {
+                    stdout.push_str(&stripped);
+                    stdout.push('\n');
+                }
+            }
+            self.ctx.broadcast(ServerMessage::ActionLog(ActionLogPayload {
+                action_id: self.action_id.to_string(),
+                run_id: self.run_id.to_string(),
+                line: if settings.ansi_mode.strips_stream() { stripped } else { line },
+                stream,
+                ts: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            }));
+        }
+    }
+
+    fn redact(&self, mut line: String) -> String {
+        for secret in self.secrets {
+            line = line.replace(secret.as_str(), "***");
+        }
+        line
+    }
+}
+
+/// Values of the secret inputs of a run, given or defaulted
+fn secret_values(action: &StoredAction, given: &HashMap<String, String>) -> Vec<String> {
+    declared_inputs(action)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|input| input.secret)
+        .filter_map(|input| given.get(&input.name).cloned().or(input.default))
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// The inputs of a run as the history keeps them. Every value is masked if
+/// the action's declarations cannot be read, as any of them may be secret.
+fn recorded_inputs(action: &StoredAction, given: &HashMap<String, String>) -> BTreeMap<String, String> {
+    let declared = declared_inputs(action).ok();
+    given
+        .iter()
+        .map(|(name, value)| {
+            let secret = match &declared {
+                Some(inputs) => inputs.iter().any(|input| input.secret && input.name == *name),
+                None => true,
+            };
+            (name.clone(), if secret { "***".to_string() } else { value.clone() })
+        })
+        .collect()
+}
+
+/// The environment an action runs with: each declared input, given or
+/// defaulted. Inputs the action does not declare, names that cannot be
+/// environment variables, missing required inputs and values that do not
+/// fit their input are all reported. Actions whose declarations cannot be
+/// read, saved before they were checked, get what was given unchecked.
+fn action_env(action: &StoredAction, given: &HashMap<String, String>) -> Result<Vec<(String, String)>, Vec<InvalidInput>> {
+    let declared = match declared_inputs(action) {
+        Ok(declared) => declared,
+        Err(e) => {
+            warn!("Action {} has inputs the server cannot read, not checking them: {:#}", action.id, e);
+            return unchecked_env(given);
+        }
+    };
+
+    let mut invalid = Vec::new();
+    let mut unknown: Vec<&String> = given.keys().filter(|name| !declared.iter().any(|d| &d.name == *name)).collect();
+    unknown.sort();
+    for name in unknown {
+        invalid.push(InvalidInput {
+            name: name.clone(),
+            problem: "The action has no such input".to_string(),
+        });
+    }
+
+    let mut env = Vec::new();
+    for input in &declared {
+        if !is_env_name(&input.name) {
+            invalid.push(InvalidInput {
+                name: input.name.clone(),
+                problem: "Not a valid environment variable name; use letters, digits and _".to_string(),
+            });
+            continue;
+        }
+        match given.get(&input.name).or(input.default.as_ref()) {
+            Some(value) => match input.check_value(value) {
+                Ok(()) => env.push((input.name.clone(), value.clone())),
+                Err(problem) => invalid.push(InvalidInput {
+                    name: input.name.clone(),
+                    problem,
+                }),
+            },
+            None if input.required => invalid.push(InvalidInput {
+                name: input.name.clone(),
+                problem: "Required, but not given".to_string(),
+            }),
+            None => {}
+        }
+    }
+    if invalid.is_empty() {
+        Ok(env)
+    } else {
+        Err(invalid)
+    }
+}
+
+/// `given` as it is, as far as it can be put in an environment
+fn unchecked_env(given: &HashMap<String, String>) -> Result<Vec<(String, String)>, Vec<InvalidInput>> {
+    let mut invalid = Vec::new();
+    let mut env = Vec::new();
+    for (name, value) in given {
+        if !is_env_name(name) {
+            invalid.push(InvalidInput {
+                name: name.clone(),
+                problem: "Not a valid environment variable name; use letters, digits and _".to_string(),
+            });
+        } else if value.contains('\0') {
+            invalid.push(InvalidInput {
+                name: name.clone(),
+                problem: "The value contains a NUL character".to_string(),
+            });
+        } else {
+            env.push((name.clone(), value.clone()));
+        }
+    }
+    if invalid.is_empty() {
+        Ok(env)
+    } else {
+        invalid.sort_by(|a, b| a.name.cmp(&b.name));
+        Err(invalid)
+    }
+}
+
+/// A letter or `_`, then letters, digits and `_`
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRICKY: [&str; 3] = ["it's \"quoted\"", "one\ntwo\n", "$(reboot)"];
+
+    fn action(inputs: serde_json::Value) -> StoredAction {
+        serde_json::from_value(serde_json::json!({
+            "id": "action-1",
+            "name": "Echo",
+            "description": "",
+            "script": "printf '%s|%s|%s' \"$FIRST\" \"$SECOND\" \"$THIRD\"",
+            "inputs": inputs,
+            "outputs": [],
+            "interpreter": "sh",
+            "created_at": "",
+            "updated_at": "",
+        }))
+        .unwrap()
+    }
+
+    fn tricky_action() -> StoredAction {
+        action(serde_json::json!([{ "name": "FIRST" }, { "name": "SECOND" }, { "name": "THIRD" }]))
+    }
+
+    fn tricky_inputs() -> HashMap<String, String> {
+        ["FIRST", "SECOND", "THIRD"]
+            .into_iter()
+            .zip(TRICKY)
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn names(invalid: &[InvalidInput]) -> Vec<&str> {
+        invalid.iter().map(|i| i.name.as_str()).collect()
+    }
+
+    #[test]
+    fn values_are_passed_on_as_given() {
+        let env = action_env(&tricky_action(), &tricky_inputs()).unwrap();
+        let expected: Vec<(String, String)> = ["FIRST", "SECOND", "THIRD"]
+            .into_iter()
+            .zip(TRICKY)
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(env, expected);
+    }
+
+    #[test]
+    fn defaults_fill_in_and_required_inputs_are_checked() {
+        let action = action(serde_json::json!([
+            { "name": "LEVEL", "default": "$(reboot)" },
+            { "name": "TARGET", "required": true },
+        ]));
+        assert_eq!(names(&action_env(&action, &HashMap::new()).unwrap_err()), ["TARGET"]);
+
+        let given = HashMap::from([("TARGET".to_string(), "x".to_string())]);
+        let env = action_env(&action, &given).unwrap();
+        assert_eq!(env[0], ("LEVEL".to_string(), "$(reboot)".to_string()));
+    }
+
+    #[test]
+    fn undeclared_inputs_are_rejected() {
+        let mut given = tricky_inputs();
+        given.insert("PATH".to_string(), "/tmp".to_string());
+        assert_eq!(names(&action_env(&tricky_action(), &given).unwrap_err()), ["PATH"]);
+    }
+
+    #[test]
+    fn names_must_be_environment_variables() {
+        let action = action(serde_json::json!([{ "name": "A=B; reboot" }, { "name": "1ST" }]));
+        let invalid = action_env(&action, &HashMap::new()).unwrap_err();
+        assert_eq!(names(&invalid), ["A=B; reboot", "1ST"]);
+
+        let given = HashMap::from([("X Y".to_string(), "1".to_string())]);
+        assert_eq!(names(&unchecked_env(&given).unwrap_err()), ["X Y"]);
+    }
+
+    #[test]
+    fn values_must_not_hold_nul() {
+        let given = HashMap::from([("FIRST".to_string(), "a\0b".to_string())]);
+        let action = action(serde_json::json!([{ "name": "FIRST" }]));
+        assert_eq!(names(&action_env(&action, &given).unwrap_err()), ["FIRST"]);
+    }
+
+    fn input(declaration: serde_json::Value) -> ActionInput {
+        serde_json::from_value(declaration).unwrap()
+    }
+
+    fn validate_error(inputs: serde_json::Value) -> String {
+        validate(&action(inputs)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn numbers_and_booleans_are_checked() {
+        let number = input(serde_json::json!({ "name": "COUNT", "type": "number" }));
+        for value in ["3", "-2.5", " 1e3 "] {
+            assert_eq!(number.check_value(value), Ok(()), "{}", value);
+        }
+        for value in ["three", "", "NaN", "inf"] {
+            assert_eq!(number.check_value(value), Err("Must be a number".to_string()), "{}", value);
+        }
+
+        let boolean = input(serde_json::json!({ "name": "DRY_RUN", "type": "boolean" }));
+        assert_eq!(boolean.check_value("true"), Ok(()));
+        assert_eq!(boolean.check_value("false"), Ok(()));
+        assert_eq!(boolean.check_value("yes"), Err("Must be true or false".to_string()));
+        assert_eq!(boolean.check_value("True"), Err("Must be true or false".to_string()));
+    }
+
+    #[test]
+    fn a_choice_takes_only_its_choices() {
+        let choice = input(serde_json::json!({ "name": "MODE", "type": "choice", "choices": ["debug", "release"] }));
+        assert_eq!(choice.check_value("release"), Ok(()));
+        assert_eq!(choice.check_value("Release"), Err("Must be one of debug, release".to_string()));
+
+        let action = action(serde_json::json!([{ "name": "MODE", "type": "choice", "choices": ["debug", "release"] }]));
+        let given = HashMap::from([("MODE".to_string(), "profile".to_string())]);
+        let invalid = action_env(&action, &given).unwrap_err();
+        assert_eq!((invalid[0].name.as_str(), invalid[0].problem.as_str()), ("MODE", "Must be one of debug, release"));
+    }
+
+    #[test]
+    fn declarations_are_checked_when_saved() {
+        assert_eq!(
+            validate_error(serde_json::json!([{ "name": "TARGET" }, { "name": "TARGET" }])),
+            "Input TARGET is declared twice"
+        );
+        assert_eq!(
+            validate_error(serde_json::json!([{ "name": "MY-INPUT" }])),
+            "Input \"MY-INPUT\" is not a valid environment variable name; use letters, digits and _"
+        );
+        assert_eq!(
+            validate_error(serde_json::json!([{ "name": "MODE", "type": "choice" }])),
+            "Input MODE is a choice but has no choices"
+        );
+        assert_eq!(
+            validate_error(serde_json::json!([{ "name": "COUNT", "type": "number", "default": "many" }])),
+            "The default of input COUNT does not fit it: Must be a number"
+        );
+        assert!(validate(&action(serde_json::json!([{ "name": "COUNT", "type": "number", "default": "2" }]))).is_ok());
+    }
+
+    #[test]
+    fn legacy_inputs_that_cannot_be_read_are_not_checked() {
+        let legacy = action(serde_json::json!(["FIRST", { "name": "SECOND", "type": "number" }]));
+        assert_eq!(validate(&legacy).unwrap_err().to_string(), "Input 1 is not a valid declaration");
+
+        let given = HashMap::from([("SECOND".to_string(), "not a number".to_string())]);
+        let env = action_env(&legacy, &given).unwrap();
+        assert_eq!(env, [("SECOND".to_string(), "not a number".to_string())]);
+        assert!(check_input_names(&legacy, ["ANYTHING"].into_iter()).is_ok());
+    }
+
+    fn script(interpreter: &str, script: &str) -> StoredAction {
+        let mut action = action(serde_json::json!([]));
+        action.interpreter = Some(interpreter.to_string());
+        action.script = script.to_string();
+        action
+    }
+
+    /// Runs `action` in a scratch directory and returns what it printed
+    async fn stdout_of(action: &StoredAction) -> String {
+        let dir = process::TempDir::new("action-test");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let spawned = spawn(action, &dir.0, &[]).await.unwrap();
+        let output = spawned.child.wait_with_output().await.unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn the_shebang_names_the_interpreter_and_its_options() {
+        let python = "#!/usr/bin/env -S python3 -u\nprint('hi')";
+        assert_eq!(shebang(python), Some(("python3".to_string(), vec!["-u".to_string()])));
+        assert_eq!(shebang("#!/bin/sh -e\necho hi"), Some(("/bin/sh".to_string(), vec!["-e".to_string()])));
+        assert_eq!(shebang("echo hi\n#!/bin/sh"), None);
+        assert_eq!(shebang("#!/usr/bin/env -S"), None);
+    }
+
+    #[test]
+    fn unknown_interpreters_are_refused() {
+        assert_eq!(
+            validate(&script("ruby", "puts 1")).unwrap_err().to_string(),
+            "Unknown interpreter ruby; use auto, bash, sh, zsh, pwsh, python3, node or the full path of a program"
+        );
+        assert!(validate(&script("/opt/ruby/bin/ruby", "puts 1")).is_ok());
+        assert!(validate(&script("auto", "puts 1")).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn auto_without_a_shebang_runs_bash() {
+        let output = stdout_of(&script("auto", "echo \"bash ${BASH_VERSINFO[0]}\"")).await;
+        assert!(output.starts_with("bash ") && output.trim() != "bash", "{}", output);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn auto_runs_the_shebang_with_its_options() {
+        if which::which("python3").is_err() {
+            return;
+        }
+        let python = "#!/usr/bin/env -S python3 -u\nimport sys\nprint(sys.stdout.write_through)";
+        assert_eq!(stdout_of(&script("auto", python)).await, "True\n");
+    }
+
+    #[tokio::test]
+    async fn a_missing_interpreter_is_reported() {
+        let dir = process::TempDir::new("action-test");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let action = script("auto", "#!/usr/bin/env -S buildforge-missing-python3 -u\nprint('hi')");
+        let e = spawn(&action, &dir.0, &[]).await.err().unwrap();
+        assert_eq!(e.to_string(), "buildforge-missing-python3 not found on server");
+    }
+
+    /// The result of a run of `action()` that exited with `exit_code`
+    fn result(exit_code: Option<i32>, outputs: &[(&str, &str)]) -> ActionResultPayload {
+        let mut result = invalid_inputs_result(&action(serde_json::json!([])), "run-1", Vec::new());
+        result.success = exit_code == Some(0);
+        result.exit_code = exit_code;
+        result.outputs = outputs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        result
+    }
+
+    fn expect(expectations: serde_json::Value) -> (TestExpectations, Vec<Regex>) {
+        let expectations: TestExpectations = serde_json::from_value(expectations).unwrap();
+        let patterns = expectations.stdout_matches.iter().map(|p| Regex::new(p).unwrap()).collect();
+        (expectations, patterns)
+    }
+
+    /// Each assertion's name, what the run did and whether it passed
+    fn checked(assertions: &[TestAssertion]) -> Vec<(&str, Option<&str>, bool)> {
+        assertions.iter().map(|a| (a.name.as_str(), a.actual.as_deref(), a.passed)).collect()
+    }
+
+    #[test]
+    fn a_missing_output_fails_its_assertion() {
+        let (expected, patterns) = expect(serde_json::json!({ "outputs": { "VERSION": "1.2.0", "CHANNEL": "beta" } }));
+        let result = result(Some(0), &[("VERSION", "1.2.0")]);
+        let assertions = check_expectations(&expected, &patterns, &result, "");
+        assert_eq!(checked(&assertions), [("output CHANNEL", None, false), ("output VERSION", Some("1.2.0"), true)]);
+        assert!(!passed(&result, &assertions));
+    }
+
+    #[test]
+    fn stdout_must_match_every_pattern() {
+        let (expected, patterns) = expect(serde_json::json!({ "success": true, "stdout_matches": [r"v\d+\.\d+", "^done$"] }));
+        let result = result(Some(0), &[]);
+        let assertions = check_expectations(&expected, &patterns, &result, "built v1.2\ndone.\n");
+        assert_eq!(
+            checked(&assertions),
+            [
+                ("success", Some("true"), true),
+                (r"stdout matches v\d+\.\d+", Some("v1.2"), true),
+                ("stdout matches ^done$", None, false),
+            ]
+        );
+        assert!(!passed(&result, &assertions));
+        let assertions = check_expectations(&expected, &patterns[..1], &result, "built v1.2\n");
+        assert!(passed(&result, &assertions));
+    }
+
+    #[test]
+    fn without_expectations_the_exit_status_decides() {
+        let (expected, patterns) = expect(serde_json::json!({}));
+        for (exit_code, pass) in [(Some(0), true), (Some(1), false)] {
+            let result = result(exit_code, &[]);
+            let assertions = check_expectations(&expected, &patterns, &result, "");
+            assert!(assertions.is_empty());
+            assert_eq!(passed(&result, &assertions), pass);
+        }
+    }
+
+    #[test]
+    fn a_run_killed_by_its_timeout_never_passes() {
+        let mut timed_out = result(None, &[("VERSION", "1.2.0")]);
+        timed_out.stopped = Some(Stopped::TimedOut);
+        assert!(!passed(&timed_out, &[]));
+
+        // Even when every assertion holds, as a killed script did not succeed
+        let (expected, patterns) = expect(serde_json::json!({ "success": false, "outputs": { "VERSION": "1.2.0" } }));
+        let assertions = check_expectations(&expected, &patterns, &timed_out, "");
+        assert!(assertions.iter().all(|assertion| assertion.passed));
+        assert!(!passed(&timed_out, &assertions));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn the_script_sees_values_literally() {
+        let dir = process::TempDir::new("action-test");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let action = tricky_action();
+        let env = action_env(&action, &tricky_inputs()).unwrap();
+
+        let spawned = spawn(&action, &dir.0, &env).await.unwrap();
+        let output = spawned.child.wait_with_output().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), TRICKY.join("|"));
+    }
+}
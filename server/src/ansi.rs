@@ -0,0 +1,181 @@
+//! ANSI escape sequences and carriage-return progress bars in build output,
+//! as written by cargo, vite and most tools that think they are talking to a
+//! terminal.
+//!
+//! Stripping removes colors, cursor movement and hyperlinks, and reduces a
+//! line a progress bar rewrote with `\r` to what was last left on screen.
+
+use serde::{Deserialize, Serialize};
+
+/// What the build log does with escape sequences
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AnsiMode {
+    /// Strip them from the saved log and the live stream
+    Strip,
+    /// Pass them through, for clients that render them
+    Keep,
+    /// Strip them from the saved log but keep them in the live stream
+    #[default]
+    Auto,
+}
+
+impl AnsiMode {
+    pub fn strips_file(self) -> bool {
+        self != Self::Keep
+    }
+
+    pub fn strips_stream(self) -> bool {
+        self == Self::Strip
+    }
+}
+
+/// `line` as a terminal would end up showing it, without escape sequences
+pub fn strip(line: &str) -> String {
+    if !line.contains(['\x1b', '\r']) {
+        return line.to_string();
+    }
+    // Progress bars redraw over the same line, going back to its start with
+    // a carriage return or by moving the cursor to the first column, as
+    // Node's `cursorTo(0)` does; the last non-blank redraw is what was left
+    // on screen
+    line.replace("\x1b[1G", "\r")
+        .replace("\x1b[G", "\r")
+        .split('\r')
+        .rev()
+        .map(strip_escapes)
+        .find(|part| !part.trim().is_empty())
+        .unwrap_or_default()
+}
+
+fn strip_escapes(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI, such as colors and erasing the line: parameters, then a
+            // final byte between @ and ~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC, such as hyperlinks and window titles, ended by BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Character set selection takes one more character
+            Some('(' | ')' | '*' | '+') => {
+                chars.next();
+            }
+            // Anything else, such as ESC 7, is two characters long
+            _ => {}
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_colors_and_styles() {
+        assert_eq!(strip("\x1b[1m\x1b[32m   Compiling\x1b[0m app v0.1.0"), "   Compiling app v0.1.0");
+        assert_eq!(strip("\x1b[38;5;208mwarn\x1b[39m: \x1b[38;2;255;0;0mred\x1b[m"), "warn: red");
+    }
+
+    #[test]
+    fn strips_cursor_movement_and_erasing() {
+        assert_eq!(strip("\x1b[2K\x1b[1Gbuilding"), "building");
+        assert_eq!(strip("\x1b[?25lhidden cursor\x1b[?25h"), "hidden cursor");
+        assert_eq!(strip("\x1b7saved\x1b8 \x1b(Bascii"), "saved ascii");
+    }
+
+    #[test]
+    fn strips_hyperlinks() {
+        assert_eq!(strip("see \x1b]8;;https://example.com\x07docs\x1b]8;;\x1b\\ now"), "see docs now");
+    }
+
+    #[test]
+    fn keeps_what_a_progress_bar_left_on_screen() {
+        assert_eq!(strip("[1/3] fetch\r[2/3] build\r[3/3] done"), "[3/3] done");
+        assert_eq!(strip("Downloading 100%\r\x1b[2K\r"), "Downloading 100%");
+    }
+
+    #[test]
+    fn drops_a_sequence_cut_off_at_the_end_of_a_chunk() {
+        assert_eq!(strip("done\x1b"), "done");
+        assert_eq!(strip("done\x1b[3"), "done");
+        assert_eq!(strip("done\x1b[38;5;"), "done");
+        assert_eq!(strip("link \x1b]8;;https://exa"), "link ");
+        assert_eq!(strip("set \x1b("), "set ");
+    }
+
+    /// `cargo build --color=always` with an unused variable, as captured
+    const CARGO_BUILD: &[(&str, &str)] = &[
+        ("\x1b[1m\x1b[92m   Compiling\x1b[0m app v0.1.0 (/srv/app)", "   Compiling app v0.1.0 (/srv/app)"),
+        (
+            "\x1b[1m\x1b[33mwarning\x1b[0m\x1b[1m: unused variable: `unused`\x1b[0m",
+            "warning: unused variable: `unused`",
+        ),
+        (" \x1b[1m\x1b[94m--> \x1b[0msrc/main.rs:2:9", " --> src/main.rs:2:9"),
+        (
+            "\x1b[1m\x1b[94m2\x1b[0m \x1b[1m\x1b[94m|\x1b[0m     let unused = 1;",
+            "2 |     let unused = 1;",
+        ),
+        (
+            "  \x1b[1m\x1b[94m|\x1b[0m         \x1b[1m\x1b[33m^^^^^^\x1b[0m \x1b[1m\x1b[33mhelp: if this is intentional, prefix it with an underscore: `_unused`\x1b[0m",
+            "  |         ^^^^^^ help: if this is intentional, prefix it with an underscore: `_unused`",
+        ),
+        (
+            "\x1b[1m\x1b[92m    Finished\x1b[0m `dev` profile [unoptimized + debuginfo] target(s) in 0.36s",
+            "    Finished `dev` profile [unoptimized + debuginfo] target(s) in 0.36s",
+        ),
+    ];
+
+    /// `vite build` on a terminal: the module count redrawn with Node's
+    /// `clearLine(0)` and `cursorTo(0)`, then the summary, all on one line
+    const VITE_BUILD: &str = "\x1b[2K\x1b[1Gtransforming (1) \x1b[2msrc/main.ts\x1b[22m\
+        \x1b[2K\x1b[1Gtransforming (37) \x1b[2mnode_modules/react/index.js\x1b[22m\
+        \x1b[2K\x1b[1G\x1b[32m✓\x1b[39m 42 modules transformed.";
+
+    #[test]
+    fn strips_cargo_output() {
+        for (colored, plain) in CARGO_BUILD {
+            assert_eq!(strip(colored), *plain);
+        }
+    }
+
+    #[test]
+    fn keeps_the_last_redraw_of_vite_progress() {
+        assert_eq!(strip(VITE_BUILD), "✓ 42 modules transformed.");
+        assert_eq!(strip("\x1b[2K\x1b[Gtransforming (3) src/a.ts"), "transforming (3) src/a.ts");
+    }
+
+    #[test]
+    fn leaves_plain_text_alone() {
+        assert_eq!(strip("plain [text] with ~ and @"), "plain [text] with ~ and @");
+    }
+
+    #[test]
+    fn modes_say_where_to_strip() {
+        assert!(AnsiMode::Strip.strips_file() && AnsiMode::Strip.strips_stream());
+        assert!(AnsiMode::Auto.strips_file() && !AnsiMode::Auto.strips_stream());
+        assert!(!AnsiMode::Keep.strips_file() && !AnsiMode::Keep.strips_stream());
+    }
+}
@@ -0,0 +1,442 @@
+//! REST API for scripts and other CI systems, served on `--http-port` next
+//! to the WebSocket port:
+//!
+//! - `GET /api/workflows` and `GET /api/workflows/{id}`
+//! - `POST /api/builds`, which starts a build and returns its id
+//! - `GET /api/builds/{id}`: status, progress and the failed node
+//! - `GET /api/builds/{id}/logs`: the log as text, a page at a time with
+//!   `offset` and `limit`, or to the end of the build with `follow=true`
+//! - `POST /api/builds/{id}/cancel`
+//! - `GET /healthz`, which needs no token, see `health`
+//! - `GET /badge/{id}.svg`, see `badge`
+//!
+//! The handlers work on the same data, build registry and history as the
+//! WebSocket messages, so a build started here shows up in every client.
+//! With `--api-token` set, every request must carry it as
+//! `Authorization: Bearer <token>`. Errors are JSON such as
+//! `{"error": {"code": "not_found", "message": "Build not found: ..."}}`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn};
+
+use crate::builds::{self, status};
+use crate::{audit, badge, build_log, health, http, ServerContext};
+
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Time a request may take to arrive
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a followed log is checked for new lines
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What went wrong, for scripts to tell apart without parsing the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Unauthorized,
+    NotFound,
+    MethodNotAllowed,
+    BadRequest,
+    /// Headers or body over the size limit
+    TooLarge,
+    /// A body sent with a `Transfer-Encoding`
+    NotImplemented,
+    /// The server runs with `--read-only`
+    ReadOnly,
+    ShuttingDown,
+    /// A build of the workflow is running and its policy rejects another
+    AlreadyRunning,
+    Internal,
+}
+
+impl ErrorCode {
+    fn status(self) -> u16 {
+        match self {
+            Self::Unauthorized => 401,
+            Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::BadRequest => 400,
+            Self::TooLarge => 413,
+            Self::NotImplemented => 501,
+            Self::ReadOnly | Self::ShuttingDown => 503,
+            Self::AlreadyRunning => 409,
+            Self::Internal => 500,
+        }
+    }
+
+    /// Why `http::read_request` failed
+    fn of_request(e: &anyhow::Error) -> Self {
+        match e.downcast_ref::<http::Refused>() {
+            Some(http::Refused::Encoded) => Self::NotImplemented,
+            Some(_) => Self::TooLarge,
+            None => Self::BadRequest,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ApiError {
+    code: ErrorCode,
+    message: String,
+}
+
+fn fail(code: ErrorCode, message: impl Into<String>) -> ApiError {
+    ApiError {
+        code,
+        message: message.into(),
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        fail(ErrorCode::Internal, format!("{:#}", e.into()))
+    }
+}
+
+/// What a request is answered with
+enum Reply {
+    Json(u16, serde_json::Value),
+    Text(String),
+    Svg(String),
+    Follow { build_id: String, offset: usize },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct StartBuildRequest {
+    /// Id or name of the workflow
+    workflow_id: String,
+    /// The workflow's next version if unset
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default)]
+    variables: HashMap<String, String>,
+    #[serde(rename = "ref", default)]
+    git_ref: Option<String>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct BuildStarted {
+    build_id: String,
+    /// `running`, or `queued` behind another build of the workflow
+    status: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct BuildState {
+    build_id: String,
+    workflow_id: String,
+    /// A `builds::status` value, or `queued`
+    status: String,
+    /// Percent done, while running
+    progress: Option<u8>,
+    current_node: Option<String>,
+    /// Place in the workflow's queue, while queued
+    queue_position: Option<usize>,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    duration_ms: Option<u64>,
+    failed_node: Option<String>,
+    release_url: Option<String>,
+}
+
+/// Accepts API requests on `port` for as long as the server runs
+pub async fn serve(ctx: Arc<ServerContext>, port: u16) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind the HTTP API to {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("HTTP API listening on {}", addr);
+    if ctx.api_token.is_none() {
+        warn!("The HTTP API has no --api-token; anyone who can reach port {} can start builds", port);
+    }
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, peer, &ctx).await {
+                        warn!("HTTP API error for {}: {:#}", peer, e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept HTTP API connection: {}", e),
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, peer: SocketAddr, ctx: &Arc<ServerContext>) -> Result<()> {
+    let reply = match tokio::time::timeout(READ_TIMEOUT, http::read_request(&mut stream, MAX_BODY_BYTES)).await {
+        Ok(Ok(request)) => route(ctx, peer, &request).await,
+        Ok(Err(e)) => Err(fail(ErrorCode::of_request(&e), format!("{:#}", e))),
+        Err(_) => Err(fail(ErrorCode::BadRequest, "The request took too long")),
+    };
+    match reply {
+        Ok(Reply::Json(status, body)) => {
+            http::respond(&mut stream, status, "application/json", &serde_json::to_vec(&body)?).await
+        }
+        Ok(Reply::Text(text)) => http::respond(&mut stream, 200, "text/plain; charset=utf-8", text.as_bytes()).await,
+        Ok(Reply::Svg(svg)) => {
+            // Viewers must not keep showing an old status
+            let headers = [("Cache-Control", "no-cache")];
+            http::respond_with_headers(&mut stream, 200, "image/svg+xml; charset=utf-8", &headers, svg.as_bytes()).await
+        }
+        Ok(Reply::Follow { build_id, offset }) => follow(ctx, &mut stream, &build_id, offset).await,
+        Err(e) => {
+            let body = serde_json::json!({ "error": { "code": e.code, "message": e.message } });
+            http::respond(&mut stream, e.code.status(), "application/json", &serde_json::to_vec(&body)?).await
+        }
+    }
+}
+
+/// Compares digests rather than the tokens themselves, so the time taken
+/// says nothing about how much of the token was right
+fn token_matches(given: Option<&str>, token: &str) -> bool {
+    given.is_some_and(|given| Sha256::digest(given) == Sha256::digest(token))
+}
+
+async fn route(ctx: &Arc<ServerContext>, peer: SocketAddr, request: &http::Request) -> Result<Reply, ApiError> {
+    // Probes carry no token
+    if request.method == "GET" && request.path == health::PATH {
+        let (status, report) = health::check(ctx).await;
+        return Ok(Reply::Json(status, serde_json::to_value(report)?));
+    }
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let badge = match segments.as_slice() {
+        ["badge.svg"] => Some(""),
+        ["badge", file] => file.strip_suffix(".svg"),
+        _ => None,
+    };
+    // READMEs cannot send a token
+    let public = badge.is_some() && ctx.public_badges;
+    if let Some(token) = ctx.api_token.as_ref().filter(|_| !public) {
+        let given = request.header("authorization").and_then(|h| h.strip_prefix("Bearer "));
+        if !token_matches(given.map(str::trim), token) {
+            return Err(fail(ErrorCode::Unauthorized, "Missing or wrong bearer token"));
+        }
+    }
+    if let (Some(id), "GET") = (badge, request.method.as_str()) {
+        let name = request.query.get("name").map(String::as_str);
+        let label = request.query.get("label").map(String::as_str);
+        return Ok(Reply::Svg(badge::for_workflow(ctx, id, name, label).await));
+    }
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["api", "workflows"]) => {
+            let data = ctx.data.read().await;
+            Ok(Reply::Json(200, serde_json::to_value(&data.workflows)?))
+        }
+        ("GET", ["api", "workflows", id]) => {
+            let data = ctx.data.read().await;
+            let workflow = data.workflow(id).map_err(|e| fail(ErrorCode::NotFound, e.to_string()))?;
+            Ok(Reply::Json(200, serde_json::to_value(workflow)?))
+        }
+        ("POST", ["api", "builds"]) => start_build(ctx, peer, request).await,
+        ("GET", ["api", "builds", id]) => Ok(Reply::Json(200, serde_json::to_value(build_state(ctx, id).await?)?)),
+        ("GET", ["api", "builds", id, "logs"]) => logs(ctx, id, &request.query).await,
+        ("POST", ["api", "builds", id, "cancel"]) => {
+            refuse_changes(ctx)?;
+            let entry = audit::AuditEntry::new("BuildCancel", id, None, &format!("api {}", peer));
+            if let Err(e) = ctx.audit.record(entry).await {
+                warn!("Failed to write audit log: {}", e);
+            }
+            if !builds::cancel(ctx, id).await {
+                return Err(fail(ErrorCode::NotFound, format!("No running or queued build {}", id)));
+            }
+            Ok(Reply::Json(202, serde_json::json!({ "build_id": id })))
+        }
+        (_, ["api", "workflows"] | ["api", "workflows", _] | ["api", "builds"] | ["api", "builds", ..]) => {
+            Err(fail(ErrorCode::MethodNotAllowed, format!("{} is not allowed on {}", request.method, request.path)))
+        }
+        _ => Err(fail(ErrorCode::NotFound, format!("No such endpoint: {}", request.path))),
+    }
+}
+
+/// Fails if the server takes no new builds or changes now
+fn refuse_changes(ctx: &ServerContext) -> Result<(), ApiError> {
+    if ctx.read_only {
+        return Err(fail(ErrorCode::ReadOnly, "The server is read-only"));
+    }
+    if ctx.shutting_down.load(Ordering::Relaxed) {
+        return Err(fail(ErrorCode::ShuttingDown, "Server is shutting down"));
+    }
+    Ok(())
+}
+
+async fn start_build(ctx: &Arc<ServerContext>, peer: SocketAddr, request: &http::Request) -> Result<Reply, ApiError> {
+    refuse_changes(ctx)?;
+    let body: StartBuildRequest = serde_json::from_slice(&request.body)
+        .map_err(|e| fail(ErrorCode::BadRequest, format!("Invalid request body: {}", e)))?;
+    let workflow = ctx
+        .data
+        .read()
+        .await
+        .workflow(&body.workflow_id)
+        .map_err(|e| fail(ErrorCode::NotFound, e.to_string()))?
+        .clone();
+    let version = body.version.unwrap_or_else(|| workflow.next_version.clone());
+    let payload = builds::from_workflow(&workflow, version, body.git_ref, body.variables, body.dry_run)
+        .map_err(|e| fail(ErrorCode::BadRequest, format!("{:#}", e)))?;
+    let build_id = payload.build_id.clone();
+    info!("Starting build {} of {} v{} for API client {}", build_id, workflow.name, payload.version, peer);
+
+    let entry = audit::AuditEntry::new("BuildStart", &build_id, None, &format!("api {}", peer));
+    if let Err(e) = ctx.audit.record(entry).await {
+        warn!("Failed to write audit log: {}", e);
+    }
+    let status = match builds::submit(ctx, payload, ctx.github_token.clone(), None).await {
+        builds::Submission::Started => status::RUNNING,
+        builds::Submission::Queued => "queued",
+        builds::Submission::Rejected { running_build_id } => {
+            return Err(fail(
+                ErrorCode::AlreadyRunning,
+                format!("Build {} of this workflow is already running", running_build_id),
+            ));
+        }
+    };
+    let started = BuildStarted {
+        build_id,
+        status: status.to_string(),
+    };
+    Ok(Reply::Json(202, serde_json::to_value(started)?))
+}
+
+/// The live state of a running or queued build, or its history record
+async fn build_state(ctx: &ServerContext, build_id: &str) -> Result<BuildState, ApiError> {
+    let (running, queued) = builds::snapshot(ctx).await;
+    if let Some(build) = running.into_iter().find(|b| b.build_id == build_id) {
+        let failed_node = builds::node_runs(ctx, build_id)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .find(|run| run.status == status::FAILED)
+            .map(|run| run.name);
+        return Ok(BuildState {
+            build_id: build.build_id,
+            workflow_id: build.workflow_id,
+            status: status::RUNNING.to_string(),
+            progress: Some(build.progress),
+            current_node: build.current_node,
+            started_at: Some(build.started_at),
+            failed_node,
+            ..Default::default()
+        });
+    }
+    if let Some(build) = queued.into_iter().find(|b| b.build_id == build_id) {
+        return Ok(BuildState {
+            build_id: build.build_id,
+            workflow_id: build.workflow_id,
+            status: "queued".to_string(),
+            queue_position: Some(build.position),
+            ..Default::default()
+        });
+    }
+    let record = ctx
+        .history
+        .get(build_id)
+        .await?
+        .ok_or_else(|| fail(ErrorCode::NotFound, format!("Build not found: {}", build_id)))?;
+    Ok(BuildState {
+        build_id: record.id,
+        workflow_id: record.workflow_id,
+        status: record.status,
+        started_at: Some(record.started_at),
+        finished_at: record.finished_at,
+        duration_ms: record.duration_ms,
+        failed_node: record.failed_node,
+        release_url: record.release_url,
+        ..Default::default()
+    })
+}
+
+/// Where the full log of `build_id` is, or will be once it starts
+async fn log_path(ctx: &ServerContext, build_id: &str) -> Result<PathBuf, ApiError> {
+    let record = ctx.history.get(build_id).await?;
+    if record.is_none() && !builds::is_active(ctx, build_id).await {
+        return Err(fail(ErrorCode::NotFound, format!("Build not found: {}", build_id)));
+    }
+    Ok(record
+        .and_then(|r| r.log_file)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| build_log::default_path(&ctx.data_dir, build_id)))
+}
+
+async fn logs(ctx: &ServerContext, build_id: &str, query: &HashMap<String, String>) -> Result<Reply, ApiError> {
+    let number = |name: &str, default: usize| match query.get(name) {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| fail(ErrorCode::BadRequest, format!("{} must be a number", name))),
+        None => Ok(default),
+    };
+    let offset = number("offset", 0)?;
+    let limit = number("limit", crate::default_log_page_size())?;
+    let path = log_path(ctx, build_id).await?;
+    if query.get("follow").is_some_and(|f| f == "true" || f == "1") {
+        return Ok(Reply::Follow {
+            build_id: build_id.to_string(),
+            offset,
+        });
+    }
+    let (lines, _) = build_log::read_page(&path, offset, limit).await?;
+    let mut text = lines.join("\n");
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    Ok(Reply::Text(text))
+}
+
+/// Streams the log from line `offset` until the build has ended and every
+/// line is out, or the client goes away. A queued build is waited for.
+/// Only what was appended since the last check is read.
+async fn follow(ctx: &ServerContext, stream: &mut TcpStream, build_id: &str, offset: usize) -> Result<()> {
+    http::respond_streaming(stream, 200, "text/plain; charset=utf-8").await?;
+    let path = log_path(ctx, build_id).await.map_err(|e| anyhow::anyhow!(e.message))?;
+    let (mut position, mut skip) = build_log::seek_line(&path, offset).await?;
+    loop {
+        // Checked before reading, so the lines written just before the end are not missed
+        let running = builds::is_active(ctx, build_id).await;
+        let (lines, next) = build_log::read_from(&path, position, !running).await?;
+        position = next;
+        let skipped = skip.min(lines.len());
+        skip -= skipped;
+        for line in &lines[skipped..] {
+            stream.write_all(line.as_bytes()).await?;
+            stream.write_all(b"\n").await?;
+        }
+        stream.flush().await?;
+        if !running {
+            return Ok(());
+        }
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_exact_token_matches() {
+        assert!(token_matches(Some("s3cret"), "s3cret"));
+        assert!(!token_matches(Some("s3cre"), "s3cret"));
+        assert!(!token_matches(Some("s3cret!"), "s3cret"));
+        assert!(!token_matches(Some(""), "s3cret"));
+        assert!(!token_matches(None, "s3cret"));
+    }
+}
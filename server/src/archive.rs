@@ -0,0 +1,241 @@
+//! Packing build output into zip and tar archives, as done by the `archive`
+//! node, and unpacking tools fetched by the `fetch_tool` node. The cache
+//! nodes keep their directories as `tar.zst`.
+//!
+//! Tarballs keep symlinks as links and keep each file's mode, so executables
+//! still run after unpacking. Zips are mostly unpacked on Windows, so links
+//! to files are stored as the file they point to, and modes are reduced to
+//! 644, or 755 for executables and directories.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "zip" => Ok(Self::Zip),
+            "tar.gz" | "tgz" => Ok(Self::TarGz),
+            "tar.xz" | "txz" => Ok(Self::TarXz),
+            "tar.zst" | "tzst" => Ok(Self::TarZst),
+            other => anyhow::bail!("Unknown archive format {}, expected zip, tar.gz, tar.xz or tar.zst", other),
+        }
+    }
+
+    /// The format a file name's extension says it has
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        [Self::Zip, Self::TarGz, Self::TarXz, Self::TarZst]
+            .into_iter()
+            .find(|format| name.ends_with(&format!(".{}", format.extension())))
+            .or_else(|| name.ends_with(".tgz").then_some(Self::TarGz))
+            .or_else(|| name.ends_with(".txz").then_some(Self::TarXz))
+            .or_else(|| name.ends_with(".tzst").then_some(Self::TarZst))
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::TarGz => "tar.gz",
+            Self::TarXz => "tar.xz",
+            Self::TarZst => "tar.zst",
+        }
+    }
+}
+
+/// What to do when a job's inputs match nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IfEmpty {
+    Fail,
+    Skip,
+}
+
+impl IfEmpty {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            other => anyhow::bail!("Unknown if_empty {}, expected fail or skip", other),
+        }
+    }
+}
+
+pub struct Job {
+    /// Files and directories to pack, directories with everything in them
+    pub inputs: Vec<PathBuf>,
+    pub format: Format,
+    pub output: PathBuf,
+    /// Put the contents of an input directory at the root of the archive
+    /// instead of under the directory's own name
+    pub strip_leading_dir: bool,
+    /// Matched against paths inside the archive
+    pub exclude: Vec<glob::Pattern>,
+    /// Name entries by their path under this directory instead of under the
+    /// parent of their input
+    pub base: Option<PathBuf>,
+}
+
+/// A file, directory or link and its path inside the archive
+struct Entry {
+    path: PathBuf,
+    name: String,
+}
+
+/// Writes the archive of `job` and returns how many entries it holds. The
+/// archive is written next to its final path and renamed into place, so a
+/// failed job never leaves half an archive behind.
+pub async fn create(job: Job) -> Result<usize> {
+    tokio::task::spawn_blocking(move || {
+        let entries = entries(&job)?;
+        if let Some(dir) = job.output.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut partial = job.output.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let written = match job.format {
+            Format::Zip => write_zip(&partial, &entries),
+            Format::TarGz => {
+                let encoder = flate2::write::GzEncoder::new(File::create(&partial)?, flate2::Compression::default());
+                write_tar(encoder, &entries).and_then(|encoder| Ok(encoder.finish()?)).map(|_| ())
+            }
+            Format::TarXz => {
+                let encoder = xz2::write::XzEncoder::new(File::create(&partial)?, 6);
+                write_tar(encoder, &entries).and_then(|encoder| Ok(encoder.finish()?)).map(|_| ())
+            }
+            Format::TarZst => {
+                let encoder = zstd::stream::write::Encoder::new(File::create(&partial)?, 3)?;
+                write_tar(encoder, &entries).and_then(|encoder| Ok(encoder.finish()?)).map(|_| ())
+            }
+        };
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e.context(format!("Failed to write {}", job.output.display())));
+        }
+        std::fs::rename(&partial, &job.output)?;
+        Ok(entries.len())
+    })
+    .await?
+}
+
+/// Unpacks `archive` into `dir`. Entries that would land outside `dir` are
+/// refused.
+pub async fn extract(archive: &Path, format: Format, dir: &Path) -> Result<()> {
+    let archive = archive.to_path_buf();
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        std::fs::create_dir_all(&dir)?;
+        let file = File::open(&archive).with_context(|| format!("Failed to open {}", archive.display()))?;
+        match format {
+            Format::Zip => zip::ZipArchive::new(file)?.extract(&dir)?,
+            Format::TarGz => tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(&dir)?,
+            Format::TarXz => tar::Archive::new(xz2::read::XzDecoder::new(file)).unpack(&dir)?,
+            Format::TarZst => tar::Archive::new(zstd::stream::read::Decoder::new(file)?).unpack(&dir)?,
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Everything under the inputs of `job` that is not excluded, in a stable
+/// order
+fn entries(job: &Job) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for input in &job.inputs {
+        let metadata = std::fs::symlink_metadata(input).with_context(|| format!("Failed to read {}", input.display()))?;
+        let base = if let Some(base) = &job.base {
+            base.clone()
+        } else if metadata.is_dir() && job.strip_leading_dir {
+            input.clone()
+        } else {
+            input.parent().map(Path::to_path_buf).unwrap_or_default()
+        };
+        walk(input, &base, job, &mut entries)?;
+    }
+    Ok(entries)
+}
+
+fn walk(path: &Path, base: &Path, job: &Job, entries: &mut Vec<Entry>) -> Result<()> {
+    if path == job.output {
+        return Ok(());
+    }
+    let name = path
+        .strip_prefix(base)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    if !name.is_empty() {
+        if job.exclude.iter().any(|pattern| pattern.matches(&name)) {
+            return Ok(());
+        }
+        entries.push(Entry {
+            path: path.to_path_buf(),
+            name,
+        });
+    }
+
+    // Links to directories are kept as links, not followed
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        let mut children: Vec<PathBuf> = std::fs::read_dir(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<_>>()?;
+        children.sort();
+        for child in children {
+            walk(&child, base, job, entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_tar<W: Write>(writer: W, entries: &[Entry]) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    for entry in entries {
+        builder
+            .append_path_with_name(&entry.path, &entry.name)
+            .with_context(|| format!("Failed to add {}", entry.path.display()))?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+fn write_zip(output: &Path, entries: &[Entry]) -> Result<()> {
+    let mut zip = zip::ZipWriter::new(File::create(output)?);
+    let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for entry in entries {
+        // Follows links, Windows has no use for them
+        let metadata = std::fs::metadata(&entry.path).with_context(|| format!("Failed to read {}", entry.path.display()))?;
+        if metadata.is_dir() {
+            zip.add_directory(format!("{}/", entry.name), options.unix_permissions(0o755))?;
+            continue;
+        }
+        let mode = if is_executable(&metadata) { 0o755 } else { 0o644 };
+        zip.start_file(entry.name.as_str(), options.unix_permissions(mode))?;
+        let mut file = File::open(&entry.path).with_context(|| format!("Failed to open {}", entry.path.display()))?;
+        std::io::copy(&mut file, &mut zip)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
@@ -0,0 +1,266 @@
+//! Files produced by a build, as recorded in its history entry, and the
+//! listing clients get with `GetArtifacts`.
+//!
+//! `DownloadArtifact` sends one of them as binary WebSocket frames. Each
+//! frame starts with a 4-byte big-endian length, then a [`ChunkHeader`] as
+//! JSON of that length, then up to [`CHUNK_SIZE`] bytes of the file. A
+//! download can start at an offset to resume an interrupted one.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::ServerContext;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactInfo {
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the contents
+    pub sha256: String,
+    /// Target triple the artifact was built for, if a cargo_build node made it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Last modification of the file as the build left it, RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<String>,
+}
+
+impl ArtifactInfo {
+    /// Reads `path` to record its size, checksum and modification time
+    pub async fn describe(path: &Path) -> Result<Self> {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("Failed to open artifact {}", path.display()))?;
+
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            size += n as u64;
+        }
+
+        Ok(Self {
+            path: path.to_string_lossy().to_string(),
+            size,
+            sha256: hex::encode(hasher.finalize()),
+            target: None,
+            modified_at: modified_at(path).await,
+        })
+    }
+}
+
+/// When `path` was last modified, RFC 3339
+pub async fn modified_at(path: &Path) -> Option<String> {
+    let modified = tokio::fs::metadata(path).await.and_then(|m| m.modified()).ok()?;
+    Some(chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339())
+}
+
+/// Copies an artifact out of a build workspace to
+/// `data_dir/artifacts/<build_id>`, keeping its path relative to `root`
+pub async fn retain(data_dir: &Path, build_id: &str, root: &Path, path: &Path) -> Result<PathBuf> {
+    let relative = match path.strip_prefix(root) {
+        Ok(relative) => relative.to_path_buf(),
+        // Patterns may reach outside the workspace
+        Err(_) => PathBuf::from(path.file_name().unwrap_or_default()),
+    };
+    let target = data_dir.join("artifacts").join(build_id).join(relative);
+    if let Some(dir) = target.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::copy(path, &target)
+        .await
+        .with_context(|| format!("Failed to retain artifact {}", path.display()))?;
+    Ok(target)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetArtifactsQuery {
+    pub build_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildArtifactsPayload {
+    pub build_id: String,
+    /// Empty for a build that produced nothing, or is still running
+    pub artifacts: Vec<BuildArtifact>,
+}
+
+/// An artifact as a client lists it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildArtifact {
+    /// File name
+    pub name: String,
+    /// Relative to the directory the build ran in
+    pub relative_path: String,
+    /// Where the file is on the server
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<String>,
+}
+
+/// Artifacts recorded on a build of the history
+pub async fn list(ctx: &ServerContext, build_id: &str) -> Result<BuildArtifactsPayload> {
+    let record = ctx
+        .history
+        .get(build_id)
+        .await?
+        .with_context(|| format!("Build not found: {}", build_id))?;
+
+    // Retained copies first, as they are also under the data directory
+    let mut roots = vec![ctx.data_dir.join("artifacts").join(build_id)];
+    if let Ok(Some(repo)) = crate::resolve_repo(ctx, &record.workflow_id).await {
+        roots.push(PathBuf::from(repo.path));
+    }
+    roots.push(ctx.workdir.clone());
+
+    let artifacts = record
+        .artifacts
+        .into_iter()
+        .map(|artifact| {
+            let path = Path::new(&artifact.path);
+            let relative_path = roots
+                .iter()
+                .find_map(|root| path.strip_prefix(root).ok())
+                .unwrap_or_else(|| Path::new(path.file_name().unwrap_or_default()))
+                .to_string_lossy()
+                .replace('\\', "/");
+            BuildArtifact {
+                name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                relative_path,
+                path: artifact.path.clone(),
+                size: artifact.size,
+                sha256: artifact.sha256,
+                target: artifact.target,
+                modified_at: artifact.modified_at,
+            }
+        })
+        .collect();
+    Ok(BuildArtifactsPayload {
+        build_id: build_id.to_string(),
+        artifacts,
+    })
+}
+
+/// Bytes of the file in one download frame
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadArtifactRequest {
+    pub build_id: String,
+    /// `relative_path` or `path` of one of the build's artifacts
+    pub path: String,
+    /// Where to start, to resume an interrupted download
+    #[serde(default)]
+    pub offset: u64,
+}
+
+/// Sent before the first frame of a download
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactDownloadPayload {
+    pub build_id: String,
+    /// As the request named it
+    pub path: String,
+    pub offset: u64,
+    pub size: u64,
+    /// Of the whole file, as recorded on the build
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkHeader {
+    pub build_id: String,
+    pub path: String,
+    /// Of the first byte of this chunk in the file
+    pub offset: u64,
+    pub total: u64,
+    /// No frames follow for this download
+    pub last: bool,
+}
+
+/// A download in progress on one connection
+pub struct Download {
+    file: tokio::fs::File,
+    build_id: String,
+    path: String,
+    offset: u64,
+    total: u64,
+    done: bool,
+}
+
+impl Download {
+    /// Opens one of the build's artifacts. Only files recorded on the build
+    /// can be named, so a path cannot reach anything else on the server.
+    pub async fn open(ctx: &ServerContext, request: DownloadArtifactRequest) -> Result<(Self, ArtifactDownloadPayload)> {
+        let listing = list(ctx, &request.build_id).await?;
+        let artifact = listing
+            .artifacts
+            .into_iter()
+            .find(|a| a.relative_path == request.path || a.path == request.path)
+            .with_context(|| format!("{} is not an artifact of build {}", request.path, request.build_id))?;
+        let mut file = tokio::fs::File::open(&artifact.path)
+            .await
+            .with_context(|| format!("Failed to open artifact {}", artifact.path))?;
+        let total = file.metadata().await?.len();
+        if request.offset > total {
+            anyhow::bail!("Offset {} is past the end of {} ({} bytes)", request.offset, request.path, total);
+        }
+        file.seek(std::io::SeekFrom::Start(request.offset)).await?;
+
+        let payload = ArtifactDownloadPayload {
+            build_id: request.build_id.clone(),
+            path: request.path.clone(),
+            offset: request.offset,
+            size: total,
+            sha256: artifact.sha256,
+        };
+        let download = Self {
+            file,
+            build_id: request.build_id,
+            path: request.path,
+            offset: request.offset,
+            total,
+            done: false,
+        };
+        Ok((download, payload))
+    }
+
+    /// The next frame, or `None` once the last one was sent. A file that is
+    /// already complete at the offset still gets one empty, last frame.
+    pub async fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; CHUNK_SIZE.min((self.total - self.offset) as usize)];
+        self.file.read_exact(&mut data).await.with_context(|| format!("Failed to read {}", self.path))?;
+        let header = ChunkHeader {
+            build_id: self.build_id.clone(),
+            path: self.path.clone(),
+            offset: self.offset,
+            total: self.total,
+            last: self.offset + data.len() as u64 >= self.total,
+        };
+        self.offset += data.len() as u64;
+        self.done = header.last;
+
+        let header = serde_json::to_vec(&header)?;
+        let mut frame = Vec::with_capacity(4 + header.len() + data.len());
+        frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&data);
+        Ok(Some(frame))
+    }
+}
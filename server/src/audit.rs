@@ -0,0 +1,101 @@
+//! Append-only audit trail of mutating requests, stored as JSON lines in
+//! `data_dir/audit.log`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::ClientInfoPayload;
+
+/// Size at which `audit.log` is rotated to `audit.log.1`
+const MAX_LOG_BYTES: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: String,
+    pub action: String,
+    pub entity_id: String,
+    pub client: Option<ClientInfoPayload>,
+    pub peer: String,
+}
+
+impl AuditEntry {
+    pub fn new(
+        action: &str,
+        entity_id: &str,
+        client: Option<&ClientInfoPayload>,
+        peer: &str,
+    ) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            action: action.to_string(),
+            entity_id: entity_id.to_string(),
+            client: client.cloned(),
+            peer: peer.to_string(),
+        }
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    /// Serializes appends and rotation
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            path: data_dir.join("audit.log"),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn record(&self, entry: AuditEntry) -> Result<()> {
+        let _guard = self.lock.lock().await;
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        if let Ok(meta) = tokio::fs::metadata(&self.path).await {
+            if meta.len() >= MAX_LOG_BYTES {
+                tokio::fs::rename(&self.path, self.path.with_extension("log.1")).await?;
+            }
+        }
+
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Newest entries first, skipping `offset` of them. Also returns the total
+    /// number of entries in the current log file.
+    pub async fn read(&self, offset: usize, limit: usize) -> Result<(Vec<AuditEntry>, usize)> {
+        let _guard = self.lock.lock().await;
+
+        let content = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+            Err(e) => return Err(e.into()),
+        };
+
+        let lines: Vec<&str> = content.lines().filter(|l| !l.trim().is_empty()).collect();
+        let entries = lines
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        Ok((entries, lines.len()))
+    }
+}
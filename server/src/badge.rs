@@ -0,0 +1,180 @@
+//! Build status badges in the style of shields.io, for READMEs:
+//! `GET /badge/{workflow_id}.svg`, or `GET /badge.svg?name=<workflow name>`,
+//! on the REST API port.
+//!
+//! A badge shows the workflow's latest finished build, passing or failing,
+//! or running while a build of it runs. A workflow that never finished a
+//! build, or does not exist, gets a grey badge rather than an error, so an
+//! embedded image never breaks. `?label=` replaces the workflow name on the
+//! left.
+
+use crate::builds::{self, status};
+use crate::ServerContext;
+
+/// Verdana 11px, the font badges are drawn in
+const FONT_FAMILY: &str = "Verdana,Geneva,DejaVu Sans,sans-serif";
+
+/// Space left and right of each half's text
+const PADDING: f64 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadgeStatus {
+    Passing,
+    Failing,
+    Running,
+    Unknown,
+    NotFound,
+}
+
+impl BadgeStatus {
+    fn message(self) -> &'static str {
+        match self {
+            Self::Passing => "passing",
+            Self::Failing => "failing",
+            Self::Running => "running",
+            Self::Unknown => "unknown",
+            Self::NotFound => "not found",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Self::Passing => "#4c1",
+            Self::Failing => "#e05d44",
+            Self::Running => "#dfb317",
+            Self::Unknown | Self::NotFound => "#9f9f9f",
+        }
+    }
+}
+
+/// The badge of the workflow with `id`, or named `name` if given
+pub async fn for_workflow(ctx: &ServerContext, id: &str, name: Option<&str>, label: Option<&str>) -> String {
+    let workflow = {
+        let data = ctx.data.read().await;
+        data.workflows
+            .iter()
+            .find(|w| match name {
+                Some(name) => w.name == name,
+                None => w.id == id,
+            })
+            .map(|w| (w.id.clone(), w.name.clone()))
+    };
+    let Some((workflow_id, workflow_name)) = workflow else {
+        return render(label.unwrap_or("build"), BadgeStatus::NotFound);
+    };
+
+    let badge_status = if builds::workflow_running(ctx, &workflow_id).await {
+        BadgeStatus::Running
+    } else {
+        match ctx.history.last_finished(&workflow_id).await {
+            Ok(Some(record)) if record.status == status::SUCCESS => BadgeStatus::Passing,
+            Ok(Some(_)) => BadgeStatus::Failing,
+            Ok(None) => BadgeStatus::Unknown,
+            Err(e) => {
+                tracing::warn!("Failed to read the last build of {} for its badge: {}", workflow_id, e);
+                BadgeStatus::Unknown
+            }
+        }
+    };
+    render(label.unwrap_or(&workflow_name), badge_status)
+}
+
+/// A flat two-part badge, `label` on grey and the status on its color
+pub fn render(label: &str, badge_status: BadgeStatus) -> String {
+    let message = badge_status.message();
+    let label_width = (text_width(label) + 2.0 * PADDING).round();
+    let message_width = (text_width(message) + 2.0 * PADDING).round();
+    let width = label_width + message_width;
+    let label = escape(label);
+    format!(
+        concat!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}">"##,
+            r##"<title>{label}: {message}</title>"##,
+            r##"<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>"##,
+            r##"<clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath>"##,
+            r##"<g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/>"##,
+            r##"<rect x="{label_width}" width="{message_width}" height="20" fill="{color}"/>"##,
+            r##"<rect width="{width}" height="20" fill="url(#s)"/></g>"##,
+            r##"<g fill="#fff" text-anchor="middle" font-family="{font}" font-size="11">"##,
+            r##"<text x="{label_x}" y="15" fill="#010101" fill-opacity=".3">{label}</text><text x="{label_x}" y="14">{label}</text>"##,
+            r##"<text x="{message_x}" y="15" fill="#010101" fill-opacity=".3">{message}</text><text x="{message_x}" y="14">{message}</text>"##,
+            r##"</g></svg>"##,
+        ),
+        width = width,
+        label_width = label_width,
+        message_width = message_width,
+        label_x = label_width / 2.0,
+        message_x = label_width + message_width / 2.0,
+        label = label,
+        message = message,
+        color = badge_status.color(),
+        font = FONT_FAMILY,
+    )
+}
+
+/// Width of `text` in Verdana 11px, from the advance of each character
+fn text_width(text: &str) -> f64 {
+    text.chars().map(char_width).sum()
+}
+
+fn char_width(c: char) -> f64 {
+    match c {
+        ' ' | 'f' => 3.87,
+        'i' | 'l' => 3.02,
+        'j' => 3.79,
+        '\'' => 2.95,
+        '!' | 't' => 4.33,
+        'I' => 4.63,
+        'r' => 4.69,
+        ',' | '.' => 4.0,
+        '(' | ')' | '-' | '/' | ':' | ';' | '[' | '\\' | ']' | '|' => 4.99,
+        'J' => 5.0,
+        '"' => 5.05,
+        'c' | 's' => 5.73,
+        'z' => 5.78,
+        '?' => 6.0,
+        'L' => 6.12,
+        'F' => 6.32,
+        'k' | 'v' | 'x' | 'y' => 6.51,
+        'e' => 6.55,
+        'a' => 6.61,
+        'P' => 6.63,
+        'o' => 6.68,
+        'Y' => 6.77,
+        'T' => 6.78,
+        'b' | 'd' | 'g' | 'p' | 'q' => 6.85,
+        'E' | 'h' | 'n' | 'u' => 6.96,
+        '{' | '}' => 6.98,
+        '0'..='9' | '$' | '*' | '_' | '`' => 7.0,
+        'A' | 'S' | 'V' => 7.52,
+        'B' | 'X' | 'Z' => 7.54,
+        'K' => 7.62,
+        'R' => 7.65,
+        'C' => 7.68,
+        '&' => 7.99,
+        'U' => 8.05,
+        'N' => 8.23,
+        'H' => 8.27,
+        'D' => 8.48,
+        'G' => 8.53,
+        'O' | 'Q' => 8.66,
+        'w' => 8.98,
+        '#' | '+' | '<' | '=' | '>' | '^' | '~' => 9.0,
+        'M' => 9.27,
+        'm' => 10.7,
+        'W' => 10.87,
+        '@' => 11.0,
+        '%' => 11.84,
+        // Wide scripts take about two Latin letters
+        c if c as u32 >= 0x2E80 => 11.0,
+        _ => 7.0,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,530 @@
+//! Per-build log: every line is appended to `data_dir/logs/<build_id>.log`
+//! and broadcast to connected clients as `BuildLog`, tagged with the stream
+//! it came from and when it arrived. In the file that reads
+//! `2024-05-01T12:00:00.000Z [stderr] warning: ...`. Secret values
+//! registered with `add_secret` are replaced by `***` in both, and escape
+//! sequences are stripped from either or both as the `AnsiMode` says.
+//!
+//! What the processes of a node write goes through `output`, which holds it
+//! to the node's `OutputLimits` before it reaches either, so the file and the
+//! stream always agree.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::ansi::{self, AnsiMode};
+use crate::{BuildLogPayload, ServerContext, ServerMessage};
+
+/// Lines kept in memory for `BuildRecord::logs`
+const TAIL_LINES: usize = 200;
+
+/// How stale the file may get while a build runs, so `GetBuildLogs` sees recent output
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Where a line of the log came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+    /// The server's own account of the build
+    System,
+}
+
+impl Stream {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+            Self::System => "system",
+        }
+    }
+}
+
+/// Output a node may write unless it or the server settings say otherwise
+pub const DEFAULT_MAX_OUTPUT_BYTES: u64 = 10 * 1024 * 1024;
+/// Longer lines lose their middle unless the node or the settings say otherwise
+pub const DEFAULT_MAX_LINE_BYTES: usize = 16 * 1024;
+
+/// How much the processes of one node may write to the log
+#[derive(Debug, Clone, Copy)]
+pub struct OutputLimits {
+    /// Output past this many bytes is dropped; unlimited if unset
+    pub max_bytes: Option<u64>,
+    /// Lines longer than this are cut in the middle; unlimited if unset
+    pub max_line_bytes: Option<usize>,
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: Some(DEFAULT_MAX_OUTPUT_BYTES),
+            max_line_bytes: Some(DEFAULT_MAX_LINE_BYTES),
+        }
+    }
+}
+
+/// Output of the node that is running
+#[derive(Default)]
+struct NodeOutput {
+    limits: OutputLimits,
+    written: u64,
+    truncated: bool,
+}
+
+pub struct BuildLog {
+    build_id: String,
+    path: PathBuf,
+    file: Mutex<(BufWriter<File>, Instant)>,
+    tail: Mutex<VecDeque<String>>,
+    secrets: std::sync::Mutex<Vec<String>>,
+    output: std::sync::Mutex<NodeOutput>,
+    ansi_mode: AnsiMode,
+    ctx: Arc<ServerContext>,
+}
+
+impl BuildLog {
+    pub async fn create(ctx: &Arc<ServerContext>, build_id: &str, ansi_mode: AnsiMode) -> Result<Self> {
+        let path = default_path(&ctx.data_dir, build_id);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let file = File::create(&path).await?;
+
+        Ok(Self {
+            build_id: build_id.to_string(),
+            path,
+            file: Mutex::new((BufWriter::new(file), Instant::now())),
+            tail: Mutex::new(VecDeque::with_capacity(TAIL_LINES)),
+            secrets: std::sync::Mutex::new(Vec::new()),
+            output: std::sync::Mutex::new(NodeOutput::default()),
+            ansi_mode,
+            ctx: ctx.clone(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Hides `value` in every line recorded from now on
+    pub fn add_secret(&self, value: &str) {
+        if !value.is_empty() {
+            self.secrets.lock().unwrap().push(value.to_string());
+        }
+    }
+
+
+    /// Starts counting the output of a node afresh, held to `limits`
+    pub fn start_node(&self, limits: OutputLimits) {
+        *self.output.lock().unwrap() = NodeOutput {
+            limits,
+            ..NodeOutput::default()
+        };
+    }
+
+    /// Whether output of the current node has been dropped
+    pub fn output_truncated(&self) -> bool {
+        self.output.lock().unwrap().truncated
+    }
+
+    pub fn max_line_bytes(&self) -> Option<usize> {
+        self.output.lock().unwrap().limits.max_line_bytes
+    }
+
+    /// Records one line a process wrote, already cut to `max_line_bytes`
+    /// by `process`. Once the node has written its
+    /// `max_bytes`, a single marker is recorded and the rest is dropped.
+    pub async fn output(&self, stream: Stream, line: &str) {
+        let marker = {
+            let mut output = self.output.lock().unwrap();
+            if output.truncated {
+                return;
+            }
+            let size = line.len() as u64 + 1;
+            match output.limits.max_bytes {
+                Some(max) if output.written + size > max => {
+                    output.truncated = true;
+                    Some(format!("[output truncated after {} bytes, the rest of this node's output is dropped]", output.written))
+                }
+                _ => {
+                    output.written += size;
+                    None
+                }
+            }
+        };
+        match marker {
+            Some(marker) => self.record(Stream::System, &marker).await,
+            None => self.record(stream, line).await,
+        }
+    }
+
+    /// Records one line from the server itself
+    pub async fn line(&self, line: &str) {
+        self.record(Stream::System, line).await;
+    }
+
+    async fn record(&self, stream: Stream, line: &str) {
+        let (stripped, kept) = redact(line, &self.secrets.lock().unwrap(), self.ansi_mode);
+        let stored = if self.ansi_mode.strips_file() { &stripped } else { &kept };
+        let streamed = if self.ansi_mode.strips_stream() { &stripped } else { &kept };
+        let ts = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let tagged = format!("{} [{}] {}", ts, stream.as_str(), stored);
+        info!("[{}] {}", stream.as_str(), stripped);
+
+        // Lines are written in the order they arrive, stdout and stderr
+        // interleaved as the process wrote them, give or take buffering
+        let mut guard = self.file.lock().await;
+        let (file, last_flush) = &mut *guard;
+        let written = async {
+            file.write_all(tagged.as_bytes()).await?;
+            file.write_all(b"\n").await?;
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                file.flush().await?;
+                *last_flush = Instant::now();
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        if let Err(e) = written.await {
+            error!("Failed to write build log {}: {}", self.path.display(), e);
+        }
+        drop(guard);
+
+        let mut tail = self.tail.lock().await;
+        if tail.len() == TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(tagged);
+        drop(tail);
+
+        self.ctx.broadcast(ServerMessage::BuildLog(BuildLogPayload {
+            build_id: self.build_id.clone(),
+            log: streamed.clone(),
+            stream,
+            ts,
+        }));
+    }
+
+    /// The most recent lines as written to the file, oldest first
+    pub async fn tail(&self) -> Vec<String> {
+        self.tail.lock().await.iter().cloned().collect()
+    }
+
+    pub async fn flush(&self) {
+        if let Err(e) = self.file.lock().await.0.flush().await {
+            error!("Failed to flush build log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// `line` with `secrets` replaced by `***`, stripped of escape sequences and
+/// as `mode` keeps it. Secrets are looked for in the stripped text, so one
+/// split by a color is still found; a line that shows a secret is then kept
+/// stripped too, as the raw text may not give the secret away in one piece.
+fn redact(line: &str, secrets: &[String], mode: AnsiMode) -> (String, String) {
+    let hide = |text: &str| {
+        secrets
+            .iter()
+            .fold(text.to_string(), |text, secret| text.replace(secret.as_str(), "***"))
+    };
+    let plain = ansi::strip(line);
+    let stripped = hide(&plain);
+    let kept = if mode == AnsiMode::Strip || stripped != plain {
+        stripped.clone()
+    } else {
+        hide(line)
+    };
+    (stripped, kept)
+}
+
+/// Where the log of `build_id` lives when it is not recorded elsewhere
+pub fn default_path(data_dir: &Path, build_id: &str) -> PathBuf {
+    data_dir.join("logs").join(format!("{}.log", build_id))
+}
+
+/// Lines between the byte offsets remembered for each log
+const INDEX_STEP: usize = 1000;
+/// Logs whose line index is kept; past this the indexes start over
+const INDEXED_LOGS: usize = 256;
+
+/// Where every `INDEX_STEP`th line of a log starts, so a page is read by
+/// seeking close to it rather than from the top. Logs only ever grow, so each
+/// read scans just what was appended since the last one.
+#[derive(Debug, Default)]
+struct LineIndex {
+    /// Byte offsets of lines 0, `INDEX_STEP`, 2 * `INDEX_STEP`, ...
+    checkpoints: Vec<u64>,
+    /// Lines ending in a newline before `scanned`
+    lines: usize,
+    /// Bytes looked at so far, always just after a newline
+    scanned: u64,
+}
+
+static INDEXES: LazyLock<std::sync::Mutex<HashMap<PathBuf, LineIndex>>> = LazyLock::new(Default::default);
+
+impl LineIndex {
+    /// Takes in the lines appended since the last scan; `len` is the size
+    /// of the file
+    fn extend(&mut self, file: &mut std::fs::File, len: u64) -> std::io::Result<()> {
+        if len < self.scanned {
+            // Not the file that was indexed
+            *self = Self::default();
+        }
+        file.seek(SeekFrom::Start(self.scanned))?;
+        let mut reader = std::io::BufReader::new(file);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            if self.lines.is_multiple_of(INDEX_STEP) {
+                self.checkpoints.push(self.scanned);
+            }
+            self.lines += 1;
+            self.scanned += read as u64;
+        }
+        Ok(())
+    }
+
+    /// Lines in a file of `len` bytes, counting an unfinished last line
+    fn total(&self, len: u64) -> usize {
+        self.lines + usize::from(len > self.scanned)
+    }
+}
+
+/// Reads `limit` lines starting at `offset`, plus the total line count
+pub async fn read_page(path: &Path, offset: usize, limit: usize) -> Result<(Vec<String>, usize)> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || read_page_blocking(&path, offset, limit)).await?
+}
+
+fn read_page_blocking(path: &Path, offset: usize, limit: usize) -> Result<(Vec<String>, usize)> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let (start, total) = {
+        let mut indexes = INDEXES.lock().unwrap();
+        if indexes.len() >= INDEXED_LOGS && !indexes.contains_key(path) {
+            indexes.clear();
+        }
+        let index = indexes.entry(path.to_path_buf()).or_default();
+        index.extend(&mut file, len)?;
+        let checkpoint = (offset / INDEX_STEP).min(index.checkpoints.len().saturating_sub(1));
+        // Nothing is indexed before the first newline
+        let start = index.checkpoints.get(checkpoint).copied().unwrap_or(0);
+        ((start, checkpoint * INDEX_STEP), index.total(len))
+    };
+    if offset >= total {
+        return Ok((Vec::new(), total));
+    }
+    let (position, first) = start;
+
+    file.seek(SeekFrom::Start(position))?;
+    let page = std::io::BufReader::new(file)
+        .split(b'\n')
+        .skip(offset - first)
+        .take(limit)
+        .map(|line| {
+            let line = line?;
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            Ok(String::from_utf8_lossy(line).into_owned())
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    Ok((page, total))
+}
+
+/// Where line `line` starts, for `read_from`: the byte offset of the last
+/// finished line at or before it, and how many lines to skip from there
+/// when the log has not reached it yet
+pub async fn seek_line(path: &Path, line: usize) -> Result<(u64, usize)> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || seek_line_blocking(&path, line)).await?
+}
+
+fn seek_line_blocking(path: &Path, line: usize) -> Result<(u64, usize)> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, line)),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let (mut position, first) = {
+        let mut indexes = INDEXES.lock().unwrap();
+        if indexes.len() >= INDEXED_LOGS && !indexes.contains_key(path) {
+            indexes.clear();
+        }
+        let index = indexes.entry(path.to_path_buf()).or_default();
+        index.extend(&mut file, len)?;
+        if line >= index.lines {
+            return Ok((index.scanned, line - index.lines));
+        }
+        let checkpoint = line / INDEX_STEP;
+        (index.checkpoints[checkpoint], checkpoint * INDEX_STEP)
+    };
+    file.seek(SeekFrom::Start(position))?;
+    let mut reader = std::io::BufReader::new(file);
+    let mut skipped = Vec::new();
+    for _ in first..line {
+        skipped.clear();
+        position += reader.read_until(b'\n', &mut skipped)? as u64;
+    }
+    Ok((position, 0))
+}
+
+/// Reads the finished lines from byte `position` on, and the unfinished
+/// last one too once `ended`, with the position to carry on from
+pub async fn read_from(path: &Path, position: u64, ended: bool) -> Result<(Vec<String>, u64)> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || read_from_blocking(&path, position, ended)).await?
+}
+
+fn read_from_blocking(path: &Path, mut position: u64, ended: bool) -> Result<(Vec<String>, u64)> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), position)),
+        Err(e) => return Err(e.into()),
+    };
+    file.seek(SeekFrom::Start(position))?;
+    let mut reader = std::io::BufReader::new(file);
+    let mut lines = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        let finished = line.last() == Some(&b'\n');
+        if read == 0 || !(finished || ended) {
+            break;
+        }
+        position += read as u64;
+        let text = line.strip_suffix(b"\n").unwrap_or(&line);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        lines.push(String::from_utf8_lossy(text).into_owned());
+    }
+    Ok((lines, position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(name: &str, content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("buildforge-log-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("build.log");
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn secrets() -> Vec<String> {
+        vec!["hunter2".to_string()]
+    }
+
+    #[test]
+    fn a_secret_split_by_a_color_is_hidden_everywhere() {
+        let line = "token: hun\x1b[1mter2\x1b[0m";
+        for mode in [AnsiMode::Keep, AnsiMode::Auto, AnsiMode::Strip] {
+            let (stripped, kept) = redact(line, &secrets(), mode);
+            assert_eq!((stripped.as_str(), kept.as_str()), ("token: ***", "token: ***"), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn colors_are_kept_on_lines_without_secrets() {
+        let line = "\x1b[32mok\x1b[0m hunter";
+        let (stripped, kept) = redact(line, &secrets(), AnsiMode::Keep);
+        assert_eq!((stripped.as_str(), kept.as_str()), ("ok hunter", line));
+        let (_, kept) = redact("\x1b[31mhunter2\x1b[0m", &secrets(), AnsiMode::Keep);
+        assert_eq!(kept, "***");
+    }
+
+    #[tokio::test]
+    async fn pages_match_the_lines_of_the_file() {
+        let content: String = (0..2500).map(|i| format!("line {}\n", i)).collect();
+        let path = log("pages", &content);
+        let lines: Vec<&str> = content.lines().collect();
+        for (offset, limit) in [(0, 10), (995, 10), (1000, 1), (2499, 5), (1999, 1002), (2500, 1), (9000, 1)] {
+            let (page, total) = read_page(&path, offset, limit).await.unwrap();
+            let expected: Vec<&str> = lines.iter().skip(offset).take(limit).copied().collect();
+            assert_eq!(page, expected, "offset {} limit {}", offset, limit);
+            assert_eq!(total, 2500);
+        }
+    }
+
+    #[tokio::test]
+    async fn appended_lines_are_picked_up() {
+        let path = log("append", "a\r\nb\npart");
+        assert_eq!(read_page(&path, 0, 10).await.unwrap(), (vec!["a".into(), "b".into(), "part".into()], 3));
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, b"ial\nc\n").unwrap();
+        assert_eq!(read_page(&path, 2, 10).await.unwrap(), (vec!["partial".into(), "c".into()], 4));
+    }
+
+    #[tokio::test]
+    async fn an_unfinished_first_line_is_read() {
+        let path = log("unfinished", "no newline yet");
+        assert_eq!(read_page(&path, 0, 10).await.unwrap(), (vec!["no newline yet".into()], 1));
+    }
+
+    #[tokio::test]
+    async fn a_replaced_file_is_indexed_again() {
+        let path = log("replace", "one\ntwo\nthree\n");
+        assert_eq!(read_page(&path, 0, 10).await.unwrap().1, 3);
+        std::fs::write(&path, "x\n").unwrap();
+        assert_eq!(read_page(&path, 0, 10).await.unwrap(), (vec!["x".into()], 1));
+    }
+
+    #[tokio::test]
+    async fn a_missing_log_is_empty() {
+        let path = std::env::temp_dir().join("buildforge-log-missing.log");
+        assert_eq!(read_page(&path, 0, 10).await.unwrap(), (Vec::new(), 0));
+    }
+
+    #[tokio::test]
+    async fn read_from_takes_finished_lines_until_the_end() {
+        let path = log("read-from", "a\r\nb\npart");
+        let (lines, position) = read_from(&path, 0, false).await.unwrap();
+        assert_eq!((lines, position), (vec!["a".to_string(), "b".to_string()], 5));
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, b"ial\nlast").unwrap();
+        let (lines, position) = read_from(&path, position, false).await.unwrap();
+        assert_eq!((lines, position), (vec!["partial".to_string()], 13));
+        let (lines, position) = read_from(&path, position, true).await.unwrap();
+        assert_eq!((lines, position), (vec!["last".to_string()], 17));
+        assert_eq!(read_from(&path, position, true).await.unwrap(), (Vec::new(), 17));
+    }
+
+    #[tokio::test]
+    async fn seek_line_finds_where_a_line_starts() {
+        let content: String = (0..2500).map(|i| format!("line {}\n", i)).collect();
+        let path = log("seek", &content);
+        for line in [0, 1, 999, 1000, 1001, 2499] {
+            let (position, skip) = seek_line(&path, line).await.unwrap();
+            let (lines, _) = read_from(&path, position, true).await.unwrap();
+            assert_eq!((lines[0].clone(), skip), (format!("line {}", line), 0));
+        }
+        assert_eq!(seek_line(&path, 2503).await.unwrap(), (content.len() as u64, 3));
+    }
+
+    #[tokio::test]
+    async fn seek_line_waits_for_a_missing_log() {
+        let path = std::env::temp_dir().join("buildforge-log-missing-seek.log");
+        assert_eq!(seek_line(&path, 4).await.unwrap(), (0, 4));
+        assert_eq!(read_from(&path, 0, false).await.unwrap(), (Vec::new(), 0));
+    }
+}
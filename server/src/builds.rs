@@ -0,0 +1,941 @@
+//! Registry of running and queued builds.
+//!
+//! Builds of the same workflow share a working directory, so at most one of
+//! them runs at a time. What happens to a second request is decided by the
+//! workflow's [`ConcurrencyPolicy`].
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Mutex};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::build_log::BuildLog;
+use crate::{environment, requirements, retention};
+use crate::{
+    execute_build, BuildCompletePayload, BuildEdge, BuildNode, BuildProgressPayload, BuildQueuedPayload, BuildRecord,
+    BuildStartPayload, BuildStartedPayload, NodeEventPayload, NodeRun, RunningBuildInfo, ServerContext, ServerMessage,
+    StoredWorkflow,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConcurrencyPolicy {
+    /// Wait for the running build to finish, then start
+    #[default]
+    Queue,
+    /// Refuse the new build with `BuildAlreadyRunning`
+    Reject,
+    /// Cancel the running build and start the new one once it has stopped
+    CancelPrevious,
+}
+
+/// What happens to a build when the connection that started it closes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisconnectPolicy {
+    /// Keep building; the client can catch up from history later
+    #[default]
+    Continue,
+    /// Cancel the build, or drop it from the queue
+    Cancel,
+}
+
+/// Values of `BuildRecord::status` and `NodeRun::status`
+pub mod status {
+    pub const RUNNING: &str = "running";
+    pub const SUCCESS: &str = "success";
+    pub const FAILED: &str = "failed";
+    pub const CANCELLED: &str = "cancelled";
+    /// The server stopped while the build was running
+    pub const INTERRUPTED: &str = "interrupted";
+    /// Only for nodes that never ran
+    pub const SKIPPED: &str = "skipped";
+}
+
+/// When a node runs, set with its `run_on` setting. Nodes that run after a
+/// failure, such as a notification to an alerts channel, cannot fail the
+/// build a second time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RunOn {
+    /// Only while every node before it succeeded
+    #[default]
+    Success,
+    /// Only once the build has failed
+    Failure,
+    /// Either way, but not after the build was cancelled
+    Always,
+}
+
+impl RunOn {
+    pub fn of(node: &BuildNode) -> Result<Self> {
+        match node.config.get("run_on").and_then(|v| v.as_str()).unwrap_or("success") {
+            "success" => Ok(Self::Success),
+            "failure" => Ok(Self::Failure),
+            "always" => Ok(Self::Always),
+            other => anyhow::bail!("Unknown run_on {}, expected success, failure or always", other),
+        }
+    }
+
+    /// Whether the node runs, given whether the build has failed or was
+    /// cancelled so far
+    pub fn runs(self, failed: bool, cancelled: bool) -> bool {
+        match self {
+            _ if cancelled => false,
+            Self::Success => !failed,
+            Self::Failure => failed,
+            Self::Always => true,
+        }
+    }
+}
+
+/// Attached to the error of a build that failed in a node
+#[derive(Debug, thiserror::Error)]
+#[error("Node {node_id} failed")]
+pub struct NodeFailed {
+    pub node_id: String,
+}
+
+/// Error returned by build steps that stopped because the build was cancelled
+#[derive(Debug, thiserror::Error)]
+#[error("Build cancelled")]
+pub struct BuildCancelled;
+
+/// Handed to a running build so it can notice cancellation
+#[derive(Debug, Clone)]
+pub struct CancelToken(watch::Receiver<bool>);
+
+impl CancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the build has been cancelled
+    pub async fn cancelled(&self) {
+        let mut rx = self.0.clone();
+        if rx.wait_for(|cancelled| *cancelled).await.is_err() {
+            // The registry entry is gone without cancelling; never resolve
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+struct RunningBuild {
+    workflow_id: String,
+    /// Connection that submitted the build, if it came in over WebSocket
+    origin: Option<u64>,
+    on_disconnect: DisconnectPolicy,
+    project_name: String,
+    started_at: String,
+    current_node: Option<String>,
+    progress: u8,
+    started: Instant,
+    estimated_duration_ms: Option<u64>,
+    node_runs: Vec<NodeRun>,
+    commit_sha: Option<String>,
+    cancel: watch::Sender<bool>,
+}
+
+struct QueuedBuild {
+    payload: BuildStartPayload,
+    github_token: Option<String>,
+    origin: Option<u64>,
+    estimated_duration_ms: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct BuildRegistry {
+    running: HashMap<String, RunningBuild>,
+    queued: VecDeque<QueuedBuild>,
+}
+
+pub type SharedRegistry = Arc<Mutex<BuildRegistry>>;
+
+pub enum Submission {
+    Started,
+    Queued,
+    Rejected { running_build_id: String },
+}
+
+impl BuildRegistry {
+    /// Id of the build currently running for `workflow_id`, if any
+    fn running_for(&self, workflow_id: &str) -> Option<&str> {
+        if workflow_id.is_empty() {
+            return None;
+        }
+        self.running
+            .iter()
+            .find(|(_, b)| b.workflow_id == workflow_id)
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// Whether `limit` running builds are reached
+    fn at_capacity(&self, limit: Option<usize>) -> bool {
+        limit.is_some_and(|limit| self.running.len() >= limit)
+    }
+
+    /// Removes and returns the oldest queued build that can start now: there
+    /// is capacity left and nothing else of its workflow is running
+    fn take_startable(&mut self, limit: Option<usize>) -> Option<QueuedBuild> {
+        if self.at_capacity(limit) {
+            return None;
+        }
+        let index = self
+            .queued
+            .iter()
+            .position(|b| self.running_for(&b.payload.workflow_id).is_none())?;
+        self.queued.remove(index)
+    }
+
+    /// Current position and wait estimate of every build queued for `workflow_id`
+    fn queue_updates(&self, workflow_id: &str) -> Vec<BuildQueuedPayload> {
+        // Time left on the running build, if we can tell
+        let mut wait_ms = self
+            .running
+            .values()
+            .find(|b| b.workflow_id == workflow_id)
+            .and_then(|b| {
+                let elapsed = b.started.elapsed().as_millis() as u64;
+                b.estimated_duration_ms.map(|d| d.saturating_sub(elapsed))
+            });
+
+        self.queued
+            .iter()
+            .filter(|b| b.payload.workflow_id == workflow_id)
+            .enumerate()
+            .map(|(index, b)| {
+                let update = BuildQueuedPayload {
+                    build_id: b.payload.build_id.clone(),
+                    workflow_id: workflow_id.to_string(),
+                    position: index + 1,
+                    estimated_wait_ms: wait_ms,
+                    estimated_duration_ms: b.estimated_duration_ms,
+                };
+                wait_ms = wait_ms.zip(b.estimated_duration_ms).map(|(w, d)| w + d);
+                update
+            })
+            .collect()
+    }
+}
+
+/// Re-sends the queue position of every build waiting for `workflow_id`
+fn broadcast_queue(ctx: &ServerContext, registry: &BuildRegistry, workflow_id: &str) {
+    for update in registry.queue_updates(workflow_id) {
+        ctx.broadcast(ServerMessage::BuildQueued(update));
+    }
+}
+
+async fn estimate_duration(ctx: &ServerContext, workflow_id: &str) -> Option<u64> {
+    ctx.history
+        .estimate_duration(workflow_id)
+        .await
+        .map_err(|e| error!("Failed to estimate build duration: {:#}", e))
+        .ok()
+        .flatten()
+}
+
+/// Starts a build now, queues it, or rejects it according to the workflow's
+/// concurrency policy.
+pub async fn submit(
+    ctx: &Arc<ServerContext>,
+    payload: BuildStartPayload,
+    github_token: Option<String>,
+    origin: Option<u64>,
+) -> Submission {
+    let stored_policy = ctx
+        .data
+        .read()
+        .await
+        .workflows
+        .iter()
+        .find(|w| w.id == payload.workflow_id)
+        .map(|w| w.concurrency);
+    let policy = payload.concurrency.or(stored_policy).unwrap_or_default();
+    let estimated_duration_ms = estimate_duration(ctx, &payload.workflow_id).await;
+
+    let mut registry = ctx.builds.lock().await;
+    let build = QueuedBuild {
+        payload,
+        github_token,
+        origin,
+        estimated_duration_ms,
+    };
+
+    let Some(running_build_id) = registry
+        .running_for(&build.payload.workflow_id)
+        .map(str::to_string)
+    else {
+        if registry.at_capacity(ctx.settings().max_concurrent_builds) {
+            info!(
+                "Queued build {}: the limit of concurrent builds is reached",
+                build.payload.build_id
+            );
+            let workflow_id = build.payload.workflow_id.clone();
+            registry.queued.push_back(build);
+            broadcast_queue(ctx, &registry, &workflow_id);
+            return Submission::Queued;
+        }
+        start(ctx, &mut registry, build);
+        return Submission::Started;
+    };
+
+    match policy {
+        ConcurrencyPolicy::Queue => {
+            info!(
+                "Queued build {} behind {} for workflow {}",
+                build.payload.build_id, running_build_id, build.payload.workflow_id
+            );
+            let workflow_id = build.payload.workflow_id.clone();
+            registry.queued.push_back(build);
+            broadcast_queue(ctx, &registry, &workflow_id);
+            Submission::Queued
+        }
+        ConcurrencyPolicy::Reject => {
+            warn!(
+                "Rejected build {}: workflow {} is already building ({})",
+                build.payload.build_id, build.payload.workflow_id, running_build_id
+            );
+            Submission::Rejected { running_build_id }
+        }
+        ConcurrencyPolicy::CancelPrevious => {
+            let workflow_id = build.payload.workflow_id.clone();
+            info!(
+                "Cancelling build {} in favour of {} for workflow {}",
+                running_build_id, build.payload.build_id, workflow_id
+            );
+            if let Some(running) = registry.running.get(&running_build_id) {
+                let _ = running.cancel.send(true);
+            }
+            // Anything already waiting is superseded as well
+            registry.queued.retain(|b| b.payload.workflow_id != workflow_id);
+            registry.queued.push_front(build);
+            broadcast_queue(ctx, &registry, &workflow_id);
+            Submission::Queued
+        }
+    }
+}
+
+/// Records which node a running build is on and tells the clients
+pub async fn report_progress(ctx: &ServerContext, build_id: &str, node_id: &str, progress: u8) {
+    if let Some(build) = ctx.builds.lock().await.running.get_mut(build_id) {
+        build.current_node = Some(node_id.to_string());
+        build.progress = progress;
+    }
+    ctx.broadcast(ServerMessage::BuildProgress(BuildProgressPayload {
+        build_id: build_id.to_string(),
+        progress,
+        current_node: node_id.to_string(),
+    }));
+}
+
+/// Broadcasts NodeStart and records the node on its running build
+pub async fn node_started(ctx: &ServerContext, build_id: &str, node: &NodeRun) {
+    if let Some(build) = ctx.builds.lock().await.running.get_mut(build_id) {
+        build.node_runs.push(node.clone());
+    }
+    ctx.broadcast(ServerMessage::NodeStart(NodeEventPayload {
+        build_id: build_id.to_string(),
+        node: node.clone(),
+    }));
+}
+
+/// Broadcasts NodeComplete and stores the final state of the node, replacing
+/// the entry added by `node_started`
+pub async fn node_finished(ctx: &ServerContext, build_id: &str, node: NodeRun) {
+    if let Some(build) = ctx.builds.lock().await.running.get_mut(build_id) {
+        match build
+            .node_runs
+            .iter_mut()
+            .find(|r| r.node_id == node.node_id && r.attempt == node.attempt && !r.skipped)
+        {
+            Some(existing) => *existing = node.clone(),
+            None => build.node_runs.push(node.clone()),
+        }
+    }
+    ctx.broadcast(ServerMessage::NodeComplete(NodeEventPayload {
+        build_id: build_id.to_string(),
+        node,
+    }));
+}
+
+/// Node runs of a build that is still running
+pub async fn node_runs(ctx: &ServerContext, build_id: &str) -> Option<Vec<NodeRun>> {
+    ctx.builds
+        .lock()
+        .await
+        .running
+        .get(build_id)
+        .map(|b| b.node_runs.clone())
+}
+
+/// Brings history records of builds that are still running up to date: the
+/// stored record is only written when a build starts and when it ends
+pub async fn fill_live(ctx: &ServerContext, records: &mut [BuildRecord]) {
+    let registry = ctx.builds.lock().await;
+    for record in records.iter_mut().filter(|r| r.status == status::RUNNING) {
+        if let Some(build) = registry.running.get(&record.id) {
+            record.node_runs = build.node_runs.clone();
+            record.commit_sha = build.commit_sha.clone();
+            record.duration_ms = Some(build.started.elapsed().as_millis() as u64);
+        }
+    }
+}
+
+/// Records the commit a running build checked out
+pub async fn commit_checked_out(ctx: &ServerContext, build_id: &str, sha: &str) {
+    if let Some(build) = ctx.builds.lock().await.running.get_mut(build_id) {
+        build.commit_sha = Some(sha.to_string());
+    }
+}
+
+/// Live state of every running build and the current queue, oldest first
+pub async fn snapshot(ctx: &ServerContext) -> (Vec<RunningBuildInfo>, Vec<BuildQueuedPayload>) {
+    let registry = ctx.builds.lock().await;
+
+    let mut running: Vec<RunningBuildInfo> = registry
+        .running
+        .iter()
+        .map(|(id, b)| RunningBuildInfo {
+            build_id: id.clone(),
+            workflow_id: b.workflow_id.clone(),
+            project_name: b.project_name.clone(),
+            current_node: b.current_node.clone(),
+            progress: b.progress,
+            started_at: b.started_at.clone(),
+        })
+        .collect();
+    running.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+
+    let mut workflows: Vec<&str> = Vec::new();
+    for queued in &registry.queued {
+        if !workflows.contains(&queued.payload.workflow_id.as_str()) {
+            workflows.push(&queued.payload.workflow_id);
+        }
+    }
+    let queued = workflows
+        .into_iter()
+        .flat_map(|w| registry.queue_updates(w))
+        .collect();
+
+    (running, queued)
+}
+
+/// Applies each build's `on_disconnect` policy after its originating connection closed
+pub async fn connection_closed(ctx: &ServerContext, connection_id: u64) {
+    let mut registry = ctx.builds.lock().await;
+    for workflow_id in registry.disconnect(connection_id) {
+        broadcast_queue(ctx, &registry, &workflow_id);
+    }
+}
+
+impl BuildRegistry {
+    /// Cancels the running builds and drops the queued builds of
+    /// `connection_id` that asked for it, returning the workflows whose queue
+    /// changed
+    fn disconnect(&mut self, connection_id: u64) -> Vec<String> {
+        for (build_id, build) in &self.running {
+            if build.origin != Some(connection_id) {
+                continue;
+            }
+            match build.on_disconnect {
+                DisconnectPolicy::Cancel => {
+                    warn!("Client of build {} disconnected, cancelling", build_id);
+                    let _ = build.cancel.send(true);
+                }
+                DisconnectPolicy::Continue => {
+                    info!("Client of build {} disconnected, build continues", build_id);
+                }
+            }
+        }
+
+        let mut affected: Vec<String> = Vec::new();
+        self.queued.retain(|b| {
+            let abandoned = b.origin == Some(connection_id)
+                && b.payload.on_disconnect == DisconnectPolicy::Cancel;
+            if abandoned {
+                warn!("Client of queued build {} disconnected, dropping it", b.payload.build_id);
+                affected.push(b.payload.workflow_id.clone());
+            }
+            !abandoned
+        });
+        affected.sort();
+        affected.dedup();
+        affected
+    }
+}
+
+pub async fn is_running(ctx: &ServerContext, build_id: &str) -> bool {
+    ctx.builds.lock().await.running.contains_key(build_id)
+}
+
+/// How many builds are running and how many are queued
+pub async fn counts(ctx: &ServerContext) -> (usize, usize) {
+    let registry = ctx.builds.lock().await;
+    (registry.running.len(), registry.queued.len())
+}
+
+/// Whether `build_id` is running or waiting in the queue
+pub async fn is_active(ctx: &ServerContext, build_id: &str) -> bool {
+    let registry = ctx.builds.lock().await;
+    registry.running.contains_key(build_id) || registry.queued.iter().any(|b| b.payload.build_id == build_id)
+}
+
+/// Whether a build of `workflow_id` is running
+pub async fn workflow_running(ctx: &ServerContext, workflow_id: &str) -> bool {
+    ctx.builds.lock().await.running_for(workflow_id).is_some()
+}
+
+/// Drops every queued build and cancels every running one
+pub async fn cancel_all(ctx: &ServerContext) {
+    let mut registry = ctx.builds.lock().await;
+    registry.queued.clear();
+    for (build_id, build) in &registry.running {
+        warn!("Cancelling build {}", build_id);
+        let _ = build.cancel.send(true);
+    }
+}
+
+/// Drops every queued build so nothing new starts
+pub async fn clear_queue(ctx: &ServerContext) {
+    let mut registry = ctx.builds.lock().await;
+    if !registry.queued.is_empty() {
+        info!("Dropping {} queued builds", registry.queued.len());
+        registry.queued.clear();
+    }
+}
+
+/// Resolves once no build is running
+pub async fn wait_idle(ctx: &ServerContext) {
+    while !ctx.builds.lock().await.running.is_empty() {
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    }
+}
+
+/// Cancels a running or queued build. Returns false if the build is unknown.
+pub async fn cancel(ctx: &ServerContext, build_id: &str) -> bool {
+    let mut registry = ctx.builds.lock().await;
+    if let Some(running) = registry.running.get(build_id) {
+        let _ = running.cancel.send(true);
+        return true;
+    }
+    let Some(index) = registry
+        .queued
+        .iter()
+        .position(|b| b.payload.build_id == build_id)
+    else {
+        return false;
+    };
+    if let Some(removed) = registry.queued.remove(index) {
+        info!("Removed queued build {}", build_id);
+        broadcast_queue(ctx, &registry, &removed.payload.workflow_id);
+    }
+    true
+}
+
+/// Registers `build` as running and spawns it. The caller holds the registry
+/// lock, so checking for a running build and starting one is atomic.
+fn start(ctx: &Arc<ServerContext>, registry: &mut BuildRegistry, build: QueuedBuild) {
+    let QueuedBuild {
+        payload,
+        github_token,
+        origin,
+        estimated_duration_ms,
+    } = build;
+    let (cancel_tx, cancel_rx) = watch::channel(false);
+    registry.running.insert(
+        payload.build_id.clone(),
+        RunningBuild {
+            workflow_id: payload.workflow_id.clone(),
+            origin,
+            on_disconnect: payload.on_disconnect,
+            project_name: payload.project_name.clone(),
+            started_at: chrono::Utc::now().to_rfc3339(),
+            current_node: None,
+            progress: 0,
+            started: Instant::now(),
+            estimated_duration_ms,
+            node_runs: Vec::new(),
+            commit_sha: None,
+            cancel: cancel_tx,
+        },
+    );
+    ctx.broadcast(ServerMessage::BuildStarted(BuildStartedPayload {
+        build_id: payload.build_id.clone(),
+        workflow_id: payload.workflow_id.clone(),
+        estimated_duration_ms,
+    }));
+
+    let ctx = ctx.clone();
+    // Root span, so a queued build started from the previous one's task does not nest in it
+    let span = info_span!(
+        parent: None,
+        "build",
+        build_id = %payload.build_id,
+        workflow_id = %payload.workflow_id
+    );
+    tokio::spawn(
+        async move {
+            let build_id = payload.build_id.clone();
+            let workflow_id = payload.workflow_id.clone();
+
+            run_build(&ctx, payload, github_token, CancelToken(cancel_rx)).await;
+            retention::apply(&ctx).await;
+            let estimate = estimate_duration(&ctx, &workflow_id).await;
+
+            let mut registry = ctx.builds.lock().await;
+            registry.running.remove(&build_id);
+            for queued in registry.queued.iter_mut() {
+                if queued.payload.workflow_id == workflow_id {
+                    queued.estimated_duration_ms = estimate;
+                }
+            }
+            start_queued(&ctx, &mut registry);
+        }
+        .instrument(span),
+    );
+}
+
+/// Starts queued builds for as long as the concurrency rules allow
+fn start_queued(ctx: &Arc<ServerContext>, registry: &mut BuildRegistry) {
+    let limit = ctx.settings().max_concurrent_builds;
+    while let Some(next) = registry.take_startable(limit) {
+        info!("Starting queued build {}", next.payload.build_id);
+        let workflow_id = next.payload.workflow_id.clone();
+        start(ctx, registry, next);
+        broadcast_queue(ctx, registry, &workflow_id);
+    }
+}
+
+/// Starts whatever queued builds can run now, e.g. after the limit was raised
+pub async fn schedule(ctx: &Arc<ServerContext>) {
+    let mut registry = ctx.builds.lock().await;
+    start_queued(ctx, &mut registry);
+}
+
+/// Executes a build and keeps its history record up to date: it is written
+/// as running when the build starts and finalized when it ends.
+async fn run_build(
+    ctx: &Arc<ServerContext>,
+    payload: BuildStartPayload,
+    github_token: Option<String>,
+    cancel: CancelToken,
+) {
+    let started_at = chrono::Utc::now();
+    let mut record = BuildRecord {
+        id: payload.build_id.clone(),
+        workflow_id: payload.workflow_id.clone(),
+        status: status::RUNNING.to_string(),
+        started_at: started_at.to_rfc3339(),
+        finished_at: None,
+        duration_ms: None,
+        logs: vec![],
+        log_file: None,
+        failed_node: None,
+        artifacts: vec![],
+        release_url: None,
+        node_runs: vec![],
+        environment: HashMap::new(),
+        commit_sha: None,
+        project_name: payload.project_name.clone(),
+        version: payload.version.clone(),
+    };
+
+    let log = match BuildLog::create(ctx, &payload.build_id, payload.ansi_mode.unwrap_or(ctx.settings().ansi_mode)).await {
+        Ok(log) => Some(log),
+        Err(e) => {
+            error!("Failed to create build log: {:#}", e);
+            None
+        }
+    };
+    record.log_file = log.as_ref().map(|l| l.path().to_string_lossy().to_string());
+
+    let node_names: HashMap<String, String> =
+        payload.nodes.iter().map(|n| (n.id.clone(), n.name.clone())).collect();
+    record.environment = environment::snapshot(&payload.nodes).await;
+    // Required tools by the version their requirements were checked against
+    let requirements = crate::build_requirements(ctx, &payload).await;
+    if !requirements.is_empty() {
+        record.environment.extend(requirements::check(&requirements).await.versions);
+    }
+    if let Some(log) = &log {
+        let mut entries: Vec<_> = record.environment.iter().collect();
+        entries.sort();
+        for (name, version) in entries {
+            log.line(&format!("[environment] {}: {}", name, version)).await;
+        }
+    }
+    if let Err(e) = ctx.history.upsert(record.clone()).await {
+        error!("Failed to record build {} in history: {:#}", record.id, e);
+    }
+
+    let mut result = match &log {
+        Some(log) => {
+            let result = execute_build(ctx, payload, github_token, cancel, log).await;
+            log.flush().await;
+            result
+        }
+        None => Err(anyhow::anyhow!("Build log could not be created")),
+    };
+
+    record.status = match &result {
+        Ok(_) => status::SUCCESS,
+        Err(e) if e.is::<BuildCancelled>() => {
+            info!("Build cancelled");
+            status::CANCELLED
+        }
+        Err(e) => {
+            error!("Build failed: {:#}", e);
+            status::FAILED
+        }
+    }
+    .to_string();
+    record.failed_node = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<NodeFailed>())
+        .map(|f| f.node_id.clone());
+
+    if let Ok(outputs) = result.as_mut() {
+        record.artifacts = std::mem::take(&mut outputs.artifacts);
+        record.release_url = outputs.release_url.take();
+    }
+
+    if let Some(build) = ctx.builds.lock().await.running.get(&record.id) {
+        record.node_runs = build.node_runs.clone();
+        record.commit_sha = build.commit_sha.clone();
+    }
+
+    let finished_at = chrono::Utc::now();
+    let duration_ms = (finished_at - started_at).num_milliseconds().max(0) as u64;
+    record.finished_at = Some(finished_at.to_rfc3339());
+    record.duration_ms = Some(duration_ms);
+    record.logs = match &log {
+        Some(log) => log.tail().await,
+        None => vec![],
+    };
+
+    if let Err(e) = ctx.history.upsert(record.clone()).await {
+        error!("Failed to record build {} in history: {:#}", record.id, e);
+    }
+
+    let error = match &result {
+        Err(e) if !e.is::<BuildCancelled>() => Some(excerpt(&e.root_cause().to_string())),
+        _ => None,
+    };
+    // Matrix instances fail as `node@values`
+    let failed_node = record.failed_node.as_ref().map(|id| {
+        let node_id = id.split('@').next().unwrap_or(id);
+        node_names.get(node_id).cloned().unwrap_or_else(|| id.clone())
+    });
+    ctx.broadcast(ServerMessage::BuildComplete(BuildCompletePayload {
+        build_id: record.id,
+        status: record.status,
+        success: result.is_ok(),
+        duration: duration_ms / 1000,
+        artifacts: record.artifacts.iter().map(|a| a.path.clone()).collect(),
+        release_url: record.release_url,
+        project_name: record.project_name,
+        version: record.version,
+        failed_node,
+        error,
+    }));
+}
+
+/// The first non-empty line of `message`, shortened to fit a notification
+fn excerpt(message: &str) -> String {
+    const MAX_CHARS: usize = 160;
+    let line = message.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or_default();
+    if line.chars().count() <= MAX_CHARS {
+        return line.to_string();
+    }
+    let cut: String = line.chars().take(MAX_CHARS - 1).collect();
+    format!("{}…", cut.trim_end())
+}
+
+/// The build request a client would send for `workflow`, for builds started
+/// by the server itself. With `dry_run`, every node is set to `dry_run`.
+pub fn from_workflow(
+    workflow: &StoredWorkflow,
+    version: String,
+    git_ref: Option<String>,
+    env: HashMap<String, String>,
+    dry_run: bool,
+) -> Result<BuildStartPayload> {
+    let nodes = workflow
+        .nodes
+        .iter()
+        .map(|node| {
+            let id = node.get("id").and_then(|v| v.as_str()).context("A node of the workflow has no id")?;
+            let node_type = node
+                .get("type")
+                .and_then(|v| v.as_str())
+                .with_context(|| format!("Node {} has no type", id))?;
+            let mut config = node.get("config").cloned().unwrap_or_else(|| serde_json::json!({}));
+            if dry_run {
+                if let Some(config) = config.as_object_mut() {
+                    config.insert("dry_run".to_string(), serde_json::Value::Bool(true));
+                }
+            }
+            Ok(BuildNode {
+                id: id.to_string(),
+                node_type: node_type.to_string(),
+                name: node.get("name").and_then(|v| v.as_str()).unwrap_or(node_type).to_string(),
+                config,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // The app stores connections as from/to
+    let edges = workflow
+        .connections
+        .iter()
+        .map(|connection| {
+            let end = |key: &str, alias: &str| {
+                connection
+                    .get(key)
+                    .or_else(|| connection.get(alias))
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string)
+                    .context("A connection of the workflow does not name both of its nodes")
+            };
+            Ok(BuildEdge {
+                id: connection
+                    .get("id")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string)
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+                source: end("from", "source")?,
+                target: end("to", "target")?,
+                wait: connection
+                    .get("wait")
+                    .cloned()
+                    .map(serde_json::from_value)
+                    .transpose()
+                    .context("wait must be all or any")?
+                    .unwrap_or_default(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(BuildStartPayload {
+        build_id: uuid::Uuid::new_v4().to_string(),
+        workflow_id: workflow.id.clone(),
+        concurrency: None,
+        on_disconnect: DisconnectPolicy::default(),
+        git_ref,
+        commit: None,
+        force_clean: false,
+        project_name: workflow.name.clone(),
+        version,
+        nodes,
+        edges,
+        github_token: None,
+        env,
+        ansi_mode: None,
+        tag: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(build_id: &str, workflow_id: &str, on_disconnect: DisconnectPolicy) -> BuildStartPayload {
+        let mut payload = start_request(build_id, workflow_id);
+        payload["on_disconnect"] = serde_json::to_value(on_disconnect).unwrap();
+        serde_json::from_value(payload).unwrap()
+    }
+
+    /// A `BuildStart` payload as a client sends it
+    fn start_request(build_id: &str, workflow_id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "build_id": build_id,
+            "workflow_id": workflow_id,
+            "project_name": "app",
+            "version": "1.0.0",
+            "nodes": [],
+            "edges": [],
+            "github_token": null,
+        })
+    }
+
+    /// Registers a build as running mid-node and returns what it watches for
+    /// cancellation
+    fn run(registry: &mut BuildRegistry, build_id: &str, origin: u64, on_disconnect: DisconnectPolicy) -> CancelToken {
+        let (cancel, rx) = watch::channel(false);
+        registry.running.insert(
+            build_id.to_string(),
+            RunningBuild {
+                workflow_id: format!("wf-{}", build_id),
+                origin: Some(origin),
+                on_disconnect,
+                project_name: "app".to_string(),
+                started_at: String::new(),
+                current_node: Some("build".to_string()),
+                progress: 50,
+                started: Instant::now(),
+                estimated_duration_ms: None,
+                node_runs: Vec::new(),
+                commit_sha: None,
+                cancel,
+            },
+        );
+        CancelToken(rx)
+    }
+
+    fn queue(registry: &mut BuildRegistry, build_id: &str, workflow_id: &str, origin: u64, on_disconnect: DisconnectPolicy) {
+        registry.queued.push_back(QueuedBuild {
+            payload: payload(build_id, workflow_id, on_disconnect),
+            github_token: None,
+            origin: Some(origin),
+            estimated_duration_ms: None,
+        });
+    }
+
+    fn queued_ids(registry: &BuildRegistry) -> Vec<&str> {
+        registry.queued.iter().map(|b| b.payload.build_id.as_str()).collect()
+    }
+
+    #[test]
+    fn disconnect_cancels_running_builds_that_asked_for_it() {
+        let mut registry = BuildRegistry::default();
+        let cancelled = run(&mut registry, "a", 1, DisconnectPolicy::Cancel);
+        let continued = run(&mut registry, "b", 1, DisconnectPolicy::Continue);
+        registry.disconnect(1);
+        assert!(cancelled.is_cancelled());
+        assert!(!continued.is_cancelled());
+        // Cancelling is up to the build; it stays registered until it stops
+        assert_eq!(registry.running.len(), 2);
+    }
+
+    #[test]
+    fn disconnect_leaves_builds_of_other_connections_alone() {
+        let mut registry = BuildRegistry::default();
+        let other = run(&mut registry, "a", 2, DisconnectPolicy::Cancel);
+        queue(&mut registry, "q", "wf", 2, DisconnectPolicy::Cancel);
+        assert!(registry.disconnect(1).is_empty());
+        assert!(!other.is_cancelled());
+        assert_eq!(queued_ids(&registry), ["q"]);
+    }
+
+    #[test]
+    fn disconnect_drops_queued_builds_that_asked_for_it() {
+        let mut registry = BuildRegistry::default();
+        queue(&mut registry, "q1", "wf", 1, DisconnectPolicy::Cancel);
+        queue(&mut registry, "q2", "wf", 1, DisconnectPolicy::Continue);
+        queue(&mut registry, "q3", "other", 1, DisconnectPolicy::Cancel);
+        queue(&mut registry, "q4", "other", 1, DisconnectPolicy::Cancel);
+        assert_eq!(registry.disconnect(1), ["other", "wf"]);
+        assert_eq!(queued_ids(&registry), ["q2"]);
+    }
+
+    #[test]
+    fn on_disconnect_defaults_to_continue() {
+        let payload: BuildStartPayload = serde_json::from_value(start_request("a", "wf")).unwrap();
+        assert_eq!(payload.on_disconnect, DisconnectPolicy::Continue);
+        let parsed: DisconnectPolicy = serde_json::from_str("\"cancel\"").unwrap();
+        assert_eq!(parsed, DisconnectPolicy::Cancel);
+    }
+}
@@ -0,0 +1,213 @@
+//! Export and import of everything the server stores, for backups and for
+//! moving a server to another machine.
+//!
+//! A bundle is a single JSON document holding the server data, the build
+//! history and optionally the build logs. Imports are all-or-nothing: the
+//! bundle is fully validated and staged before anything is replaced.
+//!
+//! Single workflows are exported as YAML instead, see `workflow_file`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{build_log, builds, retention, schema, BuildRecord, ServerContext, ServerData};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataBundle {
+    /// Schema version of `data`, see `schema`
+    pub schema_version: u32,
+    pub exported_at: String,
+    /// Version of the server that wrote the bundle
+    pub server_version: String,
+    pub data: ServerData,
+    pub history: Vec<BuildRecord>,
+    /// Full build logs by build id, if they were included
+    #[serde(default)]
+    pub logs: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataImportResult {
+    pub merge: bool,
+    pub workflows: usize,
+    pub actions: usize,
+    pub repos: usize,
+    pub builds: usize,
+    /// Entries that already existed and were overwritten when merging, as
+    /// "workflow:<id>", "action:<id>" or "repo:<id>" (only if their contents
+    /// differed) and "build:<id>"
+    pub conflicts: Vec<String>,
+}
+
+pub async fn export(ctx: &ServerContext, include_logs: bool) -> Result<DataBundle> {
+    let data = ctx.data.read().await.clone();
+    let history = ctx.history.all().await?;
+
+    let mut logs = HashMap::new();
+    if include_logs {
+        for record in &history {
+            let Some(path) = &record.log_file else {
+                continue;
+            };
+            match tokio::fs::read(path).await {
+                Ok(content) => {
+                    logs.insert(record.id.clone(), String::from_utf8_lossy(&content).into_owned());
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("Failed to read build log {}", path)),
+            }
+        }
+    }
+
+    info!(
+        "Exported {} workflows, {} actions, {} repos, {} builds ({} logs)",
+        data.workflows.len(),
+        data.actions.len(),
+        data.repos.len(),
+        history.len(),
+        logs.len()
+    );
+    Ok(DataBundle {
+        schema_version: schema::CURRENT_VERSION,
+        exported_at: chrono::Utc::now().to_rfc3339(),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        data,
+        history,
+        logs,
+    })
+}
+
+/// Replaces (`merge == false`) or upserts by id (`merge == true`) the server
+/// data and history with the contents of `bundle`
+pub async fn import(ctx: &ServerContext, mut bundle: serde_json::Value, merge: bool) -> Result<DataImportResult> {
+    if !builds::snapshot(ctx).await.0.is_empty() {
+        anyhow::bail!("Cannot import data while builds are running");
+    }
+
+    // Validate and upgrade everything before touching any state
+    let version = schema::version_of(&bundle);
+    let data_value = bundle
+        .get_mut("data")
+        .context("Bundle has no data section")?;
+    data_value["schema_version"] = version.into();
+    let migrated = schema::upgrade(data_value, "Import bundle")?;
+    let incoming: ServerData = serde_json::from_value(data_value.take()).context("Invalid bundle data")?;
+    let mut history: Vec<BuildRecord> = match bundle.get_mut("history") {
+        Some(history) => serde_json::from_value(history.take()).context("Invalid bundle history")?,
+        None => Vec::new(),
+    };
+    history.extend(migrated.history);
+    let logs: HashMap<String, String> = match bundle.get_mut("logs") {
+        Some(logs) => serde_json::from_value(logs.take()).context("Invalid bundle logs")?,
+        None => HashMap::new(),
+    };
+
+    // Held until the end so no other change interleaves with the import
+    let mut data = ctx.data.write().await;
+    let mut conflicts = Vec::new();
+    let mut merged = if merge { data.clone() } else { ServerData::default() };
+    merged.schema_version = schema::CURRENT_VERSION;
+    upsert_all(&mut merged.workflows, incoming.workflows, |w| &w.id, "workflow", &mut conflicts)?;
+    upsert_all(&mut merged.actions, incoming.actions, |a| &a.id, "action", &mut conflicts)?;
+    upsert_all(&mut merged.repos, incoming.repos, |r| &r.id, "repo", &mut conflicts)?;
+
+    // Logs are staged next to their final location and only moved into place
+    // once the history has been committed
+    let mut staged: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for record in &mut history {
+        record.log_file = None;
+        let Some(content) = logs.get(&record.id) else {
+            continue;
+        };
+        let path = build_log::default_path(&ctx.data_dir, &record.id);
+        let temp = path.with_extension("log.import");
+        let written = async {
+            if let Some(dir) = temp.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(&temp, content).await
+        };
+        if let Err(e) = written.await {
+            discard(&staged).await;
+            return Err(e).with_context(|| format!("Failed to write {}", temp.display()));
+        }
+        record.log_file = Some(path.to_string_lossy().to_string());
+        staged.push((temp, path));
+    }
+
+    let imported_builds = history.len();
+    let replaced = if merge { Vec::new() } else { ctx.history.all().await? };
+    let existing = match ctx.history.import_bundle(history, !merge).await {
+        Ok(existing) => existing,
+        Err(e) => {
+            discard(&staged).await;
+            return Err(e.context("Failed to import build history"));
+        }
+    };
+    if merge {
+        conflicts.extend(existing.into_iter().map(|id| format!("build:{}", id)));
+    }
+
+    for record in replaced.iter().filter(|r| r.status != builds::status::RUNNING) {
+        retention::remove_build_files(ctx, record).await;
+    }
+    for (temp, path) in &staged {
+        if let Err(e) = tokio::fs::rename(temp, path).await {
+            warn!("Failed to move imported log to {}: {}", path.display(), e);
+        }
+    }
+
+    let result = DataImportResult {
+        merge,
+        workflows: merged.workflows.len(),
+        actions: merged.actions.len(),
+        repos: merged.repos.len(),
+        builds: imported_builds,
+        conflicts,
+    };
+    *data = merged;
+    ctx.persistence.mark_dirty();
+
+    info!(
+        "Imported {} workflows, {} actions, {} repos, {} builds ({} conflicts, merge: {})",
+        result.workflows,
+        result.actions,
+        result.repos,
+        result.builds,
+        result.conflicts.len(),
+        merge
+    );
+    Ok(result)
+}
+
+/// Upserts `incoming` into `items` by id, noting ids whose contents differed
+fn upsert_all<T: Serialize>(
+    items: &mut Vec<T>,
+    incoming: Vec<T>,
+    id: impl Fn(&T) -> &String,
+    kind: &str,
+    conflicts: &mut Vec<String>,
+) -> Result<()> {
+    for item in incoming {
+        match items.iter_mut().find(|existing| id(existing) == id(&item)) {
+            Some(existing) => {
+                if serde_json::to_value(&*existing)? != serde_json::to_value(&item)? {
+                    conflicts.push(format!("{}:{}", kind, id(&item)));
+                }
+                *existing = item;
+            }
+            None => items.push(item),
+        }
+    }
+    Ok(())
+}
+
+async fn discard(staged: &[(PathBuf, PathBuf)]) {
+    for (temp, _) in staged {
+        let _ = tokio::fs::remove_file(temp).await;
+    }
+}
@@ -0,0 +1,236 @@
+//! Directories kept between builds, such as `node_modules` or `target`,
+//! restored by the `cache_restore` node and stored by the `cache_save` node.
+//!
+//! Each entry is `data_dir/cache/<key>.tar.zst`, with paths relative to the
+//! build directory. Keys usually end in `$HASH(<glob>)`, so a changed lock
+//! file starts a new entry. Entries are evicted by age and by total size,
+//! least recently used first, after every save.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::archive;
+
+const EXTENSION: &str = ".tar.zst";
+/// Age at which an unfinished save is taken to be abandoned
+const ABANDONED_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub fn dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("cache")
+}
+
+/// `key` with anything but letters, digits, `.`, `_` and `-` replaced, so it
+/// can be a file name
+pub fn sanitize_key(key: &str) -> Result<String> {
+    let key: String = key
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '-' })
+        .collect();
+    if key.is_empty() || key.chars().all(|c| c == '.') {
+        anyhow::bail!("The cache key is empty");
+    }
+    Ok(key)
+}
+
+/// Replaces every `$HASH(<glob>)` in `text` with a short hash of the files
+/// the glob matches under `workdir`, or with `none` if it matches nothing
+pub fn expand_hashes(text: &str, workdir: &Path) -> String {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("$HASH(") {
+        let Some(end) = rest[start..].find(')') else {
+            break;
+        };
+        let pattern = &rest[start + "$HASH(".len()..start + end];
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&hash_files(workdir, pattern.trim()));
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn hash_files(workdir: &Path, pattern: &str) -> String {
+    let Ok(paths) = glob::glob(&workdir.join(pattern).to_string_lossy()) else {
+        return "none".to_string();
+    };
+    let mut paths: Vec<PathBuf> = paths.flatten().filter(|p| p.is_file()).collect();
+    if paths.is_empty() {
+        return "none".to_string();
+    }
+    paths.sort();
+    let mut hasher = Sha256::new();
+    for path in &paths {
+        // The name too, so moving a lock file changes the key
+        hasher.update(path.strip_prefix(workdir).unwrap_or(path).to_string_lossy().as_bytes());
+        hasher.update([0]);
+        match std::fs::read(path) {
+            Ok(contents) => hasher.update(&contents),
+            Err(_) => hasher.update(b"unreadable"),
+        }
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())[..16].to_string()
+}
+
+/// A cache entry and its size in bytes
+pub struct Entry {
+    pub key: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// The entry for `key`, or else the most recently used one whose key starts
+/// with one of `restore_keys`, tried in order
+pub async fn find(cache_dir: &Path, key: &str, restore_keys: &[String]) -> Result<Option<Entry>> {
+    let exact = cache_dir.join(format!("{}{}", key, EXTENSION));
+    if let Ok(metadata) = tokio::fs::metadata(&exact).await {
+        return Ok(Some(Entry {
+            key: key.to_string(),
+            path: exact,
+            size: metadata.len(),
+        }));
+    }
+    let entries = list(cache_dir).await?;
+    for prefix in restore_keys {
+        let found = entries
+            .iter()
+            .filter(|(entry, _)| entry.key.starts_with(prefix.as_str()))
+            .max_by_key(|(_, used)| *used);
+        if let Some((entry, _)) = found {
+            return Ok(Some(Entry {
+                key: entry.key.clone(),
+                path: entry.path.clone(),
+                size: entry.size,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Unpacks `entry` into `workdir` and marks it as just used
+pub async fn restore(entry: &Entry, workdir: &Path) -> Result<()> {
+    archive::extract(&entry.path, archive::Format::TarZst, workdir)
+        .await
+        .with_context(|| format!("Failed to restore the cache {}", entry.key))?;
+    touch(&entry.path).await;
+    Ok(())
+}
+
+/// Packs `paths` of `workdir` as the entry for `key` and returns its size
+pub async fn save(cache_dir: &Path, key: &str, workdir: &Path, paths: Vec<PathBuf>) -> Result<u64> {
+    tokio::fs::create_dir_all(cache_dir).await?;
+    let output = cache_dir.join(format!("{}{}", key, EXTENSION));
+    // Another build may be saving the same key; the last one to finish wins
+    let staging = cache_dir.join(format!("{}.tmp", uuid::Uuid::new_v4()));
+    let job = archive::Job {
+        inputs: paths,
+        format: archive::Format::TarZst,
+        output: staging.clone(),
+        strip_leading_dir: false,
+        exclude: Vec::new(),
+        base: Some(workdir.to_path_buf()),
+    };
+    if let Err(e) = archive::create(job).await {
+        let _ = tokio::fs::remove_file(&staging).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&staging, &output).await?;
+    Ok(tokio::fs::metadata(&output).await?.len())
+}
+
+/// Removes entries unused for longer than `max_age` and then, least recently
+/// used first, as many as it takes to bring the cache under `max_size`
+/// bytes. Returns the keys removed.
+pub async fn evict(cache_dir: &Path, max_size: Option<u64>, max_age: Option<Duration>) -> Result<Vec<String>> {
+    remove_abandoned(cache_dir).await;
+    let mut entries = list(cache_dir).await?;
+    entries.sort_by_key(|(_, used)| *used);
+    let now = SystemTime::now();
+    let mut total: u64 = entries.iter().map(|(entry, _)| entry.size).sum();
+    let mut removed = Vec::new();
+    for (entry, used) in entries {
+        let too_old = max_age.is_some_and(|age| now.duration_since(used).unwrap_or_default() > age);
+        let too_big = max_size.is_some_and(|size| total > size);
+        if !too_old && !too_big {
+            continue;
+        }
+        tokio::fs::remove_file(&entry.path).await?;
+        total -= entry.size;
+        removed.push(entry.key);
+    }
+    Ok(removed)
+}
+
+/// Removes what saves that never finished, say because the server stopped,
+/// left behind
+async fn remove_abandoned(cache_dir: &Path) {
+    let Ok(mut dir) = tokio::fs::read_dir(cache_dir).await else {
+        return;
+    };
+    while let Ok(Some(item)) = dir.next_entry().await {
+        // `<uuid>.tmp`, or the `.tmp.partial` it is written as
+        if !item.file_name().to_string_lossy().contains(".tmp") {
+            continue;
+        }
+        let abandoned = item
+            .metadata()
+            .await
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified.elapsed().unwrap_or_default() > ABANDONED_AFTER);
+        if abandoned {
+            let _ = tokio::fs::remove_file(item.path()).await;
+        }
+    }
+}
+
+/// Every entry with the time it was last saved or restored
+async fn list(cache_dir: &Path) -> Result<Vec<(Entry, SystemTime)>> {
+    let mut entries = Vec::new();
+    let mut dir = match tokio::fs::read_dir(cache_dir).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(item) = dir.next_entry().await? {
+        let name = item.file_name().to_string_lossy().to_string();
+        let Some(key) = name.strip_suffix(EXTENSION) else {
+            continue;
+        };
+        let metadata = item.metadata().await?;
+        entries.push((
+            Entry {
+                key: key.to_string(),
+                path: item.path(),
+                size: metadata.len(),
+            },
+            metadata.modified()?,
+        ));
+    }
+    Ok(entries)
+}
+
+async fn touch(path: &Path) {
+    let path = path.to_path_buf();
+    let _ = tokio::task::spawn_blocking(move || {
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+    })
+    .await;
+}
+
+pub fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 * 1024 {
+        format!("{:.1} GB", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
+    } else if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{} KB", bytes / 1024)
+    }
+}
@@ -0,0 +1,198 @@
+//! Release notes built from the commits since the previous tag, as done by
+//! the `changelog` node.
+//!
+//! Subjects following Conventional Commits (`feat(scope)!: ...`) are grouped
+//! by type when asked to; breaking changes are called out either way.
+
+use std::path::Path;
+
+use anyhow::Result;
+use tokio::process::Command;
+
+/// Separates the fields of one commit in `git log` output
+const FIELD_SEP: char = '\u{1f}';
+/// Separates commits in `git log` output
+const RECORD_SEP: char = '\u{1e}';
+
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub sha: String,
+    pub author: String,
+    /// Conventional Commit type, e.g. `feat`; None for other subjects
+    pub kind: Option<String>,
+    pub scope: Option<String>,
+    pub description: String,
+    pub breaking: bool,
+}
+
+impl Commit {
+    fn parse(sha: &str, author: &str, subject: &str, body: &str) -> Self {
+        let breaking_footer = body.contains("BREAKING CHANGE:") || body.contains("BREAKING-CHANGE:");
+        let conventional = subject.split_once(": ").and_then(|(prefix, description)| {
+            let (prefix, bang) = match prefix.strip_suffix('!') {
+                Some(prefix) => (prefix, true),
+                None => (prefix, false),
+            };
+            let (kind, scope) = match prefix.split_once('(') {
+                Some((kind, scope)) => (kind, Some(scope.strip_suffix(')')?)),
+                None => (prefix, None),
+            };
+            let valid = !kind.is_empty() && kind.chars().all(|c| c.is_ascii_alphanumeric());
+            valid.then(|| (kind.to_lowercase(), scope.map(str::to_string), description, bang))
+        });
+        match conventional {
+            Some((kind, scope, description, bang)) => Self {
+                sha: sha.to_string(),
+                author: author.to_string(),
+                kind: Some(kind),
+                scope,
+                description: description.trim().to_string(),
+                breaking: bang || breaking_footer,
+            },
+            None => Self {
+                sha: sha.to_string(),
+                author: author.to_string(),
+                kind: None,
+                scope: None,
+                description: subject.trim().to_string(),
+                breaking: breaking_footer,
+            },
+        }
+    }
+}
+
+pub struct Options {
+    /// Start of the range; the latest tag before `to_ref` if unset
+    pub from_tag: Option<String>,
+    pub to_ref: String,
+    pub group_by_type: bool,
+    pub include_authors: bool,
+}
+
+pub struct Changelog {
+    /// Tag the range started at; None if it covers the whole history
+    pub from_tag: Option<String>,
+    pub commits: Vec<Commit>,
+    pub markdown: String,
+}
+
+/// Collects and renders the commits of `dir` in the range given by `options`
+pub async fn generate(dir: &Path, options: &Options) -> Result<Changelog> {
+    let from_tag = match &options.from_tag {
+        Some(tag) => Some(tag.clone()),
+        None => previous_tag(dir, &options.to_ref).await?,
+    };
+    let range = match &from_tag {
+        Some(tag) => format!("{}..{}", tag, options.to_ref),
+        None => options.to_ref.clone(),
+    };
+    let format = format!("--format=%H{0}%an{0}%s{0}%b{1}", FIELD_SEP, RECORD_SEP);
+    let log = git(dir, &["log", "--no-merges", &format, &range]).await?;
+    let commits = log
+        .split(RECORD_SEP)
+        .filter_map(|record| {
+            let mut fields = record.trim_start_matches('\n').splitn(4, FIELD_SEP);
+            let (sha, author, subject) = (fields.next()?, fields.next()?, fields.next()?);
+            let body = fields.next().unwrap_or("");
+            (!sha.is_empty()).then(|| Commit::parse(sha, author, subject, body))
+        })
+        .collect::<Vec<_>>();
+    let markdown = render(&commits, options);
+    Ok(Changelog {
+        from_tag,
+        commits,
+        markdown,
+    })
+}
+
+/// Latest tag reachable from `to_ref`, leaving out a tag on `to_ref` itself so
+/// a tagged release still lists its own changes. None if there is no tag.
+async fn previous_tag(dir: &Path, to_ref: &str) -> Result<Option<String>> {
+    let head = git(dir, &["rev-parse", &format!("{}^{{commit}}", to_ref)]).await?;
+    let Some(tag) = describe(dir, to_ref).await else {
+        return Ok(None);
+    };
+    let tagged = git(dir, &["rev-parse", &format!("{}^{{commit}}", tag)]).await?;
+    if tagged.trim() != head.trim() {
+        return Ok(Some(tag));
+    }
+    // A root commit has no parent to look behind
+    Ok(describe(dir, &format!("{}^", to_ref)).await)
+}
+
+async fn describe(dir: &Path, rev: &str) -> Option<String> {
+    git(dir, &["describe", "--tags", "--abbrev=0", rev])
+        .await
+        .ok()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+}
+
+async fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Section heading for a Conventional Commit type, in display order
+const SECTIONS: &[(&str, &str)] = &[
+    ("feat", "Features"),
+    ("fix", "Bug Fixes"),
+    ("perf", "Performance"),
+    ("refactor", "Refactoring"),
+    ("docs", "Documentation"),
+    ("chore", "Chores"),
+];
+
+fn render(commits: &[Commit], options: &Options) -> String {
+    if commits.is_empty() {
+        return "No changes.\n".to_string();
+    }
+    let mut out = String::new();
+    let breaking: Vec<&Commit> = commits.iter().filter(|c| c.breaking).collect();
+    if !breaking.is_empty() {
+        section(&mut out, "Breaking Changes", &breaking, options);
+    }
+    if !options.group_by_type {
+        section(&mut out, "Changes", &commits.iter().collect::<Vec<_>>(), options);
+        return out;
+    }
+    for (kind, title) in SECTIONS {
+        let entries: Vec<&Commit> = commits.iter().filter(|c| c.kind.as_deref() == Some(*kind)).collect();
+        section(&mut out, title, &entries, options);
+    }
+    let other: Vec<&Commit> = commits
+        .iter()
+        .filter(|c| !SECTIONS.iter().any(|(kind, _)| c.kind.as_deref() == Some(*kind)))
+        .collect();
+    section(&mut out, "Other Changes", &other, options);
+    out
+}
+
+fn section(out: &mut String, title: &str, commits: &[&Commit], options: &Options) {
+    if commits.is_empty() {
+        return;
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out.push_str(&format!("## {}\n\n", title));
+    for commit in commits {
+        out.push_str("- ");
+        if let Some(scope) = &commit.scope {
+            out.push_str(&format!("**{}:** ", scope));
+        }
+        out.push_str(&commit.description);
+        out.push_str(&format!(" ({})", &commit.sha[..commit.sha.len().min(7)]));
+        if options.include_authors {
+            out.push_str(&format!(" by {}", commit.author));
+        }
+        out.push('\n');
+    }
+}
@@ -0,0 +1,126 @@
+//! Checksum files for releases, as written by the `checksums` node: a sums
+//! file in the format `sha256sum` reads and checks, optionally signed with
+//! minisign, and optionally one sidecar file per input.
+//!
+//! Files are hashed in chunks, never read into memory whole.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+use crate::build_log::BuildLog;
+use crate::builds::CancelToken;
+use crate::process::{self, TempFile};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            other => anyhow::bail!("Unknown algorithm {}, expected sha256 or sha512", other),
+        }
+    }
+
+    /// Conventional name of the sums file, e.g. `SHA256SUMS`
+    pub fn sums_name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA256SUMS",
+            Self::Sha512 => "SHA512SUMS",
+        }
+    }
+
+    /// Extension of sidecar files, e.g. `sha256`
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+}
+
+/// Hex-encoded digest of the contents of `path`
+pub async fn hash_file(path: &Path, algorithm: Algorithm) -> Result<String> {
+    match algorithm {
+        Algorithm::Sha256 => hash_with::<Sha256>(path).await,
+        Algorithm::Sha512 => hash_with::<Sha512>(path).await,
+    }
+}
+
+async fn hash_with<D: Digest>(path: &Path) -> Result<String> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = D::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// One `<hash>  <name>` line
+pub fn sums_line(hash: &str, name: &str) -> String {
+    format!("{}  {}\n", hash, name)
+}
+
+/// Contents of a sums file for `(name, hash)` entries, sorted by name so the
+/// file only changes when a file does
+pub fn sums_file(entries: &[(String, String)]) -> String {
+    let mut entries: Vec<&(String, String)> = entries.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.iter().map(|(name, hash)| sums_line(hash, name)).collect()
+}
+
+/// Signs `path` with a minisign secret key, writing `<path>.minisig`. The key
+/// goes through a private temporary file, the password through stdin.
+pub async fn minisign(
+    path: &Path,
+    secret_key: &str,
+    password: Option<&str>,
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<PathBuf> {
+    let key_file = TempFile::new("minisign-key");
+    key_file.write_private(secret_key).await?;
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(".minisig");
+    let signature = PathBuf::from(signature);
+
+    let mut child = process::configure(
+        Command::new("minisign")
+            .arg("-S")
+            .arg("-s")
+            .arg(&key_file.0)
+            .arg("-m")
+            .arg(path)
+            .arg("-x")
+            .arg(&signature),
+    )
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .context("Failed to run minisign; is it installed on the server?")?;
+    // Dropping stdin without writing leaves keys without a password working
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Some(password) = password {
+            stdin.write_all(format!("{}\n", password).as_bytes()).await?;
+        }
+    }
+    process::run(child, cancel, log).await?.check("minisign")?;
+    Ok(signature)
+}
@@ -0,0 +1,187 @@
+//! Removal of what builds leave on disk besides their history: scripts of
+//! builds that died with the server, workspaces kept after a failure,
+//! artifacts retained from workspaces and per-build tool directories.
+//!
+//! Stale scripts go at startup. The rest goes once it is older than
+//! `workspace_retention_days`, checked every hour and whenever a client sends
+//! `RunCleanup`. Nothing of a running or queued build is touched, and
+//! artifacts stay for as long as their build is in history; `retention`
+//! removes them with it.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{builds, tools, workspace, ServerContext, ServerMessage};
+
+/// Prefix of the scripts script nodes write
+const SCRIPT_PREFIX: &str = ".buildforge-";
+/// Age at which a script is taken to be left over from a crash
+const STALE_SCRIPT_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CleanupReport {
+    pub removed: Vec<RemovedPath>,
+    pub reclaimed_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemovedPath {
+    pub path: String,
+    pub bytes: u64,
+}
+
+impl CleanupReport {
+    fn add(&mut self, path: &Path, bytes: u64) {
+        self.removed.push(RemovedPath {
+            path: path.to_string_lossy().to_string(),
+            bytes,
+        });
+        self.reclaimed_bytes += bytes;
+    }
+}
+
+/// Removes scripts older than a day from the directories builds run in
+pub async fn remove_stale_scripts(ctx: &ServerContext) -> CleanupReport {
+    let mut dirs = vec![ctx.workdir.clone()];
+    dirs.extend(ctx.data.read().await.repos.iter().map(|r| PathBuf::from(&r.path)));
+    let mut report = CleanupReport::default();
+    for dir in dirs {
+        let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some(rest) = name.strip_prefix(SCRIPT_PREFIX) else {
+                continue;
+            };
+            // `.buildforge-<build_id>.sh`
+            let build_id = rest.split('.').next().unwrap_or_default();
+            if builds::is_active(ctx, build_id).await {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let stale = metadata.is_file()
+                && metadata.modified().is_ok_and(|m| m.elapsed().unwrap_or_default() > STALE_SCRIPT_AGE);
+            if !stale {
+                continue;
+            }
+            match tokio::fs::remove_file(entry.path()).await {
+                Ok(()) => report.add(&entry.path(), metadata.len()),
+                Err(e) => warn!("Failed to remove {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+    log(&report, "stale scripts");
+    report
+}
+
+/// Removes kept workspaces, tool directories and retained artifacts of
+/// builds no longer in history older than the retention period; nothing if
+/// there is none
+pub async fn run(ctx: &ServerContext) -> CleanupReport {
+    let mut report = CleanupReport::default();
+    let Some(days) = ctx.settings().workspace_retention_days else {
+        return report;
+    };
+    let max_age = Duration::from_secs(days * 24 * 60 * 60);
+    let artifacts = ctx.data_dir.join("artifacts");
+    let roots = [workspace::root(&ctx.data_dir), artifacts.clone(), tools::root(&ctx.data_dir)];
+    for root in &roots {
+        let Ok(mut entries) = tokio::fs::read_dir(root).await else {
+            continue;
+        };
+        // Every entry is named after the build it belongs to
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let build_id = entry.file_name().to_string_lossy().to_string();
+            if builds::is_active(ctx, &build_id).await {
+                continue;
+            }
+            // Clients still list and download the artifacts of recorded builds
+            if *root == artifacts && !matches!(ctx.history.get(&build_id).await, Ok(None)) {
+                continue;
+            }
+            let old = entry
+                .metadata()
+                .await
+                .and_then(|m| m.modified())
+                .is_ok_and(|m| m.elapsed().unwrap_or_default() > max_age);
+            if !old {
+                continue;
+            }
+            let path = entry.path();
+            let measured = path.clone();
+            let bytes = tokio::task::spawn_blocking(move || size(&measured)).await.unwrap_or_default();
+            let removed = if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                tokio::fs::remove_dir_all(&path).await
+            } else {
+                tokio::fs::remove_file(&path).await
+            };
+            match removed {
+                Ok(()) => report.add(&path, bytes),
+                Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+    }
+    if report.removed.iter().any(|r| Path::new(&r.path).starts_with(&roots[0])) {
+        prune_worktrees(ctx).await;
+    }
+    log(&report, &format!("files older than {} days", days));
+    report
+}
+
+/// Cleans up every hour, telling clients when anything was removed
+pub async fn run_periodically(ctx: Arc<ServerContext>) {
+    let mut interval = tokio::time::interval(INTERVAL);
+    loop {
+        interval.tick().await;
+        let report = run(&ctx).await;
+        if !report.removed.is_empty() {
+            ctx.broadcast(ServerMessage::CleanupReport(report));
+        }
+    }
+}
+
+/// Kept workspaces of git repos are worktrees, which their repo keeps
+/// track of until told they are gone
+async fn prune_worktrees(ctx: &ServerContext) {
+    let repos: Vec<String> = ctx.data.read().await.repos.iter().map(|r| r.path.clone()).collect();
+    for repo in repos {
+        let _ = tokio::process::Command::new("git")
+            .arg("-C")
+            .arg(&repo)
+            .args(["worktree", "prune"])
+            .output()
+            .await;
+    }
+}
+
+fn log(report: &CleanupReport, what: &str) {
+    if !report.removed.is_empty() {
+        info!(
+            "Removed {} {}, reclaiming {} bytes",
+            report.removed.len(),
+            what,
+            report.reclaimed_bytes
+        );
+    }
+}
+
+/// Bytes taken by the files under `path`, not following links
+fn size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| size(&entry.path())).sum())
+        .unwrap_or_default()
+}
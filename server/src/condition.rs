@@ -0,0 +1,380 @@
+//! The expressions of `condition` nodes, such as
+//! `$BRANCH == "main" && !contains($VERSION, "-rc")`.
+//!
+//! An expression is parsed once, before the build starts, so a typo fails the
+//! build up front instead of halfway through. Variables are looked up when it
+//! is evaluated:
+//!
+//! - `$NAME` and `${node.output}`, as in any node setting
+//! - `"text"` or `'text'` and numbers
+//! - `==`, `!=`, `<`, `<=`, `>`, `>=`, numeric if both sides are numbers
+//! - `&&`, `||`, `!` and parentheses
+//! - `contains(text, part)` and `matches(text, "regex")`
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Text(String),
+    Number(f64),
+    /// As written, `$NAME` or `${node.output}`
+    Variable(String),
+    Ident(String),
+    Cmp(Cmp),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Comma,
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Text(String),
+    Number(f64),
+    Bool(bool),
+}
+
+impl Value {
+    fn text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Number(n) => n.to_string(),
+            Self::Bool(b) => b.to_string(),
+        }
+    }
+
+    fn number(&self) -> Option<f64> {
+        match self {
+            Self::Text(text) => text.trim().parse().ok(),
+            Self::Number(n) => Some(*n),
+            Self::Bool(_) => None,
+        }
+    }
+
+    /// Empty text, `false` and `0` are false
+    fn truthy(&self) -> bool {
+        match self {
+            Self::Text(text) => !text.is_empty() && text != "false" && text != "0",
+            Self::Number(n) => *n != 0.0,
+            Self::Bool(b) => *b,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Variable(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Cmp, Box<Expr>, Box<Expr>),
+    Contains(Box<Expr>, Box<Expr>),
+    Matches(Box<Expr>, Regex),
+}
+
+/// A parsed condition, ready to be evaluated
+#[derive(Debug)]
+pub struct Condition(Expr);
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self> {
+        let tokens = tokenize(source)?;
+        if tokens.is_empty() {
+            anyhow::bail!("The condition is empty");
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            anyhow::bail!("Unexpected {} in the condition", describe(token));
+        }
+        Ok(Self(expr))
+    }
+
+    /// Evaluates the condition, looking variables up with `lookup`, which
+    /// gets them as written and returns `None` for unknown ones
+    pub fn evaluate(&self, lookup: &dyn Fn(&str) -> Option<String>) -> Result<bool> {
+        Ok(eval(&self.0, lookup)?.truthy())
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::Open);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                i += 1;
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                i += 1;
+            }
+            '&' if next == Some('&') => {
+                tokens.push(Token::And);
+                i += 2;
+            }
+            '|' if next == Some('|') => {
+                tokens.push(Token::Or);
+                i += 2;
+            }
+            '=' if next == Some('=') => {
+                tokens.push(Token::Cmp(Cmp::Eq));
+                i += 2;
+            }
+            '!' if next == Some('=') => {
+                tokens.push(Token::Cmp(Cmp::Ne));
+                i += 2;
+            }
+            '!' => {
+                tokens.push(Token::Not);
+                i += 1;
+            }
+            '<' | '>' => {
+                let or_equal = next == Some('=');
+                tokens.push(Token::Cmp(match (c, or_equal) {
+                    ('<', false) => Cmp::Lt,
+                    ('<', true) => Cmp::Le,
+                    ('>', false) => Cmp::Gt,
+                    _ => Cmp::Ge,
+                }));
+                i += if or_equal { 2 } else { 1 };
+            }
+            '"' | '\'' => {
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => anyhow::bail!("Unterminated string in the condition"),
+                        Some(&q) if q == c => break,
+                        Some('\\') => {
+                            let escaped = chars.get(i + 1).context("Unterminated string in the condition")?;
+                            // Keep the backslash unless it escapes a quote, so regexes read as usual
+                            if *escaped != c && *escaped != '\\' {
+                                text.push('\\');
+                            }
+                            text.push(*escaped);
+                            i += 2;
+                        }
+                        Some(&ch) => {
+                            text.push(ch);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::Text(text));
+                i += 1;
+            }
+            '$' if next == Some('{') => {
+                let end = chars[i..]
+                    .iter()
+                    .position(|&ch| ch == '}')
+                    .context("Unterminated ${ in the condition")?;
+                let name: String = chars[i..i + end + 1].iter().collect();
+                if name.len() <= 3 {
+                    anyhow::bail!("Empty ${{}} in the condition");
+                }
+                tokens.push(Token::Variable(name));
+                i += end + 1;
+            }
+            '$' => {
+                let len = chars[i + 1..]
+                    .iter()
+                    .take_while(|ch| ch.is_ascii_alphanumeric() || **ch == '_')
+                    .count();
+                if len == 0 {
+                    anyhow::bail!("$ without a variable name in the condition");
+                }
+                tokens.push(Token::Variable(chars[i..i + 1 + len].iter().collect()));
+                i += 1 + len;
+            }
+            c if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                let len = 1 + chars[i + 1..]
+                    .iter()
+                    .take_while(|ch| ch.is_ascii_digit() || **ch == '.')
+                    .count();
+                let number: String = chars[i..i + len].iter().collect();
+                tokens.push(Token::Number(
+                    number.parse().with_context(|| format!("{} is not a number", number))?,
+                ));
+                i += len;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|ch| ch.is_ascii_alphanumeric() || **ch == '_')
+                    .count();
+                tokens.push(Token::Ident(chars[i..i + len].iter().collect()));
+                i += len;
+            }
+            other => anyhow::bail!("Unexpected {} in the condition", other),
+        }
+    }
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Text(text) => format!("\"{}\"", text),
+        Token::Number(n) => n.to_string(),
+        Token::Variable(name) | Token::Ident(name) => name.clone(),
+        Token::Cmp(_) => "comparison".to_string(),
+        Token::And => "&&".to_string(),
+        Token::Or => "||".to_string(),
+        Token::Not => "!".to_string(),
+        Token::Open => "(".to_string(),
+        Token::Close => ")".to_string(),
+        Token::Comma => ",".to_string(),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, wanted: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(wanted) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, wanted: Token) -> Result<()> {
+        match self.next() {
+            Some(token) if token == wanted => Ok(()),
+            Some(token) => anyhow::bail!("Expected {} but found {} in the condition", describe(&wanted), describe(&token)),
+            None => anyhow::bail!("Expected {} at the end of the condition", describe(&wanted)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut left = self.and()?;
+        while self.eat(&Token::Or) {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut left = self.unary()?;
+        while self.eat(&Token::And) {
+            left = Expr::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let left = self.primary()?;
+        if let Some(Token::Cmp(cmp)) = self.tokens.get(self.pos).cloned() {
+            self.pos += 1;
+            return Ok(Expr::Compare(cmp, Box::new(left), Box::new(self.primary()?)));
+        }
+        Ok(left)
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Text(text)) => Ok(Expr::Literal(Value::Text(text))),
+            Some(Token::Number(n)) => Ok(Expr::Literal(Value::Number(n))),
+            Some(Token::Variable(name)) => Ok(Expr::Variable(name)),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                self.expect(Token::Close)?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "contains" => {
+                    self.expect(Token::Open)?;
+                    let text = self.or()?;
+                    self.expect(Token::Comma)?;
+                    let part = self.or()?;
+                    self.expect(Token::Close)?;
+                    Ok(Expr::Contains(Box::new(text), Box::new(part)))
+                }
+                "matches" => {
+                    self.expect(Token::Open)?;
+                    let text = self.or()?;
+                    self.expect(Token::Comma)?;
+                    // A literal, so a bad pattern is caught before the build
+                    let Some(Token::Text(pattern)) = self.next() else {
+                        anyhow::bail!("The pattern of matches() must be a quoted string");
+                    };
+                    let regex = Regex::new(&pattern).with_context(|| format!("Invalid regex {}", pattern))?;
+                    self.expect(Token::Close)?;
+                    Ok(Expr::Matches(Box::new(text), regex))
+                }
+                other => anyhow::bail!("Unknown function or word {} in the condition", other),
+            },
+            Some(token) => anyhow::bail!("Unexpected {} in the condition", describe(&token)),
+            None => anyhow::bail!("The condition ends too early"),
+        }
+    }
+}
+
+fn eval(expr: &Expr, lookup: &dyn Fn(&str) -> Option<String>) -> Result<Value> {
+    Ok(match expr {
+        Expr::Literal(value) => value.clone(),
+        Expr::Variable(name) => Value::Text(lookup(name).with_context(|| format!("{} is not set in this build", name))?),
+        Expr::Not(inner) => Value::Bool(!eval(inner, lookup)?.truthy()),
+        Expr::And(left, right) => Value::Bool(eval(left, lookup)?.truthy() && eval(right, lookup)?.truthy()),
+        Expr::Or(left, right) => Value::Bool(eval(left, lookup)?.truthy() || eval(right, lookup)?.truthy()),
+        Expr::Compare(cmp, left, right) => {
+            let (left, right) = (eval(left, lookup)?, eval(right, lookup)?);
+            let ordering = match (left.number(), right.number()) {
+                (Some(l), Some(r)) => l.partial_cmp(&r).unwrap_or(std::cmp::Ordering::Equal),
+                _ => left.text().cmp(&right.text()),
+            };
+            Value::Bool(match cmp {
+                Cmp::Eq => ordering.is_eq(),
+                Cmp::Ne => !ordering.is_eq(),
+                Cmp::Lt => ordering.is_lt(),
+                Cmp::Le => ordering.is_le(),
+                Cmp::Gt => ordering.is_gt(),
+                Cmp::Ge => ordering.is_ge(),
+            })
+        }
+        Expr::Contains(text, part) => Value::Bool(eval(text, lookup)?.text().contains(&eval(part, lookup)?.text())),
+        Expr::Matches(text, regex) => Value::Bool(regex.is_match(&eval(text, lookup)?.text())),
+    })
+}
@@ -0,0 +1,129 @@
+//! Running command and script nodes inside a container instead of on the
+//! server itself.
+//!
+//! The build's working directory is mounted at `/work`, so whatever the node
+//! writes there is still on disk for the nodes after it. The container runs
+//! as the server's user so those files stay removable.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::build_log::BuildLog;
+use crate::builds::CancelToken;
+use crate::{process, BuildNode};
+
+/// Where the working directory is mounted in the container
+pub const MOUNT: &str = "/work";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerConfig {
+    pub image: String,
+    /// Passed to `docker run --cpus`
+    #[serde(default)]
+    pub cpus: Option<f64>,
+    /// Passed to `docker run --memory`, e.g. `2g`
+    #[serde(default)]
+    pub memory: Option<String>,
+}
+
+/// Container a command or script node runs in: its own `container`, which
+/// is an image name or a full config, else the workflow's. `false` runs the
+/// node on the host even if the workflow has a container.
+pub fn for_node(node: &BuildNode, workflow_default: Option<&ContainerConfig>) -> Result<Option<ContainerConfig>> {
+    match node.config.get("container") {
+        None | Some(serde_json::Value::Null) => Ok(workflow_default.cloned()),
+        Some(serde_json::Value::Bool(false)) => Ok(None),
+        Some(serde_json::Value::String(image)) if !image.is_empty() => Ok(Some(ContainerConfig {
+            image: image.clone(),
+            cpus: None,
+            memory: None,
+        })),
+        Some(value) => serde_json::from_value(value.clone())
+            .map(Some)
+            .with_context(|| format!("Invalid container setting on node {}", node.name)),
+    }
+}
+
+/// Where `cwd` on the host is inside the container
+pub fn container_path(workdir: &Path, cwd: &Path) -> Result<String> {
+    let relative = cwd
+        .strip_prefix(workdir)
+        .with_context(|| format!("{} is outside the build directory mounted into the container", cwd.display()))?;
+    Ok(Path::new(MOUNT).join(relative).to_string_lossy().to_string())
+}
+
+/// Runs `program` in a fresh container with the build's working directory
+/// mounted. Environment values go through docker's own environment rather
+/// than its command line.
+pub async fn run(
+    config: &ContainerConfig,
+    workdir: &Path,
+    cwd: &str,
+    program: &[&str],
+    env: &[(String, String)],
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<process::Finished> {
+    let name = format!("buildforge-{}", uuid::Uuid::new_v4());
+    let mut command = Command::new("docker");
+    command
+        .args(["run", "--rm", "--name", &name, "-w", cwd])
+        .arg("-v")
+        .arg(format!("{}:{}", workdir.display(), MOUNT));
+    #[cfg(unix)]
+    {
+        // getuid and getgid always succeed
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        command.args(["--user", &format!("{}:{}", uid, gid)]);
+    }
+    if let Some(cpus) = config.cpus {
+        command.args(["--cpus", &cpus.to_string()]);
+    }
+    if let Some(memory) = &config.memory {
+        command.args(["--memory", memory]);
+    }
+    for (key, _) in env {
+        command.args(["-e", key]);
+    }
+    command.arg(&config.image).args(program);
+
+    let child = process::configure(&mut command)
+        .envs(env.iter().cloned())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to start docker")?;
+
+    // Killing the docker client does not stop the container, this does
+    let mut guard = KillOnDrop(Some(name));
+    let finished = process::run(child, cancel, log).await;
+    if finished.is_ok() {
+        guard.0 = None;
+    }
+    finished
+}
+
+/// Kills the named container, if it is still set, when dropped
+struct KillOnDrop(Option<String>);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let Some(name) = self.0.take() else {
+            return;
+        };
+        if let Err(e) = std::process::Command::new("docker")
+            .args(["kill", &name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            warn!("Failed to kill container {}: {}", name, e);
+        }
+    }
+}
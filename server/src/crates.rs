@@ -0,0 +1,323 @@
+//! Building Rust crates for several targets, as done by the `cargo_build`
+//! node, and publishing them, as done by the `cargo_publish` node.
+//!
+//! Each target builds in its own target directory so targets can build at
+//! the same time without waiting on cargo's directory lock.
+//!
+//! Versions already on the registry are found through its sparse index, which
+//! crates.io and most alternative registries serve. The token only ever
+//! reaches cargo through its environment.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::build_log::BuildLog;
+use crate::builds::CancelToken;
+use crate::process;
+
+pub const CRATES_IO_INDEX: &str = "https://index.crates.io/";
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+    #[serde(default)]
+    workspace_members: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    id: String,
+    manifest_path: String,
+    #[serde(default)]
+    dependencies: Vec<Dependency>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Dependency {
+    name: String,
+    /// None for normal dependencies
+    kind: Option<String>,
+}
+
+/// The packages to publish, dependencies first. An empty `names` means the
+/// package of `manifest_path` itself.
+pub async fn packages_in_order(workdir: &Path, manifest_path: &str, names: &[String]) -> Result<Vec<Package>> {
+    let output = Command::new("cargo")
+        .args(["metadata", "--format-version", "1", "--no-deps", "--manifest-path", manifest_path])
+        .current_dir(workdir)
+        .output()
+        .await
+        .context("Failed to run cargo; is it installed on the server?")?;
+    if !output.status.success() {
+        anyhow::bail!("cargo metadata failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let metadata: Metadata = serde_json::from_slice(&output.stdout).context("Unexpected cargo metadata output")?;
+
+    let selected: Vec<Package> = if names.is_empty() {
+        let manifest = workdir.join(manifest_path).canonicalize().ok();
+        let root = metadata.packages.iter().find(|p| {
+            metadata.workspace_members.contains(&p.id)
+                && Path::new(&p.manifest_path).canonicalize().ok() == manifest
+        });
+        vec![root
+            .cloned()
+            .context("The manifest is a virtual workspace; list the crates to publish in packages")?]
+    } else {
+        names
+            .iter()
+            .map(|name| {
+                metadata
+                    .packages
+                    .iter()
+                    .find(|p| &p.name == name)
+                    .cloned()
+                    .with_context(|| format!("Package {} is not part of the workspace", name))
+            })
+            .collect::<Result<_>>()?
+    };
+    dependency_order(selected)
+}
+
+/// Sorts `packages` so each comes after those of them it depends on
+fn dependency_order(packages: Vec<Package>) -> Result<Vec<Package>> {
+    let names: HashSet<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+    let mut waiting_on: HashMap<&str, usize> = HashMap::new();
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for package in &packages {
+        let deps: HashSet<&str> = package
+            .dependencies
+            .iter()
+            // Dev-dependencies are not needed to publish
+            .filter(|d| d.kind.as_deref() != Some("dev") && names.contains(d.name.as_str()))
+            .map(|d| d.name.as_str())
+            .collect();
+        waiting_on.insert(&package.name, deps.len());
+        for dep in deps {
+            dependents.entry(dep).or_default().push(&package.name);
+        }
+    }
+
+    let mut ready: VecDeque<&str> = packages
+        .iter()
+        .map(|p| p.name.as_str())
+        .filter(|name| waiting_on[name] == 0)
+        .collect();
+    let mut order = Vec::new();
+    while let Some(name) = ready.pop_front() {
+        order.push(name.to_string());
+        for dependent in dependents.get(name).into_iter().flatten() {
+            let count = waiting_on.get_mut(dependent).expect("every package is counted");
+            *count -= 1;
+            if *count == 0 {
+                ready.push_back(*dependent);
+            }
+        }
+    }
+    if order.len() != packages.len() {
+        anyhow::bail!("The packages to publish depend on each other in a cycle");
+    }
+    Ok(order
+        .iter()
+        .filter_map(|name| packages.iter().find(|p| &p.name == name).cloned())
+        .collect())
+}
+
+/// Path of a crate in a sparse index, e.g. `se/rd/serde`
+fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+/// Whether `version` of `name` is in the sparse index at `index`
+pub async fn is_published(index: &str, name: &str, version: &str, token: Option<&str>) -> Result<bool> {
+    let url = format!("{}/{}", index.trim_start_matches("sparse+").trim_end_matches('/'), index_path(name));
+    let mut request = reqwest::Client::new()
+        .get(&url)
+        .header(reqwest::header::USER_AGENT, concat!("BuildForge/", env!("CARGO_PKG_VERSION")));
+    if let Some(token) = token {
+        request = request.header(reqwest::header::AUTHORIZATION, token);
+    }
+    let response = request.send().await.with_context(|| format!("Failed to query {}", index))?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    if !response.status().is_success() {
+        anyhow::bail!("{} answered {} for {}", index, response.status(), name);
+    }
+    // One JSON object per published version
+    let text = response.text().await?;
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .any(|entry| entry.get("vers").and_then(|v| v.as_str()) == Some(version)))
+}
+
+pub struct Publish<'a> {
+    pub manifest_path: &'a str,
+    /// A registry from the cargo configuration; crates.io if unset
+    pub registry: Option<&'a str>,
+    pub dry_run: bool,
+    pub allow_dirty: bool,
+}
+
+/// Variable cargo reads the token of `registry` from
+fn token_variable(registry: Option<&str>) -> String {
+    match registry {
+        Some(name) => format!("CARGO_REGISTRIES_{}_TOKEN", name.to_uppercase().replace('-', "_")),
+        None => "CARGO_REGISTRY_TOKEN".to_string(),
+    }
+}
+
+/// Runs `cargo publish` for one package. Cargo's output goes to the build log
+/// as it is, and a rejected publish fails with cargo's own error.
+pub async fn publish(
+    workdir: &Path,
+    package: &Package,
+    publish: &Publish<'_>,
+    token: Option<&str>,
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<i32> {
+    let mut command = Command::new("cargo");
+    command.args(["publish", "--manifest-path", publish.manifest_path, "--package", &package.name]);
+    if let Some(registry) = publish.registry {
+        command.args(["--registry", registry]);
+    }
+    if publish.dry_run {
+        command.arg("--dry-run");
+    }
+    if publish.allow_dirty {
+        command.arg("--allow-dirty");
+    }
+    if let Some(token) = token {
+        command.env(token_variable(publish.registry), token);
+    }
+    let child = process::configure(&mut command)
+        .current_dir(workdir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run cargo; is it installed on the server?")?;
+    process::run(child, cancel, log).await?.check("cargo publish")
+}
+
+pub struct TargetBuild<'a> {
+    pub manifest_path: &'a str,
+    pub package: Option<&'a str>,
+    /// Cargo profile; `dev` builds into `debug`
+    pub profile: &'a str,
+    pub features: &'a [String],
+    /// Each target gets `<target_dir>/<triple>`
+    pub target_dir: &'a Path,
+}
+
+/// Targets rustup has installed, or None without rustup
+pub async fn installed_targets() -> Option<HashSet<String>> {
+    let output = Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .output()
+        .await
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).lines().map(|l| l.trim().to_string()).collect())
+}
+
+pub async fn add_target(target: &str, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    log.line(&format!("[cargo] Installing target {}", target)).await;
+    let child = process::configure(Command::new("rustup").args(["target", "add", target]))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    process::run(child, cancel, log).await?.check("rustup target add")?;
+    Ok(())
+}
+
+/// Builds `target` and returns the binaries it produced
+pub async fn build_target(
+    workdir: &Path,
+    build: &TargetBuild<'_>,
+    target: &str,
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<Vec<PathBuf>> {
+    let target_dir = workdir.join(build.target_dir).join(target);
+    let mut command = Command::new("cargo");
+    command
+        .args(["build", "--manifest-path", build.manifest_path, "--target", target, "--profile", build.profile])
+        .arg("--target-dir")
+        .arg(&target_dir);
+    if let Some(package) = build.package {
+        command.args(["--package", package]);
+    }
+    if !build.features.is_empty() {
+        command.args(["--features", &build.features.join(",")]);
+    }
+    log.line(&format!("[cargo] Building {} ({})", target, build.profile)).await;
+    let child = process::configure(&mut command)
+        .current_dir(workdir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run cargo; is it installed on the server?")?;
+    process::run(child, cancel, log).await?.check("cargo build")?;
+
+    let profile_dir = match build.profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        other => other,
+    };
+    binaries(&target_dir.join(target).join(profile_dir)).await
+}
+
+/// Executables and dynamic libraries directly in `dir`
+async fn binaries(dir: &Path) -> Result<Vec<PathBuf>> {
+    const EXTENSIONS: [&str; 5] = ["exe", "dll", "so", "dylib", "wasm"];
+    let mut found = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Failed to read {}", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let binary = match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => EXTENSIONS.contains(&extension),
+            None => is_executable(&metadata),
+        };
+        if binary {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
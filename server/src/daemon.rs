@@ -0,0 +1,290 @@
+//! Running under a service manager.
+//!
+//! Under systemd with `Type=notify`, the server sends `READY=1` once its data
+//! is loaded and it listens, and `STOPPING=1` when a graceful shutdown
+//! begins, asking for as much time as the grace period needs. On Windows,
+//! `service install` registers the server with the service control manager,
+//! which starts it as `service run`; a stop request goes through the same
+//! shutdown as Ctrl-C. `--pid-file` writes the pid for anything else that
+//! tracks the process.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tracing::warn;
+
+/// Tells the service manager the server is up
+pub fn ready(port: u16) {
+    #[cfg(unix)]
+    notify(&format!("READY=1\nSTATUS=Listening on port {}", port));
+    #[cfg(windows)]
+    windows::report(windows_service::service::ServiceState::Running, Duration::ZERO);
+    #[cfg(not(any(unix, windows)))]
+    let _ = port;
+}
+
+/// Tells the service manager a graceful shutdown began and may take `wait`
+pub fn stopping(wait: Duration) {
+    #[cfg(unix)]
+    notify(&format!(
+        "STOPPING=1\nSTATUS=Waiting for running builds\nEXTEND_TIMEOUT_USEC={}",
+        wait.as_micros()
+    ));
+    #[cfg(windows)]
+    windows::report(windows_service::service::ServiceState::StopPending, wait);
+    #[cfg(not(any(unix, windows)))]
+    let _ = wait;
+}
+
+/// Sends `state` to systemd, if it started the server with a notify socket
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| match socket_path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), &socket_path),
+    });
+    if let Err(e) = sent {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Holds `--pid-file` for as long as the server runs, and removes it after
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write the pid file {}", path.display()))?;
+        Ok(Self(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove the pid file {}: {}", self.0.display(), e);
+            }
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum ServiceCommand {
+    /// Register the server as a Windows service that starts with the system,
+    /// with the options given here
+    Install,
+    /// Stop and remove the Windows service
+    Uninstall,
+    /// Run as the service; only the service control manager calls this
+    Run,
+}
+
+/// Runs a `service` subcommand
+pub async fn service(args: &crate::Args, command: ServiceCommand) -> Result<()> {
+    #[cfg(windows)]
+    return windows::command(args, command).await;
+    #[cfg(not(windows))]
+    {
+        let _ = (args, command);
+        anyhow::bail!("Services are only for Windows; under systemd, use Type=notify and run the server in the foreground")
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    //! The Windows service: `install` and `uninstall` talk to the service
+    //! control manager, `run` hands it the process.
+
+    use std::ffi::OsString;
+    use std::sync::{Mutex, OnceLock};
+    use std::time::Duration;
+
+    use anyhow::{Context, Result};
+    use tracing::{error, info};
+    use windows_service::service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+        ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    use super::ServiceCommand;
+    use crate::{lockfile, open_context, serve, shutdown, Args};
+
+    const SERVICE_NAME: &str = "BuildForge";
+
+    /// Options the service was started with, and the runtime to run it on
+    static SERVICE: OnceLock<(Args, tokio::runtime::Handle)> = OnceLock::new();
+
+    static STATUS: Mutex<Option<ServiceStatusHandle>> = Mutex::new(None);
+
+    pub(super) async fn command(args: &Args, command: ServiceCommand) -> Result<()> {
+        match command {
+            ServiceCommand::Install => install(args),
+            ServiceCommand::Uninstall => uninstall(),
+            ServiceCommand::Run => {
+                let _ = SERVICE.set((args.clone(), tokio::runtime::Handle::current()));
+                // Blocks until the service has stopped
+                tokio::task::block_in_place(|| service_dispatcher::start(SERVICE_NAME, ffi_service_main))
+                    .context("Failed to start as a service; use service install to register it")
+            }
+        }
+    }
+
+    /// Sets the state the service control manager shows
+    pub(super) fn report(state: ServiceState, wait_hint: Duration) {
+        let status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(handle) = status.as_ref() else {
+            return;
+        };
+        let controls_accepted = match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        let result = handle.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint,
+            process_id: None,
+        });
+        if let Err(e) = result {
+            error!("Failed to report the service state: {}", e);
+        }
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let Some((args, runtime)) = SERVICE.get() else {
+            return;
+        };
+        let handler = |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                shutdown::request();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        match service_control_handler::register(SERVICE_NAME, handler) {
+            Ok(handle) => *STATUS.lock().unwrap_or_else(|e| e.into_inner()) = Some(handle),
+            Err(e) => {
+                error!("Failed to register the service control handler: {}", e);
+                return;
+            }
+        }
+        report(ServiceState::StartPending, Duration::from_secs(30));
+
+        let code = runtime.block_on(async {
+            let _lock = lockfile::acquire(&args.data_dir)?;
+            let ctx = open_context(args).await?;
+            serve(ctx).await
+        });
+        let code = match code {
+            Ok(code) => code,
+            Err(e) => {
+                error!("The service failed: {:#}", e);
+                1
+            }
+        };
+        if let Some(handle) = STATUS.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            let _ = handle.set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: ServiceState::Stopped,
+                controls_accepted: ServiceControlAccept::empty(),
+                exit_code: match code {
+                    0 => ServiceExitCode::Win32(0),
+                    code => ServiceExitCode::ServiceSpecific(code as u32),
+                },
+                checkpoint: 0,
+                wait_hint: Duration::ZERO,
+                process_id: None,
+            });
+        }
+    }
+
+    fn install(args: &Args) -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+            .context("Failed to open the service control manager; run as administrator")?;
+        let info = ServiceInfo {
+            name: OsString::from(SERVICE_NAME),
+            display_name: OsString::from("BuildForge Server"),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()?,
+            launch_arguments: launch_arguments(args)?,
+            dependencies: Vec::new(),
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager
+            .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+            .context("Failed to create the service")?;
+        service.set_description("Runs BuildForge workflows for the BuildForge app")?;
+        info!("Installed the {} service; start it with: sc start {}", SERVICE_NAME, SERVICE_NAME);
+        Ok(())
+    }
+
+    fn uninstall() -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .context("Failed to open the service control manager; run as administrator")?;
+        let service = manager
+            .open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+            .context("Failed to open the service; is it installed?")?;
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            info!("Stopping the {} service", SERVICE_NAME);
+            service.stop()?;
+        }
+        // Removed once the service has stopped and every handle is closed
+        service.delete().context("Failed to remove the service")?;
+        info!("Removed the {} service", SERVICE_NAME);
+        Ok(())
+    }
+
+    /// This process's options for the service, with the directories made
+    /// absolute, since services start in the system directory
+    fn launch_arguments(args: &Args) -> Result<Vec<OsString>> {
+        let mut launch = Vec::new();
+        let mut raw = std::env::args_os().skip(1);
+        while let Some(arg) = raw.next() {
+            let text = arg.to_string_lossy();
+            match text.as_ref() {
+                "service" | "install" => {}
+                "--data-dir" | "--workdir" | "-w" => {
+                    raw.next();
+                }
+                _ if text.starts_with("--data-dir=") || text.starts_with("--workdir=") => {}
+                _ => launch.push(arg),
+            }
+        }
+        let cwd = std::env::current_dir()?;
+        let absolute = |path: &std::path::Path| -> Result<OsString> { Ok(cwd.join(path).into_os_string()) };
+        launch.extend([
+            "--data-dir".into(),
+            absolute(&args.data_dir)?,
+            "--workdir".into(),
+            absolute(&args.workdir)?,
+            "service".into(),
+            "run".into(),
+        ]);
+        Ok(launch)
+    }
+}
@@ -0,0 +1,188 @@
+//! Building macOS disk images, as done by the `dmg` node, with `hdiutil`.
+//!
+//! The app and a link to /Applications are staged in a scratch directory,
+//! turned into a writable image, laid out through Finder if a background or
+//! icon positions are set, and converted into a compressed read-only image.
+//! The scratch directory and any volume still mounted are removed however the
+//! node ends, as a leftover mount makes the next build fail with "resource
+//! busy".
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::build_log::BuildLog;
+use crate::builds::CancelToken;
+use crate::process;
+
+pub struct Dmg {
+    pub app: PathBuf,
+    pub volume_name: String,
+    pub output: PathBuf,
+    pub background: Option<PathBuf>,
+    /// Finder window size
+    pub window_size: (u32, u32),
+    pub app_position: Option<(i32, i32)>,
+    pub applications_position: Option<(i32, i32)>,
+}
+
+impl Dmg {
+    fn has_layout(&self) -> bool {
+        self.background.is_some() || self.app_position.is_some() || self.applications_position.is_some()
+    }
+}
+
+/// Fails unless this server can build disk images
+pub fn check_available() -> Result<()> {
+    if !cfg!(target_os = "macos") {
+        anyhow::bail!("Disk images can only be built on macOS servers");
+    }
+    which::which("hdiutil").map_err(|_| anyhow::anyhow!("hdiutil is not installed on the server"))?;
+    Ok(())
+}
+
+pub async fn create(dmg: &Dmg, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    let app_name = dmg
+        .app
+        .file_name()
+        .with_context(|| format!("{} is not an app bundle", dmg.app.display()))?
+        .to_string_lossy()
+        .to_string();
+    let scratch = process::TempDir::new("dmg");
+    let staging = scratch.0.join("staging");
+    tokio::fs::create_dir_all(&staging).await?;
+
+    log.line(&format!("[dmg] Staging {}", app_name)).await;
+    // ditto keeps the bundle's links, modes and extended attributes
+    let mut ditto = Command::new("ditto");
+    ditto.arg(&dmg.app).arg(staging.join(&app_name));
+    process::run_tool(&mut ditto, "ditto", cancel, log).await?;
+    #[cfg(unix)]
+    tokio::fs::symlink("/Applications", staging.join("Applications")).await?;
+    let background_name = match &dmg.background {
+        Some(background) => {
+            let name = background.file_name().unwrap_or_default().to_string_lossy().to_string();
+            tokio::fs::create_dir_all(staging.join(".background")).await?;
+            tokio::fs::copy(background, staging.join(".background").join(&name))
+                .await
+                .with_context(|| format!("Failed to copy {}", background.display()))?;
+            Some(name)
+        }
+        None => None,
+    };
+
+    let writable = scratch.0.join("writable.dmg");
+    log.line(&format!("[dmg] Creating volume {}", dmg.volume_name)).await;
+    let mut create = Command::new("hdiutil");
+    create
+        .args(["create", "-volname", &dmg.volume_name, "-fs", "HFS+", "-format", "UDRW", "-ov", "-srcfolder"])
+        .arg(&staging)
+        .arg(&writable);
+    process::run_tool(&mut create, "hdiutil create", cancel, log).await?;
+
+    if dmg.has_layout() {
+        let mount = scratch.0.join("mount");
+        let mut attach = Command::new("hdiutil");
+        attach
+            .args(["attach", "-readwrite", "-noverify", "-noautoopen", "-nobrowse", "-mountpoint"])
+            .arg(&mount)
+            .arg(&writable);
+        tokio::fs::create_dir_all(&mount).await?;
+        // Set before attaching, so a cancel while attaching still detaches
+        let mut mounted = Mounted(Some(mount.clone()));
+        process::run_tool(&mut attach, "hdiutil attach", cancel, log).await?;
+
+        log.line("[dmg] Laying out the window").await;
+        let mut osascript = Command::new("osascript");
+        osascript.args(["-e", &layout_script(dmg, &app_name, background_name.as_deref())]);
+        process::run_tool(&mut osascript, "osascript", cancel, log).await?;
+
+        let mut detach = Command::new("hdiutil");
+        detach.arg("detach").arg(&mount);
+        process::run_tool(&mut detach, "hdiutil detach", cancel, log).await?;
+        mounted.0 = None;
+    }
+
+    if let Some(dir) = dmg.output.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    log.line(&format!("[dmg] Compressing into {}", dmg.output.display())).await;
+    let mut convert = Command::new("hdiutil");
+    convert
+        .arg("convert")
+        .arg(&writable)
+        .args(["-format", "UDZO", "-imagekey", "zlib-level=9", "-ov", "-o"])
+        .arg(&dmg.output);
+    process::run_tool(&mut convert, "hdiutil convert", cancel, log).await?;
+    Ok(())
+}
+
+/// AppleScript that has Finder arrange the mounted volume
+fn layout_script(dmg: &Dmg, app_name: &str, background: Option<&str>) -> String {
+    let (width, height) = dmg.window_size;
+    let mut script = format!(
+        "tell application \"Finder\"\n\
+         tell disk \"{}\"\n\
+         open\n\
+         set current view of container window to icon view\n\
+         set toolbar visible of container window to false\n\
+         set statusbar visible of container window to false\n\
+         set the bounds of container window to {{100, 100, {}, {}}}\n\
+         set viewOptions to the icon view options of container window\n\
+         set arrangement of viewOptions to not arranged\n\
+         set icon size of viewOptions to 128\n",
+        quote(&dmg.volume_name),
+        100 + width,
+        100 + height
+    );
+    if let Some(background) = background {
+        script.push_str(&format!(
+            "set background picture of viewOptions to file \".background:{}\"\n",
+            quote(background)
+        ));
+    }
+    if let Some((x, y)) = dmg.app_position {
+        script.push_str(&format!("set position of item \"{}\" of container window to {{{}, {}}}\n", quote(app_name), x, y));
+    }
+    if let Some((x, y)) = dmg.applications_position {
+        script.push_str(&format!("set position of item \"Applications\" of container window to {{{}, {}}}\n", x, y));
+    }
+    script.push_str("close\nupdate without registering applications\nend tell\nend tell\n");
+    script
+}
+
+/// Escapes text for an AppleScript string literal
+fn quote(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Force-detaches the volume mounted at the path, if still set, when dropped
+struct Mounted(Option<PathBuf>);
+
+impl Drop for Mounted {
+    fn drop(&mut self) {
+        let Some(mount) = self.0.take() else {
+            return;
+        };
+        // Waited for, the scratch directory holding the mount goes next
+        if let Err(e) = detach(&mount) {
+            warn!("Failed to detach {}: {}", mount.display(), e);
+        }
+    }
+}
+
+fn detach(mount: &Path) -> Result<()> {
+    let status = std::process::Command::new("hdiutil")
+        .args(["detach", "-force"])
+        .arg(mount)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("hdiutil detach exited with {}", status);
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! Image builds for the `docker_build` node.
+//!
+//! One platform or none builds with plain `docker build` and pushes each tag
+//! with `docker push`. More than one goes through `docker buildx`, which can
+//! only keep a multi-platform image by pushing it.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::build_log::BuildLog;
+use crate::builds::CancelToken;
+use crate::process;
+
+pub struct ImageBuild {
+    pub dockerfile: String,
+    pub context: String,
+    pub tags: Vec<String>,
+    pub build_args: Vec<(String, String)>,
+    pub platforms: Vec<String>,
+    pub push: bool,
+}
+
+impl ImageBuild {
+    pub fn uses_buildx(&self) -> bool {
+        self.platforms.len() > 1
+    }
+}
+
+/// Credentials for `docker login`
+pub struct Login {
+    /// Docker Hub if unset
+    pub registry: Option<String>,
+    pub username: String,
+    pub password: String,
+}
+
+/// Fails unless docker, and buildx if `buildx` is set, can be run
+pub async fn check_available(buildx: bool) -> Result<()> {
+    which::which("docker").map_err(|_| anyhow::anyhow!("docker is not installed on the server"))?;
+    if buildx {
+        let version = Command::new("docker").args(["buildx", "version"]).output().await?;
+        if !version.status.success() {
+            anyhow::bail!("Building for more than one platform needs docker buildx, which is not installed");
+        }
+    }
+    Ok(())
+}
+
+pub async fn login(login: &Login, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    let registry = login.registry.as_deref().unwrap_or("Docker Hub");
+    log.line(&format!("[docker] Logging in to {} as {}", registry, login.username)).await;
+    let mut command = Command::new("docker");
+    command.args(["login", "--username", &login.username, "--password-stdin"]);
+    if let Some(registry) = &login.registry {
+        command.arg(registry);
+    }
+    let mut child = process::configure(&mut command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // The password never goes on the command line, where `ps` would show it
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(login.password.as_bytes()).await?;
+    }
+    process::run(child, cancel, log).await?.check("Docker login")?;
+    Ok(())
+}
+
+/// Builds `image` in `dir`, pushing it if asked to, and returns the digest of
+/// the pushed image
+pub async fn build(dir: &Path, image: &ImageBuild, cancel: &CancelToken, log: &BuildLog) -> Result<Option<String>> {
+    let metadata = metadata_path();
+    let mut command = Command::new("docker");
+    if image.uses_buildx() {
+        command.args(["buildx", "build", "--platform", &image.platforms.join(",")]);
+        command.arg("--metadata-file").arg(&metadata);
+        if image.push {
+            command.arg("--push");
+        } else {
+            log.line("[docker] A multi-platform image is only kept by pushing it, this build only checks that it builds").await;
+        }
+    } else {
+        command.arg("build");
+        if let Some(platform) = image.platforms.first() {
+            command.args(["--platform", platform]);
+        }
+    }
+    command.args(["--file", &image.dockerfile]);
+    for tag in &image.tags {
+        command.args(["--tag", tag]);
+    }
+    for (key, value) in &image.build_args {
+        command.arg("--build-arg").arg(format!("{}={}", key, value));
+    }
+    command.arg(&image.context);
+
+    log.line(&format!("[docker] Building {}", image.tags.join(", "))).await;
+    let child = process::configure(&mut command)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let built = process::run(child, cancel, log).await?.check("Docker build");
+    let metadata_json = tokio::fs::read_to_string(&metadata).await.ok();
+    let _ = tokio::fs::remove_file(&metadata).await;
+    built?;
+
+    if !image.push {
+        return Ok(None);
+    }
+    if image.uses_buildx() {
+        let digest = metadata_json
+            .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+            .and_then(|m| m.get("containerimage.digest")?.as_str().map(str::to_string));
+        return Ok(digest);
+    }
+
+    for tag in &image.tags {
+        log.line(&format!("[docker] Pushing {}", tag)).await;
+        let child = process::configure(Command::new("docker").args(["push", tag]))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        process::run(child, cancel, log).await?.check("Docker push")?;
+    }
+    let Some(tag) = image.tags.first() else {
+        return Ok(None);
+    };
+    pushed_digest(tag).await.map(Some)
+}
+
+/// Digest the registry gave the pushed `tag`
+async fn pushed_digest(tag: &str) -> Result<String> {
+    let output = Command::new("docker")
+        .args(["image", "inspect", "--format", "{{json .RepoDigests}}", tag])
+        .output()
+        .await?;
+    if !output.status.success() {
+        anyhow::bail!("Could not inspect {}: {}", tag, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let repo_digests: Vec<String> = serde_json::from_slice(&output.stdout).context("Unexpected docker inspect output")?;
+    // Entries look like `registry/name@sha256:...`, one per repository
+    let repository = repository_of(tag);
+    repo_digests
+        .iter()
+        .filter_map(|d| d.split_once('@'))
+        .find(|(name, _)| *name == repository)
+        .or_else(|| repo_digests.first().and_then(|d| d.split_once('@')))
+        .map(|(_, digest)| digest.to_string())
+        .with_context(|| format!("docker reported no digest for {}", tag))
+}
+
+/// `tag` without its `:tag` part; a `:` before the last `/` is a registry port
+fn repository_of(tag: &str) -> &str {
+    match tag.rfind(':') {
+        Some(i) if !tag[i..].contains('/') => &tag[..i],
+        _ => tag,
+    }
+}
+
+/// Where buildx writes what it built, including the digest
+fn metadata_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("buildforge-docker-{}.json", uuid::Uuid::new_v4()))
+}
@@ -0,0 +1,112 @@
+//! Snapshot of the toolchain a build runs with, recorded on its history entry
+//! so two runs can be compared.
+
+use std::collections::{BTreeSet, HashMap};
+use std::process::Stdio;
+use std::time::Duration;
+
+use futures_util::future::join_all;
+use tokio::process::Command;
+
+use crate::BuildNode;
+
+/// How long a single `--version` probe may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A tool whose version is worth recording
+pub struct Tool {
+    pub name: &'static str,
+    /// Words in a command, script or node type that mean the tool is used
+    pub triggers: &'static [&'static str],
+    pub program: &'static str,
+    pub args: &'static [&'static str],
+}
+
+pub const TOOLS: &[Tool] = &[
+    Tool { name: "rustc", triggers: &["cargo", "rustc", "rustup", "cargo_publish", "cargo_build"], program: "rustc", args: &["--version"] },
+    Tool { name: "cargo", triggers: &["cargo", "cargo_publish", "cargo_build"], program: "cargo", args: &["--version"] },
+    Tool { name: "node", triggers: &["node", "npm", "npx", "yarn", "pnpm", "npm_publish"], program: "node", args: &["--version"] },
+    Tool { name: "npm", triggers: &["npm", "npx", "npm_publish"], program: "npm", args: &["--version"] },
+    Tool { name: "yarn", triggers: &["yarn"], program: "yarn", args: &["--version"] },
+    Tool { name: "pnpm", triggers: &["pnpm"], program: "pnpm", args: &["--version"] },
+    Tool { name: "go", triggers: &["go"], program: "go", args: &["version"] },
+    Tool { name: "python", triggers: &["python", "python3", "pip", "pip3"], program: "python3", args: &["--version"] },
+    Tool { name: "java", triggers: &["java", "javac", "gradle", "gradlew", "mvn"], program: "java", args: &["-version"] },
+    Tool { name: "dotnet", triggers: &["dotnet"], program: "dotnet", args: &["--version"] },
+    Tool { name: "docker", triggers: &["docker", "docker_build"], program: "docker", args: &["--version"] },
+    Tool { name: "git", triggers: &["git", "git_tag", "changelog", "version_bump"], program: "git", args: &["--version"] },
+    Tool { name: "make", triggers: &["make"], program: "make", args: &["--version"] },
+    Tool { name: "cmake", triggers: &["cmake"], program: "cmake", args: &["--version"] },
+    Tool { name: "gcc", triggers: &["gcc", "g++"], program: "gcc", args: &["--version"] },
+    Tool { name: "clang", triggers: &["clang", "clang++"], program: "clang", args: &["--version"] },
+];
+
+/// Tools referenced by the node types, commands and scripts of a workflow
+pub fn referenced_tools(nodes: &[BuildNode]) -> Vec<&'static Tool> {
+    let mut words: BTreeSet<String> = BTreeSet::new();
+    for node in nodes {
+        words.insert(node.node_type.clone());
+        for key in ["command", "script"] {
+            if let Some(text) = node.config.get(key).and_then(|v| v.as_str()) {
+                words.extend(
+                    text.split(|c: char| !(c.is_alphanumeric() || matches!(c, '-' | '_' | '+' | '.' | '/')))
+                        .filter(|w| !w.is_empty())
+                        .map(|w| w.rsplit('/').next().unwrap_or(w).to_string()),
+                );
+            }
+        }
+    }
+    TOOLS
+        .iter()
+        .filter(|tool| tool.triggers.iter().any(|t| words.contains(*t)))
+        .collect()
+}
+
+/// OS, architecture and the versions of every tool the workflow uses. Probes
+/// run concurrently and each is cut off after [`PROBE_TIMEOUT`].
+pub async fn snapshot(nodes: &[BuildNode]) -> HashMap<String, String> {
+    let mut snapshot = HashMap::from([
+        ("os".to_string(), std::env::consts::OS.to_string()),
+        ("arch".to_string(), std::env::consts::ARCH.to_string()),
+        ("buildforge".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+    ]);
+
+    let tools = referenced_tools(nodes);
+    let versions = join_all(tools.iter().map(|tool| probe(tool))).await;
+    for (tool, version) in tools.iter().zip(versions) {
+        snapshot.insert(tool.name.to_string(), version);
+    }
+    snapshot
+}
+
+/// Runs the tool's version command and returns its first line of output
+pub async fn probe(tool: &Tool) -> String {
+    probe_program(tool.program, tool.args).await.unwrap_or_else(|why| why)
+}
+
+/// First line `program` prints when run with `args`, or why there is none
+pub async fn probe_program(program: &str, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    match tokio::time::timeout(PROBE_TIMEOUT, output).await {
+        Err(_) => Err("timed out".to_string()),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => Err("not found".to_string()),
+        Ok(Err(e)) => Err(format!("error: {}", e)),
+        Ok(Ok(output)) => {
+            // Some tools, e.g. java, print their version on stderr
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Ok(stdout
+                .lines()
+                .chain(stderr.lines())
+                .map(str::trim)
+                .find(|l| !l.is_empty())
+                .unwrap_or("unknown")
+                .to_string())
+        }
+    }
+}
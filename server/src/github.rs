@@ -0,0 +1,573 @@
+//! Calls to the GitHub REST API made by build nodes.
+//!
+//! Every call goes through [`GitHubEndpoints`], so a GitHub Enterprise Server
+//! can be used instead of github.com. Its base URLs are taken from the node,
+//! then the workflow's repo, then the server settings.
+
+use anyhow::Result;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+const API_BASE: &str = "https://api.github.com";
+const UPLOAD_BASE: &str = "https://uploads.github.com";
+
+const NOT_FOUND: u16 = 404;
+
+/// Error response of the GitHub API, turned into a message a user can act on
+#[derive(Debug, thiserror::Error)]
+#[error("GitHub API error ({status}): {message}")]
+pub struct GitHubError {
+    pub status: u16,
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    message: String,
+    #[serde(default)]
+    errors: Vec<ErrorDetail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetail {
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    field: Option<String>,
+}
+
+/// Body of `POST /repos/{owner}/{repo}/releases`, and of the update of an
+/// existing release
+#[derive(Debug, Clone, Serialize)]
+pub struct NewRelease {
+    pub tag_name: String,
+    pub name: String,
+    pub body: String,
+    pub draft: bool,
+    pub prerelease: bool,
+    /// Branch or commit the tag is created from if it does not exist yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_commitish: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    pub id: u64,
+    pub tag_name: String,
+    pub html_url: String,
+    #[serde(default)]
+    pub assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReleaseAsset {
+    pub name: String,
+}
+
+/// Base URLs of the GitHub instance to talk to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitHubEndpoints {
+    pub api_base: String,
+    /// Release assets go to a separate host
+    pub upload_base: String,
+}
+
+impl Default for GitHubEndpoints {
+    fn default() -> Self {
+        Self {
+            api_base: API_BASE.to_string(),
+            upload_base: UPLOAD_BASE.to_string(),
+        }
+    }
+}
+
+impl GitHubEndpoints {
+    /// Endpoints for the first `(api_base_url, upload_base_url)` pair that
+    /// sets an API URL, or github.com. A GitHub Enterprise Server API URL
+    /// like `https://host/api/v3` implies uploads at `https://host/api/uploads`.
+    pub fn resolve<'a>(candidates: impl IntoIterator<Item = (Option<&'a str>, Option<&'a str>)>) -> Self {
+        for (api_base, upload_base) in candidates {
+            let Some(api_base) = api_base.map(|s| s.trim().trim_end_matches('/')).filter(|s| !s.is_empty()) else {
+                continue;
+            };
+            let upload_base = match upload_base.map(|s| s.trim().trim_end_matches('/')).filter(|s| !s.is_empty()) {
+                Some(upload_base) => upload_base.to_string(),
+                None => match api_base.strip_suffix("/api/v3") {
+                    Some(host) => format!("{}/api/uploads", host),
+                    None if api_base == API_BASE => UPLOAD_BASE.to_string(),
+                    None => api_base.to_string(),
+                },
+            };
+            return Self {
+                api_base: api_base.to_string(),
+                upload_base,
+            };
+        }
+        Self::default()
+    }
+}
+
+/// Joins a base URL and a path without doubling or dropping the slash
+fn join_url(base: &str, path: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+/// How `ensure_release` arrived at the release it returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReleaseAction {
+    /// Neither the tag nor a release existed
+    Created,
+    /// The tag existed without a release, which now points at it
+    CreatedForExistingTag,
+    /// A release for the tag existed and was left as it was
+    Reused,
+    /// A release for the tag existed and was updated to match the node
+    Updated,
+}
+
+pub struct GitHubClient {
+    http: reqwest::Client,
+    token: String,
+    endpoints: GitHubEndpoints,
+}
+
+impl GitHubClient {
+    pub fn new(token: &str, endpoints: GitHubEndpoints) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            token: token.to_string(),
+            endpoints,
+        }
+    }
+
+    fn request(&self, method: Method, url: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, url)
+            .bearer_auth(&self.token)
+            .header(reqwest::header::USER_AGENT, concat!("BuildForge/", env!("CARGO_PKG_VERSION")))
+            .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+            .header("X-GitHub-Api-Version", "2022-11-28")
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&impl Serialize>,
+        repository: &str,
+    ) -> Result<T> {
+        let mut request = self.request(method, &join_url(&self.endpoints.api_base, path));
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        parse_response(request.send().await?, repository).await
+    }
+
+    /// GETs a resource that may legitimately not exist
+    async fn get_optional<T: DeserializeOwned>(&self, path: &str, repository: &str) -> Result<Option<T>> {
+        match self.call(Method::GET, path, None::<&()>, repository).await {
+            Ok(found) => Ok(Some(found)),
+            Err(e) if e.downcast_ref::<GitHubError>().is_some_and(|e| e.status == NOT_FOUND) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The release for `tag`, drafts included
+    pub async fn find_release(&self, owner: &str, repo: &str, tag: &str) -> Result<Option<Release>> {
+        // Drafts have no tag yet, so the by-tag endpoint does not find them
+        let path = format!("/repos/{}/{}/releases?per_page=100", owner, repo);
+        let releases: Vec<Release> = self
+            .call(Method::GET, &path, None::<&()>, &format!("{}/{}", owner, repo))
+            .await?;
+        Ok(releases.into_iter().find(|r| r.tag_name == tag))
+    }
+
+    pub async fn tag_exists(&self, owner: &str, repo: &str, tag: &str) -> Result<bool> {
+        let path = format!("/repos/{}/{}/git/ref/tags/{}", owner, repo, tag);
+        let found: Option<serde_json::Value> = self.get_optional(&path, &format!("{}/{}", owner, repo)).await?;
+        Ok(found.is_some())
+    }
+
+    pub async fn create_release(&self, owner: &str, repo: &str, release: &NewRelease) -> Result<Release> {
+        let path = format!("/repos/{}/{}/releases", owner, repo);
+        self.call(Method::POST, &path, Some(release), &format!("{}/{}", owner, repo)).await
+    }
+
+    pub async fn update_release(&self, owner: &str, repo: &str, id: u64, release: &NewRelease) -> Result<Release> {
+        let path = format!("/repos/{}/{}/releases/{}", owner, repo, id);
+        self.call(Method::PATCH, &path, Some(release), &format!("{}/{}", owner, repo)).await
+    }
+
+    /// Returns the release for `release.tag_name`, creating it if needed.
+    /// An existing release is only changed if `update_existing` is set, and
+    /// never deleted.
+    pub async fn ensure_release(
+        &self,
+        owner: &str,
+        repo: &str,
+        release: &NewRelease,
+        update_existing: bool,
+    ) -> Result<(Release, ReleaseAction)> {
+        if let Some(existing) = self.find_release(owner, repo, &release.tag_name).await? {
+            if !update_existing {
+                return Ok((existing, ReleaseAction::Reused));
+            }
+            // The tag already points somewhere; moving it is not an update
+            let update = NewRelease {
+                target_commitish: None,
+                ..release.clone()
+            };
+            let updated = self.update_release(owner, repo, existing.id, &update).await?;
+            return Ok((updated, ReleaseAction::Updated));
+        }
+
+        if self.tag_exists(owner, repo, &release.tag_name).await? {
+            let for_tag = NewRelease {
+                target_commitish: None,
+                ..release.clone()
+            };
+            let created = self.create_release(owner, repo, &for_tag).await?;
+            return Ok((created, ReleaseAction::CreatedForExistingTag));
+        }
+
+        let created = self.create_release(owner, repo, release).await?;
+        Ok((created, ReleaseAction::Created))
+    }
+
+    /// Uploads a file as an asset of `release`
+    pub async fn upload_asset(&self, owner: &str, repo: &str, release: &Release, path: &std::path::Path) -> Result<()> {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .ok_or_else(|| anyhow::anyhow!("{} has no file name", path.display()))?;
+        let content = tokio::fs::read(path).await?;
+        let url = join_url(
+            &self.endpoints.upload_base,
+            &format!("/repos/{}/{}/releases/{}/assets", owner, repo, release.id),
+        );
+        let request = self
+            .request(Method::POST, &url)
+            .query(&[("name", name.as_str())])
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(content);
+        let _: serde_json::Value = parse_response(request.send().await?, &format!("{}/{}", owner, repo)).await?;
+        Ok(())
+    }
+}
+
+async fn parse_response<T: DeserializeOwned>(response: reqwest::Response, repository: &str) -> Result<T> {
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap_or_default();
+    parse_body(status, &body, repository)
+}
+
+/// The value of a successful response, or what went wrong
+fn parse_body<T: DeserializeOwned>(status: u16, body: &str, repository: &str) -> Result<T> {
+    if (200..300).contains(&status) {
+        return Ok(serde_json::from_str(body)?);
+    }
+    Err(api_error(status, body, repository).into())
+}
+
+/// Explains a failed API request
+fn api_error(status: u16, body: &str, repository: &str) -> GitHubError {
+    let parsed: Option<ErrorBody> = serde_json::from_str(body).ok();
+    let message = match (status, &parsed) {
+        (401, _) => "Bad credentials: the GitHub token is invalid or expired".to_string(),
+        (403, Some(e)) => format!("The GitHub token may not do this in {}: {}", repository, e.message),
+        (404, _) => format!(
+            "Repository {} not found, or the GitHub token has no access to it",
+            repository
+        ),
+        (422, Some(e)) if e.errors.iter().any(|d| {
+            d.code.as_deref() == Some("already_exists") && d.field.as_deref() == Some("tag_name")
+        }) =>
+        {
+            format!("A release for this tag already exists in {}", repository)
+        }
+        (_, Some(e)) => e.message.clone(),
+        (_, None) => body.trim().to_string(),
+    };
+    GitHubError { status, message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_bytes, body_json, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const RELEASE: &str = r#"{
+        "id": 1,
+        "tag_name": "v1.0.0",
+        "name": "v1.0.0",
+        "draft": false,
+        "html_url": "https://github.com/octo/app/releases/tag/v1.0.0",
+        "assets": [{"id": 7, "name": "app.tar.gz", "size": 1024}]
+    }"#;
+    const ALREADY_EXISTS: &str = r#"{
+        "message": "Validation Failed",
+        "errors": [{"resource": "Release", "code": "already_exists", "field": "tag_name"}],
+        "documentation_url": "https://docs.github.com/rest/releases/releases#create-a-release"
+    }"#;
+    const FORBIDDEN: &str = r#"{"message": "Resource not accessible by personal access token"}"#;
+    const BAD_CREDENTIALS: &str = r#"{"message": "Bad credentials"}"#;
+    const NOT_FOUND_BODY: &str = r#"{"message": "Not Found"}"#;
+
+    fn error(status: u16, body: &str) -> GitHubError {
+        parse_body::<Release>(status, body, "octo/app")
+            .unwrap_err()
+            .downcast::<GitHubError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_a_release() {
+        let release: Release = parse_body(201, RELEASE, "octo/app").unwrap();
+        assert_eq!(release.id, 1);
+        assert_eq!(release.tag_name, "v1.0.0");
+        assert_eq!(release.html_url, "https://github.com/octo/app/releases/tag/v1.0.0");
+        assert_eq!(release.assets.len(), 1);
+        assert_eq!(release.assets[0].name, "app.tar.gz");
+    }
+
+    #[test]
+    fn a_release_without_assets_has_none() {
+        let release: Release = parse_body(200, r#"{"id": 2, "tag_name": "v2", "html_url": "u"}"#, "octo/app").unwrap();
+        assert!(release.assets.is_empty());
+    }
+
+    #[test]
+    fn explains_an_existing_release() {
+        let e = error(422, ALREADY_EXISTS);
+        assert_eq!(e.status, 422);
+        assert_eq!(e.message, "A release for this tag already exists in octo/app");
+    }
+
+    #[test]
+    fn explains_auth_and_access_errors() {
+        assert_eq!(error(401, BAD_CREDENTIALS).message, "Bad credentials: the GitHub token is invalid or expired");
+        assert_eq!(
+            error(403, FORBIDDEN).message,
+            "The GitHub token may not do this in octo/app: Resource not accessible by personal access token"
+        );
+        assert_eq!(
+            error(404, NOT_FOUND_BODY).message,
+            "Repository octo/app not found, or the GitHub token has no access to it"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_message_or_the_body() {
+        assert_eq!(error(500, r#"{"message": "Server Error"}"#).message, "Server Error");
+        assert_eq!(error(502, "  Bad Gateway\n").message, "Bad Gateway");
+    }
+
+    #[test]
+    fn a_malformed_success_is_an_error() {
+        let e = parse_body::<Release>(200, "<html>", "octo/app").unwrap_err();
+        assert!(e.downcast_ref::<GitHubError>().is_none());
+    }
+
+    fn new_release(target_commitish: Option<&str>) -> NewRelease {
+        NewRelease {
+            tag_name: "v1.0.0".to_string(),
+            name: "v1.0.0".to_string(),
+            body: "Notes".to_string(),
+            draft: false,
+            prerelease: true,
+            target_commitish: target_commitish.map(str::to_string),
+        }
+    }
+
+    fn release_json(target_commitish: Option<&str>) -> serde_json::Value {
+        serde_json::to_value(new_release(target_commitish)).unwrap()
+    }
+
+    /// A client for a mock GitHub whose uploads go to `/uploads`, the way a
+    /// GitHub Enterprise Server's do
+    fn client(github: &MockServer) -> GitHubClient {
+        let endpoints = GitHubEndpoints {
+            api_base: github.uri(),
+            upload_base: format!("{}/uploads", github.uri()),
+        };
+        GitHubClient::new("secret-token", endpoints)
+    }
+
+    /// Answers the release list with `releases`
+    async fn releases(github: &MockServer, releases: &str) {
+        Mock::given(method("GET"))
+            .and(path("/repos/octo/app/releases"))
+            .and(query_param("per_page", "100"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(releases, "application/json"))
+            .mount(github)
+            .await;
+    }
+
+    /// Answers the lookup of the tag `v1.0.0`
+    async fn tag(github: &MockServer, exists: bool) {
+        let response = match exists {
+            true => ResponseTemplate::new(200).set_body_raw(r#"{"ref": "refs/tags/v1.0.0"}"#, "application/json"),
+            false => ResponseTemplate::new(404).set_body_raw(NOT_FOUND_BODY, "application/json"),
+        };
+        Mock::given(method("GET"))
+            .and(path("/repos/octo/app/git/ref/tags/v1.0.0"))
+            .respond_with(response)
+            .mount(github)
+            .await;
+    }
+
+    /// Expects `count` releases created with `body`
+    async fn creates(github: &MockServer, body: serde_json::Value, count: u64) {
+        Mock::given(method("POST"))
+            .and(path("/repos/octo/app/releases"))
+            .and(body_json(body))
+            .respond_with(ResponseTemplate::new(201).set_body_raw(RELEASE, "application/json"))
+            .expect(count)
+            .mount(github)
+            .await;
+    }
+
+    /// `app.tar.gz`, in a directory that goes away with the returned `TempDir`
+    fn archive() -> (crate::process::TempDir, std::path::PathBuf) {
+        let dir = crate::process::TempDir::new("test");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let file = dir.0.join("app.tar.gz");
+        std::fs::write(&file, "archive").unwrap();
+        (dir, file)
+    }
+
+    #[test]
+    fn a_new_release_names_a_target_only_if_it_has_one() {
+        let json = release_json(None);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "tag_name": "v1.0.0",
+                "name": "v1.0.0",
+                "body": "Notes",
+                "draft": false,
+                "prerelease": true,
+            })
+        );
+        assert_eq!(release_json(Some("main"))["target_commitish"], "main");
+    }
+
+    #[tokio::test]
+    async fn requests_are_authenticated_and_versioned() {
+        let github = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/repos/octo/app/releases"))
+            .and(header("authorization", "Bearer secret-token"))
+            .and(header("accept", "application/vnd.github+json"))
+            .and(header("x-github-api-version", "2022-11-28"))
+            .and(header("user-agent", concat!("BuildForge/", env!("CARGO_PKG_VERSION"))))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("[]", "application/json"))
+            .expect(1)
+            .mount(&github)
+            .await;
+        assert!(client(&github).find_release("octo", "app", "v1.0.0").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn a_release_is_created_with_its_tag() {
+        let github = MockServer::start().await;
+        releases(&github, "[]").await;
+        tag(&github, false).await;
+        creates(&github, release_json(Some("main")), 1).await;
+
+        let (release, action) = client(&github)
+            .ensure_release("octo", "app", &new_release(Some("main")), false)
+            .await
+            .unwrap();
+        assert_eq!((release.id, action), (1, ReleaseAction::Created));
+    }
+
+    #[tokio::test]
+    async fn a_release_for_an_existing_tag_leaves_the_tag_where_it_is() {
+        let github = MockServer::start().await;
+        releases(&github, "[]").await;
+        tag(&github, true).await;
+        creates(&github, release_json(None), 1).await;
+
+        let (_, action) = client(&github)
+            .ensure_release("octo", "app", &new_release(Some("main")), false)
+            .await
+            .unwrap();
+        assert_eq!(action, ReleaseAction::CreatedForExistingTag);
+    }
+
+    #[tokio::test]
+    async fn an_existing_release_is_reused_or_updated() {
+        let github = MockServer::start().await;
+        releases(&github, &format!("[{}]", RELEASE)).await;
+        creates(&github, release_json(Some("main")), 0).await;
+        Mock::given(method("PATCH"))
+            .and(path("/repos/octo/app/releases/1"))
+            .and(body_json(release_json(None)))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(RELEASE, "application/json"))
+            .expect(1)
+            .mount(&github)
+            .await;
+
+        let client = client(&github);
+        let (_, action) = client.ensure_release("octo", "app", &new_release(Some("main")), false).await.unwrap();
+        assert_eq!(action, ReleaseAction::Reused);
+        let (_, action) = client.ensure_release("octo", "app", &new_release(Some("main")), true).await.unwrap();
+        assert_eq!(action, ReleaseAction::Updated);
+    }
+
+    #[tokio::test]
+    async fn assets_go_to_the_upload_host() {
+        let github = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/uploads/repos/octo/app/releases/1/assets"))
+            .and(query_param("name", "app.tar.gz"))
+            .and(header("content-type", "application/octet-stream"))
+            .and(header("authorization", "Bearer secret-token"))
+            .and(body_bytes(b"archive".to_vec()))
+            .respond_with(ResponseTemplate::new(201).set_body_raw(r#"{"id": 7, "name": "app.tar.gz"}"#, "application/json"))
+            .expect(1)
+            .mount(&github)
+            .await;
+
+        let (_dir, file) = archive();
+        let release: Release = parse_body(200, RELEASE, "octo/app").unwrap();
+        client(&github).upload_asset("octo", "app", &release, &file).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_explained() {
+        let github = MockServer::start().await;
+        releases(&github, "[]").await;
+        tag(&github, false).await;
+        Mock::given(method("POST"))
+            .and(path("/repos/octo/app/releases"))
+            .respond_with(ResponseTemplate::new(422).set_body_raw(ALREADY_EXISTS, "application/json"))
+            .mount(&github)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/uploads/repos/octo/app/releases/1/assets"))
+            .respond_with(ResponseTemplate::new(401).set_body_raw(BAD_CREDENTIALS, "application/json"))
+            .mount(&github)
+            .await;
+
+        let client = client(&github);
+        let e = client.ensure_release("octo", "app", &new_release(None), false).await.unwrap_err();
+        let e = e.downcast::<GitHubError>().unwrap();
+        assert_eq!((e.status, e.message.as_str()), (422, "A release for this tag already exists in octo/app"));
+
+        let (_dir, file) = archive();
+        let release: Release = parse_body(200, RELEASE, "octo/app").unwrap();
+        let e = client.upload_asset("octo", "app", &release, &file).await.unwrap_err();
+        assert_eq!(e.downcast::<GitHubError>().unwrap().status, 401);
+
+        // Nothing is mocked for this repository, so the mock answers 404
+        let missing = client.find_release("octo", "other", "v1.0.0").await.unwrap_err();
+        assert_eq!(
+            missing.to_string(),
+            "GitHub API error (404): Repository octo/other not found, or the GitHub token has no access to it"
+        );
+    }
+}
@@ -0,0 +1,1294 @@
+//! Workflows to and from GitHub Actions.
+//!
+//! `ExportWorkflowAsGitHubActions` writes a stored workflow as the YAML of a
+//! GitHub Actions workflow, for `.github/workflows/`. Nodes are taken in the order they run. A chain of nodes, each with the
+//! one before it as its only dependency, becomes one job; where the graph
+//! branches or joins, a new job starts that `needs` the jobs before it.
+//! Command, script, test and action nodes become `run:` steps, artifact
+//! nodes `actions/upload-artifact`, release nodes
+//! `softprops/action-gh-release` and env nodes steps that write
+//! `$GITHUB_ENV`. Build variables and `${secret:NAME}` become expressions
+//! such as `${{ github.sha }}` and `${{ secrets.NAME }}`. Nodes with no
+//! counterpart are kept as comments in their place, and everything lost on
+//! the way is listed in the warnings.
+//!
+//! `ImportGitHubActionsWorkflow` goes the other way, for projects moving
+//! over. Each job becomes a chain of nodes, joined to the chains of the jobs
+//! it `needs`. `run:` steps become command or script nodes; checkout is left
+//! to the workflow's repository, and upload-artifact, cache and
+//! action-gh-release become their nodes. Any other action becomes a
+//! placeholder node that keeps its `uses:` and fails the build until it is
+//! replaced. The draft is returned, not stored, along with a report of what
+//! did not convert; the app saves it with `SaveWorkflow` once reviewed.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_yaml::{Mapping, Value as Yaml};
+
+use crate::builds::{self, RunOn};
+use crate::container::ContainerConfig;
+use crate::webhooks::{Trigger, TriggerEvent};
+use crate::{BuildNode, ServerData, StoredAction, StoredWorkflow};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitHubActionsExport {
+    pub workflow_id: String,
+    /// Suggested name under `.github/workflows/`
+    pub file_name: String,
+    pub yaml: String,
+    /// What did not carry over exactly
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportGitHubActionsRequest {
+    pub yaml: String,
+}
+
+/// A workflow made from GitHub Actions YAML, not yet stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitHubActionsImport {
+    pub workflow: StoredWorkflow,
+    /// What was not converted, or only in part
+    pub report: Vec<ImportNote>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportNote {
+    /// None for the workflow as a whole
+    pub job: Option<String>,
+    pub step: Option<String>,
+    pub message: String,
+}
+
+/// Shells GitHub Actions knows by name
+const SHELLS: [&str; 6] = ["bash", "sh", "pwsh", "powershell", "cmd", "python"];
+
+struct Job {
+    id: String,
+    needs: Vec<String>,
+    condition: Option<&'static str>,
+    nodes: Vec<usize>,
+}
+
+pub fn export(data: &ServerData, id_or_name: &str) -> Result<GitHubActionsExport> {
+    let workflow = data.workflow(id_or_name)?;
+    let build = builds::from_workflow(workflow, workflow.next_version.clone(), None, HashMap::new(), false)?;
+    let nodes = &build.nodes;
+    let index: HashMap<&str, usize> = nodes.iter().enumerate().map(|(i, n)| (n.id.as_str(), i)).collect();
+    let mut before: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    let mut after: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    for edge in &build.edges {
+        let (Some(&source), Some(&target)) = (index.get(edge.source.as_str()), index.get(edge.target.as_str())) else {
+            anyhow::bail!("A connection of {} names a node that does not exist", workflow.name);
+        };
+        before[target].push(source);
+        after[source].push(target);
+    }
+
+    let mut warnings = Vec::new();
+    let order = run_order(nodes, &before, &after)?;
+    let jobs = split_jobs(nodes, &order, &before, &after);
+    if jobs.len() > 1 {
+        warnings.push(format!(
+            "The workflow branches, so it became {} jobs; each runs on a fresh machine and sees only the files it makes or downloads",
+            jobs.len()
+        ));
+    }
+    if workflow.container.as_ref().is_some_and(|c| c.cpus.is_some() || c.memory.is_some()) {
+        warnings.push("The container's cpus and memory limits are not set on GitHub's runners".to_string());
+    }
+
+    let mut yaml = String::new();
+    let _ = writeln!(yaml, "# Exported from the BuildForge workflow {}", workflow.name);
+    let _ = writeln!(yaml, "name: {}", quote(&workflow.name));
+    yaml.push('\n');
+    triggers(&mut yaml, workflow, &mut warnings);
+    yaml.push('\n');
+    if nodes.iter().any(|n| n.node_type == "release") {
+        yaml.push_str("permissions:\n  contents: write\n\n");
+    }
+    yaml.push_str("env:\n");
+    let _ = writeln!(yaml, "  VERSION: {}", quote(&workflow.next_version));
+    yaml.push('\n');
+    yaml.push_str("jobs:\n");
+
+    let job_of: HashMap<usize, usize> = jobs
+        .iter()
+        .enumerate()
+        .flat_map(|(j, job)| job.nodes.iter().map(move |&n| (n, j)))
+        .collect();
+    for (j, job) in jobs.iter().enumerate() {
+        if j > 0 {
+            yaml.push('\n');
+        }
+        let _ = writeln!(yaml, "  {}:", job.id);
+        let first = &nodes[job.nodes[0]];
+        let _ = writeln!(yaml, "    name: {}", quote(&first.name));
+        if !job.needs.is_empty() {
+            let _ = writeln!(yaml, "    needs: [{}]", job.needs.join(", "));
+        }
+        if let Some(condition) = job.condition {
+            let _ = writeln!(yaml, "    if: {}", condition);
+        }
+        yaml.push_str("    runs-on: ubuntu-latest\n");
+        if let Some(container) = &workflow.container {
+            let _ = writeln!(yaml, "    container: {}", quote(&container.image));
+        }
+        yaml.push_str("    steps:\n      - uses: actions/checkout@v4\n");
+        for (position, &n) in job.nodes.iter().enumerate() {
+            // The job's own `if` covers its first node
+            let condition = if position == 0 { None } else { step_condition(&nodes[n]) };
+            let uploads_elsewhere = nodes
+                .iter()
+                .enumerate()
+                .any(|(other, node)| node.node_type == "artifact" && job_of.get(&other) != Some(&job_of[&n]));
+            step(&mut yaml, &nodes[n], condition, &data.actions, uploads_elsewhere, nodes, &mut warnings);
+        }
+    }
+
+    Ok(GitHubActionsExport {
+        workflow_id: workflow.id.clone(),
+        file_name: format!("{}.yml", slug(&workflow.name, '-')),
+        yaml,
+        warnings,
+    })
+}
+
+/// Every node after the nodes it depends on, otherwise in the order they
+/// were added
+fn run_order(nodes: &[BuildNode], before: &[Vec<usize>], after: &[Vec<usize>]) -> Result<Vec<usize>> {
+    let mut waiting: Vec<usize> = before.iter().map(Vec::len).collect();
+    let mut ready: BTreeSet<usize> = (0..nodes.len()).filter(|&n| waiting[n] == 0).collect();
+    let mut order = Vec::with_capacity(nodes.len());
+    while let Some(n) = ready.pop_first() {
+        order.push(n);
+        for &next in &after[n] {
+            waiting[next] -= 1;
+            if waiting[next] == 0 {
+                ready.insert(next);
+            }
+        }
+    }
+    if order.len() < nodes.len() {
+        anyhow::bail!("The workflow's connections form a loop");
+    }
+    Ok(order)
+}
+
+/// Chains of nodes as jobs. A node joins the job of the node before it when
+/// that is its only dependency and it is the only node depending on it.
+fn split_jobs(nodes: &[BuildNode], order: &[usize], before: &[Vec<usize>], after: &[Vec<usize>]) -> Vec<Job> {
+    let mut jobs: Vec<Job> = Vec::new();
+    let mut job_of: HashMap<usize, usize> = HashMap::new();
+    let mut taken = BTreeSet::new();
+    for &n in order {
+        if let [only] = before[n][..] {
+            if after[only].len() == 1 {
+                let j = job_of[&only];
+                jobs[j].nodes.push(n);
+                job_of.insert(n, j);
+                continue;
+            }
+        }
+        let mut needs: Vec<String> = before[n].iter().map(|p| jobs[job_of[p]].id.clone()).collect();
+        needs.sort();
+        needs.dedup();
+        let base = slug(&nodes[n].name, '_');
+        let mut id = if base.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            base
+        } else {
+            format!("job_{}", base)
+        };
+        if !taken.insert(id.clone()) {
+            let mut i = 2;
+            while !taken.insert(format!("{}_{}", id, i)) {
+                i += 1;
+            }
+            id = format!("{}_{}", id, i);
+        }
+        job_of.insert(n, jobs.len());
+        jobs.push(Job {
+            id,
+            needs,
+            condition: step_condition(&nodes[n]),
+            nodes: vec![n],
+        });
+    }
+    jobs
+}
+
+/// `if:` for a node that runs after a failure, or always
+fn step_condition(node: &BuildNode) -> Option<&'static str> {
+    match RunOn::of(node) {
+        Ok(RunOn::Failure) => Some("failure()"),
+        Ok(RunOn::Always) => Some("always()"),
+        _ => None,
+    }
+}
+
+fn triggers(yaml: &mut String, workflow: &StoredWorkflow, warnings: &mut Vec<String>) {
+    yaml.push_str("on:\n  workflow_dispatch:\n");
+    let pushes: Vec<_> = workflow.triggers.iter().filter(|t| t.event == TriggerEvent::Push).collect();
+    if !pushes.is_empty() {
+        yaml.push_str("  push:\n");
+        // A push trigger without patterns takes every branch
+        if !pushes.iter().any(|t| t.branches.is_empty() && t.tags.is_empty()) {
+            let branches: BTreeSet<&String> = pushes.iter().flat_map(|t| &t.branches).collect();
+            let tags: BTreeSet<&String> = pushes.iter().flat_map(|t| &t.tags).collect();
+            if !branches.is_empty() {
+                let _ = writeln!(yaml, "    branches: [{}]", list(branches));
+            }
+            if !tags.is_empty() {
+                let _ = writeln!(yaml, "    tags: [{}]", list(tags));
+            }
+        }
+    }
+    let releases: Vec<_> = workflow.triggers.iter().filter(|t| t.event == TriggerEvent::Release).collect();
+    if !releases.is_empty() {
+        yaml.push_str("  release:\n    types: [published]\n");
+        if releases.iter().any(|t| !t.tags.is_empty()) {
+            warnings.push("Release triggers run for every published release; their tag patterns are dropped".to_string());
+        }
+    }
+}
+
+fn list<'a>(items: impl IntoIterator<Item = &'a String>) -> String {
+    items.into_iter().map(|s| quote(s)).collect::<Vec<_>>().join(", ")
+}
+
+fn step(
+    yaml: &mut String,
+    node: &BuildNode,
+    condition: Option<&str>,
+    actions: &[StoredAction],
+    uploads_elsewhere: bool,
+    nodes: &[BuildNode],
+    warnings: &mut Vec<String>,
+) {
+    let config = &node.config;
+    let text = |key: &str| config.get(key).and_then(|v| v.as_str()).filter(|s| !s.is_empty());
+    let flag = |key: &str| config.get(key).and_then(|v| v.as_bool()).unwrap_or(false);
+    let mut head = format!("      - name: {}\n", quote(&node.name));
+    if let Some(condition) = condition {
+        let _ = writeln!(head, "        if: {}", condition);
+    }
+    if flag("dry_run") {
+        warnings.push(format!("{} is a dry run in BuildForge but runs for real in GitHub Actions", node.name));
+    }
+    if config.get("container").is_some_and(|c| !c.is_null()) {
+        warnings.push(format!("{} runs in its own container, which the exported step does not", node.name));
+    }
+    let timeout = config
+        .get("timeout_secs")
+        .and_then(|v| v.as_u64())
+        .map(|secs| format!("        timeout-minutes: {}\n", secs.div_ceil(60)))
+        .unwrap_or_default();
+    check_outputs(node, warnings);
+
+    match node.node_type.as_str() {
+        "command" | "test" | "script" => {
+            let script = match (node.node_type.as_str(), text("command"), text("program")) {
+                ("script", ..) => text("script").unwrap_or("echo 'No script'").to_string(),
+                (_, Some(command), _) => command.to_string(),
+                (_, None, Some(program)) => {
+                    let args = config.get("args").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+                    std::iter::once(program.to_string())
+                        .chain(args.iter().map(|arg| match arg {
+                            serde_json::Value::String(s) => s.clone(),
+                            other => other.to_string(),
+                        }))
+                        .map(|arg| shell_quote(&arg))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+                (_, None, None) => "echo No command specified".to_string(),
+            };
+            if node.node_type == "test" {
+                warnings.push(format!(
+                    "{} runs its tests, but their reports are not read; add a test reporter action to see them",
+                    node.name
+                ));
+            }
+            yaml.push_str(&head);
+            if let Some(cwd) = text("cwd") {
+                let _ = writeln!(yaml, "        working-directory: {}", quote(&expressions(cwd)));
+            }
+            match text("shell") {
+                Some(shell) if SHELLS.contains(&shell) => {
+                    let _ = writeln!(yaml, "        shell: {}", shell);
+                }
+                Some(shell) => warnings.push(format!("{} uses the shell {}, which GitHub Actions does not know", node.name, shell)),
+                None => {}
+            }
+            step_env(yaml, config.get("env"));
+            yaml.push_str(&timeout);
+            block(yaml, "run", &expressions(&script));
+        }
+        "action" => {
+            let action = text("action_id").and_then(|id| actions.iter().find(|a| a.id == id));
+            let Some(action) = action else {
+                placeholder(yaml, node, "its action no longer exists", warnings);
+                return;
+            };
+            if !action.outputs.is_empty() {
+                warnings.push(format!("The outputs of action {} are not passed on to later steps", action.name));
+            }
+            yaml.push_str(&head);
+            let shell = match action.interpreter.as_deref().unwrap_or("bash") {
+                "python3" => "python".to_string(),
+                interpreter if SHELLS.contains(&interpreter) => interpreter.to_string(),
+                // Any other program runs the script file GitHub writes
+                interpreter => format!("{} {{0}}", interpreter),
+            };
+            let _ = writeln!(yaml, "        shell: {}", quote(&shell));
+            // Inputs reach the script as environment variables
+            let mut inputs = serde_json::Map::new();
+            for input in &action.inputs {
+                if let (Some(name), Some(default)) = (input.get("name").and_then(|v| v.as_str()), input.get("default")) {
+                    inputs.insert(name.to_string(), default.clone());
+                }
+            }
+            if let Some(given) = config.get("inputs").and_then(|v| v.as_object()) {
+                inputs.extend(given.clone());
+            }
+            step_env(yaml, Some(&serde_json::Value::Object(inputs)));
+            let timeout = action
+                .timeout_secs
+                .map(|secs| format!("        timeout-minutes: {}\n", secs.div_ceil(60)))
+                .unwrap_or(timeout);
+            yaml.push_str(&timeout);
+            block(yaml, "run", &action.script);
+        }
+        "artifact" => {
+            let mut paths = strings(config.get("path"));
+            if paths.is_empty() {
+                paths.push("dist/*".to_string());
+            }
+            paths.extend(strings(config.get("exclude")).into_iter().map(|p| format!("!{}", p)));
+            yaml.push_str(&head);
+            yaml.push_str("        uses: actions/upload-artifact@v4\n        with:\n");
+            let _ = writeln!(yaml, "          name: {}", quote(&slug(&node.name, '-')));
+            block_at(yaml, "          ", "path", &expressions(&paths.join("\n")));
+            let missing = if config.get("fail_if_empty").and_then(|v| v.as_bool()).unwrap_or(true) {
+                "error"
+            } else {
+                "warn"
+            };
+            let _ = writeln!(yaml, "          if-no-files-found: {}", missing);
+        }
+        "release" => {
+            let upload = flag("upload_artifacts");
+            if upload && uploads_elsewhere {
+                yaml.push_str("      - name: \"Download artifacts\"\n");
+                yaml.push_str("        uses: actions/download-artifact@v4\n        with:\n          path: artifacts\n");
+            }
+            yaml.push_str(&head);
+            yaml.push_str("        uses: softprops/action-gh-release@v2\n        with:\n");
+            let _ = writeln!(yaml, "          tag_name: {}", quote(&expressions(text("tag").unwrap_or("v1.0.0"))));
+            let _ = writeln!(yaml, "          name: {}", quote(&expressions(text("title").unwrap_or("Release"))));
+            if let Some(body) = text("body") {
+                block_at(yaml, "          ", "body", &expressions(body));
+            }
+            let _ = writeln!(yaml, "          draft: {}", flag("draft"));
+            let _ = writeln!(yaml, "          prerelease: {}", flag("prerelease"));
+            if let Some(commitish) = text("target_commitish") {
+                let _ = writeln!(yaml, "          target_commitish: {}", quote(&expressions(commitish)));
+            }
+            if upload {
+                let files = if uploads_elsewhere {
+                    vec!["artifacts/**".to_string()]
+                } else {
+                    let artifacts: Vec<String> = nodes
+                        .iter()
+                        .filter(|n| n.node_type == "artifact")
+                        .flat_map(|n| strings(n.config.get("path")))
+                        .collect();
+                    if artifacts.is_empty() {
+                        vec!["dist/*".to_string()]
+                    } else {
+                        artifacts
+                    }
+                };
+                block_at(yaml, "          ", "files", &expressions(&files.join("\n")));
+            }
+            if flag("update_existing") {
+                warnings.push(format!("{} updates an existing release, which action-gh-release does on its own", node.name));
+            }
+            if text("api_base_url").is_some() {
+                warnings.push(format!("{} releases to another GitHub server; the exported step uses github.com", node.name));
+            }
+        }
+        "env" => {
+            let Some(vars) = config.get("vars").and_then(|v| v.as_object()) else {
+                placeholder(yaml, node, "it sets no variables", warnings);
+                return;
+            };
+            let mut env = serde_json::Map::new();
+            let mut script = String::new();
+            for (name, spec) in vars {
+                let (value, command, server, secret) = match spec {
+                    serde_json::Value::String(value) => (Some(value.as_str()), None, None, false),
+                    spec => (
+                        spec.get("value").and_then(|v| v.as_str()),
+                        spec.get("command").and_then(|v| v.as_str()),
+                        spec.get("value_env").and_then(|v| v.as_str()),
+                        spec.get("secret").and_then(|v| v.as_bool()).unwrap_or(false),
+                    ),
+                };
+                match (value, command, server) {
+                    (Some(value), ..) => {
+                        env.insert(name.clone(), serde_json::Value::String(value.to_string()));
+                    }
+                    (None, Some(command), _) => {
+                        let _ = writeln!(script, "{}=\"$({})\"", name, command);
+                    }
+                    (None, None, Some(server)) => {
+                        env.insert(name.clone(), serde_json::Value::String(format!("${{secret:{}}}", server)));
+                    }
+                    (None, None, None) => continue,
+                }
+                if secret {
+                    let _ = writeln!(script, "echo \"::add-mask::${}\"", name);
+                }
+                let _ = writeln!(script, "echo \"{}=${}\" >> \"$GITHUB_ENV\"", name, name);
+            }
+            yaml.push_str(&head);
+            yaml.push_str("        shell: bash\n");
+            step_env(yaml, Some(&serde_json::Value::Object(env)));
+            block(yaml, "run", &expressions(&script));
+        }
+        "placeholder" if text("uses").is_some() => {
+            // An action brought in by ImportGitHubActionsWorkflow goes back as it was
+            yaml.push_str(&head);
+            let _ = writeln!(yaml, "        uses: {}", text("uses").unwrap_or_default());
+            if let Some(with) = config.get("with").and_then(|v| v.as_object()).filter(|with| !with.is_empty()) {
+                yaml.push_str("        with:\n");
+                for (key, value) in with {
+                    let value = value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string());
+                    let _ = writeln!(yaml, "          {}: {}", key, quote(&value));
+                }
+            }
+        }
+        other => {
+            let why = match other {
+                "condition" => "conditions are not translated; put them in the if: of the steps after it",
+                "cache_restore" | "cache_save" => "use actions/cache instead",
+                _ => "there is no equivalent step",
+            };
+            placeholder(yaml, node, why, warnings);
+        }
+    }
+}
+
+/// A commented-out stand-in for a node with no counterpart
+fn placeholder(yaml: &mut String, node: &BuildNode, why: &str, warnings: &mut Vec<String>) {
+    warnings.push(format!("{} ({}) was not converted: {}", node.name, node.node_type, why));
+    let _ = writeln!(yaml, "      # TODO: BuildForge node {:?} ({}) was not converted: {}", node.name, node.node_type, why);
+    let _ = writeln!(yaml, "      # settings: {}", node.config);
+}
+
+/// `env:` of a step, from an object of names and values
+fn step_env(yaml: &mut String, env: Option<&serde_json::Value>) {
+    let Some(env) = env.and_then(|v| v.as_object()).filter(|env| !env.is_empty()) else {
+        return;
+    };
+    yaml.push_str("        env:\n");
+    for (name, value) in env {
+        let value = match value {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        let _ = writeln!(yaml, "          {}: {}", name, quote(&expressions(&value)));
+    }
+}
+
+/// A list setting; a single string is accepted where a list is expected,
+/// as the nodes themselves do
+fn strings(value: Option<&serde_json::Value>) -> Vec<String> {
+    match value {
+        Some(serde_json::Value::String(s)) => vec![s.clone()],
+        Some(serde_json::Value::Array(items)) => items.iter().filter_map(|v| v.as_str()).map(str::to_string).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn check_outputs(node: &BuildNode, warnings: &mut Vec<String>) {
+    let config = node.config.to_string();
+    let uses_outputs = config
+        .match_indices("${")
+        .any(|(start, _)| config[start + 2..].split('}').next().is_some_and(|inner| inner.contains('.') && !inner.starts_with("secret:")));
+    if uses_outputs {
+        warnings.push(format!(
+            "{} uses the output of another node, which is left as written; pass it with step outputs",
+            node.name
+        ));
+    }
+}
+
+/// Build variables and secret references as GitHub expressions. They are
+/// filled in before the shell runs, as BuildForge does.
+fn expressions(text: &str) -> String {
+    let mut text = text
+        .replace("$VERSION", "${{ env.VERSION }}")
+        .replace("$PROJECT_ROOT", "${{ github.workspace }}")
+        .replace("$COMMIT_SHA", "${{ github.sha }}")
+        .replace("$BRANCH", "${{ github.ref_name }}")
+        .replace("$TAG", "${{ github.ref_name }}");
+    while let Some(start) = text.find("${secret:") {
+        let Some(end) = text[start..].find('}') else {
+            break;
+        };
+        let name = text[start + "${secret:".len()..start + end].to_string();
+        text.replace_range(start..=start + end, &format!("${{{{ secrets.{} }}}}", name));
+    }
+    text
+}
+
+/// A key with a literal block, indented under a step
+fn block(yaml: &mut String, key: &str, text: &str) {
+    block_at(yaml, "        ", key, text);
+}
+
+fn block_at(yaml: &mut String, indent: &str, key: &str, text: &str) {
+    // An explicit indentation keeps a first line that starts with a space
+    let indicator = if text.starts_with([' ', '\t']) { "|2" } else { "|" };
+    let _ = writeln!(yaml, "{}{}: {}", indent, key, indicator);
+    for line in text.lines() {
+        if line.is_empty() {
+            yaml.push('\n');
+        } else {
+            let _ = writeln!(yaml, "{}  {}", indent, line);
+        }
+    }
+}
+
+/// A YAML scalar that stays text whatever it holds; JSON strings are valid
+/// YAML
+fn quote(text: &str) -> String {
+    serde_json::Value::String(text.to_string()).to_string()
+}
+
+/// An argument for a POSIX shell
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c)) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Lowercase letters, digits and `separator`, for ids and file names
+pub fn slug(name: &str, separator: char) -> String {
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with(separator) {
+            slug.push(separator);
+        }
+    }
+    let slug = slug.trim_end_matches(separator).to_string();
+    if slug.is_empty() {
+        "workflow".to_string()
+    } else {
+        slug
+    }
+}
+
+#[derive(Deserialize)]
+struct Document {
+    name: Option<String>,
+    on: Option<Yaml>,
+    #[serde(default)]
+    env: Mapping,
+    #[serde(default)]
+    defaults: Defaults,
+    /// A mapping rather than a map, to keep the jobs in order
+    #[serde(default)]
+    jobs: Mapping,
+}
+
+#[derive(Deserialize, Default)]
+struct Defaults {
+    #[serde(default)]
+    run: RunDefaults,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct RunDefaults {
+    shell: Option<String>,
+    working_directory: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ImportJob {
+    name: Option<String>,
+    needs: Option<Yaml>,
+    #[serde(rename = "if")]
+    condition: Option<Yaml>,
+    runs_on: Option<Yaml>,
+    container: Option<Yaml>,
+    #[serde(default)]
+    env: Mapping,
+    #[serde(default)]
+    defaults: Defaults,
+    strategy: Option<Strategy>,
+    timeout_minutes: Option<Yaml>,
+    /// A reusable workflow, instead of steps
+    uses: Option<String>,
+    #[serde(default)]
+    with: Mapping,
+    #[serde(default)]
+    steps: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+struct Strategy {
+    matrix: Option<Yaml>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Step {
+    name: Option<String>,
+    uses: Option<String>,
+    run: Option<String>,
+    shell: Option<String>,
+    #[serde(default)]
+    with: Mapping,
+    #[serde(default)]
+    env: Mapping,
+    #[serde(rename = "if")]
+    condition: Option<Yaml>,
+    working_directory: Option<String>,
+    timeout_minutes: Option<Yaml>,
+    continue_on_error: Option<Yaml>,
+}
+
+/// Nodes and connections as the app stores them, laid out a job per row
+#[derive(Default)]
+struct Draft {
+    nodes: Vec<serde_json::Value>,
+    connections: Vec<serde_json::Value>,
+    report: Vec<ImportNote>,
+}
+
+impl Draft {
+    fn node(&mut self, node_type: &str, name: &str, config: serde_json::Value, column: usize, row: usize) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        self.nodes.push(json!({
+            "id": id,
+            "type": node_type,
+            "name": name,
+            "position": { "x": 100 + column * 250, "y": 100 + row * 150 },
+            "config": config,
+        }));
+        id
+    }
+
+    fn connect(&mut self, from: &str, to: &str) {
+        self.connections.push(json!({
+            "id": uuid::Uuid::new_v4().to_string(),
+            "from": from,
+            "to": to,
+        }));
+    }
+
+    fn note(&mut self, job: Option<&str>, step: Option<&str>, message: String) {
+        self.report.push(ImportNote {
+            job: job.map(str::to_string),
+            step: step.map(str::to_string),
+            message,
+        });
+    }
+}
+
+pub fn import(request: &ImportGitHubActionsRequest) -> Result<GitHubActionsImport> {
+    let document: Document = serde_yaml::from_str(&request.yaml).context("Not a GitHub Actions workflow")?;
+    let mut jobs = Vec::new();
+    for (id, job) in &document.jobs {
+        let id = scalar(id).context("A job has no name")?;
+        let job: ImportJob = serde_yaml::from_value(job.clone()).with_context(|| format!("Job {} is not valid", id))?;
+        jobs.push((id, job));
+    }
+    if jobs.is_empty() {
+        anyhow::bail!("The workflow has no jobs");
+    }
+
+    let mut draft = Draft::default();
+    let triggers = import_triggers(document.on.as_ref(), &mut draft);
+
+    // Workflow-wide variables go first, for every job
+    let mut roots = Vec::new();
+    if !document.env.is_empty() {
+        let mut vars = serde_json::Map::new();
+        for (name, value) in &document.env {
+            let (Some(name), Some(value)) = (scalar(name), scalar(value)) else {
+                continue;
+            };
+            let var = match secret_name(&value) {
+                Some(secret) => json!({ "value_env": secret, "secret": true }),
+                None => serde_json::Value::String(from_expressions(&value, None, None, &mut draft)),
+            };
+            vars.insert(name, var);
+        }
+        roots.push(draft.node("env", "Environment", json!({ "vars": vars }), 0, 0));
+    }
+    let first_column = roots.len();
+
+    let order = job_order(&jobs)?;
+    let mut container: Option<String> = None;
+    let mut exits: HashMap<&str, Vec<String>> = HashMap::new();
+    for (row, &j) in order.iter().enumerate() {
+        let (id, job) = &jobs[j];
+        let needs = strings_of(job.needs.as_ref());
+        let mut previous: Vec<String> = if needs.is_empty() {
+            roots.clone()
+        } else {
+            needs.iter().flat_map(|need| exits[need.as_str()].clone()).collect()
+        };
+        let mut job_importer = JobImport::new(id, job, &document.defaults.run, &mut draft);
+        if let Some(image) = job_importer.container(job.container.as_ref()) {
+            match &container {
+                None if row == 0 => container = Some(image),
+                Some(workflow_image) if *workflow_image == image => {}
+                _ => job_importer.node_container = Some(image),
+            }
+        }
+        let chain = job_importer.chain(first_column, row);
+        for node in &chain {
+            for before in &previous {
+                draft.connect(before, node);
+            }
+            previous = vec![node.clone()];
+        }
+        exits.insert(id.as_str(), previous);
+    }
+
+    let now = chrono::Utc::now().to_rfc3339();
+    let workflow = StoredWorkflow {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: document.name.clone().unwrap_or_else(|| "Imported workflow".to_string()),
+        repo_id: None,
+        nodes: draft.nodes,
+        connections: draft.connections,
+        next_version: "1.0.0".to_string(),
+        concurrency: Default::default(),
+        container: container.map(|image| ContainerConfig {
+            image,
+            cpus: None,
+            memory: None,
+        }),
+        requires: Vec::new(),
+        triggers,
+        created_at: now.clone(),
+        updated_at: now,
+    };
+    Ok(GitHubActionsImport {
+        workflow,
+        report: draft.report,
+    })
+}
+
+/// Jobs after the jobs they need, otherwise in the order they are written
+fn job_order(jobs: &[(String, ImportJob)]) -> Result<Vec<usize>> {
+    let index: HashMap<&str, usize> = jobs.iter().enumerate().map(|(i, (id, _))| (id.as_str(), i)).collect();
+    let mut before: Vec<Vec<usize>> = vec![Vec::new(); jobs.len()];
+    for (j, (id, job)) in jobs.iter().enumerate() {
+        for need in strings_of(job.needs.as_ref()) {
+            let &i = index
+                .get(need.as_str())
+                .with_context(|| format!("Job {} needs {}, which does not exist", id, need))?;
+            before[j].push(i);
+        }
+    }
+    let mut order = Vec::with_capacity(jobs.len());
+    let mut placed = vec![false; jobs.len()];
+    while order.len() < jobs.len() {
+        let next = (0..jobs.len())
+            .find(|&j| !placed[j] && before[j].iter().all(|&i| placed[i]))
+            .context("The jobs need each other in a loop")?;
+        placed[next] = true;
+        order.push(next);
+    }
+    Ok(order)
+}
+
+fn import_triggers(on: Option<&Yaml>, draft: &mut Draft) -> Vec<Trigger> {
+    let events: Vec<(String, Option<&Yaml>)> = match on {
+        Some(Yaml::String(event)) => vec![(event.clone(), None)],
+        Some(Yaml::Sequence(events)) => events.iter().filter_map(scalar).map(|event| (event, None)).collect(),
+        Some(Yaml::Mapping(events)) => events
+            .iter()
+            .filter_map(|(event, filters)| Some((scalar(event)?, Some(filters))))
+            .collect(),
+        _ => Vec::new(),
+    };
+    let mut triggers = Vec::new();
+    for (event, filters) in events {
+        let list = |key: &str| strings_of(filters.and_then(|f| f.get(key)));
+        match event.as_str() {
+            // Builds can always be started by hand
+            "workflow_dispatch" => {}
+            "push" => {
+                for ignored in ["branches-ignore", "tags-ignore", "paths", "paths-ignore"] {
+                    if !list(ignored).is_empty() {
+                        draft.note(None, None, format!("The push trigger's {} filter was dropped", ignored));
+                    }
+                }
+                triggers.push(Trigger {
+                    event: TriggerEvent::Push,
+                    branches: list("branches"),
+                    tags: list("tags"),
+                });
+            }
+            "release" => {
+                let types = list("types");
+                if types.iter().any(|t| t != "published") {
+                    draft.note(None, None, "Release triggers start builds for published releases only".to_string());
+                }
+                triggers.push(Trigger {
+                    event: TriggerEvent::Release,
+                    branches: Vec::new(),
+                    tags: Vec::new(),
+                });
+            }
+            other => draft.note(None, None, format!("The {} trigger has no BuildForge equivalent", other)),
+        }
+    }
+    triggers
+}
+
+/// Turns the steps of one job into nodes
+struct JobImport<'a> {
+    id: &'a str,
+    job: &'a ImportJob,
+    defaults: RunDefaults,
+    draft: &'a mut Draft,
+    /// An image other than the workflow's, for each command and script node
+    node_container: Option<String>,
+}
+
+impl<'a> JobImport<'a> {
+    fn new(id: &'a str, job: &'a ImportJob, defaults: &RunDefaults, draft: &'a mut Draft) -> Self {
+        let defaults = RunDefaults {
+            shell: job.defaults.run.shell.clone().or_else(|| defaults.shell.clone()),
+            working_directory: job
+                .defaults
+                .run
+                .working_directory
+                .clone()
+                .or_else(|| defaults.working_directory.clone()),
+        };
+        Self {
+            id,
+            job,
+            defaults,
+            draft,
+            node_container: None,
+        }
+    }
+
+    fn note(&mut self, step: Option<&str>, message: String) {
+        self.draft.note(Some(self.id), step, message);
+    }
+
+    fn container(&mut self, container: Option<&Yaml>) -> Option<String> {
+        let container = container?;
+        let image = scalar(container).or_else(|| container.get("image").and_then(scalar))?;
+        if container.get("options").is_some() || container.get("credentials").is_some() {
+            self.note(None, "The container's options and credentials were dropped".to_string());
+        }
+        Some(image)
+    }
+
+    /// The job's nodes in order
+    fn chain(&mut self, first_column: usize, row: usize) -> Vec<String> {
+        let job = self.job;
+        let title = job.name.clone().unwrap_or_else(|| self.id.to_string());
+        if let Some(condition) = job.condition.as_ref().and_then(scalar) {
+            self.note(None, format!("The job's if: {} was dropped; it always runs", condition));
+        }
+        if let Some(runs_on) = job.runs_on.as_ref() {
+            let runs_on = strings_of(Some(runs_on)).join(", ");
+            if runs_on.contains("windows") || runs_on.contains("macos") {
+                self.note(None, format!("The job ran on {}; it now runs on the server's own system", runs_on));
+            }
+        }
+        if job.timeout_minutes.is_some() {
+            self.note(None, "The job's timeout-minutes was dropped; steps keep their own".to_string());
+        }
+        let matrix = self.matrix();
+        let mut env = serde_json::Map::new();
+        for (name, value) in &job.env {
+            if let (Some(name), Some(value)) = (scalar(name), scalar(value)) {
+                env.insert(name, serde_json::Value::String(value));
+            }
+        }
+
+        let mut nodes = Vec::new();
+        let mut column = first_column;
+        if let Some(uses) = &job.uses {
+            self.note(None, format!("The reusable workflow {} became a placeholder", uses));
+            let config = json!({ "uses": uses, "with": to_json(&job.with) });
+            nodes.push(self.draft.node("placeholder", &title, config, column, row));
+            return nodes;
+        }
+
+        let mut cache_saves = Vec::new();
+        for step in &job.steps {
+            let name = step_name(step);
+            let Some((node_type, mut config)) = self.step(step, &name, &mut cache_saves) else {
+                continue;
+            };
+            let config_map = config.as_object_mut().expect("steps are objects");
+            if matches!(node_type, "command" | "script") {
+                let mut step_env = env.clone();
+                for (key, value) in &step.env {
+                    if let (Some(key), Some(value)) = (scalar(key), scalar(value)) {
+                        step_env.insert(key, serde_json::Value::String(value));
+                    }
+                }
+                let step_env = step_env
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let value = from_expressions(value.as_str().unwrap_or_default(), Some(self.id), Some(name.as_str()), self.draft);
+                        (key, serde_json::Value::String(value))
+                    })
+                    .collect::<serde_json::Map<_, _>>();
+                if !step_env.is_empty() {
+                    config_map.insert("env".to_string(), serde_json::Value::Object(step_env));
+                }
+                if let Some(matrix) = &matrix {
+                    config_map.insert("matrix".to_string(), matrix.clone());
+                }
+                if let Some(image) = &self.node_container {
+                    config_map.insert("container".to_string(), serde_json::Value::String(image.clone()));
+                }
+            } else if !step.env.is_empty() {
+                self.note(Some(name.as_str()), "The step's env was dropped".to_string());
+            }
+            match step.condition.as_ref().and_then(scalar).as_deref().map(str::trim) {
+                None | Some("success()") => {}
+                Some("failure()") => {
+                    config_map.insert("run_on".to_string(), json!("failure"));
+                }
+                Some("always()") | Some("!cancelled()") => {
+                    config_map.insert("run_on".to_string(), json!("always"));
+                }
+                Some(other) => self.note(Some(name.as_str()), format!("if: {} was dropped; the step always runs", other)),
+            }
+            if step.continue_on_error.is_some() {
+                self.note(Some(name.as_str()), "continue-on-error was dropped; the step fails the build".to_string());
+            }
+            nodes.push(self.draft.node(node_type, &name, config, column, row));
+            column += 1;
+        }
+        // actions/cache saves when the job ends
+        for (name, config) in cache_saves {
+            nodes.push(self.draft.node("cache_save", &name, config, column, row));
+            column += 1;
+        }
+        if nodes.is_empty() {
+            self.note(None, format!("{} had no steps to convert", title));
+        }
+        nodes
+    }
+
+    /// The job's `strategy.matrix`, as a node's `matrix`
+    fn matrix(&mut self) -> Option<serde_json::Value> {
+        let matrix = self.job.strategy.as_ref()?.matrix.as_ref()?;
+        let Some(variables) = matrix.as_mapping() else {
+            self.note(None, "The matrix is an expression and was dropped".to_string());
+            return None;
+        };
+        let mut converted = serde_json::Map::new();
+        for (name, values) in variables {
+            let Some(name) = scalar(name) else {
+                continue;
+            };
+            match values.as_sequence() {
+                _ if name == "include" || name == "exclude" => {
+                    self.note(None, format!("The matrix's {} entries were dropped", name));
+                }
+                Some(values) if values.iter().all(|v| scalar(v).is_some()) => {
+                    let values = values.iter().filter_map(scalar).map(serde_json::Value::String).collect();
+                    converted.insert(matrix_name(&name), serde_json::Value::Array(values));
+                }
+                _ => self.note(None, format!("The matrix variable {} is not a list of values and was dropped", name)),
+            }
+        }
+        (!converted.is_empty()).then_some(serde_json::Value::Object(converted))
+    }
+
+    /// The type and config of the node for `step`, None if it needs none
+    fn step(
+        &mut self,
+        step: &Step,
+        name: &str,
+        cache_saves: &mut Vec<(String, serde_json::Value)>,
+    ) -> Option<(&'static str, serde_json::Value)> {
+        let id = self.id;
+        let with = |key: &str| step.with.get(key).and_then(scalar);
+        let convert = |text: &str, draft: &mut Draft| from_expressions(text, Some(id), Some(name), draft);
+        if let Some(run) = &step.run {
+            let run = convert(run.trim_end(), self.draft);
+            let mut config = if run.lines().count() > 1 {
+                json!({ "script": run })
+            } else {
+                json!({ "command": run })
+            };
+            match step.shell.clone().or_else(|| self.defaults.shell.clone()) {
+                Some(shell) if SHELLS.contains(&shell.as_str()) => config["shell"] = json!(shell),
+                Some(shell) => self.note(Some(name), format!("The shell {} was dropped", shell)),
+                None => {}
+            }
+            if let Some(cwd) = step.working_directory.clone().or_else(|| self.defaults.working_directory.clone()) {
+                config["cwd"] = json!(convert(&cwd, self.draft));
+            }
+            match step.timeout_minutes.as_ref().map(|t| t.as_u64()) {
+                Some(Some(minutes)) => config["timeout_secs"] = json!(minutes * 60),
+                Some(None) => self.note(Some(name), "timeout-minutes is an expression and was dropped".to_string()),
+                None => {}
+            }
+            let node_type = if config.get("script").is_some() { "script" } else { "command" };
+            return Some((node_type, config));
+        }
+
+        let Some(uses) = &step.uses else {
+            self.note(Some(name), "The step has neither run nor uses".to_string());
+            return None;
+        };
+        let action = uses.split('@').next().unwrap_or_default().to_ascii_lowercase();
+        match action.as_str() {
+            "actions/checkout" => {
+                self.note(
+                    Some(name),
+                    "Checkout is done by the server; choose the workflow's repository in its settings".to_string(),
+                );
+                None
+            }
+            "actions/download-artifact" => {
+                self.note(Some(name), "Artifacts stay in the build directory, so nothing is downloaded".to_string());
+                None
+            }
+            "actions/upload-artifact" => {
+                let lines = lines_of(&convert(&with("path").unwrap_or_default(), self.draft));
+                let (exclude, path): (Vec<String>, Vec<String>) = lines.into_iter().partition(|line| line.starts_with('!'));
+                let exclude: Vec<String> = exclude.iter().map(|line| line[1..].to_string()).collect();
+                let mut config = json!({
+                    "path": path,
+                    "fail_if_empty": with("if-no-files-found").as_deref() == Some("error"),
+                });
+                if !exclude.is_empty() {
+                    config["exclude"] = json!(exclude);
+                }
+                Some(("artifact", config))
+            }
+            "actions/cache" | "actions/cache/restore" | "actions/cache/save" => {
+                let key = convert(&with("key").unwrap_or_default(), self.draft);
+                let paths = lines_of(&convert(&with("path").unwrap_or_default(), self.draft));
+                let restore_keys = lines_of(&convert(&with("restore-keys").unwrap_or_default(), self.draft));
+                if action != "actions/cache/restore" {
+                    let save = json!({ "key": key, "paths": paths });
+                    if action == "actions/cache/save" {
+                        return Some(("cache_save", save));
+                    }
+                    cache_saves.push((format!("{} (save)", name), save));
+                }
+                Some(("cache_restore", json!({ "key": key, "paths": paths, "restore_keys": restore_keys })))
+            }
+            "softprops/action-gh-release" => {
+                let mut config = json!({
+                    "tag": convert(&with("tag_name").unwrap_or_else(|| "$TAG".to_string()), self.draft),
+                    "title": convert(&with("name").unwrap_or_else(|| "$TAG".to_string()), self.draft),
+                    "draft": with("draft").as_deref() == Some("true"),
+                    "prerelease": with("prerelease").as_deref() == Some("true"),
+                });
+                if let Some(body) = with("body") {
+                    config["body"] = json!(convert(&body, self.draft));
+                }
+                if let Some(commitish) = with("target_commitish") {
+                    config["target_commitish"] = json!(convert(&commitish, self.draft));
+                }
+                if with("files").is_some() {
+                    config["upload_artifacts"] = json!(true);
+                    self.note(Some(name), "The release uploads the build's artifacts instead of its files patterns".to_string());
+                }
+                Some(("release", config))
+            }
+            _ => {
+                self.note(Some(name), format!("{} has no BuildForge equivalent; replace the placeholder", uses));
+                Some(("placeholder", json!({ "uses": uses, "with": to_json(&step.with) })))
+            }
+        }
+    }
+}
+
+fn step_name(step: &Step) -> String {
+    if let Some(name) = &step.name {
+        return name.clone();
+    }
+    if let Some(uses) = &step.uses {
+        return uses.clone();
+    }
+    let first = step.run.as_deref().unwrap_or("Step").lines().next().unwrap_or_default().trim();
+    match first.char_indices().nth(40) {
+        Some((end, _)) => format!("{}...", &first[..end]),
+        None => first.to_string(),
+    }
+}
+
+/// `${{ }}` expressions as build variables where there is one. Others are
+/// left as written and reported.
+fn from_expressions(text: &str, job: Option<&str>, step: Option<&str>, draft: &mut Draft) -> String {
+    static EXPRESSION: OnceLock<Regex> = OnceLock::new();
+    static HASH_FILES: OnceLock<Regex> = OnceLock::new();
+    let expression = EXPRESSION.get_or_init(|| Regex::new(r"\$\{\{\s*(.*?)\s*\}\}").expect("valid regex"));
+    let hash_files = HASH_FILES.get_or_init(|| Regex::new(r"^hashFiles\(\s*'([^']*)'\s*\)$").expect("valid regex"));
+    let mut unconverted = Vec::new();
+    let converted = expression.replace_all(text, |captures: &regex::Captures| {
+        let inner = &captures[1];
+        let (scope, name) = inner.split_once('.').unwrap_or((inner, ""));
+        match (scope, name) {
+            ("github", "sha") => "$COMMIT_SHA".to_string(),
+            ("github", "ref_name") | ("github", "head_ref") => "$BRANCH".to_string(),
+            ("github", "workspace") => "$PROJECT_ROOT".to_string(),
+            ("env", name) if is_name(name) => format!("${}", name),
+            ("secrets", name) if is_name(name) => {
+                unconverted.push(format!("The secret {} is read from the server's environment variable {}", name, name));
+                format!("${}", name)
+            }
+            ("matrix", name) if is_name(name) => format!("$MATRIX_{}", matrix_name(name)),
+            _ => match hash_files.captures(inner) {
+                Some(glob) => format!("$HASH({})", &glob[1]),
+                None => {
+                    unconverted.push(format!("The expression {} was left as written", &captures[0]));
+                    captures[0].to_string()
+                }
+            },
+        }
+    });
+    let converted = converted.into_owned();
+    for message in unconverted {
+        draft.note(job, step, message);
+    }
+    converted
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// A matrix variable name BuildForge accepts: letters, digits and `_`
+fn matrix_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// NAME if `value` is exactly `${{ secrets.NAME }}`
+fn secret_name(value: &str) -> Option<String> {
+    let inner = value.trim().strip_prefix("${{")?.strip_suffix("}}")?.trim();
+    let name = inner.strip_prefix("secrets.")?;
+    is_name(name).then(|| name.to_string())
+}
+
+/// A string, number or boolean as text
+fn scalar(value: &Yaml) -> Option<String> {
+    match value {
+        Yaml::String(s) => Some(s.clone()),
+        Yaml::Number(n) => Some(n.to_string()),
+        Yaml::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// A single value or a list of them
+fn strings_of(value: Option<&Yaml>) -> Vec<String> {
+    match value {
+        Some(Yaml::Sequence(values)) => values.iter().filter_map(scalar).collect(),
+        Some(value) => scalar(value).into_iter().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// The non-empty lines of a multi-line `with:` value
+fn lines_of(text: &str) -> Vec<String> {
+    text.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_string).collect()
+}
+
+fn to_json(mapping: &Mapping) -> serde_json::Value {
+    let object = mapping
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = scalar(value).map(serde_json::Value::String).unwrap_or_else(|| {
+                serde_json::Value::String(serde_yaml::to_string(value).unwrap_or_default())
+            });
+            Some((scalar(key)?, value))
+        })
+        .collect();
+    serde_json::Value::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(nodes: serde_json::Value, connections: serde_json::Value) -> ServerData {
+        serde_json::from_value(serde_json::json!({
+            "workflows": [{
+                "id": "wf",
+                "name": "My App",
+                "repo_id": null,
+                "nodes": nodes,
+                "connections": connections,
+                "next_version": "1.0.0",
+                "created_at": "",
+                "updated_at": "",
+            }],
+            "actions": [],
+            "repos": [],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn artifact_paths_and_excludes_become_upload_paths() {
+        let data = data(
+            serde_json::json!([
+                {"id": "a", "type": "artifact", "name": "Upload", "config": {"path": ["dist/*.zip", "dist/*.tar.gz"], "exclude": "dist/*.pdb"}},
+                {"id": "r", "type": "release", "name": "Release", "config": {"upload_artifacts": true}},
+            ]),
+            serde_json::json!([{"from": "a", "to": "r"}]),
+        );
+        let export = export(&data, "wf").unwrap();
+        assert!(export.yaml.contains("          path: |\n            dist/*.zip\n            dist/*.tar.gz\n            !dist/*.pdb\n"));
+        assert!(export.yaml.contains("          files: |\n            dist/*.zip\n            dist/*.tar.gz\n"));
+    }
+
+    #[test]
+    fn a_single_path_is_taken_as_a_list() {
+        let data = data(
+            serde_json::json!([{"id": "a", "type": "artifact", "name": "Upload", "config": {"path": "out/app"}}]),
+            serde_json::json!([]),
+        );
+        let export = export(&data, "wf").unwrap();
+        assert!(export.yaml.contains("          path: |\n            out/app\n"));
+    }
+
+    #[test]
+    fn strings_reads_a_string_or_a_list() {
+        assert_eq!(strings(Some(&serde_json::json!("a"))), vec!["a"]);
+        assert_eq!(strings(Some(&serde_json::json!(["a", 1, "b"]))), vec!["a", "b"]);
+        assert!(strings(Some(&serde_json::json!(3))).is_empty());
+        assert!(strings(None).is_empty());
+    }
+}
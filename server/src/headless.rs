@@ -0,0 +1,114 @@
+//! `buildforge-server run`: one build of a stored workflow, without any
+//! client, for cron jobs and CI.
+//!
+//! The build goes through the same registry and `execute_build` as one a
+//! client starts, so it is recorded in the history, audited and logged to
+//! disk as usual. Its log is printed as it runs, to stdout, or to stderr with
+//! `--json`, which prints the final build record to stdout instead. The exit
+//! code is 0 if the build succeeded and 1 otherwise.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::builds;
+use crate::{shutdown, ServerContext, ServerMessage};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct RunArgs {
+    /// Id or name of the stored workflow
+    workflow: String,
+
+    /// Version to build; the workflow's next version if unset
+    #[arg(long = "version")]
+    build_version: Option<String>,
+
+    /// Build variable as NAME=VALUE; may be repeated
+    #[arg(long = "var", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+
+    /// Branch, tag or commit to build; the repo's default branch if unset
+    #[arg(long = "ref")]
+    git_ref: Option<String>,
+
+    /// Set dry_run on every node, so the nodes that support it only log what they would do
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the build record as JSON on stdout when the build ends; the log goes to stderr
+    #[arg(long)]
+    json: bool,
+}
+
+fn parse_var(text: &str) -> Result<(String, String), String> {
+    let (name, value) = text.split_once('=').ok_or_else(|| format!("{} is not NAME=VALUE", text))?;
+    if name.is_empty() {
+        return Err(format!("{} has no name", text));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Runs the build `args` asks for and returns the process exit code
+pub async fn run(ctx: &Arc<ServerContext>, args: RunArgs) -> Result<i32> {
+    let workflow = ctx.data.read().await.workflow(&args.workflow)?.clone();
+    let payload = builds::from_workflow(
+        &workflow,
+        args.build_version.clone().unwrap_or_else(|| workflow.next_version.clone()),
+        args.git_ref.clone(),
+        args.vars.iter().cloned().collect(),
+        args.dry_run,
+    )?;
+    let build_id = payload.build_id.clone();
+    info!("Running workflow {} v{} as build {}", workflow.name, payload.version, build_id);
+
+    // Subscribed before the build starts, so no line is missed
+    let mut events = ctx.events.subscribe();
+    let entry = crate::audit::AuditEntry::new("BuildStart", &build_id, None, "cli");
+    if let Err(e) = ctx.audit.record(entry).await {
+        warn!("Failed to write audit log: {}", e);
+    }
+    builds::submit(ctx, payload, ctx.github_token.clone(), None).await;
+
+    let print = |line: &str| {
+        if args.json {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    };
+    let signal = shutdown::signal();
+    tokio::pin!(signal);
+    let mut cancelled = false;
+    let complete = loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = &mut signal, if !cancelled => {
+                warn!("Interrupted, cancelling build {}", build_id);
+                builds::cancel(ctx, &build_id).await;
+                cancelled = true;
+                continue;
+            }
+        };
+        match event {
+            Ok(ServerMessage::BuildLog(line)) if line.build_id == build_id => print(&line.log),
+            Ok(ServerMessage::BuildComplete(complete)) if complete.build_id == build_id => break complete,
+            Ok(_) => {}
+            Err(RecvError::Lagged(skipped)) => print(&format!("[{} log lines skipped]", skipped)),
+            Err(RecvError::Closed) => anyhow::bail!("The build ended without a result"),
+        }
+    };
+
+    if args.json {
+        let record = ctx
+            .history
+            .get(&build_id)
+            .await?
+            .with_context(|| format!("Build {} is missing from the history", build_id))?;
+        println!("{}", serde_json::to_string_pretty(&record)?);
+    } else {
+        print(&format!("Build {} {} in {}s", build_id, complete.status, complete.duration));
+    }
+    Ok(if complete.success { 0 } else { 1 })
+}
@@ -0,0 +1,61 @@
+//! `GET /healthz` for systemd, Kubernetes and load balancer probes, on the
+//! main port and on the REST API port.
+//!
+//! The answer is 200 with a small JSON report, or 503 once shutdown has
+//! begun or the data directory cannot be written. It never waits on the
+//! server data lock, so it stays quick while builds keep the server busy.
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{builds, ServerContext};
+
+pub const PATH: &str = "/healthz";
+
+/// How long the write probe may take before the data directory counts as
+/// not writable
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    /// `ok`, or `unavailable` with a 503
+    pub status: String,
+    pub version: String,
+    pub uptime_secs: u64,
+    pub connected_clients: usize,
+    pub running_builds: usize,
+    pub queued_builds: usize,
+    pub data_dir_writable: bool,
+    pub shutting_down: bool,
+}
+
+/// The HTTP status and report to answer a probe with
+pub async fn check(ctx: &ServerContext) -> (u16, HealthReport) {
+    let (running_builds, queued_builds) = builds::counts(ctx).await;
+    let data_dir_writable = data_dir_writable(ctx).await;
+    let shutting_down = ctx.shutting_down.load(Ordering::Relaxed);
+    let healthy = data_dir_writable && !shutting_down;
+    let report = HealthReport {
+        status: if healthy { "ok" } else { "unavailable" }.to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs: ctx.started.elapsed().as_secs(),
+        connected_clients: ctx.connected_clients.load(Ordering::Relaxed),
+        running_builds,
+        queued_builds,
+        data_dir_writable,
+        shutting_down,
+    };
+    (if healthy { 200 } else { 503 }, report)
+}
+
+/// Writes and removes a small file in the data directory
+async fn data_dir_writable(ctx: &ServerContext) -> bool {
+    let path = ctx.data_dir.join(format!(".healthz-{}", std::process::id()));
+    let probe = async {
+        tokio::fs::write(&path, b"ok").await?;
+        tokio::fs::remove_file(&path).await
+    };
+    matches!(tokio::time::timeout(WRITE_TIMEOUT, probe).await, Ok(Ok(())))
+}
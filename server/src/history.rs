@@ -0,0 +1,595 @@
+//! Build history, the history of action runs, earlier versions of actions
+//! and GitHub webhook deliveries, stored in SQLite at `data_dir/history.db`.
+//!
+//! Each record is kept as JSON alongside the columns we filter and sort on,
+//! so adding a field to `BuildRecord` or `ActionRunRecord` needs no schema
+//! change.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use tracing::info;
+
+use crate::actions::{ActionHistoryQuery, ActionRunRecord};
+use crate::builds::status;
+use crate::retention::RetentionPolicy;
+use crate::webhooks::{WebhookDeliveriesQuery, WebhookDelivery};
+use crate::{BuildHistoryQuery, BuildRecord, StoredAction};
+
+/// Schema migrations, applied in order. `PRAGMA user_version` holds how many ran.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE builds (
+        id          TEXT PRIMARY KEY,
+        workflow_id TEXT NOT NULL,
+        status      TEXT NOT NULL,
+        started_at  TEXT NOT NULL,
+        finished_at TEXT,
+        duration_ms INTEGER,
+        record      TEXT NOT NULL
+    );
+    CREATE INDEX builds_workflow ON builds (workflow_id, started_at);
+    CREATE INDEX builds_status ON builds (status, started_at);
+    CREATE INDEX builds_started ON builds (started_at);
+", "
+    CREATE TABLE action_runs (
+        id         TEXT PRIMARY KEY,
+        action_id  TEXT NOT NULL,
+        started_at TEXT NOT NULL,
+        record     TEXT NOT NULL
+    );
+    CREATE INDEX action_runs_action ON action_runs (action_id, started_at);
+", "
+    CREATE TABLE action_versions (
+        action_id TEXT NOT NULL,
+        version   INTEGER NOT NULL,
+        record    TEXT NOT NULL,
+        PRIMARY KEY (action_id, version)
+    );
+", "
+    CREATE TABLE webhook_deliveries (
+        id          TEXT PRIMARY KEY,
+        received_at TEXT NOT NULL,
+        record      TEXT NOT NULL
+    );
+    CREATE INDEX webhook_deliveries_received ON webhook_deliveries (received_at);
+"];
+
+#[derive(Clone)]
+pub struct HistoryStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl HistoryStore {
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(data_dir)?;
+        let path = data_dir.join("history.db");
+        let mut conn = Connection::open(&path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut conn)?;
+        info!("Opened build history at {}", path.display());
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Opens the database for reading only, next to a server that may be
+    /// writing it, without creating or upgrading anything
+    pub fn open_read_only(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("history.db");
+        if !path.exists() {
+            anyhow::bail!("No build history in {}", data_dir.display());
+        }
+        let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version < MIGRATIONS.len() {
+            anyhow::bail!("The build history in {} predates this version; start the server once to upgrade it", data_dir.display());
+        }
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` against the connection on the blocking thread pool
+    pub async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().map_err(|_| anyhow::anyhow!("History database lock poisoned"))?;
+            f(&mut conn)
+        })
+        .await?
+    }
+
+    /// Inserts a record, replacing any existing one with the same id
+    pub async fn upsert(&self, record: BuildRecord) -> Result<()> {
+        self.call(move |conn| upsert(conn, &record)).await
+    }
+
+    /// Inserts many records in one transaction, used when migrating old data
+    pub async fn import(&self, records: Vec<BuildRecord>) -> Result<usize> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            for record in &records {
+                upsert(&tx, record)?;
+            }
+            tx.commit()?;
+            Ok(records.len())
+        })
+        .await
+    }
+
+    /// Every record, oldest first
+    pub async fn all(&self) -> Result<Vec<BuildRecord>> {
+        self.call(|conn| {
+            let mut stmt = conn.prepare("SELECT record FROM builds ORDER BY started_at")?;
+            let records = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<BuildRecord>>>()?;
+            Ok(records)
+        })
+        .await
+    }
+
+    /// Stores imported records in one transaction, first deleting all finished
+    /// builds if `replace` is set. Returns the ids that already existed.
+    pub async fn import_bundle(&self, records: Vec<BuildRecord>, replace: bool) -> Result<Vec<String>> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            if replace {
+                tx.execute("DELETE FROM builds WHERE status != ?1", [status::RUNNING])?;
+            }
+            let mut existing = Vec::new();
+            for record in &records {
+                let exists: bool = tx.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM builds WHERE id = ?1)",
+                    [&record.id],
+                    |row| row.get(0),
+                )?;
+                if exists {
+                    existing.push(record.id.clone());
+                }
+                upsert(&tx, record)?;
+            }
+            tx.commit()?;
+            Ok(existing)
+        })
+        .await
+    }
+
+    /// Records matching `query`, newest first, and how many match in total
+    pub async fn query(&self, query: BuildHistoryQuery) -> Result<(Vec<BuildRecord>, usize)> {
+        self.call(move |conn| {
+            let mut conditions: Vec<&str> = Vec::new();
+            let mut values: Vec<String> = Vec::new();
+            if let Some(workflow_id) = query.workflow_id {
+                conditions.push("workflow_id = ?");
+                values.push(workflow_id);
+            }
+            if let Some(build_id) = query.build_id {
+                conditions.push("id = ?");
+                values.push(build_id);
+            }
+            if let Some(status) = query.status {
+                conditions.push("status = ?");
+                values.push(status);
+            }
+            let filter = if conditions.is_empty() {
+                String::new()
+            } else {
+                format!("WHERE {}", conditions.join(" AND "))
+            };
+
+            let total: usize = conn.query_row(
+                &format!("SELECT COUNT(*) FROM builds {}", filter),
+                rusqlite::params_from_iter(&values),
+                |row| row.get(0),
+            )?;
+
+            let mut stmt = conn.prepare(&format!(
+                "SELECT record FROM builds {} ORDER BY started_at DESC LIMIT {} OFFSET {}",
+                filter, query.limit, query.offset
+            ))?;
+            let records = stmt
+                .query_map(rusqlite::params_from_iter(&values), |row| row.get::<_, String>(0))?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<BuildRecord>>>()?;
+
+            Ok((records, total))
+        })
+        .await
+    }
+
+    /// The last `window` records of a workflow (all of them if `None`), newest first
+    pub async fn recent(&self, workflow_id: &str, window: Option<u32>) -> Result<Vec<BuildRecord>> {
+        let workflow_id = workflow_id.to_string();
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT record FROM builds WHERE workflow_id = ?1
+                 ORDER BY started_at DESC LIMIT ?2",
+            )?;
+            let limit = window.map(i64::from).unwrap_or(-1);
+            let records = stmt
+                .query_map(params![workflow_id, limit], |row| row.get::<_, String>(0))?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<BuildRecord>>>()?;
+            Ok(records)
+        })
+        .await
+    }
+
+    /// The latest build of a workflow that succeeded or failed
+    pub async fn last_finished(&self, workflow_id: &str) -> Result<Option<BuildRecord>> {
+        let workflow_id = workflow_id.to_string();
+        self.call(move |conn| {
+            let json: Option<String> = conn
+                .query_row(
+                    "SELECT record FROM builds WHERE workflow_id = ?1 AND status IN (?2, ?3)
+                     ORDER BY started_at DESC LIMIT 1",
+                    params![workflow_id, status::SUCCESS, status::FAILED],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(json.map(|j| serde_json::from_str(&j)).transpose()?)
+        })
+        .await
+    }
+
+    pub async fn get(&self, build_id: &str) -> Result<Option<BuildRecord>> {
+        let build_id = build_id.to_string();
+        self.call(move |conn| {
+            let json: Option<String> = conn
+                .query_row("SELECT record FROM builds WHERE id = ?1", [&build_id], |row| row.get(0))
+                .optional()?;
+            Ok(json.map(|j| serde_json::from_str(&j)).transpose()?)
+        })
+        .await
+    }
+
+    /// Deletes one record, returning it if it existed
+    pub async fn delete(&self, build_id: &str) -> Result<Option<BuildRecord>> {
+        let build_id = build_id.to_string();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let json: Option<String> = tx
+                .query_row("SELECT record FROM builds WHERE id = ?1", [&build_id], |row| row.get(0))
+                .optional()?;
+            tx.execute("DELETE FROM builds WHERE id = ?1", [&build_id])?;
+            tx.commit()?;
+            Ok(json.map(|j| serde_json::from_str(&j)).transpose()?)
+        })
+        .await
+    }
+
+    /// Deletes finished records of `workflow_id` (or all workflows) started
+    /// before `before` (or at any time). With `dry_run` nothing is deleted and
+    /// the records that would be are returned.
+    pub async fn clear(
+        &self,
+        workflow_id: Option<String>,
+        before: Option<String>,
+        dry_run: bool,
+    ) -> Result<Vec<BuildRecord>> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let filter = "WHERE status != ?1
+                 AND (?2 IS NULL OR workflow_id = ?2)
+                 AND (?3 IS NULL OR started_at < ?3)";
+            let args = params![status::RUNNING, workflow_id, before];
+
+            let records = tx
+                .prepare(&format!("SELECT record FROM builds {}", filter))?
+                .query_map(args, |row| row.get::<_, String>(0))?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<BuildRecord>>>()?;
+            if !dry_run {
+                tx.execute(&format!("DELETE FROM builds {}", filter), args)?;
+            }
+            tx.commit()?;
+            Ok(records)
+        })
+        .await
+    }
+
+    /// Flags builds left "running" by a server that stopped mid-build
+    pub async fn mark_interrupted(&self) -> Result<usize> {
+        self.call(|conn| {
+            Ok(conn.execute(
+                "UPDATE builds SET status = ?1, record = json_set(record, '$.status', ?1)
+                 WHERE status = ?2",
+                params![status::INTERRUPTED, status::RUNNING],
+            )?)
+        })
+        .await
+    }
+
+    /// Average duration of the last few successful builds of a workflow
+    pub async fn estimate_duration(&self, workflow_id: &str) -> Result<Option<u64>> {
+        const SAMPLES: u32 = 5;
+
+        let workflow_id = workflow_id.to_string();
+        self.call(move |conn| {
+            let average: Option<f64> = conn.query_row(
+                "SELECT AVG(duration_ms) FROM (
+                    SELECT duration_ms FROM builds
+                    WHERE workflow_id = ?1 AND status = ?2 AND duration_ms IS NOT NULL
+                    ORDER BY started_at DESC LIMIT ?3
+                )",
+                params![workflow_id, status::SUCCESS, SAMPLES],
+                |row| row.get(0),
+            )?;
+            Ok(average.map(|a| a as u64))
+        })
+        .await
+    }
+}
+
+impl HistoryStore {
+    /// Deletes records outside the retention policy and returns them so their
+    /// files can be cleaned up. The newest successful and newest failed build of
+    /// each workflow, and builds still running, are always kept.
+    pub async fn prune(&self, policy: RetentionPolicy) -> Result<Vec<BuildRecord>> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+
+            let mut protected: HashSet<String> = HashSet::new();
+            for kept_status in [status::SUCCESS, status::FAILED] {
+                let mut stmt = tx.prepare(
+                    "SELECT (SELECT id FROM builds
+                             WHERE workflow_id = w.workflow_id AND status = ?1
+                             ORDER BY started_at DESC LIMIT 1)
+                     FROM (SELECT DISTINCT workflow_id FROM builds) w",
+                )?;
+                let ids = stmt.query_map([kept_status], |row| row.get::<_, Option<String>>(0))?;
+                for id in ids {
+                    protected.extend(id?);
+                }
+            }
+
+            let mut candidates: Vec<(String, String)> = Vec::new();
+            if let Some(max) = policy.max_history_per_workflow {
+                let mut stmt = tx.prepare(
+                    "SELECT id, record FROM (
+                        SELECT id, record, status, ROW_NUMBER() OVER (
+                            PARTITION BY workflow_id ORDER BY started_at DESC
+                        ) AS n FROM builds
+                    ) WHERE n > ?1 AND status != ?2",
+                )?;
+                let rows = stmt.query_map(params![max as i64, status::RUNNING], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?;
+                for row in rows {
+                    candidates.push(row?);
+                }
+            }
+            if let Some(days) = policy.max_history_age_days {
+                let cutoff = chrono::Utc::now() - chrono::Duration::days(days as i64);
+                let mut stmt = tx.prepare(
+                    "SELECT id, record FROM builds WHERE started_at < ?1 AND status != ?2",
+                )?;
+                let rows = stmt.query_map(params![cutoff.to_rfc3339(), status::RUNNING], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?;
+                for row in rows {
+                    candidates.push(row?);
+                }
+            }
+
+            let mut pruned = Vec::new();
+            let mut seen: HashSet<String> = HashSet::new();
+            for (id, json) in candidates {
+                if protected.contains(&id) || !seen.insert(id.clone()) {
+                    continue;
+                }
+                tx.execute("DELETE FROM builds WHERE id = ?1", [&id])?;
+                pruned.push(serde_json::from_str(&json)?);
+            }
+            tx.commit()?;
+            Ok(pruned)
+        })
+        .await
+    }
+}
+
+impl HistoryStore {
+    /// Inserts an action run, replacing any existing one with the same id
+    pub async fn upsert_action_run(&self, record: ActionRunRecord) -> Result<()> {
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO action_runs (id, action_id, started_at, record)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![record.id, record.action_id, record.started_at, serde_json::to_string(&record)?],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Action runs matching `query`, newest first, and how many match in total
+    pub async fn query_action_runs(&self, query: ActionHistoryQuery) -> Result<(Vec<ActionRunRecord>, usize)> {
+        self.call(move |conn| {
+            let filter = "WHERE ?1 IS NULL OR action_id = ?1";
+            let total: usize = conn.query_row(
+                &format!("SELECT COUNT(*) FROM action_runs {}", filter),
+                [&query.action_id],
+                |row| row.get(0),
+            )?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT record FROM action_runs {} ORDER BY started_at DESC LIMIT {} OFFSET {}",
+                filter, query.limit, query.offset
+            ))?;
+            let records = stmt
+                .query_map([&query.action_id], |row| row.get::<_, String>(0))?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<ActionRunRecord>>>()?;
+            Ok((records, total))
+        })
+        .await
+    }
+
+    /// Deletes action runs outside the retention policy, which keeps
+    /// `max_history_per_workflow` runs of each action, and returns how many
+    /// were deleted
+    pub async fn prune_action_runs(&self, policy: RetentionPolicy) -> Result<usize> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let mut pruned = 0;
+            if let Some(max) = policy.max_history_per_workflow {
+                pruned += tx.execute(
+                    "DELETE FROM action_runs WHERE id IN (
+                        SELECT id FROM (
+                            SELECT id, ROW_NUMBER() OVER (
+                                PARTITION BY action_id ORDER BY started_at DESC
+                            ) AS n FROM action_runs
+                        ) WHERE n > ?1
+                    )",
+                    [max as i64],
+                )?;
+            }
+            if let Some(days) = policy.max_history_age_days {
+                let cutoff = chrono::Utc::now() - chrono::Duration::days(days as i64);
+                pruned += tx.execute("DELETE FROM action_runs WHERE started_at < ?1", [cutoff.to_rfc3339()])?;
+            }
+            tx.commit()?;
+            Ok(pruned)
+        })
+        .await
+    }
+}
+
+impl HistoryStore {
+    /// Keeps a saved version of an action, and only the newest `keep`
+    /// versions of it
+    pub async fn save_action_version(&self, action: StoredAction, keep: usize) -> Result<()> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO action_versions (action_id, version, record) VALUES (?1, ?2, ?3)",
+                params![action.id, action.version as i64, serde_json::to_string(&action)?],
+            )?;
+            tx.execute(
+                "DELETE FROM action_versions WHERE action_id = ?1 AND version NOT IN (
+                    SELECT version FROM action_versions WHERE action_id = ?1
+                    ORDER BY version DESC LIMIT ?2
+                )",
+                params![action.id, keep as i64],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn action_version(&self, action_id: &str, version: u64) -> Result<Option<StoredAction>> {
+        let action_id = action_id.to_string();
+        self.call(move |conn| {
+            let json: Option<String> = conn
+                .query_row(
+                    "SELECT record FROM action_versions WHERE action_id = ?1 AND version = ?2",
+                    params![action_id, version as i64],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(json.map(|j| serde_json::from_str(&j)).transpose()?)
+        })
+        .await
+    }
+
+    /// The kept versions of an action, newest first
+    pub async fn action_versions(&self, action_id: &str) -> Result<Vec<StoredAction>> {
+        let action_id = action_id.to_string();
+        self.call(move |conn| {
+            let mut stmt = conn.prepare("SELECT record FROM action_versions WHERE action_id = ?1 ORDER BY version DESC")?;
+            let versions = stmt
+                .query_map([&action_id], |row| row.get::<_, String>(0))?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<StoredAction>>>()?;
+            Ok(versions)
+        })
+        .await
+    }
+
+    pub async fn delete_action_versions(&self, action_id: &str) -> Result<()> {
+        let action_id = action_id.to_string();
+        self.call(move |conn| {
+            conn.execute("DELETE FROM action_versions WHERE action_id = ?1", [&action_id])?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl HistoryStore {
+    /// Keeps a webhook delivery, and only the newest `keep` of them
+    pub async fn record_webhook_delivery(&self, delivery: WebhookDelivery, keep: usize) -> Result<()> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO webhook_deliveries (id, received_at, record) VALUES (?1, ?2, ?3)",
+                params![delivery.id, delivery.received_at, serde_json::to_string(&delivery)?],
+            )?;
+            tx.execute(
+                "DELETE FROM webhook_deliveries WHERE id NOT IN (
+                    SELECT id FROM webhook_deliveries ORDER BY received_at DESC LIMIT ?1
+                )",
+                [keep as i64],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Webhook deliveries, newest first, and how many there are in total
+    pub async fn query_webhook_deliveries(&self, query: WebhookDeliveriesQuery) -> Result<(Vec<WebhookDelivery>, usize)> {
+        self.call(move |conn| {
+            let total: usize = conn.query_row("SELECT COUNT(*) FROM webhook_deliveries", [], |row| row.get(0))?;
+            let mut stmt = conn.prepare(
+                "SELECT record FROM webhook_deliveries ORDER BY received_at DESC LIMIT ?1 OFFSET ?2",
+            )?;
+            let deliveries = stmt
+                .query_map(params![query.limit as i64, query.offset as i64], |row| row.get::<_, String>(0))?
+                .map(|json| Ok(serde_json::from_str(&json?)?))
+                .collect::<Result<Vec<WebhookDelivery>>>()?;
+            Ok((deliveries, total))
+        })
+        .await
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        info!("Applied history schema migration {}", index + 1);
+    }
+    Ok(())
+}
+
+fn upsert(conn: &Connection, record: &BuildRecord) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO builds
+            (id, workflow_id, status, started_at, finished_at, duration_ms, record)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            record.id,
+            record.workflow_id,
+            record.status,
+            record.started_at,
+            record.finished_at,
+            record.duration_ms,
+            serde_json::to_string(record)?,
+        ],
+    )?;
+    Ok(())
+}
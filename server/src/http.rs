@@ -0,0 +1,283 @@
+//! Just enough HTTP/1.1 for the server's plain HTTP endpoints, the GitHub
+//! webhook and the REST API.
+//!
+//! Each connection carries one request and is closed after the response, so
+//! there is no keep-alive to deal with. A body comes with a `Content-Length`;
+//! a request without one has no body, and one sent with a
+//! `Transfer-Encoding` is refused.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAX_HEADER_BYTES: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// Without the query string
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// By lowercase name
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// A request refused for what it is rather than for being garbled, with
+/// its own status
+#[derive(Debug, thiserror::Error)]
+pub enum Refused {
+    #[error("The headers are longer than {MAX_HEADER_BYTES} bytes")]
+    HeadersTooLarge,
+    #[error("The body is larger than {0} bytes")]
+    BodyTooLarge(usize),
+    /// A body sent with a `Transfer-Encoding`, such as chunked
+    #[error("Transfer-Encoding is not supported; send the body with a Content-Length")]
+    Encoded,
+}
+
+impl Refused {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::HeadersTooLarge => 431,
+            Self::BodyTooLarge(_) => 413,
+            Self::Encoded => 501,
+        }
+    }
+}
+
+/// The status to answer a request `read_request` failed on with
+pub fn status_of(e: &anyhow::Error) -> u16 {
+    e.downcast_ref::<Refused>().map_or(400, Refused::status)
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+/// Reads one request, refusing bodies over `max_body` bytes
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S, max_body: usize) -> Result<Request> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let header_end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > MAX_HEADER_BYTES {
+            return Err(Refused::HeadersTooLarge.into());
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("The connection closed before the headers ended");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().context("The request line is empty")?.to_string();
+    let target = request_line.next().context("The request line has no path")?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    if headers.contains_key("transfer-encoding") {
+        return Err(Refused::Encoded.into());
+    }
+    // Without either header there is no body (RFC 9112, 6.3)
+    let length: usize = match headers.get("content-length") {
+        Some(length) => length.parse().context("Content-Length is not a number")?,
+        None => 0,
+    };
+    if length > max_body {
+        return Err(Refused::BodyTooLarge(max_body).into());
+    }
+    let mut body = buf.split_off(header_end + 4);
+    while body.len() < length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("The connection closed before the body ended");
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(length);
+
+    Ok(Request {
+        method,
+        path: decode(path),
+        query,
+        headers,
+        body,
+    })
+}
+
+/// Writes a complete response and flushes it
+pub async fn respond<S: AsyncWrite + Unpin>(stream: &mut S, status: u16, content_type: &str, body: &[u8]) -> Result<()> {
+    respond_with_headers(stream, status, content_type, &[], body).await
+}
+
+/// Writes a complete response with `headers` besides the usual ones
+pub async fn respond_with_headers<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<()> {
+    // A 204 has no body
+    let body = if status == 204 { &[][..] } else { body };
+    let extra: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        status,
+        reason(status),
+        content_type,
+        body.len(),
+        extra
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Writes the head of a response whose body runs until the connection closes
+pub async fn respond_streaming<S: AsyncWrite + Unpin>(stream: &mut S, status: u16, content_type: &str) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason(status),
+        content_type
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        _ => "Service Unavailable",
+    }
+}
+
+/// Undoes percent-encoding, and `+` for a space
+fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(request: &[u8], max_body: usize) -> Result<Request> {
+        read_request(&mut &request[..], max_body).await
+    }
+
+    #[tokio::test]
+    async fn a_post_without_a_length_has_no_body() {
+        let request = read(b"POST /api/builds/b1/cancel HTTP/1.1\r\nAuthorization: Bearer t\r\n\r\n", 1024).await.unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/api/builds/b1/cancel"));
+        assert_eq!(request.header("authorization"), Some("Bearer t"));
+        assert!(request.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_body_arriving_after_the_headers_is_read() {
+        let head = &b"POST /hook?a=1&b=x%20y+z HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello"[..];
+        let mut stream = head.chain(&b" world, and more"[..]);
+        let request = read_request(&mut stream, 1024).await.unwrap();
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(request.query["b"], "x y z");
+    }
+
+    #[tokio::test]
+    async fn headers_split_across_reads_are_joined() {
+        let mut stream = (&b"GET /healthz HT"[..]).chain(&b"TP/1.1\r\nHost: x\r"[..]).chain(&b"\n\r\n"[..]);
+        let request = read_request(&mut stream, 0).await.unwrap();
+        assert_eq!(request.path, "/healthz");
+        assert_eq!(request.header("host"), Some("x"));
+    }
+
+    #[tokio::test]
+    async fn a_body_over_the_limit_is_refused() {
+        let e = read(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world", 10).await.unwrap_err();
+        assert_eq!(status_of(&e), 413);
+        assert!(read(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello worl", 10).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn headers_over_the_limit_are_refused() {
+        let mut request = b"GET / HTTP/1.1\r\nX-Pad: ".to_vec();
+        request.resize(MAX_HEADER_BYTES + 10, b'a');
+        let e = read(&request, 0).await.unwrap_err();
+        assert_eq!(status_of(&e), 431);
+    }
+
+    #[tokio::test]
+    async fn a_chunked_body_is_refused() {
+        let e = read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n", 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(status_of(&e), 501);
+    }
+
+    #[tokio::test]
+    async fn a_cut_off_request_is_a_bad_request() {
+        let e = read(b"POST / HTTP/1.1\r\nContent-Length: 20\r\n\r\nshort", 1024).await.unwrap_err();
+        assert_eq!((status_of(&e), e.to_string().as_str()), (400, "The connection closed before the body ended"));
+        let e = read(b"GET / HTTP/1.1\r\n", 0).await.unwrap_err();
+        assert_eq!(status_of(&e), 400);
+        let e = read(b"POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n", 1024).await.unwrap_err();
+        assert_eq!(status_of(&e), 400);
+    }
+}
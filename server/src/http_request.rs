@@ -0,0 +1,208 @@
+//! Calling an external HTTP endpoint, as done by the `http_request` node, for
+//! deploy hooks, cache purges and the like.
+//!
+//! The URL, header values and body may reference secrets as
+//! `${secret:NAME}`, read from the server environment variable `NAME`. Their
+//! values are replaced by `***` in everything written to the build log.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::build_log::BuildLog;
+use crate::builds::{BuildCancelled, CancelToken};
+
+/// Characters of the response body written to the build log
+const MAX_LOGGED_BODY: usize = 2000;
+
+/// Secret values met while expanding a request, to keep out of the log
+#[derive(Default)]
+pub struct Secrets(Vec<String>);
+
+impl Secrets {
+    /// Replaces every `${secret:NAME}` in `text` with the value of the server
+    /// environment variable `NAME`
+    pub fn expand(&mut self, text: &str) -> Result<String> {
+        let mut expanded = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("${secret:") {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + "${secret:".len()..start + end];
+            let value = std::env::var(name)
+                .with_context(|| format!("The environment variable {} is not set on the server", name))?;
+            expanded.push_str(&rest[..start]);
+            expanded.push_str(&value);
+            if !value.is_empty() {
+                self.0.push(value);
+            }
+            rest = &rest[start + end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Expands secrets in every string inside a JSON value
+    pub fn expand_json(&mut self, value: &mut serde_json::Value) -> Result<()> {
+        match value {
+            serde_json::Value::String(text) => *text = self.expand(text)?,
+            serde_json::Value::Array(items) => {
+                for item in items {
+                    self.expand_json(item)?;
+                }
+            }
+            serde_json::Value::Object(fields) => {
+                for field in fields.values_mut() {
+                    self.expand_json(field)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// `text` with every secret value replaced by `***`
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for secret in &self.0 {
+            text = text.replace(secret.as_str(), "***");
+        }
+        text
+    }
+}
+
+pub enum Body {
+    Text(String),
+    Json(serde_json::Value),
+}
+
+pub struct Request {
+    pub method: reqwest::Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Body>,
+    pub timeout: Duration,
+    /// Statuses that count as success; any 2xx if empty
+    pub expected: Vec<u16>,
+    /// Further attempts after a network error, a 429 or a 5xx
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after it
+    pub retry_delay: Duration,
+    pub follow_redirects: bool,
+    pub verify_tls: bool,
+}
+
+pub struct Response {
+    pub status: reqwest::StatusCode,
+    pub body: String,
+}
+
+impl Request {
+    fn is_expected(&self, status: reqwest::StatusCode) -> bool {
+        if self.expected.is_empty() {
+            status.is_success()
+        } else {
+            self.expected.contains(&status.as_u16())
+        }
+    }
+
+    /// Writes what would be sent to the log, secrets redacted
+    pub async fn log_dry_run(&self, secrets: &Secrets, log: &BuildLog) {
+        log.line(&format!("[http] Dry run: {} {}", self.method, secrets.redact(&self.url))).await;
+        for (name, value) in &self.headers {
+            log.line(&format!("[http]   {}: {}", name, secrets.redact(value))).await;
+        }
+        let body = match &self.body {
+            Some(Body::Text(text)) => text.clone(),
+            Some(Body::Json(value)) => value.to_string(),
+            None => return,
+        };
+        log.line(&format!("[http]   {}", secrets.redact(&capped(&body)))).await;
+    }
+}
+
+/// Sends `request`, retrying as it allows, and fails unless the final status
+/// is an expected one
+pub async fn send(request: &Request, secrets: &Secrets, cancel: &CancelToken, log: &BuildLog) -> Result<Response> {
+    let client = reqwest::Client::builder()
+        .redirect(if request.follow_redirects {
+            reqwest::redirect::Policy::limited(10)
+        } else {
+            reqwest::redirect::Policy::none()
+        })
+        .danger_accept_invalid_certs(!request.verify_tls)
+        .timeout(request.timeout)
+        .build()?;
+    let shown_url = secrets.redact(&request.url);
+
+    let mut delay = request.retry_delay;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        log.line(&format!("[http] {} {}", request.method, shown_url)).await;
+        let mut builder = client.request(request.method.clone(), &request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        builder = match &request.body {
+            Some(Body::Text(text)) => builder.body(text.clone()),
+            Some(Body::Json(value)) => builder.json(value),
+            None => builder,
+        };
+
+        let result = tokio::select! {
+            result = builder.send() => result,
+            _ = cancel.cancelled() => return Err(BuildCancelled.into()),
+        };
+        match result {
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                log.line(&format!("[http] {}", status)).await;
+                if !body.is_empty() {
+                    log.line(&secrets.redact(&capped(&body))).await;
+                }
+                if request.is_expected(status) {
+                    return Ok(Response { status, body });
+                }
+                let retryable = status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+                if !retryable || attempt > request.retries {
+                    anyhow::bail!("{} {} answered {}", request.method, shown_url, status);
+                }
+            }
+            Err(e) => {
+                // reqwest puts the URL, secrets and all, in its errors
+                let message = secrets.redact(&e.without_url().to_string());
+                if attempt > request.retries {
+                    anyhow::bail!("{} {} failed: {}", request.method, shown_url, message);
+                }
+                log.line(&format!("[http] Failed: {}", message)).await;
+            }
+        }
+        log.line(&format!("[http] Retrying in {}s ({} of {})", delay.as_secs(), attempt, request.retries)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel.cancelled() => return Err(BuildCancelled.into()),
+        }
+        delay *= 2;
+    }
+}
+
+/// Value at a JSON pointer such as `/data/id` in a response body, as text
+pub fn extract(body: &str, pointer: &str) -> Result<String> {
+    let value: serde_json::Value = serde_json::from_str(body).context("The response is not JSON")?;
+    let field = value
+        .pointer(pointer)
+        .with_context(|| format!("The response has nothing at {}", pointer))?;
+    Ok(field.as_str().map(str::to_string).unwrap_or_else(|| field.to_string()))
+}
+
+fn capped(text: &str) -> String {
+    if text.chars().count() <= MAX_LOGGED_BODY {
+        return text.to_string();
+    }
+    let mut capped: String = text.chars().take(MAX_LOGGED_BODY).collect();
+    capped.push_str(" […]");
+    capped
+}
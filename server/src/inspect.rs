@@ -0,0 +1,176 @@
+//! Read-only subcommands for looking into a data directory over ssh:
+//! `list workflows`, `list actions`, `history <workflow>` and
+//! `export <workflow>`.
+//!
+//! They read `server-data.json` and the history database directly, write
+//! nothing and take no lock, so they are safe to run next to a serving
+//! server. Output is a table unless `--json` asks for JSON.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::builds::status;
+use crate::{history, workflow_file, BuildHistoryQuery, ServerData};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum ListTarget {
+    Workflows,
+    Actions,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ListArgs {
+    what: ListTarget,
+
+    /// Print JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct HistoryArgs {
+    /// Id or name of the workflow
+    workflow: String,
+
+    /// Builds to show, newest first
+    #[arg(long, default_value = "20")]
+    limit: usize,
+
+    /// Only builds with this status: success, failed, cancelled, running or interrupted
+    #[arg(long)]
+    status: Option<String>,
+
+    /// Print JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ExportArgs {
+    /// Id or name of the workflow
+    workflow: String,
+
+    /// File to write; stdout if unset
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+pub fn list(data_dir: &Path, args: ListArgs) -> Result<()> {
+    let data = ServerData::read(data_dir)?;
+    match (args.what, args.json) {
+        (ListTarget::Workflows, true) => println!("{}", serde_json::to_string_pretty(&data.workflows)?),
+        (ListTarget::Actions, true) => println!("{}", serde_json::to_string_pretty(&data.actions)?),
+        (ListTarget::Workflows, false) => print_table(
+            &["ID", "NAME", "NODES", "NEXT VERSION", "UPDATED"],
+            data.workflows
+                .iter()
+                .map(|w| {
+                    vec![
+                        w.id.clone(),
+                        w.name.clone(),
+                        w.nodes.len().to_string(),
+                        w.next_version.clone(),
+                        w.updated_at.clone(),
+                    ]
+                })
+                .collect(),
+        ),
+        (ListTarget::Actions, false) => print_table(
+            &["ID", "NAME", "VERSION", "INTERPRETER", "UPDATED"],
+            data.actions
+                .iter()
+                .map(|a| {
+                    vec![
+                        a.id.clone(),
+                        a.name.clone(),
+                        a.version.to_string(),
+                        a.interpreter.clone().unwrap_or_else(|| "bash".to_string()),
+                        a.updated_at.clone(),
+                    ]
+                })
+                .collect(),
+        ),
+    }
+    Ok(())
+}
+
+pub async fn history(data_dir: &Path, args: HistoryArgs) -> Result<()> {
+    if let Some(wanted) = &args.status {
+        let known = [status::SUCCESS, status::FAILED, status::CANCELLED, status::RUNNING, status::INTERRUPTED];
+        if !known.contains(&wanted.as_str()) {
+            anyhow::bail!("Unknown status {}; use one of {}", wanted, known.join(", "));
+        }
+    }
+    let data = ServerData::read(data_dir)?;
+    let workflow = data.workflow(&args.workflow)?;
+    let store = history::HistoryStore::open_read_only(data_dir)?;
+    let (records, total) = store
+        .query(BuildHistoryQuery {
+            workflow_id: Some(workflow.id.clone()),
+            build_id: None,
+            status: args.status,
+            limit: args.limit,
+            offset: 0,
+        })
+        .await?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&records)?);
+        return Ok(());
+    }
+    print_table(
+        &["BUILD", "STATUS", "STARTED", "DURATION", "FAILED NODE"],
+        records
+            .iter()
+            .map(|r| {
+                vec![
+                    r.id.clone(),
+                    r.status.clone(),
+                    r.started_at.clone(),
+                    r.duration_ms.map(|ms| format!("{}s", ms / 1000)).unwrap_or_default(),
+                    r.failed_node.clone().unwrap_or_default(),
+                ]
+            })
+            .collect(),
+    );
+    if total > records.len() {
+        println!("{} of {} builds", records.len(), total);
+    }
+    Ok(())
+}
+
+pub fn export(data_dir: &Path, args: ExportArgs) -> Result<()> {
+    let data = ServerData::read(data_dir)?;
+    let export = workflow_file::export(&data, &args.workflow)?;
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, &export.yaml).with_context(|| format!("Failed to write {}", path.display()))?;
+            eprintln!("Exported workflow {} to {}", args.workflow, path.display());
+        }
+        None => print!("{}", export.yaml),
+    }
+    Ok(())
+}
+
+/// Prints `rows` under `headers`, each column as wide as its widest cell
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(headers.to_vec());
+    for row in &rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
@@ -0,0 +1,311 @@
+//! Windows installers, as built by the `windows_installer` node: an MSI with
+//! the WiX Toolset (`candle` and `light`) or an installer executable with
+//! NSIS (`makensis`).
+//!
+//! Without a template of its own the node generates a plain one that installs
+//! the given files into Program Files and registers an uninstaller. A custom
+//! template receives the product details as preprocessor variables instead.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tokio::process::Command;
+
+use crate::build_log::BuildLog;
+use crate::builds::CancelToken;
+use crate::process::{self, TempFile};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Toolchain {
+    Wix,
+    Nsis,
+}
+
+impl Toolchain {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "msi" | "wix" => Ok(Self::Wix),
+            "nsis" | "exe" => Ok(Self::Nsis),
+            other => anyhow::bail!("Unknown installer kind {}, expected msi or nsis", other),
+        }
+    }
+}
+
+/// Fails, saying how to install it, unless `toolchain` is on the PATH
+pub fn check_available(toolchain: Toolchain) -> Result<()> {
+    match toolchain {
+        Toolchain::Wix => {
+            for program in ["candle", "light"] {
+                if which::which(program).is_err() {
+                    anyhow::bail!(
+                        "{} is not installed on the server. Install the WiX Toolset v3 from \
+                         https://github.com/wixtoolset/wix3/releases and add its bin directory to the PATH",
+                        program
+                    );
+                }
+            }
+        }
+        Toolchain::Nsis => {
+            if which::which("makensis").is_err() {
+                anyhow::bail!(
+                    "makensis is not installed on the server. Install NSIS from https://nsis.sourceforge.io/Download \
+                     on Windows, with `apt install nsis` on Debian and Ubuntu, or with `brew install makensis` on macOS"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+pub struct Installer {
+    pub toolchain: Toolchain,
+    pub files: Vec<PathBuf>,
+    pub product_name: String,
+    /// Four numeric parts, see [`msi_version`]
+    pub version: String,
+    pub manufacturer: String,
+    pub upgrade_code: Option<uuid::Uuid>,
+    pub icon: Option<PathBuf>,
+    pub license: Option<PathBuf>,
+    /// Installs into the 64-bit Program Files
+    pub x64: bool,
+    /// Used instead of the generated .wxs or .nsi
+    pub template: Option<PathBuf>,
+    pub output: PathBuf,
+}
+
+/// `version` as the four numeric parts Windows installers need, and whether
+/// that changed it. Pre-release and build suffixes are dropped and missing
+/// parts are zero, so `1.2.0-beta.1` becomes `1.2.0.0`.
+pub fn msi_version(version: &str) -> (String, bool) {
+    let core = version
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default();
+    let mut parts: Vec<u32> = core
+        .split('.')
+        .map(|part| {
+            let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse().unwrap_or(0)
+        })
+        .take(4)
+        .collect();
+    parts.resize(4, 0);
+    let normalized = parts.iter().map(u32::to_string).collect::<Vec<_>>().join(".");
+    let changed = normalized != version;
+    (normalized, changed)
+}
+
+pub async fn build(installer: &Installer, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    if let Some(dir) = installer.output.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    match installer.toolchain {
+        Toolchain::Wix => build_msi(installer, cancel, log).await,
+        Toolchain::Nsis => build_nsis(installer, cancel, log).await,
+    }
+}
+
+async fn build_msi(installer: &Installer, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    let generated = TempFile::new("installer.wxs");
+    let source = match &installer.template {
+        Some(template) => template.clone(),
+        None => {
+            tokio::fs::write(&generated.0, wxs(installer)?).await?;
+            generated.0.clone()
+        }
+    };
+    let object = TempFile::new("installer.wixobj");
+
+    log.line(&format!("[installer] Compiling {}", source.display())).await;
+    let mut candle = Command::new("candle");
+    candle
+        .args(["-nologo", "-arch", if installer.x64 { "x64" } else { "x86" }])
+        .args(defines(installer, "-d"))
+        .arg("-out")
+        .arg(&object.0)
+        .arg(&source);
+    process::run_tool(&mut candle, "candle", cancel, log).await?;
+
+    log.line(&format!("[installer] Linking {}", installer.output.display())).await;
+    let mut light = Command::new("light");
+    light.args(["-nologo", "-spdb"]);
+    if installer.license.is_some() || installer.template.is_some() {
+        light.args(["-ext", "WixUIExtension"]);
+    }
+    light.arg("-out").arg(&installer.output).arg(&object.0);
+    process::run_tool(&mut light, "light", cancel, log).await?;
+    Ok(())
+}
+
+async fn build_nsis(installer: &Installer, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    let generated = TempFile::new("installer.nsi");
+    let source = match &installer.template {
+        Some(template) => template.clone(),
+        None => {
+            tokio::fs::write(&generated.0, nsi(installer)).await?;
+            generated.0.clone()
+        }
+    };
+
+    log.line(&format!("[installer] Building {}", installer.output.display())).await;
+    let mut makensis = Command::new("makensis");
+    makensis
+        .args(["-V3", "-INPUTCHARSET", "UTF8"])
+        .args(defines(installer, "-D"))
+        .arg(format!("-DOUTFILE={}", installer.output.display()))
+        .arg(&source);
+    process::run_tool(&mut makensis, "makensis", cancel, log).await?;
+    Ok(())
+}
+
+/// Product details as preprocessor variables, for custom templates
+fn defines(installer: &Installer, flag: &str) -> Vec<String> {
+    let mut defines = vec![
+        format!("{}ProductName={}", flag, installer.product_name),
+        format!("{}ProductVersion={}", flag, installer.version),
+        format!("{}Manufacturer={}", flag, installer.manufacturer),
+    ];
+    if let Some(code) = installer.upgrade_code {
+        defines.push(format!("{}UpgradeCode={}", flag, braced(code)));
+    }
+    if let Some(icon) = &installer.icon {
+        defines.push(format!("{}Icon={}", flag, icon.display()));
+    }
+    if let Some(license) = &installer.license {
+        defines.push(format!("{}License={}", flag, license.display()));
+    }
+    defines
+}
+
+fn braced(code: uuid::Uuid) -> String {
+    format!("{{{}}}", code.hyphenated().to_string().to_uppercase())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+/// A WiX source installing the files into `Program Files\<product>`
+fn wxs(installer: &Installer) -> Result<String> {
+    let upgrade_code = installer
+        .upgrade_code
+        .context("An MSI needs an upgrade_code, a GUID that stays the same across versions")?;
+    let program_files = if installer.x64 { "ProgramFiles64Folder" } else { "ProgramFilesFolder" };
+    let mut components = String::new();
+    let mut refs = String::new();
+    for (index, file) in installer.files.iter().enumerate() {
+        components.push_str(&format!(
+            "          <Component Id=\"Component{i}\" Guid=\"*\">\n            <File Id=\"File{i}\" Source=\"{}\" KeyPath=\"yes\" />\n          </Component>\n",
+            xml_escape(&file.to_string_lossy()),
+            i = index
+        ));
+        refs.push_str(&format!("      <ComponentRef Id=\"Component{}\" />\n", index));
+    }
+    let mut extras = String::new();
+    if let Some(icon) = &installer.icon {
+        extras.push_str(&format!(
+            "    <Icon Id=\"ProductIcon\" SourceFile=\"{}\" />\n    <Property Id=\"ARPPRODUCTICON\" Value=\"ProductIcon\" />\n",
+            xml_escape(&icon.to_string_lossy())
+        ));
+    }
+    if let Some(license) = &installer.license {
+        extras.push_str(&format!(
+            "    <WixVariable Id=\"WixUILicenseRtf\" Value=\"{}\" />\n    <UIRef Id=\"WixUI_Minimal\" />\n",
+            xml_escape(&license.to_string_lossy())
+        ));
+    }
+    let name = xml_escape(&installer.product_name);
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Wix xmlns="http://schemas.microsoft.com/wix/2006/wi">
+  <Product Id="*" Name="{name}" Language="1033" Version="{version}" Manufacturer="{manufacturer}" UpgradeCode="{upgrade_code}">
+    <Package InstallerVersion="500" Compressed="yes" InstallScope="perMachine" />
+    <MajorUpgrade DowngradeErrorMessage="A newer version of {name} is already installed." />
+    <MediaTemplate EmbedCab="yes" />
+{extras}    <Directory Id="TARGETDIR" Name="SourceDir">
+      <Directory Id="{program_files}">
+        <Directory Id="INSTALLFOLDER" Name="{name}">
+{components}        </Directory>
+      </Directory>
+    </Directory>
+    <Feature Id="Main" Title="{name}" Level="1">
+{refs}    </Feature>
+  </Product>
+</Wix>
+"#,
+        version = installer.version,
+        manufacturer = xml_escape(&installer.manufacturer),
+        upgrade_code = braced(upgrade_code),
+    ))
+}
+
+/// An NSIS script installing the files into `Program Files\<product>`
+fn nsi(installer: &Installer) -> String {
+    let name = nsis_escape(&installer.product_name);
+    // The upgrade code keys the uninstall entry, so a new version replaces it
+    let key = format!(
+        "Software\\Microsoft\\Windows\\CurrentVersion\\Uninstall\\{}",
+        installer.upgrade_code.map(braced).unwrap_or_else(|| name.clone())
+    );
+    let program_files = if installer.x64 { "$PROGRAMFILES64" } else { "$PROGRAMFILES" };
+    let mut script = format!(
+        "Unicode true\n!include \"MUI2.nsh\"\nName \"{name}\"\nOutFile \"${{OUTFILE}}\"\nInstallDir \"{program_files}\\{name}\"\nRequestExecutionLevel admin\n"
+    );
+    if let Some(icon) = &installer.icon {
+        script.push_str(&format!("!define MUI_ICON \"{}\"\n", nsis_escape(&icon.to_string_lossy())));
+    }
+    if let Some(license) = &installer.license {
+        script.push_str(&format!("!insertmacro MUI_PAGE_LICENSE \"{}\"\n", nsis_escape(&license.to_string_lossy())));
+    }
+    script.push_str(
+        "!insertmacro MUI_PAGE_DIRECTORY\n!insertmacro MUI_PAGE_INSTFILES\n\
+         !insertmacro MUI_UNPAGE_CONFIRM\n!insertmacro MUI_UNPAGE_INSTFILES\n\
+         !insertmacro MUI_LANGUAGE \"English\"\n",
+    );
+    script.push_str(&format!(
+        "VIProductVersion \"{version}\"\nVIAddVersionKey \"ProductName\" \"{name}\"\n\
+         VIAddVersionKey \"CompanyName\" \"{manufacturer}\"\nVIAddVersionKey \"FileVersion\" \"{version}\"\n\
+         VIAddVersionKey \"ProductVersion\" \"{version}\"\n",
+        version = installer.version,
+        manufacturer = nsis_escape(&installer.manufacturer),
+    ));
+
+    script.push_str("Section \"Install\"\n  SetOutPath \"$INSTDIR\"\n");
+    for file in &installer.files {
+        script.push_str(&format!("  File \"{}\"\n", nsis_escape(&file.to_string_lossy())));
+    }
+    let manufacturer = nsis_escape(&installer.manufacturer);
+    script.push_str("  WriteUninstaller \"$INSTDIR\\Uninstall.exe\"\n");
+    for (value, data) in [
+        ("DisplayName", format!("\"{}\"", name)),
+        ("DisplayVersion", format!("\"{}\"", installer.version)),
+        ("Publisher", format!("\"{}\"", manufacturer)),
+        ("UninstallString", "'\"$INSTDIR\\Uninstall.exe\"'".to_string()),
+    ] {
+        script.push_str(&format!("  WriteRegStr HKLM \"{}\" \"{}\" {}\n", key, value, data));
+    }
+    script.push_str("SectionEnd\n");
+
+    script.push_str("Section \"Uninstall\"\n");
+    for file in &installer.files {
+        script.push_str(&format!("  Delete \"$INSTDIR\\{}\"\n", nsis_escape(&file_name(file))));
+    }
+    script.push_str(&format!(
+        "  Delete \"$INSTDIR\\Uninstall.exe\"\n  RMDir \"$INSTDIR\"\n  DeleteRegKey HKLM \"{key}\"\nSectionEnd\n"
+    ));
+    script
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escapes text for a double-quoted NSIS string
+fn nsis_escape(text: &str) -> String {
+    text.replace('$', "$$").replace('"', "$\\\"")
+}
@@ -0,0 +1,105 @@
+//! JUnit-style XML test reports, as written by cargo-nextest, vitest's
+//! `junit` reporter and jest-junit, read by the `test` node.
+//!
+//! Every `<testcase>` is counted wherever it sits, so reports with nested
+//! `<testsuites>` and `<testsuite>` elements or a bare `<testsuite>` root all
+//! read the same.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Failures kept in a summary; `failed` still counts all of them
+const MAX_FAILURES: usize = 50;
+/// Characters kept of each failure message
+const MAX_MESSAGE_CHARS: usize = 2000;
+
+/// What a test node's reports add up to, sent with its NodeComplete and kept
+/// in the build record
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TestSummary {
+    pub total: usize,
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub duration_ms: u64,
+    /// The first failed tests, see `MAX_FAILURES`
+    pub failures: Vec<TestFailure>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestFailure {
+    /// `<classname>::<name>`, or the name alone
+    pub name: String,
+    pub message: String,
+}
+
+impl TestSummary {
+    /// Adds the test cases of one report
+    pub fn add_report(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let document = roxmltree::Document::parse(&text).with_context(|| format!("{} is not valid XML", path.display()))?;
+        let root = document.root_element();
+        if !matches!(root.tag_name().name(), "testsuites" | "testsuite") {
+            anyhow::bail!("{} is not a JUnit report", path.display());
+        }
+
+        for case in root.descendants().filter(|n| n.has_tag_name("testcase")) {
+            self.total += 1;
+            if let Some(secs) = case.attribute("time").and_then(|t| t.parse::<f64>().ok()) {
+                self.duration_ms += (secs * 1000.0).round() as u64;
+            }
+            let outcome = case
+                .children()
+                .find(|n| n.has_tag_name("failure") || n.has_tag_name("error") || n.has_tag_name("skipped"));
+            match outcome {
+                None => self.passed += 1,
+                Some(node) if node.has_tag_name("skipped") => self.skipped += 1,
+                Some(node) => {
+                    self.failed += 1;
+                    if self.failures.len() < MAX_FAILURES {
+                        self.failures.push(TestFailure {
+                            name: case_name(&case),
+                            message: failure_message(&node),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// One line for the build log
+    pub fn describe(&self) -> String {
+        format!(
+            "{} tests: {} passed, {} failed, {} skipped in {:.1}s",
+            self.total,
+            self.passed,
+            self.failed,
+            self.skipped,
+            self.duration_ms as f64 / 1000.0
+        )
+    }
+}
+
+fn case_name(case: &roxmltree::Node) -> String {
+    let name = case.attribute("name").unwrap_or("(unnamed)");
+    match case.attribute("classname").filter(|c| !c.is_empty() && *c != name) {
+        Some(class) => format!("{}::{}", class, name),
+        None => name.to_string(),
+    }
+}
+
+/// The `message` attribute, or the first line of the text if there is none
+fn failure_message(node: &roxmltree::Node) -> String {
+    let message = node
+        .attribute("message")
+        .filter(|m| !m.trim().is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            let text: String = node.descendants().filter_map(|n| n.text()).collect();
+            text.trim().lines().next().unwrap_or_default().to_string()
+        });
+    message.chars().take(MAX_MESSAGE_CHARS).collect()
+}
@@ -0,0 +1,315 @@
+//! CPU and memory limits for the processes of command, script and test
+//! nodes, so one heavy build cannot starve everything else on the server.
+//!
+//! `nice` works on every Unix server. On Linux each node gets a cgroup v2
+//! group, which caps memory and CPU time and tells us when the kernel killed
+//! a process for using too much memory. That needs the server to own its
+//! cgroup, as with `Delegate=yes` in a systemd unit. Without it memory is
+//! capped as `ulimit -v` would and the CPU limit is skipped. macOS and
+//! Windows skip what they cannot do, with a warning in the build log.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::build_log::BuildLog;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// Scheduling priority from -20 to 19, higher is nicer to other processes
+    pub nice: Option<i32>,
+    /// Cores' worth of CPU time
+    pub cpu_limit: Option<f64>,
+    pub memory_limit_mb: Option<u64>,
+}
+
+/// Error for a node whose processes were killed for using too much memory
+#[derive(Debug, thiserror::Error)]
+#[error("killed: memory limit {0} MB exceeded")]
+pub struct MemoryLimitExceeded(pub u64);
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.nice.is_none() && self.cpu_limit.is_none() && self.memory_limit_mb.is_none()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.nice.is_some_and(|nice| !(-20..=19).contains(&nice)) {
+            anyhow::bail!("nice must be between -20 and 19");
+        }
+        if self.cpu_limit.is_some_and(|cores| cores.is_nan() || cores <= 0.0) {
+            anyhow::bail!("cpu_limit must be more than 0 cores");
+        }
+        if self.memory_limit_mb == Some(0) {
+            anyhow::bail!("memory_limit_mb must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// The `nice`, `cpu_limit` and `memory_limit_mb` of a node, each falling
+    /// back to the server's default
+    pub fn for_node(config: &serde_json::Value, defaults: &ResourceLimits) -> Result<Self> {
+        let number = |key: &str| match config.get(key) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => value.as_f64().map(Some).with_context(|| format!("{} must be a number", key)),
+        };
+        let limits = Self {
+            nice: number("nice")?.map(|nice| nice as i32).or(defaults.nice),
+            cpu_limit: number("cpu_limit")?.or(defaults.cpu_limit),
+            memory_limit_mb: number("memory_limit_mb")?.map(|mb| mb as u64).or(defaults.memory_limit_mb),
+        };
+        limits.validate()?;
+        Ok(limits)
+    }
+}
+
+/// Limits put in place for one node's process. The group it ran in, if any,
+/// is removed when this is dropped.
+#[derive(Default)]
+pub struct Guard {
+    nice: Option<i32>,
+    memory_limit_mb: Option<u64>,
+    /// Address space cap when there is no cgroup
+    address_space: Option<u64>,
+    cgroup: Option<PathBuf>,
+}
+
+/// Sets up `limits` for a process of a node, logging what cannot be applied
+/// on this server
+pub async fn prepare(limits: &ResourceLimits, log: &BuildLog) -> Guard {
+    let mut guard = Guard {
+        nice: limits.nice,
+        memory_limit_mb: limits.memory_limit_mb,
+        address_space: None,
+        cgroup: None,
+    };
+    if limits.is_empty() {
+        return guard;
+    }
+    if cfg!(windows) {
+        log.line("[limits] Warning: nice, cpu_limit and memory_limit_mb are not supported on Windows servers, ignoring them").await;
+        return Guard::default();
+    }
+    if limits.nice.is_some_and(|nice| nice < 0) && !is_root() {
+        log.line("[limits] Warning: only root may raise a process's priority, ignoring the negative nice").await;
+        guard.nice = None;
+    }
+    if limits.cpu_limit.is_none() && limits.memory_limit_mb.is_none() {
+        return guard;
+    }
+    if !cfg!(target_os = "linux") {
+        log.line("[limits] Warning: cpu_limit and memory_limit_mb need a Linux server, ignoring them").await;
+        guard.memory_limit_mb = None;
+        return guard;
+    }
+
+    match cgroup::create(limits) {
+        Ok(dir) => guard.cgroup = Some(dir),
+        Err(e) => {
+            let mut warning = format!("[limits] Warning: cgroups cannot be used ({:#})", e);
+            if limits.memory_limit_mb.is_some() {
+                warning.push_str(", capping memory as ulimit -v does");
+            }
+            if limits.cpu_limit.is_some() {
+                warning.push_str(", ignoring cpu_limit");
+            }
+            log.line(&warning).await;
+            guard.address_space = limits.memory_limit_mb.map(|mb| mb * 1024 * 1024);
+        }
+    }
+    guard
+}
+
+impl Guard {
+    /// Arranges for the process `command` starts to run within the limits
+    pub fn apply(&self, command: &mut tokio::process::Command) {
+        #[cfg(unix)]
+        {
+            if self.nice.is_none() && self.address_space.is_none() && self.cgroup.is_none() {
+                return;
+            }
+            let nice = self.nice;
+            let address_space = self.address_space;
+            let procs = self
+                .cgroup
+                .as_ref()
+                .and_then(|dir| std::ffi::CString::new(dir.join("cgroup.procs").to_string_lossy().as_bytes()).ok());
+            // Runs in the child between fork and exec, so only system calls
+            unsafe {
+                command.pre_exec(move || {
+                    if let Some(procs) = &procs {
+                        // Writing 0 moves the writing process
+                        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+                        if fd < 0 || libc::write(fd, b"0".as_ptr().cast(), 1) < 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        libc::close(fd);
+                    }
+                    if let Some(bytes) = address_space {
+                        let limit = libc::rlimit {
+                            rlim_cur: bytes as libc::rlim_t,
+                            rlim_max: bytes as libc::rlim_t,
+                        };
+                        if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                    if let Some(nice) = nice {
+                        libc::setpriority(libc::PRIO_PROCESS, 0, nice);
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = command;
+    }
+
+    /// `result` of the process, or `MemoryLimitExceeded` if the kernel killed
+    /// any of it for going over the memory limit
+    pub fn check(&self, result: Result<i32>) -> Result<i32> {
+        let (Some(dir), Some(mb)) = (&self.cgroup, self.memory_limit_mb) else {
+            return result;
+        };
+        if result.is_ok() {
+            return result;
+        }
+        match cgroup::oom_kills(dir) {
+            Some(kills) if kills > 0 => Err(MemoryLimitExceeded(mb).into()),
+            _ => result,
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(dir) = self.cgroup.take() {
+            cgroup::remove(dir);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+mod cgroup {
+    use std::path::{Path, PathBuf};
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    use anyhow::{Context, Result};
+
+    use super::ResourceLimits;
+
+    /// Period `cpu.max` quotas are given in, in microseconds
+    const CPU_PERIOD: u64 = 100_000;
+
+    static PARENT: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+    /// The group node groups are made in: the server's own, once the
+    /// server has moved itself into a `server` leaf so controllers can be
+    /// handed down. Set up once and remembered, failure included.
+    fn parent() -> Result<&'static Path> {
+        PARENT
+            .get_or_init(|| setup().map_err(|e| format!("{:#}", e)))
+            .as_deref()
+            .map_err(|e| anyhow::anyhow!("{}", e))
+    }
+
+    fn setup() -> Result<PathBuf> {
+        let own = std::fs::read_to_string("/proc/self/cgroup").context("Failed to read /proc/self/cgroup")?;
+        let relative = own
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .context("the server is not in a cgroup v2 hierarchy")?;
+        let dir = Path::new("/sys/fs/cgroup").join(relative.trim().trim_start_matches('/'));
+        let controllers = std::fs::read_to_string(dir.join("cgroup.controllers")).unwrap_or_default();
+        if !controllers.split_whitespace().any(|c| c == "memory") {
+            anyhow::bail!("the memory controller is not available to the server's cgroup");
+        }
+
+        // A group with processes of its own cannot enable controllers for its children
+        let leaf = dir.join("server");
+        std::fs::create_dir_all(&leaf).context("the server may not create cgroups")?;
+        std::fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+            .context("the server may not move itself into a cgroup of its own")?;
+        std::fs::write(dir.join("cgroup.subtree_control"), "+memory")
+            .context("the server may not enable the memory controller")?;
+        // Without the cpu controller, cpu_limit is left out
+        let _ = std::fs::write(dir.join("cgroup.subtree_control"), "+cpu");
+        Ok(dir)
+    }
+
+    pub fn create(limits: &ResourceLimits) -> Result<PathBuf> {
+        let dir = parent()?.join(format!("node-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).context("the server may not create cgroups")?;
+        let configured = (|| {
+            if let Some(mb) = limits.memory_limit_mb {
+                std::fs::write(dir.join("memory.max"), (mb * 1024 * 1024).to_string())?;
+                // Killed at the limit instead of swapping past it
+                let _ = std::fs::write(dir.join("memory.swap.max"), "0");
+            }
+            if let Some(cores) = limits.cpu_limit {
+                let quota = ((cores * CPU_PERIOD as f64) as u64).max(1000);
+                std::fs::write(dir.join("cpu.max"), format!("{} {}", quota, CPU_PERIOD))?;
+            }
+            Ok::<_, std::io::Error>(())
+        })();
+        if let Err(e) = configured {
+            let _ = std::fs::remove_dir(&dir);
+            return Err(e).context("the server may not set cgroup limits");
+        }
+        Ok(dir)
+    }
+
+    /// Processes the kernel killed in `dir` for running out of memory
+    pub fn oom_kills(dir: &Path) -> Option<u64> {
+        let events = std::fs::read_to_string(dir.join("memory.events")).ok()?;
+        events
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|count| count.trim().parse().ok())
+    }
+
+    /// Kills whatever is left in `dir` and removes it once it is empty
+    pub fn remove(dir: PathBuf) {
+        let _ = std::fs::write(dir.join("cgroup.kill"), "1");
+        std::thread::spawn(move || {
+            for _ in 0..50 {
+                if std::fs::remove_dir(&dir).is_ok() {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod cgroup {
+    use std::path::{Path, PathBuf};
+
+    use anyhow::Result;
+
+    use super::ResourceLimits;
+
+    pub fn create(_limits: &ResourceLimits) -> Result<PathBuf> {
+        anyhow::bail!("cgroups need Linux")
+    }
+
+    pub fn oom_kills(_dir: &Path) -> Option<u64> {
+        None
+    }
+
+    pub fn remove(_dir: PathBuf) {}
+}
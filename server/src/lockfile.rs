@@ -0,0 +1,103 @@
+//! One process per data directory.
+//!
+//! The server and `run` both write `server-data.json` and the history
+//! database, so each holds `data_dir/server.lock` while it runs. The lock is
+//! taken by the operating system (`flock` on Unix, an unshared open on
+//! Windows), so it goes away with the process however it ends and a crash
+//! never leaves a stale lock behind. The file holds the pid of its owner,
+//! for the error the next process shows.
+
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+const LOCK_FILE: &str = "server.lock";
+
+/// Held for as long as the data directory is in use
+pub struct DataDirLock {
+    _file: File,
+}
+
+/// Takes the lock on `data_dir`, failing if another process holds it
+pub fn acquire(data_dir: &Path) -> Result<DataDirLock> {
+    std::fs::create_dir_all(data_dir).with_context(|| format!("Failed to create {}", data_dir.display()))?;
+    let path = data_dir.join(LOCK_FILE);
+    let mut file = match open_exclusive(&path)? {
+        Some(file) => file,
+        None => {
+            let mut owner = String::new();
+            let _ = File::open(&path).and_then(|mut f| f.read_to_string(&mut owner));
+            let owner = match owner.trim() {
+                "" => String::new(),
+                pid => format!(" (pid {})", pid),
+            };
+            anyhow::bail!(
+                "The data directory {} is in use by another BuildForge server{}; stop it or use another --data-dir",
+                data_dir.display(),
+                owner
+            );
+        }
+    };
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    file.flush()?;
+    Ok(DataDirLock { _file: file })
+}
+
+/// The lock file, locked, or None if another process has it
+#[cfg(unix)]
+fn open_exclusive(path: &Path) -> Result<Option<File>> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(Some(file));
+    }
+    let error = std::io::Error::last_os_error();
+    if error.kind() == std::io::ErrorKind::WouldBlock {
+        return Ok(None);
+    }
+    Err(error).with_context(|| format!("Failed to lock {}", path.display()))
+}
+
+#[cfg(windows)]
+fn open_exclusive(path: &Path) -> Result<Option<File>> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    // ERROR_SHARING_VIOLATION
+    const SHARING_VIOLATION: i32 = 32;
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .share_mode(0)
+        .open(path)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(SHARING_VIOLATION) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to open {}", path.display())),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn open_exclusive(path: &Path) -> Result<Option<File>> {
+    // No way to lock here; the pid is still written
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map(Some)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
@@ -0,0 +1,115 @@
+//! The server's own log: to the console, and with `--log-file` also to a
+//! file rotated daily or hourly, keeping `--log-keep` old files.
+//!
+//! `--log-format json` writes one JSON object per line to both, with the
+//! fields of the current span, so every line a build logs carries its
+//! `build_id` and `workflow_id`. File writes go through a background thread
+//! and never block the runtime; the returned guard flushes them on exit.
+//! `RUST_LOG` still applies, with `--log-level` setting the server's own
+//! level on top of it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{self, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum LogRotation {
+    #[default]
+    Daily,
+    Hourly,
+    Never,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct LogArgs {
+    /// Also write the log to this file, rotated as --log-rotation says
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Format of the log on the console and in the file
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+
+    /// Level of the server's own messages: error, warn, info, debug or trace
+    #[arg(long, default_value = "info", global = true)]
+    log_level: LevelFilter,
+
+    /// When the log file starts over
+    #[arg(long, value_enum, default_value_t = LogRotation::Daily, global = true)]
+    log_rotation: LogRotation,
+
+    /// Old log files kept next to the current one; 0 keeps them all
+    #[arg(long, default_value = "7", global = true)]
+    log_keep: usize,
+}
+
+/// Installs the global subscriber. Console output goes to stderr if
+/// `to_stderr`, to keep stdout for a command's own output.
+pub fn init(args: &LogArgs, to_stderr: bool) -> Result<Option<WorkerGuard>> {
+    let filter = EnvFilter::from_default_env().add_directive(format!("buildforge_server={}", args.log_level).parse()?);
+    let console = if to_stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+
+    let (file, guard) = match &args.log_file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender(args, path)?);
+            (Some(writer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let registry = tracing_subscriber::registry().with(filter);
+    match args.log_format {
+        LogFormat::Text => registry
+            .with(fmt::layer().with_writer(console))
+            .with(file.map(|writer| fmt::layer().with_ansi(false).with_writer(writer)))
+            .init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_current_span(true).with_span_list(false).with_writer(console))
+            .with(file.map(|writer| fmt::layer().json().with_current_span(true).with_span_list(false).with_writer(writer)))
+            .init(),
+    }
+    Ok(guard)
+}
+
+fn appender(args: &LogArgs, path: &Path) -> Result<rolling::RollingFileAppender> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("{} is not a file name", path.display()))?;
+    let rotation = match args.log_rotation {
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let mut builder = rolling::Builder::new().rotation(rotation).filename_prefix(file_name);
+    // The current file counts as one of them
+    if args.log_keep > 0 {
+        builder = builder.max_log_files(args.log_keep + 1);
+    }
+    builder
+        .build(dir)
+        .with_context(|| format!("Failed to open the log file {}", path.display()))
+}
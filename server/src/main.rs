@@ -1,36 +1,243 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
+use futures_util::future::{BoxFuture, FutureExt};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::process::Command;
-use tokio::sync::RwLock;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::sync::{broadcast, RwLock};
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
 use tracing::{error, info, warn};
 
-#[derive(Parser, Debug)]
+mod actions;
+mod api;
+mod ansi;
+mod archive;
+mod artifacts;
+mod audit;
+mod badge;
+mod build_log;
+mod bundle;
+mod cache;
+mod builds;
+mod changelog;
+mod checksums;
+mod cleanup;
+mod condition;
+mod container;
+mod crates;
+mod daemon;
+mod dmg;
+mod docker;
+mod environment;
+mod github;
+mod github_actions;
+mod headless;
+mod health;
+mod history;
+mod http;
+mod http_request;
+mod inspect;
+mod installer;
+mod junit;
+mod limits;
+mod lockfile;
+mod logging;
+mod manifests;
+mod matrix;
+mod notify;
+mod npm;
+mod persist;
+mod policy;
+mod process;
+mod repos;
+mod requirements;
+mod retention;
+mod schema;
+mod settings;
+mod shell;
+mod shutdown;
+mod signing;
+mod stats;
+mod templates;
+mod tools;
+mod wait;
+mod webhooks;
+mod workflow_file;
+mod workspace;
+
+use artifacts::ArtifactInfo;
+use build_log::BuildLog;
+use builds::{BuildCancelled, CancelToken, ConcurrencyPolicy, DisconnectPolicy, NodeFailed};
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+
+    #[command(flatten)]
+    log: logging::LogArgs,
+
     /// Port to listen on
     #[arg(short, long, default_value = "9876")]
     port: u16,
 
     /// GitHub token for creating releases
-    #[arg(long, env = "GITHUB_TOKEN")]
+    #[arg(long, env = "GITHUB_TOKEN", global = true)]
     github_token: Option<String>,
 
     /// Working directory for builds
-    #[arg(short, long, default_value = ".")]
+    #[arg(short, long, default_value = ".", global = true)]
     workdir: PathBuf,
 
     /// Data directory for storing workflows, actions, and settings
-    #[arg(long, default_value = "./data")]
+    #[arg(long, default_value = "./data", global = true)]
     data_dir: PathBuf,
+
+    /// Seconds between WebSocket pings sent to each client
+    #[arg(long, default_value = "30")]
+    heartbeat_interval: u64,
+
+    /// Seconds without any traffic from a client before its connection is dropped
+    #[arg(long, default_value = "90")]
+    heartbeat_timeout: u64,
+
+    /// Keep at most this many builds per workflow in the history
+    #[arg(long)]
+    max_history_per_workflow: Option<usize>,
+
+    /// Remove builds older than this many days from the history
+    #[arg(long)]
+    max_history_age_days: Option<u64>,
+
+    /// Seconds to let running builds finish after SIGINT/SIGTERM before cancelling them
+    #[arg(long, default_value = "30")]
+    shutdown_grace_period: u64,
+
+    /// Shell for script nodes that do not specify one; powershell on Windows
+    #[arg(long, default_value = shell::DEFAULT)]
+    default_shell: String,
+
+    /// Seconds a node may run before it is stopped, unless it sets its own timeout_secs
+    #[arg(long)]
+    default_node_timeout: Option<u64>,
+
+    /// Builds running at once across all workflows
+    #[arg(long)]
+    max_concurrent_builds: Option<usize>,
+
+    /// Let clients sync and watch builds, but not change anything or run code
+    #[arg(long)]
+    read_only: bool,
+
+    /// Run every build in its own copy of the working directory under data_dir/workspaces
+    #[arg(long)]
+    isolated_workspaces: bool,
+
+    /// Leave the workspace of a failed build on disk for debugging
+    #[arg(long)]
+    keep_workspace_on_failure: bool,
+
+    /// Days kept workspaces, retained artifacts and build tool directories stay on disk
+    #[arg(long)]
+    workspace_retention_days: Option<u64>,
+
+    /// GitHub API URL for release nodes, e.g. https://github.example.com/api/v3
+    #[arg(long)]
+    github_api_url: Option<String>,
+
+    /// GitHub upload URL for release assets; derived from --github-api-url if unset
+    #[arg(long)]
+    github_upload_url: Option<String>,
+
+    /// Megabytes the dependency cache under data_dir/cache may take before old entries are evicted
+    #[arg(long, default_value = "10240")]
+    cache_max_size_mb: Option<u64>,
+
+    /// Evict dependency cache entries unused for this many days
+    #[arg(long, default_value = "30")]
+    cache_max_age_days: Option<u64>,
+
+    /// Megabytes of output a node may write to the build log before the rest is dropped
+    #[arg(long, default_value = "10")]
+    max_node_output_mb: Option<u64>,
+
+    /// Bytes after which a line of build output is cut in the middle
+    #[arg(long, default_value = "16384")]
+    max_log_line_bytes: Option<usize>,
+
+    /// Escape sequences in build output: strip, keep, or auto to strip them from saved logs only
+    #[arg(long, value_enum, default_value = "auto")]
+    ansi_mode: ansi::AnsiMode,
+
+    /// Scheduling priority of node processes, from -20 to 19, unless a node sets its own nice
+    #[arg(long)]
+    node_nice: Option<i32>,
+
+    /// Cores a node's processes may use, unless the node sets its own cpu_limit (Linux, needs cgroups)
+    #[arg(long)]
+    node_cpu_limit: Option<f64>,
+
+    /// Megabytes of memory a node's processes may use, unless the node sets its own memory_limit_mb
+    #[arg(long)]
+    node_memory_limit_mb: Option<u64>,
+
+    /// Program or shell nodes may run; repeat to allow several. Anything is allowed if none is given
+    #[arg(long)]
+    allow_program: Vec<String>,
+
+    /// Regular expression no command or script may match; may be repeated
+    #[arg(long)]
+    deny_pattern: Vec<String>,
+
+    /// Refuse workflows with script nodes
+    #[arg(long)]
+    forbid_scripts: bool,
+
+    /// Secret GitHub signs webhook deliveries with, for repos that name none of their own
+    #[arg(long, env = "BUILDFORGE_WEBHOOK_SECRET")]
+    webhook_secret: Option<String>,
+
+    /// Port for the REST API; off if unset
+    #[arg(long)]
+    http_port: Option<u16>,
+
+    /// Bearer token every REST API request must carry
+    #[arg(long, env = "BUILDFORGE_API_TOKEN")]
+    api_token: Option<String>,
+
+    /// Serve build badges on the REST API port without the token
+    #[arg(long)]
+    public_badges: bool,
+
+    /// Write the server's pid to this file while it runs
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum CliCommand {
+    /// Serve WebSocket clients; the default
+    Serve,
+    /// Run one build of a stored workflow and exit with its outcome
+    Run(headless::RunArgs),
+    /// List the stored workflows or actions
+    List(inspect::ListArgs),
+    /// Show the latest builds of a workflow
+    History(inspect::HistoryArgs),
+    /// Write a workflow and the actions it runs as a YAML workflow file
+    Export(inspect::ExportArgs),
+    /// Install, uninstall or run as a Windows service
+    #[command(subcommand)]
+    Service(daemon::ServiceCommand),
 }
 
 // =====================================================
@@ -39,10 +246,15 @@ struct Args {
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct ServerData {
+    /// See `schema`; always `schema::CURRENT_VERSION` once loaded
+    #[serde(default)]
+    schema_version: u32,
     workflows: Vec<StoredWorkflow>,
     actions: Vec<StoredAction>,
     repos: Vec<StoredRepo>,
-    build_history: Vec<BuildRecord>,
+    /// Set once a client saved settings; until then the CLI flags apply
+    #[serde(default)]
+    settings: Option<settings::ServerSettings>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,6 +265,18 @@ struct StoredWorkflow {
     nodes: Vec<serde_json::Value>,
     connections: Vec<serde_json::Value>,
     next_version: String,
+    /// What to do when a build of this workflow is requested while one is already running
+    #[serde(default)]
+    concurrency: ConcurrencyPolicy,
+    /// Container command and script nodes run in by default, see `container`
+    #[serde(default)]
+    container: Option<container::ContainerConfig>,
+    /// Tools checked before any node runs, see `requirements`
+    #[serde(default)]
+    requires: Vec<requirements::Spec>,
+    /// Pushes, tags and releases that start a build, see `webhooks`
+    #[serde(default)]
+    triggers: Vec<webhooks::Trigger>,
     created_at: String,
     updated_at: String,
 }
@@ -65,6 +289,18 @@ struct StoredAction {
     script: String,
     inputs: Vec<serde_json::Value>,
     outputs: Vec<serde_json::Value>,
+    /// Goes up by one on every save, see `actions::KEPT_VERSIONS`
+    #[serde(default)]
+    version: u64,
+    /// Program that runs the script, see `actions::INTERPRETERS`; `bash` if unset
+    #[serde(default)]
+    interpreter: Option<String>,
+    /// A run still going after this long is killed, unless `RunAction` says otherwise
+    #[serde(default)]
+    timeout_secs: Option<u64>,
+    /// Tools checked before a build with an action node for it starts, see `requirements`
+    #[serde(default)]
+    requires: Vec<requirements::Spec>,
     created_at: String,
     updated_at: String,
 }
@@ -77,6 +313,15 @@ struct StoredRepo {
     repo: Option<String>,
     default_branch: String,
     cloned_at: Option<String>,
+    /// GitHub Enterprise Server API URL; the server setting applies if unset
+    #[serde(default)]
+    api_base_url: Option<String>,
+    #[serde(default)]
+    upload_base_url: Option<String>,
+    /// Server environment variable holding this repo's webhook secret; the
+    /// server's `--webhook-secret` applies if unset
+    #[serde(default)]
+    webhook_secret_env: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -87,33 +332,278 @@ struct BuildRecord {
     started_at: String,
     finished_at: Option<String>,
     duration_ms: Option<u64>,
+    /// Last lines of the build log; the full log is in `log_file`
     logs: Vec<String>,
+    /// Full log of the build, see `build_log`
+    #[serde(default)]
+    log_file: Option<String>,
+    /// Node whose failure ended the build
+    #[serde(default)]
+    failed_node: Option<String>,
+    /// Files collected by artifact nodes
+    #[serde(default)]
+    artifacts: Vec<ArtifactInfo>,
+    /// GitHub release created by the build
+    #[serde(default)]
+    release_url: Option<String>,
+    /// Every node of the workflow, in execution order
+    #[serde(default)]
+    node_runs: Vec<NodeRun>,
+    /// OS, architecture and tool versions at build start, see `environment`
+    #[serde(default)]
+    environment: HashMap<String, String>,
+    /// Commit the workflow's repo was synced to before the build
+    #[serde(default)]
+    commit_sha: Option<String>,
+    /// As the build was started with; empty in records from older servers
+    #[serde(default)]
+    project_name: String,
+    #[serde(default)]
+    version: String,
+}
+
+/// One execution attempt of a build node, as sent in NodeStart/NodeComplete
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NodeRun {
+    node_id: String,
+    name: String,
+    node_type: String,
+    /// One of the `builds::status` values
+    status: String,
+    /// Starts at 1; retries of the same node increase it
+    attempt: u32,
+    /// The node never ran because an earlier one failed or the build was cancelled
+    #[serde(default)]
+    skipped: bool,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    duration_ms: Option<u64>,
+    /// Part of `duration_ms` spent deliberately waiting, left out of statistics
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    waited_ms: Option<u64>,
+    exit_code: Option<i32>,
+    /// What the reports of a test node add up to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tests: Option<junit::TestSummary>,
+    /// Output past the node's limit was dropped, see `build_log::OutputLimits`
+    #[serde(default)]
+    output_truncated: bool,
+    /// The stored action and version an action node ran
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    action: Option<actions::UsedAction>,
+}
+
+impl NodeRun {
+    fn start(node: &BuildNode, attempt: u32) -> Self {
+        Self {
+            node_id: node.id.clone(),
+            name: node.name.clone(),
+            node_type: node.node_type.clone(),
+            status: builds::status::RUNNING.to_string(),
+            attempt,
+            skipped: false,
+            started_at: Some(chrono::Utc::now().to_rfc3339()),
+            finished_at: None,
+            duration_ms: None,
+            waited_ms: None,
+            exit_code: None,
+            tests: None,
+            output_truncated: false,
+            action: None,
+        }
+    }
+
+    fn skipped(node: &BuildNode) -> Self {
+        Self {
+            status: builds::status::SKIPPED.to_string(),
+            attempt: 0,
+            skipped: true,
+            started_at: None,
+            ..Self::start(node, 0)
+        }
+    }
+
+    fn finish(&mut self, result: &Result<Option<i32>>) {
+        let finished_at = chrono::Utc::now();
+        if let Some(started_at) = self
+            .started_at
+            .as_deref()
+            .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+        {
+            let elapsed = finished_at - started_at.with_timezone(&chrono::Utc);
+            self.duration_ms = Some(elapsed.num_milliseconds().max(0) as u64);
+        }
+        if self.node_type == "wait" {
+            self.waited_ms = self.duration_ms;
+        }
+        self.finished_at = Some(finished_at.to_rfc3339());
+        
+        let (status, exit_code) = match result {
+            Ok(code) => (builds::status::SUCCESS, *code),
+            Err(e) if e.is::<BuildCancelled>() => (builds::status::CANCELLED, None),
+            Err(e) => (
+                builds::status::FAILED,
+                e.downcast_ref::<process::ProcessFailed>().and_then(|f| f.exit_code),
+            ),
+        };
+        self.status = status.to_string();
+        self.exit_code = exit_code;
+    }
 }
 
 type SharedData = Arc<RwLock<ServerData>>;
 
+/// State shared by every connection and build task
+struct ServerContext {
+    github_token: Option<String>,
+    webhook_secret: Option<String>,
+    /// Port of the REST API, see `api`
+    http_port: Option<u16>,
+    api_token: Option<String>,
+    /// Badges need no API token, see `badge`
+    public_badges: bool,
+    workdir: PathBuf,
+    data_dir: PathBuf,
+    data: SharedData,
+    persistence: persist::Persistence,
+    history: history::HistoryStore,
+    settings: std::sync::RwLock<settings::ServerSettings>,
+    /// Port the server is actually listening on
+    bound_port: u16,
+    builds: builds::SharedRegistry,
+    actions: actions::SharedRegistry,
+    /// Messages fanned out to every connected client
+    events: broadcast::Sender<ServerMessage>,
+    audit: audit::AuditLog,
+    connected_clients: AtomicUsize,
+    next_connection_id: AtomicU64,
+    started: Instant,
+    /// Set once a shutdown signal arrived; no new builds are accepted after that
+    shutting_down: AtomicBool,
+    /// Refuse every request that changes data or executes code, see `--read-only`
+    read_only: bool,
+}
+
+impl ServerContext {
+    /// Current settings
+    fn settings(&self) -> settings::ServerSettings {
+        self.settings.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn broadcast(&self, msg: ServerMessage) {
+        // No receivers just means no client is connected
+        let _ = self.events.send(msg);
+    }
+}
+
+impl ServerMessage {
+    /// Name of the request if it changes data or executes code
+    fn mutation(&self) -> Option<&'static str> {
+        match self {
+            ServerMessage::BuildStart(_) => Some("BuildStart"),
+            ServerMessage::BuildCancel(_) => Some("BuildCancel"),
+            ServerMessage::SaveWorkflow(_) => Some("SaveWorkflow"),
+            ServerMessage::DeleteWorkflow(_) => Some("DeleteWorkflow"),
+            ServerMessage::SaveAction(_) => Some("SaveAction"),
+            ServerMessage::DeleteAction(_) => Some("DeleteAction"),
+            ServerMessage::SaveRepo(_) => Some("SaveRepo"),
+            ServerMessage::DeleteRepo(_) => Some("DeleteRepo"),
+            ServerMessage::DeleteRepoFiles(_) => Some("DeleteRepoFiles"),
+            ServerMessage::CloneRepo(_) => Some("CloneRepo"),
+            ServerMessage::RunAction(_) => Some("RunAction"),
+            ServerMessage::CancelAction(_) => Some("CancelAction"),
+            ServerMessage::TestAction(_) => Some("TestAction"),
+            ServerMessage::SetSettings(_) => Some("SetSettings"),
+            ServerMessage::DeleteBuildRecord(_) => Some("DeleteBuildRecord"),
+            ServerMessage::ClearBuildHistory(_) => Some("ClearBuildHistory"),
+            ServerMessage::ImportData(_) => Some("ImportData"),
+            ServerMessage::ImportWorkflow(_) => Some("ImportWorkflow"),
+            ServerMessage::RunCleanup => Some("RunCleanup"),
+            _ => None,
+        }
+    }
+}
+
 impl ServerData {
-    fn load(data_dir: &PathBuf) -> Result<Self> {
-        let path = data_dir.join("server-data.json");
-        if path.exists() {
-            let content = std::fs::read_to_string(&path)?;
-            let data: ServerData = serde_json::from_str(&content)?;
-            info!("Loaded {} workflows, {} actions from {}", 
-                data.workflows.len(), data.actions.len(), path.display());
-            Ok(data)
-        } else {
+    /// Loads `server-data.json`, falling back to the backup kept by `persist`
+    /// if the main file is unreadable, and upgrades it to the current schema.
+    /// Fails rather than starting fresh so a broken file is never silently
+    /// overwritten.
+    fn load(data_dir: &Path) -> Result<(Self, schema::Migrated)> {
+        let path = data_dir.join(persist::DATA_FILE);
+        let backup = data_dir.join(persist::BACKUP_FILE);
+        if !path.exists() && !backup.exists() {
             info!("No existing data found, starting fresh");
-            Ok(ServerData::default())
+            let data = ServerData {
+                schema_version: schema::CURRENT_VERSION,
+                ..Default::default()
+            };
+            let migrated = schema::Migrated {
+                from: schema::CURRENT_VERSION,
+                history: Vec::new(),
+            };
+            return Ok((data, migrated));
         }
+        
+        let read = |path: &PathBuf| -> Result<(ServerData, schema::Migrated)> {
+            let content = std::fs::read_to_string(path)?;
+            let mut value: serde_json::Value = serde_json::from_str(&content)?;
+            let migrated = schema::migrate_file(&mut value, path)?;
+            Ok((serde_json::from_value(value)?, migrated))
+        };
+        
+        let ((data, migrated), source) = match read(&path) {
+            Ok(loaded) => (loaded, path),
+            // The backup is older, loading it would throw away newer data
+            Err(e) if e.is::<schema::SchemaTooNew>() => return Err(e),
+            Err(e) => {
+                error!("Failed to load {}: {}", path.display(), e);
+                let loaded = read(&backup).with_context(|| {
+                    format!("Neither {} nor {} could be loaded", path.display(), backup.display())
+                })?;
+                warn!("Recovered data from {}", backup.display());
+                (loaded, backup)
+            }
+        };
+        info!("Loaded {} workflows, {} actions from {}", 
+            data.workflows.len(), data.actions.len(), source.display());
+        Ok((data, migrated))
+    }
+
+    /// Reads `server-data.json` without writing anything, for the inspection
+    /// subcommands. A read that fails is retried once, as a running server
+    /// may be replacing the file at that moment.
+    fn read(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(persist::DATA_FILE);
+        let read = || -> Result<Self> {
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let mut value: serde_json::Value = serde_json::from_str(&content)
+                .with_context(|| format!("{} is not valid JSON", path.display()))?;
+            schema::upgrade(&mut value, &path.display().to_string())?;
+            Ok(serde_json::from_value(value)?)
+        };
+        read().or_else(|e| {
+            if e.is::<schema::SchemaTooNew>() {
+                return Err(e);
+            }
+            warn!("{:#}; reading it again", e);
+            std::thread::sleep(Duration::from_millis(200));
+            read()
+        })
     }
 
-    fn save(&self, data_dir: &PathBuf) -> Result<()> {
-        std::fs::create_dir_all(data_dir)?;
-        let path = data_dir.join("server-data.json");
-        let content = serde_json::to_string_pretty(self)?;
-        std::fs::write(&path, content)?;
-        info!("Saved data to {}", path.display());
-        Ok(())
+    /// The workflow with id `id_or_name`, or else the only one with that name
+    fn workflow(&self, id_or_name: &str) -> Result<&StoredWorkflow> {
+        if let Some(workflow) = self.workflows.iter().find(|w| w.id == id_or_name) {
+            return Ok(workflow);
+        }
+        let mut named = self.workflows.iter().filter(|w| w.name == id_or_name);
+        let workflow = named.next().with_context(|| format!("Workflow not found: {}", id_or_name))?;
+        if named.next().is_some() {
+            anyhow::bail!("Several workflows are named {}; give its id instead", id_or_name);
+        }
+        Ok(workflow)
     }
 }
 
@@ -122,11 +612,58 @@ impl ServerData {
 enum ServerMessage {
     Ping,
     Pong,
+    Capabilities(CapabilitiesPayload),
+    ReadOnly(ReadOnlyPayload),
     BuildStart(BuildStartPayload),
+    BuildStarted(BuildStartedPayload),
+    BuildQueued(BuildQueuedPayload),
     BuildProgress(BuildProgressPayload),
     BuildComplete(BuildCompletePayload),
+    NodeStart(NodeEventPayload),
+    NodeComplete(NodeEventPayload),
     BuildLog(BuildLogPayload),
     BuildCancel(String),
+    BuildNotCancelled(BuildNotCancelledPayload),
+    BuildAlreadyRunning(BuildAlreadyRunningPayload),
+    GetServerStatus,
+    ServerStatus(ServerStatusPayload),
+    ServerShuttingDown(ServerShuttingDownPayload),
+    DataSaveFailed(DataSaveFailedPayload),
+    ClientInfo(ClientInfoPayload),
+    GetBuildHistory(BuildHistoryQuery),
+    BuildHistory(BuildHistoryPage),
+    GetBuildStats(BuildStatsQuery),
+    BuildStats(BuildStatsPayload),
+    DeleteBuildRecord(String),
+    ClearBuildHistory(ClearBuildHistoryPayload),
+    BuildRecordsDeleted(BuildRecordsDeletedPayload),
+    BuildStillRunning(String),
+    GetBuildLogs(BuildLogsQuery),
+    BuildLogs(BuildLogsPage),
+    GetArtifacts(artifacts::GetArtifactsQuery),
+    Artifacts(artifacts::BuildArtifactsPayload),
+    /// Followed by the file as binary frames, see `artifacts`
+    DownloadArtifact(artifacts::DownloadArtifactRequest),
+    ArtifactDownload(artifacts::ArtifactDownloadPayload),
+    GetSettings,
+    SetSettings(settings::ServerSettings),
+    Settings(SettingsPayload),
+    ExportData(ExportDataRequest),
+    DataExport(bundle::DataBundle),
+    ExportWorkflow(String),
+    WorkflowExport(workflow_file::WorkflowExportPayload),
+    ImportWorkflow(workflow_file::ImportWorkflowRequest),
+    WorkflowImported(workflow_file::WorkflowImportResult),
+    ExportWorkflowAsGitHubActions(String),
+    GitHubActionsWorkflow(github_actions::GitHubActionsExport),
+    ImportGitHubActionsWorkflow(github_actions::ImportGitHubActionsRequest),
+    GitHubActionsImport(github_actions::GitHubActionsImport),
+    ImportData(ImportDataRequest),
+    DataImported(bundle::DataImportResult),
+    GetAuditLog(AuditLogQuery),
+    AuditLog(AuditLogPage),
+    RunCleanup,
+    CleanupReport(cleanup::CleanupReport),
     Error(String),
     // Data sync messages
     SyncRequest,
@@ -136,7 +673,33 @@ enum ServerMessage {
     SaveAction(StoredAction),
     DeleteAction(String),
     RunAction(RunActionPayload),
-    ActionResult(ActionResultPayload),
+    ActionLog(actions::ActionLogPayload),
+    ActionResult(actions::ActionResultPayload),
+    CancelAction(actions::CancelActionPayload),
+    ActionNotCancelled(actions::ActionNotCancelledPayload),
+    TestAction(actions::TestActionPayload),
+    ActionTestResult(actions::ActionTestReport),
+    ActionDeleted(ActionDeletedPayload),
+    ListActionVersions(String),
+    ActionVersions(actions::ActionVersionsPayload),
+    GetWebhookDeliveries(webhooks::WebhookDeliveriesQuery),
+    WebhookDeliveries(webhooks::WebhookDeliveriesPage),
+    GetActionHistory(actions::ActionHistoryQuery),
+    ActionHistory(actions::ActionHistoryPage),
+    ListTemplates,
+    Templates(templates::TemplatesPayload),
+    InstantiateTemplate(templates::InstantiateTemplatePayload),
+    TemplateInstance(templates::TemplateInstancePayload),
+    SaveRepo(StoredRepo),
+    RepoSaved(StoredRepo),
+    DeleteRepo(String),
+    /// Like DeleteRepo, and also removes the working copy if the server cloned it
+    DeleteRepoFiles(String),
+    RepoDeleted(RepoDeletedPayload),
+    CloneRepo(CloneRepoRequest),
+    CloneProgress(repos::CloneProgressPayload),
+    RepoCloned(repos::RepoClonedPayload),
+    CloneFailed(repos::CloneFailedPayload),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -150,23 +713,316 @@ struct SyncData {
 struct RunActionPayload {
     action_id: String,
     inputs: HashMap<String, String>,
+    /// Tags the run's `ActionLog` and `ActionResult`; one is made up if unset
+    #[serde(default)]
+    run_id: Option<String>,
+    /// Overrides the action's `timeout_secs`
+    #[serde(default)]
+    timeout_secs: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct ActionResultPayload {
-    action_id: String,
-    success: bool,
-    output: String,
+struct RepoDeletedPayload {
+    id: String,
+    /// Workflows that still point at the deleted repo; their builds fail
+    /// until they are given another one
+    affected_workflows: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ActionDeletedPayload {
+    id: String,
+    /// Workflows with action nodes that still name the deleted action;
+    /// their builds fail until the nodes are changed
+    affected_workflows: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CloneRepoRequest {
+    /// Chosen by the client to match up CloneProgress and the outcome
+    clone_id: String,
+    url: String,
+    #[serde(default)]
+    branch: Option<String>,
+    /// Where to clone to, relative to `--workdir`; the repo name by default
+    #[serde(default)]
+    dest: Option<String>,
+    /// Only fetch the latest commit
+    #[serde(default)]
+    shallow: bool,
+}
+
+/// Sent to every client right after it connects
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CapabilitiesPayload {
+    version: String,
+    /// Mutating requests will be answered with ReadOnly
+    read_only: bool,
+    /// Shells installed on the server that nodes can name, see `shell`
+    #[serde(default)]
+    shells: Vec<String>,
+    /// Interpreters installed on the server that actions can name
+    #[serde(default)]
+    interpreters: Vec<String>,
+    /// Set when the server restricts what workflows may run, so the editor
+    /// can warn about nodes it would refuse
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    execution_policy: Option<policy::ExecutionPolicy>,
+}
+
+/// Refusal of a request because the server is read-only
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReadOnlyPayload {
+    /// Type of the refused message
+    action: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct BuildStartPayload {
     build_id: String,
+    #[serde(default)]
+    workflow_id: String,
+    /// Overrides the stored workflow's concurrency policy for this request
+    #[serde(default)]
+    concurrency: Option<ConcurrencyPolicy>,
+    /// What happens to the build if the client that started it goes away
+    #[serde(default)]
+    on_disconnect: DisconnectPolicy,
+    /// Branch, tag or commit to build; the repo's default branch when absent
+    #[serde(rename = "ref", default)]
+    git_ref: Option<String>,
+    /// Commit of `ref` to check out rather than its head, such as the one a
+    /// push delivered
+    #[serde(default)]
+    commit: Option<String>,
+    /// Stash uncommitted changes in the repo instead of failing the build
+    #[serde(default)]
+    force_clean: bool,
     project_name: String,
     version: String,
     nodes: Vec<BuildNode>,
     edges: Vec<BuildEdge>,
     github_token: Option<String>,
+    /// Environment for every process of the build; env nodes and a node's
+    /// own `env` take precedence
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Overrides the server's `ansi_mode` for this build
+    #[serde(default)]
+    ansi_mode: Option<ansi::AnsiMode>,
+    /// Tag whose push or release started the build, as `$TAG`
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildStartedPayload {
+    build_id: String,
+    workflow_id: String,
+    estimated_duration_ms: Option<u64>,
+}
+
+/// Answer to a `BuildCancel` that had nothing to cancel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildNotCancelledPayload {
+    build_id: String,
+    reason: actions::NotCancelledReason,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildQueuedPayload {
+    build_id: String,
+    workflow_id: String,
+    /// 1-based position among the builds waiting for this workflow
+    position: usize,
+    /// Expected time until this build starts, from now
+    estimated_wait_ms: Option<u64>,
+    estimated_duration_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildAlreadyRunningPayload {
+    build_id: String,
+    workflow_id: String,
+    running_build_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ServerStatusPayload {
+    version: String,
+    uptime_secs: u64,
+    connected_clients: usize,
+    running_builds: Vec<RunningBuildInfo>,
+    queued_builds: Vec<BuildQueuedPayload>,
+    retention: retention::RetentionPolicy,
+    read_only: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ServerShuttingDownPayload {
+    /// How long running builds get to finish before they are cancelled
+    grace_period_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DataSaveFailedPayload {
+    message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClientInfoPayload {
+    name: String,
+    hostname: String,
+    app_version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildHistoryQuery {
+    #[serde(default)]
+    workflow_id: Option<String>,
+    /// Just this build
+    #[serde(default)]
+    build_id: Option<String>,
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default = "default_page_size")]
+    limit: usize,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildHistoryPage {
+    /// Newest first, without `logs`; use GetBuildLogs for those
+    records: Vec<BuildRecord>,
+    total: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildStatsQuery {
+    workflow_id: String,
+    /// Only consider the last N runs; all of them when absent
+    #[serde(default)]
+    window: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildStatsPayload {
+    workflow_id: String,
+    /// Runs the statistics cover
+    runs: usize,
+    /// Runs per `builds::status` value
+    counts: HashMap<String, usize>,
+    /// Successful runs over finished runs, 0.0 to 1.0
+    success_rate: f64,
+    average_duration_ms: u64,
+    median_duration_ms: u64,
+    p95_duration_ms: u64,
+    /// Average time a run spent in wait nodes, already left out of the
+    /// durations above
+    #[serde(default)]
+    average_wait_ms: u64,
+    /// Average duration of each node that ran, by node id
+    node_average_duration_ms: HashMap<String, u64>,
+    last_success_at: Option<String>,
+    last_failure_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClearBuildHistoryPayload {
+    #[serde(default)]
+    workflow_id: Option<String>,
+    /// RFC 3339 timestamp; only builds started before it are removed
+    #[serde(default)]
+    before: Option<String>,
+    /// Only report how many records would be removed
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildRecordsDeletedPayload {
+    /// Records removed, or that would be removed on a dry run
+    count: usize,
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildLogsQuery {
+    build_id: String,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_log_page_size")]
+    limit: usize,
+}
+
+fn default_log_page_size() -> usize {
+    1000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BuildLogsPage {
+    build_id: String,
+    offset: usize,
+    lines: Vec<String>,
+    total: usize,
+    /// False while the build is still running and the log may grow
+    complete: bool,
+    /// Per-node timings so far
+    #[serde(default)]
+    node_runs: Vec<NodeRun>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SettingsPayload {
+    settings: settings::ServerSettings,
+    /// Changed settings that only take effect once the server restarts
+    restart_required: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ExportDataRequest {
+    /// Also bundle the full build logs, which can make the bundle large
+    #[serde(default)]
+    include_logs: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ImportDataRequest {
+    /// Upsert by id instead of replacing all data
+    #[serde(default)]
+    merge: bool,
+    /// A bundle produced by ExportData
+    bundle: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuditLogQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_page_size")]
+    limit: usize,
+}
+
+fn default_page_size() -> usize {
+    50
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuditLogPage {
+    /// Newest first
+    entries: Vec<audit::AuditEntry>,
+    total: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RunningBuildInfo {
+    build_id: String,
+    workflow_id: String,
+    project_name: String,
+    current_node: Option<String>,
+    progress: u8,
+    started_at: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -176,19 +1032,41 @@ struct BuildProgressPayload {
     current_node: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NodeEventPayload {
+    build_id: String,
+    node: NodeRun,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct BuildCompletePayload {
     build_id: String,
+    /// One of the `builds::status` values
+    #[serde(default)]
+    status: String,
     success: bool,
     duration: u64,
     artifacts: Vec<String>,
     release_url: Option<String>,
+    #[serde(default)]
+    project_name: String,
+    #[serde(default)]
+    version: String,
+    /// Name of the node the build failed in
+    #[serde(default)]
+    failed_node: Option<String>,
+    /// First line of what went wrong, for a failed build
+    #[serde(default)]
+    error: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct BuildLogPayload {
     build_id: String,
     log: String,
+    stream: build_log::Stream,
+    /// When the line arrived, RFC 3339 with milliseconds
+    ts: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -205,45 +1083,147 @@ struct BuildEdge {
     id: String,
     source: String,
     target: String,
+    /// For a matrixed source, whether all of its instances must succeed
+    #[serde(default)]
+    wait: matrix::EdgeWait,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive("buildforge_server=info".parse()?)
-        )
-        .init();
-
     let args = Args::parse();
-    
+    // Stdout is for the build log, tables and JSON of the other commands
+    let serving = matches!(args.command, Some(CliCommand::Serve) | None);
+    let log_guard = logging::init(&args.log, !serving)?;
+
+    // Read-only, so they take no lock and may run next to a server
+    match args.command.clone() {
+        Some(CliCommand::List(list)) => return inspect::list(&args.data_dir, list),
+        Some(CliCommand::History(history)) => return inspect::history(&args.data_dir, history).await,
+        Some(CliCommand::Export(export)) => return inspect::export(&args.data_dir, export),
+        // The service takes the lock itself once started
+        Some(CliCommand::Service(command)) => return daemon::service(&args, command).await,
+        _ => {}
+    }
+
+    // Held until the process exits
+    let _lock = lockfile::acquire(&args.data_dir)?;
+    let ctx = open_context(&args).await?;
+
+    let code = match args.command.clone() {
+        Some(CliCommand::Run(run)) => {
+            let result = headless::run(&ctx, run).await;
+            if let Err(e) = ctx.persistence.flush(&ctx).await {
+                error!("Failed to save data: {}", e);
+            }
+            result?
+        }
+        _ => {
+            let pid_file = args.pid_file.as_deref().map(daemon::PidFile::create).transpose()?;
+            let code = serve(ctx).await?;
+            drop(pid_file);
+            code
+        }
+    };
+    // Exiting skips destructors, so the log file is flushed first
+    drop(log_guard);
+    std::process::exit(code);
+}
+
+/// Loads the data directory into a context shared by everything that runs
+async fn open_context(args: &Args) -> Result<Arc<ServerContext>> {
     // Initialize data storage
-    let data = ServerData::load(&args.data_dir).unwrap_or_default();
-    let shared_data: SharedData = Arc::new(RwLock::new(data));
+    let (data, migrated) = ServerData::load(&args.data_dir)?;
+    let history = history::HistoryStore::open(&args.data_dir)?;
+    let interrupted = history.mark_interrupted().await?;
+    if interrupted > 0 {
+        warn!("Marked {} builds left running by a previous run as interrupted", interrupted);
+    }
     
-    let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
-    let listener = TcpListener::bind(&addr).await?;
+    // Older data files carry build history inline; move it into the database
+    if !migrated.history.is_empty() {
+        let count = history.import(migrated.history).await?;
+        info!("Migrated {} build records into the history database", count);
+    }
+    // Saved settings win over the flags they were first seeded from
+    let server_settings = match &data.settings {
+        Some(saved) => {
+            info!("Using saved server settings; command-line defaults are ignored");
+            saved.clone()
+        }
+        None => settings::ServerSettings::from_args(args),
+    };
+    let shared_data: SharedData = Arc::new(RwLock::new(data));
     
-    info!("BuildForge server listening on {}", addr);
     info!("Working directory: {:?}", args.workdir);
     info!("Data directory: {:?}", args.data_dir);
+    if args.read_only {
+        info!("Read-only mode: clients cannot change data or run builds");
+    }
     
     if args.github_token.is_some() {
         info!("GitHub token configured");
     }
 
+    let ctx = Arc::new(ServerContext {
+        github_token: args.github_token.clone(),
+        webhook_secret: args.webhook_secret.clone(),
+        http_port: args.http_port,
+        api_token: args.api_token.clone().filter(|token| !token.is_empty()),
+        public_badges: args.public_badges,
+        workdir: args.workdir.clone(),
+        data_dir: args.data_dir.clone(),
+        data: shared_data,
+        persistence: Default::default(),
+        history,
+        bound_port: server_settings.port,
+        settings: std::sync::RwLock::new(server_settings),
+        builds: Default::default(),
+        actions: Default::default(),
+        events: broadcast::channel(256).0,
+        audit: audit::AuditLog::new(&args.data_dir),
+        connected_clients: AtomicUsize::new(0),
+        next_connection_id: AtomicU64::new(1),
+        started: Instant::now(),
+        shutting_down: AtomicBool::new(false),
+        read_only: args.read_only,
+    });
+
+    if migrated.from < schema::CURRENT_VERSION {
+        ctx.persistence.mark_dirty();
+    }
+    tokio::spawn(persist::run(ctx.clone()));
+    retention::apply(&ctx).await;
+    cleanup::remove_stale_scripts(&ctx).await;
+    Ok(ctx)
+}
+
+/// Accepts WebSocket clients until a shutdown signal, then drains running
+/// builds and returns the exit code
+async fn serve(ctx: Arc<ServerContext>) -> Result<i32> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], ctx.bound_port));
+    let listener = TcpListener::bind(&addr).await?;
+    info!("BuildForge server listening on {}", addr);
+    daemon::ready(ctx.bound_port);
+    if let Some(port) = ctx.http_port {
+        tokio::spawn(api::serve(ctx.clone(), port));
+    }
+    tokio::spawn(cleanup::run_periodically(ctx.clone()));
+
+    let signal = shutdown::signal();
+    tokio::pin!(signal);
+
     loop {
-        match listener.accept().await {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut signal => break,
+        };
+        match accepted {
             Ok((stream, peer)) => {
                 info!("New connection from {}", peer);
-                let github_token = args.github_token.clone();
-                let workdir = args.workdir.clone();
-                let data_dir = args.data_dir.clone();
-                let data_clone = shared_data.clone();
+                let ctx = ctx.clone();
                 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_connection(stream, github_token, workdir, data_dir, data_clone).await {
+                    if let Err(e) = handle_connection(stream, peer, ctx).await {
                         error!("Connection error: {}", e);
                     }
                 });
@@ -253,15 +1233,89 @@ async fn main() -> Result<()> {
             }
         }
     }
+
+    // Stop accepting connections before draining builds
+    drop(listener);
+    Ok(shutdown::run(&ctx).await)
 }
 
-async fn handle_connection(
-    stream: TcpStream,
-    github_token: Option<String>,
-    workdir: PathBuf,
-    data_dir: PathBuf,
-    shared_data: SharedData,
-) -> Result<()> {
+async fn get_build_logs(ctx: &ServerContext, query: BuildLogsQuery) -> Result<BuildLogsPage> {
+    let live_node_runs = builds::node_runs(ctx, &query.build_id).await;
+    let running = live_node_runs.is_some();
+    let record = ctx.history.get(&query.build_id).await?;
+    if record.is_none() && !running {
+        anyhow::bail!("Build not found: {}", query.build_id);
+    }
+    
+    let node_runs = match (live_node_runs, &record) {
+        (Some(runs), _) => runs,
+        (None, Some(record)) => record.node_runs.clone(),
+        (None, None) => Vec::new(),
+    };
+    let path = record
+        .and_then(|r| r.log_file)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| build_log::default_path(&ctx.data_dir, &query.build_id));
+    let (lines, total) = build_log::read_page(&path, query.offset, query.limit).await?;
+    
+    Ok(BuildLogsPage {
+        build_id: query.build_id,
+        offset: query.offset,
+        lines,
+        total,
+        complete: !running,
+        node_runs,
+    })
+}
+
+/// Stores new settings, applies what can change while running and tells
+/// every client
+async fn apply_settings(ctx: &Arc<ServerContext>, new_settings: settings::ServerSettings) {
+    ctx.data.write().await.settings = Some(new_settings.clone());
+    ctx.persistence.mark_dirty();
+    *ctx.settings.write().unwrap_or_else(|e| e.into_inner()) = new_settings.clone();
+    
+    // A higher limit may let queued builds start, a tighter policy prunes now
+    builds::schedule(ctx).await;
+    retention::apply(ctx).await;
+    
+    ctx.broadcast(ServerMessage::Settings(SettingsPayload {
+        restart_required: new_settings.restart_required(ctx.bound_port),
+        settings: new_settings,
+    }));
+}
+
+/// Appends to the audit log; failures are logged but never fail the request
+async fn audit(
+    ctx: &ServerContext,
+    action: &str,
+    entity_id: &str,
+    client: Option<&ClientInfoPayload>,
+    peer: SocketAddr,
+) {
+    let entry = audit::AuditEntry::new(action, entity_id, client, &peer.to_string());
+    if let Err(e) = ctx.audit.record(entry).await {
+        error!("Failed to write audit log: {}", e);
+    }
+}
+
+/// Counts a WebSocket client as connected for as long as it is alive
+struct ClientGuard<'a>(&'a ServerContext);
+
+impl<'a> ClientGuard<'a> {
+    fn register(ctx: &'a ServerContext) -> Self {
+        ctx.connected_clients.fetch_add(1, Ordering::Relaxed);
+        Self(ctx)
+    }
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn handle_connection(stream: TcpStream, peer: SocketAddr, ctx: Arc<ServerContext>) -> Result<()> {
     use tokio::io::AsyncWriteExt;
     
     // Peek at the first bytes to check if it's an HTTP request
@@ -269,6 +1323,13 @@ async fn handle_connection(
     stream.peek(&mut peek_buf).await?;
     let peek_str = String::from_utf8_lossy(&peek_buf);
     
+    if peek_str.starts_with(&format!("GET {} ", health::PATH)) || peek_str.starts_with(&format!("GET {}?", health::PATH)) {
+        let mut stream = stream;
+        let _ = tokio::time::timeout(Duration::from_secs(5), http::read_request(&mut stream, 0)).await;
+        let (status, report) = health::check(&ctx).await;
+        return http::respond(&mut stream, status, "application/json", &serde_json::to_vec(&report)?).await;
+    }
+
     // Check if this is a plain HTTP health check request
     if peek_str.contains("GET /health") || peek_str.contains("HEAD /health") {
         // Read and discard the HTTP request
@@ -283,6 +1344,10 @@ async fn handle_connection(
         info!("Handled HTTP health check request");
         return Ok(());
     }
+
+    if peek_str.starts_with(&format!("POST {} ", webhooks::PATH)) {
+        return webhooks::handle(stream, peer, &ctx).await;
+    }
     
     // Try WebSocket handshake
     let ws_stream = match accept_async(stream).await {
@@ -292,56 +1357,357 @@ async fn handle_connection(
             return Err(anyhow::anyhow!("WebSocket handshake failed: {}", e));
         }
     };
+    
+    let connection_id = ctx.next_connection_id.fetch_add(1, Ordering::Relaxed);
+    let result = serve_client(ws_stream, peer, &ctx, connection_id).await;
+    builds::connection_closed(&ctx, connection_id).await;
+    result
+}
+
+async fn serve_client(
+    ws_stream: WebSocketStream<TcpStream>,
+    peer: SocketAddr,
+    ctx: &Arc<ServerContext>,
+    connection_id: u64,
+) -> Result<()> {
     let (mut write, mut read) = ws_stream.split();
+    let mut events = ctx.events.subscribe();
+    let _client = ClientGuard::register(ctx);
     
     info!("WebSocket connection established");
     
-    while let Some(msg) = read.next().await {
-        let msg = msg?;
+    let capabilities = ServerMessage::Capabilities(CapabilitiesPayload {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        read_only: ctx.read_only,
+        shells: shell::available(),
+        interpreters: actions::available_interpreters(),
+        execution_policy: Some(ctx.settings().execution_policy).filter(|p| p.is_active()),
+    });
+    write.send(Message::Text(serde_json::to_string(&capabilities)?)).await?;
+    
+    // Identity the client announced with ClientInfo, used for auditing
+    let mut client_info: Option<ClientInfoPayload> = None;
+    
+    // Any frame from the client, pongs included, counts as a sign of life
+    let mut last_seen = Instant::now();
+    let mut heartbeat = tokio::time::interval(ctx.settings().heartbeat_interval());
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    
+    // Sent a frame at a time between everything else, so events and
+    // heartbeats keep flowing during a long download
+    let mut download: Option<artifacts::Download> = None;
+    
+    loop {
+        let msg = tokio::select! {
+            msg = read.next() => match msg {
+                Some(msg) => {
+                    last_seen = Instant::now();
+                    msg?
+                }
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > ctx.settings().heartbeat_timeout() {
+                    warn!("No traffic for {}s, dropping connection", last_seen.elapsed().as_secs());
+                    break;
+                }
+                write.send(Message::Ping(Vec::new())).await?;
+                continue;
+            }
+            event = events.recv() => {
+                match event {
+                    Ok(event) => {
+                        let text = serde_json::to_string(&event)?;
+                        write.send(Message::Text(text)).await?;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Client fell behind, skipped {} events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+                continue;
+            }
+            frame = async { download.as_mut()?.next_frame().await.transpose() }, if download.is_some() => {
+                match frame {
+                    Some(Ok(frame)) => write.send(Message::Binary(frame)).await?,
+                    Some(Err(e)) => {
+                        download = None;
+                        let response = ServerMessage::Error(format!("Failed to send artifact: {:#}", e));
+                        write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                    }
+                    None => download = None,
+                }
+                continue;
+            }
+        };
         
         if let Message::Text(text) = msg {
             let server_msg: ServerMessage = serde_json::from_str(&text)?;
             
+            if let Some(action) = server_msg.mutation().filter(|_| ctx.read_only) {
+                warn!("Refusing {}: server is read-only", action);
+                let response = ServerMessage::ReadOnly(ReadOnlyPayload { action: action.to_string() });
+                write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                continue;
+            }
+            
             match server_msg {
                 ServerMessage::Ping => {
                     let pong = serde_json::to_string(&ServerMessage::Pong)?;
                     write.send(Message::Text(pong)).await?;
                 }
+                ServerMessage::BuildStart(payload) if ctx.shutting_down.load(Ordering::Relaxed) => {
+                    warn!("Refusing build {}: server is shutting down", payload.build_id);
+                    let response = serde_json::to_string(&ServerMessage::Error(
+                        "Server is shutting down".to_string()
+                    ))?;
+                    write.send(Message::Text(response)).await?;
+                }
                 ServerMessage::BuildStart(payload) => {
                     info!("Starting build: {} v{}", payload.project_name, payload.version);
+                    audit(ctx, "BuildStart", &payload.build_id, client_info.as_ref(), peer).await;
                     
-                    let token = payload.github_token.clone().or(github_token.clone());
+                    let token = payload.github_token.clone().or(ctx.github_token.clone());
+                    let build_id = payload.build_id.clone();
+                    let workflow_id = payload.workflow_id.clone();
                     
-                    // Execute build in background
-                    let workdir = workdir.clone();
-                    let data_clone = shared_data.clone();
-                    let data_dir_clone = data_dir.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = execute_build(payload.clone(), token, workdir).await {
-                            error!("Build failed: {}", e);
+                    if let builds::Submission::Rejected { running_build_id } =
+                        builds::submit(ctx, payload, token, Some(connection_id)).await
+                    {
+                        let response = serde_json::to_string(&ServerMessage::BuildAlreadyRunning(
+                            BuildAlreadyRunningPayload { build_id, workflow_id, running_build_id },
+                        ))?;
+                        write.send(Message::Text(response)).await?;
+                    }
+                }
+                ServerMessage::ClientInfo(info) => {
+                    info!("Client identified as {} on {} (v{})", info.name, info.hostname, info.app_version);
+                    client_info = Some(info);
+                }
+                ServerMessage::GetBuildHistory(query) => {
+                    let response = match ctx.history.query(query).await {
+                        Ok((mut records, total)) => {
+                            for record in &mut records {
+                                record.logs.clear();
+                            }
+                            builds::fill_live(ctx, &mut records).await;
+                            ServerMessage::BuildHistory(BuildHistoryPage { records, total })
                         }
-                        // Record build in history
-                        let mut data = data_clone.write().await;
-                        data.build_history.push(BuildRecord {
-                            id: payload.build_id.clone(),
-                            workflow_id: String::new(),
-                            status: "completed".to_string(),
-                            started_at: chrono::Utc::now().to_rfc3339(),
-                            finished_at: Some(chrono::Utc::now().to_rfc3339()),
-                            duration_ms: None,
-                            logs: vec![],
-                        });
-                        let _ = data.save(&data_dir_clone);
-                    });
+                        Err(e) => ServerMessage::Error(format!("Failed to query build history: {}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::GetActionHistory(query) => {
+                    let response = match ctx.history.query_action_runs(query).await {
+                        Ok((records, total)) => ServerMessage::ActionHistory(actions::ActionHistoryPage { records, total }),
+                        Err(e) => ServerMessage::Error(format!("Failed to query action history: {}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::GetWebhookDeliveries(query) => {
+                    let response = match ctx.history.query_webhook_deliveries(query).await {
+                        Ok((deliveries, total)) => ServerMessage::WebhookDeliveries(webhooks::WebhookDeliveriesPage { deliveries, total }),
+                        Err(e) => ServerMessage::Error(format!("Failed to query webhook deliveries: {}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::GetBuildStats(query) => {
+                    let response = match ctx.history.recent(&query.workflow_id, query.window).await {
+                        Ok(records) => ServerMessage::BuildStats(stats::compute(&query.workflow_id, &records)),
+                        Err(e) => ServerMessage::Error(format!("Failed to compute build stats: {}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::DeleteBuildRecord(build_id) => {
+                    info!("Deleting build record: {}", build_id);
+                    audit(ctx, "DeleteBuildRecord", &build_id, client_info.as_ref(), peer).await;
+                    let response = if builds::is_running(ctx, &build_id).await {
+                        ServerMessage::BuildStillRunning(build_id)
+                    } else {
+                        match ctx.history.delete(&build_id).await {
+                            Ok(Some(record)) => {
+                                retention::remove_build_files(ctx, &record).await;
+                                ServerMessage::BuildRecordsDeleted(BuildRecordsDeletedPayload {
+                                    count: 1,
+                                    dry_run: false,
+                                })
+                            }
+                            Ok(None) => ServerMessage::Error(format!("Build not found: {}", build_id)),
+                            Err(e) => ServerMessage::Error(format!("Failed to delete build record: {}", e)),
+                        }
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::RunCleanup => {
+                    info!("Cleaning up on request");
+                    audit(ctx, "RunCleanup", "*", client_info.as_ref(), peer).await;
+                    let mut report = cleanup::remove_stale_scripts(ctx).await;
+                    let old = cleanup::run(ctx).await;
+                    report.removed.extend(old.removed);
+                    report.reclaimed_bytes += old.reclaimed_bytes;
+                    let response = ServerMessage::CleanupReport(report);
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::ClearBuildHistory(request) => {
+                    if !request.dry_run {
+                        let scope = request.workflow_id.as_deref().unwrap_or("*");
+                        info!("Clearing build history of {}", scope);
+                        audit(ctx, "ClearBuildHistory", scope, client_info.as_ref(), peer).await;
+                    }
+                    let dry_run = request.dry_run;
+                    let response = match ctx.history.clear(request.workflow_id, request.before, dry_run).await {
+                        Ok(records) => {
+                            if !dry_run {
+                                for record in &records {
+                                    retention::remove_build_files(ctx, record).await;
+                                }
+                            }
+                            ServerMessage::BuildRecordsDeleted(BuildRecordsDeletedPayload {
+                                count: records.len(),
+                                dry_run,
+                            })
+                        }
+                        Err(e) => ServerMessage::Error(format!("Failed to clear build history: {}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::GetBuildLogs(query) => {
+                    let response = match get_build_logs(ctx, query).await {
+                        Ok(page) => ServerMessage::BuildLogs(page),
+                        Err(e) => ServerMessage::Error(format!("Failed to read build logs: {}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::GetArtifacts(query) => {
+                    let response = match artifacts::list(ctx, &query.build_id).await {
+                        Ok(payload) => ServerMessage::Artifacts(payload),
+                        Err(e) => ServerMessage::Error(format!("Failed to list artifacts: {:#}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::DownloadArtifact(_) if download.is_some() => {
+                    let response = ServerMessage::Error("Another download is still in progress on this connection".to_string());
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::DownloadArtifact(request) => {
+                    info!("Sending artifact {} of build {} from byte {}", request.path, request.build_id, request.offset);
+                    let response = match artifacts::Download::open(ctx, request).await {
+                        Ok((started, payload)) => {
+                            download = Some(started);
+                            ServerMessage::ArtifactDownload(payload)
+                        }
+                        Err(e) => ServerMessage::Error(format!("Failed to send artifact: {:#}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::GetAuditLog(query) => {
+                    let response = match ctx.audit.read(query.offset, query.limit).await {
+                        Ok((entries, total)) => ServerMessage::AuditLog(AuditLogPage { entries, total }),
+                        Err(e) => ServerMessage::Error(format!("Failed to read audit log: {}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::GetServerStatus => {
+                    let (running_builds, queued_builds) = builds::snapshot(ctx).await;
+                    let status = ServerStatusPayload {
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        uptime_secs: ctx.started.elapsed().as_secs(),
+                        connected_clients: ctx.connected_clients.load(Ordering::Relaxed),
+                        running_builds,
+                        queued_builds,
+                        retention: ctx.settings().retention,
+                        read_only: ctx.read_only,
+                    };
+                    let response = serde_json::to_string(&ServerMessage::ServerStatus(status))?;
+                    write.send(Message::Text(response)).await?;
                 }
                 ServerMessage::BuildCancel(build_id) => {
                     warn!("Build cancel requested: {}", build_id);
-                    // TODO: Implement build cancellation
+                    audit(ctx, "BuildCancel", &build_id, client_info.as_ref(), peer).await;
+                    if !builds::cancel(ctx, &build_id).await {
+                        let reason = match ctx.history.get(&build_id).await {
+                            Ok(Some(_)) => actions::NotCancelledReason::AlreadyFinished,
+                            _ => actions::NotCancelledReason::NotFound,
+                        };
+                        let response = serde_json::to_string(&ServerMessage::BuildNotCancelled(
+                            BuildNotCancelledPayload { build_id, reason }
+                        ))?;
+                        write.send(Message::Text(response)).await?;
+                    }
                 }
                 // Data sync handlers
+                ServerMessage::GetSettings => {
+                    let settings = ctx.settings();
+                    let response = ServerMessage::Settings(SettingsPayload {
+                        restart_required: settings.restart_required(ctx.bound_port),
+                        settings,
+                    });
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::SetSettings(new_settings) => {
+                    if let Err(e) = new_settings.validate() {
+                        let response = ServerMessage::Error(format!("Invalid settings: {}", e));
+                        write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                        continue;
+                    }
+                    info!("Updating server settings");
+                    audit(ctx, "SetSettings", "settings", client_info.as_ref(), peer).await;
+                    apply_settings(ctx, new_settings).await;
+                }
+                ServerMessage::ExportData(request) => {
+                    info!("Exporting server data");
+                    let response = match bundle::export(ctx, request.include_logs).await {
+                        Ok(bundle) => ServerMessage::DataExport(bundle),
+                        Err(e) => ServerMessage::Error(format!("Failed to export data: {:#}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::ExportWorkflow(workflow_id) => {
+                    let response = match workflow_file::export(&*ctx.data.read().await, &workflow_id) {
+                        Ok(export) => ServerMessage::WorkflowExport(export),
+                        Err(e) => ServerMessage::Error(format!("Failed to export workflow: {:#}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::ImportWorkflow(request) => {
+                    let response = match workflow_file::import(ctx, &request).await {
+                        Ok(result) => {
+                            info!("Imported workflow {} ({})", result.name, result.workflow_id);
+                            // The id it was stored under, which a copy gets new
+                            audit(ctx, "ImportWorkflow", &result.workflow_id, client_info.as_ref(), peer).await;
+                            ServerMessage::WorkflowImported(result)
+                        }
+                        Err(e) => ServerMessage::Error(format!("Failed to import workflow: {:#}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::ExportWorkflowAsGitHubActions(workflow_id) => {
+                    let response = match github_actions::export(&*ctx.data.read().await, &workflow_id) {
+                        Ok(export) => ServerMessage::GitHubActionsWorkflow(export),
+                        Err(e) => ServerMessage::Error(format!("Failed to export workflow to GitHub Actions: {:#}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::ImportGitHubActionsWorkflow(request) => {
+                    let response = match github_actions::import(&request) {
+                        Ok(import) => ServerMessage::GitHubActionsImport(import),
+                        Err(e) => ServerMessage::Error(format!("Failed to import GitHub Actions workflow: {:#}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::ImportData(request) => {
+                    info!("Importing server data (merge: {})", request.merge);
+                    let scope = if request.merge { "merge" } else { "replace" };
+                    audit(ctx, "ImportData", scope, client_info.as_ref(), peer).await;
+                    let response = match bundle::import(ctx, request.bundle, request.merge).await {
+                        Ok(result) => ServerMessage::DataImported(result),
+                        Err(e) => ServerMessage::Error(format!("Failed to import data: {:#}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
                 ServerMessage::SyncRequest => {
                     info!("Sync request received");
-                    let data = shared_data.read().await;
+                    let data = ctx.data.read().await;
                     let sync_data = SyncData {
                         workflows: data.workflows.clone(),
                         actions: data.actions.clone(),
@@ -350,247 +1716,2783 @@ async fn handle_connection(
                     let response = serde_json::to_string(&ServerMessage::SyncResponse(sync_data))?;
                     write.send(Message::Text(response)).await?;
                 }
-                ServerMessage::SaveWorkflow(workflow) => {
-                    info!("Saving workflow: {}", workflow.name);
-                    let mut data = shared_data.write().await;
-                    if let Some(existing) = data.workflows.iter_mut().find(|w| w.id == workflow.id) {
-                        *existing = workflow;
-                    } else {
-                        data.workflows.push(workflow);
+                ServerMessage::SaveWorkflow(workflow) => {
+                    info!("Saving workflow: {}", workflow.name);
+                    let valid = workflow.triggers.iter().try_for_each(webhooks::Trigger::validate).and_then(|()| {
+                        workflow.nodes.iter().try_for_each(|node| {
+                            let text = |key: &str| node.get(key).and_then(|v| v.as_str()).unwrap_or_default();
+                            let config = node.get("config").unwrap_or(&serde_json::Value::Null);
+                            check_node_config(text("type"), config).with_context(|| format!("Node {}", text("id")))
+                        })
+                    });
+                    if let Err(e) = valid {
+                        let response = ServerMessage::Error(format!("{:#}", e));
+                        write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                        continue;
+                    }
+                    audit(ctx, "SaveWorkflow", &workflow.id, client_info.as_ref(), peer).await;
+                    let mut data = ctx.data.write().await;
+                    if let Some(existing) = data.workflows.iter_mut().find(|w| w.id == workflow.id) {
+                        *existing = workflow;
+                    } else {
+                        data.workflows.push(workflow);
+                    }
+                    ctx.persistence.mark_dirty();
+                }
+                ServerMessage::DeleteWorkflow(id) => {
+                    info!("Deleting workflow: {}", id);
+                    audit(ctx, "DeleteWorkflow", &id, client_info.as_ref(), peer).await;
+                    let mut data = ctx.data.write().await;
+                    data.workflows.retain(|w| w.id != id);
+                    ctx.persistence.mark_dirty();
+                }
+                ServerMessage::SaveAction(mut action) => {
+                    if let Err(e) = actions::validate(&action) {
+                        let response = ServerMessage::Error(format!("Invalid action {}: {:#}", action.name, e));
+                        write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                        continue;
+                    }
+                    info!("Saving action: {}", action.name);
+                    audit(ctx, "SaveAction", &action.id, client_info.as_ref(), peer).await;
+                    let mut data = ctx.data.write().await;
+                    // The server numbers versions, whatever the client sent
+                    match data.actions.iter_mut().find(|a| a.id == action.id) {
+                        Some(existing) => {
+                            action.version = existing.version + 1;
+                            *existing = action.clone();
+                        }
+                        None => {
+                            action.version = 1;
+                            data.actions.push(action.clone());
+                        }
+                    }
+                    ctx.persistence.mark_dirty();
+                    drop(data);
+                    if let Err(e) = ctx.history.save_action_version(action, actions::KEPT_VERSIONS).await {
+                        error!("Failed to keep the saved action version: {:#}", e);
+                    }
+                }
+                ServerMessage::ListTemplates => {
+                    let response = ServerMessage::Templates(templates::list());
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::InstantiateTemplate(request) => {
+                    let response = match templates::instantiate(&request) {
+                        Ok(instance) => ServerMessage::TemplateInstance(instance),
+                        Err(e) => ServerMessage::Error(format!("{:#}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::ListActionVersions(action_id) => {
+                    let response = match ctx.history.action_versions(&action_id).await {
+                        Ok(versions) => ServerMessage::ActionVersions(actions::ActionVersionsPayload { action_id, versions }),
+                        Err(e) => ServerMessage::Error(format!("Failed to list action versions: {}", e)),
+                    };
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::DeleteAction(id) => {
+                    info!("Deleting action: {}", id);
+                    audit(ctx, "DeleteAction", &id, client_info.as_ref(), peer).await;
+                    let mut data = ctx.data.write().await;
+                    data.actions.retain(|a| a.id != id);
+                    let affected_workflows: Vec<String> = data.workflows
+                        .iter()
+                        .filter(|w| w.nodes.iter().any(|node| {
+                            node.get("type").and_then(|v| v.as_str()) == Some("action")
+                                && node.get("config").and_then(|c| c.get("action_id")).and_then(|v| v.as_str()) == Some(id.as_str())
+                        }))
+                        .map(|w| w.id.clone())
+                        .collect();
+                    if !affected_workflows.is_empty() {
+                        warn!("Workflows still using deleted action {}: {}", id, affected_workflows.join(", "));
+                    }
+                    ctx.persistence.mark_dirty();
+                    drop(data);
+                    if let Err(e) = ctx.history.delete_action_versions(&id).await {
+                        error!("Failed to delete the versions of action {}: {:#}", id, e);
+                    }
+                    ctx.broadcast(ServerMessage::ActionDeleted(ActionDeletedPayload { id, affected_workflows }));
+                }
+                ServerMessage::SaveRepo(repo) if repo.id.is_empty() || repo.path.is_empty() => {
+                    let response = ServerMessage::Error("A repo needs an id and a path".to_string());
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::SaveRepo(repo) => {
+                    info!("Saving repo: {} at {}", repo.id, repo.path);
+                    audit(ctx, "SaveRepo", &repo.id, client_info.as_ref(), peer).await;
+                    let mut data = ctx.data.write().await;
+                    if let Some(existing) = data.repos.iter_mut().find(|r| r.id == repo.id) {
+                        *existing = repo.clone();
+                    } else {
+                        data.repos.push(repo.clone());
+                    }
+                    ctx.persistence.mark_dirty();
+                    ctx.broadcast(ServerMessage::RepoSaved(repo));
+                }
+                ServerMessage::DeleteRepo(id) => {
+                    info!("Deleting repo: {}", id);
+                    audit(ctx, "DeleteRepo", &id, client_info.as_ref(), peer).await;
+                    let (_, affected_workflows) = repos::delete(ctx, &id).await;
+                    ctx.broadcast(ServerMessage::RepoDeleted(RepoDeletedPayload { id, affected_workflows }));
+                }
+                ServerMessage::DeleteRepoFiles(id) if repos::in_use(ctx, &id).await => {
+                    let response = ServerMessage::Error(format!("A build is using repo {}", id));
+                    write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                }
+                ServerMessage::DeleteRepoFiles(id) => {
+                    info!("Deleting repo and its files: {}", id);
+                    audit(ctx, "DeleteRepoFiles", &id, client_info.as_ref(), peer).await;
+                    let (removed, affected_workflows) = repos::delete(ctx, &id).await;
+                    if let Some(repo) = removed {
+                        if let Err(e) = repos::remove_working_copy(ctx, &repo).await {
+                            let response = ServerMessage::Error(format!("Repo deleted, but not its files: {:#}", e));
+                            write.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                        }
+                    }
+                    ctx.broadcast(ServerMessage::RepoDeleted(RepoDeletedPayload { id, affected_workflows }));
+                }
+                ServerMessage::CloneRepo(request) => {
+                    info!("Clone requested: {}", repos::redact(&request.url, ctx.github_token.as_deref()));
+                    audit(ctx, "CloneRepo", &request.clone_id, client_info.as_ref(), peer).await;
+                    repos::start_clone(ctx, request);
+                }
+                ServerMessage::RunAction(mut payload) => {
+                    info!("Running action: {}", payload.action_id);
+                    let action = ctx.data.read().await
+                        .actions
+                        .iter()
+                        .find(|a| a.id == payload.action_id)
+                        .cloned();
+                    if let Some(action) = action {
+                        // The audit entry and the run's history record share the run id
+                        let run_id = payload.run_id.get_or_insert_with(|| uuid::Uuid::new_v4().to_string()).clone();
+                        let triggered_by = client_info
+                            .as_ref()
+                            .map(|client| format!("{}@{}", client.name, client.hostname))
+                            .unwrap_or_else(|| peer.to_string());
+                        match actions::start(ctx, action, payload, Some(triggered_by)).await {
+                            Ok(_) => {
+                                info!("Action run {} started", run_id);
+                                audit(ctx, "RunAction", &run_id, client_info.as_ref(), peer).await;
+                            }
+                            Err(e) => {
+                                let response = serde_json::to_string(&ServerMessage::Error(format!("{:#}", e)))?;
+                                write.send(Message::Text(response)).await?;
+                            }
+                        }
+                    } else {
+                        let response = serde_json::to_string(&ServerMessage::Error(
+                            format!("Action not found: {}", payload.action_id)
+                        ))?;
+                        write.send(Message::Text(response)).await?;
+                    }
+                }
+                ServerMessage::TestAction(payload) => {
+                    info!("Testing action: {}", payload.action.id);
+                    match actions::start_test(ctx, payload).await {
+                        Ok(run_id) => {
+                            info!("Action test run {} started", run_id);
+                            audit(ctx, "TestAction", &run_id, client_info.as_ref(), peer).await;
+                        }
+                        Err(e) => {
+                            let response = serde_json::to_string(&ServerMessage::Error(format!("{:#}", e)))?;
+                            write.send(Message::Text(response)).await?;
+                        }
+                    }
+                }
+                ServerMessage::CancelAction(payload) => {
+                    warn!("Action cancel requested: {}", payload.run_id);
+                    audit(ctx, "CancelAction", &payload.run_id, client_info.as_ref(), peer).await;
+                    if let Err(refused) = actions::cancel(ctx, &payload.run_id).await {
+                        let response = serde_json::to_string(&ServerMessage::ActionNotCancelled(refused))?;
+                        write.send(Message::Text(response)).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    
+    info!("WebSocket connection closed");
+    Ok(())
+}
+
+/// State of a build while its nodes execute
+struct BuildRun<'a> {
+    ctx: &'a ServerContext,
+    payload: &'a BuildStartPayload,
+    github_token: Option<String>,
+    workdir: PathBuf,
+    /// `workdir` is a per-build workspace that is removed after the build
+    isolated: bool,
+    /// Repo of the workflow, if it has one
+    repo: Option<StoredRepo>,
+    /// Set when the workflow's repo was synced before the build
+    commit_sha: Option<String>,
+    /// `$VERSION`; starts as the requested version, a version_bump node changes it
+    version: String,
+    /// Container command and script nodes run in unless they set their own
+    container: Option<container::ContainerConfig>,
+    /// Values of the matrix instance being run, see `matrix`
+    matrix: matrix::Combination,
+    cancel: CancelToken,
+    log: &'a BuildLog,
+    outputs: BuildOutputs,
+    /// Values nodes produced for later nodes, by `<node id or name>.<output>`
+    node_outputs: HashMap<String, String>,
+    started: Instant,
+    /// Directories of fetched tools, put first on the PATH of later nodes
+    tool_dirs: Vec<PathBuf>,
+    /// Name of the node the build failed in, for nodes that run on failure
+    failed_node: Option<String>,
+    /// Set by a condition node that evaluated to false
+    condition_unmet: bool,
+    /// Nodes skipped because a condition before them was false
+    bypassed: HashSet<String>,
+    /// Set by a test node, recorded on its node run
+    test_summary: Option<junit::TestSummary>,
+    /// Set by an action node, recorded on its node run
+    used_action: Option<actions::UsedAction>,
+    /// Variables set by env nodes for the processes of later nodes
+    vars: BTreeMap<String, String>,
+}
+
+impl BuildRun<'_> {
+    /// Replaces the build variables `$VERSION`, `$PROJECT_ROOT`,
+    /// `$COMMIT_SHA` and `$BRANCH`, hashes of files written as
+    /// `$HASH(<glob>)`, and node outputs written as `${node.output}`, in a
+    /// node setting
+    fn substitute(&self, text: &str) -> String {
+        // Checked to be UTF-8 by preflight
+        self.substitute_with_root(text, &self.workdir.to_string_lossy())
+    }
+
+    /// Like `substitute`, for text used inside a container, where
+    /// `$PROJECT_ROOT` is the mounted build directory
+    fn substitute_in_container(&self, text: &str) -> String {
+        self.substitute_with_root(text, container::MOUNT)
+    }
+
+    fn substitute_with_root(&self, text: &str, project_root: &str) -> String {
+        let mut variables = vec![
+            ("$VERSION".to_string(), self.version.clone()),
+            ("$PROJECT_ROOT".to_string(), project_root.to_string()),
+        ];
+        if let Some(sha) = &self.commit_sha {
+            variables.push(("$COMMIT_SHA".to_string(), sha.clone()));
+        }
+        if let Some(branch) = self.branch() {
+            variables.push(("$BRANCH".to_string(), branch.to_string()));
+        }
+        if let Some(tag) = &self.payload.tag {
+            variables.push(("$TAG".to_string(), tag.clone()));
+        }
+        for (name, value) in &self.matrix {
+            variables.push((format!("$MATRIX_{}", name), value.clone()));
+        }
+        let mut text = replace_variables(text, &variables);
+        if text.contains("$HASH(") {
+            text = cache::expand_hashes(&text, &self.workdir);
+        }
+        // Last, so variables inside an output are left as they are
+        let outputs: Vec<(String, String)> = self
+            .node_outputs
+            .iter()
+            .map(|(key, value)| (format!("${{{}}}", key), value.clone()))
+            .collect();
+        replace_variables(&text, &outputs)
+    }
+
+    /// The requested ref, or the default branch of the workflow's repo
+    fn branch(&self) -> Option<&str> {
+        self.payload.git_ref.as_deref().or(self.repo.as_ref().map(|r| r.default_branch.as_str()))
+    }
+
+    /// Makes `value` available to later nodes as `${<node id>.<name>}` and
+    /// `${<node name>.<name>}`
+    fn set_output(&mut self, node: &BuildNode, name: &str, value: String) {
+        self.node_outputs.insert(format!("{}.{}", node.name, name), value.clone());
+        self.node_outputs.insert(format!("{}.{}", node.id, name), value);
+    }
+
+    /// Build variables, the build request's `env` and variables of env nodes,
+    /// passed on top of the server's own environment to every process a node
+    /// starts
+    fn env(&self) -> Vec<(String, String)> {
+        let mut env = vec![
+            ("VERSION".to_string(), self.version.clone()),
+            ("PROJECT_ROOT".to_string(), self.workdir.to_string_lossy().to_string()),
+        ];
+        if let Some(sha) = &self.commit_sha {
+            env.push(("COMMIT_SHA".to_string(), sha.clone()));
+        }
+        if let Some(tag) = &self.payload.tag {
+            env.push(("TAG".to_string(), tag.clone()));
+        }
+        for (name, value) in &self.matrix {
+            env.push((format!("MATRIX_{}", name), value.clone()));
+        }
+        // Later entries win, so env nodes override the build request
+        env.extend(self.payload.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        env.extend(self.vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if !self.tool_dirs.is_empty() {
+            let inherited = self.vars.get("PATH")
+                .or(self.payload.env.get("PATH"))
+                .map(std::ffi::OsString::from)
+                .or_else(|| std::env::var_os("PATH"))
+                .unwrap_or_default();
+            let dirs = self.tool_dirs.iter().cloned().chain(std::env::split_paths(&inherited));
+            if let Ok(path) = std::env::join_paths(dirs) {
+                env.push(("PATH".to_string(), path.to_string_lossy().to_string()));
+            }
+        }
+        env
+    }
+
+    /// `env()` as seen from inside a container, where the working directory
+    /// is mounted at `container::MOUNT`
+    fn container_env(&self) -> Vec<(String, String)> {
+        self.env()
+            .into_iter()
+            // The host's PATH means nothing inside the image
+            .filter(|(key, _)| key != "PATH")
+            .map(|(key, value)| match key.as_str() {
+                "PROJECT_ROOT" => (key, container::MOUNT.to_string()),
+                _ => (key, value),
+            })
+            .collect()
+    }
+}
+
+/// What a successful build produced
+#[derive(Default)]
+struct BuildOutputs {
+    artifacts: Vec<ArtifactInfo>,
+    release_url: Option<String>,
+}
+
+/// Default container of the workflow's command and script nodes
+async fn workflow_container(ctx: &ServerContext, workflow_id: &str) -> Option<container::ContainerConfig> {
+    let data = ctx.data.read().await;
+    data.workflows
+        .iter()
+        .find(|w| w.id == workflow_id)
+        .and_then(|w| w.container.clone())
+}
+
+/// Tool requirements of a workflow, checked before any of its nodes run
+async fn workflow_requires(ctx: &ServerContext, workflow_id: &str) -> Vec<requirements::Spec> {
+    let data = ctx.data.read().await;
+    data.workflows
+        .iter()
+        .find(|w| w.id == workflow_id)
+        .map(|w| w.requires.clone())
+        .unwrap_or_default()
+}
+
+/// The `requires` list of a require node
+fn node_requires(node: &BuildNode) -> Result<Vec<requirements::Requirement>> {
+    let specs: Vec<requirements::Spec> = node.config.get("requires")
+        .cloned()
+        .map(serde_json::from_value)
+        .transpose()
+        .context("requires must be a list of requirements such as \"node >= 18.17\"")?
+        .unwrap_or_default();
+    if specs.is_empty() {
+        anyhow::bail!("The require node needs requires, a list such as [\"node >= 18.17\", \"cargo\"]");
+    }
+    specs.iter().map(requirements::Requirement::parse).collect()
+}
+
+/// Every requirement of a build, the workflow's and its require nodes',
+/// leaving out any that do not parse, which preflight reports
+async fn build_requirements(ctx: &ServerContext, payload: &BuildStartPayload) -> Vec<requirements::Requirement> {
+    let mut requirements: Vec<_> = workflow_requires(ctx, &payload.workflow_id)
+        .await
+        .iter()
+        .filter_map(|spec| requirements::Requirement::parse(spec).ok())
+        .collect();
+    for node in payload.nodes.iter().filter(|n| n.node_type == "require") {
+        requirements.extend(node_requires(node).unwrap_or_default());
+    }
+    requirements
+}
+
+/// The repo a workflow builds, if it has one
+async fn resolve_repo(ctx: &ServerContext, workflow_id: &str) -> Result<Option<StoredRepo>> {
+    let data = ctx.data.read().await;
+    let Some(repo_id) = data
+        .workflows
+        .iter()
+        .find(|w| w.id == workflow_id)
+        .and_then(|w| w.repo_id.as_ref())
+    else {
+        return Ok(None);
+    };
+    let repo = data
+        .repos
+        .iter()
+        .find(|r| &r.id == repo_id)
+        .with_context(|| format!("Repo {} of workflow {} not found", repo_id, workflow_id))?;
+    Ok(Some(repo.clone()))
+}
+
+async fn execute_build(
+    ctx: &ServerContext,
+    payload: BuildStartPayload,
+    github_token: Option<String>,
+    cancel: CancelToken,
+    log: &BuildLog,
+) -> Result<BuildOutputs> {
+    let start_time = std::time::Instant::now();
+    
+    // Workflows with a repo build in it at the requested ref, others in --workdir
+    let repo = resolve_repo(ctx, &payload.workflow_id).await?;
+    let (workdir, commit_sha) = match &repo {
+        Some(repo) => {
+            let sha = repos::sync(
+                repo,
+                payload.commit.as_deref().or(payload.git_ref.as_deref()),
+                payload.force_clean,
+                github_token.as_deref(),
+                &payload.build_id,
+                &cancel,
+                log,
+            )
+            .await?;
+            builds::commit_checked_out(ctx, &payload.build_id, &sha).await;
+            (PathBuf::from(&repo.path), Some(sha))
+        }
+        None => (ctx.workdir.clone(), None),
+    };
+    
+    let workspace = if ctx.settings().isolated_workspaces {
+        let workspace = workspace::Workspace::create(&ctx.data_dir, &payload.build_id, &workdir, log).await?;
+        Some(workspace)
+    } else {
+        None
+    };
+    let workdir = workspace.as_ref().map(|w| w.path().to_path_buf()).unwrap_or(workdir);
+    info!("Building in {}", workdir.display());
+    
+    // Sort nodes by dependencies (topological sort)
+    let sorted_nodes = topological_sort(&payload.nodes, &payload.edges)?;
+    let total_nodes = sorted_nodes.len();
+    
+    let mut run = BuildRun {
+        ctx,
+        payload: &payload,
+        github_token,
+        workdir,
+        isolated: workspace.is_some(),
+        repo,
+        commit_sha,
+        version: payload.version.clone(),
+        container: workflow_container(ctx, &payload.workflow_id).await,
+        matrix: Vec::new(),
+        cancel,
+        log,
+        outputs: BuildOutputs::default(),
+        node_outputs: HashMap::new(),
+        started: start_time,
+        tool_dirs: Vec::new(),
+        failed_node: None,
+        condition_unmet: false,
+        bypassed: HashSet::new(),
+        test_summary: None,
+        used_action: None,
+        vars: BTreeMap::new(),
+    };
+    
+    // A failed check skips every node, as a failing first node would
+    let mut failure: Option<anyhow::Error> = preflight(&run, &sorted_nodes).await.err();
+    if let Some(node_id) = failure.as_ref().and_then(|e| e.downcast_ref::<NodeFailed>()).map(|f| &f.node_id) {
+        run.failed_node = Some(sorted_nodes.iter().find(|n| &n.id == node_id).map_or(node_id.clone(), |n| n.name.clone()));
+    }
+    for (index, node) in sorted_nodes.iter().enumerate() {
+        let progress = ((index as f32 / total_nodes as f32) * 100.0) as u8;
+        
+        if failure.is_none() && run.cancel.is_cancelled() {
+            failure = Some(BuildCancelled.into());
+        }
+        if run.bypassed.contains(&node.id) {
+            builds::node_finished(ctx, &payload.build_id, NodeRun::skipped(node)).await;
+            continue;
+        }
+        let cancelled = failure.as_ref().is_some_and(|e| e.is::<BuildCancelled>());
+        // Checked by preflight
+        let run_on = builds::RunOn::of(node).unwrap_or_default();
+        if !run_on.runs(failure.is_some(), cancelled) {
+            // Record what did not run so the history shows the whole workflow
+            builds::node_finished(ctx, &payload.build_id, NodeRun::skipped(node)).await;
+            continue;
+        }
+        
+        info!("Executing node: {} ({})", node.name, node.node_type);
+        builds::report_progress(ctx, &payload.build_id, &node.id, progress).await;
+        
+        let result = run_expanded(&mut run, node).await;
+        if std::mem::take(&mut run.condition_unmet) {
+            bypass_after(&mut run, node).await;
+        }
+        if let Err(e) = result {
+            if failure.is_none() {
+                if !e.is::<BuildCancelled>() {
+                    run.failed_node = Some(node.name.clone());
+                }
+                failure = Some(e);
+            } else {
+                log.line(&format!("{} failed after the build had already failed: {:#}", node.name, e)).await;
+            }
+        }
+    }
+    tools::remove_build_dir(&ctx.data_dir, &payload.build_id).await;
+    if let Some(workspace) = workspace {
+        let failed = failure.as_ref().is_some_and(|e| !e.is::<BuildCancelled>());
+        if failed && ctx.settings().keep_workspace_on_failure {
+            log.line(&format!("[workspace] Kept {} for debugging", workspace.path().display())).await;
+            workspace.keep();
+        } else {
+            workspace.remove().await;
+        }
+    }
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    
+    let duration = start_time.elapsed().as_secs();
+    info!("Build completed in {}s", duration);
+    
+    Ok(run.outputs)
+}
+
+/// Skips every node that only runs after `node` succeeded, following edges
+/// until a node with a `run_on` of its own
+async fn bypass_after(run: &mut BuildRun<'_>, node: &BuildNode) {
+    let mut pending = vec![node.id.clone()];
+    let mut names = Vec::new();
+    while let Some(id) = pending.pop() {
+        for edge in run.payload.edges.iter().filter(|e| e.source == id) {
+            let Some(target) = run.payload.nodes.iter().find(|n| n.id == edge.target) else {
+                continue;
+            };
+            if builds::RunOn::of(target).unwrap_or_default() == builds::RunOn::Success
+                && run.bypassed.insert(target.id.clone())
+            {
+                names.push(target.name.clone());
+                pending.push(target.id.clone());
+            }
+        }
+    }
+    if !names.is_empty() {
+        run.log.line(&format!("[condition] Skipping {}", names.join(", "))).await;
+    }
+}
+
+/// Adds a file to the build's artifacts. Files in a per-build workspace are
+/// copied out first, as the workspace is removed after the build.
+async fn collect_artifact(run: &mut BuildRun<'_>, path: PathBuf) -> Result<()> {
+    // Before the copy, which is as new as the build
+    let modified_at = artifacts::modified_at(&path).await;
+    let path = if run.isolated && path.starts_with(&run.workdir) {
+        artifacts::retain(&run.ctx.data_dir, &run.payload.build_id, &run.workdir, &path).await?
+    } else {
+        path
+    };
+    let mut artifact = ArtifactInfo::describe(&path).await?;
+    artifact.modified_at = modified_at;
+    info!("Collected artifact: {:?} ({} bytes, sha256 {})", path, artifact.size, artifact.sha256);
+    run.outputs.artifacts.push(artifact);
+    Ok(())
+}
+
+/// Runs a node, once per combination if it has a matrix
+async fn run_expanded(run: &mut BuildRun<'_>, node: &BuildNode) -> Result<()> {
+    let combinations = matrix::combinations(node).context(NodeFailed { node_id: node.id.clone() })?;
+    let Some(combinations) = combinations else {
+        return run_node(run, node).await.context(NodeFailed { node_id: node.id.clone() });
+    };
+    
+    // Instances run one after another, each recorded as a node of its own
+    let mut succeeded = 0;
+    let mut first_error = None;
+    for combination in &combinations {
+        let instance = matrix::instance(node, combination);
+        run.log.line(&format!("[matrix] {}", instance.name)).await;
+        run.matrix = combination.clone();
+        let result = run_node(run, &instance).await;
+        run.matrix.clear();
+        match result {
+            Ok(()) => succeeded += 1,
+            Err(e) if e.is::<BuildCancelled>() => return Err(e),
+            Err(e) => {
+                first_error.get_or_insert(e.context(NodeFailed { node_id: instance.id.clone() }));
+            }
+        }
+    }
+    let needs_all = run.payload.edges.iter()
+        .filter(|e| e.source == node.id)
+        .all(|e| e.wait == matrix::EdgeWait::All);
+    let enough = if needs_all { succeeded == combinations.len() } else { succeeded > 0 };
+    match first_error {
+        Some(e) if !enough => Err(e),
+        Some(e) => {
+            run.log.line(&format!("[matrix] {} of {} instances of {} succeeded, enough to go on: {:#}",
+                succeeded, combinations.len(), node.name, e)).await;
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// The node's `max_output_mb` and `max_line_bytes`, or the server's limits
+fn output_limits(run: &BuildRun<'_>, node: &BuildNode) -> build_log::OutputLimits {
+    let settings = run.ctx.settings();
+    build_log::OutputLimits {
+        max_bytes: node.config.get("max_output_mb")
+            .and_then(|v| v.as_u64())
+            .or(settings.max_node_output_mb)
+            .map(|mb| mb * 1024 * 1024),
+        max_line_bytes: node.config.get("max_line_bytes")
+            .and_then(|v| v.as_u64())
+            .map(|bytes| bytes as usize)
+            .or(settings.max_log_line_bytes),
+    }
+}
+
+/// Runs a node, or one instance of a matrixed node, recording it as a node
+/// run of the build
+async fn run_node(run: &mut BuildRun<'_>, node: &BuildNode) -> Result<()> {
+    let ctx = run.ctx;
+    let build_id = run.payload.build_id.clone();
+    let mut node_run = NodeRun::start(node, 1);
+    builds::node_started(ctx, &build_id, &node_run).await;
+    run.log.start_node(output_limits(run, node));
+    let timeout = node.config.get("timeout_secs")
+        .and_then(|v| v.as_u64())
+        .or(ctx.settings().default_node_timeout_secs);
+    let result = match timeout {
+        // Dropping the node stops the processes it started, see `process::run`
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), execute_node(run, node))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Node timed out after {}s", secs))),
+        None => execute_node(run, node).await,
+    };
+    node_run.finish(&result);
+    node_run.tests = run.test_summary.take();
+    node_run.action = run.used_action.take();
+    node_run.output_truncated = run.log.output_truncated();
+    builds::node_finished(ctx, &build_id, node_run).await;
+    result.map(|_| ())
+}
+
+/// Replaces every variable of `variables`, named with its `$`, in a single
+/// pass over `text`, so a value is never searched for variables in turn.
+/// Where names overlap, the longest one wins.
+fn replace_variables(text: &str, variables: &[(String, String)]) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('$') {
+        replaced.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let variable = variables
+            .iter()
+            .filter(|(name, _)| rest.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len());
+        match variable {
+            Some((name, value)) => {
+                replaced.push_str(value);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                replaced.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+/// Fails if `$PROJECT_ROOT` could not be filled in with `workdir`
+fn check_workdir(workdir: &Path) -> Result<()> {
+    if workdir.to_str().is_none() {
+        anyhow::bail!("The build directory {} is not valid UTF-8, so $PROJECT_ROOT cannot be filled in; move it to a plain path",
+            workdir.display());
+    }
+    Ok(())
+}
+
+/// Checks run before the first node, so a build does not fail halfway
+/// through on something that could have been known up front
+async fn preflight(run: &BuildRun<'_>, nodes: &[BuildNode]) -> Result<()> {
+    check_workdir(&run.workdir)?;
+    let requires = workflow_requires(run.ctx, &run.payload.workflow_id).await;
+    if !requires.is_empty() {
+        let requirements = requires.iter()
+            .map(requirements::Requirement::parse)
+            .collect::<Result<Vec<_>>>()
+            .context("Invalid requires in the workflow")?;
+        requirements::check(&requirements).await.check()?;
+    }
+    for node in nodes {
+        matrix::combinations(node).context(NodeFailed { node_id: node.id.clone() })?;
+        builds::RunOn::of(node).context(NodeFailed { node_id: node.id.clone() })?;
+        if node.node_type == "release" {
+            if run.github_token.is_none() {
+                return Err(anyhow::anyhow!("The release node needs a GitHub token, none was configured")
+                    .context(NodeFailed { node_id: node.id.clone() }));
+            }
+            release_target(run, node)
+                .await
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if matches!(node.node_type.as_str(), "command" | "script" | "test") {
+            let containerized = container::for_node(node, run.container.as_ref())
+                .context(NodeFailed { node_id: node.id.clone() })?;
+            if containerized.is_some() {
+                docker::check_available(false)
+                    .await
+                    .context("The node is set to run in a container; remove its container setting, or set it to false, to run it on the server instead")
+                    .context(NodeFailed { node_id: node.id.clone() })?;
+            }
+        }
+        if matches!(node.node_type.as_str(), "npm_publish" | "cargo_publish") {
+            node_secret(node, "token_env")
+                .and_then(|_| skip_if_published(node))
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "codesign" {
+            signing::check_codesign_available()
+                .and_then(|_| codesign_identity(node).map(|_| ()))
+                .and_then(|_| node_secret(node, "keychain_password_env").map(|_| ()))
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "signtool" {
+            signtool_tool(node)
+                .and_then(|tool| signing::check_authenticode_available(tool, &authenticode_certificate(run, node)?))
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "checksums" {
+            checksums::Algorithm::parse(node.config.get("algorithm").and_then(|v| v.as_str()).unwrap_or("sha256"))
+                .and_then(|_| node_secret(node, "minisign_password_env"))
+                .and_then(|_| match node_secret(node, "minisign_key_env")? {
+                    Some(_) => which::which("minisign")
+                        .map(|_| ())
+                        .map_err(|_| anyhow::anyhow!("Signing the sums file needs minisign, which is not installed on the server")),
+                    None => Ok(()),
+                })
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "dmg" {
+            dmg::check_available()
+                .and_then(|_| match node.config.get("sign_identity").and_then(|v| v.as_str()) {
+                    Some(_) => signing::check_codesign_available(),
+                    None => Ok(()),
+                })
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "windows_installer" {
+            installer_toolchain(node)
+                .and_then(installer::check_available)
+                .and_then(|_| upgrade_code(node).map(|_| ()))
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "notify" {
+            notify_target(run, node)
+                .map(|_| ())
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if matches!(node.node_type.as_str(), "command" | "test") {
+            command_argv(run, node)
+                .and_then(|argv| match argv {
+                    Some(argv) => check_command_policy(run, node, &argv),
+                    None => Ok(()),
+                })
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "action" {
+            let action = node_action(run, node).await.context(NodeFailed { node_id: node.id.clone() })?;
+            let inputs = action_node_inputs(run, node).context(NodeFailed { node_id: node.id.clone() })?;
+            actions::check_input_names(&action, inputs.keys().map(String::as_str))
+                .context(NodeFailed { node_id: node.id.clone() })?;
+            if !action.requires.is_empty() {
+                let requirements = action.requires.iter()
+                    .map(requirements::Requirement::parse)
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("Invalid requires in action {}", action.name))
+                    .context(NodeFailed { node_id: node.id.clone() })?;
+                requirements::check(&requirements).await.check()
+                    .context(NodeFailed { node_id: node.id.clone() })?;
+            }
+        }
+        if node.node_type == "script" {
+            container::for_node(node, run.container.as_ref())
+                .and_then(|containerized| {
+                    let (shell, script) = node_script(run, node, containerized.is_some());
+                    run.ctx.settings().execution_policy.check_script(shell.program(), &script)
+                })
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if matches!(node.node_type.as_str(), "command" | "script" | "test") {
+            node_env(run, node, false)
+                .and_then(|_| limits::ResourceLimits::for_node(&node.config, &run.ctx.settings().resource_limits))
+                .map(|_| ())
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "env" {
+            env_vars(node)
+                .and_then(|vars| {
+                    for var in vars {
+                        match var.source {
+                            EnvSource::Server(name) => {
+                                std::env::var(name)
+                                    .with_context(|| format!("The environment variable {} is not set on the server", name))?;
+                            }
+                            EnvSource::Command(command) => {
+                                run.ctx.settings().execution_policy.check_command(&run.substitute(command))?;
+                            }
+                            EnvSource::Value(_) => {}
+                        }
+                    }
+                    Ok(())
+                })
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "artifact" {
+            artifact_excludes(run, node)
+                .map(|_| ())
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if matches!(node.node_type.as_str(), "cache_restore" | "cache_save") {
+            node.config.get("key")
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.trim().is_empty())
+                .context("The cache node needs a key")
+                .and_then(|_| cache_paths(run, node).map(|_| ()))
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "require" {
+            node_requires(node)
+                .map(|_| ())
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "condition" {
+            condition_of(node)
+                .map(|_| ())
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+        if node.node_type == "wait" {
+            if let Some(condition) = node.config.get("until_http").filter(|c| !c.is_null()) {
+                wait_condition(run, condition)
+                    .map(|_| ())
+                    .context(NodeFailed { node_id: node.id.clone() })?;
+            }
+        }
+        if node.node_type == "docker_build" {
+            docker::check_available(docker_image(run, node).uses_buildx())
+                .await
+                .context(NodeFailed { node_id: node.id.clone() })?;
+            docker_login(node)
+                .map(|_| ())
+                .context(NodeFailed { node_id: node.id.clone() })?;
+        }
+    }
+    Ok(())
+}
+
+/// Value of the server environment variable named by the node setting
+/// `key`, if the node sets one. Secrets are referenced this way so they are
+/// never stored in a workflow.
+fn node_secret(node: &BuildNode, key: &str) -> Result<Option<String>> {
+    let Some(name) = node.config.get(key).and_then(|v| v.as_str()).filter(|s| !s.is_empty()) else {
+        return Ok(None);
+    };
+    std::env::var(name)
+        .map(Some)
+        .with_context(|| format!("The environment variable {} is not set on the server", name))
+}
+
+/// Whether a publish node fails (the default) or skips when the version is
+/// already published
+fn skip_if_published(node: &BuildNode) -> Result<bool> {
+    match node.config.get("if_exists").and_then(|v| v.as_str()).unwrap_or("fail") {
+        "fail" => Ok(false),
+        "skip" => Ok(true),
+        other => anyhow::bail!("Unknown if_exists {}, expected fail or skip", other),
+    }
+}
+
+/// Identity a codesign node signs with, `-` for ad-hoc signing
+fn codesign_identity(node: &BuildNode) -> Result<String> {
+    node.config.get("identity")
+        .and_then(|v| v.as_str())
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .context("The codesign node needs an identity, or - to sign ad-hoc")
+}
+
+/// Tool a signtool node signs with
+fn signtool_tool(node: &BuildNode) -> Result<signing::AuthenticodeTool> {
+    signing::AuthenticodeTool::parse(node.config.get("tool").and_then(|v| v.as_str()).filter(|s| !s.is_empty()))
+}
+
+/// Certificate of a signtool node: a `pfx` file, with its password read from
+/// the server environment variable in `pfx_password_env`, or the `thumbprint`
+/// of one in the Windows certificate store
+fn authenticode_certificate(run: &BuildRun<'_>, node: &BuildNode) -> Result<signing::Certificate> {
+    let config_str = |key: &str| node.config.get(key)
+        .and_then(|v| v.as_str())
+        .filter(|s| !s.is_empty())
+        .map(|s| run.substitute(s));
+    match (config_str("pfx"), config_str("thumbprint")) {
+        (Some(pfx), None) => Ok(signing::Certificate::Pfx {
+            path: run.workdir.join(pfx),
+            password: node_secret(node, "pfx_password_env")?,
+        }),
+        (None, Some(thumbprint)) => Ok(signing::Certificate::Store { thumbprint }),
+        (Some(_), Some(_)) => anyhow::bail!("The signtool node takes either a pfx or a thumbprint, not both"),
+        (None, None) => anyhow::bail!("The signtool node needs a pfx or a certificate thumbprint"),
+    }
+}
+
+/// Provider and webhook URL of a notify node. The URL is `webhook_url`, or
+/// read from the server environment variable in `webhook_url_env`.
+fn notify_target(run: &BuildRun<'_>, node: &BuildNode) -> Result<(notify::Provider, String)> {
+    let provider = notify::Provider::parse(node.config.get("provider").and_then(|v| v.as_str()).unwrap_or("generic_webhook"))?;
+    let url = match node_secret(node, "webhook_url_env")? {
+        Some(url) => url,
+        None => node.config.get("webhook_url")
+            .and_then(|v| v.as_str())
+            .filter(|s| !s.is_empty())
+            .map(|s| run.substitute(s))
+            .context("The notify node needs a webhook_url or webhook_url_env")?,
+    };
+    if !(url.starts_with("https://") || url.starts_with("http://")) {
+        anyhow::bail!("The webhook URL must start with http:// or https://");
+    }
+    Ok((provider, url))
+}
+
+/// The `exclude` globs of an artifact node, matched against paths relative to
+/// the build directory
+fn artifact_excludes(run: &BuildRun<'_>, node: &BuildNode) -> Result<Vec<glob::Pattern>> {
+    config_list(run, node, "exclude")
+        .iter()
+        .map(|p| glob::Pattern::new(p).with_context(|| format!("Invalid exclude pattern {}", p)))
+        .collect()
+}
+
+/// Whether a relative path or pattern stays inside the directory it is
+/// relative to
+fn stays_inside(pattern: &str) -> bool {
+    Path::new(pattern).components().all(|c| matches!(c, std::path::Component::Normal(_) | std::path::Component::CurDir))
+}
+
+/// Refuses the `inputs` of archive job `index` that reach outside the build
+/// directory, unless `allow_outside` is set, and an `output` that does at all
+fn check_archive_paths(index: usize, inputs: &[String], output: &str, allow_outside: bool) -> Result<()> {
+    if let Some(pattern) = inputs.iter().find(|pattern| !allow_outside && !stays_inside(pattern)) {
+        anyhow::bail!(
+            "The input {} of archive job {} reaches outside the build directory; set allow_outside_workspace to allow it",
+            pattern,
+            index + 1
+        );
+    }
+    if !stays_inside(output) {
+        anyhow::bail!("The output {} of archive job {} must be relative to the build directory, without ..", output, index + 1);
+    }
+    Ok(())
+}
+
+/// The `key` of a cache node, with its variables and `$HASH(...)` filled in
+fn cache_key(run: &BuildRun<'_>, node: &BuildNode) -> Result<String> {
+    let key = node.config.get("key")
+        .and_then(|v| v.as_str())
+        .context("The cache node needs a key")?;
+    cache::sanitize_key(&run.substitute(key))
+}
+
+/// The `paths` of a cache node, which must stay inside the build directory
+fn cache_paths(run: &BuildRun<'_>, node: &BuildNode) -> Result<Vec<String>> {
+    let paths = config_list(run, node, "paths");
+    for path in &paths {
+        if !stays_inside(path) {
+            anyhow::bail!("Cache path {} must be relative to the build directory, without ..", path);
+        }
+    }
+    Ok(paths)
+}
+
+/// The parsed `expression` of a condition node, with its source
+fn condition_of(node: &BuildNode) -> Result<(&str, condition::Condition)> {
+    let expression = node.config.get("expression")
+        .and_then(|v| v.as_str())
+        .filter(|s| !s.trim().is_empty())
+        .context("The condition node needs an expression")?;
+    let parsed = condition::Condition::parse(expression)
+        .with_context(|| format!("Invalid condition {}", expression))?;
+    Ok((expression, parsed))
+}
+
+/// Where the value of an env node variable comes from
+enum EnvSource<'a> {
+    /// Text, with build variables filled in
+    Value(&'a str),
+    /// The output of a command run in the build directory
+    Command(&'a str),
+    /// A variable of the server's environment
+    Server(&'a str),
+}
+
+struct EnvVar<'a> {
+    name: &'a str,
+    source: EnvSource<'a>,
+    /// Kept out of the build log
+    secret: bool,
+}
+
+/// The `vars` of an env node. Each is text, or an object with one of
+/// `value`, `command` or `value_env` and an optional `secret`.
+fn env_vars(node: &BuildNode) -> Result<Vec<EnvVar<'_>>> {
+    let vars = node.config.get("vars")
+        .and_then(|v| v.as_object())
+        .filter(|vars| !vars.is_empty())
+        .context("The env node needs vars, an object of names and values")?;
+    let mut parsed = Vec::new();
+    for (name, spec) in vars {
+        check_env_name(name)?;
+        let (source, secret) = match spec {
+            serde_json::Value::String(value) => (EnvSource::Value(value), false),
+            serde_json::Value::Object(spec) => {
+                let text = |key: &str| spec.get(key).and_then(|v| v.as_str());
+                let source = match (text("value"), text("command"), text("value_env")) {
+                    (Some(value), None, None) => EnvSource::Value(value),
+                    (None, Some(command), None) => EnvSource::Command(command),
+                    (None, None, Some(server)) => EnvSource::Server(server),
+                    _ => anyhow::bail!("{} needs exactly one of value, command or value_env", name),
+                };
+                (source, spec.get("secret").and_then(|v| v.as_bool()).unwrap_or(false))
+            }
+            _ => anyhow::bail!("{} must be text or an object with value, command or value_env", name),
+        };
+        parsed.push(EnvVar { name, source, secret });
+    }
+    Ok(parsed)
+}
+
+fn check_env_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['=', '\0']) {
+        anyhow::bail!("{:?} is not a valid environment variable name", name);
+    }
+    Ok(())
+}
+
+/// The `until_http` setting of a wait node: `url`, `expected_status` (200
+/// unless given), `interval_secs` and `timeout_secs`
+fn wait_condition(run: &BuildRun<'_>, condition: &serde_json::Value) -> Result<wait::HttpCondition> {
+    let url = condition.get("url")
+        .and_then(|v| v.as_str())
+        .filter(|s| !s.is_empty())
+        .map(|s| run.substitute(s))
+        .context("until_http needs a url")?;
+    if !(url.starts_with("https://") || url.starts_with("http://")) {
+        anyhow::bail!("The until_http URL must start with http:// or https://");
+    }
+    let expected = match condition.get("expected_status") {
+        None => vec![200],
+        Some(serde_json::Value::Array(codes)) => codes.iter().filter_map(|c| c.as_u64()).map(|c| c as u16).collect(),
+        Some(code) => vec![code.as_u64().context("expected_status must be a status code or a list of them")? as u16],
+    };
+    let secs = |key: &str, default: u64| condition.get(key).and_then(|v| v.as_u64()).unwrap_or(default);
+    Ok(wait::HttpCondition {
+        url,
+        expected,
+        interval: Duration::from_secs(secs("interval_secs", 10).max(1)),
+        timeout: Duration::from_secs(secs("timeout_secs", 300)),
+    })
+}
+
+/// Whether a windows_installer node builds an MSI (the default) or with NSIS
+fn installer_toolchain(node: &BuildNode) -> Result<installer::Toolchain> {
+    installer::Toolchain::parse(node.config.get("kind").and_then(|v| v.as_str()).unwrap_or("msi"))
+}
+
+/// The `upgrade_code` GUID of a windows_installer node
+fn upgrade_code(node: &BuildNode) -> Result<Option<uuid::Uuid>> {
+    node.config.get("upgrade_code")
+        .and_then(|v| v.as_str())
+        .filter(|s| !s.is_empty())
+        .map(|code| {
+            uuid::Uuid::parse_str(code.trim_matches(|c| c == '{' || c == '}'))
+                .with_context(|| format!("upgrade_code {} is not a GUID", code))
+        })
+        .transpose()
+}
+
+/// A list setting of `node` with variables substituted. A single string is
+/// accepted where a list is expected.
+fn config_list(run: &BuildRun<'_>, node: &BuildNode, key: &str) -> Vec<String> {
+    match node.config.get(key) {
+        Some(serde_json::Value::String(s)) => vec![run.substitute(s)],
+        Some(serde_json::Value::Array(items)) => {
+            items.iter().filter_map(|v| v.as_str()).map(|s| run.substitute(s)).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// What a docker_build node builds
+fn docker_image(run: &BuildRun<'_>, node: &BuildNode) -> docker::ImageBuild {
+    let config_str = |key: &str, default: &str| {
+        run.substitute(node.config.get(key).and_then(|v| v.as_str()).unwrap_or(default))
+    };
+    let build_args = node.config.get("build_args")
+        .and_then(|v| v.as_object())
+        .map(|args| {
+            args.iter()
+                .map(|(key, value)| {
+                    let value = value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string());
+                    (key.clone(), run.substitute(&value))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    docker::ImageBuild {
+        dockerfile: config_str("dockerfile", "Dockerfile"),
+        context: config_str("context", "."),
+        tags: config_list(run, node, "tags"),
+        build_args,
+        platforms: config_list(run, node, "platforms"),
+        push: node.config.get("push").and_then(|v| v.as_bool()).unwrap_or(false),
+    }
+}
+
+/// Registry credentials of a docker_build node, if it names a user. The
+/// password is read from the server environment variable in `password_env`
+/// so it is never stored in the workflow.
+fn docker_login(node: &BuildNode) -> Result<Option<docker::Login>> {
+    let config_str = |key: &str| node.config.get(key).and_then(|v| v.as_str()).filter(|s| !s.is_empty());
+    let Some(username) = config_str("username") else {
+        return Ok(None);
+    };
+    let password = node_secret(node, "password_env")?
+        .context("A docker_build node with a username needs password_env, the variable holding the password")?;
+    Ok(Some(docker::Login {
+        registry: config_str("registry").map(str::to_string),
+        username: username.to_string(),
+        password,
+    }))
+}
+
+/// Repository a release node publishes to, and where it came from: the
+/// node's `owner` and `repo`, the workflow's repo, or the origin remote of
+/// the working directory
+async fn release_target(run: &BuildRun<'_>, node: &BuildNode) -> Result<(String, String, &'static str)> {
+    let config_str = |key: &str| node.config.get(key).and_then(|v| v.as_str()).filter(|s| !s.is_empty());
+    if let (Some(owner), Some(repo)) = (config_str("owner"), config_str("repo")) {
+        return Ok((owner.to_string(), repo.to_string(), "node config"));
+    }
+    if let Some(StoredRepo { owner: Some(owner), repo: Some(repo), .. }) = &run.repo {
+        return Ok((owner.clone(), repo.clone(), "workflow's repo"));
+    }
+    if let Some((owner, repo)) = repos::origin_url(&run.workdir)
+        .await
+        .and_then(|url| repos::parse_remote(&url))
+    {
+        return Ok((owner, repo, "origin remote"));
+    }
+    anyhow::bail!(
+        "Release node {} has no repository to release to: set owner and repo on the node, \
+         give the workflow a repo, or build in a clone with an origin remote",
+        node.name
+    )
+}
+
+/// GitHub instance a release node talks to: the node's `api_base_url`, else
+/// the workflow's repo, else the server settings
+fn github_endpoints(run: &BuildRun<'_>, node: &BuildNode) -> github::GitHubEndpoints {
+    let config_str = |key: &str| node.config.get(key).and_then(|v| v.as_str());
+    let settings = run.ctx.settings();
+    let repo = run.repo.as_ref();
+    github::GitHubEndpoints::resolve([
+        (config_str("api_base_url"), config_str("upload_base_url")),
+        (
+            repo.and_then(|r| r.api_base_url.as_deref()),
+            repo.and_then(|r| r.upload_base_url.as_deref()),
+        ),
+        (settings.github_api_url.as_deref(), settings.github_upload_url.as_deref()),
+    ])
+}
+
+/// Runs one node, returning the exit code of the process it ran, if any
+async fn execute_node(run: &mut BuildRun<'_>, node: &BuildNode) -> Result<Option<i32>> {
+    let workdir = run.workdir.clone();
+    let build_id = &run.payload.build_id;
+    let mut exit_code = None;
+    
+    match node.node_type.as_str() {
+        "command" => {
+            let argv = match command_argv(run, node)? {
+                Some(argv) => argv,
+                None => command_shell(run, node)?.command_argv("echo No command specified"),
+            };
+            let code = run_node_command(run, node, &argv).await?;
+            if !is_dry_run(node) {
+                exit_code = Some(code);
+            }
+        }
+        "test" => {
+            let argv = command_argv(run, node)?.context("The test node needs a command")?;
+            // With some slack for filesystems that keep coarse modification times
+            let started = std::time::SystemTime::now() - Duration::from_secs(2);
+            let outcome = run_node_command(run, node, &argv).await;
+            if is_dry_run(node) {
+                return outcome.map(|_| None);
+            }
+            if outcome.as_ref().is_err_and(|e| !e.is::<process::ProcessFailed>()) {
+                return outcome.map(Some);
+            }
+            
+            let summary = read_test_reports(run, node, started).await?;
+            let allow_failures = node.config.get("allow_failures")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            match summary {
+                // The reports decide, unless the runner failed without a failing test
+                Some(summary) => {
+                    run.log.line(&format!("[test] {}", summary.describe())).await;
+                    for failure in &summary.failures {
+                        run.log.line(&format!("[test] FAILED {}: {}", failure.name, failure.message)).await;
+                    }
+                    let failed = summary.failed;
+                    run.test_summary = Some(summary);
+                    if failed > 0 && !allow_failures {
+                        anyhow::bail!("{} tests failed", failed);
+                    }
+                    if failed > 0 {
+                        run.log.line("[test] Going on despite the failures, allow_failures is set").await;
+                        exit_code = outcome.ok();
+                    } else {
+                        exit_code = Some(outcome?);
+                    }
+                }
+                None => exit_code = Some(outcome?),
+            }
+        }
+        "script" => {
+            let containerized = container::for_node(node, run.container.as_ref())?;
+            let (shell, script) = node_script(run, node, containerized.is_some());
+            run.ctx.settings().execution_policy.check_script(shell.program(), &script)?;
+            let env = node_env(run, node, containerized.is_some())?;
+            let resource_limits = node_limits(run, node, containerized.is_some()).await?;
+            if is_dry_run(node) {
+                log_dry_run(run, &format!("{} script:\n{}", shell.program(), script), &env).await;
+                return Ok(None);
+            }
+            exit_code = Some(match containerized {
+                Some(container) => run_script_in_container(&script, &shell, &container, &env, run).await?,
+                None => {
+                    let limits = limits::prepare(&resource_limits, run.log).await;
+                    let result = run_script_with_shell(&script, &shell, &env, &limits, run).await;
+                    limits.check(result)?
+                }
+            });
+        }
+        "action" => {
+            let action = node_action(run, node).await?;
+            let inputs = action_node_inputs(run, node)?;
+            let env = node_env(run, node, false)?;
+            if is_dry_run(node) {
+                log_dry_run(run, &format!("action {}", action.name), &env).await;
+                return Ok(None);
+            }
+            run.log.line(&format!("[action] Running {} version {}", action.name, action.version)).await;
+            run.used_action = Some(actions::UsedAction {
+                id: action.id.clone(),
+                version: action.version,
+            });
+            let (code, outputs) = actions::run_in_build(&action, &inputs, &workdir, &env, &run.cancel, run.log).await?;
+            for (name, value) in outputs {
+                run.set_output(node, &name, value);
+            }
+            exit_code = Some(code);
+        }
+        "artifact" => {
+            let mut patterns = config_list(run, node, "path");
+            if patterns.is_empty() {
+                patterns.push("dist/*".to_string());
+            }
+            let exclude = artifact_excludes(run, node)?;
+            let allow_outside = node.config.get("allow_outside_workspace")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            let fail_if_empty = node.config.get("fail_if_empty")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true);
+            
+            let mut full_patterns = Vec::new();
+            let mut seen = HashSet::new();
+            let mut collected = 0;
+            for pattern in &patterns {
+                if !allow_outside && !stays_inside(pattern) {
+                    anyhow::bail!("The pattern {} reaches outside the build directory; set allow_outside_workspace to allow it", pattern);
+                }
+                let full_pattern = workdir.join(pattern);
+                let full_pattern = full_pattern.to_str()
+                    .with_context(|| format!("The pattern {} is not valid UTF-8", full_pattern.display()))?
+                    .to_string();
+                for entry in glob::glob(&full_pattern).with_context(|| format!("Invalid pattern {}", pattern))? {
+                    let path = match entry {
+                        Ok(path) => path,
+                        Err(e) => {
+                            run.log.line(&format!("[artifact] Skipping {}: {}", e.path().display(), e.error())).await;
+                            continue;
+                        }
+                    };
+                    if !path.is_file() || !seen.insert(path.clone()) {
+                        continue;
+                    }
+                    let relative = path.strip_prefix(&workdir).unwrap_or(&path);
+                    let relative = relative.components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    if exclude.iter().any(|p| p.matches(&relative)) {
+                        continue;
+                    }
+                    collect_artifact(run, path).await?;
+                    collected += 1;
+                }
+                full_patterns.push(full_pattern);
+            }
+            if collected == 0 && fail_if_empty {
+                anyhow::bail!("No files match {}", full_patterns.join(", "));
+            }
+        }
+        "fetch_tool" => {
+            // $OS and $ARCH as Rust names them, e.g. linux and x86_64
+            let platform = |text: &str| {
+                run.substitute(text)
+                    .replace("$TOOL_VERSION", node.config.get("version").and_then(|v| v.as_str()).unwrap_or(""))
+                    .replace("$OS", std::env::consts::OS)
+                    .replace("$ARCH", std::env::consts::ARCH)
+            };
+            let url = platform(node.config.get("url")
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .context("The fetch_tool node needs a url")?);
+            // One checksum, or one per `<os>-<arch>`
+            let sha256 = match node.config.get("sha256") {
+                Some(serde_json::Value::String(sha)) => sha.clone(),
+                Some(serde_json::Value::Object(by_platform)) => {
+                    let key = format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH);
+                    by_platform.get(&key)
+                        .and_then(|v| v.as_str())
+                        .map(str::to_string)
+                        .with_context(|| format!("sha256 has no checksum for {}", key))?
+                }
+                _ => anyhow::bail!("The fetch_tool node needs the sha256 of the download"),
+            };
+            let format = match node.config.get("extract") {
+                None | Some(serde_json::Value::Bool(false)) => None,
+                Some(serde_json::Value::Bool(true)) => Some(archive::Format::from_file_name(&tools::file_name(&url))
+                    .context("Cannot tell the archive format from the URL; set extract to zip, tar.gz, tar.xz or tar.zst")?),
+                Some(value) => Some(archive::Format::parse(value.as_str().unwrap_or_default())?),
+            };
+            let dir = match node.config.get("destination").and_then(|v| v.as_str()).filter(|s| !s.is_empty()) {
+                Some(dir) => workdir.join(platform(dir)),
+                None => tools::build_dir(&run.ctx.data_dir, build_id),
+            };
+            let name = node.config.get("name")
+                .and_then(|v| v.as_str())
+                .map(&platform)
+                .unwrap_or_else(|| tools::file_name(&url));
+            let binary_path = node.config.get("binary_path").and_then(|v| v.as_str()).map(&platform);
+            
+            let download = tools::fetch(&run.ctx.data_dir, &url, &sha256, &run.cancel, run.log).await?;
+            let binary = tools::install(&download, &dir, format, &name, binary_path.as_deref()).await?;
+            let path_dir = binary.as_ref().and_then(|b| b.parent()).map(Path::to_path_buf).unwrap_or(dir);
+            run.log.line(&format!("[tool] Installed into {}", path_dir.display())).await;
+            if let Some(binary) = binary {
+                run.set_output(node, "path", binary.to_string_lossy().to_string());
+            }
+            if !run.tool_dirs.contains(&path_dir) {
+                run.tool_dirs.insert(0, path_dir);
+            }
+        }
+        "http_request" => {
+            let mut secrets = http_request::Secrets::default();
+            let method = node.config.get("method")
+                .and_then(|v| v.as_str())
+                .unwrap_or("POST")
+                .to_uppercase();
+            let method = reqwest::Method::from_bytes(method.as_bytes())
+                .with_context(|| format!("Unknown method {}", method))?;
+            let url = node.config.get("url")
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .context("The http_request node needs a url")?;
+            let url = secrets.expand(&run.substitute(url))?;
+            let mut headers = Vec::new();
+            if let Some(fields) = node.config.get("headers").and_then(|v| v.as_object()) {
+                for (name, value) in fields {
+                    let value = value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string());
+                    headers.push((name.clone(), secrets.expand(&run.substitute(&value))?));
+                }
+            }
+            let body = match node.config.get("body") {
+                None | Some(serde_json::Value::Null) => None,
+                Some(serde_json::Value::String(text)) => Some(http_request::Body::Text(secrets.expand(&run.substitute(text))?)),
+                Some(value) => {
+                    // Substituted as text so variables work anywhere in the object
+                    let mut value: serde_json::Value = serde_json::from_str(&run.substitute(&value.to_string()))
+                        .context("The body is no longer valid JSON once its variables are filled in")?;
+                    secrets.expand_json(&mut value)?;
+                    Some(http_request::Body::Json(value))
+                }
+            };
+            let config_u64 = |key: &str, default: u64| node.config.get(key)
+                .and_then(|v| v.as_u64())
+                .unwrap_or(default);
+            let config_bool = |key: &str, default: bool| node.config.get(key)
+                .and_then(|v| v.as_bool())
+                .unwrap_or(default);
+            let request = http_request::Request {
+                method,
+                url,
+                headers,
+                body,
+                timeout: Duration::from_secs(config_u64("request_timeout_secs", 30)),
+                expected: node.config.get("expected_status")
+                    .and_then(|v| v.as_array())
+                    .map(|codes| codes.iter().filter_map(|c| c.as_u64()).map(|c| c as u16).collect())
+                    .unwrap_or_default(),
+                retries: config_u64("retries", 0) as u32,
+                retry_delay: Duration::from_secs(config_u64("retry_delay_secs", 2)),
+                follow_redirects: config_bool("follow_redirects", true),
+                verify_tls: config_bool("verify_tls", true),
+            };
+            
+            if config_bool("dry_run", false) {
+                request.log_dry_run(&secrets, run.log).await;
+                return Ok(None);
+            }
+            let response = http_request::send(&request, &secrets, &run.cancel, run.log).await?;
+            run.set_output(node, "status", response.status.as_u16().to_string());
+            if let Some(outputs) = node.config.get("outputs").and_then(|v| v.as_object()) {
+                for (name, pointer) in outputs {
+                    let pointer = pointer.as_str().with_context(|| format!("Output {} must be a JSON pointer such as /id", name))?;
+                    let value = http_request::extract(&response.body, pointer)?;
+                    run.set_output(node, name, value);
+                }
+            }
+        }
+        "cache_restore" => {
+            let key = cache_key(run, node)?;
+            let restore_keys = config_list(run, node, "restore_keys")
+                .iter()
+                .map(|prefix| cache::sanitize_key(prefix))
+                .collect::<Result<Vec<_>>>()?;
+            match cache::find(&cache::dir(&run.ctx.data_dir), &key, &restore_keys).await? {
+                Some(entry) => {
+                    let started = Instant::now();
+                    cache::restore(&entry, &workdir).await?;
+                    let exact = entry.key == key;
+                    run.log.line(&format!("[cache] {} for {}: restored {} ({}) in {:.1}s",
+                        if exact { "Hit" } else { "Partial hit" },
+                        key, entry.key, cache::format_size(entry.size), started.elapsed().as_secs_f64())).await;
+                    run.set_output(node, "hit", exact.to_string());
+                    run.set_output(node, "key", entry.key);
+                }
+                None => {
+                    run.log.line(&format!("[cache] Miss for {}", key)).await;
+                    run.set_output(node, "hit", "false".to_string());
+                }
+            }
+        }
+        "cache_save" => {
+            let key = cache_key(run, node)?;
+            let cache_dir = cache::dir(&run.ctx.data_dir);
+            if cache::find(&cache_dir, &key, &[]).await?.is_some() {
+                run.log.line(&format!("[cache] {} is already cached, not saving it again", key)).await;
+                return Ok(None);
+            }
+            let mut paths = Vec::new();
+            for path in cache_paths(run, node)? {
+                if tokio::fs::symlink_metadata(workdir.join(&path)).await.is_ok() {
+                    paths.push(workdir.join(path));
+                } else {
+                    run.log.line(&format!("[cache] {} does not exist, leaving it out", path)).await;
+                }
+            }
+            if paths.is_empty() {
+                run.log.line("[cache] Nothing to save").await;
+                return Ok(None);
+            }
+            
+            let started = Instant::now();
+            let size = cache::save(&cache_dir, &key, &workdir, paths).await?;
+            run.log.line(&format!("[cache] Saved {} ({}) in {:.1}s", key, cache::format_size(size), started.elapsed().as_secs_f64())).await;
+            let settings = run.ctx.settings();
+            let max_size = settings.cache_max_size_mb.map(|mb| mb * 1024 * 1024);
+            let max_age = settings.cache_max_age_days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+            match cache::evict(&cache_dir, max_size, max_age).await {
+                Ok(removed) if !removed.is_empty() => {
+                    run.log.line(&format!("[cache] Evicted {}", removed.join(", "))).await;
+                }
+                Ok(_) => {}
+                // The entry is saved; a full cache is for the next save to deal with
+                Err(e) => run.log.line(&format!("[cache] Warning: eviction failed: {:#}", e)).await,
+            }
+        }
+        "condition" => {
+            let (expression, condition) = condition_of(node)?;
+            let met = condition.evaluate(&|name: &str| {
+                let value = run.substitute(name);
+                (value != name).then_some(value)
+            })?;
+            run.log.line(&format!("[condition] {} is {}", run.substitute(expression), met)).await;
+            if !met {
+                run.condition_unmet = true;
+            }
+        }
+        "require" => {
+            let requirements = node_requires(node)?;
+            let report = requirements::check(&requirements).await;
+            for (tool, version) in &report.versions {
+                run.log.line(&format!("[require] {}: {}", tool, version)).await;
+            }
+            report.check()?;
+        }
+        "env" => {
+            for var in env_vars(node)? {
+                let value = match var.source {
+                    EnvSource::Value(value) => run.substitute(value),
+                    EnvSource::Command(command) => {
+                        let command = run.substitute(command);
+                        run.ctx.settings().execution_policy.check_command(&command)?;
+                        let argv = shell::Shell::for_commands().command_argv(&command);
+                        let mut command = Command::new(&argv[0]);
+                        command.args(&argv[1..]).current_dir(&workdir).envs(run.env());
+                        let output = process::capture(&mut command, "The env command", &run.cancel, run.log)
+                            .await
+                            .with_context(|| format!("Failed to get the value of {}", var.name))?;
+                        output.trim_end_matches(['\r', '\n']).to_string()
+                    }
+                    EnvSource::Server(name) => std::env::var(name)
+                        .with_context(|| format!("The environment variable {} is not set on the server", name))?,
+                };
+                if var.secret {
+                    run.log.add_secret(&value);
+                    run.log.line(&format!("[env] {}=***", var.name)).await;
+                } else {
+                    run.log.line(&format!("[env] {}={}", var.name, value)).await;
+                }
+                run.vars.insert(var.name.to_string(), value);
+            }
+        }
+        "wait" => {
+            // A number, or text so it can come from a variable
+            let duration_secs = match node.config.get("duration_secs") {
+                None | Some(serde_json::Value::Null) => 0,
+                Some(serde_json::Value::String(text)) => {
+                    let text = run.substitute(text);
+                    text.trim().parse::<u64>()
+                        .with_context(|| format!("duration_secs must be a whole number of seconds, got {}", text))?
+                }
+                Some(value) => value.as_u64().context("duration_secs must be a whole number of seconds")?,
+            };
+            let until_http = match node.config.get("until_http") {
+                None | Some(serde_json::Value::Null) => None,
+                Some(condition) => Some(wait_condition(run, condition)?),
+            };
+            if duration_secs == 0 && until_http.is_none() {
+                anyhow::bail!("The wait node needs duration_secs or until_http");
+            }
+            if duration_secs > 0 {
+                wait::sleep(Duration::from_secs(duration_secs), &run.cancel, run.log).await?;
+            }
+            if let Some(condition) = until_http {
+                wait::until_http(&condition, &run.cancel, run.log).await?;
+            }
+        }
+        "notify" => {
+            let (provider, url) = notify_target(run, node)?;
+            let summary = notify::Summary {
+                project: run.payload.project_name.clone(),
+                version: run.version.clone(),
+                succeeded: run.failed_node.is_none(),
+                duration: run.started.elapsed(),
+                failed_node: run.failed_node.clone(),
+                artifacts: run.outputs.artifacts.iter()
+                    .filter_map(|a| Path::new(&a.path).file_name().map(|n| n.to_string_lossy().to_string()))
+                    .collect(),
+                release_url: run.outputs.release_url.clone(),
+            };
+            let template = node.config.get("message")
+                .and_then(|v| v.as_str())
+                .unwrap_or("$PROJECT_NAME $VERSION: build $STATUS in $DURATION");
+            let message = run.substitute(&summary.render(template));
+            let timeout = Duration::from_secs(node.config.get("request_timeout_secs")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(10));
+            let fail_build = node.config.get("fail_build_on_error")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            
+            run.log.line(&format!("[notify] Posting to {}", notify::describe(provider, &url))).await;
+            let body = notify::payload(provider, &message, &summary);
+            match notify::post(provider, &url, &body, timeout).await {
+                Ok(()) => {}
+                Err(e) if fail_build => return Err(e),
+                Err(e) => run.log.line(&format!("[notify] Warning: {:#}", e)).await,
+            }
+        }
+        "windows_installer" => {
+            let config_str = |key: &str| node.config.get(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(|s| run.substitute(s));
+            let toolchain = installer_toolchain(node)?;
+            let product_name = config_str("product_name").context("The windows_installer node needs a product_name")?;
+            let version = config_str("version").unwrap_or_else(|| run.version.clone());
+            let (installer_version, changed) = installer::msi_version(&version);
+            if changed {
+                run.log.line(&format!(
+                    "[installer] Windows installers need a version of four numbers, using {} for {}", installer_version, version
+                )).await;
+            }
+            let patterns = config_list(run, node, "files");
+            let mut files = Vec::new();
+            for pattern in &patterns {
+                files.extend(glob::glob(&workdir.join(pattern).to_string_lossy())?
+                    .filter_map(|entry| entry.ok())
+                    .filter(|path| path.is_file()));
+            }
+            let template = config_str("template").map(|t| workdir.join(t));
+            if files.is_empty() && template.is_none() {
+                anyhow::bail!("The files of the windows_installer node match nothing");
+            }
+            let extension = match toolchain {
+                installer::Toolchain::Wix => "msi",
+                installer::Toolchain::Nsis => "exe",
+            };
+            let output_name = match toolchain {
+                installer::Toolchain::Wix => format!("{}-{}.{}", product_name, version, extension),
+                installer::Toolchain::Nsis => format!("{}-{}-setup.{}", product_name, version, extension),
+            };
+            let output = workdir.join(config_str("output_dir").unwrap_or_else(|| "dist".to_string())).join(output_name);
+            let installer = installer::Installer {
+                toolchain,
+                files,
+                product_name,
+                version: installer_version,
+                manufacturer: config_str("manufacturer").unwrap_or_default(),
+                upgrade_code: upgrade_code(node)?,
+                icon: config_str("icon").map(|i| workdir.join(i)),
+                license: config_str("license").map(|l| workdir.join(l)),
+                x64: match config_str("arch").as_deref().unwrap_or("x64") {
+                    "x64" => true,
+                    "x86" => false,
+                    other => anyhow::bail!("Unknown arch {}, expected x64 or x86", other),
+                },
+                template,
+                output: output.clone(),
+            };
+            
+            installer::build(&installer, &run.cancel, run.log).await?;
+            run.set_output(node, "installer", output.to_string_lossy().to_string());
+            collect_artifact(run, output).await?;
+        }
+        "dmg" => {
+            let config_str = |key: &str| node.config.get(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(|s| run.substitute(s));
+            // Sizes and positions are written as [x, y]
+            let config_pair = |key: &str| -> Result<Option<(i64, i64)>> {
+                let Some(value) = node.config.get(key) else {
+                    return Ok(None);
+                };
+                match value.as_array().map(|v| v.as_slice()) {
+                    Some([x, y]) if x.is_i64() && y.is_i64() => Ok(Some((x.as_i64().unwrap_or(0), y.as_i64().unwrap_or(0)))),
+                    _ => anyhow::bail!("{} must be two whole numbers, as in [160, 180]", key),
+                }
+            };
+            let app = workdir.join(config_str("app").context("The dmg node needs the path of the .app in app")?);
+            let volume_name = config_str("volume_name").unwrap_or_else(|| {
+                app.file_stem().unwrap_or_default().to_string_lossy().to_string()
+            });
+            let output = workdir.join(config_str("output").unwrap_or_else(|| format!("{}.dmg", volume_name)));
+            let (width, height) = config_pair("window_size")?.unwrap_or((600, 400));
+            let dmg = dmg::Dmg {
+                app,
+                volume_name,
+                output: output.clone(),
+                background: config_str("background").map(|b| workdir.join(b)),
+                window_size: (width.max(1) as u32, height.max(1) as u32),
+                app_position: config_pair("app_position")?.map(|(x, y)| (x as i32, y as i32)),
+                applications_position: config_pair("applications_position")?.map(|(x, y)| (x as i32, y as i32)),
+            };
+            
+            dmg::create(&dmg, &run.cancel, run.log).await?;
+            if let Some(identity) = config_str("sign_identity") {
+                let sign = signing::Codesign {
+                    paths: vec![output.clone()],
+                    identity,
+                    entitlements: None,
+                    deep: false,
+                    options: None,
+                    keychain: None,
+                    // Gatekeeper only assesses a disk image once it is notarized
+                    assess: false,
+                };
+                signing::codesign(&sign, &run.cancel, run.log).await?;
+            }
+            collect_artifact(run, output).await?;
+        }
+        "archive" => {
+            let jobs = node.config.get("jobs")
+                .and_then(|v| v.as_array())
+                .filter(|jobs| !jobs.is_empty())
+                .context("The archive node needs at least one job in jobs")?;
+            let allow_outside = node.config.get("allow_outside_workspace")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            for (index, job) in jobs.iter().enumerate() {
+                // Read in a block of its own so the closures borrowing the job
+                // are gone before anything below awaits
+                let (format, mut output, if_empty, exclude, patterns, strip_leading_dir) = {
+                    let job_str = |key: &str| job.get(key)
+                        .and_then(|v| v.as_str())
+                        .filter(|s| !s.is_empty())
+                        .map(|s| run.substitute(s));
+                    // A single string is accepted where a list is expected
+                    let job_list = |key: &str| -> Vec<String> {
+                        match job.get(key) {
+                            Some(serde_json::Value::String(s)) => vec![run.substitute(s)],
+                            Some(serde_json::Value::Array(items)) => {
+                                items.iter().filter_map(|v| v.as_str()).map(|s| run.substitute(s)).collect()
+                            }
+                            _ => Vec::new(),
+                        }
+                    };
+                    let format = archive::Format::parse(&job_str("format").unwrap_or_else(|| "tar.gz".to_string()))?;
+                    let Some(output) = job_str("output") else {
+                        anyhow::bail!("Archive job {} has no output name", index + 1);
+                    };
+                    let if_empty = archive::IfEmpty::parse(&job_str("if_empty").unwrap_or_else(|| "fail".to_string()))?;
+                    let exclude = job_list("exclude")
+                        .iter()
+                        .map(|p| glob::Pattern::new(p).with_context(|| format!("Invalid exclude pattern {}", p)))
+                        .collect::<Result<Vec<_>>>()?;
+                    let strip_leading_dir = job.get("strip_leading_dir")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false);
+                    (format, output, if_empty, exclude, job_list("input"), strip_leading_dir)
+                };
+                check_archive_paths(index, &patterns, &output, allow_outside)?;
+                if !output.ends_with(&format!(".{}", format.extension())) {
+                    output = format!("{}.{}", output, format.extension());
+                }
+                
+                let mut inputs = Vec::new();
+                for pattern in patterns {
+                    inputs.extend(glob::glob(&workdir.join(&pattern).to_string_lossy())?.filter_map(|entry| entry.ok()));
+                }
+                if inputs.is_empty() {
+                    if if_empty == archive::IfEmpty::Fail {
+                        anyhow::bail!("The input of archive job {} matches nothing", index + 1);
+                    }
+                    run.log.line(&format!("[archive] Warning: the input of {} matches nothing, skipping it", output)).await;
+                    continue;
+                }
+                
+                let output = workdir.join(output);
+                let job = archive::Job {
+                    inputs,
+                    format,
+                    output: output.clone(),
+                    strip_leading_dir,
+                    exclude,
+                    base: None,
+                };
+                let count = archive::create(job).await?;
+                run.log.line(&format!("[archive] Wrote {} with {} entries", output.display(), count)).await;
+                collect_artifact(run, output).await?;
+            }
+        }
+        "checksums" => {
+            let algorithm = checksums::Algorithm::parse(node.config.get("algorithm")
+                .and_then(|v| v.as_str())
+                .unwrap_or("sha256"))?;
+            let output = workdir.join(node.config.get("output")
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(|s| run.substitute(s))
+                .unwrap_or_else(|| algorithm.sums_name().to_string()));
+            let sidecars = node.config.get("sidecars")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            
+            // Without paths, whatever the build has collected so far
+            let patterns = config_list(run, node, "paths");
+            let mut files = Vec::new();
+            if patterns.is_empty() {
+                files.extend(run.outputs.artifacts.iter().map(|a| PathBuf::from(&a.path)));
+            }
+            for pattern in &patterns {
+                for path in glob::glob(&workdir.join(pattern).to_string_lossy())?.flatten() {
+                    if path.is_file() {
+                        files.push(path);
+                    }
+                }
+            }
+            files.retain(|f| f != &output);
+            if files.is_empty() {
+                anyhow::bail!("Nothing to checksum; collect artifacts before this node or set paths");
+            }
+            
+            let mut entries: Vec<(String, String)> = Vec::new();
+            let mut produced = Vec::new();
+            for file in &files {
+                let name = file.file_name().unwrap_or_default().to_string_lossy().to_string();
+                if entries.iter().any(|(n, _)| n == &name) {
+                    anyhow::bail!("More than one file is named {}, the sums file could not tell them apart", name);
+                }
+                let hash = checksums::hash_file(file, algorithm).await?;
+                if sidecars {
+                    let mut sidecar = file.as_os_str().to_owned();
+                    sidecar.push(format!(".{}", algorithm.extension()));
+                    tokio::fs::write(&sidecar, checksums::sums_line(&hash, &name)).await?;
+                    produced.push(PathBuf::from(sidecar));
+                }
+                entries.push((name, hash));
+            }
+            if let Some(dir) = output.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(&output, checksums::sums_file(&entries)).await?;
+            run.log.line(&format!("[checksums] Wrote {} for {} files", output.display(), entries.len())).await;
+            
+            if let Some(key) = node_secret(node, "minisign_key_env")? {
+                let password = node_secret(node, "minisign_password_env")?;
+                let signature = checksums::minisign(&output, &key, password.as_deref(), &run.cancel, run.log).await?;
+                run.log.line(&format!("[checksums] Signed as {}", signature.display())).await;
+                produced.insert(0, signature);
+            }
+            produced.insert(0, output.clone());
+            for path in produced {
+                collect_artifact(run, path).await?;
+            }
+            run.set_output(node, "file", output.to_string_lossy().to_string());
+        }
+        "changelog" => {
+            let config_str = |key: &str| node.config.get(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(|s| run.substitute(s));
+            let options = changelog::Options {
+                from_tag: config_str("from_tag"),
+                to_ref: config_str("to_ref").unwrap_or_else(|| "HEAD".to_string()),
+                group_by_type: node.config.get("group_by_type")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(true),
+                include_authors: node.config.get("include_authors")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            };
+            
+            let changelog = changelog::generate(&workdir, &options).await?;
+            match &changelog.from_tag {
+                Some(tag) => run.log.line(&format!(
+                    "[changelog] {} commits from {} to {}", changelog.commits.len(), tag, options.to_ref
+                )).await,
+                None => run.log.line(&format!(
+                    "[changelog] No previous tag, listing all {} commits up to {}", changelog.commits.len(), options.to_ref
+                )).await,
+            }
+            
+            if let Some(output_file) = config_str("output_file") {
+                let path = workdir.join(&output_file);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::write(&path, &changelog.markdown)
+                    .await
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                run.log.line(&format!("[changelog] Wrote {}", path.display())).await;
+            }
+            run.set_output(node, "changelog", changelog.markdown);
+        }
+        "version_bump" => {
+            let files: Vec<String> = match node.config.get("files").and_then(|v| v.as_array()) {
+                Some(files) => files.iter().filter_map(|f| f.as_str()).map(|f| run.substitute(f)).collect(),
+                None => manifests::DEFAULT_FILES
+                    .iter()
+                    .filter(|f| workdir.join(f).is_file())
+                    .map(|f| f.to_string())
+                    .collect(),
+            };
+            if files.is_empty() {
+                anyhow::bail!("No manifests to bump: list them in files");
+            }
+            let mut found = Vec::new();
+            for file in &files {
+                found.push((file, manifests::Manifest::read(&workdir.join(file)).await?));
+            }
+            
+            let (first_file, first) = &found[0];
+            let current = first.version().to_string();
+            for (file, manifest) in &found[1..] {
+                if manifest.version() != current {
+                    run.log.line(&format!(
+                        "[version] Warning: {} has version {}, {} has {}",
+                        file, manifest.version(), first_file, current
+                    )).await;
+                }
+            }
+            
+            let bump = node.config.get("bump")
+                .and_then(|v| v.as_str())
+                .unwrap_or("patch");
+            let new_version = match bump {
+                "explicit" => node.config.get("version")
+                    .and_then(|v| v.as_str())
+                    .filter(|v| !v.is_empty())
+                    .map(|v| run.substitute(v))
+                    .context("An explicit version bump needs a version")?,
+                kind => manifests::Bump::parse(kind)
+                    .with_context(|| format!("Unknown bump {}, expected major, minor, patch or explicit", kind))?
+                    .apply(&current)?,
+            };
+            
+            let dry_run = node.config.get("dry_run")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            run.log.line(&format!(
+                "[version] {} -> {}{}", current, new_version, if dry_run { " (dry run)" } else { "" }
+            )).await;
+            for (file, manifest) in &found {
+                let (number, old, new) = manifest.changed_line(&new_version);
+                run.log.line(&format!("[version] --- {}", file)).await;
+                run.log.line(&format!("[version] -{}: {}", number, old)).await;
+                run.log.line(&format!("[version] +{}: {}", number, new)).await;
+                if !dry_run {
+                    let path = workdir.join(file);
+                    tokio::fs::write(&path, manifest.with_version(&new_version))
+                        .await
+                        .with_context(|| format!("Failed to write {}", path.display()))?;
+                }
+            }
+            
+            run.version = new_version.clone();
+            run.set_output(node, "version", new_version);
+            
+            let commit = node.config.get("commit")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            if commit && !dry_run {
+                let message = run.substitute(node.config.get("commit_message")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("Bump version to $VERSION"));
+                let paths: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+                repos::commit(&workdir, &paths, &message, &run.cancel, run.log).await?;
+            }
+        }
+        "git_tag" => {
+            let tag = run.substitute(node.config.get("tag")
+                .and_then(|v| v.as_str())
+                .unwrap_or("v$VERSION"));
+            let message = run.substitute(node.config.get("message")
+                .and_then(|v| v.as_str())
+                .unwrap_or("Release $VERSION"));
+            let if_exists = node.config.get("if_exists")
+                .and_then(|v| v.as_str())
+                .unwrap_or("fail");
+            let request = repos::TagRequest {
+                tag: &tag,
+                message: &message,
+                sign: node.config.get("sign")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+                if_exists: repos::IfTagExists::parse(if_exists)
+                    .with_context(|| format!("Unknown if_exists {}, expected fail, skip or force", if_exists))?,
+                push_branch: node.config.get("push_branch")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            };
+            
+            run.log.line(&format!("[git] Tagging {}", tag)).await;
+            repos::tag_and_push(&workdir, &request, run.github_token.as_deref(), &run.cancel, run.log).await?;
+            run.set_output(node, "tag", tag);
+        }
+        "codesign" => {
+            let patterns = config_list(run, node, "paths");
+            if patterns.is_empty() {
+                anyhow::bail!("The codesign node needs at least one path in paths");
+            }
+            let config_str = |key: &str| node.config.get(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(|s| run.substitute(s));
+            let keychain = config_str("keychain").map(|path| -> Result<signing::Keychain> {
+                Ok(signing::Keychain { path, password: node_secret(node, "keychain_password_env")? })
+            }).transpose()?;
+            let options = config_list(run, node, "options");
+            let sign = signing::Codesign {
+                paths: signing::expand(&workdir, &patterns)?,
+                identity: codesign_identity(node)?,
+                entitlements: config_str("entitlements").map(|e| workdir.join(e)),
+                deep: node.config.get("deep")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+                options: (!options.is_empty()).then(|| options.join(",")),
+                keychain,
+                assess: node.config.get("assess")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(true),
+            };
+            
+            signing::codesign(&sign, &run.cancel, run.log).await?;
+        }
+        "signtool" => {
+            let patterns = config_list(run, node, "paths");
+            if patterns.is_empty() {
+                anyhow::bail!("The signtool node needs at least one path in paths");
+            }
+            let sign = signing::Authenticode {
+                paths: signing::expand(&workdir, &patterns)?,
+                certificate: authenticode_certificate(run, node)?,
+                tool: signtool_tool(node)?,
+                digest: node.config.get("digest")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("sha256")
+                    .to_string(),
+                timestamp_urls: config_list(run, node, "timestamp_urls"),
+                timestamp_rounds: node.config.get("timestamp_rounds")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(3) as u32,
+            };
+            if sign.timestamp_urls.is_empty() {
+                run.log.line("[signtool] No timestamp_urls, signatures will expire with the certificate").await;
+            }
+            
+            signing::authenticode(&sign, &run.cancel, run.log).await?;
+        }
+        "docker_build" => {
+            let image = docker_image(run, node);
+            if image.tags.is_empty() && image.push {
+                anyhow::bail!("Pushing an image needs at least one tag");
+            }
+            if let Some(login) = docker_login(node)? {
+                docker::login(&login, &run.cancel, run.log).await?;
+            }
+            if let Some(digest) = docker::build(&workdir, &image, &run.cancel, run.log).await? {
+                run.log.line(&format!("[docker] Pushed {}", digest)).await;
+                run.set_output(node, "digest", digest);
+            }
+        }
+        "npm_publish" => {
+            let config_str = |key: &str| node.config.get(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(|s| run.substitute(s));
+            let access = config_str("access");
+            if let Some(access) = access.as_deref().filter(|a| !matches!(*a, "public" | "restricted")) {
+                anyhow::bail!("Unknown access {}, expected public or restricted", access);
+            }
+            let publish = npm::Publish {
+                package_dir: workdir.join(config_str("package_dir").unwrap_or_else(|| ".".to_string())),
+                registry: npm::registry_url(&config_str("registry").unwrap_or_else(|| npm::DEFAULT_REGISTRY.to_string())),
+                tag: config_str("tag"),
+                access,
+                dry_run: node.config.get("dry_run")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            };
+            let token = node_secret(node, "token_env")?;
+            
+            let (name, version) = npm::package(&publish.package_dir).await?;
+            run.set_output(node, "version", version.clone());
+            if npm::is_published(&publish.registry, &name, &version, token.as_deref()).await? {
+                if !skip_if_published(node)? {
+                    anyhow::bail!("{}@{} is already published to {}", name, version, publish.registry);
+                }
+                run.log.line(&format!("[npm] {}@{} is already published, skipping", name, version)).await;
+                return Ok(None);
+            }
+            
+            run.log.line(&format!("[npm] {}@{}{}", name, version, if publish.dry_run { " (dry run)" } else { "" })).await;
+            exit_code = Some(npm::publish(&publish, token.as_deref(), &run.cancel, run.log).await?);
+        }
+        "cargo_publish" => {
+            let config_str = |key: &str| node.config.get(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(|s| run.substitute(s));
+            let manifest_path = config_str("manifest_path").unwrap_or_else(|| "Cargo.toml".to_string());
+            let registry = config_str("registry").filter(|r| r != "crates-io");
+            let names: Vec<String> = node.config.get("packages")
+                .and_then(|v| v.as_array())
+                .map(|names| names.iter().filter_map(|n| n.as_str()).map(str::to_string).collect())
+                .unwrap_or_default();
+            let wait = Duration::from_secs(node.config.get("wait_secs")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0));
+            let publish = crates::Publish {
+                manifest_path: &manifest_path,
+                registry: registry.as_deref(),
+                dry_run: node.config.get("dry_run")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+                allow_dirty: node.config.get("allow_dirty")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            };
+            let token = node_secret(node, "token_env")?;
+            // Alternative registries need their sparse index to check for existing versions
+            let index = match &registry {
+                None => Some(crates::CRATES_IO_INDEX.to_string()),
+                Some(_) => config_str("index_url"),
+            };
+            
+            let packages = crates::packages_in_order(&workdir, &manifest_path, &names).await?;
+            let mut published = 0;
+            for package in &packages {
+                let exists = match &index {
+                    Some(index) => crates::is_published(index, &package.name, &package.version, token.as_deref()).await?,
+                    None => {
+                        run.log.line(&format!(
+                            "[cargo] No index_url for registry {}, not checking whether {} {} exists",
+                            registry.as_deref().unwrap_or_default(), package.name, package.version
+                        )).await;
+                        false
                     }
-                    let _ = data.save(&data_dir);
-                }
-                ServerMessage::DeleteWorkflow(id) => {
-                    info!("Deleting workflow: {}", id);
-                    let mut data = shared_data.write().await;
-                    data.workflows.retain(|w| w.id != id);
-                    let _ = data.save(&data_dir);
+                };
+                if exists {
+                    if !skip_if_published(node)? {
+                        anyhow::bail!("{} {} is already published", package.name, package.version);
+                    }
+                    run.log.line(&format!("[cargo] {} {} is already published, skipping", package.name, package.version)).await;
+                    continue;
                 }
-                ServerMessage::SaveAction(action) => {
-                    info!("Saving action: {}", action.name);
-                    let mut data = shared_data.write().await;
-                    if let Some(existing) = data.actions.iter_mut().find(|a| a.id == action.id) {
-                        *existing = action;
-                    } else {
-                        data.actions.push(action);
+                
+                if published > 0 && !wait.is_zero() && !publish.dry_run {
+                    run.log.line(&format!("[cargo] Waiting {}s for the index to update", wait.as_secs())).await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = run.cancel.cancelled() => return Err(BuildCancelled.into()),
                     }
-                    let _ = data.save(&data_dir);
                 }
-                ServerMessage::DeleteAction(id) => {
-                    info!("Deleting action: {}", id);
-                    let mut data = shared_data.write().await;
-                    data.actions.retain(|a| a.id != id);
-                    let _ = data.save(&data_dir);
+                run.log.line(&format!(
+                    "[cargo] Publishing {} {}{}", package.name, package.version, if publish.dry_run { " (dry run)" } else { "" }
+                )).await;
+                exit_code = Some(crates::publish(&workdir, package, &publish, token.as_deref(), &run.cancel, run.log).await?);
+                published += 1;
+            }
+        }
+        "cargo_build" => {
+            let targets: Vec<String> = node.config.get("targets")
+                .and_then(|v| v.as_array())
+                .map(|t| t.iter().filter_map(|t| t.as_str()).map(|t| run.substitute(t)).collect())
+                .unwrap_or_default();
+            if targets.is_empty() {
+                anyhow::bail!("The cargo_build node needs at least one target in targets");
+            }
+            let config_str = |key: &str, default: &str| {
+                run.substitute(node.config.get(key).and_then(|v| v.as_str()).filter(|s| !s.is_empty()).unwrap_or(default))
+            };
+            let manifest_path = config_str("manifest_path", "Cargo.toml");
+            let profile = config_str("profile", "release");
+            let package = node.config.get("package").and_then(|v| v.as_str()).map(|s| run.substitute(s));
+            let target_dir = PathBuf::from(config_str("target_dir", "target/buildforge"));
+            let features: Vec<String> = node.config.get("features")
+                .and_then(|v| v.as_array())
+                .map(|f| f.iter().filter_map(|f| f.as_str()).map(str::to_string).collect())
+                .unwrap_or_default();
+            let fail_fast = node.config.get("fail_fast")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            let parallel = node.config.get("parallel")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true);
+            let build = crates::TargetBuild {
+                manifest_path: &manifest_path,
+                package: package.as_deref(),
+                profile: &profile,
+                features: &features,
+                target_dir: &target_dir,
+            };
+            
+            // rustup is not safe to run concurrently, install targets up front
+            match crates::installed_targets().await {
+                Some(installed) => {
+                    for target in targets.iter().filter(|t| !installed.contains(*t)) {
+                        crates::add_target(target, &run.cancel, run.log).await?;
+                    }
                 }
-                ServerMessage::RunAction(payload) => {
-                    info!("Running action: {}", payload.action_id);
-                    let data = shared_data.read().await;
-                    if let Some(action) = data.actions.iter().find(|a| a.id == payload.action_id) {
-                        // Build environment with inputs
-                        let mut script = action.script.clone();
-                        for (key, value) in &payload.inputs {
-                            script = format!("export {}=\"{}\"\n{}", key, value, script);
+                None => run.log.line("[cargo] rustup not found, assuming the targets are installed").await,
+            }
+            
+            let limit = if parallel { targets.len() } else { 1 };
+            let shared: &BuildRun = run;
+            // Boxed up front, a lazily mapped iterator held across the awaits
+            // below keeps the build's future from being Send
+            let instances: Vec<BoxFuture<'_, (String, Option<Vec<PathBuf>>)>> = targets
+                .iter()
+                .map(|t| build_instance(shared, node, &build, t).boxed())
+                .collect();
+            let mut instances = futures_util::stream::iter(instances).buffer_unordered(limit);
+            let mut built = Vec::new();
+            let mut failures = Vec::new();
+            while let Some((target, binaries)) = instances.next().await {
+                match binaries {
+                    Some(binaries) => built.push((target, binaries)),
+                    None => {
+                        failures.push(target);
+                        if fail_fast {
+                            break;
                         }
-                        
-                        let result = run_script(&script, &workdir).await;
-                        let (success, output) = match result {
-                            Ok(out) => (true, out),
-                            Err(e) => (false, e.to_string()),
-                        };
-                        
-                        let response = serde_json::to_string(&ServerMessage::ActionResult(ActionResultPayload {
-                            action_id: payload.action_id,
-                            success,
-                            output,
-                        }))?;
-                        write.send(Message::Text(response)).await?;
+                    }
+                }
+            }
+            drop(instances);
+            
+            // Stopped by fail_fast before they finished
+            for target in targets.iter().filter(|t| !built.iter().any(|(b, _)| b == *t) && !failures.contains(t)) {
+                let mut instance = NodeRun::start(&instance_node(node, target), 1);
+                instance.finish(&Err(BuildCancelled.into()));
+                builds::node_finished(run.ctx, build_id, instance).await;
+            }
+            if run.cancel.is_cancelled() {
+                return Err(BuildCancelled.into());
+            }
+            
+            for (target, binaries) in built {
+                for path in binaries {
+                    let path = if run.isolated {
+                        artifacts::retain(&run.ctx.data_dir, build_id, &workdir, &path).await?
                     } else {
-                        let response = serde_json::to_string(&ServerMessage::Error(
-                            format!("Action not found: {}", payload.action_id)
-                        ))?;
-                        write.send(Message::Text(response)).await?;
+                        path
+                    };
+                    let mut artifact = ArtifactInfo::describe(&path).await?;
+                    artifact.target = Some(target.clone());
+                    info!("Collected artifact: {:?} for {} ({} bytes, sha256 {})", path, target, artifact.size, artifact.sha256);
+                    run.outputs.artifacts.push(artifact);
+                }
+            }
+            if !failures.is_empty() {
+                anyhow::bail!("cargo build failed for {}", failures.join(", "));
+            }
+        }
+        "release" => {
+            let Some(token) = &run.github_token else {
+                anyhow::bail!("The release node needs a GitHub token, none was configured");
+            };
+            let (owner, repo, source) = release_target(run, node).await?;
+            run.log.line(&format!("[release] Releasing to {}/{} (from the {})", owner, repo, source)).await;
+            
+            let tag = run.substitute(node.config.get("tag")
+                .and_then(|v| v.as_str())
+                .unwrap_or("v1.0.0"));
+            
+            let title = run.substitute(node.config.get("title")
+                .and_then(|v| v.as_str())
+                .unwrap_or("Release"));
+            
+            let body = run.substitute(node.config.get("body")
+                .and_then(|v| v.as_str())
+                .unwrap_or(""));
+            
+            let draft = node.config.get("draft")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            
+            let prerelease = node.config.get("prerelease")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            
+            // Only used by GitHub if the tag does not exist yet
+            let target_commitish = node.config.get("target_commitish")
+                .and_then(|v| v.as_str())
+                .map(|s| run.substitute(s))
+                .or_else(|| run.commit_sha.clone());
+            
+            let release = github::NewRelease {
+                tag_name: tag,
+                name: title,
+                body,
+                draft,
+                prerelease,
+                target_commitish,
+            };
+            let update_existing = node.config.get("update_existing")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            
+            let endpoints = github_endpoints(run, node);
+            if endpoints != github::GitHubEndpoints::default() {
+                run.log.line(&format!("[release] Using the GitHub API at {}", endpoints.api_base)).await;
+            }
+            let client = github::GitHubClient::new(token, endpoints);
+            
+            run.log.line(&format!("[release] Tag {}", release.tag_name)).await;
+            let (release, action) = client
+                .ensure_release(&owner, &repo, &release, update_existing)
+                .await?;
+            let what = match action {
+                github::ReleaseAction::Created => "Created a new release and tag",
+                github::ReleaseAction::CreatedForExistingTag => "The tag already existed, created a release for it",
+                github::ReleaseAction::Reused => "A release for the tag already existed, left it unchanged",
+                github::ReleaseAction::Updated => "A release for the tag already existed, updated it to match the node",
+            };
+            run.log.line(&format!("[release] {}: {}", what, release.html_url)).await;
+            
+            let upload_artifacts = node.config.get("upload_artifacts")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            if upload_artifacts {
+                for artifact in &run.outputs.artifacts {
+                    let path = PathBuf::from(&artifact.path);
+                    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                    if release.assets.iter().any(|a| a.name == name) {
+                        run.log.line(&format!("[release] {} is already attached, skipping", name)).await;
+                        continue;
                     }
+                    run.log.line(&format!("[release] Uploading {}", name)).await;
+                    client.upload_asset(&owner, &repo, &release, &path).await?;
                 }
-                _ => {}
             }
+            run.outputs.release_url = Some(release.html_url);
+        }
+        "placeholder" => {
+            // Left by ImportGitHubActionsWorkflow for an action with no equivalent
+            let uses = node.config.get("uses").and_then(|v| v.as_str()).unwrap_or("a GitHub action");
+            anyhow::bail!("{} stands in for {}; replace it with nodes that do the same", node.name, uses);
+        }
+        _ => {
+            warn!("Unknown node type: {}", node.node_type);
         }
     }
     
-    info!("WebSocket connection closed");
+    Ok(exit_code)
+}
+
+/// What a command or test node runs: its `command` through `sh -c`, or its
+/// `program` with its `args`, each substituted on its own, without a shell.
+/// `None` if it has neither.
+fn command_argv(run: &BuildRun<'_>, node: &BuildNode) -> Result<Option<Vec<String>>> {
+    let containerized = container::for_node(node, run.container.as_ref())?.is_some();
+    let substitute = |text: &str| if containerized {
+        run.substitute_in_container(text)
+    } else {
+        run.substitute(text)
+    };
+    match (config_text(&node.config, "command"), config_text(&node.config, "program")) {
+        (Some(_), Some(_)) => anyhow::bail!("The node has both a command and a program; give it one or the other"),
+        (Some(command), None) => {
+            if node.config.get("args").is_some_and(|v| !v.is_null()) {
+                anyhow::bail!("args only apply to a program; put them in the command instead");
+            }
+            Ok(Some(command_shell(run, node)?.command_argv(&substitute(command))))
+        }
+        (None, Some(program)) => program_argv(program, node.config.get("args"), substitute).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+/// `program` and its `args` as they are spawned, each substituted on its own
+fn program_argv(program: &str, args: Option<&serde_json::Value>, substitute: impl Fn(&str) -> String) -> Result<Vec<String>> {
+    let mut argv = vec![substitute(program)];
+    match args {
+        None | Some(serde_json::Value::Null) => {}
+        Some(serde_json::Value::Array(args)) => {
+            for arg in args {
+                let arg = match arg {
+                    serde_json::Value::String(arg) => arg.clone(),
+                    serde_json::Value::Number(n) => n.to_string(),
+                    serde_json::Value::Bool(b) => b.to_string(),
+                    other => anyhow::bail!("Argument {} must be text, a number or true/false", other),
+                };
+                argv.push(substitute(&arg));
+            }
+        }
+        Some(_) => anyhow::bail!("args must be a list"),
+    }
+    Ok(argv)
+}
+
+/// Settings of a node that could never run, refused when its workflow is
+/// saved or imported instead of when a build reaches it
+fn check_node_config(node_type: &str, config: &serde_json::Value) -> Result<()> {
+    let set = |key: &str| config_text(config, key).is_some();
+    if matches!(node_type, "command" | "test") && set("command") && set("program") {
+        anyhow::bail!("a {} node runs either a command or a program, not both", node_type);
+    }
     Ok(())
 }
 
-async fn run_script(script: &str, workdir: &PathBuf) -> Result<String> {
-    let output = Command::new("bash")
-        .arg("-c")
-        .arg(script)
-        .current_dir(workdir)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .await?;
-    
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let stderr = String::from_utf8_lossy(&output.stderr);
+/// The shell a command node's `command` runs in: its own `shell`, else `sh`
+/// in a container and the platform's usual one on the server
+fn command_shell(run: &BuildRun<'_>, node: &BuildNode) -> Result<shell::Shell> {
+    if let Some(name) = node.config.get("shell").and_then(|v| v.as_str()).filter(|s| !s.is_empty()) {
+        return Ok(shell::Shell::parse(name));
+    }
+    Ok(match container::for_node(node, run.container.as_ref())? {
+        Some(_) => shell::Shell::Posix("sh".to_string()),
+        None => shell::Shell::for_commands(),
+    })
+}
+
+/// Runs `argv` for a command or test node in its `cwd`, in a container if it
+/// has one
+async fn run_node_command(run: &BuildRun<'_>, node: &BuildNode, argv: &[String]) -> Result<i32> {
+    let cwd = node.config.get("cwd")
+        .and_then(|v| v.as_str())
+        .map(|s| run.substitute(s))
+        .unwrap_or_else(|| run.workdir.to_string_lossy().to_string());
     
-    if output.status.success() {
-        Ok(format!("{}{}", stdout, stderr))
+    check_command_policy(run, node, argv)?;
+    let containerized = container::for_node(node, run.container.as_ref())?;
+    let env = node_env(run, node, containerized.is_some())?;
+    let resource_limits = node_limits(run, node, containerized.is_some()).await?;
+    // Callers that look at the exit code check for dry runs themselves
+    if is_dry_run(node) {
+        log_dry_run(run, &argv.join(" "), &env).await;
+        return Ok(0);
+    }
+    match containerized {
+        Some(container) => {
+            let cwd = container::container_path(&run.workdir, Path::new(&cwd))?;
+            info!("Running in {}: {:?} in {}", container.image, argv, cwd);
+            let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+            container::run(&container, &run.workdir, &cwd, &argv, &env, &run.cancel, run.log)
+                .await?
+                .check("Command")
+        }
+        None => {
+            let limits = limits::prepare(&resource_limits, run.log).await;
+            limits.check(run_command(argv, &cwd, &env, &limits, &run.cancel, run.log).await)
+        }
+    }
+}
+
+/// The shell a script node runs in, and its body with variables filled in
+fn node_script(run: &BuildRun<'_>, node: &BuildNode, containerized: bool) -> (shell::Shell, String) {
+    let script = node.config.get("script")
+        .and_then(|v| v.as_str())
+        .unwrap_or("echo 'No script'");
+    let default_shell = shell::Shell::parse(&run.ctx.settings().default_shell);
+    let shell = node.config.get("shell")
+        .and_then(|v| v.as_str())
+        .filter(|s| !s.is_empty())
+        .map(shell::Shell::parse);
+    if containerized {
+        // Containers run Linux, whatever the server's default is
+        let shell = shell.unwrap_or(match default_shell {
+            shell::Shell::Posix(_) => default_shell,
+            _ => shell::Shell::Posix("sh".to_string()),
+        });
+        (shell, run.substitute_in_container(script))
     } else {
-        anyhow::bail!("Script failed: {}{}", stdout, stderr)
+        (shell.unwrap_or(default_shell), run.substitute(script))
     }
 }
 
-async fn execute_build(
-    payload: BuildStartPayload,
-    github_token: Option<String>,
-    workdir: PathBuf,
-) -> Result<()> {
-    let start_time = std::time::Instant::now();
-    let build_id = &payload.build_id;
-    
-    // Sort nodes by dependencies (topological sort)
-    let sorted_nodes = topological_sort(&payload.nodes, &payload.edges)?;
-    let total_nodes = sorted_nodes.len();
-    let mut artifacts: Vec<String> = Vec::new();
-    let mut release_url: Option<String> = None;
-    
-    for (index, node) in sorted_nodes.iter().enumerate() {
-        let progress = ((index as f32 / total_nodes as f32) * 100.0) as u8;
-        
-        info!("Executing node: {} ({})", node.name, node.node_type);
-        
-        match node.node_type.as_str() {
-            "command" => {
-                let command = node.config.get("command")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("echo 'No command specified'");
-                
-                let cwd = node.config.get("cwd")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.replace("$PROJECT_ROOT", workdir.to_str().unwrap_or(".")))
-                    .unwrap_or_else(|| workdir.to_string_lossy().to_string());
-                
-                run_command(command, &cwd, build_id).await?;
-            }
-            "script" => {
-                let script = node.config.get("script")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("echo 'No script'");
-                
-                let shell = node.config.get("shell")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("bash");
-                
-                run_script_with_shell(script, shell, &workdir, build_id).await?;
-            }
-            "artifact" => {
-                let path_pattern = node.config.get("path")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("dist/*");
-                
-                let full_pattern = workdir.join(path_pattern);
-                for entry in glob::glob(full_pattern.to_str().unwrap())? {
-                    if let Ok(path) = entry {
-                        artifacts.push(path.to_string_lossy().to_string());
-                        info!("Collected artifact: {:?}", path);
-                    }
-                }
-            }
-            "release" => {
-                if let Some(token) = &github_token {
-                    let tag = node.config.get("tag")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("v1.0.0")
-                        .replace("$VERSION", &payload.version);
-                    
-                    let title = node.config.get("title")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("Release")
-                        .replace("$VERSION", &payload.version);
-                    
-                    let body = node.config.get("body")
-                        .and_then(|v| v.as_str())
-                        .unwrap_or("")
-                        .to_string();
-                    
-                    let draft = node.config.get("draft")
-                        .and_then(|v| v.as_bool())
-                        .unwrap_or(false);
-                    
-                    let prerelease = node.config.get("prerelease")
-                        .and_then(|v| v.as_bool())
-                        .unwrap_or(false);
-                    
-                    // Create GitHub release
-                    // release_url = create_github_release(...).await?;
-                    info!("Would create release: {} - {}", tag, title);
-                } else {
-                    warn!("No GitHub token provided, skipping release");
-                }
+/// Refuses what the server's execution policy does not let a command or
+/// test node run
+fn check_command_policy(run: &BuildRun<'_>, node: &BuildNode, argv: &[String]) -> Result<()> {
+    check_argv_policy(&run.ctx.settings().execution_policy, &node.config, argv)
+}
+
+fn check_argv_policy(policy: &policy::ExecutionPolicy, config: &serde_json::Value, argv: &[String]) -> Result<()> {
+    // `command_argv` hands a `command` to the shell as the last argument,
+    // unless the node names a program
+    match argv.last() {
+        Some(command) if config_text(config, "program").is_none() => policy.check_command(command),
+        _ => policy.check_argv(argv),
+    }
+}
+
+/// A text setting of a node; an empty one counts as unset
+fn config_text<'a>(config: &'a serde_json::Value, key: &str) -> Option<&'a str> {
+    config.get(key).and_then(|v| v.as_str()).filter(|s| !s.is_empty())
+}
+
+/// The resource limits of a node's processes. Containers take theirs from
+/// their `cpus` and `memory` instead.
+async fn node_limits(run: &BuildRun<'_>, node: &BuildNode, containerized: bool) -> Result<limits::ResourceLimits> {
+    if !containerized {
+        return limits::ResourceLimits::for_node(&node.config, &run.ctx.settings().resource_limits);
+    }
+    let own = ["nice", "cpu_limit", "memory_limit_mb"]
+        .iter()
+        .any(|key| node.config.get(*key).is_some_and(|v| !v.is_null()));
+    if own {
+        run.log.line("[limits] The node runs in a container; set cpus and memory on its container instead of nice, cpu_limit and memory_limit_mb").await;
+    }
+    Ok(limits::ResourceLimits::default())
+}
+
+/// The environment of a command, script or test node: `run.env()` with the
+/// node's own `env` on top
+fn node_env(run: &BuildRun<'_>, node: &BuildNode, containerized: bool) -> Result<Vec<(String, String)>> {
+    let mut env = if containerized { run.container_env() } else { run.env() };
+    let Some(vars) = node.config.get("env").filter(|v| !v.is_null()) else {
+        return Ok(env);
+    };
+    let vars = vars.as_object().context("env must be an object of names and values")?;
+    for (name, value) in vars {
+        check_env_name(name)?;
+        let value = match value {
+            serde_json::Value::String(value) => value.clone(),
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => value.to_string(),
+            _ => anyhow::bail!("The value of {} in env must be text", name),
+        };
+        let value = if containerized { run.substitute_in_container(&value) } else { run.substitute(&value) };
+        env.push((name.clone(), value));
+    }
+    Ok(env)
+}
+
+/// The stored action an action node's `action_id` names, in the `version`
+/// the node pins, or the latest
+async fn node_action(run: &BuildRun<'_>, node: &BuildNode) -> Result<StoredAction> {
+    let action_id = node.config.get("action_id")
+        .and_then(|v| v.as_str())
+        .filter(|s| !s.is_empty())
+        .context("The action node needs an action_id")?;
+    let pinned = actions::pinned_version(&node.config)?;
+    let latest = run.ctx.data.read().await
+        .actions
+        .iter()
+        .find(|a| a.id == action_id)
+        .cloned()
+        .with_context(|| format!("Action {} not found; it may have been deleted", action_id))?;
+    match pinned {
+        Some(version) if version != latest.version => run.ctx.history
+            .action_version(action_id, version)
+            .await?
+            .with_context(|| format!(
+                "Version {} of action {} is no longer kept; pin a newer version or latest (now {})",
+                version, latest.name, latest.version
+            )),
+        _ => Ok(latest),
+    }
+}
+
+/// The `inputs` of an action node, with build variables and node outputs
+/// filled in
+fn action_node_inputs(run: &BuildRun<'_>, node: &BuildNode) -> Result<HashMap<String, String>> {
+    let Some(inputs) = node.config.get("inputs").filter(|v| !v.is_null()) else {
+        return Ok(HashMap::new());
+    };
+    let inputs = inputs.as_object().context("inputs must map input names to values")?;
+    inputs.iter()
+        .map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => run.substitute(s),
+                serde_json::Value::Number(n) => n.to_string(),
+                serde_json::Value::Bool(b) => b.to_string(),
+                _ => anyhow::bail!("Input {} must be text, a number or true/false", name),
+            };
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+fn is_dry_run(node: &BuildNode) -> bool {
+    node.config.get("dry_run").and_then(|v| v.as_bool()).unwrap_or(false)
+}
+
+/// Logs what a node set to `dry_run` would run, and with which variables on
+/// top of the server's environment. Secrets are redacted by the log.
+async fn log_dry_run(run: &BuildRun<'_>, what: &str, env: &[(String, String)]) {
+    run.log.line(&format!("[dry run] Would run: {}", what)).await;
+    // Later entries win, as they do when the process starts
+    let effective: BTreeMap<&str, &str> = env.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    for (name, value) in effective {
+        run.log.line(&format!("[dry run]   {}={}", name, value)).await;
+    }
+}
+
+/// Sums up the JUnit reports a test node's `reports` globs match, leaving out
+/// files older than `since`, which an earlier build left behind. `None`, with
+/// a warning in the log, if there is no usable report.
+async fn read_test_reports(run: &BuildRun<'_>, node: &BuildNode, since: std::time::SystemTime) -> Result<Option<junit::TestSummary>> {
+    let patterns = config_list(run, node, "reports");
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut summary = junit::TestSummary::default();
+    let mut read = 0;
+    for pattern in &patterns {
+        let full_pattern = run.workdir.join(pattern);
+        let paths = glob::glob(&full_pattern.to_string_lossy())
+            .with_context(|| format!("Invalid reports pattern {}", pattern))?;
+        for path in paths.flatten() {
+            let fresh = std::fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified >= since);
+            if !fresh {
+                run.log.line(&format!("[test] Ignoring {}, it was not written by this run", path.display())).await;
+                continue;
             }
-            _ => {
-                warn!("Unknown node type: {}", node.node_type);
+            match summary.add_report(&path) {
+                Ok(()) => read += 1,
+                Err(e) => run.log.line(&format!("[test] Warning: {:#}", e)).await,
             }
         }
     }
-    
-    let duration = start_time.elapsed().as_secs();
-    info!("Build completed in {}s", duration);
-    
-    Ok(())
+    if read == 0 {
+        run.log.line(&format!("[test] Warning: no usable report matches {}, the command's exit status decides", patterns.join(", "))).await;
+        return Ok(None);
+    }
+    Ok(Some(summary))
 }
 
-async fn run_command(command: &str, cwd: &str, build_id: &str) -> Result<()> {
-    info!("[{}] Running: {} in {}", build_id, command, cwd);
+async fn run_command(
+    argv: &[String],
+    cwd: &str,
+    env: &[(String, String)],
+    limits: &limits::Guard,
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<i32> {
+    info!("Running: {:?} in {}", argv, cwd);
     
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
+    let (program, args) = argv.split_first().context("Nothing to run")?;
+    let mut command = Command::new(program);
+    command.args(args);
+    limits.apply(&mut command);
+    let child = process::configure(&mut command)
         .current_dir(cwd)
+        .envs(env.iter().cloned())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()?
-        .wait_with_output()
-        .await?;
+        .spawn()
+        .with_context(|| format!("Failed to start {}", program))?;
+    
+    let output = process::run(child, cancel, log).await?;
     
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        error!("[{}] Command failed: {}", build_id, stderr);
-        anyhow::bail!("Command failed: {}", stderr);
+        error!("Command failed: {}", output.stderr_tail);
     }
-    
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    info!("[{}] Output: {}", build_id, stdout);
-    
-    Ok(())
+    output.check("Command")
 }
 
-async fn run_script_with_shell(script: &str, shell: &str, workdir: &PathBuf, build_id: &str) -> Result<()> {
-    info!("[{}] Running script with {}", build_id, shell);
+/// Runs `script` with `shell` in the build directory, held to `limits`
+async fn run_script_with_shell(
+    script: &str,
+    shell: &shell::Shell,
+    env: &[(String, String)],
+    limits: &limits::Guard,
+    run: &BuildRun<'_>,
+) -> Result<i32> {
+    info!("Running script with {:?}", shell);
     
-    let script_path = workdir.join(format!(".buildforge-{}.sh", build_id));
-    tokio::fs::write(&script_path, script).await?;
+    let workdir = &run.workdir;
+    let script_path = workdir.join(format!(".buildforge-{}.{}", run.payload.build_id, shell.script_extension()));
+    tokio::fs::write(&script_path, shell.script_contents(script)).await?;
     
-    let result = Command::new(shell)
-        .arg(&script_path)
+    let argv = shell.script_argv(&script_path.to_string_lossy());
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    limits.apply(&mut command);
+    let child = process::configure(&mut command)
         .current_dir(workdir)
+        .envs(env.iter().cloned())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()?
-        .wait_with_output()
-        .await;
+        .spawn();
+    
+    let result = match child {
+        Ok(child) => process::run(child, &run.cancel, run.log).await,
+        Err(e) => Err(e.into()),
+    };
     
     // Cleanup script file
     let _ = tokio::fs::remove_file(&script_path).await;
     
     let output = result?;
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        error!("[{}] Script failed: {}", build_id, stderr);
-        anyhow::bail!("Script failed: {}", stderr);
+        error!("Script failed: {}", output.stderr_tail);
+    }
+    output.check("Script")
+}
+
+/// One target of a cargo_build node, reported as its own node named
+/// `<node>@<target>`
+fn instance_node(node: &BuildNode, target: &str) -> BuildNode {
+    BuildNode {
+        id: format!("{}@{}", node.id, target),
+        node_type: node.node_type.clone(),
+        name: format!("{}@{}", node.name, target),
+        config: serde_json::Value::Null,
     }
+}
+
+/// Builds one target of a cargo_build node, returning the target and the
+/// binaries it produced, or None if it failed
+async fn build_instance(
+    run: &BuildRun<'_>,
+    node: &BuildNode,
+    build: &crates::TargetBuild<'_>,
+    target: &str,
+) -> (String, Option<Vec<PathBuf>>) {
+    let build_id = &run.payload.build_id;
+    let mut instance = NodeRun::start(&instance_node(node, target), 1);
+    builds::node_started(run.ctx, build_id, &instance).await;
+    let (result, binaries) = match crates::build_target(&run.workdir, build, target, &run.cancel, run.log).await {
+        Ok(binaries) => (Ok(None), Some(binaries)),
+        Err(e) => {
+            run.log.line(&format!("[cargo] {} failed: {:#}", target, e)).await;
+            (Err(e), None)
+        }
+    };
+    instance.finish(&result);
+    builds::node_finished(run.ctx, build_id, instance).await;
+    (target.to_string(), binaries)
+}
+
+/// Like `run_script_with_shell`, in `container` with the working directory
+/// mounted
+async fn run_script_in_container(
+    script: &str,
+    shell: &shell::Shell,
+    container: &container::ContainerConfig,
+    env: &[(String, String)],
+    run: &BuildRun<'_>,
+) -> Result<i32> {
+    info!("Running script with {:?} in {}", shell, container.image);
     
-    Ok(())
+    let file_name = format!(".buildforge-{}.{}", run.payload.build_id, shell.script_extension());
+    let script_path = run.workdir.join(&file_name);
+    tokio::fs::write(&script_path, shell.script_contents(script)).await?;
+    
+    let in_container = format!("{}/{}", container::MOUNT, file_name);
+    let argv = shell.script_argv(&in_container);
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let result = container::run(
+        container,
+        &run.workdir,
+        container::MOUNT,
+        &argv,
+        env,
+        &run.cancel,
+        run.log,
+    )
+    .await;
+    
+    let _ = tokio::fs::remove_file(&script_path).await;
+    
+    let output = result?;
+    if !output.status.success() {
+        error!("Script failed: {}", output.stderr_tail);
+    }
+    output.check("Script")
 }
 
 fn topological_sort(nodes: &[BuildNode], edges: &[BuildEdge]) -> Result<Vec<BuildNode>> {
@@ -648,3 +4550,247 @@ fn topological_sort(nodes: &[BuildNode], edges: &[BuildEdge]) -> Result<Vec<Buil
     
     Ok(sorted_nodes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A context on a fresh data directory, which goes away with the
+    /// returned `TempDir`; `args` are flags on top
+    pub(crate) async fn context(args: &[&str]) -> (Arc<ServerContext>, process::TempDir) {
+        let dir = process::TempDir::new("test");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let path = dir.0.to_string_lossy().to_string();
+        let argv = ["buildforge-server", "--data-dir", &path, "--workdir", &path];
+        let args = Args::try_parse_from(argv.iter().chain(args)).unwrap();
+        (open_context(&args).await.unwrap(), dir)
+    }
+
+    fn argv(program: &str, args: serde_json::Value) -> Result<Vec<String>> {
+        program_argv(program, Some(&args), |text| text.replace("$TARGET", "x86_64 linux"))
+    }
+
+    fn argv_error(program: &str, args: serde_json::Value) -> String {
+        argv(program, args).unwrap_err().to_string()
+    }
+
+    fn variables(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn variables_are_replaced_inside_quoted_strings() {
+        let variables = variables(&[("$PROJECT_ROOT", "/srv/app"), ("$VERSION", "1.2.0")]);
+        assert_eq!(
+            replace_variables(r#"cp "$PROJECT_ROOT/assets/icon.png" 'dist/app-$VERSION.png'"#, &variables),
+            r#"cp "/srv/app/assets/icon.png" 'dist/app-1.2.0.png'"#
+        );
+        assert_eq!(replace_variables("echo \"v$VERSION\"", &variables), "echo \"v1.2.0\"");
+    }
+
+    #[test]
+    fn values_with_spaces_and_dollars_are_kept_as_they_are() {
+        let variables = variables(&[
+            ("$PROJECT_ROOT", "/home/me/My Projects/app"),
+            ("$VERSION", "$BRANCH"),
+            ("$BRANCH", "main"),
+        ]);
+        assert_eq!(
+            replace_variables("\"$PROJECT_ROOT/build.sh\" $VERSION $BRANCH", &variables),
+            "\"/home/me/My Projects/app/build.sh\" $BRANCH main"
+        );
+    }
+
+    #[test]
+    fn unknown_variables_and_lone_dollars_are_left_alone() {
+        let variables = variables(&[("$TAG", "v1")]);
+        assert_eq!(replace_variables("echo $HOME costs $5 at $TAG$", &variables), "echo $HOME costs $5 at v1$");
+    }
+
+    #[test]
+    fn the_longest_overlapping_name_wins() {
+        let variables = variables(&[("$MATRIX_os", "linux"), ("$MATRIX_os_version", "22.04")]);
+        assert_eq!(replace_variables("$MATRIX_os-$MATRIX_os_version", &variables), "linux-22.04");
+    }
+
+    #[test]
+    fn a_utf8_workdir_passes() {
+        assert!(check_workdir(Path::new("/srv/My Builds/app")).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_workdir_that_is_not_utf8_is_refused() {
+        use std::os::unix::ffi::OsStrExt;
+        let workdir = Path::new(std::ffi::OsStr::from_bytes(b"/srv/caf\xe9"));
+        let e = check_workdir(workdir).unwrap_err();
+        assert!(e.to_string().contains("is not valid UTF-8, so $PROJECT_ROOT cannot be filled in"));
+    }
+
+    #[test]
+    fn a_command_node_may_not_have_both_a_command_and_a_program() {
+        let both = serde_json::json!({"command": "cargo build", "program": "cargo"});
+        let e = check_node_config("command", &both).unwrap_err();
+        assert_eq!(e.to_string(), "a command node runs either a command or a program, not both");
+        assert!(check_node_config("test", &both).is_err());
+        assert!(check_node_config("command", &serde_json::json!({"command": "cargo build"})).is_ok());
+        assert!(check_node_config("command", &serde_json::json!({"program": "cargo", "command": ""})).is_ok());
+        // Other nodes are free to use the names for something else
+        assert!(check_node_config("notify", &both).is_ok());
+    }
+
+    #[test]
+    fn an_empty_program_leaves_the_command_to_the_policy() {
+        let config = serde_json::json!({"command": "rm -rf /tmp/x", "program": ""});
+        let argv = ["sh", "-c", "rm -rf /tmp/x"].map(String::from);
+        let policy = |allowed: &[&str]| policy::ExecutionPolicy {
+            allowed_programs: allowed.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(check_argv_policy(&policy(&["sh"]), &config, &argv).is_err());
+        assert!(check_argv_policy(&policy(&["rm"]), &config, &argv).is_ok());
+
+        let program = serde_json::json!({"command": "", "program": "sh", "args": ["-c", "rm -rf /tmp/x"]});
+        assert!(check_argv_policy(&policy(&["sh"]), &program, &argv).is_ok());
+    }
+
+    #[test]
+    fn program_args_are_substituted_one_by_one() {
+        let argv = argv("cargo", serde_json::json!(["build", "--target", "$TARGET", 2, true])).unwrap();
+        assert_eq!(argv, ["cargo", "build", "--target", "x86_64 linux", "2", "true"]);
+        assert_eq!(program_argv("ls", None, str::to_string).unwrap(), ["ls"]);
+        assert!(argv_error("cargo", serde_json::json!("build --release")).contains("args must be a list"));
+        assert!(argv_error("cargo", serde_json::json!([{"a": 1}])).contains("must be text"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn program_args_reach_the_process_unsplit() {
+        let argv = argv("printf", serde_json::json!(["%s|", "two words", "it's \"quoted\"", "$TARGET", "*"])).unwrap();
+        let output = Command::new(&argv[0]).args(&argv[1..]).output().await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "two words|it's \"quoted\"|x86_64 linux|*|");
+    }
+
+    #[test]
+    fn archive_jobs_stay_in_the_build_directory() {
+        let inputs = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert!(check_archive_paths(0, &inputs(&["dist/*", "./README.md"]), "app-1.0.zip", false).is_ok());
+        assert_eq!(
+            check_archive_paths(1, &inputs(&["dist/*", "../secrets/*"]), "app.zip", false).unwrap_err().to_string(),
+            "The input ../secrets/* of archive job 2 reaches outside the build directory; set allow_outside_workspace to allow it"
+        );
+        assert!(check_archive_paths(0, &inputs(&["/etc/*"]), "app.zip", false).is_err());
+        assert!(check_archive_paths(0, &inputs(&["../shared/*"]), "app.zip", true).is_ok());
+        for output in ["../app.zip", "/tmp/app.zip", "dist/../../app.zip"] {
+            let e = check_archive_paths(0, &inputs(&["dist/*"]), output, true).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                format!("The output {} of archive job 1 must be relative to the build directory, without ..", output)
+            );
+        }
+    }
+
+    /// Serves `ctx` on a free port the way `main` does, and returns the port
+    async fn serve(ctx: &Arc<ServerContext>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                tokio::spawn(handle_connection(stream, peer, ctx.clone()));
+            }
+        });
+        port
+    }
+
+    /// Starts a build that sleeps for a minute from a new connection, and
+    /// drops the connection once the sleep has begun
+    async fn start_and_disconnect(port: u16, build_id: &str, on_disconnect: DisconnectPolicy) {
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port)).await.unwrap();
+        let build = serde_json::json!({
+            "build_id": build_id,
+            "on_disconnect": on_disconnect,
+            "project_name": "sleeper",
+            "version": "1.0.0",
+            "nodes": [{ "id": "n1", "type": "command", "name": "Sleep", "config": { "command": "sleep 60" } }],
+            "edges": [],
+            "github_token": null,
+        });
+        let start = serde_json::json!({ "type": "BuildStart", "payload": build });
+        socket.send(Message::Text(start.to_string())).await.unwrap();
+        let started = async {
+            while let Some(Ok(message)) = socket.next().await {
+                if message.to_text().is_ok_and(|text| text.contains("\"NodeStart\"")) {
+                    return;
+                }
+            }
+            panic!("The connection closed before the build started");
+        };
+        tokio::time::timeout(Duration::from_secs(5), started).await.expect("The build did not start");
+        drop(socket);
+    }
+
+    #[tokio::test]
+    async fn an_import_is_audited_under_the_id_it_was_stored_as() {
+        let (ctx, _dir) = context(&[]).await;
+        let port = serve(&ctx).await;
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}", port)).await.unwrap();
+        let yaml = "format_version: 1\nid: wf\nname: App\nnext_version: 1.0.0\nnodes:\n  - id: build\n    type: command\n    config: {command: make}\n";
+
+        let mut imported = Vec::new();
+        for overwrite in [false, false, true] {
+            let import = serde_json::json!({ "type": "ImportWorkflow", "payload": { "yaml": yaml, "overwrite": overwrite } });
+            socket.send(Message::Text(import.to_string())).await.unwrap();
+            while let Some(Ok(message)) = socket.next().await {
+                let Ok(ServerMessage::WorkflowImported(result)) = serde_json::from_str(message.to_text().unwrap_or_default()) else {
+                    continue;
+                };
+                imported.push(result.workflow_id);
+                break;
+            }
+        }
+        assert_eq!(imported.len(), 3);
+        assert_eq!(imported[0], "wf");
+        assert_ne!(imported[1], "wf");
+        assert_eq!(imported[2], "wf");
+
+        let (entries, _) = ctx.audit.read(0, 10).await.unwrap();
+        let mut audited: Vec<(String, String)> = entries.into_iter().map(|e| (e.action, e.entity_id)).collect();
+        audited.reverse();
+        let expected: Vec<(String, String)> = imported.into_iter().map(|id| ("ImportWorkflow".to_string(), id)).collect();
+        assert_eq!(audited, expected);
+    }
+
+    /// Waits up to `timeout` for `build_id` to end, and returns its status
+    async fn ended(ctx: &ServerContext, build_id: &str, timeout: Duration) -> Option<String> {
+        let deadline = Instant::now() + timeout;
+        while builds::is_active(ctx, build_id).await {
+            if Instant::now() > deadline {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Some(ctx.history.get(build_id).await.unwrap().unwrap().status)
+    }
+
+    #[tokio::test]
+    async fn a_dropped_client_cancels_its_build_if_asked() {
+        let (ctx, _dir) = context(&[]).await;
+        let port = serve(&ctx).await;
+        start_and_disconnect(port, "cancel", DisconnectPolicy::Cancel).await;
+        let status = ended(&ctx, "cancel", Duration::from_secs(5)).await;
+        assert_eq!(status.as_deref(), Some(builds::status::CANCELLED));
+    }
+
+    #[tokio::test]
+    async fn a_dropped_client_leaves_its_build_running_if_asked() {
+        let (ctx, _dir) = context(&[]).await;
+        let port = serve(&ctx).await;
+        start_and_disconnect(port, "continue", DisconnectPolicy::Continue).await;
+        assert_eq!(ended(&ctx, "continue", Duration::from_secs(1)).await, None);
+
+        assert!(builds::cancel(&ctx, "continue").await);
+        let status = ended(&ctx, "continue", Duration::from_secs(5)).await;
+        assert_eq!(status.as_deref(), Some(builds::status::CANCELLED));
+    }
+}
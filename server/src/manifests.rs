@@ -0,0 +1,211 @@
+//! Reading and rewriting the version of project manifests, as done by the
+//! `version_bump` node.
+//!
+//! Only the version string itself is replaced, so comments, key order and
+//! indentation of the file stay as they were. Known formats are Cargo.toml
+//! (`[package].version`), package.json (`"version"`) and tauri.conf.json
+//! (`package.version`, or `version` in Tauri 2).
+
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// Manifests looked for when the node does not list any
+pub const DEFAULT_FILES: &[&str] = &[
+    "Cargo.toml",
+    "package.json",
+    "tauri.conf.json",
+    "src-tauri/Cargo.toml",
+    "src-tauri/tauri.conf.json",
+];
+
+/// A manifest and where its version is in it
+pub struct Manifest {
+    pub text: String,
+    /// Byte range of the version, without quotes
+    span: Range<usize>,
+}
+
+impl Manifest {
+    /// Reads `path` and finds its version by the file's name
+    pub async fn read(path: &Path) -> Result<Self> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let span = if name.ends_with(".toml") {
+            toml_version(&text)
+        } else if name == "tauri.conf.json" {
+            json_string(&text, &["package", "version"]).or_else(|| json_string(&text, &["version"]))
+        } else if name.ends_with(".json") {
+            json_string(&text, &["version"])
+        } else {
+            anyhow::bail!("{} is not a manifest BuildForge knows how to version", path.display());
+        };
+        let span = span.with_context(|| format!("No version found in {}", path.display()))?;
+        Ok(Self { text, span })
+    }
+
+    pub fn version(&self) -> &str {
+        &self.text[self.span.clone()]
+    }
+
+    /// The manifest with its version replaced
+    pub fn with_version(&self, version: &str) -> String {
+        format!("{}{}{}", &self.text[..self.span.start], version, &self.text[self.span.end..])
+    }
+
+    /// Number, old and new text of the line holding the version
+    pub fn changed_line(&self, version: &str) -> (usize, String, String) {
+        let start = self.text[..self.span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let end = self.text[self.span.end..].find('\n').map(|i| self.span.end + i).unwrap_or(self.text.len());
+        let number = self.text[..start].matches('\n').count() + 1;
+        let old = self.text[start..end].to_string();
+        let new = format!(
+            "{}{}{}",
+            &self.text[start..self.span.start],
+            version,
+            &self.text[self.span.end..end]
+        );
+        (number, old, new)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bump {
+    Major,
+    Minor,
+    Patch,
+}
+
+impl Bump {
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "major" => Some(Self::Major),
+            "minor" => Some(Self::Minor),
+            "patch" => Some(Self::Patch),
+            _ => None,
+        }
+    }
+
+    /// Bumps a `MAJOR.MINOR.PATCH` version; pre-release and build metadata
+    /// are dropped
+    pub fn apply(self, version: &str) -> Result<String> {
+        let core = version.split(['-', '+']).next().unwrap_or(version);
+        let parts: Vec<u64> = core
+            .split('.')
+            .map(|p| p.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .ok()
+            .filter(|p: &Vec<u64>| p.len() == 3)
+            .with_context(|| format!("{} is not a MAJOR.MINOR.PATCH version", version))?;
+        let (major, minor, patch) = (parts[0], parts[1], parts[2]);
+        Ok(match self {
+            Self::Major => format!("{}.0.0", major + 1),
+            Self::Minor => format!("{}.{}.0", major, minor + 1),
+            Self::Patch => format!("{}.{}.{}", major, minor, patch + 1),
+        })
+    }
+}
+
+/// The quoted value of `version` in the `[package]` table
+fn toml_version(text: &str) -> Option<Range<usize>> {
+    let mut table = String::new();
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            table = trimmed.trim_matches(|c| c == '[' || c == ']').trim().to_string();
+            continue;
+        }
+        if table != "package" {
+            continue;
+        }
+        let Some(rest) = trimmed.strip_prefix("version") else {
+            continue;
+        };
+        // `version.workspace = true` has no version of its own
+        let Some(value) = rest.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value_start = start + (value.as_ptr() as usize - line.as_ptr() as usize) + 1;
+        let len = value[1..].find(quote)?;
+        return Some(value_start..value_start + len);
+    }
+    None
+}
+
+/// The string at `path` of nested object keys in a JSON document
+fn json_string(text: &str, path: &[&str]) -> Option<Range<usize>> {
+    // Makes sure the document parses before scanning it by hand
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    path.iter().try_fold(&value, |v, key| v.get(key))?.as_str()?;
+
+    let bytes = text.as_bytes();
+    // Open containers: whether each is an object, and the key it is under
+    let mut stack: Vec<(bool, Option<String>)> = Vec::new();
+    let mut pending_key: Option<String> = None;
+    let mut expecting_key = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'{' => {
+                stack.push((true, pending_key.take()));
+                expecting_key = true;
+            }
+            b'[' => {
+                stack.push((false, pending_key.take()));
+                expecting_key = false;
+            }
+            b'}' | b']' => {
+                stack.pop();
+                pending_key = None;
+            }
+            b',' => {
+                expecting_key = stack.last().is_some_and(|(object, _)| *object);
+                pending_key = None;
+            }
+            b'"' => {
+                let end = string_end(bytes, i)?;
+                if expecting_key {
+                    pending_key = Some(text[i + 1..end].to_string());
+                    expecting_key = false;
+                } else {
+                    let at_path = stack.len() == path.len()
+                        && stack
+                            .iter()
+                            .skip(1)
+                            .map(|(_, key)| key.as_deref())
+                            .chain([pending_key.as_deref()])
+                            .eq(path.iter().map(|k| Some(*k)));
+                    if at_path {
+                        return Some(i + 1..end);
+                    }
+                    pending_key = None;
+                }
+                i = end;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Index of the quote closing the string that starts at `start`
+fn string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
@@ -0,0 +1,91 @@
+//! Running one node once per combination of values, set with a `matrix`
+//! object in its config such as `{"ARCH": ["amd64", "arm64"]}`.
+//!
+//! Each combination runs as its own instance of the node, with the values
+//! available as `$MATRIX_<NAME>` in its settings and environment.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::BuildNode;
+
+/// Most instances a single node may expand into
+pub const MAX_INSTANCES: usize = 64;
+
+/// Values of one instance, in the order the matrix lists the variables
+pub type Combination = Vec<(String, String)>;
+
+/// What a node downstream of a matrixed node needs from its instances
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeWait {
+    /// Every instance must succeed
+    #[default]
+    All,
+    /// One successful instance is enough
+    Any,
+}
+
+/// The combinations `node` expands into, or None if it has no matrix
+pub fn combinations(node: &BuildNode) -> Result<Option<Vec<Combination>>> {
+    let Some(matrix) = node.config.get("matrix") else {
+        return Ok(None);
+    };
+    let matrix = matrix
+        .as_object()
+        .ok_or_else(|| anyhow::anyhow!("The matrix of {} must map variable names to lists of values", node.name))?;
+    if matrix.is_empty() {
+        anyhow::bail!("The matrix of {} has no variables", node.name);
+    }
+
+    let mut combinations: Vec<Combination> = vec![Vec::new()];
+    for (name, values) in matrix {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            anyhow::bail!("Matrix variable {:?} of {} may only use letters, digits and _", name, node.name);
+        }
+        let values: Vec<String> = values
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("Matrix variable {} of {} must be a list", name, node.name))?
+            .iter()
+            .map(|v| v.as_str().map(str::to_string).unwrap_or_else(|| v.to_string()))
+            .collect();
+        if values.is_empty() {
+            anyhow::bail!("Matrix variable {} of {} has no values", name, node.name);
+        }
+        if combinations.len() * values.len() > MAX_INSTANCES {
+            anyhow::bail!(
+                "The matrix of {} expands into more than {} instances",
+                node.name,
+                MAX_INSTANCES
+            );
+        }
+        combinations = combinations
+            .iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push((name.to_uppercase(), value.clone()));
+                    combination
+                })
+            })
+            .collect();
+    }
+    Ok(Some(combinations))
+}
+
+/// The node as run for one combination: `<id>@<values>` named
+/// `<name> [NAME=value, ...]`, without the matrix
+pub fn instance(node: &BuildNode, combination: &Combination) -> BuildNode {
+    let values: Vec<&str> = combination.iter().map(|(_, value)| value.as_str()).collect();
+    let labels: Vec<String> = combination.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+    let mut config = node.config.clone();
+    if let Some(config) = config.as_object_mut() {
+        config.remove("matrix");
+    }
+    BuildNode {
+        id: format!("{}@{}", node.id, values.join(",")),
+        node_type: node.node_type.clone(),
+        name: format!("{} [{}]", node.name, labels.join(", ")),
+        config,
+    }
+}
@@ -0,0 +1,177 @@
+//! Posting a message about the build to a chat or webhook, as done by the
+//! `notify` node.
+//!
+//! Webhook URLs carry their own credentials, so they never appear in the
+//! build log or in an error; only the host is shown.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::json;
+
+/// Longest message sent, in characters; Discord's limit for an embed
+const MAX_MESSAGE_CHARS: usize = 4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Slack,
+    Discord,
+    /// A JSON object with the message and the build details
+    Webhook,
+}
+
+impl Provider {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "slack" => Ok(Self::Slack),
+            "discord" => Ok(Self::Discord),
+            "generic_webhook" | "webhook" => Ok(Self::Webhook),
+            other => anyhow::bail!("Unknown provider {}, expected slack, discord or generic_webhook", other),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Slack => "Slack",
+            Self::Discord => "Discord",
+            Self::Webhook => "webhook",
+        }
+    }
+}
+
+/// What a notification can tell about the build
+pub struct Summary {
+    pub project: String,
+    pub version: String,
+    pub succeeded: bool,
+    pub duration: Duration,
+    pub failed_node: Option<String>,
+    /// File names
+    pub artifacts: Vec<String>,
+    pub release_url: Option<String>,
+}
+
+impl Summary {
+    fn status(&self) -> &'static str {
+        if self.succeeded {
+            "succeeded"
+        } else {
+            "failed"
+        }
+    }
+
+    fn duration_text(&self) -> String {
+        let secs = self.duration.as_secs();
+        if secs >= 60 {
+            format!("{}m {}s", secs / 60, secs % 60)
+        } else {
+            format!("{}s", secs)
+        }
+    }
+
+    /// Replaces `$PROJECT_NAME`, `$STATUS`, `$DURATION`, `$FAILED_NODE`,
+    /// `$ARTIFACTS` and `$RELEASE_URL` in a message template
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("$PROJECT_NAME", &self.project)
+            .replace("$STATUS", self.status())
+            .replace("$DURATION", &self.duration_text())
+            .replace("$FAILED_NODE", self.failed_node.as_deref().unwrap_or("none"))
+            .replace("$ARTIFACTS", &self.artifacts.join(", "))
+            .replace("$RELEASE_URL", self.release_url.as_deref().unwrap_or(""))
+    }
+}
+
+/// Request body for `provider`
+pub fn payload(provider: Provider, message: &str, summary: &Summary) -> serde_json::Value {
+    let message: String = message.chars().take(MAX_MESSAGE_CHARS).collect();
+    let title = format!("{} {}", summary.project, summary.version);
+    let mut details = vec![("Status", summary.status().to_string()), ("Duration", summary.duration_text())];
+    if let Some(node) = &summary.failed_node {
+        details.push(("Failed node", node.clone()));
+    }
+    if let Some(url) = &summary.release_url {
+        details.push(("Release", url.clone()));
+    }
+    match provider {
+        Provider::Slack => {
+            let context: Vec<serde_json::Value> = details
+                .iter()
+                .map(|(name, value)| json!({ "type": "mrkdwn", "text": format!("*{}:* {}", name, value) }))
+                .collect();
+            json!({
+                "text": message,
+                "attachments": [{
+                    "color": if summary.succeeded { "#2eb67d" } else { "#e01e5a" },
+                    "blocks": [
+                        { "type": "section", "text": { "type": "mrkdwn", "text": message } },
+                        { "type": "context", "elements": context },
+                    ],
+                }],
+            })
+        }
+        Provider::Discord => {
+            let fields: Vec<serde_json::Value> = details
+                .iter()
+                .map(|(name, value)| json!({ "name": name, "value": value, "inline": true }))
+                .collect();
+            json!({
+                "embeds": [{
+                    "title": title,
+                    "description": message,
+                    "color": if summary.succeeded { 0x2eb67d } else { 0xe01e5a },
+                    "fields": fields,
+                }],
+            })
+        }
+        Provider::Webhook => json!({
+            "message": message,
+            "project": summary.project,
+            "version": summary.version,
+            "status": summary.status(),
+            "duration_secs": summary.duration.as_secs(),
+            "failed_node": summary.failed_node,
+            "artifacts": summary.artifacts,
+            "release_url": summary.release_url,
+        }),
+    }
+}
+
+/// `url` cut down to its scheme and host, safe to log
+pub fn redact(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+            // Drop user info, which is a credential too
+            let host = host.rsplit('@').next().unwrap_or_default();
+            format!("{}://{}/…", scheme, host)
+        }
+        None => "(webhook URL)".to_string(),
+    }
+}
+
+pub async fn post(provider: Provider, url: &str, body: &serde_json::Value, timeout: Duration) -> Result<()> {
+    let response = reqwest::Client::new()
+        .post(url)
+        .timeout(timeout)
+        .json(body)
+        .send()
+        .await
+        // reqwest puts the URL in its errors
+        .map_err(|e| anyhow::anyhow!("Failed to reach {} at {}: {}", provider.label(), redact(url), e.without_url()))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        anyhow::bail!(
+            "{} answered {}: {}",
+            provider.label(),
+            status,
+            text.chars().take(200).collect::<String>()
+        );
+    }
+    Ok(())
+}
+
+pub fn describe(provider: Provider, url: &str) -> String {
+    format!("{} ({})", provider.label(), redact(url))
+}
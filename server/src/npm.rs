@@ -0,0 +1,118 @@
+//! Publishing a package to an npm registry, as done by the `npm_publish` node.
+//!
+//! The token reaches npm only as `NODE_AUTH_TOKEN` in its environment. The
+//! temporary `.npmrc` names the variable instead of holding the token, and is
+//! removed however the node ends.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use tokio::process::Command;
+
+use crate::build_log::BuildLog;
+use crate::builds::CancelToken;
+use crate::process::{self, TempFile};
+
+pub const DEFAULT_REGISTRY: &str = "https://registry.npmjs.org/";
+
+pub struct Publish {
+    pub package_dir: PathBuf,
+    /// Ends with a slash
+    pub registry: String,
+    pub tag: Option<String>,
+    pub access: Option<String>,
+    pub dry_run: bool,
+}
+
+/// Name and version from the package.json in `dir`
+pub async fn package(dir: &Path) -> Result<(String, String)> {
+    let path = dir.join("package.json");
+    let text = tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let manifest: serde_json::Value =
+        serde_json::from_str(&text).with_context(|| format!("{} is not valid JSON", path.display()))?;
+    let field = |key: &str| {
+        manifest
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .with_context(|| format!("{} has no {}", path.display(), key))
+    };
+    Ok((field("name")?, field("version")?))
+}
+
+/// Normalizes a registry URL to end with exactly one slash
+pub fn registry_url(url: &str) -> String {
+    format!("{}/", url.trim().trim_end_matches('/'))
+}
+
+/// Whether `version` of `name` is already on the registry
+pub async fn is_published(registry: &str, name: &str, version: &str, token: Option<&str>) -> Result<bool> {
+    // Scoped names keep their @ but need the slash escaped
+    let url = format!("{}{}", registry, name.replace('/', "%2f"));
+    let mut request = reqwest::Client::new()
+        .get(&url)
+        .header(reqwest::header::ACCEPT, "application/vnd.npm.install-v1+json");
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.with_context(|| format!("Failed to query {}", registry))?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    if !response.status().is_success() {
+        anyhow::bail!("{} answered {} for {}", registry, response.status(), name);
+    }
+    let packument: serde_json::Value = response.json().await?;
+    Ok(packument
+        .get("versions")
+        .and_then(|v| v.as_object())
+        .is_some_and(|versions| versions.contains_key(version)))
+}
+
+/// Runs `npm publish` for `publish`, authenticating with `token` if given
+pub async fn publish(publish: &Publish, token: Option<&str>, cancel: &CancelToken, log: &BuildLog) -> Result<i32> {
+    let npmrc = TempFile::new("npmrc");
+    let mut config = format!("registry={}\n", publish.registry);
+    if token.is_some() {
+        // `//host/path/:_authToken`, the form npm matches against the registry
+        let scope = publish
+            .registry
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .unwrap_or(&publish.registry);
+        config.push_str(&format!("//{}:_authToken=${{NODE_AUTH_TOKEN}}\n", scope));
+    }
+    tokio::fs::write(&npmrc.0, config).await?;
+
+    let mut command = Command::new("npm");
+    command
+        .arg("publish")
+        .arg("--userconfig")
+        .arg(&npmrc.0)
+        .args(["--registry", &publish.registry]);
+    if let Some(tag) = &publish.tag {
+        command.args(["--tag", tag]);
+    }
+    if let Some(access) = &publish.access {
+        command.args(["--access", access]);
+    }
+    if publish.dry_run {
+        command.arg("--dry-run");
+    }
+    if let Some(token) = token {
+        command.env("NODE_AUTH_TOKEN", token);
+    }
+
+    let child = process::configure(&mut command)
+        .current_dir(&publish.package_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run npm; is it installed on the server?")?;
+    log.line(&format!("[npm] Publishing to {}", publish.registry)).await;
+    process::run(child, cancel, log).await?.check("npm publish")
+}
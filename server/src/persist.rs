@@ -0,0 +1,150 @@
+//! Debounced, atomic writes of `server-data.json`.
+//!
+//! Handlers only mark the data dirty; a background task writes it once no
+//! further change arrived for [`DEBOUNCE`], or at the latest [`MAX_DELAY`]
+//! after the first change so a steady stream of changes cannot keep the
+//! data unsaved. Each write goes to a temp file
+//! that is renamed over the target, and the previous file is kept as
+//! `server-data.json.bak`.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, Notify};
+use tracing::{error, info};
+
+use crate::{DataSaveFailedPayload, ServerContext, ServerMessage};
+
+const DEBOUNCE: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(5);
+
+pub const DATA_FILE: &str = "server-data.json";
+pub const BACKUP_FILE: &str = "server-data.json.bak";
+
+#[derive(Default)]
+pub struct Persistence {
+    dirty: AtomicBool,
+    changed: Notify,
+    /// Held for the duration of a write so a flush never races the background task
+    writing: Mutex<()>,
+}
+
+impl Persistence {
+    /// Schedules a save of the server data
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+        self.changed.notify_one();
+    }
+
+    /// Writes pending changes now, e.g. on shutdown
+    pub async fn flush(&self, ctx: &ServerContext) -> Result<()> {
+        let _writing = self.writing.lock().await;
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let result = write_snapshot(ctx).await;
+        if result.is_err() {
+            // Keep the changes pending so a later attempt can retry
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+/// Background task that performs debounced saves for the lifetime of the server
+pub async fn run(ctx: Arc<ServerContext>) {
+    loop {
+        ctx.persistence.changed.notified().await;
+        settle(&ctx.persistence.changed).await;
+
+        if let Err(e) = ctx.persistence.flush(&ctx).await {
+            error!("FAILED TO SAVE SERVER DATA: {:#}", e);
+            ctx.broadcast(ServerMessage::DataSaveFailed(DataSaveFailedPayload {
+                message: format!("{:#}", e),
+            }));
+        }
+    }
+}
+
+/// Coalesces a burst of changes into a single write: waits until none
+/// arrived for `DEBOUNCE`, but no longer than `MAX_DELAY`
+async fn settle(changed: &Notify) {
+    let deadline = tokio::time::Instant::now() + MAX_DELAY;
+    loop {
+        tokio::select! {
+            _ = changed.notified() => continue,
+            _ = tokio::time::sleep(DEBOUNCE) => break,
+            _ = tokio::time::sleep_until(deadline) => break,
+        }
+    }
+}
+
+async fn write_snapshot(ctx: &ServerContext) -> Result<()> {
+    let content = serde_json::to_string_pretty(&*ctx.data.read().await)?;
+    write_atomic(&ctx.data_dir, content.as_bytes()).await?;
+    info!("Saved data to {}", ctx.data_dir.join(DATA_FILE).display());
+    Ok(())
+}
+
+/// Replaces `data_dir/server-data.json` without ever leaving a truncated file behind
+pub async fn write_atomic(data_dir: &Path, content: &[u8]) -> Result<()> {
+    tokio::fs::create_dir_all(data_dir)
+        .await
+        .with_context(|| format!("Failed to create {}", data_dir.display()))?;
+
+    let target = data_dir.join(DATA_FILE);
+    let temp: PathBuf = data_dir.join(format!(".{}.tmp", DATA_FILE));
+
+    let mut file = tokio::fs::File::create(&temp)
+        .await
+        .with_context(|| format!("Failed to create {}", temp.display()))?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    // Keep the last known good file around in case this one turns out broken
+    if tokio::fs::try_exists(&target).await.unwrap_or(false) {
+        tokio::fs::copy(&target, data_dir.join(BACKUP_FILE))
+            .await
+            .context("Failed to back up previous data file")?;
+    }
+
+    tokio::fs::rename(&temp, &target)
+        .await
+        .with_context(|| format!("Failed to move {} into place", temp.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_once_changes_stop() {
+        let changed = Notify::new();
+        let start = Instant::now();
+        settle(&changed).await;
+        assert_eq!(start.elapsed(), DEBOUNCE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn steady_changes_do_not_delay_the_write_past_the_deadline() {
+        let changed = Arc::new(Notify::new());
+        let changes = changed.clone();
+        let writer = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(DEBOUNCE / 2).await;
+                changes.notify_one();
+            }
+        });
+        let start = Instant::now();
+        settle(&changed).await;
+        assert_eq!(start.elapsed(), MAX_DELAY);
+        writer.abort();
+    }
+}
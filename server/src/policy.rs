@@ -0,0 +1,113 @@
+//! The execution policy: which programs a server shared by several people
+//! lets workflows run.
+//!
+//! Command and Test nodes are checked by the program they start, the first
+//! word of a `command` or the `program`, and Script nodes by their shell.
+//! Denied patterns are regular expressions matched against the whole command
+//! or script. Nodes are checked before a build starts and again right before
+//! they run. This keeps honest mistakes off the server; it is not a sandbox,
+//! as an allowed program can still run anything it is told to.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExecutionPolicy {
+    /// Programs and shells nodes may start, by name; any if empty
+    pub allowed_programs: Vec<String>,
+    /// Regular expressions no command or script may match, such as
+    /// `rm\s+-rf\s+/(\s|$)` or `curl[^|]*\|\s*(ba)?sh`
+    pub denied_patterns: Vec<String>,
+    /// Refuse Script nodes, leaving stored actions and commands
+    pub forbid_scripts: bool,
+}
+
+impl ExecutionPolicy {
+    pub fn is_active(&self) -> bool {
+        !self.allowed_programs.is_empty() || !self.denied_patterns.is_empty() || self.forbid_scripts
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.denied().map(|_| ())
+    }
+
+    fn denied(&self) -> Result<Vec<(&str, Regex)>> {
+        self.denied_patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map(|regex| (pattern.as_str(), regex))
+                    .with_context(|| format!("Invalid denied pattern {}", pattern))
+            })
+            .collect()
+    }
+
+    /// Checks a command run by a shell
+    pub fn check_command(&self, command: &str) -> Result<()> {
+        self.check(first_program(command).unwrap_or_default(), command)
+    }
+
+    /// Checks a program started with `argv`, without a shell
+    pub fn check_argv(&self, argv: &[String]) -> Result<()> {
+        self.check(argv.first().map(String::as_str).unwrap_or_default(), &argv.join(" "))
+    }
+
+    pub fn check_script(&self, shell: &str, script: &str) -> Result<()> {
+        if self.forbid_scripts {
+            anyhow::bail!("The execution policy forbids script nodes; use a command node or a stored action");
+        }
+        self.check(shell, script)
+    }
+
+    fn check(&self, program: &str, text: &str) -> Result<()> {
+        if !self.allowed_programs.is_empty() {
+            let name = program_name(program);
+            if !self.allowed_programs.iter().any(|allowed| program_name(allowed) == name) {
+                anyhow::bail!(
+                    "The execution policy does not allow running {}; allowed are {}",
+                    if name.is_empty() { "nothing" } else { name.as_str() },
+                    self.allowed_programs.join(", ")
+                );
+            }
+        }
+        for (pattern, regex) in self.denied()? {
+            if regex.is_match(text) {
+                anyhow::bail!("The execution policy forbids what the node runs: it matches the denied pattern {}", pattern);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The program a shell command starts: its first word after any
+/// `NAME=value` assignments
+fn first_program(command: &str) -> Option<&str> {
+    command
+        .split_whitespace()
+        .find(|word| !is_assignment(word))
+        .map(|word| word.trim_matches(|c| c == '"' || c == '\''))
+}
+
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=')
+        .is_some_and(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+/// `program` without its directory, and without `.exe` on Windows, so
+/// `/usr/bin/cargo` and `cargo.exe` both count as `cargo`
+fn program_name(program: &str) -> String {
+    let name = Path::new(program)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let lower = name.to_ascii_lowercase();
+    match lower.strip_suffix(".exe") {
+        Some(stem) if cfg!(windows) => stem.to_string(),
+        _ if cfg!(windows) => lower,
+        _ => name,
+    }
+}
@@ -0,0 +1,322 @@
+//! Child processes spawned by build nodes.
+//!
+//! Each one gets its own process group so that cancelling a build takes down
+//! everything it started, not just the shell we spawned. On Windows the
+//! process tree is ended with `taskkill /T`.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+
+use crate::build_log::{BuildLog, Stream};
+use crate::builds::{BuildCancelled, CancelToken};
+
+/// Lines of stderr kept to explain a failure
+const STDERR_TAIL_LINES: usize = 20;
+
+pub struct Finished {
+    pub status: ExitStatus,
+    /// Last lines the process wrote to stderr
+    pub stderr_tail: String,
+}
+
+/// Error for a process that exited unsuccessfully
+#[derive(Debug, thiserror::Error)]
+#[error("{what} failed: {stderr_tail}")]
+pub struct ProcessFailed {
+    /// What was run, e.g. "Command" or "Script"
+    pub what: &'static str,
+    /// None if the process was killed by a signal
+    pub exit_code: Option<i32>,
+    pub stderr_tail: String,
+}
+
+impl Finished {
+    /// The exit code, or `ProcessFailed` if the process did not succeed
+    pub fn check(self, what: &'static str) -> Result<i32> {
+        if self.status.success() {
+            return Ok(self.status.code().unwrap_or(0));
+        }
+        Err(ProcessFailed {
+            what,
+            exit_code: self.status.code(),
+            stderr_tail: self.stderr_tail,
+        }
+        .into())
+    }
+}
+
+/// Applies the process-group and kill-on-drop settings every build process needs
+pub fn configure(command: &mut Command) -> &mut Command {
+    #[cfg(unix)]
+    command.process_group(0);
+    // CREATE_NEW_PROCESS_GROUP, so a Ctrl+C meant for the server stays there
+    #[cfg(windows)]
+    command.creation_flags(0x0000_0200);
+    command.kill_on_drop(true)
+}
+
+/// Streams the output of `child` into the build log line by line and waits
+/// for it to exit, killing its whole process group if the build is cancelled
+/// first. The child must have been spawned with piped stdout and stderr.
+pub async fn run(mut child: Child, cancel: &CancelToken, log: &BuildLog) -> Result<Finished> {
+    let pid = child.id();
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    // Whatever ends this function early, cancellation or the caller dropping
+    // it on a timeout, takes the whole process group down with it
+    let mut group = GroupGuard(pid);
+
+    let finished = async {
+        let (status, _, stderr_tail) = tokio::join!(
+            child.wait(),
+            forward_lines(stdout, Stream::Stdout, log, 0),
+            forward_lines(stderr, Stream::Stderr, log, STDERR_TAIL_LINES),
+        );
+        Ok(Finished {
+            status: status?,
+            stderr_tail: Vec::from(stderr_tail).join("\n"),
+        })
+    };
+
+    tokio::select! {
+        finished = finished => {
+            group.0 = None;
+            finished
+        }
+        _ = cancel.cancelled() => Err(BuildCancelled.into()),
+    }
+}
+
+/// Spawns `command` with its output going to the build log, and fails with
+/// `ProcessFailed` naming `what` unless it succeeds
+pub async fn run_tool(command: &mut Command, what: &'static str, cancel: &CancelToken, log: &BuildLog) -> Result<i32> {
+    let child = configure(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", what))?;
+    run(child, cancel, log).await?.check(what)
+}
+
+/// Runs `command` and returns what it wrote to stdout, which is kept out of
+/// the build log; stderr goes to the log as usual
+pub async fn capture(command: &mut Command, what: &'static str, cancel: &CancelToken, log: &BuildLog) -> Result<String> {
+    let mut child = configure(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", what))?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let mut group = GroupGuard(child.id());
+
+    let read = async move {
+        let mut output = String::new();
+        if let Some(mut stdout) = stdout {
+            stdout.read_to_string(&mut output).await?;
+        }
+        Ok::<_, std::io::Error>(output)
+    };
+    let finished = async {
+        let (status, output, stderr_tail) = tokio::join!(
+            child.wait(),
+            read,
+            forward_lines(stderr, Stream::Stderr, log, STDERR_TAIL_LINES),
+        );
+        let finished = Finished {
+            status: status?,
+            stderr_tail: Vec::from(stderr_tail).join("\n"),
+        };
+        Ok::<_, anyhow::Error>((finished, output?))
+    };
+
+    let (finished, output) = tokio::select! {
+        finished = finished => {
+            group.0 = None;
+            finished?
+        }
+        _ = cancel.cancelled() => return Err(BuildCancelled.into()),
+    };
+    finished.check(what)?;
+    Ok(output)
+}
+
+/// A file handed to a process, such as a config holding a credential,
+/// deleted when dropped
+pub struct TempFile(pub PathBuf);
+
+impl TempFile {
+    /// A unique path in the system temp directory; the file is not created
+    pub fn new(prefix: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("buildforge-{}-{}", prefix, uuid::Uuid::new_v4())))
+    }
+
+    /// Creates the file with `contents`, readable only by the server's user
+    pub async fn write_private(&self, contents: &str) -> Result<()> {
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&self.0).await?;
+        file.write_all(contents.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A scratch directory, removed with everything in it when dropped
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    /// A unique path in the system temp directory; the directory is not created
+    pub fn new(prefix: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("buildforge-{}-{}", prefix, uuid::Uuid::new_v4())))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        match std::fs::remove_dir_all(&self.0) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!("Failed to remove {}: {}", self.0.display(), e)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Kills the process group of a child that has not exited yet when dropped;
+/// set it to None once the child has exited
+pub struct GroupGuard(pub Option<u32>);
+
+impl Drop for GroupGuard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            kill_group(pid);
+        }
+    }
+}
+
+/// Copies lines from `pipe` into the log, returning the last `keep` of them
+async fn forward_lines<R: AsyncRead + Unpin>(
+    pipe: Option<R>,
+    stream: Stream,
+    log: &BuildLog,
+    keep: usize,
+) -> VecDeque<String> {
+    let mut kept = VecDeque::with_capacity(keep);
+    let Some(pipe) = pipe else {
+        return kept;
+    };
+
+    // Keeps reading past the node's output limit, or the process would block
+    // on a full pipe
+    let max_line_bytes = log.max_line_bytes();
+    let mut reader = BufReader::new(pipe);
+    while let Ok(Some(line)) = next_line(&mut reader, max_line_bytes).await {
+        log.output(stream, &line).await;
+        if keep > 0 {
+            if kept.len() == keep {
+                kept.pop_front();
+            }
+            kept.push_back(line);
+        }
+    }
+    kept
+}
+
+/// The next line of `reader` without its line ending, or None at the end.
+/// A line longer than `max` bytes loses its middle, dropped while it is
+/// read, so a process that never writes a newline cannot fill the server's
+/// memory.
+pub async fn next_line<R: AsyncBufRead + Unpin>(reader: &mut R, max: Option<usize>) -> std::io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let mut cut = 0;
+    let mut read_any = false;
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            break;
+        }
+        read_any = true;
+        let newline = buf.iter().position(|&b| b == b'\n');
+        let end = newline.unwrap_or(buf.len());
+        line.extend_from_slice(&buf[..end]);
+        reader.consume(newline.map_or(end, |i| i + 1));
+        if let Some(max) = max {
+            // Room for the next chunk before cutting again
+            if line.len() > max.saturating_mul(2).max(8192) {
+                cut += drop_middle(&mut line, max);
+            }
+        }
+        if newline.is_some() {
+            break;
+        }
+    }
+    if !read_any {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    let Some(max) = max.filter(|max| cut > 0 || line.len() > *max) else {
+        return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+    };
+    cut += drop_middle(&mut line, max);
+    let (head, tail) = line.split_at(max / 2);
+    Ok(Some(format!(
+        "{} ...[{} bytes cut]... {}",
+        String::from_utf8_lossy(head),
+        cut,
+        String::from_utf8_lossy(tail)
+    )))
+}
+
+/// Shortens `line` to `max` bytes, half from its start and half from its
+/// end, and returns how many bytes were dropped
+fn drop_middle(line: &mut Vec<u8>, max: usize) -> usize {
+    if line.len() <= max {
+        return 0;
+    }
+    let head = max / 2;
+    let dropped = line.len() - max;
+    line.drain(head..head + dropped);
+    dropped
+}
+
+#[cfg(unix)]
+fn kill_group(pid: u32) {
+    // The group id equals the pid of its leader, see `configure`
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+fn kill_group(pid: u32) {
+    // Ends the child and everything it started; kill_on_drop alone would
+    // leave the grandchildren running
+    let _ = std::process::Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+#[cfg(not(any(unix, windows)))]
+fn kill_group(_pid: u32) {
+    // kill_on_drop takes care of the direct child
+}
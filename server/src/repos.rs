@@ -0,0 +1,573 @@
+//! Working copies the server clones itself, so a headless build box does not
+//! need someone to log in and clone a repo before it can be built.
+//!
+//! Clones run in their own task. Their output is broadcast line by line as
+//! `CloneProgress`, followed by `RepoCloned` or `CloneFailed`. The GitHub
+//! token reaches git through a credential helper reading it from the
+//! environment, so it never ends up in `.git/config`, and it is redacted from
+//! everything that is logged or sent to clients.
+//!
+//! Before each build of a workflow with a repo, `sync` brings the working
+//! copy to the requested ref.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+use tracing::{error, info, warn};
+
+use crate::build_log::BuildLog;
+use crate::builds::CancelToken;
+use crate::{builds, process, CloneRepoRequest, ServerContext, ServerMessage, StoredRepo};
+
+/// Environment variable the credential helper reads the token from
+const TOKEN_ENV: &str = "BUILDFORGE_GIT_TOKEN";
+
+/// Answers git's credential requests with the token from `TOKEN_ENV`
+const CREDENTIAL_HELPER: &str =
+    "!f() { echo username=x-access-token; echo \"password=$BUILDFORGE_GIT_TOKEN\"; }; f";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloneProgressPayload {
+    pub clone_id: String,
+    pub line: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepoClonedPayload {
+    pub clone_id: String,
+    pub repo: StoredRepo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloneErrorKind {
+    /// No git binary on the server's PATH
+    GitNotFound,
+    /// The destination exists and is not an empty directory
+    DestinationNotEmpty,
+    /// The remote refused the credentials, or the repo is private and there are none
+    Auth,
+    /// Anything else git complained about
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloneFailedPayload {
+    pub clone_id: String,
+    pub kind: CloneErrorKind,
+    pub message: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+struct CloneError {
+    kind: CloneErrorKind,
+    message: String,
+}
+
+impl CloneError {
+    fn new(kind: CloneErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+/// Starts cloning in the background; progress and the outcome are broadcast
+pub fn start_clone(ctx: &Arc<ServerContext>, request: CloneRepoRequest) {
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        let clone_id = request.clone_id.clone();
+        let token = ctx.github_token.clone();
+        let message = match clone(&ctx, request, token.as_deref()).await {
+            Ok(repo) => {
+                info!("Cloned {} into {}", repo.id, repo.path);
+                ctx.broadcast(ServerMessage::RepoSaved(repo.clone()));
+                ServerMessage::RepoCloned(RepoClonedPayload { clone_id, repo })
+            }
+            Err(e) => {
+                let message = redact(&e.message, token.as_deref());
+                error!("Clone {} failed: {}", clone_id, message);
+                ServerMessage::CloneFailed(CloneFailedPayload {
+                    clone_id,
+                    kind: e.kind,
+                    message,
+                })
+            }
+        };
+        ctx.broadcast(message);
+    });
+}
+
+async fn clone(
+    ctx: &Arc<ServerContext>,
+    request: CloneRepoRequest,
+    token: Option<&str>,
+) -> Result<StoredRepo, CloneError> {
+    let git = which::which("git")
+        .map_err(|_| CloneError::new(CloneErrorKind::GitNotFound, "git is not installed on the server"))?;
+
+    let (owner, repo_name) = parse_remote(&request.url).unzip();
+    let dest = match &request.dest {
+        Some(dest) => ctx.workdir.join(dest),
+        None => {
+            let name = repo_name.clone().unwrap_or_else(|| "repo".to_string());
+            ctx.workdir.join(name)
+        }
+    };
+    if !is_empty_or_missing(&dest).await {
+        return Err(CloneError::new(
+            CloneErrorKind::DestinationNotEmpty,
+            format!("{} already exists and is not empty", dest.display()),
+        ));
+    }
+
+    let mut command = Command::new(&git);
+    with_credentials(&mut command, &request.url, token);
+    command.arg("clone").arg("--progress");
+    if let Some(branch) = &request.branch {
+        command.arg("--branch").arg(branch);
+    }
+    if request.shallow {
+        command.arg("--depth").arg("1");
+    }
+    command
+        .arg("--")
+        .arg(&request.url)
+        .arg(&dest)
+        // Fail instead of waiting for a password on a terminal nobody watches
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    info!("Cloning {} into {}", redact(&request.url, token), dest.display());
+    let mut child = command
+        .spawn()
+        .map_err(|e| CloneError::new(CloneErrorKind::Failed, format!("Failed to run git: {}", e)))?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let (status, _, stderr_lines) = tokio::join!(
+        child.wait(),
+        forward_progress(ctx, &request.clone_id, stdout, token),
+        forward_progress(ctx, &request.clone_id, stderr, token),
+    );
+    let status = status.map_err(|e| CloneError::new(CloneErrorKind::Failed, e.to_string()))?;
+
+    if !status.success() {
+        let output = stderr_lines.join("\n");
+        let kind = if is_auth_failure(&output) {
+            CloneErrorKind::Auth
+        } else {
+            CloneErrorKind::Failed
+        };
+        let last = stderr_lines.last().cloned().unwrap_or_else(|| format!("git exited with {}", status));
+        return Err(CloneError::new(kind, last));
+    }
+
+    let default_branch = match request.branch.clone() {
+        Some(branch) => branch,
+        None => current_branch(&git, &dest).await.unwrap_or_else(|e| {
+            warn!("Could not determine the branch of {}: {}", dest.display(), e);
+            "main".to_string()
+        }),
+    };
+    let path = dest.canonicalize().unwrap_or(dest).to_string_lossy().to_string();
+
+    // Cloning again into the same place refreshes the existing entry
+    let mut data = ctx.data.write().await;
+    let existing = data.repos.iter().find(|r| r.path == path);
+    let id = existing
+        .map(|r| r.id.clone())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let (api_base_url, upload_base_url, webhook_secret_env) = existing
+        .map(|r| (r.api_base_url.clone(), r.upload_base_url.clone(), r.webhook_secret_env.clone()))
+        .unwrap_or_default();
+    let repo = StoredRepo {
+        id,
+        path,
+        owner,
+        repo: repo_name,
+        default_branch,
+        cloned_at: Some(chrono::Utc::now().to_rfc3339()),
+        api_base_url,
+        upload_base_url,
+        webhook_secret_env,
+    };
+    match data.repos.iter_mut().find(|r| r.id == repo.id) {
+        Some(existing) => *existing = repo.clone(),
+        None => data.repos.push(repo.clone()),
+    }
+    ctx.persistence.mark_dirty();
+    Ok(repo)
+}
+
+/// Broadcasts every line git writes to `pipe`, returning them all. Progress
+/// lines end in `\r`, so both that and `\n` end a line.
+async fn forward_progress<R: AsyncRead + Unpin>(
+    ctx: &ServerContext,
+    clone_id: &str,
+    pipe: Option<R>,
+    token: Option<&str>,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    let Some(mut pipe) = pipe else {
+        return lines;
+    };
+
+    let mut pending: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let read = match pipe.read(&mut buf).await {
+            Ok(0) | Err(_) => 0,
+            Ok(n) => n,
+        };
+        let chunk = &buf[..read];
+        for &byte in chunk {
+            if byte == b'\n' || byte == b'\r' {
+                if !pending.is_empty() {
+                    lines.push(emit(ctx, clone_id, &pending, token));
+                    pending.clear();
+                }
+            } else {
+                pending.push(byte);
+            }
+        }
+        if read == 0 {
+            break;
+        }
+    }
+    if !pending.is_empty() {
+        lines.push(emit(ctx, clone_id, &pending, token));
+    }
+    lines
+}
+
+fn emit(ctx: &ServerContext, clone_id: &str, line: &[u8], token: Option<&str>) -> String {
+    let line = redact(&String::from_utf8_lossy(line), token);
+    ctx.broadcast(ServerMessage::CloneProgress(CloneProgressPayload {
+        clone_id: clone_id.to_string(),
+        line: line.clone(),
+    }));
+    line
+}
+
+/// Replaces every occurrence of the token
+pub fn redact(text: &str, token: Option<&str>) -> String {
+    match token {
+        Some(token) if !token.is_empty() => text.replace(token, "***"),
+        _ => text.to_string(),
+    }
+}
+
+/// Lets git authenticate to GitHub over HTTPS with `token`. Must come before
+/// the git subcommand. Other remotes never see the token.
+fn with_credentials(command: &mut Command, url: &str, token: Option<&str>) {
+    if let Some(token) = token.filter(|_| is_github_https(url)) {
+        command
+            .arg("-c")
+            .arg("credential.helper=")
+            .arg("-c")
+            .arg(format!("credential.helper={}", CREDENTIAL_HELPER))
+            .env(TOKEN_ENV, token);
+    }
+}
+
+fn is_github_https(url: &str) -> bool {
+    url.starts_with("https://github.com/")
+}
+
+fn is_auth_failure(output: &str) -> bool {
+    const MARKERS: [&str; 4] = [
+        "Authentication failed",
+        "could not read Username",
+        "Permission denied",
+        // What GitHub answers for private repos without valid credentials
+        "Repository not found",
+    ];
+    MARKERS.iter().any(|m| output.contains(m))
+}
+
+/// Owner and name of a repo from an HTTPS or SSH remote URL, such as
+/// `https://github.com/owner/repo.git`, `ssh://git@host:22/owner/repo` or
+/// `git@github.com:owner/repo.git`
+pub fn parse_remote(url: &str) -> Option<(String, String)> {
+    let url = url.trim().trim_end_matches('/');
+    let path = match url.split_once("://") {
+        // Drops the host along with any credentials or port
+        Some((_, rest)) => rest.split_once('/')?.1,
+        // scp-like syntax, [user@]host:owner/repo
+        None => url.split_once(':')?.1,
+    };
+    let (owner, name) = path.trim_end_matches(".git").rsplit_once('/')?;
+    let owner = owner.rsplit('/').next()?;
+    if owner.is_empty() || name.is_empty() {
+        return None;
+    }
+    Some((owner.to_string(), name.to_string()))
+}
+
+/// URL of the `origin` remote of the git working copy at `dir`
+pub async fn origin_url(dir: &Path) -> Option<String> {
+    let git = which::which("git").ok()?;
+    let output = git_output(&git, dir, &["remote", "get-url", "origin"]).await.ok()?;
+    let url = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !url.is_empty()).then_some(url)
+}
+
+async fn is_empty_or_missing(path: &Path) -> bool {
+    match tokio::fs::read_dir(path).await {
+        Ok(mut entries) => matches!(entries.next_entry().await, Ok(None)),
+        Err(e) => e.kind() == std::io::ErrorKind::NotFound,
+    }
+}
+
+async fn current_branch(git: &Path, dir: &Path) -> Result<String> {
+    let output = Command::new(git)
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--abbrev-ref", "HEAD"])
+        .output()
+        .await?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Output of a quick git query in `dir`
+async fn git_output(git: &Path, dir: &Path, args: &[&str]) -> Result<std::process::Output> {
+    Ok(Command::new(git).arg("-C").arg(dir).args(args).output().await?)
+}
+
+/// Runs a git command in `dir` as a build step, its output going to the build log
+async fn git_step(
+    git: &Path,
+    dir: &Path,
+    remote: &str,
+    token: Option<&str>,
+    args: &[&str],
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<()> {
+    log.line(&format!("[git] git {}", args.join(" "))).await;
+    let mut command = Command::new(git);
+    with_credentials(&mut command, remote, token);
+    let child = process::configure(command.args(args))
+        .current_dir(dir)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    process::run(child, cancel, log).await?.check("Git")?;
+    Ok(())
+}
+
+/// Brings the working copy of `repo` to `git_ref`, or its default branch:
+/// fetch, checkout and, on a branch, a fast-forward pull. Uncommitted changes
+/// fail the build unless `force_clean` is set, in which case they are
+/// stashed. Returns the commit that was checked out.
+pub async fn sync(
+    repo: &StoredRepo,
+    git_ref: Option<&str>,
+    force_clean: bool,
+    token: Option<&str>,
+    build_id: &str,
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<String> {
+    let git = which::which("git").map_err(|_| anyhow::anyhow!("git is not installed on the server"))?;
+    let dir = Path::new(&repo.path);
+    let target = git_ref.unwrap_or(&repo.default_branch);
+    log.line(&format!("[git] Syncing {} to {}", repo.path, target)).await;
+
+    let status = git_output(&git, dir, &["status", "--porcelain"]).await?;
+    if !status.status.success() {
+        anyhow::bail!("{} is not a git working copy: {}", repo.path, String::from_utf8_lossy(&status.stderr).trim());
+    }
+    if !status.stdout.is_empty() {
+        if !force_clean {
+            anyhow::bail!(
+                "The working tree of {} has uncommitted changes; commit or stash them, or start the build with force_clean",
+                repo.path
+            );
+        }
+        let message = format!("buildforge: before build {}", build_id);
+        git_step(&git, dir, "", None, &["stash", "push", "--include-untracked", "-m", &message], cancel, log).await?;
+    }
+
+    let remote = git_output(&git, dir, &["remote", "get-url", "origin"]).await?;
+    let remote = String::from_utf8_lossy(&remote.stdout).trim().to_string();
+    git_step(&git, dir, &remote, token, &["fetch", "--prune", "origin"], cancel, log).await?;
+    git_step(&git, dir, "", None, &["checkout", target], cancel, log).await?;
+
+    // Tags and commits leave HEAD detached, there is nothing to pull then
+    let on_branch = git_output(&git, dir, &["symbolic-ref", "-q", "HEAD"]).await?.status.success();
+    if on_branch {
+        git_step(&git, dir, &remote, token, &["pull", "--ff-only"], cancel, log).await?;
+    }
+
+    let head = git_output(&git, dir, &["rev-parse", "HEAD"]).await?;
+    if !head.status.success() {
+        anyhow::bail!("Could not resolve HEAD of {}", repo.path);
+    }
+    let sha = String::from_utf8_lossy(&head.stdout).trim().to_string();
+    log.line(&format!("[git] Building commit {}", sha)).await;
+    Ok(sha)
+}
+
+/// Commits `paths` in the working copy at `dir` with the server's git identity
+pub async fn commit(dir: &Path, paths: &[PathBuf], message: &str, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    let git = which::which("git").map_err(|_| anyhow::anyhow!("git is not installed on the server"))?;
+    let mut add = vec!["add", "--"];
+    add.extend(paths.iter().filter_map(|p| p.to_str()));
+    git_step(&git, dir, "", None, &add, cancel, log).await?;
+    git_step(&git, dir, "", None, &["commit", "-m", message], cancel, log).await
+}
+
+/// What `tag_and_push` does when the tag already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IfTagExists {
+    Fail,
+    /// Keeps the existing tag, which is still pushed
+    Skip,
+    /// Moves the tag to HEAD, locally and on origin
+    Force,
+}
+
+impl IfTagExists {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "fail" => Some(Self::Fail),
+            "skip" => Some(Self::Skip),
+            "force" => Some(Self::Force),
+            _ => None,
+        }
+    }
+}
+
+pub struct TagRequest<'a> {
+    pub tag: &'a str,
+    pub message: &'a str,
+    /// Sign with the server's git configuration (`user.signingkey`)
+    pub sign: bool,
+    pub if_exists: IfTagExists,
+    /// Also push the branch HEAD is on
+    pub push_branch: bool,
+}
+
+/// Creates an annotated tag at HEAD of the working copy at `dir` and pushes
+/// it to origin. The token only reaches git through the credential helper,
+/// never a command line or URL, so it does not show up in the log.
+pub async fn tag_and_push(
+    dir: &Path,
+    request: &TagRequest<'_>,
+    token: Option<&str>,
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<()> {
+    let git = which::which("git").map_err(|_| anyhow::anyhow!("git is not installed on the server"))?;
+    let tag_ref = format!("refs/tags/{}", request.tag);
+    let exists = git_output(&git, dir, &["rev-parse", "-q", "--verify", &tag_ref]).await?.status.success();
+    let force = exists && request.if_exists == IfTagExists::Force;
+
+    match (exists, request.if_exists) {
+        (true, IfTagExists::Fail) => anyhow::bail!("Tag {} already exists", request.tag),
+        (true, IfTagExists::Skip) => {
+            log.line(&format!("[git] Tag {} already exists, keeping it", request.tag)).await;
+        }
+        _ => {
+            let mut args = vec!["tag", if request.sign { "-s" } else { "-a" }];
+            if force {
+                args.push("-f");
+            }
+            args.extend([request.tag, "-m", request.message]);
+            git_step(&git, dir, "", None, &args, cancel, log).await?;
+        }
+    }
+
+    let remote = git_output(&git, dir, &["remote", "get-url", "origin"]).await?;
+    if !remote.status.success() {
+        anyhow::bail!("{} has no origin remote to push to", dir.display());
+    }
+    let remote = String::from_utf8_lossy(&remote.stdout).trim().to_string();
+    let mut push = vec!["push", "origin", tag_ref.as_str()];
+    if force {
+        push.push("--force");
+    }
+    git_step(&git, dir, &remote, token, &push, cancel, log).await?;
+
+    if request.push_branch {
+        let branch = git_output(&git, dir, &["symbolic-ref", "--short", "-q", "HEAD"]).await?;
+        if !branch.status.success() {
+            anyhow::bail!("HEAD is detached, there is no branch to push");
+        }
+        let branch = String::from_utf8_lossy(&branch.stdout).trim().to_string();
+        git_step(&git, dir, &remote, token, &["push", "origin", &branch], cancel, log).await?;
+    }
+    Ok(())
+}
+
+/// Removes a repo entry, returning it and the workflows that still use it
+pub async fn delete(ctx: &ServerContext, id: &str) -> (Option<StoredRepo>, Vec<String>) {
+    let mut data = ctx.data.write().await;
+    let removed = data
+        .repos
+        .iter()
+        .position(|r| r.id == id)
+        .map(|index| data.repos.remove(index));
+    let affected_workflows: Vec<String> = data
+        .workflows
+        .iter()
+        .filter(|w| w.repo_id.as_deref() == Some(id))
+        .map(|w| w.id.clone())
+        .collect();
+    if !affected_workflows.is_empty() {
+        warn!("Workflows still using deleted repo {}: {}", id, affected_workflows.join(", "));
+    }
+    ctx.persistence.mark_dirty();
+    (removed, affected_workflows)
+}
+
+/// Deletes the working copy of a repo the server cloned. Repos registered
+/// with SaveRepo point at directories someone else owns and are never touched.
+pub async fn remove_working_copy(ctx: &ServerContext, repo: &StoredRepo) -> Result<()> {
+    if repo.cloned_at.is_none() {
+        anyhow::bail!("{} was not cloned by the server, its files are left alone", repo.path);
+    }
+    let path = PathBuf::from(&repo.path);
+    let workdir = ctx.workdir.canonicalize()?;
+    if !path.starts_with(&workdir) || path == workdir {
+        anyhow::bail!("{} is outside the working directory, its files are left alone", repo.path);
+    }
+    info!("Removing working copy {}", path.display());
+    tokio::fs::remove_dir_all(&path).await?;
+    Ok(())
+}
+
+/// Whether a build of a workflow using `repo_id` is running
+pub async fn in_use(ctx: &ServerContext, repo_id: &str) -> bool {
+    let workflows: Vec<String> = ctx
+        .data
+        .read()
+        .await
+        .workflows
+        .iter()
+        .filter(|w| w.repo_id.as_deref() == Some(repo_id))
+        .map(|w| w.id.clone())
+        .collect();
+    for workflow_id in workflows {
+        if builds::workflow_running(ctx, &workflow_id).await {
+            return true;
+        }
+    }
+    false
+}
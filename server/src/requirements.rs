@@ -0,0 +1,176 @@
+//! Tool requirements such as `node >= 18.17`, `cargo` or `python3 < 3.13`,
+//! checked by the `require` node and, before any node runs, by a workflow's
+//! `requires`.
+//!
+//! A version is the first run of dot-separated numbers in the tool's
+//! `--version` output, so `v18.17.1`, `go1.21.3` and `1.75.0-nightly` all
+//! read as expected. Each constraint compares only as many parts as it
+//! gives, so `< 3.13` refuses 3.13.1 and `<= 3.12` accepts 3.12.4.
+
+use std::cmp::Ordering;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::environment;
+
+/// A requirement as written in a workflow: the text, or the text with a
+/// hint of what to install when it is not met
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Spec {
+    Text(String),
+    Full {
+        requirement: String,
+        /// Package that provides the tool, shown when the requirement is not met
+        #[serde(default)]
+        install_hint: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+pub struct Requirement {
+    pub tool: String,
+    constraints: Vec<(Op, Vec<u64>)>,
+    pub install_hint: Option<String>,
+    /// As written, for messages
+    text: String,
+}
+
+impl Requirement {
+    pub fn parse(spec: &Spec) -> Result<Self> {
+        let (text, install_hint) = match spec {
+            Spec::Text(text) => (text.trim(), None),
+            Spec::Full { requirement, install_hint } => (requirement.trim(), install_hint.clone()),
+        };
+        let split = text.find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '=')).unwrap_or(text.len());
+        let (tool, rest) = text.split_at(split);
+        if tool.is_empty() {
+            anyhow::bail!("The requirement {:?} names no tool", text);
+        }
+        let mut constraints = Vec::new();
+        for part in rest.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (op, version) = [(">=", Op::Ge), ("<=", Op::Le), ("==", Op::Eq), (">", Op::Gt), ("<", Op::Lt), ("=", Op::Eq)]
+                .into_iter()
+                .find_map(|(symbol, op)| part.strip_prefix(symbol).map(|v| (op, v)))
+                .with_context(|| format!("Expected >=, >, <=, < or = before the version in {:?}", text))?;
+            let version = parse_version(version.trim())
+                .with_context(|| format!("{:?} in the requirement {:?} is not a version", version.trim(), text))?;
+            constraints.push((op, version));
+        }
+        Ok(Self {
+            tool: tool.to_string(),
+            constraints,
+            install_hint,
+            text: text.to_string(),
+        })
+    }
+
+    pub fn is_met_by(&self, version: &[u64]) -> bool {
+        self.constraints.iter().all(|(op, wanted)| {
+            // Missing parts count as 0, so 18 reads as 18.0
+            let found: Vec<u64> = (0..wanted.len()).map(|i| version.get(i).copied().unwrap_or(0)).collect();
+            let ordering = found.cmp(wanted);
+            match op {
+                Op::Eq => ordering == Ordering::Equal,
+                Op::Lt => ordering == Ordering::Less,
+                Op::Le => ordering != Ordering::Greater,
+                Op::Gt => ordering == Ordering::Greater,
+                Op::Ge => ordering != Ordering::Less,
+            }
+        })
+    }
+}
+
+/// The first version number in `text`, such as the output of `--version`
+pub fn parse_version(text: &str) -> Option<Vec<u64>> {
+    static VERSION: OnceLock<Regex> = OnceLock::new();
+    let regex = VERSION.get_or_init(|| Regex::new(r"\d+(\.\d+)*").expect("valid version pattern"));
+    regex
+        .find(text)?
+        .as_str()
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+fn format_version(version: &[u64]) -> String {
+    version.iter().map(u64::to_string).collect::<Vec<_>>().join(".")
+}
+
+/// What checking the requirements found
+#[derive(Default)]
+pub struct Report {
+    /// The version of each tool, for the environment snapshot
+    pub versions: Vec<(String, String)>,
+    /// One line per unmet requirement
+    pub unmet: Vec<String>,
+}
+
+impl Report {
+    /// The unmet requirements as one error, if there are any
+    pub fn check(&self) -> Result<()> {
+        if self.unmet.is_empty() {
+            return Ok(());
+        }
+        anyhow::bail!("Unmet tool requirements:\n{}", self.unmet.join("\n"))
+    }
+}
+
+/// Finds the version of each required tool, probing each tool once
+pub async fn check(requirements: &[Requirement]) -> Report {
+    let mut report = Report::default();
+    let mut tools: Vec<&str> = requirements.iter().map(|r| r.tool.as_str()).collect();
+    tools.sort();
+    tools.dedup();
+    let outputs = futures_util::future::join_all(tools.iter().map(|tool| {
+        // Tools the snapshot knows may need other arguments, e.g. `go version`
+        match environment::TOOLS.iter().find(|t| t.name == *tool || t.program == *tool) {
+            Some(known) => environment::probe_program(known.program, known.args),
+            None => environment::probe_program(tool, &["--version"]),
+        }
+    }))
+    .await;
+
+    for (tool, output) in tools.iter().zip(outputs) {
+        let version = match &output {
+            Ok(line) => parse_version(line),
+            Err(_) => None,
+        };
+        report.versions.push((
+            tool.to_string(),
+            match (&version, &output) {
+                (Some(version), _) => format_version(version),
+                (None, Ok(line)) | (None, Err(line)) => line.clone(),
+            },
+        ));
+        for requirement in requirements.iter().filter(|r| r.tool == *tool) {
+            let problem = match (&version, &output) {
+                (_, Err(why)) => Some(why.clone()),
+                (None, Ok(_)) if !requirement.constraints.is_empty() => Some("no version in its --version output".to_string()),
+                (Some(version), _) if !requirement.is_met_by(version) => Some(format!("found {}", format_version(version))),
+                _ => None,
+            };
+            if let Some(problem) = problem {
+                let hint = requirement
+                    .install_hint
+                    .as_ref()
+                    .map(|hint| format!("; install {}", hint))
+                    .unwrap_or_default();
+                report.unmet.push(format!("  {}: {}{}", requirement.text, problem, hint));
+            }
+        }
+    }
+    report
+}
@@ -0,0 +1,75 @@
+//! Pruning of old build history, together with the logs and artifacts the
+//! pruned builds left on disk, and of old action runs.
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{BuildRecord, ServerContext};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Builds kept per workflow, and runs per action, newest first
+    pub max_history_per_workflow: Option<usize>,
+    /// Builds and action runs older than this are removed
+    pub max_history_age_days: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_history_per_workflow.is_none() && self.max_history_age_days.is_none()
+    }
+}
+
+/// Applies the server's retention policy to the build history
+pub async fn apply(ctx: &ServerContext) {
+    let policy = ctx.settings().retention;
+    if policy.is_unlimited() {
+        return;
+    }
+
+    let pruned = match ctx.history.prune(policy).await {
+        Ok(pruned) => pruned,
+        Err(e) => {
+            error!("Failed to prune build history: {:#}", e);
+            return;
+        }
+    };
+
+    for record in &pruned {
+        info!(
+            "Pruned build {} of workflow {} from {}",
+            record.id, record.workflow_id, record.started_at
+        );
+        remove_build_files(ctx, record).await;
+    }
+    if !pruned.is_empty() {
+        info!("Pruned {} builds from history", pruned.len());
+    }
+
+    match ctx.history.prune_action_runs(policy).await {
+        Ok(0) => {}
+        Ok(count) => info!("Pruned {} action runs from history", count),
+        Err(e) => error!("Failed to prune action history: {:#}", e),
+    }
+}
+
+/// Deletes the log and retained artifacts of a build removed from history
+pub async fn remove_build_files(ctx: &ServerContext, record: &BuildRecord) {
+    if let Some(log_file) = &record.log_file {
+        remove_file(log_file).await;
+    }
+    let artifacts = ctx.data_dir.join("artifacts").join(&record.id);
+    if let Err(e) = tokio::fs::remove_dir_all(&artifacts).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove {}: {}", artifacts.display(), e);
+        }
+    }
+}
+
+async fn remove_file(path: &str) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove {}: {}", path, e);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Versioning of `server-data.json`.
+//!
+//! Files are upgraded one version at a time before they are deserialized, and
+//! the original is copied to `server-data.v<N>.json.bak` first. A file written
+//! by a newer server is refused instead of being loaded with its unknown
+//! contents dropped.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::BuildRecord;
+
+/// Schema version written by this server
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Returned when the data file comes from a newer server
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "{path} has schema version {found}, but this server only understands versions up to {supported}. \
+     Upgrade the BuildForge server; nothing has been changed."
+)]
+pub struct SchemaTooNew {
+    pub path: String,
+    pub found: u32,
+    pub supported: u32,
+}
+
+/// Result of upgrading a data file
+pub struct Migrated {
+    /// Version the file had on disk
+    pub from: u32,
+    /// Build history that older files kept inline, to be moved into `history.db`
+    pub history: Vec<BuildRecord>,
+}
+
+/// Upgrades the parsed contents of `path` to [`CURRENT_VERSION`] in place,
+/// keeping a copy of the original if anything changes
+pub fn migrate_file(value: &mut Value, path: &Path) -> Result<Migrated> {
+    let from = version_of(value);
+    if from < CURRENT_VERSION {
+        let backup = path.with_file_name(format!("server-data.v{}.json.bak", from));
+        std::fs::write(&backup, serde_json::to_vec_pretty(value)?)
+            .with_context(|| format!("Failed to back up {} before migrating it", path.display()))?;
+    }
+    upgrade(value, &path.display().to_string())
+}
+
+/// Upgrades server data from `source` (a path or other description used in
+/// messages) to [`CURRENT_VERSION`] in place
+pub fn upgrade(value: &mut Value, source: &str) -> Result<Migrated> {
+    let from = version_of(value);
+    if from > CURRENT_VERSION {
+        return Err(SchemaTooNew {
+            path: source.to_string(),
+            found: from,
+            supported: CURRENT_VERSION,
+        }
+        .into());
+    }
+
+    let mut migrated = Migrated { from, history: Vec::new() };
+    for version in from..CURRENT_VERSION {
+        match version {
+            0 => v0_to_v1(value),
+            1 => migrated.history = v1_to_v2(value)?,
+            _ => unreachable!("no migration from schema version {}", version),
+        }
+        value["schema_version"] = json!(version + 1);
+        info!("Migrated {} from schema version {} to {}", source, version, version + 1);
+    }
+    Ok(migrated)
+}
+
+pub fn version_of(value: &Value) -> u32 {
+    value
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .map(|v| v as u32)
+        .unwrap_or(0)
+}
+
+/// Fills in collections and timestamps that early versions did not always write
+fn v0_to_v1(value: &mut Value) {
+    let now = chrono::Utc::now().to_rfc3339();
+    for key in ["workflows", "actions", "repos"] {
+        if !value.get(key).is_some_and(Value::is_array) {
+            value[key] = json!([]);
+        }
+    }
+    for key in ["workflows", "actions"] {
+        let Some(items) = value[key].as_array_mut() else {
+            continue;
+        };
+        for item in items.iter_mut().filter_map(Value::as_object_mut) {
+            let created_at = item
+                .get("created_at")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .unwrap_or_else(|| now.clone());
+            item.entry("created_at").or_insert_with(|| json!(created_at));
+            item.entry("updated_at").or_insert_with(|| json!(created_at));
+        }
+    }
+}
+
+/// Takes the inline build history out of the file; it now lives in SQLite
+fn v1_to_v2(value: &mut Value) -> Result<Vec<BuildRecord>> {
+    let history = match value.as_object_mut().and_then(|o| o.remove("build_history")) {
+        Some(history) => serde_json::from_value(history).context("Failed to read build_history")?,
+        None => Vec::new(),
+    };
+    Ok(history)
+}
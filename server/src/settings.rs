@@ -0,0 +1,192 @@
+//! Runtime settings, changed with `SetSettings` and persisted in
+//! `server-data.json`. The command-line flags only provide the initial values
+//! until settings have been saved once.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ansi::AnsiMode;
+use crate::limits::ResourceLimits;
+use crate::policy::ExecutionPolicy;
+use crate::retention::RetentionPolicy;
+use crate::Args;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    /// Port to listen on; changes take effect after a restart
+    pub port: u16,
+    /// Shell for script nodes that do not name one
+    pub default_shell: String,
+    /// Time limit for nodes without their own `timeout_secs`; none if unset
+    pub default_node_timeout_secs: Option<u64>,
+    /// Builds running at once across all workflows; unlimited if unset
+    pub max_concurrent_builds: Option<usize>,
+    /// Applies to connections opened after the change
+    pub heartbeat_interval_secs: u64,
+    pub heartbeat_timeout_secs: u64,
+    pub retention: RetentionPolicy,
+    pub shutdown_grace_period_secs: u64,
+    /// Run each build in its own workspace, see `workspace`
+    pub isolated_workspaces: bool,
+    /// Leave the workspace of a failed build on disk
+    pub keep_workspace_on_failure: bool,
+    /// Kept workspaces, retained artifacts and build tool directories older
+    /// than this are removed, see `cleanup`; kept forever if unset
+    pub workspace_retention_days: Option<u64>,
+    /// GitHub API used by release nodes, github.com if unset
+    pub github_api_url: Option<String>,
+    /// Host release assets are uploaded to; derived from `github_api_url` if unset
+    pub github_upload_url: Option<String>,
+    /// Total size of the dependency cache before the least recently used
+    /// entries are evicted; unlimited if unset
+    pub cache_max_size_mb: Option<u64>,
+    /// Cache entries unused for longer than this are evicted
+    pub cache_max_age_days: Option<u64>,
+    /// Output a node may write before the rest is dropped, unless it sets
+    /// its own `max_output_mb`; unlimited if unset
+    pub max_node_output_mb: Option<u64>,
+    /// Longer lines of output are cut in the middle, unless the node sets
+    /// its own `max_line_bytes`; unlimited if unset
+    pub max_log_line_bytes: Option<usize>,
+    /// Escape sequences in build output, unless a build asks otherwise
+    pub ansi_mode: AnsiMode,
+    /// Limits for the processes of nodes that set none of their own
+    pub resource_limits: ResourceLimits,
+    /// What workflows may run; anything if left empty
+    pub execution_policy: ExecutionPolicy,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            port: 9876,
+            default_shell: crate::shell::DEFAULT.to_string(),
+            default_node_timeout_secs: None,
+            max_concurrent_builds: None,
+            heartbeat_interval_secs: 30,
+            heartbeat_timeout_secs: 90,
+            retention: RetentionPolicy::default(),
+            shutdown_grace_period_secs: 30,
+            isolated_workspaces: false,
+            keep_workspace_on_failure: false,
+            workspace_retention_days: Some(7),
+            github_api_url: None,
+            github_upload_url: None,
+            cache_max_size_mb: Some(10 * 1024),
+            cache_max_age_days: Some(30),
+            max_node_output_mb: Some(crate::build_log::DEFAULT_MAX_OUTPUT_BYTES / (1024 * 1024)),
+            max_log_line_bytes: Some(crate::build_log::DEFAULT_MAX_LINE_BYTES),
+            ansi_mode: AnsiMode::default(),
+            resource_limits: ResourceLimits::default(),
+            execution_policy: ExecutionPolicy::default(),
+        }
+    }
+}
+
+impl ServerSettings {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            port: args.port,
+            default_shell: args.default_shell.clone(),
+            default_node_timeout_secs: args.default_node_timeout,
+            max_concurrent_builds: args.max_concurrent_builds,
+            heartbeat_interval_secs: args.heartbeat_interval,
+            heartbeat_timeout_secs: args.heartbeat_timeout,
+            retention: RetentionPolicy {
+                max_history_per_workflow: args.max_history_per_workflow,
+                max_history_age_days: args.max_history_age_days,
+            },
+            shutdown_grace_period_secs: args.shutdown_grace_period,
+            isolated_workspaces: args.isolated_workspaces,
+            keep_workspace_on_failure: args.keep_workspace_on_failure,
+            workspace_retention_days: args.workspace_retention_days,
+            github_api_url: args.github_api_url.clone(),
+            github_upload_url: args.github_upload_url.clone(),
+            cache_max_size_mb: args.cache_max_size_mb,
+            cache_max_age_days: args.cache_max_age_days,
+            max_node_output_mb: args.max_node_output_mb,
+            max_log_line_bytes: args.max_log_line_bytes,
+            ansi_mode: args.ansi_mode,
+            resource_limits: ResourceLimits {
+                nice: args.node_nice,
+                cpu_limit: args.node_cpu_limit,
+                memory_limit_mb: args.node_memory_limit_mb,
+            },
+            execution_policy: ExecutionPolicy {
+                allowed_programs: args.allow_program.clone(),
+                denied_patterns: args.deny_pattern.clone(),
+                forbid_scripts: args.forbid_scripts,
+            },
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.port == 0 {
+            anyhow::bail!("port must be between 1 and 65535");
+        }
+        if self.default_shell.trim().is_empty() {
+            anyhow::bail!("default_shell must not be empty");
+        }
+        if self.default_node_timeout_secs == Some(0) {
+            anyhow::bail!("default_node_timeout_secs must be at least 1");
+        }
+        if self.max_concurrent_builds == Some(0) {
+            anyhow::bail!("max_concurrent_builds must be at least 1");
+        }
+        if self.heartbeat_interval_secs == 0 {
+            anyhow::bail!("heartbeat_interval_secs must be at least 1");
+        }
+        if self.heartbeat_timeout_secs <= self.heartbeat_interval_secs {
+            anyhow::bail!("heartbeat_timeout_secs must be longer than heartbeat_interval_secs");
+        }
+        if self.cache_max_size_mb == Some(0) {
+            anyhow::bail!("cache_max_size_mb must be at least 1");
+        }
+        if self.max_node_output_mb == Some(0) {
+            anyhow::bail!("max_node_output_mb must be at least 1");
+        }
+        if self.max_log_line_bytes.is_some_and(|bytes| bytes < 80) {
+            anyhow::bail!("max_log_line_bytes must be at least 80");
+        }
+        self.resource_limits.validate().context("resource_limits")?;
+        self.execution_policy.validate().context("execution_policy")?;
+        if self.retention.max_history_per_workflow == Some(0) {
+            anyhow::bail!("retention.max_history_per_workflow must be at least 1");
+        }
+        if self.workspace_retention_days == Some(0) {
+            anyhow::bail!("workspace_retention_days must be at least 1");
+        }
+        for (field, url) in [
+            ("github_api_url", &self.github_api_url),
+            ("github_upload_url", &self.github_upload_url),
+        ] {
+            if let Some(url) = url {
+                if !(url.starts_with("https://") || url.starts_with("http://")) {
+                    anyhow::bail!("{} must be an http(s) URL", field);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Settings that differ from what the running server was started with and
+    /// cannot be applied without a restart
+    pub fn restart_required(&self, port: u16) -> Vec<String> {
+        let mut fields = Vec::new();
+        if self.port != port {
+            fields.push("port".to_string());
+        }
+        fields
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs.max(1))
+    }
+
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_timeout_secs)
+    }
+}
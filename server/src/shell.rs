@@ -0,0 +1,130 @@
+//! Shells that run the commands and scripts of build nodes.
+//!
+//! Unix servers run commands with `sh -c` and scripts with `bash` unless a
+//! node names another shell. Windows servers use `cmd /C` and PowerShell
+//! instead. Script files get the extension their shell expects and, for the
+//! Windows shells, CRLF line endings.
+
+use std::path::Path;
+
+/// Shell for script nodes on a server whose settings name none
+pub const DEFAULT: &str = if cfg!(windows) { "powershell" } else { "bash" };
+
+/// Shells offered to the editor in the capability handshake
+pub const SUPPORTED: [&str; 6] = ["bash", "sh", "zsh", "pwsh", "powershell", "cmd"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shell {
+    /// A POSIX shell: `bash`, `sh`, `zsh` or a path to one
+    Posix(String),
+    /// `pwsh` or Windows PowerShell
+    Pwsh(String),
+    Cmd,
+    /// Any other interpreter, given the script file as its argument
+    Other(String),
+}
+
+impl Shell {
+    pub fn parse(name: &str) -> Self {
+        let base = Path::new(name)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match base.as_str() {
+            "bash" | "sh" | "zsh" | "dash" | "ksh" => Self::Posix(name.to_string()),
+            "pwsh" | "powershell" => Self::Pwsh(name.to_string()),
+            "cmd" => Self::Cmd,
+            _ => Self::Other(name.to_string()),
+        }
+    }
+
+    /// What command nodes run in unless they name a shell
+    pub fn for_commands() -> Self {
+        if cfg!(windows) {
+            Self::Cmd
+        } else {
+            Self::Posix("sh".to_string())
+        }
+    }
+
+    /// Arguments that run `command`
+    pub fn command_argv(&self, command: &str) -> Vec<String> {
+        match self {
+            Self::Posix(shell) | Self::Other(shell) => vec![shell.clone(), "-c".to_string(), command.to_string()],
+            Self::Pwsh(shell) => vec![
+                shell.clone(),
+                "-NoProfile".to_string(),
+                "-NonInteractive".to_string(),
+                "-Command".to_string(),
+                command.to_string(),
+            ],
+            Self::Cmd => vec!["cmd".to_string(), "/C".to_string(), command.to_string()],
+        }
+    }
+
+    /// Arguments that run the script file at `path`
+    pub fn script_argv(&self, path: &str) -> Vec<String> {
+        match self {
+            Self::Posix(shell) | Self::Other(shell) => vec![shell.clone(), path.to_string()],
+            Self::Pwsh(shell) => vec![
+                shell.clone(),
+                "-NoProfile".to_string(),
+                "-NonInteractive".to_string(),
+                "-ExecutionPolicy".to_string(),
+                "Bypass".to_string(),
+                "-File".to_string(),
+                path.to_string(),
+            ],
+            Self::Cmd => vec!["cmd".to_string(), "/C".to_string(), path.to_string()],
+        }
+    }
+
+    /// The program that runs, as named in the settings
+    pub fn program(&self) -> &str {
+        match self {
+            Self::Posix(shell) | Self::Pwsh(shell) | Self::Other(shell) => shell,
+            Self::Cmd => "cmd",
+        }
+    }
+
+    pub fn script_extension(&self) -> &'static str {
+        match self {
+            Self::Other(program) => {
+                let base = Path::new(program)
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                if base.starts_with("python") {
+                    "py"
+                } else if base == "node" {
+                    "js"
+                } else {
+                    "sh"
+                }
+            }
+            Self::Posix(_) => "sh",
+            Self::Pwsh(_) => "ps1",
+            Self::Cmd => "cmd",
+        }
+    }
+
+    /// `script` with the line endings the shell reads: LF for POSIX shells,
+    /// which choke on a stray CR, and CRLF for cmd, which misreads labels
+    /// and blocks without it
+    pub fn script_contents(&self, script: &str) -> String {
+        let lf = script.replace("\r\n", "\n");
+        match self {
+            Self::Cmd | Self::Pwsh(_) if cfg!(windows) => lf.replace('\n', "\r\n"),
+            _ => lf,
+        }
+    }
+}
+
+/// The supported shells installed on this server
+pub fn available() -> Vec<String> {
+    SUPPORTED
+        .iter()
+        .filter(|name| which::which(name).is_ok())
+        .map(|name| name.to_string())
+        .collect()
+}
@@ -0,0 +1,102 @@
+//! Graceful shutdown on SIGINT/SIGTERM, or a stop request from the Windows
+//! service control manager.
+
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::{builds, daemon, ServerContext, ServerMessage, ServerShuttingDownPayload};
+
+/// How long cancelled builds get to record their outcome once the grace period is over
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counts the shutdowns asked for with `request`
+fn requests() -> &'static watch::Sender<u32> {
+    static REQUESTS: OnceLock<watch::Sender<u32>> = OnceLock::new();
+    REQUESTS.get_or_init(|| watch::channel(0).0)
+}
+
+/// Asks for a shutdown as if a signal had arrived
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn request() {
+    requests().send_modify(|count| *count += 1);
+}
+
+/// Resolves on Ctrl-C, SIGTERM on unix, or `request`
+pub async fn signal() {
+    let mut requested = requests().subscribe();
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+        _ = requested.changed() => {}
+    }
+}
+
+/// Drains running builds, saves data and returns the process exit code:
+/// 0 if every build finished on its own, 1 if some had to be cancelled and
+/// 130 if a second signal forced an immediate exit.
+pub async fn run(ctx: &ServerContext) -> i32 {
+    let grace_period = Duration::from_secs(ctx.settings().shutdown_grace_period_secs);
+    info!("Shutdown requested, waiting up to {}s for running builds", grace_period.as_secs());
+    ctx.shutting_down.store(true, Ordering::Relaxed);
+    daemon::stopping(grace_period + CANCEL_TIMEOUT);
+    ctx.broadcast(ServerMessage::ServerShuttingDown(ServerShuttingDownPayload {
+        grace_period_secs: grace_period.as_secs(),
+    }));
+    builds::clear_queue(ctx).await;
+
+    let code = tokio::select! {
+        code = drain(ctx, grace_period) => code,
+        _ = signal() => {
+            warn!("Second signal received, shutting down immediately");
+            builds::cancel_all(ctx).await;
+            130
+        }
+    };
+
+    if let Err(e) = ctx.persistence.flush(ctx).await {
+        error!("Failed to save data on shutdown: {}", e);
+    }
+    info!("Server stopped");
+    code
+}
+
+async fn drain(ctx: &ServerContext, grace_period: Duration) -> i32 {
+    if tokio::time::timeout(grace_period, builds::wait_idle(ctx)).await.is_ok() {
+        return 0;
+    }
+
+    warn!("Grace period expired, cancelling running builds");
+    builds::cancel_all(ctx).await;
+    if tokio::time::timeout(CANCEL_TIMEOUT, builds::wait_idle(ctx)).await.is_err() {
+        error!("Builds did not stop after cancellation");
+    }
+    1
+}
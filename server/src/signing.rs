@@ -0,0 +1,330 @@
+//! Code signing nodes. Output of the platform tools goes to the build log as
+//! it is; their messages are hard enough to read without rewording.
+//!
+//! `codesign` signs macOS bundles and binaries with Apple's `codesign`, then
+//! checks the result with `codesign --verify` and Gatekeeper's `spctl`.
+//!
+//! `signtool` signs Windows executables and installers with Authenticode,
+//! using `signtool` on Windows or `osslsigncode` anywhere else. Files are
+//! signed first and timestamped separately, so a flaky timestamp server only
+//! costs a retry of the timestamp.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::build_log::BuildLog;
+use crate::builds::{BuildCancelled, CancelToken};
+use crate::process::{self, TempFile};
+
+/// Identity for ad-hoc signing, which needs no certificate
+pub const AD_HOC: &str = "-";
+
+pub struct Codesign {
+    pub paths: Vec<PathBuf>,
+    /// Certificate name or hash, or `-` for ad-hoc signing
+    pub identity: String,
+    pub entitlements: Option<PathBuf>,
+    pub deep: bool,
+    /// Value of `--options`, e.g. `runtime`
+    pub options: Option<String>,
+    pub keychain: Option<Keychain>,
+    /// Run `spctl --assess`; never done for ad-hoc signatures, which it rejects
+    pub assess: bool,
+}
+
+pub struct Keychain {
+    pub path: String,
+    pub password: Option<String>,
+}
+
+/// Files and bundles matching `patterns`, globs relative to `workdir`. Each
+/// pattern must match something, so a typo does not go unsigned.
+pub fn expand(workdir: &Path, patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let full = workdir.join(pattern);
+        let matched: Vec<PathBuf> = glob::glob(&full.to_string_lossy())
+            .with_context(|| format!("Invalid pattern {}", pattern))?
+            .filter_map(|entry| entry.ok())
+            .collect();
+        if matched.is_empty() {
+            anyhow::bail!("Nothing to sign matches {}", pattern);
+        }
+        paths.extend(matched);
+    }
+    paths.dedup();
+    Ok(paths)
+}
+
+/// Fails unless this server can run `codesign`
+pub fn check_codesign_available() -> Result<()> {
+    if !cfg!(target_os = "macos") {
+        anyhow::bail!("codesign only runs on macOS servers");
+    }
+    which::which("codesign").map_err(|_| anyhow::anyhow!("codesign is not installed on the server"))?;
+    Ok(())
+}
+
+pub async fn codesign(sign: &Codesign, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    if let Some(keychain) = &sign.keychain {
+        if let Some(password) = &keychain.password {
+            log.line(&format!("[codesign] Unlocking keychain {}", keychain.path)).await;
+            unlock_keychain(&keychain.path, password, cancel, log).await?;
+        }
+    }
+
+    for path in &sign.paths {
+        let mut command = Command::new("codesign");
+        command.args(["--sign", &sign.identity, "--force"]);
+        // Ad-hoc signatures cannot be timestamped
+        command.arg(if sign.identity == AD_HOC { "--timestamp=none" } else { "--timestamp" });
+        if sign.deep {
+            command.arg("--deep");
+        }
+        if let Some(options) = &sign.options {
+            command.args(["--options", options]);
+        }
+        if let Some(entitlements) = &sign.entitlements {
+            command.arg("--entitlements").arg(entitlements);
+        }
+        if let Some(keychain) = &sign.keychain {
+            command.args(["--keychain", &keychain.path]);
+        }
+        command.arg(path);
+        log.line(&format!("[codesign] Signing {} as {}", path.display(), describe_identity(&sign.identity))).await;
+        process::run_tool(&mut command, "codesign", cancel, log).await?;
+    }
+
+    for path in &sign.paths {
+        log.line(&format!("[codesign] Verifying {}", path.display())).await;
+        let mut verify = Command::new("codesign");
+        verify.args(["--verify", "--deep", "--strict", "--verbose=2"]).arg(path);
+        process::run_tool(&mut verify, "codesign --verify", cancel, log).await?;
+
+        if sign.assess && sign.identity != AD_HOC {
+            let mut assess = Command::new("spctl");
+            assess.args(["--assess", "--type", assess_type(path), "--verbose"]).arg(path);
+            process::run_tool(&mut assess, "spctl --assess", cancel, log).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Unlocks `path` through `security -i`, which reads its command from stdin
+/// so the password never goes on a command line where `ps` would show it
+async fn unlock_keychain(path: &str, password: &str, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    let mut child = process::configure(Command::new("security").arg("-i"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run security")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let command = format!("unlock-keychain -p {} {}\n", quote(password), quote(path));
+        stdin.write_all(command.as_bytes()).await?;
+    }
+    process::run(child, cancel, log).await?.check("security unlock-keychain")?;
+    Ok(())
+}
+
+/// Quotes a word for the `security -i` command line
+fn quote(word: &str) -> String {
+    format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Where an Authenticode certificate comes from
+pub enum Certificate {
+    /// A PKCS#12 file and its password
+    Pfx { path: PathBuf, password: Option<String> },
+    /// A certificate in the Windows certificate store, by SHA-1 thumbprint
+    Store { thumbprint: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthenticodeTool {
+    Signtool,
+    Osslsigncode,
+}
+
+impl AuthenticodeTool {
+    /// The `tool` setting of a signtool node. Unset means signtool, which
+    /// only exists on Windows.
+    pub fn parse(name: Option<&str>) -> Result<Self> {
+        match name.unwrap_or("signtool") {
+            "signtool" if cfg!(windows) => Ok(Self::Signtool),
+            "signtool" => anyhow::bail!(
+                "signtool only runs on Windows servers; set tool to osslsigncode to sign from this server"
+            ),
+            "osslsigncode" => Ok(Self::Osslsigncode),
+            other => anyhow::bail!("Unknown tool {}, expected signtool or osslsigncode", other),
+        }
+    }
+
+    fn program(self) -> &'static str {
+        match self {
+            Self::Signtool => "signtool",
+            Self::Osslsigncode => "osslsigncode",
+        }
+    }
+}
+
+pub struct Authenticode {
+    pub paths: Vec<PathBuf>,
+    pub certificate: Certificate,
+    pub tool: AuthenticodeTool,
+    /// File and timestamp digest, e.g. `sha256`
+    pub digest: String,
+    /// RFC 3161 timestamp servers, tried in order
+    pub timestamp_urls: Vec<String>,
+    /// Times the whole list is tried before the node fails
+    pub timestamp_rounds: u32,
+}
+
+/// Fails unless this server can sign with `tool` from `certificate`
+pub fn check_authenticode_available(tool: AuthenticodeTool, certificate: &Certificate) -> Result<()> {
+    if tool == AuthenticodeTool::Osslsigncode && matches!(certificate, Certificate::Store { .. }) {
+        anyhow::bail!("osslsigncode cannot use the Windows certificate store; sign with a pfx instead");
+    }
+    which::which(tool.program())
+        .map_err(|_| anyhow::anyhow!("{} is not installed on the server", tool.program()))?;
+    Ok(())
+}
+
+pub async fn authenticode(sign: &Authenticode, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    // osslsigncode reads the password from a file; signtool only takes it
+    // on its command line
+    let password_file = match (&sign.certificate, sign.tool) {
+        (Certificate::Pfx { password: Some(password), .. }, AuthenticodeTool::Osslsigncode) => {
+            let file = TempFile::new("pfx-password");
+            file.write_private(password).await?;
+            Some(file)
+        }
+        _ => None,
+    };
+
+    for path in &sign.paths {
+        log.line(&format!("[signtool] Signing {}", path.display())).await;
+        match sign.tool {
+            AuthenticodeTool::Signtool => {
+                let mut command = Command::new("signtool");
+                command.args(["sign", "/fd", &sign.digest]);
+                match &sign.certificate {
+                    Certificate::Pfx { path, password } => {
+                        command.arg("/f").arg(path);
+                        if let Some(password) = password {
+                            command.args(["/p", password]);
+                        }
+                    }
+                    Certificate::Store { thumbprint } => {
+                        command.args(["/sha1", thumbprint]);
+                    }
+                }
+                command.arg(path);
+                process::run_tool(&mut command, "signtool sign", cancel, log).await?;
+            }
+            AuthenticodeTool::Osslsigncode => {
+                let Certificate::Pfx { path: pfx, .. } = &sign.certificate else {
+                    anyhow::bail!("osslsigncode cannot use the Windows certificate store; sign with a pfx instead");
+                };
+                let mut command = Command::new("osslsigncode");
+                command.args(["sign", "-h", &sign.digest]).arg("-pkcs12").arg(pfx);
+                if let Some(file) = &password_file {
+                    command.arg("-readpass").arg(&file.0);
+                }
+                osslsigncode_in_place(&mut command, path, "osslsigncode sign", cancel, log).await?;
+            }
+        }
+        if !sign.timestamp_urls.is_empty() {
+            timestamp(sign, path, cancel, log).await?;
+        }
+    }
+
+    for path in &sign.paths {
+        log.line(&format!("[signtool] Verifying {}", path.display())).await;
+        let mut command = Command::new(sign.tool.program());
+        match sign.tool {
+            AuthenticodeTool::Signtool => command.args(["verify", "/pa", "/v"]).arg(path),
+            AuthenticodeTool::Osslsigncode => command.args(["verify", "-in"]).arg(path),
+        };
+        process::run_tool(&mut command, "Signature verification", cancel, log).await?;
+    }
+    Ok(())
+}
+
+/// Timestamps the signature on `path`, going through the servers in order
+/// and backing off between rounds
+async fn timestamp(sign: &Authenticode, path: &Path, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    let mut delay = Duration::from_secs(2);
+    for round in 1..=sign.timestamp_rounds.max(1) {
+        for url in &sign.timestamp_urls {
+            log.line(&format!("[signtool] Timestamping {} with {}", path.display(), url)).await;
+            let mut command = Command::new(sign.tool.program());
+            let result = match sign.tool {
+                AuthenticodeTool::Signtool => {
+                    command.args(["timestamp", "/tr", url, "/td", &sign.digest]).arg(path);
+                    process::run_tool(&mut command, "signtool timestamp", cancel, log).await.map(|_| ())
+                }
+                AuthenticodeTool::Osslsigncode => {
+                    command.args(["add", "-ts", url, "-h", &sign.digest]);
+                    osslsigncode_in_place(&mut command, path, "osslsigncode add", cancel, log).await
+                }
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is::<BuildCancelled>() => return Err(e),
+                Err(e) => log.line(&format!("[signtool] Timestamping with {} failed: {:#}", url, e)).await,
+            }
+        }
+        if round < sign.timestamp_rounds {
+            log.line(&format!("[signtool] No timestamp server answered, trying again in {}s", delay.as_secs())).await;
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = cancel.cancelled() => return Err(BuildCancelled.into()),
+            }
+            delay *= 2;
+        }
+    }
+    anyhow::bail!("No timestamp server could timestamp {}", path.display())
+}
+
+/// Runs an osslsigncode command that writes a new file, then moves that file
+/// over `path`
+async fn osslsigncode_in_place(
+    command: &mut Command,
+    path: &Path,
+    what: &'static str,
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<()> {
+    let mut output = path.as_os_str().to_owned();
+    output.push(".signed");
+    let output = TempFile(PathBuf::from(output));
+    command.arg("-in").arg(path).arg("-out").arg(&output.0);
+    process::run_tool(command, what, cancel, log).await?;
+    tokio::fs::rename(&output.0, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+fn describe_identity(identity: &str) -> &str {
+    if identity == AD_HOC {
+        "ad-hoc"
+    } else {
+        identity
+    }
+}
+
+/// Gatekeeper assesses installer packages differently from apps
+fn assess_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("pkg") => "install",
+        _ => "execute",
+    }
+}
+
@@ -0,0 +1,86 @@
+//! Aggregate statistics over a workflow's recent builds, see `GetBuildStats`.
+
+use std::collections::HashMap;
+
+use crate::builds::status;
+use crate::{BuildRecord, BuildStatsPayload};
+
+/// Summarizes `records`, which must be ordered newest first
+pub fn compute(workflow_id: &str, records: &[BuildRecord]) -> BuildStatsPayload {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for record in records {
+        *counts.entry(record.status.clone()).or_default() += 1;
+    }
+
+    let finished = records.iter().filter(|r| r.status != status::RUNNING).count();
+    let successes = counts.get(status::SUCCESS).copied().unwrap_or(0);
+    let success_rate = if finished == 0 {
+        0.0
+    } else {
+        successes as f64 / finished as f64
+    };
+
+    // Wait nodes pause on purpose, so their time would only blur how long
+    // the work itself takes
+    let waited = |r: &BuildRecord| r.node_runs.iter().filter_map(|n| n.waited_ms).sum::<u64>();
+    let finished_records: Vec<&BuildRecord> = records.iter().filter(|r| r.status != status::RUNNING).collect();
+    let mut durations: Vec<u64> = finished_records
+        .iter()
+        .filter_map(|r| r.duration_ms.map(|d| d.saturating_sub(waited(r))))
+        .collect();
+    durations.sort_unstable();
+    let average_duration_ms = if durations.is_empty() {
+        0
+    } else {
+        durations.iter().sum::<u64>() / durations.len() as u64
+    };
+
+    let average_wait_ms = if finished_records.is_empty() {
+        0
+    } else {
+        finished_records.iter().map(|r| waited(r)).sum::<u64>() / finished_records.len() as u64
+    };
+
+    let mut node_durations: HashMap<String, (u64, u64)> = HashMap::new();
+    for run in records.iter().flat_map(|r| &r.node_runs) {
+        if let (false, Some(duration)) = (run.skipped, run.duration_ms) {
+            let (sum, count) = node_durations.entry(run.node_id.clone()).or_default();
+            *sum += duration;
+            *count += 1;
+        }
+    }
+    let node_average_duration_ms = node_durations
+        .into_iter()
+        .map(|(node_id, (sum, count))| (node_id, sum / count))
+        .collect();
+
+    let last_at = |wanted: &str| {
+        records
+            .iter()
+            .find(|r| r.status == wanted)
+            .map(|r| r.finished_at.clone().unwrap_or_else(|| r.started_at.clone()))
+    };
+
+    BuildStatsPayload {
+        workflow_id: workflow_id.to_string(),
+        runs: records.len(),
+        counts,
+        success_rate,
+        average_duration_ms,
+        median_duration_ms: percentile(&durations, 50),
+        p95_duration_ms: percentile(&durations, 95),
+        average_wait_ms,
+        node_average_duration_ms,
+        last_success_at: last_at(status::SUCCESS),
+        last_failure_at: last_at(status::FAILED),
+    }
+}
+
+/// Nearest-rank percentile of sorted `values`, 0 when empty
+fn percentile(values: &[u64], pct: usize) -> u64 {
+    if values.is_empty() {
+        return 0;
+    }
+    let rank = (pct * values.len()).div_ceil(100).max(1);
+    values[rank - 1]
+}
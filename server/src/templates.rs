@@ -0,0 +1,213 @@
+//! Built-in templates of actions and workflow nodes, for users to start
+//! from instead of an empty list.
+//!
+//! Each template is a JSON manifest in `templates/`, embedded in the server
+//! binary. A template holds either an action or a set of nodes with their
+//! connections, the tools it needs, as requirements such as `git` or
+//! `aws >= 2`, and the systems it runs on, none meaning any.
+//!
+//! `InstantiateTemplate` copies a template with fresh ids. The copy is
+//! returned rather than stored, so the app saves it with `SaveAction` or
+//! `SaveWorkflow` once the user has made it their own; the template itself
+//! never changes.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{requirements, StoredAction};
+
+/// The manifests, by file name for error messages
+const MANIFESTS: [(&str, &str); 7] = [
+    ("bump-version.json", include_str!("../templates/bump-version.json")),
+    ("generate-changelog.json", include_str!("../templates/generate-changelog.json")),
+    ("clean-workspace.json", include_str!("../templates/clean-workspace.json")),
+    ("upload-to-s3.json", include_str!("../templates/upload-to-s3.json")),
+    ("rust-crate.json", include_str!("../templates/rust-crate.json")),
+    ("node-app.json", include_str!("../templates/node-app.json")),
+    ("python-package.json", include_str!("../templates/python-package.json")),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Template {
+    id: String,
+    name: String,
+    description: String,
+    category: String,
+    /// Values of `std::env::consts::OS` it runs on; any if empty
+    #[serde(default)]
+    os: Vec<String>,
+    #[serde(default)]
+    requires: Vec<requirements::Spec>,
+    #[serde(default)]
+    action: Option<ActionTemplate>,
+    #[serde(default)]
+    nodes: Vec<serde_json::Value>,
+    #[serde(default)]
+    connections: Vec<serde_json::Value>,
+}
+
+/// The parts of a `StoredAction` a template gives
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ActionTemplate {
+    script: String,
+    #[serde(default)]
+    inputs: Vec<serde_json::Value>,
+    #[serde(default)]
+    outputs: Vec<serde_json::Value>,
+    #[serde(default)]
+    interpreter: Option<String>,
+    #[serde(default)]
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateKind {
+    Action,
+    Nodes,
+}
+
+/// A template as `ListTemplates` describes it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateSummary {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub category: String,
+    pub kind: TemplateKind,
+    pub os: Vec<String>,
+    pub requires: Vec<requirements::Spec>,
+    /// Whether it runs on the server's system
+    pub compatible: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplatesPayload {
+    pub templates: Vec<TemplateSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstantiateTemplatePayload {
+    pub template_id: String,
+    /// Name of the new action; the template's name if unset
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// A copy of a template with fresh ids, not yet stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateInstancePayload {
+    pub template_id: String,
+    /// Set for an action template
+    #[serde(default)]
+    pub action: Option<StoredAction>,
+    /// Set for a nodes template, to add to a workflow
+    #[serde(default)]
+    pub nodes: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub connections: Vec<serde_json::Value>,
+    /// Tools the template needs, for the workflow's `requires`
+    #[serde(default)]
+    pub requires: Vec<requirements::Spec>,
+}
+
+/// The templates, parsed once. The manifests are part of the binary, so one
+/// that does not parse is a bug in the server.
+fn templates() -> &'static [Template] {
+    static TEMPLATES: OnceLock<Vec<Template>> = OnceLock::new();
+    TEMPLATES.get_or_init(|| {
+        MANIFESTS
+            .iter()
+            .map(|(file, manifest)| {
+                serde_json::from_str(manifest)
+                    .unwrap_or_else(|e| panic!("The built-in template {} is invalid: {}", file, e))
+            })
+            .collect()
+    })
+}
+
+pub fn list() -> TemplatesPayload {
+    let templates = templates()
+        .iter()
+        .map(|template| TemplateSummary {
+            id: template.id.clone(),
+            name: template.name.clone(),
+            description: template.description.clone(),
+            category: template.category.clone(),
+            kind: if template.action.is_some() {
+                TemplateKind::Action
+            } else {
+                TemplateKind::Nodes
+            },
+            os: template.os.clone(),
+            requires: template.requires.clone(),
+            compatible: template.os.is_empty() || template.os.iter().any(|os| os == std::env::consts::OS),
+        })
+        .collect();
+    TemplatesPayload { templates }
+}
+
+/// A copy of the template `request` names, with fresh ids
+pub fn instantiate(request: &InstantiateTemplatePayload) -> Result<TemplateInstancePayload> {
+    let template = templates()
+        .iter()
+        .find(|t| t.id == request.template_id)
+        .with_context(|| format!("Template not found: {}", request.template_id))?;
+    let now = chrono::Utc::now().to_rfc3339();
+    let action = template.action.as_ref().map(|action| StoredAction {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: request
+            .name
+            .clone()
+            .filter(|name| !name.trim().is_empty())
+            .unwrap_or_else(|| template.name.clone()),
+        description: template.description.clone(),
+        script: action.script.clone(),
+        inputs: action.inputs.clone(),
+        outputs: action.outputs.clone(),
+        version: 0,
+        interpreter: action.interpreter.clone(),
+        timeout_secs: action.timeout_secs,
+        requires: template.requires.clone(),
+        created_at: now.clone(),
+        updated_at: now,
+    });
+
+    // Connections name nodes by id, so they are renamed together
+    let ids: HashMap<&str, String> = template
+        .nodes
+        .iter()
+        .filter_map(|node| node.get("id")?.as_str())
+        .map(|id| (id, uuid::Uuid::new_v4().to_string()))
+        .collect();
+    let renamed = |value: &serde_json::Value, keys: &[&str]| {
+        let mut value = value.clone();
+        for key in keys {
+            if let Some(id) = value.get(*key).and_then(|v| v.as_str()).and_then(|id| ids.get(id)) {
+                value[*key] = serde_json::Value::String(id.clone());
+            }
+        }
+        value
+    };
+    let nodes = template.nodes.iter().map(|node| renamed(node, &["id"])).collect();
+    let connections = template
+        .connections
+        .iter()
+        .map(|connection| {
+            let mut connection = renamed(connection, &["from", "to"]);
+            connection["id"] = serde_json::Value::String(uuid::Uuid::new_v4().to_string());
+            connection
+        })
+        .collect();
+
+    Ok(TemplateInstancePayload {
+        template_id: template.id.clone(),
+        action,
+        nodes,
+        connections,
+        requires: template.requires.clone(),
+    })
+}
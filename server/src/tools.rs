@@ -0,0 +1,182 @@
+//! Pinned external tools fetched by the `fetch_tool` node.
+//!
+//! Downloads are checked against their expected SHA-256 before anything else
+//! touches them, then kept in `data_dir/tool-cache/<sha256>` so later builds
+//! skip the download. Each build installs its tools into its own directory,
+//! `data_dir/tools/<build_id>` unless the node names another, which is put on
+//! the PATH of the nodes after it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::archive;
+use crate::build_log::BuildLog;
+use crate::builds::{BuildCancelled, CancelToken};
+
+/// Bytes between progress lines when the size is unknown
+const PROGRESS_STEP: u64 = 10 * 1024 * 1024;
+
+/// Directory holding the tool directories of every build
+pub fn root(data_dir: &Path) -> PathBuf {
+    data_dir.join("tools")
+}
+
+/// Where a build's tools go unless the node says otherwise
+pub fn build_dir(data_dir: &Path, build_id: &str) -> PathBuf {
+    root(data_dir).join(build_id)
+}
+
+pub async fn remove_build_dir(data_dir: &Path, build_id: &str) {
+    let _ = tokio::fs::remove_dir_all(build_dir(data_dir, build_id)).await;
+}
+
+/// Last path segment of `url`, without its query
+pub fn file_name(url: &str) -> String {
+    url.split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .rsplit('/')
+        .find(|s| !s.is_empty())
+        .unwrap_or("tool")
+        .to_string()
+}
+
+/// The verified download of `url`, from the cache if an earlier build
+/// fetched it
+pub async fn fetch(
+    data_dir: &Path,
+    url: &str,
+    sha256: &str,
+    cancel: &CancelToken,
+    log: &BuildLog,
+) -> Result<PathBuf> {
+    let sha256 = sha256.to_lowercase();
+    let cache_dir = data_dir.join("tool-cache").join(&sha256);
+    let cached = cache_dir.join(file_name(url));
+    if tokio::fs::try_exists(&cached).await.unwrap_or(false) {
+        log.line(&format!("[tool] Using cached {}", file_name(url))).await;
+        return Ok(cached);
+    }
+    tokio::fs::create_dir_all(&cache_dir).await?;
+
+    log.line(&format!("[tool] Downloading {}", url)).await;
+    let mut response = reqwest::Client::new()
+        .get(url)
+        .header(reqwest::header::USER_AGENT, concat!("BuildForge/", env!("CARGO_PKG_VERSION")))
+        .send()
+        .await
+        .with_context(|| format!("Failed to download {}", url))?;
+    if !response.status().is_success() {
+        anyhow::bail!("{} answered {}", url, response.status());
+    }
+    let total = response.content_length();
+
+    // Only renamed into the cache once the checksum matched
+    let partial = cache_dir.join(format!("{}.partial", uuid::Uuid::new_v4()));
+    let mut file = tokio::fs::File::create(&partial).await?;
+    let mut hasher = Sha256::new();
+    let mut received = 0u64;
+    let mut next_report = 0u64;
+    let downloaded: Result<()> = async {
+        loop {
+            let chunk = tokio::select! {
+                chunk = response.chunk() => chunk?,
+                _ = cancel.cancelled() => return Err(BuildCancelled.into()),
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+            received += chunk.len() as u64;
+            if received >= next_report {
+                match total {
+                    Some(total) if total > 0 => {
+                        log.line(&format!("[tool] {}% of {}", received * 100 / total, format_size(total))).await;
+                        next_report = received + total / 10;
+                    }
+                    _ => {
+                        log.line(&format!("[tool] {} so far", format_size(received))).await;
+                        next_report = received + PROGRESS_STEP;
+                    }
+                }
+            }
+        }
+        file.flush().await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = downloaded {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+
+    let actual = hex::encode(hasher.finalize());
+    if actual != sha256 {
+        let _ = tokio::fs::remove_file(&partial).await;
+        anyhow::bail!("Checksum mismatch for {}: expected {}, got {}", url, sha256, actual);
+    }
+    tokio::fs::rename(&partial, &cached).await?;
+    log.line(&format!("[tool] Checksum {} verified", actual)).await;
+    Ok(cached)
+}
+
+/// Puts a fetched tool into `dir`: unpacked if `format` is set, else copied
+/// as `name`. Returns the executable, marked as such, unless it is an archive
+/// without a `binary_path`.
+pub async fn install(
+    download: &Path,
+    dir: &Path,
+    format: Option<archive::Format>,
+    name: &str,
+    binary_path: Option<&str>,
+) -> Result<Option<PathBuf>> {
+    tokio::fs::create_dir_all(dir).await?;
+    let binary = match format {
+        Some(format) => {
+            archive::extract(download, format, dir).await?;
+            let Some(binary_path) = binary_path else {
+                return Ok(None);
+            };
+            let binary = dir.join(binary_path);
+            if !binary.starts_with(dir) || !tokio::fs::try_exists(&binary).await.unwrap_or(false) {
+                anyhow::bail!("The archive has no {}", binary_path);
+            }
+            binary
+        }
+        None => {
+            let binary = dir.join(name);
+            tokio::fs::copy(download, &binary)
+                .await
+                .with_context(|| format!("Failed to copy the tool to {}", binary.display()))?;
+            binary
+        }
+    };
+    make_executable(&binary).await?;
+    Ok(Some(binary))
+}
+
+#[cfg(unix)]
+async fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = tokio::fs::metadata(path).await?.permissions();
+    permissions.set_mode(permissions.mode() | 0o755);
+    tokio::fs::set_permissions(path, permissions).await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn make_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{} KB", bytes / 1024)
+    }
+}
@@ -0,0 +1,102 @@
+//! Deliberate pauses, as done by the `wait` node: a fixed delay, polling an
+//! HTTP endpoint until it answers as expected, or both in that order.
+//!
+//! Both log how long they have waited every [`HEARTBEAT`] and stop as soon as
+//! the build is cancelled. Time spent here is recorded as waiting on the node
+//! run, so build statistics can leave it out.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::build_log::BuildLog;
+use crate::builds::{BuildCancelled, CancelToken};
+
+/// How often a waiting node says so in the log
+pub const HEARTBEAT: Duration = Duration::from_secs(30);
+
+pub struct HttpCondition {
+    pub url: String,
+    /// Statuses that end the wait
+    pub expected: Vec<u16>,
+    pub interval: Duration,
+    /// Longest the endpoint is polled before the node fails
+    pub timeout: Duration,
+}
+
+/// Sleeps for `duration`, logging progress
+pub async fn sleep(duration: Duration, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    let started = Instant::now();
+    log.line(&format!("[wait] Waiting {}s", duration.as_secs())).await;
+    loop {
+        let remaining = duration.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(remaining.min(HEARTBEAT)) => {}
+            _ = cancel.cancelled() => return Err(BuildCancelled.into()),
+        }
+        let waited = started.elapsed().min(duration);
+        if waited < duration {
+            log.line(&format!("[wait] Waited {}/{}s", waited.as_secs(), duration.as_secs())).await;
+        }
+    }
+}
+
+/// Polls `condition.url` until it answers with an expected status
+pub async fn until_http(condition: &HttpCondition, cancel: &CancelToken, log: &BuildLog) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(condition.interval.max(Duration::from_secs(5)))
+        .build()?;
+    let started = Instant::now();
+    let mut last_heartbeat = Instant::now();
+    log.line(&format!(
+        "[wait] Waiting up to {}s for {} to answer {}",
+        condition.timeout.as_secs(),
+        condition.url,
+        expected_text(&condition.expected)
+    ))
+    .await;
+    loop {
+        let answer = tokio::select! {
+            response = client.get(&condition.url).send() => match response {
+                Ok(response) => response.status().as_u16().to_string(),
+                Err(e) => e.without_url().to_string(),
+            },
+            _ = cancel.cancelled() => return Err(BuildCancelled.into()),
+        };
+        if condition.expected.iter().any(|status| status.to_string() == answer) {
+            log.line(&format!("[wait] {} answered {} after {}s", condition.url, answer, started.elapsed().as_secs())).await;
+            return Ok(());
+        }
+        if started.elapsed() >= condition.timeout {
+            anyhow::bail!(
+                "{} did not answer {} within {}s, last answer: {}",
+                condition.url,
+                expected_text(&condition.expected),
+                condition.timeout.as_secs(),
+                answer
+            );
+        }
+        if last_heartbeat.elapsed() >= HEARTBEAT {
+            log.line(&format!(
+                "[wait] Waited {}/{}s, last answer: {}",
+                started.elapsed().as_secs(),
+                condition.timeout.as_secs(),
+                answer
+            ))
+            .await;
+            last_heartbeat = Instant::now();
+        }
+        let remaining = condition.timeout.saturating_sub(started.elapsed());
+        tokio::select! {
+            _ = tokio::time::sleep(condition.interval.min(remaining)) => {}
+            _ = cancel.cancelled() => return Err(BuildCancelled.into()),
+        }
+    }
+}
+
+fn expected_text(expected: &[u16]) -> String {
+    expected.iter().map(u16::to_string).collect::<Vec<_>>().join(" or ")
+}
@@ -0,0 +1,464 @@
+//! `POST /webhooks/github`: builds started by pushes, tags and releases.
+//!
+//! GitHub posts to the server's own port, which tells these requests apart
+//! from WebSocket clients by their first line. Each delivery must carry an
+//! `X-Hub-Signature-256` made with the repo's secret, read from the server
+//! environment variable its `webhook_secret_env` names, or else the
+//! server's `--webhook-secret`. Unsigned or wrongly signed deliveries are
+//! answered with 401.
+//!
+//! A verified delivery starts a build of every workflow of that repo with a
+//! matching trigger, such as `{ "event": "push", "branches": ["main"] }` or
+//! `{ "event": "push", "tags": ["v*"] }`, and is answered with 202 and the
+//! build ids, or with 204 if no trigger matched. A push to a branch builds
+//! the commit it delivered, even if the branch has moved on since. Every verified delivery is
+//! kept in the history database, with what it started or why it started
+//! nothing, and `GetWebhookDeliveries` pages through them.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::{audit, builds, http, BuildStartPayload, ServerContext, StoredRepo, StoredWorkflow};
+
+pub const PATH: &str = "/webhooks/github";
+
+/// GitHub sends at most 25 MB
+const MAX_BODY_BYTES: usize = 25 * 1024 * 1024;
+
+/// Time a delivery may take to arrive
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Deliveries kept in the history database
+pub const KEPT_DELIVERIES: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerEvent {
+    /// A push to a branch, or of a tag
+    Push,
+    /// A published release
+    Release,
+}
+
+/// When a delivery starts a build of a workflow. Branches and tags are glob
+/// patterns. A push to a branch matches if `branches` does, or if neither
+/// list is set; a tag, pushed or released, only matches `tags`, or any tag
+/// for a release trigger without them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trigger {
+    pub event: TriggerEvent,
+    #[serde(default)]
+    pub branches: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// What a delivery is about
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Ref {
+    Branch(String),
+    Tag(String),
+}
+
+impl Trigger {
+    fn matches(&self, event: TriggerEvent, git_ref: &Ref) -> bool {
+        if self.event != event {
+            return false;
+        }
+        let any = |patterns: &[String], name: &str| {
+            patterns
+                .iter()
+                .any(|p| glob::Pattern::new(p).is_ok_and(|pattern| pattern.matches(name)))
+        };
+        match git_ref {
+            Ref::Branch(branch) => {
+                any(&self.branches, branch) || (self.branches.is_empty() && self.tags.is_empty())
+            }
+            Ref::Tag(tag) => any(&self.tags, tag) || (event == TriggerEvent::Release && self.tags.is_empty()),
+        }
+    }
+
+    /// Fails on a pattern that is not a valid glob
+    pub fn validate(&self) -> Result<()> {
+        for pattern in self.branches.iter().chain(&self.tags) {
+            glob::Pattern::new(pattern).with_context(|| format!("Invalid trigger pattern {}", pattern))?;
+        }
+        Ok(())
+    }
+}
+
+/// A verified delivery and what came of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    /// GitHub's `X-GitHub-Delivery`
+    pub id: String,
+    pub received_at: String,
+    pub event: String,
+    /// `owner/name` of the repository
+    pub repo: String,
+    #[serde(rename = "ref", default)]
+    pub git_ref: Option<String>,
+    #[serde(default)]
+    pub commit_sha: Option<String>,
+    /// Builds it started, one per matching workflow
+    #[serde(default)]
+    pub build_ids: Vec<String>,
+    /// What happened, such as why nothing was built
+    pub outcome: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDeliveriesQuery {
+    #[serde(default = "crate::default_page_size")]
+    pub limit: usize,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDeliveriesPage {
+    /// Newest first
+    pub deliveries: Vec<WebhookDelivery>,
+    pub total: usize,
+}
+
+/// Answers one webhook request on `stream`
+pub async fn handle(mut stream: TcpStream, peer: SocketAddr, ctx: &Arc<ServerContext>) -> Result<()> {
+    let (status, body) = match tokio::time::timeout(READ_TIMEOUT, http::read_request(&mut stream, MAX_BODY_BYTES)).await {
+        Ok(Ok(request)) => deliver(ctx, peer, &request.headers, &request.body).await,
+        Ok(Err(e)) => {
+            warn!("Bad webhook request from {}: {:#}", peer, e);
+            (http::status_of(&e), format!("{:#}", e))
+        }
+        Err(_) => (408, "The request took too long".to_string()),
+    };
+    http::respond(&mut stream, status, "text/plain; charset=utf-8", body.as_bytes()).await
+}
+
+/// The HTTP status and body to answer a delivery with
+async fn deliver(ctx: &Arc<ServerContext>, peer: SocketAddr, headers: &HashMap<String, String>, body: &[u8]) -> (u16, String) {
+    let event = headers.get("x-github-event").cloned().unwrap_or_default();
+    let delivery_id = headers
+        .get("x-github-delivery")
+        .cloned()
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let payload: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(payload) => payload,
+        Err(e) => return (400, format!("The body is not JSON: {}", e)),
+    };
+    let full_name = payload
+        .pointer("/repository/full_name")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    // Read before the signature is checked, only to pick the secret
+    let repos: Vec<StoredRepo> = ctx
+        .data
+        .read()
+        .await
+        .repos
+        .iter()
+        .filter(|repo| names(repo, &full_name))
+        .cloned()
+        .collect();
+    let secret = repos
+        .iter()
+        .find_map(|repo| repo.webhook_secret_env.as_ref())
+        .and_then(|name| std::env::var(name).ok())
+        .or_else(|| ctx.webhook_secret.clone());
+    let Some(secret) = secret.filter(|s| !s.is_empty()) else {
+        warn!("Refusing webhook delivery {} for {}: no webhook secret is configured", delivery_id, full_name);
+        return (401, "No webhook secret is configured for this repository".to_string());
+    };
+    let signature = headers.get("x-hub-signature-256").map(String::as_str).unwrap_or_default();
+    if !verify(secret.as_bytes(), body, signature) {
+        warn!("Refusing webhook delivery {} from {}: bad signature", delivery_id, peer);
+        return (401, "Signature does not match".to_string());
+    }
+    if event == "ping" {
+        info!("Webhook ping from {}", full_name);
+        return (200, "pong".to_string());
+    }
+
+    let mut delivery = WebhookDelivery {
+        id: delivery_id,
+        received_at: chrono::Utc::now().to_rfc3339(),
+        event: event.clone(),
+        repo: full_name,
+        git_ref: None,
+        commit_sha: None,
+        build_ids: Vec::new(),
+        outcome: String::new(),
+    };
+    let (status, body) = start_builds(ctx, peer, &event, &payload, &repos, &mut delivery).await;
+    delivery.outcome = body.clone();
+    info!("Webhook delivery {}: {}", delivery.id, delivery.outcome);
+    if let Err(e) = ctx.history.record_webhook_delivery(delivery, KEPT_DELIVERIES).await {
+        warn!("Failed to record webhook delivery: {:#}", e);
+    }
+    (status, body)
+}
+
+/// Starts the builds `payload` triggers, noting them in `delivery`
+async fn start_builds(
+    ctx: &Arc<ServerContext>,
+    peer: SocketAddr,
+    event: &str,
+    payload: &serde_json::Value,
+    repos: &[StoredRepo],
+    delivery: &mut WebhookDelivery,
+) -> (u16, String) {
+    let text = |pointer: &str| payload.pointer(pointer).and_then(|v| v.as_str()).map(str::to_string);
+    let (trigger_event, git_ref) = match event {
+        "push" => {
+            if payload.get("deleted").and_then(|v| v.as_bool()).unwrap_or(false) {
+                return (204, "Ignored: the ref was deleted".to_string());
+            }
+            let full_ref = text("/ref").unwrap_or_default();
+            delivery.git_ref = Some(full_ref.clone());
+            delivery.commit_sha = text("/after");
+            let git_ref = if let Some(tag) = full_ref.strip_prefix("refs/tags/") {
+                Ref::Tag(tag.to_string())
+            } else if let Some(branch) = full_ref.strip_prefix("refs/heads/") {
+                Ref::Branch(branch.to_string())
+            } else {
+                return (204, format!("Ignored: unknown ref {}", full_ref));
+            };
+            (TriggerEvent::Push, git_ref)
+        }
+        "release" => {
+            let action = text("/action").unwrap_or_default();
+            if action != "published" {
+                return (204, format!("Ignored: release {}", action));
+            }
+            let Some(tag) = text("/release/tag_name") else {
+                return (400, "The release has no tag_name".to_string());
+            };
+            delivery.git_ref = Some(format!("refs/tags/{}", tag));
+            (TriggerEvent::Release, Ref::Tag(tag))
+        }
+        other => return (204, format!("Ignored: {} events do not trigger builds", other)),
+    };
+
+    if repos.is_empty() {
+        return (204, format!("No stored repo is {}", delivery.repo));
+    }
+    let workflows: Vec<_> = ctx
+        .data
+        .read()
+        .await
+        .workflows
+        .iter()
+        .filter(|w| w.repo_id.as_ref().is_some_and(|id| repos.iter().any(|r| &r.id == id)))
+        .filter(|w| w.triggers.iter().any(|t| t.matches(trigger_event, &git_ref)))
+        .cloned()
+        .collect();
+    if workflows.is_empty() {
+        return (204, "No workflow trigger matched".to_string());
+    }
+    if ctx.read_only || ctx.shutting_down.load(Ordering::Relaxed) {
+        return (503, "The server does not start builds now".to_string());
+    }
+
+    let mut notes = Vec::new();
+    for workflow in workflows {
+        let build = match build_for(&workflow, &git_ref, delivery.commit_sha.as_deref()) {
+            Ok(build) => build,
+            Err(e) => {
+                notes.push(format!("{}: {:#}", workflow.name, e));
+                continue;
+            }
+        };
+        let build_id = build.build_id.clone();
+        let entry = audit::AuditEntry::new("BuildStart", &build_id, None, &format!("github-webhook {}", peer));
+        if let Err(e) = ctx.audit.record(entry).await {
+            warn!("Failed to write audit log: {}", e);
+        }
+        match builds::submit(ctx, build, ctx.github_token.clone(), None).await {
+            builds::Submission::Rejected { running_build_id } => {
+                notes.push(format!("{}: build {} is already running", workflow.name, running_build_id));
+            }
+            _ => {
+                notes.push(format!("{}: build {}", workflow.name, build_id));
+                delivery.build_ids.push(build_id);
+            }
+        }
+    }
+    let status = if delivery.build_ids.is_empty() { 409 } else { 202 };
+    (status, notes.join("\n"))
+}
+
+/// The build of `workflow` a delivery about `git_ref` starts. A tag such as
+/// v1.4.2 names the version it builds; a push to a branch builds the commit
+/// it delivered, not the branch's head, which a later push may have moved.
+fn build_for(workflow: &StoredWorkflow, git_ref: &Ref, commit_sha: Option<&str>) -> Result<BuildStartPayload> {
+    let (name, version) = match git_ref {
+        Ref::Branch(branch) => (branch, workflow.next_version.clone()),
+        Ref::Tag(tag) => (tag, tag.strip_prefix('v').unwrap_or(tag).to_string()),
+    };
+    let mut build = builds::from_workflow(workflow, version, Some(name.clone()), HashMap::new(), false)?;
+    match git_ref {
+        Ref::Branch(_) => build.commit = commit_sha.map(str::to_string),
+        Ref::Tag(tag) => build.tag = Some(tag.clone()),
+    }
+    Ok(build)
+}
+
+/// Whether `repo` is the GitHub repository `full_name`
+fn names(repo: &StoredRepo, full_name: &str) -> bool {
+    match (&repo.owner, &repo.repo) {
+        (Some(owner), Some(name)) => format!("{}/{}", owner, name).eq_ignore_ascii_case(full_name),
+        _ => false,
+    }
+}
+
+/// Checks `signature`, `sha256=<hex>`, against the HMAC of `body`, in
+/// constant time so it cannot be guessed byte by byte
+fn verify(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let Some(Ok(given)) = signature.strip_prefix("sha256=").map(hex::decode) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret) else {
+        return false;
+    };
+    mac.update(body);
+    mac.verify_slice(&given).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "It's a Secret to Everybody";
+    const AFTER: &str = "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c";
+
+    fn sign(body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn rfc_4231_vectors_verify() {
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (&[0x0b; 20], b"Hi There", "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            (b"Jefe", b"what do ya want for nothing?", "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            // A key longer than a block is hashed first
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ];
+        for (key, data, mac) in cases {
+            assert!(verify(key, data, &format!("sha256={}", mac)));
+            assert!(!verify(key, b"something else", &format!("sha256={}", mac)));
+        }
+    }
+
+    #[test]
+    fn a_github_signature_verifies() {
+        // The example from GitHub's guide to validating deliveries
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(verify(SECRET.as_bytes(), b"Hello, World!", signature));
+        assert!(!verify(b"another secret", b"Hello, World!", signature));
+        assert!(!verify(SECRET.as_bytes(), b"Hello, World!", &signature[..70]));
+        assert!(!verify(SECRET.as_bytes(), b"Hello, World!", signature.strip_prefix("sha256=").unwrap()));
+    }
+
+    async fn context() -> (Arc<ServerContext>, crate::process::TempDir) {
+        let (ctx, dir) = crate::tests::context(&["--webhook-secret", SECRET]).await;
+        {
+            let mut data = ctx.data.write().await;
+            data.repos.push(
+                serde_json::from_value(serde_json::json!({
+                    "id": "repo-1",
+                    "path": dir.0.join("app"),
+                    "owner": "octo",
+                    "repo": "app",
+                    "default_branch": "main",
+                    "cloned_at": null,
+                }))
+                .unwrap(),
+            );
+            data.workflows.push(workflow());
+        }
+        (ctx, dir)
+    }
+
+    fn workflow() -> StoredWorkflow {
+        serde_json::from_value(serde_json::json!({
+            "id": "workflow-1",
+            "name": "App",
+            "repo_id": "repo-1",
+            "nodes": [],
+            "connections": [],
+            "next_version": "1.0.0",
+            "triggers": [{ "event": "push", "branches": ["main"] }],
+            "created_at": "",
+            "updated_at": "",
+        }))
+        .unwrap()
+    }
+
+    fn push(branch: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "ref": format!("refs/heads/{}", branch),
+            "after": AFTER,
+            "repository": { "full_name": "octo/app" },
+        }))
+        .unwrap()
+    }
+
+    async fn post(ctx: &Arc<ServerContext>, body: &[u8], signature: Option<&str>) -> u16 {
+        let mut headers = HashMap::from([
+            ("x-github-event".to_string(), "push".to_string()),
+            ("x-github-delivery".to_string(), uuid::Uuid::new_v4().to_string()),
+        ]);
+        if let Some(signature) = signature {
+            headers.insert("x-hub-signature-256".to_string(), signature.to_string());
+        }
+        deliver(ctx, "127.0.0.1:40000".parse().unwrap(), &headers, body).await.0
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_answered_by_signature_and_trigger() {
+        let (ctx, _dir) = context().await;
+        let main = push("main");
+        assert_eq!(post(&ctx, &main, None).await, 401);
+        let mut tampered = main.clone();
+        tampered.push(b' ');
+        assert_eq!(post(&ctx, &tampered, Some(&sign(&main))).await, 401);
+
+        let dev = push("dev");
+        assert_eq!(post(&ctx, &dev, Some(&sign(&dev))).await, 204);
+        assert_eq!(post(&ctx, &main, Some(&sign(&main))).await, 202);
+    }
+
+    #[test]
+    fn a_push_builds_the_commit_it_delivered() {
+        let build = build_for(&workflow(), &Ref::Branch("main".to_string()), Some(AFTER)).unwrap();
+        assert_eq!(build.git_ref.as_deref(), Some("main"));
+        assert_eq!(build.commit.as_deref(), Some(AFTER));
+        assert_eq!((build.version.as_str(), build.tag), ("1.0.0", None));
+    }
+
+    #[test]
+    fn a_tag_builds_its_version() {
+        let build = build_for(&workflow(), &Ref::Tag("v1.4.2".to_string()), Some(AFTER)).unwrap();
+        assert_eq!(build.git_ref.as_deref(), Some("v1.4.2"));
+        assert_eq!(build.commit, None);
+        assert_eq!((build.version.as_str(), build.tag.as_deref()), ("1.4.2", Some("v1.4.2")));
+    }
+}
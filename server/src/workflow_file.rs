@@ -0,0 +1,451 @@
+//! Workflows as YAML files, to review and commit next to a repository.
+//!
+//! `ExportWorkflow` writes a workflow as one self-contained document: its
+//! settings, nodes and connections, and the actions its action nodes run,
+//! inlined. What only means something on this server, the repository and
+//! the timestamps, is left out. `ImportWorkflow` validates such a document
+//! and upserts it. An id the server already uses for something else is
+//! given a new one, with action nodes following their action, unless
+//! `overwrite` says to replace what is there.
+//!
+//! `format_version` is read before anything else, so a file from a newer
+//! server is refused instead of read with its new fields dropped. Errors name
+//! the field at fault, and the line for anything the YAML parser rejects.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::builds::ConcurrencyPolicy;
+use crate::container::ContainerConfig;
+use crate::matrix::EdgeWait;
+use crate::webhooks::Trigger;
+use crate::{actions, github_actions, requirements, ServerContext, ServerData, StoredAction, StoredWorkflow};
+
+/// Version of the format written by this server
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkflowDocument {
+    pub format_version: u32,
+    pub id: String,
+    pub name: String,
+    pub next_version: String,
+    #[serde(default)]
+    pub concurrency: ConcurrencyPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<ContainerConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<requirements::Spec>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub triggers: Vec<Trigger>,
+    pub nodes: Vec<DocumentNode>,
+    #[serde(default)]
+    pub connections: Vec<DocumentConnection>,
+    /// The actions the action nodes run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<DocumentAction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentNode {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub node_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Where the app draws it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<serde_json::Value>,
+    #[serde(default)]
+    pub config: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DocumentConnection {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub from: String,
+    pub to: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait: Option<EdgeWait>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DocumentAction {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub script: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interpreter: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<requirements::Spec>,
+}
+
+impl From<&StoredAction> for DocumentAction {
+    fn from(action: &StoredAction) -> Self {
+        Self {
+            id: action.id.clone(),
+            name: action.name.clone(),
+            description: action.description.clone(),
+            script: action.script.clone(),
+            inputs: action.inputs.clone(),
+            outputs: action.outputs.clone(),
+            interpreter: action.interpreter.clone(),
+            timeout_secs: action.timeout_secs,
+            requires: action.requires.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowExportPayload {
+    pub workflow_id: String,
+    /// Suggested file name
+    pub file_name: String,
+    pub yaml: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportWorkflowRequest {
+    pub yaml: String,
+    /// Replace the workflow and actions with the same ids instead of adding
+    /// copies with new ids
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowImportResult {
+    pub workflow_id: String,
+    pub name: String,
+    /// Whether a workflow with the document's id was replaced
+    pub replaced: bool,
+    /// Ids given to the workflow and actions whose own were taken, as
+    /// `workflow:<old> -> <new>` or `action:<old> -> <new>`
+    pub remapped: Vec<String>,
+    /// Ids of the actions added or replaced; actions already on the server
+    /// as they are in the file are left alone
+    pub actions: Vec<String>,
+}
+
+/// The workflow `id_or_name` of `data` as a YAML document
+pub fn export(data: &ServerData, id_or_name: &str) -> Result<WorkflowExportPayload> {
+    let workflow = data.workflow(id_or_name)?;
+    let nodes = workflow
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| {
+            serde_json::from_value::<DocumentNode>(node.clone())
+                .with_context(|| format!("Node {} of {} is not valid", i + 1, workflow.name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // The app stores connections as from/to
+    let connections = workflow
+        .connections
+        .iter()
+        .map(|connection| {
+            let end = |key: &str, alias: &str| {
+                connection
+                    .get(key)
+                    .or_else(|| connection.get(alias))
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string)
+                    .context("A connection of the workflow does not name both of its nodes")
+            };
+            Ok(DocumentConnection {
+                id: connection.get("id").and_then(|v| v.as_str()).map(str::to_string),
+                from: end("from", "source")?,
+                to: end("to", "target")?,
+                wait: connection.get("wait").cloned().and_then(|wait| serde_json::from_value(wait).ok()),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let used: Vec<&str> = nodes
+        .iter()
+        .filter(|node| node.node_type == "action")
+        .filter_map(|node| node.config.get("action_id")?.as_str())
+        .collect();
+    let actions = data
+        .actions
+        .iter()
+        .filter(|a| used.contains(&a.id.as_str()))
+        .map(DocumentAction::from)
+        .collect();
+
+    let document = WorkflowDocument {
+        format_version: FORMAT_VERSION,
+        id: workflow.id.clone(),
+        name: workflow.name.clone(),
+        next_version: workflow.next_version.clone(),
+        concurrency: workflow.concurrency,
+        container: workflow.container.clone(),
+        requires: workflow.requires.clone(),
+        triggers: workflow.triggers.clone(),
+        nodes,
+        connections,
+        actions,
+    };
+    let yaml = format!(
+        "# BuildForge workflow, import it with ImportWorkflow\n{}",
+        serde_yaml::to_string(&document)?
+    );
+    Ok(WorkflowExportPayload {
+        workflow_id: workflow.id.clone(),
+        file_name: format!("{}.buildforge.yml", github_actions::slug(&workflow.name, '-')),
+        yaml,
+    })
+}
+
+/// Reads and checks a document, without looking at what the server has
+pub fn parse(yaml: &str) -> Result<WorkflowDocument> {
+    let value: serde_yaml::Value = serde_yaml::from_str(yaml).map_err(|e| anyhow::anyhow!("Not valid YAML: {}", e))?;
+    match value.get("format_version") {
+        None => anyhow::bail!("format_version: missing; is this a BuildForge workflow file?"),
+        Some(version) => match version.as_u64() {
+            Some(version) if version > u64::from(FORMAT_VERSION) => anyhow::bail!(
+                "format_version: {} is newer than this server understands ({}); upgrade the BuildForge server",
+                version,
+                FORMAT_VERSION
+            ),
+            Some(version) if version > 0 => {}
+            _ => anyhow::bail!("format_version: must be a whole number from 1 to {}", FORMAT_VERSION),
+        },
+    }
+    let document: WorkflowDocument = serde_yaml::from_str(yaml).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    if document.id.trim().is_empty() {
+        anyhow::bail!("id: must not be empty");
+    }
+    if document.name.trim().is_empty() {
+        anyhow::bail!("name: must not be empty");
+    }
+    if document.next_version.trim().is_empty() {
+        anyhow::bail!("next_version: must not be empty");
+    }
+    let mut node_index: HashMap<&str, usize> = HashMap::new();
+    for (i, node) in document.nodes.iter().enumerate() {
+        if node.id.trim().is_empty() {
+            anyhow::bail!("nodes[{}].id: must not be empty", i);
+        }
+        if let Some(first) = node_index.insert(&node.id, i) {
+            anyhow::bail!("nodes[{}].id: {} is the id of nodes[{}] too", i, node.id, first);
+        }
+        if node.node_type.trim().is_empty() {
+            anyhow::bail!("nodes[{}].type: must not be empty", i);
+        }
+        if !(node.config.is_object() || node.config.is_null()) {
+            anyhow::bail!("nodes[{}].config: must be a mapping", i);
+        }
+        if node.node_type == "action" && node.config.get("action_id").and_then(|v| v.as_str()).is_none() {
+            anyhow::bail!("nodes[{}].config.action_id: an action node must name its action", i);
+        }
+        crate::check_node_config(&node.node_type, &node.config).map_err(|e| anyhow::anyhow!("nodes[{}].config: {:#}", i, e))?;
+    }
+    for (i, connection) in document.connections.iter().enumerate() {
+        if !node_index.contains_key(connection.from.as_str()) {
+            anyhow::bail!("connections[{}].from: no node has the id {}", i, connection.from);
+        }
+        if !node_index.contains_key(connection.to.as_str()) {
+            anyhow::bail!("connections[{}].to: no node has the id {}", i, connection.to);
+        }
+    }
+    for (i, trigger) in document.triggers.iter().enumerate() {
+        trigger.validate().map_err(|e| anyhow::anyhow!("triggers[{}]: {:#}", i, e))?;
+    }
+    let mut action_index: HashMap<&str, usize> = HashMap::new();
+    for (i, action) in document.actions.iter().enumerate() {
+        if action.id.trim().is_empty() {
+            anyhow::bail!("actions[{}].id: must not be empty", i);
+        }
+        if let Some(first) = action_index.insert(&action.id, i) {
+            anyhow::bail!("actions[{}].id: {} is the id of actions[{}] too", i, action.id, first);
+        }
+        actions::validate(&stored_action(action, 0))
+            .map_err(|e| anyhow::anyhow!("actions[{}] ({}): {:#}", i, action.name, e))?;
+    }
+    Ok(document)
+}
+
+/// Validates `request.yaml` and upserts its workflow and actions. Nothing is
+/// changed unless all of it can be imported.
+pub async fn import(ctx: &ServerContext, request: &ImportWorkflowRequest) -> Result<WorkflowImportResult> {
+    let mut document = parse(&request.yaml)?;
+    let mut remapped = Vec::new();
+
+    let mut data = ctx.data.write().await;
+    // Which actions to store, and the ids action nodes should use
+    let mut saved = Vec::new();
+    let mut action_ids: HashMap<String, String> = HashMap::new();
+    for action in &document.actions {
+        let existing = data.actions.iter().find(|a| a.id == action.id);
+        let id = match existing {
+            None => {
+                saved.push(stored_action(action, 1));
+                action.id.clone()
+            }
+            Some(existing) if serde_json::to_value(DocumentAction::from(existing))? == serde_json::to_value(action)? => {
+                action.id.clone()
+            }
+            Some(existing) if request.overwrite => {
+                let mut replacement = stored_action(action, existing.version + 1);
+                replacement.created_at = existing.created_at.clone();
+                saved.push(replacement);
+                action.id.clone()
+            }
+            Some(_) => {
+                let mut copy = stored_action(action, 1);
+                copy.id = uuid::Uuid::new_v4().to_string();
+                remapped.push(format!("action:{} -> {}", action.id, copy.id));
+                let id = copy.id.clone();
+                saved.push(copy);
+                id
+            }
+        };
+        action_ids.insert(action.id.clone(), id);
+    }
+    for (i, node) in document.nodes.iter_mut().enumerate() {
+        if node.config.is_null() {
+            node.config = serde_json::json!({});
+        }
+        if node.node_type != "action" {
+            continue;
+        }
+        let action_id = node.config["action_id"].as_str().unwrap_or_default().to_string();
+        match action_ids.get(&action_id) {
+            Some(id) => node.config["action_id"] = serde_json::Value::String(id.clone()),
+            None if data.actions.iter().any(|a| a.id == action_id) => {}
+            None => anyhow::bail!(
+                "nodes[{}].config.action_id: action {} is neither in the file nor on the server",
+                i,
+                action_id
+            ),
+        }
+    }
+
+    let now = chrono::Utc::now().to_rfc3339();
+    let existing = data.workflows.iter().position(|w| w.id == document.id);
+    let mut workflow = StoredWorkflow {
+        id: document.id.clone(),
+        name: document.name,
+        repo_id: None,
+        nodes: document.nodes.iter().map(serde_json::to_value).collect::<Result<_, _>>()?,
+        connections: document
+            .connections
+            .iter()
+            .map(|connection| -> Result<serde_json::Value> {
+                let mut value = serde_json::to_value(connection)?;
+                if connection.id.is_none() {
+                    value["id"] = serde_json::Value::String(uuid::Uuid::new_v4().to_string());
+                }
+                Ok(value)
+            })
+            .collect::<Result<_>>()?,
+        next_version: document.next_version,
+        concurrency: document.concurrency,
+        container: document.container,
+        requires: document.requires,
+        triggers: document.triggers,
+        created_at: now.clone(),
+        updated_at: now,
+    };
+    let replaced = match existing {
+        Some(index) if request.overwrite => {
+            // The repository is this server's, so the one already set stays
+            let old = &data.workflows[index];
+            workflow.repo_id = old.repo_id.clone();
+            workflow.created_at = old.created_at.clone();
+            data.workflows[index] = workflow.clone();
+            true
+        }
+        Some(_) => {
+            workflow.id = uuid::Uuid::new_v4().to_string();
+            remapped.push(format!("workflow:{} -> {}", document.id, workflow.id));
+            data.workflows.push(workflow.clone());
+            false
+        }
+        None => {
+            data.workflows.push(workflow.clone());
+            false
+        }
+    };
+    for action in &saved {
+        match data.actions.iter_mut().find(|a| a.id == action.id) {
+            Some(existing) => *existing = action.clone(),
+            None => data.actions.push(action.clone()),
+        }
+    }
+    ctx.persistence.mark_dirty();
+    drop(data);
+
+    for action in &saved {
+        if let Err(e) = ctx.history.save_action_version(action.clone(), actions::KEPT_VERSIONS).await {
+            tracing::error!("Failed to keep the imported action version: {:#}", e);
+        }
+    }
+    Ok(WorkflowImportResult {
+        workflow_id: workflow.id,
+        name: workflow.name,
+        replaced,
+        remapped,
+        actions: saved.into_iter().map(|a| a.id).collect(),
+    })
+}
+
+fn stored_action(action: &DocumentAction, version: u64) -> StoredAction {
+    let now = chrono::Utc::now().to_rfc3339();
+    StoredAction {
+        id: action.id.clone(),
+        name: action.name.clone(),
+        description: action.description.clone(),
+        script: action.script.clone(),
+        inputs: action.inputs.clone(),
+        outputs: action.outputs.clone(),
+        version,
+        interpreter: action.interpreter.clone(),
+        timeout_secs: action.timeout_secs,
+        requires: action.requires.clone(),
+        created_at: now.clone(),
+        updated_at: now,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(config: &str) -> String {
+        format!(
+            "format_version: 1\nid: wf\nname: App\nnext_version: 1.0.0\nnodes:\n  - id: build\n    type: command\n    config: {}\n",
+            config
+        )
+    }
+
+    #[test]
+    fn refuses_a_command_node_with_both_a_command_and_a_program() {
+        let e = parse(&document("{command: cargo build, program: cargo}")).unwrap_err();
+        assert_eq!(
+            format!("{:#}", e),
+            "nodes[0].config: a command node runs either a command or a program, not both"
+        );
+    }
+
+    #[test]
+    fn keeps_exec_form_arguments_as_written() {
+        let parsed = parse(&document("{program: cargo, args: [build, \"--features\", \"a b\", \"$TARGET\"]}")).unwrap();
+        assert_eq!(parsed.nodes[0].config["args"], serde_json::json!(["build", "--features", "a b", "$TARGET"]));
+    }
+}
@@ -0,0 +1,174 @@
+//! Per-build copies of the directory a workflow builds in, used when
+//! `isolated_workspaces` is set so two builds of the same repo, or a build and
+//! someone editing the repo by hand, never share files.
+//!
+//! Git working copies get a detached `git worktree` at the commit that is
+//! checked out; other directories are copied. Workspaces live in
+//! `data_dir/workspaces/<build_id>` and are removed when the build ends, even
+//! if it is cancelled or its task goes away.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::build_log::BuildLog;
+
+pub struct Workspace {
+    path: PathBuf,
+    /// Repo the worktree belongs to; None for copies
+    repo: Option<PathBuf>,
+    /// Set once the workspace was removed or is deliberately kept
+    done: bool,
+}
+
+/// Directory holding every workspace
+pub fn root(data_dir: &Path) -> PathBuf {
+    data_dir.join("workspaces")
+}
+
+impl Workspace {
+    /// Creates the workspace of `build_id` from `source`
+    pub async fn create(data_dir: &Path, build_id: &str, source: &Path, log: &BuildLog) -> Result<Self> {
+        let root = root(data_dir);
+        tokio::fs::create_dir_all(&root).await?;
+        let path = root.join(build_id);
+
+        if is_git_work_tree(source).await {
+            log.line(&format!("[workspace] Creating worktree {}", path.display())).await;
+            let output = Command::new("git")
+                .arg("-C")
+                .arg(source)
+                .args(["worktree", "add", "--detach"])
+                .arg(&path)
+                .arg("HEAD")
+                .output()
+                .await?;
+            if !output.status.success() {
+                anyhow::bail!(
+                    "Failed to create worktree: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            return Ok(Self {
+                path,
+                repo: Some(source.to_path_buf()),
+                done: false,
+            });
+        }
+
+        log.line(&format!("[workspace] Copying {} to {}", source.display(), path.display())).await;
+        let workspace = Self {
+            path: path.clone(),
+            repo: None,
+            done: false,
+        };
+        // The data directory may sit inside the source, never copy it into itself
+        let exclude = data_dir.canonicalize()?;
+        let source = source.to_path_buf();
+        tokio::task::spawn_blocking(move || copy_dir(&source, &path, &exclude))
+            .await?
+            .context("Failed to copy the working directory")?;
+        Ok(workspace)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Leaves the workspace on disk, e.g. to debug a failed build
+    pub fn keep(mut self) {
+        info!("Keeping workspace {}", self.path.display());
+        self.done = true;
+    }
+
+    pub async fn remove(mut self) {
+        self.done = true;
+        let (path, repo) = (self.path.clone(), self.repo.clone());
+        if let Err(e) = tokio::task::spawn_blocking(move || remove(&path, repo.as_deref())).await {
+            warn!("Failed to remove workspace {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        // The build went away without cleaning up, e.g. its task was aborted
+        let (path, repo) = (self.path.clone(), self.repo.clone());
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || remove(&path, repo.as_deref()));
+            }
+            Err(_) => remove(&path, repo.as_deref()),
+        }
+    }
+}
+
+/// Deletes a workspace and unregisters its worktree, logging failures
+fn remove(path: &Path, repo: Option<&Path>) {
+    info!("Removing workspace {}", path.display());
+    if let Err(e) = std::fs::remove_dir_all(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove workspace {}: {}", path.display(), e);
+        }
+    }
+    if let Some(repo) = repo {
+        let pruned = std::process::Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args(["worktree", "prune"])
+            .status();
+        if !pruned.is_ok_and(|s| s.success()) {
+            warn!("Failed to prune worktrees of {}", repo.display());
+        }
+    }
+}
+
+async fn is_git_work_tree(dir: &Path) -> bool {
+    Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .output()
+        .await
+        .is_ok_and(|o| o.status.success())
+}
+
+/// Copies `from` into `to` recursively, skipping `exclude`
+fn copy_dir(from: &Path, to: &Path, exclude: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let source = entry.path();
+        if source.canonicalize().is_ok_and(|p| p == exclude) {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&source, &target, exclude)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&source, &target)?;
+        } else {
+            std::fs::copy(&source, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(source: &Path, target: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(std::fs::read_link(source)?, target)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(source: &Path, target: &Path) -> std::io::Result<()> {
+    if source.is_dir() {
+        return Ok(());
+    }
+    std::fs::copy(source, target).map(|_| ())
+}
@@ -0,0 +1,127 @@
+//! What `commands::cancel` makes of the server's answers to a
+//! `BuildCancel`, apart from Tauri so it can be tested on its own. The
+//! server answers a cancel with `BuildNotCancelled` when there was nothing
+//! to cancel; otherwise the build's `BuildComplete` is the answer.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+/// Finished builds remembered, so the oldest are forgotten as new ones finish
+const FINISHED_BUILDS: usize = 100;
+
+/// Why the server did not cancel a build
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotCancelledReason {
+    /// The server has no build with this id
+    NotFound,
+    /// The build finished before the cancel arrived
+    AlreadyFinished,
+}
+
+/// Builds the server has reported finished, so cancelling one again is not
+/// an error
+#[derive(Debug, Default)]
+pub struct Finished(VecDeque<String>);
+
+impl Finished {
+    /// Notes that `build_id` finished, and says whether it was news
+    pub fn record(&mut self, build_id: &str) -> bool {
+        if self.contains(build_id) {
+            return false;
+        }
+        if self.0.len() == FINISHED_BUILDS {
+            self.0.pop_front();
+        }
+        self.0.push_back(build_id.to_string());
+        true
+    }
+
+    pub fn contains(&self, build_id: &str) -> bool {
+        self.0.iter().any(|id| id == build_id)
+    }
+}
+
+/// A message from the server that may answer a cancel
+#[derive(Debug, Clone, Copy)]
+pub enum Reply<'a> {
+    /// The `BuildComplete` of the named build
+    Complete(&'a str),
+    /// A `BuildNotCancelled` for the named build
+    NotCancelled { build_id: &'a str, reason: NotCancelledReason },
+    /// A `ReadOnly` refusal of the named action
+    Refused(&'a str),
+}
+
+/// How cancelling `build_id` ended, if `reply` says; `finished` is whether
+/// the build had already finished by then
+pub fn outcome(reply: Reply<'_>, build_id: &str, finished: bool) -> Option<Result<(), String>> {
+    match reply {
+        Reply::Complete(id) if id == build_id => Some(Ok(())),
+        Reply::NotCancelled { build_id: id, reason } if id == build_id => {
+            Some(if finished || reason == NotCancelledReason::AlreadyFinished {
+                Ok(())
+            } else {
+                Err(format!("Build {} is not running on the server", build_id))
+            })
+        }
+        Reply::Refused(action) => Some(Err(format!("The server is read-only and refused {}", action))),
+        Reply::Complete(_) | Reply::NotCancelled { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn not_cancelled(build_id: &str, reason: NotCancelledReason) -> Reply<'_> {
+        Reply::NotCancelled { build_id, reason }
+    }
+
+    #[test]
+    fn the_build_completing_ends_the_cancel() {
+        assert_eq!(outcome(Reply::Complete("b1"), "b1", false), Some(Ok(())));
+        assert_eq!(outcome(Reply::Complete("b2"), "b1", false), None);
+    }
+
+    #[test]
+    fn an_unknown_build_is_an_error() {
+        let reply = not_cancelled("b1", NotCancelledReason::NotFound);
+        assert_eq!(outcome(reply, "b1", false), Some(Err("Build b1 is not running on the server".to_string())));
+    }
+
+    #[test]
+    fn cancelling_a_finished_build_again_is_not_an_error() {
+        let reply = not_cancelled("b1", NotCancelledReason::AlreadyFinished);
+        assert_eq!(outcome(reply, "b1", false), Some(Ok(())));
+
+        let mut finished = Finished::default();
+        assert!(finished.record("b1"));
+        assert!(!finished.record("b1"));
+        let reply = not_cancelled("b1", NotCancelledReason::NotFound);
+        assert_eq!(outcome(reply, "b1", finished.contains("b1")), Some(Ok(())));
+    }
+
+    #[test]
+    fn answers_about_other_builds_are_ignored() {
+        assert_eq!(outcome(not_cancelled("b2", NotCancelledReason::NotFound), "b1", false), None);
+    }
+
+    #[test]
+    fn a_read_only_server_refuses() {
+        let refused = outcome(Reply::Refused("BuildCancel"), "b1", false);
+        assert_eq!(refused, Some(Err("The server is read-only and refused BuildCancel".to_string())));
+    }
+
+    #[test]
+    fn only_the_latest_finished_builds_are_remembered() {
+        let mut finished = Finished::default();
+        for i in 0..=FINISHED_BUILDS {
+            assert!(finished.record(&format!("b{}", i)));
+        }
+        assert!(!finished.contains("b0"));
+        assert!(finished.contains("b1"));
+        assert!(finished.contains(&format!("b{}", FINISHED_BUILDS)));
+    }
+}
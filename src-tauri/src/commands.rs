@@ -1,4 +1,14 @@
-use crate::server::{ServerConnection, ServerStatus};
+use crate::server::{
+    parse_chunk, BuildArtifact, BuildEdge, BuildHistoryPage, BuildHistoryQuery, BuildLogsQuery, BuildNode,
+    BuildStartPayload, DataImportResult, DownloadArtifactRequest, ExportDataRequest, GetArtifactsQuery,
+    GitHubActionsExport, GitHubActionsImport, ImportDataRequest, ImportGitHubActionsRequest, ImportWorkflowRequest,
+    RequestError, ServerConnection, ServerMessage, ServerSession, ServerSettings, ServerStatus, ServerStatusReport,
+    SettingsPayload, WorkflowExportPayload, WorkflowImportResult,
+};
+use crate::cancel::{self, Reply};
+use crate::health::ServerHealth;
+use crate::notifications::Notifier;
+use crate::tray::{CloseBehavior, Tray};
 use crate::AppState;
 use notify_rust::Notification;
 use serde::{Deserialize, Serialize};
@@ -12,13 +22,18 @@ pub struct ConnectServerRequest {
     pub port: u16,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StartBuildRequest {
     pub server_id: String,
+    #[serde(default)]
+    pub workflow_id: String,
     pub project_name: String,
     pub version: String,
     pub nodes: Vec<serde_json::Value>,
     pub edges: Vec<serde_json::Value>,
+    /// Only if the user chose to let the server use their GitHub token
+    #[serde(default)]
+    pub github_token: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,16 +47,16 @@ pub struct GitHubUser {
 #[tauri::command]
 pub async fn connect_server(
     request: ConnectServerRequest,
+    app_handle: tauri::AppHandle,
     state: State<'_, AppState>,
 ) -> Result<ServerConnection, String> {
-    let mut server = ServerConnection::new(request.name, request.address, request.port);
-    
-    server.connect().await?;
+    let server = ServerConnection::new(request.name, request.address, request.port);
+    let session = ServerSession::connect(app_handle, server).await?;
     
     let mut servers = state.servers.lock().await;
-    servers.push(server.clone());
+    servers.push(session.clone());
     
-    Ok(server)
+    Ok(session.info())
 }
 
 #[tauri::command]
@@ -51,69 +66,816 @@ pub async fn disconnect_server(
 ) -> Result<(), String> {
     let mut servers = state.servers.lock().await;
     
-    if let Some(server) = servers.iter_mut().find(|s| s.id == server_id) {
-        server.disconnect();
+    if let Some(session) = servers.iter().find(|s| s.id() == server_id) {
+        session.close();
     }
     
     Ok(())
 }
 
+/// Sends a BuildStart to the server and returns the build id once the server
+/// has started or queued it. Its progress, logs and result come to the
+/// frontend as `build:<build_id>` events.
 #[tauri::command]
-pub async fn start_build(
-    request: StartBuildRequest,
-    state: State<'_, AppState>,
-) -> Result<String, String> {
-    let servers = state.servers.lock().await;
-    
-    let server = servers
-        .iter()
-        .find(|s| s.id == request.server_id)
-        .ok_or("Server not found")?;
-    
-    if server.status != ServerStatus::Online {
-        return Err("Server is not online".to_string());
+pub async fn start_build(request: StartBuildRequest, app_handle: tauri::AppHandle) -> Result<String, String> {
+    submit_build(&app_handle, request).await
+}
+
+/// What `start_build` does, for the tray's "Run again" too. A build that
+/// starts becomes the one "Run again" repeats.
+pub async fn submit_build(app: &tauri::AppHandle, request: StartBuildRequest) -> Result<String, String> {
+    use tauri::Manager;
+
+    let state = app.state::<AppState>();
+    let tray = app.state::<Tray>();
+    // Without the token, which is not written to disk
+    let repeat = StartBuildRequest {
+        github_token: None,
+        ..request.clone()
+    };
+    let session = find_session(&state, &request.server_id).await?;
+    let server = session.info();
+    match server.status {
+        ServerStatus::Online => {}
+        ServerStatus::Connecting => return Err(format!("Not connected to {} yet; try again once it is online", server.name)),
+        ServerStatus::Offline => return Err(format!("Not connected to {}", server.name)),
     }
     
-    // Generate build ID
     let build_id = uuid::Uuid::new_v4().to_string();
-    
-    // In a real implementation, this would send the build request over WebSocket
-    // For now, we just return the build ID
-    
+    let nodes = request
+        .nodes
+        .iter()
+        .map(|node| -> Result<BuildNode, String> {
+            let text = |key: &str| node.get(key).and_then(|v| v.as_str()).map(str::to_string);
+            let id = text("id").ok_or("A node has no id")?;
+            let node_type = text("type").ok_or_else(|| format!("Node {} has no type", id))?;
+            Ok(BuildNode {
+                name: text("name").unwrap_or_else(|| node_type.clone()),
+                config: node.get("config").cloned().unwrap_or_else(|| serde_json::json!({})),
+                id,
+                node_type,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // The workflow editor stores connections as from/to
+    let edges = request
+        .edges
+        .iter()
+        .map(|edge| -> Result<BuildEdge, String> {
+            let end = |key: &str, alias: &str| {
+                edge.get(key)
+                    .or_else(|| edge.get(alias))
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string)
+                    .ok_or("A connection does not name both of its nodes")
+            };
+            Ok(BuildEdge {
+                id: edge
+                    .get("id")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string)
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+                source: end("source", "from")?,
+                target: end("target", "to")?,
+                wait: edge.get("wait").and_then(|v| v.as_str()).map(str::to_string),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    
+    tray.build_requested(&build_id, &request.project_name, &request.version);
+    let payload = BuildStartPayload {
+        build_id: build_id.clone(),
+        workflow_id: request.workflow_id,
+        on_disconnect: "continue".to_string(),
+        project_name: request.project_name,
+        version: request.version,
+        nodes,
+        edges,
+        github_token: request.github_token.filter(|token| !token.is_empty()),
+    };
+    let reply = session
+        .request(ServerMessage::BuildStart(payload), std::time::Duration::from_secs(30), |m| match m {
+            ServerMessage::BuildStarted(started) => started.build_id == build_id,
+            ServerMessage::BuildQueued(queued) => queued.build_id == build_id,
+            ServerMessage::BuildStillRunning(_) => true,
+            _ => false,
+        })
+        .await?;
+    if let ServerMessage::BuildStillRunning(running) = reply {
+        return Err(format!("The workflow is already running as build {}", running));
+    }
+    app.state::<Notifier>().build_requested(&build_id, repeat.clone());
+    tray.remember(app, repeat, &build_id);
     Ok(build_id)
 }
 
+/// Asks the server to cancel `build_id` and waits briefly for it to end.
+/// Cancelling a build that has already finished is not an error, so the UI
+/// can cancel twice; a build the server has never heard of is.
 #[tauri::command]
 pub async fn cancel_build(
-    _build_id: String,
+    build_id: String,
     server_id: String,
     state: State<'_, AppState>,
 ) -> Result<(), String> {
-    let servers = state.servers.lock().await;
-    
-    let _server = servers
+    cancel(&state, &build_id, &server_id).await
+}
+
+/// What `cancel_build` does, for the tray too
+pub async fn cancel(state: &AppState, build_id: &str, server_id: &str) -> Result<(), String> {
+    const CANCEL_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+    
+    let session = find_session(state, server_id).await?;
+    if session.has_finished(build_id) {
+        return Ok(());
+    }
+    
+    let mut incoming = session.subscribe();
+    session.send(ServerMessage::BuildCancel(build_id.to_string())).await?;
+    let ended = async {
+        loop {
+            let message = match incoming.recv().await {
+                Ok(message) => message,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                    return Err("The connection to the server has ended".to_string())
+                }
+            };
+            let reply = match &message {
+                ServerMessage::BuildComplete(complete) => Reply::Complete(&complete.build_id),
+                ServerMessage::BuildNotCancelled(answer) => Reply::NotCancelled {
+                    build_id: &answer.build_id,
+                    reason: answer.reason,
+                },
+                ServerMessage::ReadOnly(refused) => Reply::Refused(&refused.action),
+                _ => continue,
+            };
+            if let Some(outcome) = cancel::outcome(reply, build_id, session.has_finished(build_id)) {
+                return outcome;
+            }
+        }
+    };
+    // A build whose nodes take a while to stop still gets cancelled; the
+    // result arrives as its BuildComplete event
+    tokio::time::timeout(CANCEL_WAIT, ended).await.unwrap_or(Ok(()))
+}
+
+/// Whether the tray's "Run again" increases the last number of the version
+#[tauri::command]
+pub async fn set_quick_run_bump_version(
+    enabled: bool,
+    app_handle: tauri::AppHandle,
+    tray: State<'_, Tray>,
+) -> Result<(), String> {
+    tray.set_bump_version(&app_handle, enabled);
+    Ok(())
+}
+
+/// What closing the main window does: `quit` or `minimize_to_tray`
+#[tauri::command]
+pub async fn get_close_behavior(tray: State<'_, Tray>) -> Result<CloseBehavior, String> {
+    Ok(tray.close_behavior())
+}
+
+#[tauri::command]
+pub async fn set_close_behavior(
+    close_behavior: CloseBehavior,
+    app_handle: tauri::AppHandle,
+    tray: State<'_, Tray>,
+) -> Result<(), String> {
+    tray.set_close_behavior(&app_handle, close_behavior);
+    Ok(())
+}
+
+/// Turns the notifications for finished builds on or off
+#[tauri::command]
+pub async fn set_notifications_enabled(enabled: bool, notifier: State<'_, Notifier>) -> Result<(), String> {
+    notifier.set_enabled(enabled);
+    Ok(())
+}
+
+/// The build the window shows, or none; while the window has focus, that
+/// build finishing needs no notification
+#[tauri::command]
+pub async fn set_viewed_build(build_id: Option<String>, notifier: State<'_, Notifier>) -> Result<(), String> {
+    notifier.set_viewed_build(build_id);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_server_status(
+    server_id: String,
+    state: State<'_, AppState>,
+) -> Result<ServerStatusReport, String> {
+    let session = find_session(&state, &server_id).await?;
+    Ok(session.check(std::time::Duration::from_secs(2)).await)
+}
+
+/// One page of a server's build history, newest first, optionally for one
+/// workflow. Fails with a `RequestError`, so the frontend can tell an
+/// offline server from a slow one or a refused query.
+#[tauri::command]
+pub async fn get_build_history(
+    server_id: String,
+    workflow_id: Option<String>,
+    limit: usize,
+    offset: usize,
+    state: State<'_, AppState>,
+) -> Result<BuildHistoryPage, RequestError> {
+    let session = find_session(&state, &server_id).await.map_err(RequestError::UnknownServer)?;
+    let query = BuildHistoryQuery {
+        workflow_id: workflow_id.filter(|id| !id.is_empty()),
+        build_id: None,
+        limit,
+        offset,
+    };
+    let reply = session
+        .request(ServerMessage::GetBuildHistory(query), std::time::Duration::from_secs(10), |m| {
+            matches!(m, ServerMessage::BuildHistory(_))
+        })
+        .await?;
+    match reply {
+        ServerMessage::BuildHistory(page) => Ok(page),
+        _ => Err(RequestError::Server("Unexpected reply from the server".to_string())),
+    }
+}
+
+/// Artifacts recorded on a build, from history or the latest run; empty if
+/// the build produced none
+#[tauri::command]
+pub async fn get_build_artifacts(
+    server_id: String,
+    build_id: String,
+    state: State<'_, AppState>,
+) -> Result<Vec<BuildArtifact>, RequestError> {
+    let session = find_session(&state, &server_id).await.map_err(RequestError::UnknownServer)?;
+    let query = GetArtifactsQuery { build_id: build_id.clone() };
+    let reply = session
+        .request(ServerMessage::GetArtifacts(query), std::time::Duration::from_secs(10), |m| {
+            matches!(m, ServerMessage::Artifacts(payload) if payload.build_id == build_id)
+        })
+        .await
+        .map_err(|e| match e {
+            RequestError::Server(message) if message.contains("Build not found") => RequestError::UnknownBuild(message),
+            e => e,
+        })?;
+    match reply {
+        ServerMessage::Artifacts(payload) => Ok(payload.artifacts),
+        _ => Err(RequestError::Server("Unexpected reply from the server".to_string())),
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadedArtifact {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Downloads one of a build's artifacts to `destination`, emitting
+/// `artifact-download:<build_id>` events with `path`, `bytes` and `total` as
+/// it goes. An interrupted download says how many bytes it wrote; passing
+/// that as `offset` resumes it into the same file.
+#[tauri::command]
+pub async fn download_artifact(
+    server_id: String,
+    build_id: String,
+    artifact_path: String,
+    destination: String,
+    offset: Option<u64>,
+    app_handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<DownloadedArtifact, RequestError> {
+    use tauri::Manager;
+    use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+    use tokio::sync::broadcast::error::RecvError;
+    const CHUNK_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
+    const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+    let session = find_session(&state, &server_id).await.map_err(RequestError::UnknownServer)?;
+    let local = |e: std::io::Error| RequestError::Local(format!("{}: {}", destination, e));
+
+    let offset = offset.unwrap_or(0);
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(offset == 0)
+        .open(&destination)
+        .await
+        .map_err(local)?;
+    let length = file.metadata().await.map_err(local)?.len();
+    if length < offset {
+        return Err(RequestError::Local(format!(
+            "{} has only {} bytes; resume from there",
+            destination, length
+        )));
+    }
+    file.set_len(offset).await.map_err(local)?;
+    file.seek(std::io::SeekFrom::Start(offset)).await.map_err(local)?;
+
+    // Subscribed before asking, so the first frames are not missed
+    let mut chunks = session.subscribe_chunks();
+    let request = DownloadArtifactRequest {
+        build_id: build_id.clone(),
+        path: artifact_path.clone(),
+        offset,
+    };
+    let reply = session
+        .request(ServerMessage::DownloadArtifact(request), std::time::Duration::from_secs(10), |m| {
+            matches!(m, ServerMessage::ArtifactDownload(d) if d.build_id == build_id && d.path == artifact_path)
+        })
+        .await
+        .map_err(|e| match e {
+            RequestError::Server(message) if message.contains("Build not found") => RequestError::UnknownBuild(message),
+            e => e,
+        })?;
+    let ServerMessage::ArtifactDownload(download) = reply else {
+        return Err(RequestError::Server("Unexpected reply from the server".to_string()));
+    };
+
+    let event = format!("artifact-download:{}", build_id);
+    let mut written = offset;
+    let mut reported = std::time::Instant::now();
+    loop {
+        let frame = match tokio::time::timeout(CHUNK_WAIT, chunks.recv()).await {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(RecvError::Lagged(_))) => {
+                return Err(RequestError::Local(format!(
+                    "Fell behind the server after {} bytes; resume from there",
+                    written
+                )))
+            }
+            Ok(Err(RecvError::Closed)) => {
+                return Err(RequestError::Disconnected(format!(
+                    "The connection ended after {} bytes; resume from there",
+                    written
+                )))
+            }
+            Err(_) => {
+                return Err(RequestError::Timeout(format!(
+                    "The server stopped sending after {} bytes; resume from there",
+                    written
+                )))
+            }
+        };
+        let Some((header, data)) = parse_chunk(&frame) else {
+            continue;
+        };
+        if header.build_id != build_id || header.path != artifact_path {
+            continue;
+        }
+        if header.offset != written {
+            return Err(RequestError::Server(format!(
+                "The server sent byte {} after {} bytes; resume from there",
+                header.offset, written
+            )));
+        }
+        file.write_all(data).await.map_err(local)?;
+        written += data.len() as u64;
+        if header.last || reported.elapsed() >= PROGRESS_INTERVAL {
+            let _ = app_handle.emit_all(
+                &event,
+                serde_json::json!({ "path": artifact_path, "bytes": written, "total": header.total }),
+            );
+            reported = std::time::Instant::now();
+        }
+        if header.last {
+            break;
+        }
+    }
+    file.flush().await.map_err(local)?;
+    drop(file);
+
+    // Over the whole file, so a resumed download is checked too
+    let sha256 = sha256_file(&destination).await.map_err(local)?;
+    if sha256 != download.sha256 {
+        let _ = tokio::fs::remove_file(&destination).await;
+        return Err(RequestError::ChecksumMismatch(format!(
+            "{} has SHA-256 {} but the server recorded {}; the file was removed",
+            artifact_path, sha256, download.sha256
+        )));
+    }
+    Ok(DownloadedArtifact {
+        path: destination,
+        size: written,
+        sha256,
+    })
+}
+
+async fn sha256_file(path: &str) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Writes the full log of a build to `destination`, or to a file the user
+/// picks when there is none, and returns its path. The log is fetched and
+/// written a page at a time. A build that is still running gets what it has
+/// logged so far.
+#[tauri::command]
+pub async fn export_build_logs(
+    server_id: String,
+    build_id: String,
+    destination: Option<String>,
+    window: tauri::Window,
+    state: State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
+    use tokio::io::AsyncWriteExt;
+    const PAGE_SIZE: usize = 1000;
+    const PAGE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+    let session = find_session(&state, &server_id).await?;
+    let query = BuildHistoryQuery {
+        workflow_id: None,
+        build_id: Some(build_id.clone()),
+        limit: 1,
+        offset: 0,
+    };
+    let reply = session
+        .request(ServerMessage::GetBuildHistory(query), PAGE_TIMEOUT, |m| {
+            matches!(m, ServerMessage::BuildHistory(page) if page.records.iter().all(|r| r.id == build_id))
+        })
+        .await?;
+    let ServerMessage::BuildHistory(page) = reply else {
+        return Err("Unexpected reply from the server".to_string());
+    };
+    let record = page
+        .records
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("Build not found: {}", build_id))?;
+
+    let path = match destination {
+        Some(destination) => std::path::PathBuf::from(destination),
+        None => {
+            let project = if record.project_name.is_empty() { &record.workflow_id } else { &record.project_name };
+            let file_name = if record.version.is_empty() {
+                format!("{}-{}.log", project, build_id)
+            } else {
+                format!("{}-{}-{}.log", project, record.version, build_id)
+            };
+            let picked = FileDialogBuilder::new()
+                .set_title("Export Build Log")
+                .set_parent(&window)
+                .set_file_name(&file_name)
+                .add_filter("Log", &["log", "txt"])
+                .save_file();
+            match picked {
+                Some(path) => path,
+                None => return Ok(None),
+            }
+        }
+    };
+    let failed = |e: std::io::Error| format!("Failed to write {}: {}", path.display(), e);
+
+    let file = tokio::fs::File::create(&path).await.map_err(failed)?;
+    let mut out = tokio::io::BufWriter::new(file);
+    let server = session.info();
+    let project = if record.project_name.is_empty() { "-" } else { record.project_name.as_str() };
+    let header = format!(
+        "Workflow: {} ({})\nVersion:  {}\nBuild:    {}\nStatus:   {}\nDuration: {}\nStarted:  {}\nServer:   {} ({}:{})\n\n",
+        project,
+        record.workflow_id,
+        if record.version.is_empty() { "-" } else { record.version.as_str() },
+        record.id,
+        record.status,
+        record.duration_ms.map(format_duration).unwrap_or_else(|| "-".to_string()),
+        record.started_at,
+        server.name,
+        server.address,
+        server.port,
+    );
+    out.write_all(header.as_bytes()).await.map_err(failed)?;
+
+    let mut offset = 0;
+    let complete = loop {
+        let query = BuildLogsQuery {
+            build_id: build_id.clone(),
+            offset,
+            limit: PAGE_SIZE,
+        };
+        let reply = session
+            .request(ServerMessage::GetBuildLogs(query), PAGE_TIMEOUT, |m| {
+                matches!(m, ServerMessage::BuildLogs(page) if page.build_id == build_id && page.offset == offset)
+            })
+            .await?;
+        let ServerMessage::BuildLogs(page) = reply else {
+            return Err("Unexpected reply from the server".to_string());
+        };
+        for line in &page.lines {
+            out.write_all(line.as_bytes()).await.map_err(failed)?;
+            out.write_all(b"\n").await.map_err(failed)?;
+        }
+        offset += page.lines.len();
+        if page.lines.is_empty() || offset >= page.total {
+            break page.complete;
+        }
+    };
+    if !complete {
+        out.write_all(b"\n(build still running)\n").await.map_err(failed)?;
+    }
+    out.flush().await.map_err(failed)?;
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+/// `1h 2m 3s`, leaving out leading zero units
+pub fn format_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+/// Health of every configured server as of the latest background checks;
+/// changes come as `servers:health` events
+#[tauri::command]
+pub async fn get_servers_health(state: State<'_, AppState>) -> Result<Vec<ServerHealth>, String> {
+    Ok(state.health.snapshot().await)
+}
+
+/// How long one round of background checks over all servers takes
+#[tauri::command]
+pub async fn set_health_check_interval(seconds: u64, state: State<'_, AppState>) -> Result<(), String> {
+    state.health.set_interval(seconds)
+}
+
+/// Sends `message` over the server's session; what the server answers comes
+/// to the frontend as `server:<id>:message` events
+#[tauri::command]
+pub async fn send_server_message(
+    server_id: String,
+    message: ServerMessage,
+    state: State<'_, AppState>,
+) -> Result<(), String> {
+    let session = find_session(&state, &server_id).await?;
+    session.send(message).await
+}
+
+pub async fn find_session(state: &AppState, server_id: &str) -> Result<ServerSession, String> {
+    state
+        .servers
+        .lock()
+        .await
         .iter()
-        .find(|s| s.id == server_id)
-        .ok_or("Server not found")?;
+        .find(|s| s.id() == server_id)
+        .cloned()
+        .ok_or_else(|| "Server not found".to_string())
+}
+
+/// Looks up a configured server by id
+async fn find_server(state: &AppState, server_id: &str) -> Result<ServerConnection, String> {
+    Ok(find_session(state, server_id).await?.info())
+}
+
+#[tauri::command]
+pub async fn get_server_settings(
+    server_id: String,
+    state: State<'_, AppState>,
+) -> Result<SettingsPayload, String> {
+    let server = find_server(&state, &server_id).await?;
+    let reply = server
+        .request(&ServerMessage::GetSettings, |m| matches!(m, ServerMessage::Settings(_)))
+        .await?;
+    let ServerMessage::Settings(payload) = reply else {
+        unreachable!("request only returns accepted replies");
+    };
+    Ok(payload)
+}
+
+#[tauri::command]
+pub async fn set_server_settings(
+    server_id: String,
+    settings: ServerSettings,
+    state: State<'_, AppState>,
+) -> Result<SettingsPayload, String> {
+    let server = find_server(&state, &server_id).await?;
+    let reply = server
+        .request(&ServerMessage::SetSettings(settings), |m| matches!(m, ServerMessage::Settings(_)))
+        .await?;
+    let ServerMessage::Settings(payload) = reply else {
+        unreachable!("request only returns accepted replies");
+    };
+    Ok(payload)
+}
+
+#[tauri::command]
+pub async fn export_server_data(
+    server_id: String,
+    include_logs: bool,
+    window: tauri::Window,
+    state: State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
+    
+    let server = find_server(&state, &server_id).await?;
+    let file_name = format!("buildforge-{}-{}.json", server.name, chrono::Local::now().format("%Y%m%d"));
+    let Some(path) = FileDialogBuilder::new()
+        .set_title("Export Server Data")
+        .set_parent(&window)
+        .set_file_name(&file_name)
+        .add_filter("BuildForge bundle", &["json"])
+        .save_file()
+    else {
+        return Ok(None);
+    };
     
-    // In a real implementation, this would send a cancel request over WebSocket
+    let reply = server
+        .request(
+            &ServerMessage::ExportData(ExportDataRequest { include_logs }),
+            |m| matches!(m, ServerMessage::DataExport(_)),
+        )
+        .await?;
+    let ServerMessage::DataExport(bundle) = reply else {
+        unreachable!("request only returns accepted replies");
+    };
     
-    Ok(())
+    let content = serde_json::to_vec_pretty(&bundle).map_err(|e| e.to_string())?;
+    std::fs::write(&path, content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(Some(path.to_string_lossy().to_string()))
 }
 
 #[tauri::command]
-pub async fn get_server_status(
+pub async fn import_server_data(
     server_id: String,
+    merge: bool,
+    window: tauri::Window,
     state: State<'_, AppState>,
-) -> Result<String, String> {
-    let servers = state.servers.lock().await;
+) -> Result<Option<DataImportResult>, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
     
-    let server = servers
-        .iter()
-        .find(|s| s.id == server_id)
-        .ok_or("Server not found")?;
+    let server = find_server(&state, &server_id).await?;
+    let Some(path) = FileDialogBuilder::new()
+        .set_title("Import Server Data")
+        .set_parent(&window)
+        .add_filter("BuildForge bundle", &["json"])
+        .pick_file()
+    else {
+        return Ok(None);
+    };
     
-    Ok(serde_json::to_string(&server.status).unwrap())
+    let content = std::fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let bundle: serde_json::Value =
+        serde_json::from_slice(&content).map_err(|e| format!("Not a BuildForge bundle: {}", e))?;
+    
+    let reply = server
+        .request(
+            &ServerMessage::ImportData(ImportDataRequest { merge, bundle }),
+            |m| matches!(m, ServerMessage::DataImported(_)),
+        )
+        .await?;
+    let ServerMessage::DataImported(result) = reply else {
+        unreachable!("request only returns accepted replies");
+    };
+    Ok(Some(result))
+}
+
+/// Writes `workflow_id` with the actions it runs to a YAML file the user
+/// picks, and returns its path
+#[tauri::command]
+pub async fn export_workflow_to_file(
+    server_id: String,
+    workflow_id: String,
+    window: tauri::Window,
+    state: State<'_, AppState>,
+) -> Result<Option<String>, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
+    
+    let server = find_server(&state, &server_id).await?;
+    let reply = server
+        .request(&ServerMessage::ExportWorkflow(workflow_id), |m| matches!(m, ServerMessage::WorkflowExport(_)))
+        .await?;
+    let ServerMessage::WorkflowExport(WorkflowExportPayload { file_name, yaml, .. }) = reply else {
+        unreachable!("request only returns accepted replies");
+    };
+    
+    let Some(path) = FileDialogBuilder::new()
+        .set_title("Export Workflow")
+        .set_parent(&window)
+        .set_file_name(&file_name)
+        .add_filter("BuildForge workflow", &["yml", "yaml"])
+        .save_file()
+    else {
+        return Ok(None);
+    };
+    std::fs::write(&path, yaml).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(Some(path.to_string_lossy().to_string()))
+}
+
+/// Imports a workflow file the user picks. With `overwrite`, the workflow
+/// and actions with the file's ids are replaced rather than copied.
+#[tauri::command]
+pub async fn import_workflow_from_file(
+    server_id: String,
+    overwrite: bool,
+    window: tauri::Window,
+    state: State<'_, AppState>,
+) -> Result<Option<WorkflowImportResult>, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
+    
+    let server = find_server(&state, &server_id).await?;
+    let Some(path) = FileDialogBuilder::new()
+        .set_title("Import Workflow")
+        .set_parent(&window)
+        .add_filter("BuildForge workflow", &["yml", "yaml"])
+        .pick_file()
+    else {
+        return Ok(None);
+    };
+    
+    let yaml = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let reply = server
+        .request(
+            &ServerMessage::ImportWorkflow(ImportWorkflowRequest { yaml, overwrite }),
+            |m| matches!(m, ServerMessage::WorkflowImported(_)),
+        )
+        .await?;
+    let ServerMessage::WorkflowImported(result) = reply else {
+        unreachable!("request only returns accepted replies");
+    };
+    Ok(Some(result))
+}
+
+/// Writes `workflow_id` as a GitHub Actions workflow to a file the user picks,
+/// and returns the export for its warnings
+#[tauri::command]
+pub async fn export_workflow_as_github_actions(
+    server_id: String,
+    workflow_id: String,
+    window: tauri::Window,
+    state: State<'_, AppState>,
+) -> Result<Option<GitHubActionsExport>, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
+    
+    let server = find_server(&state, &server_id).await?;
+    let reply = server
+        .request(
+            &ServerMessage::ExportWorkflowAsGitHubActions(workflow_id),
+            |m| matches!(m, ServerMessage::GitHubActionsWorkflow(_)),
+        )
+        .await?;
+    let ServerMessage::GitHubActionsWorkflow(export) = reply else {
+        unreachable!("request only returns accepted replies");
+    };
+    
+    let Some(path) = FileDialogBuilder::new()
+        .set_title("Export to GitHub Actions")
+        .set_parent(&window)
+        .set_file_name(&export.file_name)
+        .add_filter("GitHub Actions workflow", &["yml", "yaml"])
+        .save_file()
+    else {
+        return Ok(None);
+    };
+    std::fs::write(&path, &export.yaml).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(Some(export))
+}
+
+/// Converts a GitHub Actions workflow file the user picks into a draft
+/// workflow, which is saved only once the user has reviewed it
+#[tauri::command]
+pub async fn import_github_actions_workflow(
+    server_id: String,
+    window: tauri::Window,
+    state: State<'_, AppState>,
+) -> Result<Option<GitHubActionsImport>, String> {
+    use tauri::api::dialog::blocking::FileDialogBuilder;
+    
+    let server = find_server(&state, &server_id).await?;
+    let Some(path) = FileDialogBuilder::new()
+        .set_title("Import GitHub Actions Workflow")
+        .set_parent(&window)
+        .add_filter("GitHub Actions workflow", &["yml", "yaml"])
+        .pick_file()
+    else {
+        return Ok(None);
+    };
+    
+    let yaml = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let reply = server
+        .request(
+            &ServerMessage::ImportGitHubActionsWorkflow(ImportGitHubActionsRequest { yaml }),
+            |m| matches!(m, ServerMessage::GitHubActionsImport(_)),
+        )
+        .await?;
+    let ServerMessage::GitHubActionsImport(import) = reply else {
+        unreachable!("request only returns accepted replies");
+    };
+    Ok(Some(import))
 }
 
 #[tauri::command]
@@ -134,12 +896,31 @@ pub async fn send_notification(
     Ok(())
 }
 
+/// REST API of the GitHub instance at `api_base_url`, github.com if unset.
+/// GitHub Enterprise Server uses `https://host/api/v3`.
+fn github_api_url(api_base_url: Option<&str>, path: &str) -> String {
+    let base = api_base_url
+        .map(|s| s.trim().trim_end_matches('/'))
+        .filter(|s| !s.is_empty())
+        .unwrap_or("https://api.github.com");
+    format!("{}/{}", base, path.trim_start_matches('/'))
+}
+
+/// Web host (OAuth and device flow) belonging to `api_base_url`
+fn github_web_url(api_base_url: Option<&str>, path: &str) -> String {
+    let base = match api_base_url.map(|s| s.trim().trim_end_matches('/')).filter(|s| !s.is_empty()) {
+        Some(api) => api.strip_suffix("/api/v3").unwrap_or("https://github.com"),
+        None => "https://github.com",
+    };
+    format!("{}/{}", base, path.trim_start_matches('/'))
+}
+
 #[tauri::command]
-pub async fn validate_github_token(token: String) -> Result<GitHubUser, String> {
+pub async fn validate_github_token(token: String, api_base_url: Option<String>) -> Result<GitHubUser, String> {
     let client = reqwest::Client::new();
     
     let response = client
-        .get("https://api.github.com/user")
+        .get(github_api_url(api_base_url.as_deref(), "/user"))
         .header("Authorization", format!("Bearer {}", token))
         .header("User-Agent", "BuildForge/1.0.0")
         .header("Accept", "application/vnd.github+json")
@@ -449,6 +1230,10 @@ pub async fn get_branches(path: String) -> Result<Vec<String>, String> {
     }
 }
 
+// The server started by start_local_server, so stopping it never touches a
+// server run by systemd or as a Windows service
+static LOCAL_SERVER: Lazy<StdMutex<Option<std::process::Child>>> = Lazy::new(|| StdMutex::new(None));
+
 #[tauri::command]
 pub async fn start_local_server() -> Result<String, String> {
     use std::process::Command;
@@ -464,41 +1249,43 @@ pub async fn start_local_server() -> Result<String, String> {
     #[cfg(target_os = "linux")]
     let server_path = "../server/target/debug/buildforge-server";
     
-    Command::new(server_path)
+    let child = Command::new(server_path)
         .spawn()
         .map_err(|e| format!("Failed to start server: {}. Make sure the server is built with 'cargo build' in the server directory.", e))?;
+    *LOCAL_SERVER.lock().unwrap_or_else(|e| e.into_inner()) = Some(child);
     
     Ok("Server started on port 9876".to_string())
 }
 
 #[tauri::command]
 pub async fn stop_local_server() -> Result<String, String> {
-    use std::process::Command;
-    
-    // Kill the server process
-    #[cfg(target_os = "macos")]
-    {
-        Command::new("pkill")
-            .args(["-f", "buildforge-server"])
-            .output()
-            .map_err(|e| e.to_string())?;
+    if !stop_spawned_server()? {
+        return Err("No server was started from this app".to_string());
     }
-    #[cfg(target_os = "windows")]
+    Ok("Server stopped".to_string())
+}
+
+/// Stops the server start_local_server started, if it is still running.
+/// On Unix it gets SIGTERM, so running builds can finish as on Ctrl-C.
+pub fn stop_spawned_server() -> Result<bool, String> {
+    let Some(mut child) = LOCAL_SERVER.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return Ok(false);
+    };
+    if child.try_wait().map_err(|e| e.to_string())?.is_some() {
+        return Ok(false);
+    }
+    #[cfg(unix)]
     {
-        Command::new("taskkill")
-            .args(["/F", "/IM", "buildforge-server.exe"])
+        std::process::Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
             .output()
             .map_err(|e| e.to_string())?;
     }
-    #[cfg(target_os = "linux")]
+    #[cfg(not(unix))]
     {
-        Command::new("pkill")
-            .args(["-f", "buildforge-server"])
-            .output()
-            .map_err(|e| e.to_string())?;
+        child.kill().map_err(|e| e.to_string())?;
     }
-    
-    Ok("Server stopped".to_string())
+    Ok(true)
 }
 
 // OAuth callback server state
@@ -589,7 +1376,7 @@ pub async fn check_oauth_result() -> Result<Option<serde_json::Value>, String> {
 }
 
 #[tauri::command]
-pub async fn exchange_oauth_code(code: String) -> Result<serde_json::Value, String> {
+pub async fn exchange_oauth_code(code: String, api_base_url: Option<String>) -> Result<serde_json::Value, String> {
     // Note: In production, this should be done through a backend server to keep the client secret secure
     // For development, we'll use GitHub's device flow or direct token exchange
     // This is a simplified version - you need to add your GitHub OAuth App's client secret
@@ -599,7 +1386,7 @@ pub async fn exchange_oauth_code(code: String) -> Result<serde_json::Value, Stri
     
     let client = reqwest::Client::new();
     let response = client
-        .post("https://github.com/login/oauth/access_token")
+        .post(github_web_url(api_base_url.as_deref(), "/login/oauth/access_token"))
         .header("Accept", "application/json")
         .json(&serde_json::json!({
             "client_id": client_id,
@@ -753,12 +1540,12 @@ pub struct DeviceCodeResponse {
 static DEVICE_CODE: Lazy<Arc<StdMutex<Option<String>>>> = Lazy::new(|| Arc::new(StdMutex::new(None)));
 
 #[tauri::command]
-pub async fn start_device_flow() -> Result<DeviceCodeResponse, String> {
+pub async fn start_device_flow(api_base_url: Option<String>) -> Result<DeviceCodeResponse, String> {
     let client_id = "Ov23li4L1cL2GgCWNENc";
     
     let client = reqwest::Client::new();
     let response = client
-        .post("https://github.com/login/device/code")
+        .post(github_web_url(api_base_url.as_deref(), "/login/device/code"))
         .header("Accept", "application/json")
         .header("User-Agent", "BuildForge/1.0.0")
         .form(&[("client_id", client_id), ("scope", "repo user workflow")])
@@ -790,7 +1577,7 @@ pub async fn start_device_flow() -> Result<DeviceCodeResponse, String> {
 }
 
 #[tauri::command]
-pub async fn poll_device_flow() -> Result<Option<serde_json::Value>, String> {
+pub async fn poll_device_flow(api_base_url: Option<String>) -> Result<Option<serde_json::Value>, String> {
     let device_code = DEVICE_CODE.lock().unwrap().clone();
     
     let device_code = match device_code {
@@ -802,7 +1589,7 @@ pub async fn poll_device_flow() -> Result<Option<serde_json::Value>, String> {
     
     let client = reqwest::Client::new();
     let response = client
-        .post("https://github.com/login/oauth/access_token")
+        .post(github_web_url(api_base_url.as_deref(), "/login/oauth/access_token"))
         .header("Accept", "application/json")
         .header("User-Agent", "BuildForge/1.0.0")
         .form(&[
@@ -0,0 +1,140 @@
+//! Background health checks of every configured server.
+//!
+//! Each round checks every server once, spread evenly over the interval so
+//! the probes do not all fire together. A connected server is pinged over
+//! its session and one that is not connected gets a TCP connect. Whenever
+//! the picture changes, the frontend gets all of it as `servers:health`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager};
+use tokio::sync::Mutex;
+
+use crate::server::{ServerSession, ServerStatus};
+use crate::tray::Tray;
+
+pub const DEFAULT_INTERVAL_SECS: u64 = 30;
+const MIN_INTERVAL_SECS: u64 = 5;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServerHealth {
+    pub server_id: String,
+    pub name: String,
+    pub status: ServerStatus,
+    pub latency_ms: Option<u64>,
+    /// Last time the server answered
+    pub last_seen: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+    pub error: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct HealthMonitor {
+    health: Arc<Mutex<Vec<ServerHealth>>>,
+    interval_secs: Arc<AtomicU64>,
+}
+
+impl Default for HealthMonitor {
+    fn default() -> Self {
+        Self {
+            health: Arc::default(),
+            interval_secs: Arc::new(AtomicU64::new(DEFAULT_INTERVAL_SECS)),
+        }
+    }
+}
+
+impl HealthMonitor {
+    /// Health of every server as of the latest checks
+    pub async fn snapshot(&self) -> Vec<ServerHealth> {
+        self.health.lock().await.clone()
+    }
+
+    /// Takes effect from the next round
+    pub fn set_interval(&self, seconds: u64) -> Result<(), String> {
+        if seconds < MIN_INTERVAL_SECS {
+            return Err(format!("The interval must be at least {} seconds", MIN_INTERVAL_SECS));
+        }
+        self.interval_secs.store(seconds, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.load(Ordering::Relaxed))
+    }
+
+    /// Checks the servers in `servers` until the app exits
+    pub fn spawn(&self, app: AppHandle, servers: Arc<Mutex<Vec<ServerSession>>>) {
+        let monitor = self.clone();
+        tauri::async_runtime::spawn(async move {
+            loop {
+                let sessions = servers.lock().await.clone();
+                monitor.forget_removed(&app, &sessions).await;
+                if sessions.is_empty() {
+                    tokio::time::sleep(monitor.interval()).await;
+                    continue;
+                }
+                let gap = monitor.interval() / sessions.len() as u32;
+                for session in sessions {
+                    monitor.probe(&app, &session).await;
+                    tokio::time::sleep(gap).await;
+                }
+            }
+        });
+    }
+
+    async fn probe(&self, app: &AppHandle, session: &ServerSession) {
+        let report = session.check(PROBE_TIMEOUT).await;
+        let info = session.info();
+        let answered = report.error.is_none();
+
+        let mut health = self.health.lock().await;
+        let index = match health.iter().position(|h| h.server_id == info.id) {
+            Some(index) => index,
+            None => {
+                health.push(ServerHealth {
+                    server_id: info.id.clone(),
+                    name: info.name.clone(),
+                    status: report.status,
+                    latency_ms: None,
+                    last_seen: None,
+                    consecutive_failures: 0,
+                    error: None,
+                });
+                health.len() - 1
+            }
+        };
+        let before = health[index].clone();
+        let entry = &mut health[index];
+        entry.name = info.name;
+        entry.status = report.status;
+        entry.latency_ms = report.latency_ms;
+        entry.error = report.error;
+        if answered {
+            entry.last_seen = Some(Utc::now());
+            entry.consecutive_failures = 0;
+        } else {
+            entry.consecutive_failures += 1;
+        }
+        if *entry != before {
+            let _ = app.emit_all("servers:health", health.clone());
+            if let Some(tray) = app.try_state::<Tray>() {
+                tray.changed();
+            }
+        }
+    }
+
+    /// Drops servers that are no longer configured
+    async fn forget_removed(&self, app: &AppHandle, sessions: &[ServerSession]) {
+        let mut health = self.health.lock().await;
+        let count = health.len();
+        health.retain(|h| sessions.iter().any(|s| s.id() == h.server_id));
+        if health.len() != count {
+            let _ = app.emit_all("servers:health", health.clone());
+        }
+    }
+}
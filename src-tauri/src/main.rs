@@ -4,14 +4,21 @@
 )]
 
 mod server;
+mod session;
+mod cancel;
 mod commands;
+mod health;
+mod notifications;
+mod taskbar;
+mod tray;
 
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use tauri::{Manager, SystemTray, SystemTrayEvent, CustomMenuItem, SystemTrayMenu};
+use tauri::{Manager, SystemTray};
 
 pub struct AppState {
-    servers: Arc<Mutex<Vec<server::ServerConnection>>>,
+    servers: Arc<Mutex<Vec<server::ServerSession>>>,
+    health: health::HealthMonitor,
 }
 
 fn main() {
@@ -28,52 +35,50 @@ fn main() {
         eprintln!("========================");
     }));
     
-    let quit = CustomMenuItem::new("quit".to_string(), "Quit BuildForge");
-    let show = CustomMenuItem::new("show".to_string(), "Show BuildForge");
-    let tray_menu = SystemTrayMenu::new()
-        .add_item(show)
-        .add_native_item(tauri::SystemTrayMenuItem::Separator)
-        .add_item(quit);
-
-    let tray = SystemTray::new().with_menu(tray_menu);
-
     tauri::Builder::default()
-        .system_tray(tray)
-        .on_system_tray_event(|app, event| match event {
-            SystemTrayEvent::LeftClick { .. } => {
-                if let Some(window) = app.get_window("main") {
-                    window.show().unwrap();
-                    window.set_focus().unwrap();
-                }
-            }
-            SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
-                "quit" => {
-                    // Stop local server before quitting
-                    let _ = std::process::Command::new("pkill")
-                        .args(["-f", "buildforge-server"])
-                        .output();
-                    std::process::exit(0);
-                }
-                "show" => {
-                    if let Some(window) = app.get_window("main") {
-                        window.show().unwrap();
-                        window.set_focus().unwrap();
-                    }
-                }
-                _ => {}
-            },
-            _ => {}
-        })
+        .system_tray(SystemTray::new().with_menu(tray::initial_menu()))
+        .on_system_tray_event(tray::on_event)
         .manage(AppState {
             servers: Arc::new(Mutex::new(Vec::new())),
+            health: health::HealthMonitor::default(),
+        })
+        .manage(tray::Tray::default())
+        .manage(notifications::Notifier::default())
+        .setup(|app| {
+            let state = app.state::<AppState>();
+            state.health.spawn(app.handle(), state.servers.clone());
+            let tray = app.state::<tray::Tray>();
+            tray.load(&app.handle());
+            tray.spawn(app.handle());
+            Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             commands::connect_server,
             commands::disconnect_server,
             commands::start_build,
             commands::cancel_build,
+            commands::set_quick_run_bump_version,
+            commands::get_close_behavior,
+            commands::set_close_behavior,
             commands::get_server_status,
+            commands::get_servers_health,
+            commands::get_build_history,
+            commands::get_build_artifacts,
+            commands::download_artifact,
+            commands::export_build_logs,
+            commands::set_health_check_interval,
+            commands::send_server_message,
+            commands::get_server_settings,
+            commands::set_server_settings,
+            commands::export_server_data,
+            commands::import_server_data,
+            commands::export_workflow_to_file,
+            commands::import_workflow_from_file,
+            commands::export_workflow_as_github_actions,
+            commands::import_github_actions_workflow,
             commands::send_notification,
+            commands::set_notifications_enabled,
+            commands::set_viewed_build,
             commands::validate_github_token,
             commands::get_git_remote,
             commands::detect_build_system,
@@ -101,11 +106,8 @@ fn main() {
             commands::install_package,
         ])
         .on_window_event(|event| {
-            if let tauri::WindowEvent::CloseRequested { .. } = event.event() {
-                // Stop local server when window closes
-                let _ = std::process::Command::new("pkill")
-                    .args(["-f", "buildforge-server"])
-                    .output();
+            if let tauri::WindowEvent::CloseRequested { api, .. } = event.event() {
+                event.window().state::<tray::Tray>().close_requested(event.window(), api);
             }
         })
         .run(tauri::generate_context!())
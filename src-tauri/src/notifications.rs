@@ -0,0 +1,166 @@
+//! Desktop notifications, above all for builds that finish.
+//!
+//! Those are made from the server's `BuildComplete` message: the project and
+//! version, how long the build took and, when it failed, the node it failed
+//! in and the first line of the error.
+//!
+//! notify-rust only reports clicks through D-Bus, so on Linux and the BSDs
+//! clicking one shows the window and emits `notification:open-build` with the
+//! build id, for the frontend to open that build's log, and there are "View
+//! log" and "Re-run" buttons. Elsewhere the notification is only shown.
+//!
+//! No notification is shown for the build the focused window is showing.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tauri::{AppHandle, Manager};
+
+use crate::commands::{self, StartBuildRequest};
+use crate::server::BuildCompletePayload;
+
+#[derive(Default)]
+pub struct Notifier {
+    state: Mutex<NotifierState>,
+}
+
+#[derive(Default)]
+struct NotifierState {
+    muted: bool,
+    /// The build whose log or details the frontend shows
+    viewed_build: Option<String>,
+    /// What started the builds this app started, for "Re-run"
+    requests: HashMap<String, StartBuildRequest>,
+}
+
+impl Notifier {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.lock().muted = !enabled;
+    }
+
+    pub fn set_viewed_build(&self, build_id: Option<String>) {
+        self.lock().viewed_build = build_id;
+    }
+
+    /// Keeps the request of a build this app started, so it can be run again
+    pub fn build_requested(&self, build_id: &str, request: StartBuildRequest) {
+        self.lock().requests.insert(build_id.to_string(), request);
+    }
+
+    /// Tells the user how a build went
+    pub fn build_complete(&self, app: &AppHandle, complete: &BuildCompletePayload) {
+        let (request, viewed) = {
+            let mut state = self.lock();
+            if state.muted {
+                state.requests.remove(&complete.build_id);
+                return;
+            }
+            (
+                state.requests.remove(&complete.build_id),
+                state.viewed_build.as_deref() == Some(complete.build_id.as_str()),
+            )
+        };
+        if viewed && is_focused(app) {
+            return;
+        }
+
+        let label = match (complete.project_name.as_str(), complete.version.as_str()) {
+            ("", _) => "The build".to_string(),
+            (project_name, "") => project_name.to_string(),
+            (project_name, version) => format!("{} v{}", project_name, version),
+        };
+        let summary = match complete.status.as_str() {
+            "success" => format!("{} succeeded", label),
+            "cancelled" => format!("{} was cancelled", label),
+            _ => format!("{} failed", label),
+        };
+        let mut body = format!("Took {}", commands::format_duration(complete.duration * 1000));
+        match (&complete.failed_node, &complete.error) {
+            (Some(node), Some(error)) => body.push_str(&format!("\nFailed in {}: {}", node, error)),
+            (Some(node), None) => body.push_str(&format!("\nFailed in {}", node)),
+            (None, Some(error)) => body.push_str(&format!("\n{}", error)),
+            (None, None) => {}
+        }
+        show_build(app, &summary, &body, complete.build_id.clone(), request);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, NotifierState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn is_focused(app: &AppHandle) -> bool {
+    app.get_window("main").is_some_and(|window| {
+        window.is_visible().unwrap_or(false) && window.is_focused().unwrap_or(false)
+    })
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn show_build(app: &AppHandle, summary: &str, body: &str, build_id: String, request: Option<StartBuildRequest>) {
+    let mut notification = notify_rust::Notification::new();
+    notification
+        .summary(summary)
+        .body(body)
+        .appname("BuildForge")
+        .action("default", "Open")
+        .action("view-log", "View log");
+    if request.is_some() {
+        notification.action("rerun", "Re-run");
+    }
+    let handle = match notification.show() {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("Failed to show a notification: {}", e);
+            return;
+        }
+    };
+    let app = app.clone();
+    // Waiting blocks until the notification is clicked or goes away
+    std::thread::spawn(move || {
+        handle.wait_for_action(|action| match action {
+            "default" | "view-log" => open_build(&app, &build_id),
+            "rerun" => {
+                if let Some(request) = request {
+                    tauri::async_runtime::spawn(async move { rerun(&app, request).await });
+                }
+            }
+            _ => {}
+        })
+    });
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn show_build(_app: &AppHandle, summary: &str, body: &str, _build_id: String, _request: Option<StartBuildRequest>) {
+    notify(summary, body);
+}
+
+/// Shows the window at the log of `build_id`
+#[cfg(all(unix, not(target_os = "macos")))]
+fn open_build(app: &AppHandle, build_id: &str) {
+    if let Some(window) = app.get_window("main") {
+        let _ = window.show();
+        let _ = window.unminimize();
+        let _ = window.set_focus();
+    }
+    let _ = app.emit_all("notification:open-build", build_id);
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+async fn rerun(app: &AppHandle, request: StartBuildRequest) {
+    let project_name = request.project_name.clone();
+    if let Err(e) = commands::submit_build(app, request).await {
+        notify(&format!("{} was not run", project_name), &e);
+    }
+}
+
+/// Shows a notification with nothing to click
+pub fn notify(title: &str, body: &str) {
+    let shown = notify_rust::Notification::new()
+        .summary(title)
+        .body(body)
+        .appname("BuildForge")
+        .show();
+    if let Err(e) = shown {
+        eprintln!("Failed to show a notification: {}", e);
+    }
+}
@@ -1,7 +1,25 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio_tungstenite::connect_async;
+use tauri::{AppHandle, Manager};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
 use uuid::Uuid;
 
+use crate::cancel;
+use crate::notifications::Notifier;
+use crate::session::{self, Backoff, Event, Socket};
+pub use crate::session::ServerStatus;
+use crate::tray::Tray;
+
+
+/// How often an idle session pings the server, so a dead link is noticed
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConnection {
     pub id: String,
@@ -9,14 +27,19 @@ pub struct ServerConnection {
     pub address: String,
     pub port: u16,
     pub status: ServerStatus,
+    /// The server only allows syncing and watching builds
+    #[serde(default)]
+    pub read_only: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-pub enum ServerStatus {
-    Online,
-    Offline,
-    Connecting,
+/// What `get_server_status` found just now
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStatusReport {
+    pub status: ServerStatus,
+    /// Round trip of a Ping, or of a TCP connect to a server without a session
+    pub latency_ms: Option<u64>,
+    /// Why the server could not be reached
+    pub error: Option<String>,
 }
 
 #[allow(dead_code)]
@@ -25,21 +48,315 @@ pub enum ServerStatus {
 pub enum ServerMessage {
     Ping,
     Pong,
+    Capabilities(CapabilitiesPayload),
+    ReadOnly(ReadOnlyPayload),
     BuildStart(BuildStartPayload),
+    BuildStarted(BuildStartedPayload),
+    BuildQueued(BuildQueuedPayload),
+    /// BuildStart was refused: this build of the workflow is still running
+    BuildStillRunning(String),
     BuildProgress(BuildProgressPayload),
     BuildComplete(BuildCompletePayload),
+    NodeStart(NodeEventPayload),
+    NodeComplete(NodeEventPayload),
     BuildLog(BuildLogPayload),
+    BuildCancel(String),
+    BuildNotCancelled(BuildNotCancelledPayload),
+    GetBuildHistory(BuildHistoryQuery),
+    BuildHistory(BuildHistoryPage),
+    GetBuildLogs(BuildLogsQuery),
+    BuildLogs(BuildLogsPage),
+    GetArtifacts(GetArtifactsQuery),
+    Artifacts(BuildArtifactsPayload),
+    DownloadArtifact(DownloadArtifactRequest),
+    /// Followed by the file as binary frames, see `parse_chunk`
+    ArtifactDownload(ArtifactDownloadPayload),
+    GetServerStatus,
+    ServerStatus(ServerStatusPayload),
+    ClientInfo(ClientInfoPayload),
+    DataSaveFailed(DataSaveFailedPayload),
+    GetSettings,
+    SetSettings(ServerSettings),
+    Settings(SettingsPayload),
+    ExportData(ExportDataRequest),
+    /// The bundle is passed through to a file untouched
+    DataExport(serde_json::Value),
+    ImportData(ImportDataRequest),
+    DataImported(DataImportResult),
+    ExportWorkflow(String),
+    WorkflowExport(WorkflowExportPayload),
+    ImportWorkflow(ImportWorkflowRequest),
+    WorkflowImported(WorkflowImportResult),
+    ExportWorkflowAsGitHubActions(String),
+    GitHubActionsWorkflow(GitHubActionsExport),
+    ImportGitHubActionsWorkflow(ImportGitHubActionsRequest),
+    GitHubActionsImport(GitHubActionsImport),
     Error(String),
+    SyncRequest,
+    /// Workflows, actions and repos, passed on to the frontend as they are
+    SyncResponse(serde_json::Value),
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapabilitiesPayload {
+    pub version: String,
+    pub read_only: bool,
+    /// Shells installed on the server that nodes can name
+    #[serde(default)]
+    pub shells: Vec<String>,
+    /// Interpreters installed on the server that actions can name
+    #[serde(default)]
+    pub interpreters: Vec<String>,
+    /// Present when the server restricts what workflows may run
+    #[serde(default)]
+    pub execution_policy: Option<ExecutionPolicy>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadOnlyPayload {
+    pub action: String,
 }
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BuildStartPayload {
     pub build_id: String,
+    pub workflow_id: String,
+    /// "continue" or "cancel"
+    pub on_disconnect: String,
     pub project_name: String,
     pub version: String,
     pub nodes: Vec<BuildNode>,
     pub edges: Vec<BuildEdge>,
+    /// For release nodes, if the user lets the server use their token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub github_token: Option<String>,
+}
+
+/// Answer to a `BuildCancel` that had nothing to cancel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildNotCancelledPayload {
+    pub build_id: String,
+    pub reason: cancel::NotCancelledReason,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildStartedPayload {
+    pub build_id: String,
+    pub workflow_id: String,
+    pub estimated_duration_ms: Option<u64>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildQueuedPayload {
+    pub build_id: String,
+    pub workflow_id: String,
+    pub position: usize,
+    pub estimated_wait_ms: Option<u64>,
+    pub estimated_duration_ms: Option<u64>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStatusPayload {
+    pub version: String,
+    pub uptime_secs: u64,
+    pub connected_clients: usize,
+    pub running_builds: Vec<RunningBuildInfo>,
+    pub queued_builds: Vec<BuildQueuedPayload>,
+    pub retention: RetentionPolicy,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_history_per_workflow: Option<usize>,
+    pub max_history_age_days: Option<u64>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecutionPolicy {
+    #[serde(default)]
+    pub allowed_programs: Vec<String>,
+    #[serde(default)]
+    pub denied_patterns: Vec<String>,
+    #[serde(default)]
+    pub forbid_scripts: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    pub nice: Option<i32>,
+    pub cpu_limit: Option<f64>,
+    pub memory_limit_mb: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerSettings {
+    pub port: u16,
+    pub default_shell: String,
+    pub default_node_timeout_secs: Option<u64>,
+    pub max_concurrent_builds: Option<usize>,
+    pub heartbeat_interval_secs: u64,
+    pub heartbeat_timeout_secs: u64,
+    pub retention: RetentionPolicy,
+    pub shutdown_grace_period_secs: u64,
+    #[serde(default)]
+    pub isolated_workspaces: bool,
+    #[serde(default)]
+    pub keep_workspace_on_failure: bool,
+    #[serde(default)]
+    pub workspace_retention_days: Option<u64>,
+    #[serde(default)]
+    pub github_api_url: Option<String>,
+    #[serde(default)]
+    pub github_upload_url: Option<String>,
+    #[serde(default)]
+    pub cache_max_size_mb: Option<u64>,
+    #[serde(default)]
+    pub cache_max_age_days: Option<u64>,
+    #[serde(default)]
+    pub max_node_output_mb: Option<u64>,
+    #[serde(default)]
+    pub max_log_line_bytes: Option<usize>,
+    /// "strip", "keep" or "auto"
+    #[serde(default = "default_ansi_mode")]
+    pub ansi_mode: String,
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
+    #[serde(default)]
+    pub execution_policy: ExecutionPolicy,
+}
+
+fn default_ansi_mode() -> String {
+    "auto".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingsPayload {
+    pub settings: ServerSettings,
+    /// Changed settings that need a server restart to take effect
+    pub restart_required: Vec<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataSaveFailedPayload {
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportDataRequest {
+    pub include_logs: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportDataRequest {
+    pub merge: bool,
+    pub bundle: serde_json::Value,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataImportResult {
+    pub merge: bool,
+    pub workflows: usize,
+    pub actions: usize,
+    pub repos: usize,
+    pub builds: usize,
+    pub conflicts: Vec<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowExportPayload {
+    pub workflow_id: String,
+    pub file_name: String,
+    pub yaml: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportWorkflowRequest {
+    pub yaml: String,
+    pub overwrite: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkflowImportResult {
+    pub workflow_id: String,
+    pub name: String,
+    pub replaced: bool,
+    pub remapped: Vec<String>,
+    pub actions: Vec<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitHubActionsExport {
+    pub workflow_id: String,
+    pub file_name: String,
+    pub yaml: String,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportGitHubActionsRequest {
+    pub yaml: String,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitHubActionsImport {
+    /// A draft, stored only once the app saves it
+    pub workflow: serde_json::Value,
+    pub report: Vec<ImportNote>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportNote {
+    pub job: Option<String>,
+    pub step: Option<String>,
+    pub message: String,
+}
+
+/// Identifies this app to the server, which records it in its audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInfoPayload {
+    pub name: String,
+    pub hostname: String,
+    pub app_version: String,
+}
+
+impl ClientInfoPayload {
+    pub fn current() -> Self {
+        Self {
+            name: "BuildForge Desktop".to_string(),
+            hostname: hostname::get()
+                .map(|h| h.to_string_lossy().to_string())
+                .unwrap_or_else(|_| "unknown".to_string()),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunningBuildInfo {
+    pub build_id: String,
+    pub workflow_id: String,
+    pub project_name: String,
+    pub current_node: Option<String>,
+    pub progress: u8,
+    pub started_at: String,
 }
 
 #[allow(dead_code)]
@@ -54,10 +371,219 @@ pub struct BuildProgressPayload {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BuildCompletePayload {
     pub build_id: String,
+    /// "success", "failed" or "cancelled"
+    pub status: String,
     pub success: bool,
     pub duration: u64,
     pub artifacts: Vec<String>,
     pub release_url: Option<String>,
+    #[serde(default)]
+    pub project_name: String,
+    #[serde(default)]
+    pub version: String,
+    /// Name of the node the build failed in
+    #[serde(default)]
+    pub failed_node: Option<String>,
+    /// First line of what went wrong, for a failed build
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildHistoryQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workflow_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildHistoryPage {
+    /// Newest first, without logs
+    pub records: Vec<BuildRecord>,
+    /// Records matching the query, across all pages
+    pub total: usize,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildRecord {
+    pub id: String,
+    pub workflow_id: String,
+    /// "running", "success", "failed", "cancelled" or "interrupted"; a
+    /// running build's record has its live node runs and duration
+    pub status: String,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub duration_ms: Option<u64>,
+    #[serde(default)]
+    pub failed_node: Option<String>,
+    #[serde(default)]
+    pub artifacts: Vec<ArtifactInfo>,
+    #[serde(default)]
+    pub release_url: Option<String>,
+    #[serde(default)]
+    pub node_runs: Vec<NodeRun>,
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+    #[serde(default)]
+    pub commit_sha: Option<String>,
+    #[serde(default)]
+    pub project_name: String,
+    #[serde(default)]
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildLogsQuery {
+    pub build_id: String,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildLogsPage {
+    pub build_id: String,
+    pub offset: usize,
+    pub lines: Vec<String>,
+    pub total: usize,
+    /// False while the build is still running and the log may grow
+    pub complete: bool,
+    #[serde(default)]
+    pub node_runs: Vec<NodeRun>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactInfo {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub modified_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetArtifactsQuery {
+    pub build_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildArtifactsPayload {
+    pub build_id: String,
+    pub artifacts: Vec<BuildArtifact>,
+}
+
+/// An artifact as the server lists it, passed on to the frontend in camelCase
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct BuildArtifact {
+    pub name: String,
+    /// Relative to the directory the build ran in
+    pub relative_path: String,
+    /// Where the file is on the server
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub modified_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadArtifactRequest {
+    pub build_id: String,
+    /// `relative_path` or `path` of one of the build's artifacts
+    pub path: String,
+    /// Where to start, to resume an interrupted download
+    pub offset: u64,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactDownloadPayload {
+    pub build_id: String,
+    pub path: String,
+    pub offset: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Leads every binary frame of a download
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkHeader {
+    pub build_id: String,
+    pub path: String,
+    /// Of the first byte of this chunk in the file
+    pub offset: u64,
+    pub total: u64,
+    /// No frames follow for this download
+    pub last: bool,
+}
+
+/// Splits a binary frame into its header and the bytes of the file: a 4-byte
+/// big-endian length, the header as JSON of that length, then the data
+pub fn parse_chunk(frame: &[u8]) -> Option<(ChunkHeader, &[u8])> {
+    let length = u32::from_be_bytes(frame.get(..4)?.try_into().ok()?) as usize;
+    let header = serde_json::from_slice(frame.get(4..4 + length)?).ok()?;
+    Some((header, &frame[4 + length..]))
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeEventPayload {
+    pub build_id: String,
+    pub node: NodeRun,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeRun {
+    pub node_id: String,
+    pub name: String,
+    pub node_type: String,
+    /// "running", "success", "failed", "cancelled" or "skipped"
+    pub status: String,
+    pub attempt: u32,
+    #[serde(default)]
+    pub skipped: bool,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub duration_ms: Option<u64>,
+    /// Part of `duration_ms` spent in a wait node
+    #[serde(default)]
+    pub waited_ms: Option<u64>,
+    pub exit_code: Option<i32>,
+    /// Set for test nodes that read their reports
+    #[serde(default)]
+    pub tests: Option<TestSummary>,
+    /// Some of the node's output was dropped from the log
+    #[serde(default)]
+    pub output_truncated: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestSummary {
+    pub total: usize,
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub duration_ms: u64,
+    pub failures: Vec<TestFailure>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestFailure {
+    pub name: String,
+    pub message: String,
 }
 
 #[allow(dead_code)]
@@ -65,12 +591,18 @@ pub struct BuildCompletePayload {
 pub struct BuildLogPayload {
     pub build_id: String,
     pub log: String,
+    /// "stdout", "stderr" or "system"; missing from older servers
+    #[serde(default)]
+    pub stream: Option<String>,
+    #[serde(default)]
+    pub ts: Option<String>,
 }
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BuildNode {
     pub id: String,
+    #[serde(rename = "type")]
     pub node_type: String,
     pub name: String,
     pub config: serde_json::Value,
@@ -82,6 +614,9 @@ pub struct BuildEdge {
     pub id: String,
     pub source: String,
     pub target: String,
+    /// "all" (default) or "any" instances of a matrixed source must succeed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait: Option<String>,
 }
 
 impl ServerConnection {
@@ -92,27 +627,444 @@ impl ServerConnection {
             address,
             port,
             status: ServerStatus::Offline,
+            read_only: false,
         }
     }
 
-    pub async fn connect(&mut self) -> Result<(), String> {
-        self.status = ServerStatus::Connecting;
-        
-        let url = format!("ws://{}:{}", self.address, self.port);
-        
-        match connect_async(&url).await {
-            Ok((_ws_stream, _)) => {
-                self.status = ServerStatus::Online;
-                Ok(())
+    /// Sends one request on its own connection and waits for the first reply
+    /// `is_reply` accepts, skipping events broadcast to every client. An
+    /// `Error` from the server is returned as `Err`.
+    pub async fn request(
+        &self,
+        message: &ServerMessage,
+        is_reply: impl Fn(&ServerMessage) -> bool,
+    ) -> Result<ServerMessage, String> {
+        const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+        let (mut ws_stream, _) = connect_async(&self.url())
+            .await
+            .map_err(|e| format!("Failed to connect: {}", e))?;
+
+        for outgoing in [&ServerMessage::ClientInfo(ClientInfoPayload::current()), message] {
+            let text = serde_json::to_string(outgoing).map_err(|e| e.to_string())?;
+            ws_stream.send(Message::Text(text)).await.map_err(|e| e.to_string())?;
+        }
+
+        let reply = async {
+            while let Some(frame) = ws_stream.next().await {
+                let Message::Text(text) = frame.map_err(|e| e.to_string())? else {
+                    continue;
+                };
+                // Messages this client does not know yet are not replies
+                let Ok(incoming) = serde_json::from_str::<ServerMessage>(&text) else {
+                    continue;
+                };
+                match incoming {
+                    ServerMessage::Error(message) => return Err(message),
+                    ServerMessage::ReadOnly(refused) => {
+                        return Err(format!("The server is read-only and refused {}", refused.action))
+                    }
+                    incoming if is_reply(&incoming) => return Ok(incoming),
+                    _ => {}
+                }
             }
-            Err(e) => {
-                self.status = ServerStatus::Offline;
-                Err(format!("Failed to connect: {}", e))
+            Err("Connection closed before the server replied".to_string())
+        };
+        let result = tokio::time::timeout(REQUEST_TIMEOUT, reply)
+            .await
+            .map_err(|_| "Timed out waiting for the server".to_string())?;
+        let _ = ws_stream.close(None).await;
+        result
+    }
+
+    fn url(&self) -> String {
+        format!("ws://{}:{}", self.address, self.port)
+    }
+}
+
+/// Why `ServerSession::request` got no reply. Commands that pass it on to
+/// the frontend send it as `{ "code": ..., "message": ... }`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "code", content = "message", rename_all = "snake_case")]
+pub enum RequestError {
+    /// The server is not found among the configured ones
+    UnknownServer(String),
+    /// The session is not connected
+    Offline(String),
+    /// The connection ended before the reply came
+    Disconnected(String),
+    /// The server does not know the build
+    UnknownBuild(String),
+    /// Reading or writing a file on this machine failed
+    Local(String),
+    /// A downloaded file does not match the checksum the server recorded
+    ChecksumMismatch(String),
+    /// No reply within the timeout
+    Timeout(String),
+    /// The server answered with an error
+    Server(String),
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownServer(message)
+            | Self::Offline(message)
+            | Self::Disconnected(message)
+            | Self::UnknownBuild(message)
+            | Self::Local(message)
+            | Self::ChecksumMismatch(message)
+            | Self::Timeout(message)
+            | Self::Server(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<RequestError> for String {
+    fn from(error: RequestError) -> Self {
+        error.to_string()
+    }
+}
+
+/// A connected server. A task owns the socket: it sends what `send` queues,
+/// emits everything the server sends to the frontend as `server:<id>:message`
+/// and every status change as `server:<id>:status`, and reconnects when the
+/// connection drops, until `close`.
+#[derive(Clone)]
+pub struct ServerSession {
+    app: AppHandle,
+    info: Arc<std::sync::Mutex<ServerConnection>>,
+    /// Where the status stands, which `info` follows
+    state: Arc<std::sync::Mutex<session::State>>,
+    outgoing: mpsc::Sender<ServerMessage>,
+    /// Every message from the server this app knows, for `request`
+    incoming: broadcast::Sender<ServerMessage>,
+    /// Binary frames from the server, the chunks of artifact downloads
+    chunks: broadcast::Sender<Arc<Vec<u8>>>,
+    finished: Arc<std::sync::Mutex<cancel::Finished>>,
+    closed: Arc<watch::Sender<bool>>,
+}
+
+enum Ended {
+    Closed,
+    Lost(String),
+}
+
+impl ServerSession {
+    /// Connects to `server` and starts its session, or fails if the first
+    /// connection cannot be made
+    pub async fn connect(app: AppHandle, mut server: ServerConnection) -> Result<Self, String> {
+        server.status = ServerStatus::Connecting;
+        let (socket, first) = open(&server.url()).await?;
+        server.status = ServerStatus::Online;
+        if let Some(Ok(ServerMessage::Capabilities(capabilities))) = first.as_deref().map(serde_json::from_str) {
+            server.read_only = capabilities.read_only;
+        }
+
+        let (outgoing, queued) = mpsc::channel(64);
+        let (closed, closing) = watch::channel(false);
+        let session = Self {
+            app,
+            state: Arc::new(std::sync::Mutex::new(session::State::new(server.status, server.read_only))),
+            info: Arc::new(std::sync::Mutex::new(server)),
+            outgoing,
+            incoming: broadcast::channel(256).0,
+            chunks: broadcast::channel(64).0,
+            finished: Arc::default(),
+            closed: Arc::new(closed),
+        };
+        tokio::spawn(session.clone().run(socket, first, queued, closing));
+        Ok(session)
+    }
+
+    /// The server's details and current status
+    pub fn info(&self) -> ServerConnection {
+        self.info.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn id(&self) -> String {
+        self.info().id
+    }
+
+    /// Queues `message` for the server; replies arrive as events
+    pub async fn send(&self, message: ServerMessage) -> Result<(), String> {
+        if self.info().status != ServerStatus::Online {
+            return Err("Server is not online".to_string());
+        }
+        self.queue(message).await
+    }
+
+    async fn queue(&self, message: ServerMessage) -> Result<(), String> {
+        self.outgoing
+            .send(message)
+            .await
+            .map_err(|_| "The connection to the server has ended".to_string())
+    }
+
+    /// Sends `message` and waits for the first reply `is_reply` accepts. An
+    /// `Error` from the server in the meantime is returned as `Err`; the
+    /// server does not say what an error answers, so one caused by another
+    /// message on the session ends the wait too.
+    pub async fn request(
+        &self,
+        message: ServerMessage,
+        timeout: Duration,
+        is_reply: impl Fn(&ServerMessage) -> bool,
+    ) -> Result<ServerMessage, RequestError> {
+        let info = self.info();
+        if info.status != ServerStatus::Online {
+            return Err(RequestError::Offline(format!("{} is not online", info.name)));
+        }
+        // Subscribed before sending, so a quick reply is not missed
+        let mut incoming = self.incoming.subscribe();
+        self.queue(message).await.map_err(RequestError::Disconnected)?;
+        let reply = async {
+            loop {
+                match incoming.recv().await {
+                    Ok(ServerMessage::Error(message)) => return Err(RequestError::Server(message)),
+                    Ok(ServerMessage::ReadOnly(refused)) => {
+                        return Err(RequestError::Server(format!(
+                            "The server is read-only and refused {}",
+                            refused.action
+                        )))
+                    }
+                    Ok(incoming) if is_reply(&incoming) => return Ok(incoming),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(RequestError::Disconnected(
+                            "The connection to the server has ended".to_string(),
+                        ))
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(timeout, reply).await.map_err(|_| {
+            RequestError::Timeout(format!("No reply from the server within {} s", timeout.as_secs()))
+        })?
+    }
+
+    /// Round-trip time of a Ping. A server that does not answer within
+    /// `timeout` is marked offline until it is heard from again.
+    pub async fn ping(&self, timeout: Duration) -> Result<Duration, String> {
+        let mut incoming = self.incoming.subscribe();
+        let started = std::time::Instant::now();
+        let pong = async {
+            self.queue(ServerMessage::Ping).await?;
+            loop {
+                match incoming.recv().await {
+                    Ok(ServerMessage::Pong) => return Ok(started.elapsed()),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err("The connection to the server has ended".to_string())
+                    }
+                }
+            }
+        };
+        let result = tokio::time::timeout(timeout, pong)
+            .await
+            .unwrap_or_else(|_| Err(format!("No answer within {} ms", timeout.as_millis())));
+        if result.is_err() {
+            self.update(Event::PingFailed);
+        }
+        result
+    }
+
+    /// Pings the server if the session is live. Otherwise tries a TCP
+    /// connect, to tell a server that is down from one that is not connected.
+    pub async fn check(&self, timeout: Duration) -> ServerStatusReport {
+        let info = self.info();
+        if info.status == ServerStatus::Online {
+            return match self.ping(timeout).await {
+                Ok(latency) => ServerStatusReport {
+                    status: ServerStatus::Online,
+                    latency_ms: Some(latency.as_millis() as u64),
+                    error: None,
+                },
+                // The session has marked itself offline and told the frontend
+                Err(e) => ServerStatusReport {
+                    status: ServerStatus::Offline,
+                    latency_ms: None,
+                    error: Some(e),
+                },
+            };
+        }
+
+        let started = std::time::Instant::now();
+        let connect = TcpStream::connect((info.address.as_str(), info.port));
+        let (latency_ms, error) = match tokio::time::timeout(timeout, connect).await {
+            Ok(Ok(_)) => (Some(started.elapsed().as_millis() as u64), None),
+            Ok(Err(e)) => (None, Some(format!("{}:{} is unreachable: {}", info.address, info.port, e))),
+            Err(_) => (None, Some(format!("{}:{} did not answer within {} ms", info.address, info.port, timeout.as_millis()))),
+        };
+        ServerStatusReport {
+            status: info.status,
+            latency_ms,
+            error,
+        }
+    }
+
+    /// Whether `close` was called
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    /// Messages from the server from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerMessage> {
+        self.incoming.subscribe()
+    }
+
+    /// Binary frames from the server from now on
+    pub fn subscribe_chunks(&self) -> broadcast::Receiver<Arc<Vec<u8>>> {
+        self.chunks.subscribe()
+    }
+
+    /// Whether the server has reported `build_id` finished
+    pub fn has_finished(&self, build_id: &str) -> bool {
+        self.finished.lock().unwrap_or_else(|e| e.into_inner()).contains(build_id)
+    }
+
+    /// Closes the connection for good
+    pub fn close(&self) {
+        let _ = self.closed.send(true);
+    }
+
+    async fn run(
+        self,
+        mut socket: Socket,
+        mut first: Option<String>,
+        mut queued: mpsc::Receiver<ServerMessage>,
+        mut closing: watch::Receiver<bool>,
+    ) {
+        loop {
+            self.update(Event::Connected);
+            if let Some(text) = first.take() {
+                self.received(&text);
             }
+            match self.pump(&mut socket, &mut queued, &mut closing).await {
+                Ended::Closed => {
+                    let _ = socket.close(None).await;
+                    self.update(Event::Closed);
+                    return;
+                }
+                Ended::Lost(reason) => {
+                    eprintln!("Lost the connection to {}: {}", self.info().name, reason);
+                    self.update(Event::Lost);
+                }
+            }
+
+            let url = self.info().url();
+            let mut backoff = Backoff::default();
+            (socket, first) = loop {
+                tokio::select! {
+                    _ = closing.changed() => {
+                        self.update(Event::Closed);
+                        return;
+                    }
+                    _ = tokio::time::sleep(backoff.next_delay()) => {}
+                }
+                if let Ok(opened) = open(&url).await {
+                    break opened;
+                }
+            };
+        }
+    }
+
+    /// Moves messages both ways until the connection ends or is closed
+    async fn pump(
+        &self,
+        socket: &mut Socket,
+        queued: &mut mpsc::Receiver<ServerMessage>,
+        closing: &mut watch::Receiver<bool>,
+    ) -> Ended {
+        let mut keepalive = tokio::time::interval(KEEPALIVE);
+        keepalive.tick().await;
+        loop {
+            let outgoing = tokio::select! {
+                // Also when every handle is gone
+                _ = closing.changed() => return Ended::Closed,
+                Some(message) = queued.recv() => message,
+                _ = keepalive.tick() => ServerMessage::Ping,
+                frame = socket.next() => {
+                    match frame {
+                        Some(Ok(Message::Text(text))) => self.received(&text),
+                        Some(Ok(Message::Binary(data))) => {
+                            let _ = self.chunks.send(Arc::new(data));
+                        }
+                        Some(Ok(Message::Close(_))) | None => return Ended::Lost("closed by the server".to_string()),
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => return Ended::Lost(e.to_string()),
+                    }
+                    continue;
+                }
+            };
+            let text = match serde_json::to_string(&outgoing) {
+                Ok(text) => text,
+                Err(e) => {
+                    eprintln!("Failed to encode a message for the server: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = socket.send(Message::Text(text)).await {
+                return Ended::Lost(e.to_string());
+            }
+        }
+    }
+
+    /// Passes a message from the server on to the frontend. Messages this app
+    /// does not know are passed on too, as the JSON they came in. Those about
+    /// a build also go out as `build:<build_id>`.
+    fn received(&self, text: &str) {
+        let Ok(message) = serde_json::from_str::<serde_json::Value>(text) else {
+            return;
+        };
+        if let Some(build_id) = session::build_id(&message) {
+            let _ = self.app.emit_all(&format!("build:{}", build_id), message.clone());
+        }
+        let known = serde_json::from_value::<ServerMessage>(message.clone()).ok();
+        let read_only = match &known {
+            Some(ServerMessage::Capabilities(capabilities)) => Some(capabilities.read_only),
+            _ => None,
+        };
+        self.update(Event::Heard { read_only });
+        if let Some(known) = known {
+            if let ServerMessage::BuildComplete(complete) = &known {
+                let first = self.finished.lock().unwrap_or_else(|e| e.into_inner()).record(&complete.build_id);
+                if first {
+                    if let Some(notifier) = self.app.try_state::<Notifier>() {
+                        notifier.build_complete(&self.app, complete);
+                    }
+                }
+            }
+            if let Some(tray) = self.app.try_state::<Tray>() {
+                tray.message(&known);
+            }
+            let _ = self.incoming.send(known);
         }
+        let _ = self.app.emit_all(&format!("server:{}:message", self.id()), message);
     }
 
-    pub fn disconnect(&mut self) {
-        self.status = ServerStatus::Offline;
+    /// Moves the status on for `event`, telling the tray and the frontend
+    /// if it changed
+    fn update(&self, event: Event) {
+        let info = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if !state.apply(event) {
+                return;
+            }
+            let mut info = self.info.lock().unwrap_or_else(|e| e.into_inner());
+            info.status = state.status;
+            info.read_only = state.read_only;
+            info.clone()
+        };
+        if let Some(tray) = self.app.try_state::<Tray>() {
+            tray.changed();
+        }
+        let _ = self.app.emit_all(&format!("server:{}:status", info.id), info);
     }
 }
+
+/// Connects as this app, see `session::open`
+async fn open(url: &str) -> Result<(Socket, Option<String>), String> {
+    let hello = serde_json::to_string(&ServerMessage::ClientInfo(ClientInfoPayload::current())).map_err(|e| e.to_string())?;
+    session::open(url, &hello).await
+}
@@ -0,0 +1,206 @@
+//! How a server session connects, how its status follows what happens to
+//! the connection, and how long it waits between attempts to reconnect.
+//! `ServerSession` owns the socket and tells the app; the rest is here,
+//! apart from Tauri, so it can be tested on its own, down to a connection
+//! to a real server in `tests/session.rs`.
+
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Wait before the first attempt to reconnect
+const FIRST_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between attempts to reconnect
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerStatus {
+    Online,
+    Offline,
+    Connecting,
+}
+
+/// Something that happened to a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A connection was made, the first or a new one
+    Connected,
+    /// The connection dropped; the session tries to make a new one
+    Lost,
+    /// The session was closed for good
+    Closed,
+    /// A ping went unanswered
+    PingFailed,
+    /// The server sent a message, which said what this connection may do
+    /// if `read_only` is set
+    Heard { read_only: Option<bool> },
+}
+
+/// What the frontend is shown of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    pub status: ServerStatus,
+    /// The server only allows syncing and watching builds
+    pub read_only: bool,
+    /// Nothing changes the state any more
+    pub closed: bool,
+}
+
+impl State {
+    pub fn new(status: ServerStatus, read_only: bool) -> Self {
+        Self {
+            status,
+            read_only,
+            closed: false,
+        }
+    }
+
+    /// Takes in `event`, and says whether the state changed
+    pub fn apply(&mut self, event: Event) -> bool {
+        let before = *self;
+        if self.closed {
+            return false;
+        }
+        match event {
+            Event::Connected => self.status = ServerStatus::Online,
+            Event::Lost => self.status = ServerStatus::Connecting,
+            Event::Closed => {
+                self.status = ServerStatus::Offline;
+                self.closed = true;
+            }
+            // A session still reconnecting is not offline
+            Event::PingFailed if self.status == ServerStatus::Online => self.status = ServerStatus::Offline,
+            Event::PingFailed => {}
+            // Heard from again after a missed ping
+            Event::Heard { read_only } => {
+                if self.status == ServerStatus::Offline || read_only.is_some() {
+                    self.status = ServerStatus::Online;
+                }
+                if let Some(read_only) = read_only {
+                    self.read_only = read_only;
+                }
+            }
+        }
+        *self != before
+    }
+}
+
+/// Waits between attempts to reconnect, doubling up to a limit
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: FIRST_RECONNECT_DELAY,
+        }
+    }
+}
+
+impl Backoff {
+    /// How long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        delay
+    }
+}
+
+/// Connects to `url` and introduces the app with `hello`, its `ClientInfo`.
+/// Returns the first message too, which is the server announcing what this
+/// connection may do.
+pub async fn open(url: &str, hello: &str) -> Result<(Socket, Option<String>), String> {
+    let (mut socket, _) = connect_async(url).await.map_err(|e| format!("Failed to connect: {}", e))?;
+    socket
+        .send(Message::Text(hello.to_string()))
+        .await
+        .map_err(|e| format!("Failed to connect: {}", e))?;
+
+    let first = match tokio::time::timeout(Duration::from_secs(5), socket.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => Some(text),
+        _ => None,
+    };
+    Ok((socket, first))
+}
+
+/// The build a message from the server is about, from its `build_id`
+pub fn build_id(message: &serde_json::Value) -> Option<&str> {
+    message.get("payload")?.get("build_id")?.as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_lost_connection_is_reconnecting_until_it_is_back() {
+        let mut state = State::new(ServerStatus::Online, false);
+        assert!(state.apply(Event::Lost));
+        assert_eq!(state.status, ServerStatus::Connecting);
+        assert!(!state.apply(Event::Lost));
+        assert!(state.apply(Event::Connected));
+        assert_eq!(state.status, ServerStatus::Online);
+    }
+
+    #[test]
+    fn a_missed_ping_is_offline_until_the_server_is_heard_from() {
+        let mut state = State::new(ServerStatus::Online, false);
+        assert!(state.apply(Event::PingFailed));
+        assert_eq!(state.status, ServerStatus::Offline);
+        assert!(state.apply(Event::Heard { read_only: None }));
+        assert_eq!(state.status, ServerStatus::Online);
+        assert!(!state.apply(Event::Heard { read_only: None }));
+    }
+
+    #[test]
+    fn a_missed_ping_while_reconnecting_changes_nothing() {
+        let mut state = State::new(ServerStatus::Connecting, false);
+        assert!(!state.apply(Event::PingFailed));
+        assert_eq!(state.status, ServerStatus::Connecting);
+    }
+
+    #[test]
+    fn capabilities_set_read_only() {
+        let mut state = State::new(ServerStatus::Connecting, false);
+        assert!(state.apply(Event::Heard { read_only: Some(true) }));
+        assert_eq!(state, State::new(ServerStatus::Online, true));
+        assert!(state.apply(Event::Heard { read_only: Some(false) }));
+        assert!(!state.read_only);
+    }
+
+    #[test]
+    fn a_closed_session_stays_offline() {
+        let mut state = State::new(ServerStatus::Online, false);
+        assert!(state.apply(Event::Closed));
+        assert_eq!(state.status, ServerStatus::Offline);
+        for event in [Event::Connected, Event::Lost, Event::Heard { read_only: Some(true) }, Event::Closed] {
+            assert!(!state.apply(event));
+        }
+        assert_eq!(state.status, ServerStatus::Offline);
+        assert!(!state.read_only);
+    }
+
+    #[test]
+    fn reconnect_delays_double_up_to_the_limit() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..7).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+    }
+
+    #[test]
+    fn build_id_comes_from_the_payload() {
+        let complete = serde_json::json!({ "type": "BuildComplete", "payload": { "build_id": "b1", "success": true } });
+        assert_eq!(build_id(&complete), Some("b1"));
+        assert_eq!(build_id(&serde_json::json!({ "type": "Pong" })), None);
+        assert_eq!(build_id(&serde_json::json!({ "type": "Error", "payload": "Build not found" })), None);
+    }
+}
@@ -0,0 +1,142 @@
+//! Build progress where the app shows while minimized: the taskbar button on
+//! Windows, the dock badge on macOS and the launcher entry on Linux desktops
+//! that read Unity's launcher API.
+//!
+//! Platforms without any of these, or where a call fails, just show nothing.
+
+use tauri::{AppHandle, Manager, UserAttentionType};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Indicator {
+    Hidden,
+    /// Of the least advanced of `count` running builds
+    Progress { percent: u8, count: usize },
+    /// A build failed and nothing runs now
+    Failed,
+}
+
+/// Shows `indicator` on the main window's taskbar button or dock icon
+pub fn show(app: &AppHandle, indicator: Indicator) {
+    let Some(window) = app.get_window("main") else {
+        return;
+    };
+    if indicator == Indicator::Failed {
+        let _ = window.request_user_attention(Some(UserAttentionType::Critical));
+    }
+    #[cfg(target_os = "linux")]
+    linux::show(indicator);
+    #[cfg(any(windows, target_os = "macos"))]
+    {
+        let on_main = window.clone();
+        let _ = window.run_on_main_thread(move || platform::show(&on_main, indicator));
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use windows::Win32::Foundation::HWND;
+    use windows::Win32::System::Com::{CoCreateInstance, CLSCTX_INPROC_SERVER};
+    use windows::Win32::UI::Shell::{ITaskbarList3, TaskbarList, TBPF_ERROR, TBPF_NOPROGRESS, TBPF_NORMAL};
+
+    use super::Indicator;
+
+    pub fn show(window: &tauri::Window, indicator: Indicator) {
+        let Ok(hwnd) = window.hwnd() else {
+            return;
+        };
+        let _ = set(HWND(hwnd.0), indicator);
+    }
+
+    fn set(hwnd: HWND, indicator: Indicator) -> windows::core::Result<()> {
+        // The main thread has COM set up for the webview already
+        unsafe {
+            let taskbar: ITaskbarList3 = CoCreateInstance(&TaskbarList, None, CLSCTX_INPROC_SERVER)?;
+            taskbar.HrInit()?;
+            match indicator {
+                Indicator::Hidden => taskbar.SetProgressState(hwnd, TBPF_NOPROGRESS),
+                Indicator::Progress { percent, .. } => {
+                    taskbar.SetProgressState(hwnd, TBPF_NORMAL)?;
+                    taskbar.SetProgressValue(hwnd, percent as u64, 100)
+                }
+                Indicator::Failed => {
+                    taskbar.SetProgressState(hwnd, TBPF_ERROR)?;
+                    taskbar.SetProgressValue(hwnd, 100, 100)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use cocoa::appkit::NSApp;
+    use cocoa::base::{id, nil};
+    use cocoa::foundation::NSString;
+    use objc::{msg_send, sel, sel_impl};
+
+    use super::Indicator;
+
+    /// The dock has no progress bar without drawing one, so the badge says
+    /// how far a single build is, or how many run
+    pub fn show(_window: &tauri::Window, indicator: Indicator) {
+        let badge = match indicator {
+            Indicator::Hidden => None,
+            Indicator::Progress { percent, count: 1 } => Some(format!("{}%", percent)),
+            Indicator::Progress { count, .. } => Some(count.to_string()),
+            Indicator::Failed => Some("!".to_string()),
+        };
+        unsafe {
+            let dock_tile: id = msg_send![NSApp(), dockTile];
+            let label = match badge {
+                Some(badge) => NSString::alloc(nil).init_str(&badge),
+                None => nil,
+            };
+            let _: () = msg_send![dock_tile, setBadgeLabel: label];
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::Indicator;
+
+    /// Emits the launcher entry update with `gdbus`, so there is no D-Bus
+    /// dependency; without it, or a launcher that listens, nothing happens
+    pub fn show(indicator: Indicator) {
+        let Some(desktop_id) = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.file_stem().map(|stem| stem.to_string_lossy().to_string()))
+        else {
+            return;
+        };
+        let properties = match indicator {
+            Indicator::Hidden => {
+                "{'progress-visible': <false>, 'count-visible': <false>, 'urgent': <false>}".to_string()
+            }
+            Indicator::Progress { percent, count } => format!(
+                "{{'progress': <{:.2}>, 'progress-visible': <true>, 'count': <int64 {}>, 'count-visible': <{}>, 'urgent': <false>}}",
+                percent as f64 / 100.0,
+                count,
+                count > 1
+            ),
+            Indicator::Failed => "{'progress-visible': <false>, 'count-visible': <false>, 'urgent': <true>}".to_string(),
+        };
+        let desktop_uri = format!("application://{}.desktop", desktop_id);
+        std::thread::spawn(move || {
+            let _ = std::process::Command::new("gdbus")
+                .args([
+                    "emit",
+                    "--session",
+                    "--object-path",
+                    "/dev/buildforge/launcher",
+                    "--signal",
+                    "com.canonical.Unity.LauncherEntry.Update",
+                    &desktop_uri,
+                    &properties,
+                ])
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status();
+        });
+    }
+}
@@ -0,0 +1,511 @@
+//! The system tray menu: every server with its status, the builds running
+//! now and the last few that finished.
+//!
+//! Sessions feed it the build messages they receive and mark it changed when
+//! a server's status does. The menu is rebuilt at most twice a second, since
+//! progress can come much faster than that while a build logs. The taskbar
+//! or dock progress, see `taskbar`, is updated along with it.
+//!
+//! "Run again" repeats the last build this app started, kept in
+//! `last_run.json` in the app data directory so it survives a restart. While
+//! that build runs the item cancels it instead.
+//!
+//! Closing the window hides it to the tray unless the user chose to quit on
+//! close instead, in `window.json`. Only Quit stops the server this app
+//! started.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tauri::{
+    AppHandle, CustomMenuItem, Manager, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem, SystemTraySubmenu,
+};
+use tokio::sync::Notify;
+
+use crate::commands::{self, StartBuildRequest};
+use crate::notifications::notify;
+use crate::server::{ServerConnection, ServerMessage, ServerStatus};
+use crate::taskbar::{self, Indicator};
+use crate::AppState;
+
+const RECENT_BUILDS: usize = 5;
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// How long the taskbar shows that a build failed
+const FAILURE_SHOWN: Duration = Duration::from_secs(5);
+const LAST_RUN_FILE: &str = "last_run.json";
+const WINDOW_FILE: &str = "window.json";
+
+#[derive(Clone, Default)]
+pub struct Tray {
+    builds: Arc<Mutex<TrayBuilds>>,
+    window: Arc<Mutex<WindowSettings>>,
+    changed: Arc<Notify>,
+}
+
+/// What closing the main window does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseBehavior {
+    /// Quit the app, stopping the server it started
+    Quit,
+    /// Hide the window; builds go on and the tray stays
+    #[default]
+    MinimizeToTray,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct WindowSettings {
+    #[serde(default)]
+    close_behavior: CloseBehavior,
+    /// The window was hidden to the tray before, and the user was told
+    #[serde(default)]
+    hidden_before: bool,
+}
+
+#[derive(Default)]
+struct TrayBuilds {
+    /// `project v1.2` of builds this app started, by build id
+    labels: HashMap<String, String>,
+    /// In the order they started
+    running: Vec<RunningBuild>,
+    /// Newest first
+    recent: VecDeque<FinishedBuild>,
+    last_run: LastRun,
+    /// Server and build id of the last run while it is running
+    current: Option<(String, String)>,
+    /// When a build last failed
+    failed_at: Option<Instant>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct LastRun {
+    request: Option<StartBuildRequest>,
+    /// Run again with the last number of the version increased
+    #[serde(default)]
+    bump_version: bool,
+}
+
+struct RunningBuild {
+    build_id: String,
+    label: String,
+    progress: u8,
+    node: Option<String>,
+}
+
+struct FinishedBuild {
+    build_id: String,
+    label: String,
+    status: String,
+}
+
+impl Tray {
+    /// Names a build this app is about to start; builds started elsewhere
+    /// show their workflow id
+    pub fn build_requested(&self, build_id: &str, project_name: &str, version: &str) {
+        let label = if version.is_empty() {
+            project_name.to_string()
+        } else {
+            format!("{} v{}", project_name, version)
+        };
+        self.lock().labels.insert(build_id.to_string(), label);
+    }
+
+    /// Takes note of a message from a server
+    pub fn message(&self, message: &ServerMessage) {
+        let mut builds = self.lock();
+        match message {
+            ServerMessage::BuildStarted(started) => {
+                if builds.running.iter().any(|b| b.build_id == started.build_id) {
+                    return;
+                }
+                let label = builds
+                    .labels
+                    .get(&started.build_id)
+                    .cloned()
+                    .unwrap_or_else(|| started.workflow_id.clone());
+                builds.running.push(RunningBuild {
+                    build_id: started.build_id.clone(),
+                    label,
+                    progress: 0,
+                    node: None,
+                });
+            }
+            ServerMessage::BuildProgress(progress) => {
+                match builds.running.iter_mut().find(|b| b.build_id == progress.build_id) {
+                    // The same progress again is not worth a rebuild
+                    Some(build) if build.progress != progress.progress => build.progress = progress.progress,
+                    _ => return,
+                }
+            }
+            ServerMessage::NodeStart(event) => {
+                match builds.running.iter_mut().find(|b| b.build_id == event.build_id) {
+                    Some(build) => build.node = Some(event.node.name.clone()),
+                    None => return,
+                }
+            }
+            ServerMessage::BuildComplete(complete) => {
+                let was_current = builds.current.as_ref().is_some_and(|(_, id)| *id == complete.build_id);
+                if was_current {
+                    builds.current = None;
+                }
+                match builds.running.iter().position(|b| b.build_id == complete.build_id) {
+                    Some(index) => {
+                        let build = builds.running.remove(index);
+                        builds.labels.remove(&build.build_id);
+                        builds.recent.push_front(FinishedBuild {
+                            build_id: build.build_id,
+                            label: build.label,
+                            status: complete.status.clone(),
+                        });
+                        builds.recent.truncate(RECENT_BUILDS);
+                        if complete.status == "failed" {
+                            builds.failed_at = Some(Instant::now());
+                        }
+                    }
+                    None if was_current => {}
+                    None => return,
+                }
+            }
+            _ => return,
+        }
+        drop(builds);
+        self.changed();
+    }
+
+    /// Reads what "Run again" repeats and what closing the window does, at
+    /// startup
+    pub fn load(&self, app: &AppHandle) {
+        if let Some(last_run) = load(app, LAST_RUN_FILE) {
+            self.lock().last_run = last_run;
+        }
+        if let Some(window) = load(app, WINDOW_FILE) {
+            *self.window_settings() = window;
+        }
+        self.changed();
+    }
+
+    pub fn close_behavior(&self) -> CloseBehavior {
+        self.window_settings().close_behavior
+    }
+
+    pub fn set_close_behavior(&self, app: &AppHandle, close_behavior: CloseBehavior) {
+        let window = {
+            let mut window = self.window_settings();
+            window.close_behavior = close_behavior;
+            window.clone()
+        };
+        save(app, WINDOW_FILE, &window);
+    }
+
+    /// Hides the window instead of closing it, if the user wants that
+    pub fn close_requested(&self, window: &tauri::Window, api: &tauri::CloseRequestApi) {
+        if self.close_behavior() == CloseBehavior::Quit {
+            // Stop the server this app started as the app goes away
+            let _ = commands::stop_spawned_server();
+            return;
+        }
+        api.prevent_close();
+        let _ = window.hide();
+        let first_time = {
+            let mut settings = self.window_settings();
+            !std::mem::replace(&mut settings.hidden_before, true)
+        };
+        if first_time {
+            save(&window.app_handle(), WINDOW_FILE, &*self.window_settings());
+            notify(
+                "BuildForge is still running in the tray",
+                "Builds go on while the window is closed. Use Quit in the tray menu to exit.",
+            );
+        }
+    }
+
+    /// Makes a build that just started the one "Run again" repeats
+    pub fn remember(&self, app: &AppHandle, request: StartBuildRequest, build_id: &str) {
+        let last_run = {
+            let mut builds = self.lock();
+            builds.current = Some((request.server_id.clone(), build_id.to_string()));
+            builds.last_run.request = Some(request);
+            builds.last_run.clone()
+        };
+        save(app, LAST_RUN_FILE, &last_run);
+        self.changed();
+    }
+
+    /// Whether "Run again" increases the version
+    pub fn set_bump_version(&self, app: &AppHandle, bump_version: bool) {
+        let last_run = {
+            let mut builds = self.lock();
+            builds.last_run.bump_version = bump_version;
+            builds.last_run.clone()
+        };
+        save(app, LAST_RUN_FILE, &last_run);
+        self.changed();
+    }
+
+    /// Asks for the menu to be rebuilt, for instance after a status change
+    pub fn changed(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Rebuilds the menu whenever something changed, until the app exits
+    pub fn spawn(&self, app: AppHandle) {
+        let tray = self.clone();
+        tauri::async_runtime::spawn(async move {
+            let mut shown = Indicator::Hidden;
+            loop {
+                tray.changed.notified().await;
+                // Whatever else changes meanwhile goes into the same rebuild
+                tokio::time::sleep(DEBOUNCE).await;
+                let servers: Vec<ServerConnection> = app
+                    .state::<AppState>()
+                    .servers
+                    .lock()
+                    .await
+                    .iter()
+                    .map(|s| s.info())
+                    .collect();
+                let (menu, indicator) = {
+                    let builds = tray.lock();
+                    (menu(&servers, &builds), tray.indicator(&builds))
+                };
+                let handle = app.tray_handle();
+                if let Err(e) = handle.set_menu(menu) {
+                    eprintln!("Failed to update the tray menu: {}", e);
+                }
+                #[cfg(target_os = "macos")]
+                let _ = handle.set_title(&match indicator {
+                    Indicator::Progress { count, .. } => format!("⚙ {}", count),
+                    _ => String::new(),
+                });
+                if indicator != shown {
+                    taskbar::show(&app, indicator);
+                    shown = indicator;
+                }
+            }
+        });
+    }
+
+    /// The least advanced running build, so the taskbar never overstates
+    /// how far along things are
+    fn indicator(&self, builds: &TrayBuilds) -> Indicator {
+        if let Some(percent) = builds.running.iter().map(|b| b.progress).min() {
+            return Indicator::Progress {
+                percent,
+                count: builds.running.len(),
+            };
+        }
+        match builds.failed_at {
+            Some(failed_at) if failed_at.elapsed() < FAILURE_SHOWN => {
+                // Come back to clear it
+                let tray = self.clone();
+                tauri::async_runtime::spawn(async move {
+                    tokio::time::sleep(FAILURE_SHOWN).await;
+                    tray.changed();
+                });
+                Indicator::Failed
+            }
+            _ => Indicator::Hidden,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TrayBuilds> {
+        self.builds.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn window_settings(&self) -> std::sync::MutexGuard<'_, WindowSettings> {
+        self.window.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The menu the app starts with, before any server is added
+pub fn initial_menu() -> SystemTrayMenu {
+    menu(&[], &TrayBuilds::default())
+}
+
+fn menu(servers: &[ServerConnection], builds: &TrayBuilds) -> SystemTrayMenu {
+    let mut menu = SystemTrayMenu::new();
+    if servers.is_empty() {
+        menu = menu.add_item(CustomMenuItem::new("no-servers", "No servers").disabled());
+    }
+    for server in servers {
+        let (dot, status) = match server.status {
+            ServerStatus::Online => ("🟢", "online"),
+            ServerStatus::Connecting => ("🟡", "connecting"),
+            ServerStatus::Offline => ("🔴", "offline"),
+        };
+        let title = format!("{} {} — {}", dot, server.name, status);
+        menu = menu.add_item(CustomMenuItem::new(format!("server:{}", server.id), title).disabled());
+    }
+
+    if !builds.running.is_empty() {
+        menu = menu.add_native_item(SystemTrayMenuItem::Separator);
+        for build in &builds.running {
+            let title = match &build.node {
+                Some(node) => format!("{} — {}% ({})", build.label, build.progress, node),
+                None => format!("{} — {}%", build.label, build.progress),
+            };
+            menu = menu.add_item(CustomMenuItem::new(format!("build:{}", build.build_id), title));
+        }
+    }
+
+    if !builds.recent.is_empty() {
+        let mut recent = SystemTrayMenu::new();
+        for build in &builds.recent {
+            let icon = match build.status.as_str() {
+                "success" => "✅",
+                "cancelled" => "⏹",
+                _ => "❌",
+            };
+            let title = format!("{} {}", icon, build.label);
+            recent = recent.add_item(CustomMenuItem::new(format!("build:{}", build.build_id), title));
+        }
+        menu = menu
+            .add_native_item(SystemTrayMenuItem::Separator)
+            .add_submenu(SystemTraySubmenu::new("Recent builds", recent));
+    }
+
+    if builds.current.is_some() {
+        menu = menu
+            .add_native_item(SystemTrayMenuItem::Separator)
+            .add_item(CustomMenuItem::new("cancel-current", "Cancel current build"));
+    } else if let Some(request) = &builds.last_run.request {
+        let title = format!("Run again: {} (v{})", request.project_name, next_version(&builds.last_run));
+        menu = menu
+            .add_native_item(SystemTrayMenuItem::Separator)
+            .add_item(CustomMenuItem::new("run-again", title));
+    }
+
+    menu.add_native_item(SystemTrayMenuItem::Separator)
+        .add_item(CustomMenuItem::new("show".to_string(), "Show BuildForge"))
+        .add_item(CustomMenuItem::new("quit".to_string(), "Quit BuildForge"))
+}
+
+pub fn on_event(app: &AppHandle, event: SystemTrayEvent) {
+    match event {
+        SystemTrayEvent::LeftClick { .. } => show(app),
+        SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
+            "quit" => {
+                // Stop the server this app started before quitting
+                let _ = crate::commands::stop_spawned_server();
+                std::process::exit(0);
+            }
+            "show" => show(app),
+            "run-again" => {
+                let app = app.clone();
+                tauri::async_runtime::spawn(async move { run_again(&app).await });
+            }
+            "cancel-current" => {
+                let app = app.clone();
+                tauri::async_runtime::spawn(async move { cancel_current(&app).await });
+            }
+            id => {
+                if let Some(build_id) = id.strip_prefix("build:") {
+                    show(app);
+                    let _ = app.emit_all("tray:open-build", build_id);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+fn show(app: &AppHandle) {
+    if let Some(window) = app.get_window("main") {
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
+
+/// Starts the last run again, or says why it cannot
+async fn run_again(app: &AppHandle) {
+    let tray = app.state::<Tray>();
+    let (request, version) = {
+        let builds = tray.lock();
+        let Some(request) = builds.last_run.request.clone() else {
+            return;
+        };
+        (request, next_version(&builds.last_run))
+    };
+    let state = app.state::<AppState>();
+    let server = match commands::find_session(&state, &request.server_id).await {
+        Ok(session) => session.info(),
+        Err(_) => {
+            notify(&format!("{} was not run", request.project_name), "Its server has been removed");
+            return;
+        }
+    };
+    if server.status != ServerStatus::Online {
+        let body = format!("{} is not connected; try again once it is online", server.name);
+        notify(&format!("{} was not run", request.project_name), &body);
+        return;
+    }
+    let project_name = request.project_name.clone();
+    if let Err(e) = commands::submit_build(app, StartBuildRequest { version, ..request }).await {
+        notify(&format!("{} was not run", project_name), &e);
+    }
+}
+
+async fn cancel_current(app: &AppHandle) {
+    let tray = app.state::<Tray>();
+    let Some((server_id, build_id)) = tray.lock().current.clone() else {
+        return;
+    };
+    let state = app.state::<AppState>();
+    if let Err(e) = commands::cancel(&state, &build_id, &server_id).await {
+        // Most likely it ended while this app was not looking
+        tray.lock().current = None;
+        tray.changed();
+        notify("The build was not cancelled", &e);
+    }
+}
+
+/// The version "Run again" starts
+fn next_version(last_run: &LastRun) -> String {
+    let version = last_run.request.as_ref().map(|r| r.version.as_str()).unwrap_or_default();
+    if last_run.bump_version {
+        bump(version)
+    } else {
+        version.to_string()
+    }
+}
+
+/// Increases the last number in `version`: 1.2.3 becomes 1.2.4 and
+/// 2.0-beta.9 becomes 2.0-beta.10
+fn bump(version: &str) -> String {
+    let Some(end) = version.rfind(|c: char| c.is_ascii_digit()).map(|i| i + 1) else {
+        return version.to_string();
+    };
+    let start = version[..end].rfind(|c: char| !c.is_ascii_digit()).map_or(0, |i| i + 1);
+    match version[start..end].parse::<u64>() {
+        Ok(number) => format!("{}{}{}", &version[..start], number + 1, &version[end..]),
+        Err(_) => version.to_string(),
+    }
+}
+
+/// Reads `file` from the app data directory, if it is there
+fn load<T: serde::de::DeserializeOwned>(app: &AppHandle, file: &str) -> Option<T> {
+    let path = app.path_resolver().app_data_dir()?.join(file);
+    let json = std::fs::read_to_string(&path).ok()?;
+    match serde_json::from_str(&json) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            eprintln!("Ignoring {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn save<T: Serialize>(app: &AppHandle, file: &str, value: &T) {
+    let Some(dir) = app.path_resolver().app_data_dir() else {
+        return;
+    };
+    let written = std::fs::create_dir_all(&dir).and_then(|_| {
+        let json = serde_json::to_string_pretty(value).map_err(std::io::Error::from)?;
+        std::fs::write(dir.join(file), json)
+    });
+    if let Err(e) = written {
+        eprintln!("Failed to save {}: {}", file, e);
+    }
+}
@@ -0,0 +1,78 @@
+//! Cancelling a build on a real server: a running build stops promptly, and
+//! the server says why when there is nothing to cancel
+
+mod common;
+
+#[allow(dead_code)]
+#[path = "../src/cancel.rs"]
+mod cancel;
+#[allow(dead_code)]
+#[path = "../src/session.rs"]
+mod session;
+
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use cancel::{NotCancelledReason, Reply};
+use common::{next_of, send, TestServer, HELLO};
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a cancelled build may take to end
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The answer a `BuildNotCancelled` gives `commands::cancel`
+fn not_cancelled(message: &Value, build_id: &str, finished: bool) -> Option<Result<(), String>> {
+    let reason: NotCancelledReason = serde_json::from_value(message["payload"]["reason"].clone()).unwrap();
+    let reply = Reply::NotCancelled {
+        build_id: message["payload"]["build_id"].as_str().unwrap(),
+        reason,
+    };
+    cancel::outcome(reply, build_id, finished)
+}
+
+#[tokio::test]
+async fn a_long_build_is_cancelled_promptly() {
+    let server = TestServer::start();
+    let (mut socket, _) = session::open(&server.url(), HELLO).await.unwrap();
+
+    let build = json!({
+        "build_id": "sleeper",
+        "project_name": "sleeper",
+        "version": "1.0.0",
+        "nodes": [{ "id": "n1", "type": "command", "name": "Sleep", "config": { "command": "sleep 60" } }],
+        "edges": [],
+    });
+    send(&mut socket, json!({ "type": "BuildStart", "payload": build })).await;
+    next_of(&mut socket, "NodeStart", REPLY_TIMEOUT).await;
+
+    let cancelled = Instant::now();
+    send(&mut socket, json!({ "type": "BuildCancel", "payload": "sleeper" })).await;
+    let complete = next_of(&mut socket, "BuildComplete", CANCEL_TIMEOUT).await;
+    assert!(cancelled.elapsed() < CANCEL_TIMEOUT);
+    assert_eq!(session::build_id(&complete), Some("sleeper"));
+    assert_eq!(complete["payload"]["status"], "cancelled");
+    let reply = Reply::Complete(session::build_id(&complete).unwrap());
+    assert_eq!(cancel::outcome(reply, "sleeper", false), Some(Ok(())));
+
+    // A second cancel, say from another window, finds the build finished
+    send(&mut socket, json!({ "type": "BuildCancel", "payload": "sleeper" })).await;
+    let again = next_of(&mut socket, "BuildNotCancelled", REPLY_TIMEOUT).await;
+    assert_eq!(again["payload"]["reason"], "already_finished");
+    assert_eq!(not_cancelled(&again, "sleeper", false), Some(Ok(())));
+}
+
+#[tokio::test]
+async fn a_build_the_server_never_ran_is_not_cancelled() {
+    let server = TestServer::start();
+    let (mut socket, _) = session::open(&server.url(), HELLO).await.unwrap();
+
+    send(&mut socket, json!({ "type": "BuildCancel", "payload": "nobody" })).await;
+    let answer = next_of(&mut socket, "BuildNotCancelled", REPLY_TIMEOUT).await;
+    assert_eq!(answer["payload"]["reason"], "not_found");
+    assert_eq!(
+        not_cancelled(&answer, "nobody", false),
+        Some(Err("Build nobody is not running on the server".to_string()))
+    );
+}
@@ -0,0 +1,109 @@
+//! A `buildforge-server` from this repository, on a free port and a fresh
+//! data directory, for the tests that talk to a real server. The server is
+//! built first, unless `BUILDFORGE_SERVER` names a binary to use instead.
+
+#![allow(dead_code)]
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::Message;
+
+/// How long a started server may take to listen
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `ClientInfo` the tests introduce themselves with
+pub const HELLO: &str = r#"{"type":"ClientInfo","payload":{"name":"BuildForge tests","hostname":"test","app_version":"0"}}"#;
+
+pub struct TestServer {
+    child: Child,
+    pub port: u16,
+    data_dir: PathBuf,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        let binary = std::env::var_os("BUILDFORGE_SERVER").map(PathBuf::from).unwrap_or_else(build_server);
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let data_dir = std::env::temp_dir().join(format!("buildforge-app-test-{}-{}", std::process::id(), port));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let child = Command::new(binary)
+            .arg("--port")
+            .arg(port.to_string())
+            .arg("--data-dir")
+            .arg(&data_dir)
+            .arg("--workdir")
+            .arg(&data_dir)
+            .env_remove("GITHUB_TOKEN")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start buildforge-server");
+        let server = Self { child, port, data_dir };
+
+        let started = Instant::now();
+        while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(started.elapsed() < START_TIMEOUT, "buildforge-server did not start listening");
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        server
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+/// Builds the server crate next to this one and returns its binary
+fn build_server() -> PathBuf {
+    let server = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../server");
+    let status = Command::new(env!("CARGO"))
+        .arg("build")
+        .arg("--manifest-path")
+        .arg(server.join("Cargo.toml"))
+        .status()
+        .expect("Failed to run cargo");
+    assert!(status.success(), "Failed to build buildforge-server");
+    server.join("target/debug/buildforge-server")
+}
+
+pub async fn send<S>(socket: &mut S, message: Value)
+where
+    S: SinkExt<Message> + Unpin,
+    S::Error: std::fmt::Debug,
+{
+    socket.send(Message::Text(message.to_string())).await.unwrap();
+}
+
+/// The next message of type `kind`, skipping others, within `timeout`
+pub async fn next_of<S>(socket: &mut S, kind: &str, timeout: Duration) -> Value
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let wait = async {
+        loop {
+            let Some(Ok(Message::Text(text))) = socket.next().await else {
+                continue;
+            };
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message["type"] == kind {
+                return message;
+            }
+        }
+    };
+    tokio::time::timeout(timeout, wait)
+        .await
+        .unwrap_or_else(|_| panic!("No {} within {} ms", kind, timeout.as_millis()))
+}
@@ -0,0 +1,47 @@
+//! A session against a real server: the handshake, Ping and SyncRequest
+
+mod common;
+
+#[allow(dead_code)]
+#[path = "../src/session.rs"]
+mod session;
+
+use std::time::Duration;
+
+use serde_json::json;
+
+use common::{next_of, send, TestServer, HELLO};
+use session::{Event, ServerStatus, State};
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn ping_and_sync_go_both_ways() {
+    let server = TestServer::start();
+    let (mut socket, first) = session::open(&server.url(), HELLO).await.unwrap();
+
+    let capabilities: serde_json::Value = serde_json::from_str(&first.expect("The server announced nothing")).unwrap();
+    assert_eq!(capabilities["type"], "Capabilities");
+    let mut state = State::new(ServerStatus::Connecting, true);
+    let read_only = capabilities["payload"]["read_only"].as_bool();
+    assert!(state.apply(Event::Heard { read_only }));
+    assert_eq!(state, State::new(ServerStatus::Online, false));
+
+    send(&mut socket, json!({ "type": "Ping" })).await;
+    next_of(&mut socket, "Pong", REPLY_TIMEOUT).await;
+
+    send(&mut socket, json!({ "type": "SyncRequest" })).await;
+    let sync = next_of(&mut socket, "SyncResponse", REPLY_TIMEOUT).await;
+    for list in ["workflows", "actions", "repos"] {
+        assert!(sync["payload"][list].is_array(), "SyncResponse has no {}", list);
+    }
+}
+
+#[tokio::test]
+async fn a_server_that_is_gone_cannot_be_opened() {
+    let server = TestServer::start();
+    let url = server.url();
+    drop(server);
+    let e = session::open(&url, HELLO).await.unwrap_err();
+    assert!(e.starts_with("Failed to connect"), "{}", e);
+}
@@ -0,0 +1,286 @@
+import { useEffect, useState } from "react";
+import { X, Settings, AlertCircle } from "lucide-react";
+import { invoke } from "@tauri-apps/api/tauri";
+import { toast } from "./ui/toaster";
+import type { Server } from "../store/appStore";
+
+interface RetentionPolicy {
+  max_history_per_workflow: number | null;
+  max_history_age_days: number | null;
+}
+
+interface ExecutionPolicy {
+  allowed_programs: string[];
+  denied_patterns: string[];
+  forbid_scripts: boolean;
+}
+
+interface ResourceLimits {
+  nice: number | null;
+  cpu_limit: number | null;
+  memory_limit_mb: number | null;
+}
+
+interface ServerSettings {
+  port: number;
+  default_shell: string;
+  default_node_timeout_secs: number | null;
+  max_concurrent_builds: number | null;
+  heartbeat_interval_secs: number;
+  heartbeat_timeout_secs: number;
+  retention: RetentionPolicy;
+  shutdown_grace_period_secs: number;
+  isolated_workspaces: boolean;
+  keep_workspace_on_failure: boolean;
+  workspace_retention_days: number | null;
+  github_api_url: string | null;
+  github_upload_url: string | null;
+  cache_max_size_mb: number | null;
+  cache_max_age_days: number | null;
+  max_node_output_mb: number | null;
+  max_log_line_bytes: number | null;
+  ansi_mode: "strip" | "keep" | "auto";
+  resource_limits: ResourceLimits;
+  execution_policy: ExecutionPolicy;
+}
+
+interface SettingsPayload {
+  settings: ServerSettings;
+  restart_required: string[];
+}
+
+interface ServerSettingsModalProps {
+  server: Server;
+  onClose: () => void;
+}
+
+// Empty inputs mean "not set" for the optional numeric settings
+const toOptional = (value: string) => (value.trim() === "" ? null : Number(value));
+const toOptionalText = (value: string) => (value.trim() === "" ? null : value.trim());
+
+export function ServerSettingsModal({ server, onClose }: ServerSettingsModalProps) {
+  const [serverId, setServerId] = useState<string | null>(null);
+  const [readOnly, setReadOnly] = useState(false);
+  const [settings, setSettings] = useState<ServerSettings | null>(null);
+  const [restartRequired, setRestartRequired] = useState<string[]>([]);
+  const [loading, setLoading] = useState(true);
+  const [saving, setSaving] = useState(false);
+  const [error, setError] = useState<string | null>(null);
+
+  useEffect(() => {
+    const load = async () => {
+      try {
+        const connection = await invoke<{ id: string; read_only: boolean }>("connect_server", {
+          request: { name: server.name, address: server.address, port: server.port },
+        });
+        setServerId(connection.id);
+        setReadOnly(connection.read_only);
+        const payload = await invoke<SettingsPayload>("get_server_settings", { serverId: connection.id });
+        setSettings(payload.settings);
+        setRestartRequired(payload.restart_required);
+      } catch (e) {
+        setError(String(e));
+      } finally {
+        setLoading(false);
+      }
+    };
+    load();
+  }, [server]);
+
+  const update = (changes: Partial<ServerSettings>) => {
+    setSettings((prev) => (prev ? { ...prev, ...changes } : prev));
+  };
+
+  const save = async () => {
+    if (!settings || !serverId) return;
+    setSaving(true);
+    setError(null);
+    try {
+      const payload = await invoke<SettingsPayload>("set_server_settings", { serverId, settings });
+      setSettings(payload.settings);
+      setRestartRequired(payload.restart_required);
+      toast({
+        type: "success",
+        title: "Server settings saved",
+        message: payload.restart_required.length > 0 ? "Some changes apply after a server restart" : undefined,
+      });
+    } catch (e) {
+      setError(String(e));
+    } finally {
+      setSaving(false);
+    }
+  };
+
+  const numberInput = (label: string, value: number | null, onChange: (value: string) => void, placeholder?: string) => (
+    <label className="block">
+      <span className="text-sm text-slate-400">{label}</span>
+      <input
+        type="number"
+        min={0}
+        value={value ?? ""}
+        placeholder={placeholder}
+        onChange={(e) => onChange(e.target.value)}
+        className="mt-1 w-full px-3 py-2 bg-slate-900 border border-slate-700 rounded-lg text-white text-sm focus:outline-none focus:border-blue-500"
+      />
+    </label>
+  );
+
+  const checkbox = (label: string, checked: boolean, onChange: (checked: boolean) => void) => (
+    <label className="flex items-center gap-2 text-sm text-slate-300">
+      <input type="checkbox" checked={checked} onChange={(e) => onChange(e.target.checked)} className="accent-blue-500" />
+      {label}
+    </label>
+  );
+
+  return (
+    <div className="fixed inset-0 bg-black/50 flex items-center justify-center z-50">
+      <div className="bg-slate-800 border border-slate-700 rounded-xl p-6 w-full max-w-2xl max-h-[80vh] overflow-auto">
+        <div className="flex items-center justify-between mb-6">
+          <h3 className="text-xl font-bold text-white flex items-center gap-2">
+            <Settings className="w-5 h-5 text-blue-400" />
+            {server.name} Settings
+          </h3>
+          <button onClick={onClose} className="p-2 text-slate-400 hover:text-white rounded-lg hover:bg-slate-700">
+            <X className="w-5 h-5" />
+          </button>
+        </div>
+
+        {loading && <p className="text-slate-400 text-sm">Loading settings...</p>}
+
+        {error && (
+          <div className="mb-4 p-3 bg-red-500/10 border border-red-500/30 rounded-lg text-red-400 text-sm flex items-center gap-2">
+            <AlertCircle className="w-4 h-4 shrink-0" />
+            {error}
+          </div>
+        )}
+
+        {readOnly && (
+          <div className="mb-4 p-3 bg-slate-700/50 border border-slate-600 rounded-lg text-slate-300 text-sm">
+            This server is read-only. Its settings can be viewed but not changed.
+          </div>
+        )}
+
+        {restartRequired.length > 0 && (
+          <div className="mb-4 p-3 bg-yellow-500/10 border border-yellow-500/30 rounded-lg text-yellow-400 text-sm">
+            Restart the server to apply: {restartRequired.join(", ")}
+          </div>
+        )}
+
+        {settings && (
+          <div className="space-y-6">
+            <fieldset disabled={readOnly} className="grid grid-cols-2 gap-4 disabled:opacity-60">
+              {numberInput("Port", settings.port, (v) => update({ port: Number(v) }))}
+              <label className="block">
+                <span className="text-sm text-slate-400">Default shell</span>
+                <input
+                  type="text"
+                  value={settings.default_shell}
+                  onChange={(e) => update({ default_shell: e.target.value })}
+                  className="mt-1 w-full px-3 py-2 bg-slate-900 border border-slate-700 rounded-lg text-white text-sm focus:outline-none focus:border-blue-500"
+                />
+              </label>
+              {numberInput("Max concurrent builds", settings.max_concurrent_builds,
+                (v) => update({ max_concurrent_builds: toOptional(v) }), "Unlimited")}
+              {numberInput("Default node timeout (s)", settings.default_node_timeout_secs,
+                (v) => update({ default_node_timeout_secs: toOptional(v) }), "None")}
+              {numberInput("Heartbeat interval (s)", settings.heartbeat_interval_secs,
+                (v) => update({ heartbeat_interval_secs: Number(v) }))}
+              {numberInput("Heartbeat timeout (s)", settings.heartbeat_timeout_secs,
+                (v) => update({ heartbeat_timeout_secs: Number(v) }))}
+              {numberInput("Builds kept per workflow", settings.retention.max_history_per_workflow,
+                (v) => update({ retention: { ...settings.retention, max_history_per_workflow: toOptional(v) } }), "Unlimited")}
+              {numberInput("Days builds are kept", settings.retention.max_history_age_days,
+                (v) => update({ retention: { ...settings.retention, max_history_age_days: toOptional(v) } }), "Unlimited")}
+              {numberInput("Shutdown grace period (s)", settings.shutdown_grace_period_secs,
+                (v) => update({ shutdown_grace_period_secs: Number(v) }))}
+              {numberInput("Cache size limit (MB)", settings.cache_max_size_mb,
+                (v) => update({ cache_max_size_mb: toOptional(v) }), "Unlimited")}
+              {numberInput("Days unused cache entries are kept", settings.cache_max_age_days,
+                (v) => update({ cache_max_age_days: toOptional(v) }), "Unlimited")}
+              {numberInput("Days kept workspaces and artifacts stay", settings.workspace_retention_days,
+                (v) => update({ workspace_retention_days: toOptional(v) }), "Forever")}
+              {numberInput("Output per node (MB)", settings.max_node_output_mb,
+                (v) => update({ max_node_output_mb: toOptional(v) }), "Unlimited")}
+              {numberInput("Log line length (bytes)", settings.max_log_line_bytes,
+                (v) => update({ max_log_line_bytes: toOptional(v) }), "Unlimited")}
+              <label className="block">
+                <span className="text-sm text-slate-400">Color codes in logs</span>
+                <select
+                  value={settings.ansi_mode}
+                  onChange={(e) => update({ ansi_mode: e.target.value as ServerSettings["ansi_mode"] })}
+                  className="mt-1 w-full px-3 py-2 bg-slate-900 border border-slate-700 rounded-lg text-white text-sm focus:outline-none focus:border-blue-500"
+                >
+                  <option value="auto">Strip from saved logs only</option>
+                  <option value="strip">Strip everywhere</option>
+                  <option value="keep">Keep</option>
+                </select>
+              </label>
+              {numberInput("Node priority (nice)", settings.resource_limits.nice,
+                (v) => update({ resource_limits: { ...settings.resource_limits, nice: toOptional(v) } }), "Unchanged")}
+              {numberInput("Node CPU limit (cores)", settings.resource_limits.cpu_limit,
+                (v) => update({ resource_limits: { ...settings.resource_limits, cpu_limit: toOptional(v) } }), "Unlimited")}
+              {numberInput("Node memory limit (MB)", settings.resource_limits.memory_limit_mb,
+                (v) => update({ resource_limits: { ...settings.resource_limits, memory_limit_mb: toOptional(v) } }), "Unlimited")}
+              {(["github_api_url", "github_upload_url"] as const).map((field) => (
+                <label key={field} className="block col-span-2">
+                  <span className="text-sm text-slate-400">
+                    {field === "github_api_url" ? "GitHub API URL" : "GitHub upload URL"}
+                  </span>
+                  <input
+                    type="text"
+                    value={settings[field] ?? ""}
+                    placeholder={field === "github_api_url" ? "https://api.github.com" : "Derived from the API URL"}
+                    onChange={(e) => update({ [field]: toOptionalText(e.target.value) })}
+                    className="mt-1 w-full px-3 py-2 bg-slate-900 border border-slate-700 rounded-lg text-white text-sm focus:outline-none focus:border-blue-500"
+                  />
+                </label>
+              ))}
+              <label className="block col-span-2">
+                <span className="text-sm text-slate-400">Programs nodes may run (comma-separated)</span>
+                <input
+                  type="text"
+                  value={settings.execution_policy.allowed_programs.join(", ")}
+                  placeholder="Any"
+                  onChange={(e) => update({ execution_policy: { ...settings.execution_policy,
+                    allowed_programs: e.target.value.split(",").map((p) => p.trim()).filter(Boolean) } })}
+                  className="mt-1 w-full px-3 py-2 bg-slate-900 border border-slate-700 rounded-lg text-white text-sm focus:outline-none focus:border-blue-500"
+                />
+              </label>
+              <label className="block col-span-2">
+                <span className="text-sm text-slate-400">Denied patterns (regular expressions, one per line)</span>
+                <textarea
+                  rows={3}
+                  value={settings.execution_policy.denied_patterns.join("\n")}
+                  onChange={(e) => update({ execution_policy: { ...settings.execution_policy,
+                    denied_patterns: e.target.value.split("\n").filter((p) => p.trim() !== "") } })}
+                  className="mt-1 w-full px-3 py-2 bg-slate-900 border border-slate-700 rounded-lg text-white text-sm font-mono focus:outline-none focus:border-blue-500"
+                />
+              </label>
+              <div className="col-span-2 space-y-2">
+                {checkbox("Run each build in its own workspace", settings.isolated_workspaces,
+                  (checked) => update({ isolated_workspaces: checked }))}
+                {checkbox("Keep the workspace of failed builds", settings.keep_workspace_on_failure,
+                  (checked) => update({ keep_workspace_on_failure: checked }))}
+                {checkbox("Forbid script nodes", settings.execution_policy.forbid_scripts,
+                  (checked) => update({ execution_policy: { ...settings.execution_policy, forbid_scripts: checked } }))}
+              </div>
+            </fieldset>
+
+            <div className="flex justify-end gap-2">
+              <button onClick={onClose} className="px-4 py-2 bg-slate-700 hover:bg-slate-600 rounded-lg text-white text-sm">
+                Cancel
+              </button>
+              <button
+                onClick={save}
+                disabled={saving || readOnly}
+                className="px-4 py-2 bg-blue-600 hover:bg-blue-500 rounded-lg text-white text-sm font-medium disabled:opacity-50"
+              >
+                {saving ? "Saving..." : "Save"}
+              </button>
+            </div>
+          </div>
+        )}
+      </div>
+    </div>
+  );
+}
@@ -349,7 +349,7 @@ export function ActionsTab() {
     }
   };
 
-  const updateInput = (index: number, field: string, value: string | boolean) => {
+  const updateInput = (index: number, field: string, value: string | boolean | string[]) => {
     if (editingAction?.inputs) {
       const newInputs = [...editingAction.inputs];
       newInputs[index] = { ...newInputs[index], [field]: value };
@@ -600,6 +600,25 @@ export function ActionsTab() {
                                 className="w-full px-2 py-1 bg-slate-700 border border-slate-600 rounded text-white"
                                 placeholder="Input name"
                               />
+                              <select
+                                value={input.type || "string"}
+                                onChange={(e) => updateInput(idx, "type", e.target.value)}
+                                className="w-full px-2 py-1 bg-slate-700 border border-slate-600 rounded text-white"
+                              >
+                                <option value="string">Text</option>
+                                <option value="number">Number</option>
+                                <option value="boolean">True / false</option>
+                                <option value="choice">Choice</option>
+                              </select>
+                              {input.type === "choice" && (
+                                <input
+                                  type="text"
+                                  value={(input.choices || []).join(", ")}
+                                  onChange={(e) => updateInput(idx, "choices", e.target.value.split(",").map(c => c.trim()).filter(Boolean))}
+                                  className="w-full px-2 py-1 bg-slate-700 border border-slate-600 rounded text-white"
+                                  placeholder="Choices, separated by commas"
+                                />
+                              )}
                               <div className="flex items-center gap-2">
                                 <label className="flex items-center gap-1 text-slate-400">
                                   <input
@@ -610,6 +629,15 @@ export function ActionsTab() {
                                   />
                                   Required
                                 </label>
+                                <label className="flex items-center gap-1 text-slate-400">
+                                  <input
+                                    type="checkbox"
+                                    checked={!!input.secret}
+                                    onChange={(e) => updateInput(idx, "secret", e.target.checked)}
+                                    className="w-3 h-3"
+                                  />
+                                  Secret
+                                </label>
                                 <button
                                   onClick={() => removeInput(idx)}
                                   className="text-red-400 hover:text-red-300 ml-auto"
@@ -1,6 +1,8 @@
 import { useEffect, useState, useRef, useMemo } from "react";
 import { Plus, Server as ServerIcon, Wifi, Trash2, RefreshCw, Search, Play, Square, Terminal, Box, Download, Settings, X, Gauge } from "lucide-react";
 import { useAppStore } from "../../store/appStore";
+import type { Server } from "../../store/appStore";
+import { ServerSettingsModal } from "../ServerSettingsModal";
 import { invoke } from "@tauri-apps/api/tauri";
 
 // Fastfetch ASCII art - exact from fastfetch source
@@ -101,6 +103,7 @@ export function ServersTab() {
   const { servers, setServers } = useAppStore();
   const [showAddModal, setShowAddModal] = useState(false);
   const [showConfigModal, setShowConfigModal] = useState(false);
+  const [settingsServer, setSettingsServer] = useState<Server | null>(null);
   const [isScanning, setIsScanning] = useState(false);
   const [localServerRunning, setLocalServerRunning] = useState(true); // Auto-start enabled
   const [newServer, setNewServer] = useState({ name: "", address: "", port: "9999", targetOS: "any" as "windows" | "macos" | "linux" | "any" });
@@ -896,6 +899,14 @@ export function ServersTab() {
                   <Wifi className="w-4 h-4" />
                   {server.id === selectedServerId ? "Reconnect" : "Use Server"}
                 </button>
+                <button
+                  onClick={() => setSettingsServer(server)}
+                  disabled={server.status !== "online"}
+                  title="Server settings"
+                  className="p-2 text-slate-400 hover:text-white rounded hover:bg-slate-700 disabled:opacity-30"
+                >
+                  <Settings className="w-4 h-4" />
+                </button>
                 <button
                   onClick={() => removeServer(server.id)}
                   className="p-2 text-slate-500 hover:text-red-400 opacity-0 group-hover:opacity-100"
@@ -917,6 +928,10 @@ export function ServersTab() {
         </div>
       </div>
 
+      {settingsServer && (
+        <ServerSettingsModal server={settingsServer} onClose={() => setSettingsServer(null)} />
+      )}
+
       {/* Config Modal */}
       {showConfigModal && (
         <div className="fixed inset-0 bg-black/50 flex items-center justify-center z-50">
@@ -3388,19 +3388,38 @@ export function WorkflowsTab() {
                                   {input.name}
                                   {input.required && <span className="text-red-400 ml-1">*</span>}
                                 </label>
-                                <input
-                                  type="text"
-                                  value={(selectedNodeData.config.actionInputs as Record<string, string>)?.[input.name] || input.default || ""}
-                                  onChange={(e) => {
-                                    const currentInputs = (selectedNodeData.config.actionInputs as Record<string, string>) || {};
-                                    updateNodeConfig(selectedNodeData.id, "actionInputs", {
-                                      ...currentInputs,
-                                      [input.name]: e.target.value
-                                    });
-                                  }}
-                                  placeholder={input.description || input.default || ""}
-                                  className="w-full bg-slate-800 border border-slate-600 rounded px-2 py-1.5 text-sm text-white font-mono"
-                                />
+                                {input.type === "choice" || input.type === "boolean" ? (
+                                  <select
+                                    value={(selectedNodeData.config.actionInputs as Record<string, string>)?.[input.name] || input.default || ""}
+                                    onChange={(e) => {
+                                      const currentInputs = (selectedNodeData.config.actionInputs as Record<string, string>) || {};
+                                      updateNodeConfig(selectedNodeData.id, "actionInputs", {
+                                        ...currentInputs,
+                                        [input.name]: e.target.value
+                                      });
+                                    }}
+                                    className="w-full bg-slate-800 border border-slate-600 rounded px-2 py-1.5 text-sm text-white font-mono"
+                                  >
+                                    <option value="">{input.description || "Select..."}</option>
+                                    {(input.type === "boolean" ? ["true", "false"] : input.choices || []).map(choice => (
+                                      <option key={choice} value={choice}>{choice}</option>
+                                    ))}
+                                  </select>
+                                ) : (
+                                  <input
+                                    type={input.type === "number" ? "number" : "text"}
+                                    value={(selectedNodeData.config.actionInputs as Record<string, string>)?.[input.name] || input.default || ""}
+                                    onChange={(e) => {
+                                      const currentInputs = (selectedNodeData.config.actionInputs as Record<string, string>) || {};
+                                      updateNodeConfig(selectedNodeData.id, "actionInputs", {
+                                        ...currentInputs,
+                                        [input.name]: e.target.value
+                                      });
+                                    }}
+                                    placeholder={input.description || input.default || ""}
+                                    className="w-full bg-slate-800 border border-slate-600 rounded px-2 py-1.5 text-sm text-white font-mono"
+                                  />
+                                )}
                               </div>
                             ))}
                           </div>
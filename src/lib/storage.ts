@@ -126,11 +126,16 @@ export interface LocalAction {
   updatedAt: string;
 }
 
+export type ActionInputType = "string" | "number" | "boolean" | "choice";
+
 export interface ActionInput {
   name: string;
   description: string;
+  type?: ActionInputType; // "string" if unset
   required: boolean;
+  secret?: boolean; // Masked in the server's run history and output
   default?: string;
+  choices?: string[]; // Allowed values of a "choice" input
 }
 
 export interface ActionOutput {